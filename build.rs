@@ -0,0 +1,8 @@
+fn main() {
+    // the protobuf codec is opt-in, the default build needs no protoc
+    #[cfg(feature = "proto")]
+    {
+        println!("cargo:rerun-if-changed=proto/raftor.proto");
+        prost_build::compile_protos(&["proto/raftor.proto"], &["proto/"]).unwrap();
+    }
+}
@@ -0,0 +1,214 @@
+//! Tie-breaker for two node clusters.
+//!
+//! Both nodes compete for a lease held on a shared disk or granted by an external HTTP service.
+//! Only the lease holder sends raft RPCs: the other node stops campaigning and replicating but
+//! keeps following. Once the lease holder dies and its lease expires, the survivor takes the
+//! lease over. The lease is the tie-breaking vote: while its peer has no session, the holder
+//! answers the RPCs meant for the peer in its place, so a lone survivor is elected and commits,
+//! see `crate::network::witness`.
+
+use actix::prelude::*;
+use actix_raft::NodeId;
+use actix_web::client::Client;
+use futures::future::{self, Future};
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::config::ArbitrationConfig;
+use crate::network::{Network, SetLease};
+use crate::raft::timing::now_millis;
+
+enum Tiebreaker {
+    /// Lease file holding `<node_id> <expires_at_millis>`
+    File(PathBuf),
+    /// Service answering `PUT` lease requests with a success status when granted
+    Http(String),
+}
+
+#[derive(Serialize)]
+struct LeaseRequest {
+    node_id: NodeId,
+    ttl_secs: u64,
+    /// The holder committed entries its peer lacks, the service must not hand the lease over
+    solo: bool,
+}
+
+impl Tiebreaker {
+    fn from_config(config: &ArbitrationConfig) -> Option<Tiebreaker> {
+        match (&config.lease_file, &config.tiebreaker_url) {
+            (Some(path), None) => Some(Tiebreaker::File(path.into())),
+            (None, Some(url)) => Some(Tiebreaker::Http(url.clone())),
+            _ => None,
+        }
+    }
+
+    /// Acquire or renew the lease, resolves to whether it is held by `id`.
+    fn acquire(&self, id: NodeId, ttl_secs: u64, solo: bool) -> Box<dyn Future<Item = bool, Error = ()>> {
+        match self {
+            Tiebreaker::File(path) => Box::new(future::result(
+                acquire_file_lease(path, id, ttl_secs, solo).map_err(|err| error!("Failed to acquire lease file {:?}: {:?}", path, err)),
+            )),
+            Tiebreaker::Http(url) => Box::new(
+                Client::default()
+                    .put(url.as_str())
+                    .send_json(&LeaseRequest {
+                        node_id: id,
+                        ttl_secs: ttl_secs,
+                        solo: solo,
+                    })
+                    .map(|res| res.status().is_success())
+                    .map_err(|err| error!("Tie-breaker request failed: {:?}", err)),
+            ),
+        }
+    }
+}
+
+/// The lease is granted when the file is empty, expired or already ours. Both nodes hold an
+/// exclusive `flock` on the file while reading and rewriting it, so only one of them wins an
+/// expired lease. A solo lease is never taken over: its holder committed entries its peer
+/// lacks, an operator removes the file once the holder's data is recovered.
+fn acquire_file_lease(path: &Path, id: NodeId, ttl_secs: u64, solo: bool) -> io::Result<bool> {
+    let mut file = OpenOptions::new().read(true).write(true).create(true).open(path)?;
+    lock_exclusive(&file)?;
+
+    let mut content = String::new();
+    file.read_to_string(&mut content)?;
+
+    let now = now_millis();
+    if !lease_available(&content, id, now) {
+        return Ok(false);
+    }
+
+    file.set_len(0)?;
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(format!("{} {} {}", id, now + ttl_secs * 1000, solo as u8).as_bytes())?;
+    file.sync_all()?;
+    // closing the file releases the lock
+    Ok(true)
+}
+
+/// Whether the lease `<node_id> <expires_at_millis> <solo>` can be taken by `id`.
+fn lease_available(content: &str, id: NodeId, now: u64) -> bool {
+    let mut parts = content.split_whitespace();
+    let holder = match parts.next().and_then(|part| part.parse::<NodeId>().ok()) {
+        Some(holder) => holder,
+        None => return true,
+    };
+    let expires = parts.next().and_then(|part| part.parse::<u64>().ok()).unwrap_or(0);
+    let solo = parts.next() == Some("1");
+
+    if holder != id && solo {
+        warn!("Tie-breaker lease is held solo by node {}, remove it once that node's data is recovered", holder);
+    }
+    holder == id || (expires <= now && !solo)
+}
+
+#[cfg(unix)]
+fn lock_exclusive(file: &File) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    // blocks only while the other node rewrites the lease
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn lock_exclusive(_file: &File) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other, "lease files need flock"))
+}
+
+/// Keeps the tie-breaking lease: without it the node sends no raft RPCs, with it the lease
+/// votes in place of an unreachable peer.
+pub struct Arbitration {
+    id: NodeId,
+    tiebreaker: Tiebreaker,
+    lease_secs: u64,
+    net: Addr<Network>,
+    /// Whether entries only this node holds were acknowledged with the lease's vote
+    solo: bool,
+}
+
+impl Arbitration {
+    pub fn new(id: NodeId, config: &ArbitrationConfig, net: Addr<Network>) -> Option<Arbitration> {
+        let tiebreaker = match Tiebreaker::from_config(config) {
+            Some(tiebreaker) => tiebreaker,
+            None => {
+                error!("Arbitration needs exactly one of `lease_file` and `tiebreaker_url`");
+                return None;
+            }
+        };
+
+        Some(Arbitration {
+            id: id,
+            tiebreaker: tiebreaker,
+            lease_secs: config.lease_secs,
+            net: net,
+            solo: false,
+        })
+    }
+
+    fn renew(&mut self, ctx: &mut Context<Self>) {
+        fut::wrap_future::<_, Self>(self.tiebreaker.acquire(self.id, self.lease_secs, self.solo))
+            .then(|res, act, _| {
+                // the network steps down and back up as the lease changes hands
+                fut::wrap_future::<_, Self>(act.net.send(SetLease(res.unwrap_or(false))))
+            })
+            .map(|solo, act, _| act.solo = solo)
+            .map_err(|err, act, _| error!("Node {} failed to record the tie-breaker lease: {:?}", act.id, err))
+            .then(|_, _, _| fut::ok(()))
+            .wait(ctx);
+    }
+}
+
+impl Actor for Arbitration {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        // renew well before the lease runs out
+        let interval = Duration::from_millis(self.lease_secs * 1000 / 3);
+
+        self.renew(ctx);
+        ctx.run_interval(interval, |act, ctx| act.renew(ctx));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expired_lease_is_taken_over() {
+        assert!(lease_available("", 1, 100));
+        assert!(lease_available("2 100 0", 1, 100));
+        assert!(!lease_available("2 101 0", 1, 100));
+        assert!(lease_available("1 101 0", 1, 100));
+    }
+
+    #[test]
+    fn solo_lease_is_kept_by_its_holder() {
+        assert!(!lease_available("2 100 1", 1, 500));
+        assert!(lease_available("2 100 1", 2, 500));
+    }
+
+    #[test]
+    fn file_lease_has_a_single_holder() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("lease");
+
+        assert!(acquire_file_lease(&path, 1, 60, false).unwrap());
+        assert!(!acquire_file_lease(&path, 2, 60, false).unwrap());
+        assert!(acquire_file_lease(&path, 1, 60, false).unwrap());
+
+        // expired right away, but kept solo
+        assert!(acquire_file_lease(&path, 1, 0, true).unwrap());
+        assert!(!acquire_file_lease(&path, 2, 60, false).unwrap());
+
+        assert!(acquire_file_lease(&path, 1, 0, false).unwrap());
+        assert!(acquire_file_lease(&path, 2, 60, false).unwrap());
+    }
+}
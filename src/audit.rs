@@ -0,0 +1,201 @@
+//! Development audit of the messages handled by the actors of a node: `Network`, `Node`,
+//! `NodeSession` and `RaftClient` count every message they handle by type and how long the
+//! handler ran, and log each of them under the `raftor::audit` target. Only built with the
+//! `audit` feature and off until switched on, through `audit_messages` or `/admin/audit` of
+//! the admin listener.
+
+use serde::{Deserialize, Serialize};
+
+/// Handling of one message type by one actor.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct MessageAudit {
+    pub actor: String,
+    pub message: String,
+    pub count: u64,
+    /// Time spent in the handler, futures it returns run on afterwards and aren't included
+    pub total_us: u64,
+    pub max_us: u64,
+}
+
+/// Audited messages, most frequent first.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AuditReport {
+    /// Whether raftor was built with the `audit` feature
+    pub available: bool,
+    pub enabled: bool,
+    pub messages: Vec<MessageAudit>,
+}
+
+/// Count the message handled in the current scope when built with the `audit` feature and
+/// the audit is on.
+#[macro_export]
+macro_rules! audit_message {
+    ($msg:ty) => {
+        #[cfg(feature = "audit")]
+        let _audit = $crate::audit::enter::<Self, $msg>();
+    };
+}
+
+#[cfg(feature = "audit")]
+pub use self::enabled::*;
+
+#[cfg(feature = "audit")]
+mod enabled {
+    use lazy_static::lazy_static;
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Mutex;
+    use std::time::Instant;
+
+    use super::{AuditReport, MessageAudit};
+
+    static ENABLED: AtomicBool = AtomicBool::new(false);
+
+    lazy_static! {
+        static ref AUDITED: Mutex<HashMap<(&'static str, &'static str), MessageAudit>> = Mutex::new(HashMap::new());
+    }
+
+    /// Records the message once dropped at the end of the handler.
+    pub struct AuditGuard {
+        actor: &'static str,
+        message: &'static str,
+        start: Instant,
+    }
+
+    /// Start auditing a message of type `M` handled by actor `A`, `None` while the audit is
+    /// off.
+    pub fn enter<A, M>() -> Option<AuditGuard> {
+        if !ENABLED.load(Ordering::Relaxed) {
+            return None;
+        }
+
+        Some(AuditGuard {
+            actor: std::any::type_name::<A>(),
+            message: std::any::type_name::<M>(),
+            start: Instant::now(),
+        })
+    }
+
+    impl Drop for AuditGuard {
+        fn drop(&mut self) {
+            let elapsed = self.start.elapsed();
+            let us = elapsed.as_micros() as u64;
+            let mut audited = AUDITED.lock().unwrap_or_else(|err| err.into_inner());
+            let audit = audited.entry((self.actor, self.message)).or_insert_with(|| MessageAudit {
+                actor: short_name(self.actor),
+                message: short_name(self.message),
+                ..MessageAudit::default()
+            });
+            audit.count += 1;
+            audit.total_us += us;
+            audit.max_us = audit.max_us.max(us);
+
+            debug!(target: "raftor::audit", "{} handled {} in {:?}", audit.actor, audit.message, elapsed);
+        }
+    }
+
+    pub fn set_enabled(enabled: bool) {
+        if ENABLED.swap(enabled, Ordering::Relaxed) != enabled {
+            info!("Message audit {}", if enabled { "enabled" } else { "disabled" });
+        }
+    }
+
+    pub fn report() -> AuditReport {
+        let mut messages = AUDITED
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        messages.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.message.cmp(&b.message)));
+
+        AuditReport {
+            available: true,
+            enabled: ENABLED.load(Ordering::Relaxed),
+            messages: messages,
+        }
+    }
+
+    pub fn reset() {
+        AUDITED.lock().unwrap_or_else(|err| err.into_inner()).clear();
+    }
+
+    /// Type name without module paths, also within generic arguments.
+    pub(super) fn short_name(name: &str) -> String {
+        let mut short = String::with_capacity(name.len());
+        let mut segment = String::new();
+
+        for c in name.chars() {
+            match c {
+                ':' => segment.clear(),
+                '<' | '>' | ',' | ' ' | '&' | '(' | ')' | '[' | ']' | ';' => {
+                    short.push_str(&segment);
+                    segment.clear();
+                    short.push(c);
+                }
+                _ => segment.push(c),
+            }
+        }
+        short.push_str(&segment);
+        short
+    }
+}
+
+#[cfg(not(feature = "audit"))]
+pub fn set_enabled(enabled: bool) {
+    if enabled {
+        warn!("Message audit requested but raftor was built without the `audit` feature");
+    }
+}
+
+#[cfg(not(feature = "audit"))]
+pub fn report() -> AuditReport {
+    AuditReport::default()
+}
+
+#[cfg(not(feature = "audit"))]
+pub fn reset() {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "audit")]
+    #[test]
+    fn module_paths_are_stripped_from_type_names() {
+        assert_eq!(
+            enabled::short_name("actix::address::Addr<raftor::network::Network>"),
+            "Addr<Network>"
+        );
+        assert_eq!(
+            enabled::short_name("core::result::Result<(alloc::string::String, u64), ()>"),
+            "Result<(String, u64), ()>"
+        );
+    }
+
+    #[cfg(feature = "audit")]
+    #[test]
+    fn handled_messages_are_counted_while_enabled() {
+        struct Auditor;
+        struct Audited;
+
+        drop(enter::<Auditor, Audited>());
+        set_enabled(true);
+        drop(enter::<Auditor, Audited>());
+        drop(enter::<Auditor, Audited>());
+        set_enabled(false);
+
+        let report = report();
+        assert!(report.available);
+        let audit = report.messages.iter().find(|audit| audit.message == "Audited").unwrap();
+        assert_eq!((audit.actor.as_str(), audit.count), ("Auditor", 2));
+    }
+
+    #[cfg(not(feature = "audit"))]
+    #[test]
+    fn nothing_is_audited_without_the_feature() {
+        set_enabled(true);
+        let report = report();
+        assert!(!report.available && !report.enabled && report.messages.is_empty());
+    }
+}
@@ -0,0 +1,219 @@
+use actix::prelude::*;
+use actix_web::client::Client;
+use actix_web::http::header;
+use serde::Deserialize;
+use std::time::{Duration, Instant};
+
+use crate::raft::storage::MemoryStorageData;
+
+/// Load to generate against a running node.
+#[derive(Clone, Debug)]
+pub struct BenchConfig {
+    /// Public address of the node, point this at the leader
+    pub target: String,
+    /// Admin address of the same node, writes are proposed through it
+    pub admin: String,
+    /// Bearer token of the admin listener, when it's protected by one
+    pub admin_token: Option<String>,
+    pub requests: usize,
+    pub concurrency: usize,
+    /// Fraction of requests which are reads, the rest are writes
+    pub read_ratio: f64,
+}
+
+impl BenchConfig {
+    pub fn new(target: String) -> BenchConfig {
+        BenchConfig {
+            target: target,
+            admin: "127.0.0.1:9080".to_owned(),
+            admin_token: None,
+            requests: 1000,
+            concurrency: 16,
+            read_ratio: 0.0,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct CpuUsage {
+    cpu_millis: Option<u64>,
+}
+
+/// Generates synthetic write/read load against a running node and reports throughput,
+/// latency percentiles and the CPU time spent by the target.
+pub struct Bench {
+    config: BenchConfig,
+    client: Client,
+    started: Instant,
+    sent: usize,
+    done: usize,
+    errors: usize,
+    writes: Vec<Duration>,
+    reads: Vec<Duration>,
+    cpu_start: Option<u64>,
+}
+
+impl Bench {
+    pub fn new(config: BenchConfig) -> Bench {
+        Bench {
+            config: config,
+            client: Client::default(),
+            started: Instant::now(),
+            sent: 0,
+            done: 0,
+            errors: 0,
+            writes: Vec::new(),
+            reads: Vec::new(),
+            cpu_start: None,
+        }
+    }
+
+    fn cpu_millis(&self) -> impl Future<Item = Option<u64>, Error = ()> {
+        self.client
+            .get(format!("http://{}/admin/cpu", self.config.target))
+            .send()
+            .map_err(|_| ())
+            .and_then(|mut res| res.json::<CpuUsage>().map_err(|_| ()))
+            .map(|usage| usage.cpu_millis)
+    }
+
+    fn next(&mut self, ctx: &mut Context<Self>) {
+        if self.sent >= self.config.requests {
+            return;
+        }
+
+        self.sent += 1;
+
+        let read = (self.sent % 100) < (self.config.read_ratio * 100.0) as usize;
+        let request = if read {
+            self.client
+                .get(format!("http://{}/node/{}", self.config.target, self.sent))
+                .send()
+        } else {
+            // an empty application command is replicated and applied without touching any state
+            let request = self.client.put(format!("http://{}/admin/propose", self.config.admin));
+            let request = match self.config.admin_token {
+                Some(ref token) => request.header(header::AUTHORIZATION, format!("Bearer {}", token)),
+                None => request,
+            };
+            request.send_json(&MemoryStorageData::App(Vec::new()))
+        };
+
+        let sent_at = Instant::now();
+
+        fut::wrap_future::<_, Self>(request)
+            .then(move |res, act, ctx| {
+                let elapsed = Instant::now().duration_since(sent_at);
+
+                match res {
+                    Ok(ref res) if res.status().is_success() => {
+                        if read {
+                            act.reads.push(elapsed);
+                        } else {
+                            act.writes.push(elapsed);
+                        }
+                    }
+                    _ => act.errors += 1,
+                }
+
+                act.done += 1;
+
+                if act.done == act.config.requests {
+                    act.finish(ctx);
+                } else {
+                    act.next(ctx);
+                }
+
+                fut::ok(())
+            })
+            .spawn(ctx);
+    }
+
+    fn finish(&mut self, ctx: &mut Context<Self>) {
+        let elapsed = Instant::now().duration_since(self.started);
+
+        fut::wrap_future::<_, Self>(self.cpu_millis())
+            .then(move |cpu_end, act, _| {
+                let secs = elapsed.as_millis() as f64 / 1000.0;
+                let completed = act.writes.len() + act.reads.len();
+
+                println!("[bench] {} requests in {:.2}s, {} errors", act.done, secs, act.errors);
+                println!("[bench] throughput {:.1} req/s", completed as f64 / secs);
+                report("writes", &mut act.writes);
+                report("reads", &mut act.reads);
+
+                match (act.cpu_start, cpu_end.ok().and_then(|cpu| cpu)) {
+                    (Some(start), Some(end)) => println!(
+                        "[bench] target cpu {:.1}% ({}ms)",
+                        (end - start) as f64 / elapsed.as_millis() as f64 * 100.0,
+                        end - start
+                    ),
+                    _ => println!("[bench] target cpu unavailable"),
+                }
+
+                System::current().stop();
+                fut::ok(())
+            })
+            .wait(ctx);
+    }
+}
+
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    sorted[((sorted.len() - 1) as f64 * p) as usize]
+}
+
+fn report(name: &str, latencies: &mut Vec<Duration>) {
+    if latencies.is_empty() {
+        return;
+    }
+
+    latencies.sort();
+
+    println!(
+        "[bench] {:<6} {:>6} ok  p50 {:?}  p90 {:?}  p99 {:?}  max {:?}",
+        name,
+        latencies.len(),
+        percentile(latencies, 0.5),
+        percentile(latencies, 0.9),
+        percentile(latencies, 0.99),
+        latencies[latencies.len() - 1]
+    );
+}
+
+impl Actor for Bench {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        println!(
+            "[bench] {} requests against {} with concurrency {}",
+            self.config.requests, self.config.target, self.config.concurrency
+        );
+
+        fut::wrap_future::<_, Self>(self.cpu_millis())
+            .then(|cpu, act, ctx| {
+                act.cpu_start = cpu.ok().and_then(|cpu| cpu);
+                act.started = Instant::now();
+
+                for _ in 0..act.config.concurrency {
+                    act.next(ctx);
+                }
+
+                fut::ok(())
+            })
+            .wait(ctx);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles_pick_from_the_sorted_latencies() {
+        let latencies = (1..=100).map(Duration::from_millis).collect::<Vec<_>>();
+        assert_eq!(percentile(&latencies, 0.5), Duration::from_millis(50));
+        assert_eq!(percentile(&latencies, 0.99), Duration::from_millis(99));
+        assert_eq!(percentile(&latencies, 1.0), Duration::from_millis(100));
+        assert_eq!(percentile(&latencies[..1], 0.9), Duration::from_millis(1));
+    }
+}
@@ -0,0 +1,246 @@
+//! Time source of the node.
+//!
+//! Timeouts, leases, rates and ages read the monotonic `now`, timestamps kept as metadata,
+//! e.g. in events, the data dir lock or entry timings, read the wall clock with `wall_millis`.
+//! The source is process wide and set with `set_clock` before the node starts: tests install
+//! a `ManualClock` to drive time themselves, and `clock = "Boottime"` keeps timeouts counting
+//! while the host is suspended.
+//!
+//! Timers fire on the clock of the runtime they run on. The system of a node is built on
+//! `timer_clock`, which reads the installed clock, and timers are armed with `delay`, so they
+//! follow the source. Arbiters actix starts keep the operating system clock, actors timed by
+//! the source are started in `arbiter`, which is the system's own one under a virtual clock.
+
+use actix::Arbiter;
+use once_cell::sync::Lazy;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::timer::Delay;
+
+/// A time source.
+pub trait Clock: Send + Sync {
+    /// Monotonic time, for timeouts and leases.
+    fn now(&self) -> Instant;
+
+    /// Wall clock time in unix milliseconds, for metadata. It may jump, never time a lease
+    /// with it.
+    fn wall_millis(&self) -> u64;
+
+    /// Whether the clock only moves when told to, rather than with the time of the host.
+    fn is_virtual(&self) -> bool {
+        false
+    }
+}
+
+/// The clocks of the operating system, `Instant` and `SystemTime`.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn wall_millis(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() * 1000 + d.subsec_millis() as u64)
+            .unwrap_or(0)
+    }
+}
+
+/// Monotonic time counting the time the host was suspended, `CLOCK_BOOTTIME` on Linux, so a
+/// lease held across a suspend is known to have expired once the host resumes.
+#[cfg(target_os = "linux")]
+pub struct BootTimeClock {
+    base: Instant,
+    base_boottime: Duration,
+}
+
+#[cfg(target_os = "linux")]
+impl BootTimeClock {
+    pub fn new() -> BootTimeClock {
+        BootTimeClock {
+            base: Instant::now(),
+            base_boottime: boottime(),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Clock for BootTimeClock {
+    fn now(&self) -> Instant {
+        self.base + (boottime() - self.base_boottime)
+    }
+
+    fn wall_millis(&self) -> u64 {
+        SystemClock.wall_millis()
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn boottime() -> Duration {
+    let mut ts = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    // CLOCK_BOOTTIME can't fail on the kernels raftor runs on
+    unsafe {
+        libc::clock_gettime(libc::CLOCK_BOOTTIME, &mut ts);
+    }
+    Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32)
+}
+
+/// A clock only moving when told to, for tests running in virtual time. Advance it from the
+/// system thread, e.g. in a future spawned on it, so its timers see the new time on their next
+/// turn rather than once the runtime wakes up on its own.
+pub struct ManualClock {
+    base: Instant,
+    elapsed: Mutex<Duration>,
+    wall_base: AtomicU64,
+}
+
+impl ManualClock {
+    /// A clock starting at the given wall clock time in unix milliseconds.
+    pub fn new(wall_millis: u64) -> ManualClock {
+        ManualClock {
+            base: Instant::now(),
+            elapsed: Mutex::new(Duration::from_millis(0)),
+            wall_base: AtomicU64::new(wall_millis),
+        }
+    }
+
+    /// Move both clocks forward.
+    pub fn advance(&self, by: Duration) {
+        *self.elapsed.lock().unwrap() += by;
+    }
+
+    /// Jump the wall clock alone, as NTP would, leaving the monotonic time alone.
+    pub fn set_wall(&self, wall_millis: u64) {
+        let elapsed = self.elapsed.lock().unwrap().as_millis() as u64;
+        self.wall_base.store(wall_millis.saturating_sub(elapsed), Ordering::Relaxed);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.base + *self.elapsed.lock().unwrap()
+    }
+
+    fn wall_millis(&self) -> u64 {
+        self.wall_base.load(Ordering::Relaxed) + self.elapsed.lock().unwrap().as_millis() as u64
+    }
+
+    fn is_virtual(&self) -> bool {
+        true
+    }
+}
+
+/// The installed clock.
+static CLOCK: Lazy<RwLock<Arc<dyn Clock>>> = Lazy::new(|| RwLock::new(Arc::new(SystemClock)));
+
+/// Read time from the given clock from now on, keep the `Arc` to drive a `ManualClock`.
+pub fn set_clock(clock: Arc<dyn Clock>) {
+    *CLOCK.write().unwrap_or_else(|err| err.into_inner()) = clock;
+}
+
+fn with_clock<T>(read: impl FnOnce(&dyn Clock) -> T) -> T {
+    read(&**CLOCK.read().unwrap_or_else(|err| err.into_inner()))
+}
+
+/// Monotonic time of the installed clock.
+pub fn now() -> Instant {
+    with_clock(|clock| clock.now())
+}
+
+/// Time since `since`, an instant of the installed clock.
+pub fn elapsed(since: Instant) -> Duration {
+    now().saturating_duration_since(since)
+}
+
+/// Wall clock time of the installed clock in unix milliseconds.
+pub fn wall_millis() -> u64 {
+    with_clock(|clock| clock.wall_millis())
+}
+
+/// Whether the installed clock is virtual, see `Clock::is_virtual`.
+pub fn is_virtual() -> bool {
+    with_clock(|clock| clock.is_virtual())
+}
+
+/// Reads the installed clock for the timer of a runtime.
+struct TimerNow;
+
+impl tokio::clock::Now for TimerNow {
+    fn now(&self) -> Instant {
+        now()
+    }
+}
+
+/// Clock of a runtime whose timers follow the installed clock.
+pub fn timer_clock() -> tokio::clock::Clock {
+    tokio::clock::Clock::new_with_now(TimerNow)
+}
+
+/// A timer firing `after` from now on the clock of the current runtime, which is the installed
+/// clock on the system of a node. `Instant::now()` would arm it on the operating system clock.
+pub fn delay(after: Duration) -> Delay {
+    Delay::new(tokio::clock::now() + after)
+}
+
+/// Arbiter to start actors timed by the installed clock in: a new one, or the current one under
+/// a virtual clock as new arbiters keep the operating system clock.
+pub fn arbiter() -> Arbiter {
+    if is_virtual() {
+        Arbiter::current()
+    } else {
+        Arbiter::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future;
+
+    #[test]
+    fn manual_clocks_move_when_told_to() {
+        let clock = ManualClock::new(1_000);
+        let start = clock.now();
+
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(clock.now() - start, Duration::from_secs(2));
+        assert_eq!(clock.wall_millis(), 3_000);
+
+        // an NTP jump leaves the monotonic time alone
+        clock.set_wall(10_000);
+        assert_eq!(clock.now() - start, Duration::from_secs(2));
+        assert_eq!(clock.wall_millis(), 10_000);
+    }
+
+    #[test]
+    fn delays_fire_in_virtual_time() {
+        let clock = Arc::new(ManualClock::new(0));
+        let mut runtime = tokio::runtime::current_thread::Builder::new()
+            .clock(tokio::clock::Clock::new_with_now(ManualNow(clock.clone())))
+            .build()
+            .unwrap();
+
+        // an hour long timer fires as soon as the clock moved past it
+        let started = Instant::now();
+        runtime
+            .block_on(future::lazy(move || {
+                let timer = delay(Duration::from_secs(3600));
+                clock.advance(Duration::from_secs(3601));
+                timer
+            }))
+            .unwrap();
+        assert!(started.elapsed() < Duration::from_secs(60));
+    }
+
+    /// `TimerNow` reads the process wide clock, which other tests share
+    struct ManualNow(Arc<ManualClock>);
+
+    impl tokio::clock::Now for ManualNow {
+        fn now(&self) -> Instant {
+            self.0.now()
+        }
+    }
+}
@@ -1,4 +1,10 @@
+use actix_raft::NodeId;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use crate::mailbox::Mailbox;
+use crate::raft::compaction::CompactionSchedule;
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum NetworkType {
@@ -11,19 +17,1733 @@ pub struct NodeInfo {
     pub cluster_addr: String,
     pub app_addr: String,
     pub public_addr: String,
+    /// Operator assigned id, by default ids are hashed from `cluster_addr`.
+    #[serde(default)]
+    pub id: Option<NodeId>,
+    /// Free form labels (e.g. `zone`) used by placement rules.
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    /// Metadata of the cluster the node belongs to, as far as it knows. Joins announcing
+    /// other metadata than the cluster's are refused.
+    #[serde(default)]
+    pub cluster: Option<ClusterMetadata>,
+}
+
+/// Name and labels of the cluster, replicated through the raft log and edited through the
+/// admin API.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct ClusterMetadata {
+    pub name: String,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+}
+
+impl ClusterMetadata {
+    /// Why the metadata may not be set, `None` when it is valid.
+    pub fn invalid(&self) -> Option<String> {
+        if self.name.trim().is_empty() {
+            return Some("the cluster name is empty".to_owned());
+        }
+        if self.labels.keys().any(|name| name.trim().is_empty()) {
+            return Some("a label has an empty name".to_owned());
+        }
+        None
+    }
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub enum JoinStrategy {
     Static,
     Dynamic,
 }
 
+/// What a node restarting from its data does when the membership it persisted doesn't list
+/// it, e.g. because it was removed while it was down.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub enum RemovedMemberPolicy {
+    /// Don't start raft and exit, leaving the data dir to the operator.
+    Halt,
+    /// Wipe the log and snapshots of the data dir and join the cluster again as a non-voter.
+    Rejoin,
+    /// Stay up without raft until an operator sends `POST /admin/rejoin` to the admin listener.
+    Wait,
+}
+
+impl Default for RemovedMemberPolicy {
+    fn default() -> Self {
+        RemovedMemberPolicy::Halt
+    }
+}
+
+/// Monotonic clock timeouts and leases are measured with, see `clock`.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub enum ClockSource {
+    /// The clock of `Instant`, which stops while the host is suspended on Linux.
+    Monotonic,
+    /// `CLOCK_BOOTTIME`, which keeps counting while the host is suspended, Linux only.
+    Boottime,
+}
+
+impl Default for ClockSource {
+    fn default() -> Self {
+        ClockSource::Monotonic
+    }
+}
+
+/// Which of the connected peers form the cluster when it bootstraps.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub enum BootstrapPolicy {
+    /// Only the peers listed under `[[nodes]]`, other peers are left out.
+    Strict,
+    /// Every peer connected when the cluster forms.
+    Open,
+    /// Only the listed peers, waiting until a majority of them connected.
+    QuorumOfConfigured,
+}
+
+impl Default for BootstrapPolicy {
+    fn default() -> Self {
+        BootstrapPolicy::Open
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub enum StorageFaultPolicy {
+    /// Stop accepting client proposals on this node.
+    RejectProposals,
+    /// Stop accepting client proposals and cut this node off from raft, letting a healthy
+    /// node take over leadership.
+    StepDown,
+}
+
+/// What a node does with a snapshot written by a newer state machine version.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub enum SnapshotVersionPolicy {
+    /// Refuse to install it and log a critical alert.
+    Reject,
+    /// Refuse to install it but keep it under `staged/` in the snapshot dir for after an upgrade.
+    Stage,
+    /// Install it through the snapshot migrator, rejecting it when none is set.
+    Migrate,
+}
+
+impl Default for SnapshotVersionPolicy {
+    fn default() -> Self {
+        SnapshotVersionPolicy::Reject
+    }
+}
+
+/// How a node picks its own id.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "strategy")]
+pub enum NodeIdStrategy {
+    /// Hash of the cluster address, or the `id` assigned in the node list.
+    Hash,
+    /// Random id generated on first start and kept in `path`, peers learn it when joining.
+    Persisted { path: String },
+    /// The `id` assigned in the node list, starting without one is an error.
+    Assigned,
+}
+
+impl Default for NodeIdStrategy {
+    fn default() -> Self {
+        NodeIdStrategy::Hash
+    }
+}
+
+/// What a node takes part in.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub enum NodeRole {
+    /// Raft member which also serves clients.
+    Coordinator,
+    /// Raft member without a client listener, it doesn't own any client sessions.
+    Data,
+    /// Serves clients and forwards their proposals to the cluster without being a raft member.
+    Gateway,
+}
+
+impl NodeRole {
+    pub fn is_member(&self) -> bool {
+        *self != NodeRole::Gateway
+    }
+
+    pub fn serves_clients(&self) -> bool {
+        *self != NodeRole::Data
+    }
+}
+
+impl Default for NodeRole {
+    fn default() -> Self {
+        NodeRole::Coordinator
+    }
+}
+
+impl Default for StorageFaultPolicy {
+    fn default() -> Self {
+        StorageFaultPolicy::RejectProposals
+    }
+}
+
 pub type NodeList = Vec<NodeInfo>;
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct ConfigSchema {
     pub discovery_host: String,
     pub join_strategy: JoinStrategy,
     pub nodes: NodeList,
+    /// Which connected peers form the cluster when it bootstraps.
+    #[serde(default)]
+    pub bootstrap_policy: BootstrapPolicy,
+    /// How long to wait for peers to connect before forming the cluster, in milliseconds.
+    #[serde(default = "default_bootstrap_delay_ms")]
+    pub bootstrap_delay_ms: u64,
+    /// Wait for `PUT /cluster/bootstrap` before bootstrapping a new cluster, nodes restarting
+    /// or joining an existing cluster don't wait.
+    #[serde(default)]
+    pub manual_bootstrap: bool,
+    /// What a node restarting from its data does once it finds it isn't a member anymore.
+    #[serde(default)]
+    pub removed_member: RemovedMemberPolicy,
+    /// Start raft right away with this node as its only member, without waiting for peers.
+    /// The cluster grows later through the membership API.
+    #[serde(default)]
+    pub single_node: bool,
+    #[serde(default)]
+    pub raft_timings: RaftTimings,
+    /// Role of this node, usually set per node through `APP_ROLE`.
+    #[serde(default)]
+    pub role: NodeRole,
+    /// Max number of client proposals the leader keeps in flight at once.
+    #[serde(default = "default_max_pending_proposals")]
+    pub max_pending_proposals: usize,
+    /// Max number of proposals a client may have waiting for admission on the leader.
+    #[serde(default = "default_max_queued_proposals")]
+    pub max_queued_proposals: usize,
+    /// Max number of clients with proposals waiting for admission on the leader.
+    #[serde(default = "default_max_queued_clients")]
+    pub max_queued_clients: usize,
+    #[serde(default)]
+    pub telemetry: Option<TelemetryConfig>,
+    /// What to do when the storage can no longer persist data (disk full, IO errors).
+    #[serde(default)]
+    pub storage_fault_policy: StorageFaultPolicy,
+    /// Sign, and optionally encrypt, snapshot chunks sent to peers.
+    #[serde(default)]
+    pub snapshot_transfer: Option<SnapshotTransferConfig>,
+    /// What to do with snapshots written by a newer state machine version.
+    #[serde(default)]
+    pub snapshot_version_policy: SnapshotVersionPolicy,
+    /// Keep snapshots, and with the `persistent` feature the raft log and hard state, under
+    /// this directory so a restarted node recovers them.
+    #[serde(default)]
+    pub data_dir: Option<String>,
+    /// Voters acknowledging log writes before they reach the disk, see `CommitQuorum`.
+    #[serde(default)]
+    pub commit_quorum: Option<CommitQuorum>,
+    /// Superseded snapshots kept on disk besides the current one.
+    #[serde(default = "default_snapshot_retention")]
+    pub snapshot_retention: usize,
+    /// Backups each node keeps under its snapshot dir, the oldest are removed once a new one
+    /// is dumped.
+    #[serde(default = "default_backup_retention")]
+    pub backup_retention: usize,
+    /// Entries applied by a projection between checkpoints of its progress.
+    #[serde(default = "default_projection_checkpoint_entries")]
+    pub projection_checkpoint_entries: u64,
+    /// Bytes of snapshot data sent to a peer per `InstallSnapshotRequest`.
+    #[serde(default = "default_snapshot_chunk_size")]
+    pub snapshot_chunk_size: u64,
+    /// How long heartbeats of raft groups to the same peer are held to be sent as one frame,
+    /// 0 sends each heartbeat on its own.
+    #[serde(default = "default_group_heartbeat_window_ms")]
+    pub group_heartbeat_window_ms: u64,
+    /// Move group replicas and leadership off overloaded nodes, see `GroupRebalance`.
+    #[serde(default)]
+    pub group_rebalance: Option<GroupRebalance>,
+    /// Schedule log compaction instead of compacting whenever the log grows.
+    #[serde(default)]
+    pub compaction: Option<CompactionConfig>,
+    /// Snapshot when followers lag too far behind the leader, see `SnapshotTrigger`.
+    #[serde(default)]
+    pub snapshot_trigger: Option<SnapshotTrigger>,
+    /// Followers far behind ask the leader to catch them up, see `CatchUpConfig`.
+    #[serde(default)]
+    pub catch_up: Option<CatchUpConfig>,
+    /// Keep at most this many recent log entries in memory, spilling older ones to disk.
+    #[serde(default)]
+    pub log_cache_entries: Option<usize>,
+    /// How a node starting from a persisted log reads it back, see `LogReplayConfig`.
+    #[serde(default)]
+    pub log_replay: LogReplayConfig,
+    /// Append per-entry commit/apply timings as JSON lines to this file.
+    #[serde(default)]
+    pub entry_timings_file: Option<String>,
+    /// Inbound rate limit applied to every peer session.
+    #[serde(default)]
+    pub peer_rate_limit: Option<PeerRateLimit>,
+    /// Refuse sessions with peers running a version out of this range, fencing off builds
+    /// deployed by mistake.
+    #[serde(default)]
+    pub peer_versions: Option<PeerVersions>,
+    /// Outbound bandwidth of the node across all its peers in bytes per second, changed at
+    /// runtime through `/admin/egress-limit` of the admin listener.
+    #[serde(default)]
+    pub egress_limit_bytes_per_sec: Option<u64>,
+    /// Smaller batches, compression and slower snapshots on links to low-bandwidth peers.
+    #[serde(default)]
+    pub constrained_links: Option<ConstrainedLinks>,
+    /// Zstd dictionary trained from small entries for wire and log store compression, needs
+    /// the `dictionary` feature. Configure it alike on every node of the cluster.
+    #[serde(default)]
+    pub compression_dictionary: Option<CompressionDictionary>,
+    /// Tie-breaker for two node clusters, only used with the `arbitration` feature.
+    #[serde(default)]
+    pub arbitration: Option<ArbitrationConfig>,
+    /// Hedge replica reads by asking a second replica when the first is slow.
+    #[serde(default)]
+    pub hedged_reads: Option<HedgeConfig>,
+    /// Rules raft membership changes are validated against.
+    #[serde(default)]
+    pub placement: Vec<PlacementRule>,
+    /// Announce this cluster to a federation meta-cluster.
+    #[serde(default)]
+    pub federation: Option<FederationConfig>,
+    /// Announcements this node takes when it's part of a meta-cluster.
+    #[serde(default)]
+    pub federation_registry: FederationRegistryConfig,
+    /// Serve `POST /propose` and `GET /read` for services which don't speak the wire protocol.
+    #[serde(default)]
+    pub http_gateway: bool,
+    /// Paging of the query answers of the gateway.
+    #[serde(default)]
+    pub read_streaming: ReadStreaming,
+    /// Mailbox capacities of the network, peer and raft actors, see `mailbox`.
+    #[serde(default)]
+    pub mailboxes: MailboxCapacities,
+    #[serde(default)]
+    pub node_id: NodeIdStrategy,
+    /// Serve a signed cluster descriptor at `GET /cluster/descriptor` for client bootstrap.
+    #[serde(default)]
+    pub descriptor: Option<DescriptorConfig>,
+    /// Append term changes and leadership transitions as JSON lines to this file.
+    #[serde(default)]
+    pub leadership_history_file: Option<String>,
+    /// Step down as leader after failing to reach a quorum for this many election timeouts.
+    #[serde(default)]
+    pub leader_check_timeouts: Option<u64>,
+    /// Peer state of nodes which left the raft membership is reported stale for this long,
+    /// then pruned once they're disconnected.
+    #[serde(default = "default_metrics_retention_secs")]
+    pub metrics_retention_secs: u64,
+    /// Abort a membership change still syncing non-voters when it didn't complete within this
+    /// many seconds, e.g. because a new node never caught up.
+    #[serde(default)]
+    pub membership_change_timeout_secs: Option<u64>,
+    /// How long a membership change prepared through `PrepareMembershipChange` can be
+    /// confirmed.
+    #[serde(default = "default_membership_confirm_timeout_ms")]
+    pub membership_confirm_timeout_ms: u64,
+    /// Leadership priority of nodes by cluster address, leadership lands on the caught up
+    /// voter with the highest priority. Unlisted nodes have priority 0.
+    #[serde(default)]
+    pub leadership_priority: BTreeMap<String, u32>,
+    /// Capture diagnostics when writes stop being applied.
+    #[serde(default)]
+    pub write_stall: Option<WriteStallConfig>,
+    /// Hold members which just joined as learners, cordoned, until their replication lag
+    /// stayed under an SLO, see `LearnerPromotionConfig`.
+    #[serde(default)]
+    pub learner_promotion: Option<LearnerPromotionConfig>,
+    /// Count the failure domains holding the commits of the leader, see
+    /// `FailureDomainConfig`.
+    #[serde(default)]
+    pub failure_domains: Option<FailureDomainConfig>,
+    /// Purge the debris of the state machine once it's older than a retention window, see
+    /// `TombstoneRetentionConfig`.
+    #[serde(default)]
+    pub tombstone_retention: Option<TombstoneRetentionConfig>,
+    /// Flush the log store and write a crash report to `data_dir` when the node panics. Turn it
+    /// off when the embedding application installs its own panic hook.
+    #[serde(default = "default_crash_reports")]
+    pub crash_reports: bool,
+    /// Count and log the messages handled by the network actors from the start, needs the
+    /// `audit` feature, see `audit`.
+    #[serde(default)]
+    pub audit_messages: bool,
+    /// Publish applied entries to Kafka topics or NATS subjects.
+    #[serde(default)]
+    pub log_sinks: Vec<LogSinkConfig>,
+    /// Serve the admin API (cluster status, membership changes, leadership transfer) on this
+    /// address, keep it off networks clients can reach.
+    #[serde(default)]
+    pub admin_addr: Option<String>,
+    /// Bearer token the admin API requires in the `Authorization` header when set.
+    #[serde(default)]
+    pub admin_token: Option<String>,
+    /// Bearer tokens of the clients of the HTTP gateway and the JSON protocol, mapped to the
+    /// client name their proposals are admitted, validated and hooked as. Without any, the
+    /// clients of the gateway are all `gateway` and those of the JSON protocol `json`.
+    #[serde(default)]
+    pub client_tokens: BTreeMap<String, String>,
+    /// Directory state dumps are exported to, the admin API only takes file names under it.
+    #[serde(default = "default_dump_dir")]
+    pub dump_dir: String,
+    /// Serve the JSON-over-TCP client protocol on this address, see `json_protocol`.
+    #[serde(default)]
+    pub json_client_addr: Option<String>,
+    /// How long resolved peer host names are cached, the system resolver doesn't tell record
+    /// TTLs. Addresses are also resolved again whenever connecting to them fails.
+    #[serde(default = "default_dns_cache_ttl_secs")]
+    pub dns_cache_ttl_secs: u64,
+    /// Clock timeouts and leases are measured with.
+    #[serde(default)]
+    pub clock: ClockSource,
+    /// Bounds on resolving and connecting to a peer, a dial exceeding them is abandoned and
+    /// retried after the reconnect backoff.
+    #[serde(default)]
+    pub dial_timeouts: DialTimeouts,
+    /// Limits on nodes joining through `/cluster/join`.
+    #[serde(default)]
+    pub join_admission: JoinAdmission,
+    /// Caps on the bytes held by in-memory structures.
+    #[serde(default)]
+    pub memory_limits: MemoryLimits,
+    /// Encrypt peer connections, needs the `tls` feature.
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+    /// Take client and admin connections on a listener of their own, e.g. on a public
+    /// interface while peers connect to the cluster address on a private one.
+    #[serde(default)]
+    pub client_listener: Option<ClientListener>,
+    /// Tune `max_pending_proposals` from the observed commit latency.
+    #[serde(default)]
+    pub adaptive_window: Option<AdaptiveWindow>,
+    /// Find peers at runtime in addition to those listed under `[[nodes]]`.
+    #[serde(default)]
+    pub discovery: Option<DiscoveryConfig>,
+    /// Cluster metadata this node announces when joining, until it learns the replicated one.
+    #[serde(default)]
+    pub cluster: Option<ClusterMetadata>,
+    /// Delay the start of raft so nodes started together don't stand for election at once.
+    #[serde(default)]
+    pub startup_jitter: Option<StartupJitter>,
+    /// Hold the election of a follower restarting shortly after it last heard from its
+    /// leader, needs `data_dir`.
+    #[serde(default)]
+    pub fast_restart: Option<FastRestart>,
+    /// Lease of the process running the node on `data_dir`, and how a warm standby started
+    /// with `--standby` waits to take it over.
+    #[serde(default)]
+    pub warm_standby: WarmStandby,
+    /// Report readiness, status and watchdog pings to systemd and stop cleanly on SIGTERM,
+    /// see `service`.
+    #[serde(default)]
+    pub service: Option<ServiceIntegration>,
+    /// Environment checks printed when the node starts, see `preflight`.
+    #[serde(default)]
+    pub startup_checks: StartupChecks,
+}
+
+impl ConfigSchema {
+    /// Create a configuration with default settings for the given nodes.
+    pub fn new(discovery_host: String, nodes: NodeList) -> ConfigSchema {
+        ConfigSchema {
+            discovery_host: discovery_host,
+            join_strategy: JoinStrategy::Static,
+            nodes: nodes,
+            bootstrap_policy: BootstrapPolicy::default(),
+            bootstrap_delay_ms: default_bootstrap_delay_ms(),
+            manual_bootstrap: false,
+            removed_member: RemovedMemberPolicy::default(),
+            single_node: false,
+            raft_timings: RaftTimings::default(),
+            role: NodeRole::default(),
+            max_pending_proposals: default_max_pending_proposals(),
+            max_queued_proposals: default_max_queued_proposals(),
+            max_queued_clients: default_max_queued_clients(),
+            telemetry: None,
+            storage_fault_policy: StorageFaultPolicy::default(),
+            snapshot_transfer: None,
+            snapshot_version_policy: SnapshotVersionPolicy::default(),
+            data_dir: None,
+            commit_quorum: None,
+            snapshot_retention: default_snapshot_retention(),
+            backup_retention: default_backup_retention(),
+            projection_checkpoint_entries: default_projection_checkpoint_entries(),
+            snapshot_chunk_size: default_snapshot_chunk_size(),
+            group_heartbeat_window_ms: default_group_heartbeat_window_ms(),
+            group_rebalance: None,
+            compaction: None,
+            snapshot_trigger: None,
+            catch_up: None,
+            log_cache_entries: None,
+            log_replay: LogReplayConfig::default(),
+            entry_timings_file: None,
+            peer_rate_limit: None,
+            peer_versions: None,
+            egress_limit_bytes_per_sec: None,
+            constrained_links: None,
+            compression_dictionary: None,
+            arbitration: None,
+            hedged_reads: None,
+            placement: Vec::new(),
+            federation: None,
+            federation_registry: FederationRegistryConfig::default(),
+            http_gateway: false,
+            read_streaming: ReadStreaming::default(),
+            mailboxes: MailboxCapacities::default(),
+            node_id: NodeIdStrategy::default(),
+            descriptor: None,
+            leadership_history_file: None,
+            leader_check_timeouts: None,
+            metrics_retention_secs: default_metrics_retention_secs(),
+            membership_change_timeout_secs: None,
+            membership_confirm_timeout_ms: default_membership_confirm_timeout_ms(),
+            leadership_priority: BTreeMap::new(),
+            write_stall: None,
+            learner_promotion: None,
+            failure_domains: None,
+            tombstone_retention: None,
+            crash_reports: default_crash_reports(),
+            audit_messages: false,
+            log_sinks: Vec::new(),
+            admin_addr: None,
+            admin_token: None,
+            client_tokens: BTreeMap::new(),
+            dump_dir: default_dump_dir(),
+            json_client_addr: None,
+            dns_cache_ttl_secs: default_dns_cache_ttl_secs(),
+            clock: ClockSource::default(),
+            dial_timeouts: DialTimeouts::default(),
+            join_admission: JoinAdmission::default(),
+            memory_limits: MemoryLimits::default(),
+            tls: None,
+            client_listener: None,
+            adaptive_window: None,
+            discovery: None,
+            cluster: None,
+            startup_jitter: None,
+            fast_restart: None,
+            warm_standby: WarmStandby::default(),
+            service: None,
+            startup_checks: StartupChecks::default(),
+        }
+    }
+
+    /// Load `Config.toml` (or another format of `Config`) from the working directory,
+    /// overridden by `APP_` prefixed environment variables.
+    pub fn load() -> Result<ConfigSchema, ::config::ConfigError> {
+        let mut config = ::config::Config::default();
+
+        config
+            .merge(::config::File::with_name("Config"))?
+            .merge(::config::Environment::with_prefix("APP"))?;
+
+        let schema = config.try_into::<ConfigSchema>()?;
+        schema.validate().map_err(::config::ConfigError::Message)?;
+        Ok(schema)
+    }
+
+    /// Reject the values the node can't run with, which deserialize fine.
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(ref arbitration) = self.arbitration {
+            if arbitration.lease_secs == 0 {
+                return Err("arbitration.lease_secs must be at least 1".to_owned());
+            }
+        }
+        if self.federation.as_ref().map_or(false, |federation| federation.announce_secs == 0) {
+            return Err("federation.announce_secs must be at least 1".to_owned());
+        }
+        if self.federation_registry.record_ttl_secs == 0 {
+            return Err("federation_registry.record_ttl_secs must be at least 1".to_owned());
+        }
+        if let Some(ref compaction) = self.compaction {
+            if compaction.logs_since_last == 0 {
+                return Err("compaction.logs_since_last must be at least 1".to_owned());
+            }
+            CompactionSchedule::from_config(compaction).map_err(|err| format!("compaction: {}", err))?;
+        }
+        if self.max_pending_proposals == 0 {
+            return Err("max_pending_proposals must be at least 1".to_owned());
+        }
+        if self.max_queued_proposals == 0 || self.max_queued_clients == 0 {
+            return Err("max_queued_proposals and max_queued_clients must be at least 1".to_owned());
+        }
+        if self.tombstone_retention.as_ref().map_or(false, |retention| retention.interval_ms == 0) {
+            return Err("tombstone_retention.interval_ms must be at least 1".to_owned());
+        }
+        if self.log_replay.batch_entries == 0 || self.log_replay.threads == 0 {
+            return Err("log_replay.batch_entries and threads must be at least 1".to_owned());
+        }
+        if self.warm_standby.fence_check_ms == 0 || self.warm_standby.poll_ms == 0 {
+            return Err("warm_standby.fence_check_ms and poll_ms must be at least 1".to_owned());
+        }
+        if let Some(ref rebalance) = self.group_rebalance {
+            if rebalance.interval_secs == 0 || rebalance.traffic_unit == 0 {
+                return Err("group_rebalance.interval_secs and traffic_unit must be at least 1".to_owned());
+            }
+            if rebalance.replica_skew < 1.0 || rebalance.leader_skew == 0 {
+                return Err("group_rebalance.replica_skew and leader_skew must be at least 1".to_owned());
+            }
+        }
+        let client_tls = self.client_listener.as_ref().and_then(|listener| listener.tls.as_ref());
+        if self.tls.iter().chain(client_tls).any(|tls| tls.handshake_timeout_ms == 0) {
+            return Err("tls.handshake_timeout_ms must be at least 1".to_owned());
+        }
+        if self.tls.iter().chain(client_tls).any(|tls| tls.reload_check_secs == Some(0)) {
+            return Err("tls.reload_check_secs must be at least 1".to_owned());
+        }
+        if client_tls.map_or(false, |tls| !tls.peers.is_empty()) {
+            return Err("client_listener.tls doesn't take peer overrides".to_owned());
+        }
+        for (addr, peer) in self.tls.iter().flat_map(|tls| tls.peers.iter()) {
+            if peer.plaintext && (peer.ca.is_some() || peer.server_name.is_some()) {
+                return Err(format!("tls.peers.\"{}\" is plaintext, ca and server_name don't apply", addr));
+            }
+            if (peer.plaintext || peer.ca.is_some()) && addr.parse::<std::net::SocketAddr>().is_err() {
+                return Err(format!("tls.peers.\"{}\" must be an IP address and port to override ca or plaintext", addr));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn with_role(mut self, role: NodeRole) -> ConfigSchema {
+        self.role = role;
+        self
+    }
+
+    pub fn with_data_dir(mut self, data_dir: String) -> ConfigSchema {
+        self.data_dir = Some(data_dir);
+        self
+    }
+
+    pub fn with_bootstrap_delay(mut self, delay: Duration) -> ConfigSchema {
+        self.bootstrap_delay_ms = delay.as_millis() as u64;
+        self
+    }
+
+    pub fn with_manual_bootstrap(mut self, manual: bool) -> ConfigSchema {
+        self.manual_bootstrap = manual;
+        self
+    }
+
+    pub fn with_single_node(mut self, single_node: bool) -> ConfigSchema {
+        self.single_node = single_node;
+        self
+    }
+
+    pub fn with_raft_timings(mut self, timings: RaftTimings) -> ConfigSchema {
+        self.raft_timings = timings;
+        self
+    }
+
+    pub fn bootstrap_delay(&self) -> Duration {
+        Duration::from_millis(self.bootstrap_delay_ms)
+    }
+
+    /// Election timeouts of the node, pushed later the lower its leadership priority so the
+    /// nodes with the highest priority usually stand for election first. Nodes of the lowest
+    /// priority start where the range of the highest ends.
+    pub fn election_timeouts(&self, cluster_addr: &str) -> (u64, u64) {
+        let timings = &self.raft_timings;
+        let top = self.leadership_priority.values().cloned().max().unwrap_or(0);
+        let own = self.leadership_priority.get(cluster_addr).cloned().unwrap_or(0);
+
+        if top == 0 {
+            return (timings.election_timeout_min, timings.election_timeout_max);
+        }
+
+        let range = timings.election_timeout_max.saturating_sub(timings.election_timeout_min);
+        let delay = range * u64::from(top - own) / u64::from(top);
+        (timings.election_timeout_min + delay, timings.election_timeout_max + delay)
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct WriteStallConfig {
+    /// How long proposals may be pending without the applied index advancing.
+    #[serde(default = "default_stall_threshold_secs")]
+    pub threshold_secs: u64,
+    /// Directory the diagnostics bundles of stalls are written to.
+    #[serde(default = "default_stall_diagnostics_dir")]
+    pub diagnostics_dir: String,
+}
+
+fn default_stall_threshold_secs() -> u64 {
+    30
+}
+
+fn default_stall_diagnostics_dir() -> String {
+    "diagnostics".to_owned()
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct LearnerPromotionConfig {
+    /// Entries a learner may be behind the leader's log and still count as keeping up.
+    #[serde(default = "default_promotion_max_lag")]
+    pub max_lag: u64,
+    /// How long the lag must stay under `max_lag` before the learner is promoted.
+    #[serde(default = "default_promotion_sustain_ms")]
+    pub sustain_ms: u64,
+    /// How often the leader checks the lag of its learners.
+    #[serde(default = "default_promotion_interval_ms")]
+    pub interval_ms: u64,
+}
+
+fn default_promotion_max_lag() -> u64 {
+    100
+}
+
+fn default_promotion_sustain_ms() -> u64 {
+    30000
+}
+
+fn default_promotion_interval_ms() -> u64 {
+    1000
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct TombstoneRetentionConfig {
+    /// How long idempotency keys, idle client sessions, released locks and superseded
+    /// checkpoints are kept after their last write.
+    #[serde(default = "default_tombstone_retention_ms")]
+    pub retention_ms: u64,
+    /// How often the leader proposes a purge.
+    #[serde(default = "default_tombstone_interval_ms")]
+    pub interval_ms: u64,
+}
+
+fn default_tombstone_retention_ms() -> u64 {
+    24 * 60 * 60 * 1000
+}
+
+fn default_tombstone_interval_ms() -> u64 {
+    60 * 1000
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct FailureDomainConfig {
+    /// Node label whose values are the failure domains.
+    #[serde(default = "default_failure_domain_label")]
+    pub label: String,
+    /// Warn and record an alert when commits start being held in a single domain while the
+    /// voters span several.
+    #[serde(default = "default_warn_single_domain")]
+    pub warn_single_domain: bool,
+}
+
+fn default_failure_domain_label() -> String {
+    "zone".to_owned()
+}
+
+fn default_warn_single_domain() -> bool {
+    true
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct LogSinkConfig {
+    /// The checkpoint of the sink is replicated under this name, renaming a sink ships the
+    /// whole log again
+    pub name: String,
+    #[serde(flatten)]
+    pub backend: SinkBackend,
+    /// Max entries published at once
+    #[serde(default = "default_sink_batch_entries")]
+    pub batch_entries: usize,
+    /// How often the leader looks for entries to publish
+    #[serde(default = "default_sink_interval_ms")]
+    pub interval_ms: u64,
+}
+
+fn default_sink_batch_entries() -> usize {
+    256
+}
+
+fn default_sink_interval_ms() -> u64 {
+    500
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(tag = "backend", rename_all = "snake_case")]
+pub enum SinkBackend {
+    /// Needs the `kafka-sink` feature
+    Kafka { brokers: String, topic: String },
+    /// Needs the `nats-sink` feature
+    Nats { url: String, subject: String },
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct DiscoveryConfig {
+    #[serde(flatten)]
+    pub backend: DiscoveryBackend,
+    /// How often peers are looked up again
+    #[serde(default = "default_discovery_interval_secs")]
+    pub interval_secs: u64,
+    /// Have the leader add discovered nodes to the cluster, held to `[join_admission]`
+    #[serde(default)]
+    pub auto_admit: bool,
+}
+
+fn default_discovery_interval_secs() -> u64 {
+    30
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(tag = "backend", rename_all = "snake_case")]
+pub enum DiscoveryBackend {
+    /// Every address the host name resolves to is a node listening on these ports
+    Dns {
+        host: String,
+        cluster_port: u16,
+        app_port: u16,
+        public_port: u16,
+    },
+    /// Public addresses of nodes asked for the nodes they know
+    Gossip { seeds: Vec<String> },
+}
+
+/// Certificates of the peer connections of a node, all PEM encoded.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct TlsConfig {
+    /// Certificate chain of this node, presented to the peers it connects to and accepts
+    pub cert: String,
+    /// Private key of the certificate, PKCS#8 or RSA
+    pub key: String,
+    /// CA the certificates of the peers must be signed by
+    pub ca: String,
+    /// Name the certificates of all nodes carry, peer addresses are often IPs which can't be
+    /// verified
+    #[serde(default = "default_tls_server_name")]
+    pub server_name: String,
+    /// Only accept peers presenting a certificate signed by the CA
+    #[serde(default = "default_require_client_auth")]
+    pub require_client_auth: bool,
+    /// Verify the peers this node connects to by their node id: the certificate of node 42
+    /// must carry `node-42.<server_name>`, a certificate of another node is refused
+    #[serde(default = "default_verify_peer_id")]
+    pub verify_peer_id: bool,
+    /// Connections which didn't complete the handshake in time are dropped
+    #[serde(default = "default_tls_handshake_timeout_ms")]
+    pub handshake_timeout_ms: u64,
+    /// Check the certificate, key and CA files this often and reload them once they changed,
+    /// see `ReloadTls`
+    #[serde(default)]
+    pub reload_check_secs: Option<u64>,
+    /// Settings of single peers by cluster address overriding the ones above, e.g. for a node
+    /// whose certificate another CA signed or a legacy node without TLS. Only read for `[tls]`.
+    #[serde(default)]
+    pub peers: BTreeMap<String, PeerTlsOverride>,
+}
+
+/// TLS of one peer, see `TlsConfig::peers`. Connections accepted from the IP of a peer with a
+/// `ca` or `plaintext` override get the same treatment, so those peers are listed by IP.
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct PeerTlsOverride {
+    /// CA the certificate of the peer is signed by, instead of `ca`
+    #[serde(default)]
+    pub ca: Option<String>,
+    /// Name the certificate of the peer carries, instead of `node-<id>.<server_name>`
+    #[serde(default)]
+    pub server_name: Option<String>,
+    /// Talk to the peer without TLS, both on the connections to it and those accepted from it
+    #[serde(default)]
+    pub plaintext: bool,
+}
+
+/// Listener of the cluster network for client and admin connections, see
+/// `ConfigSchema::client_listener`. Peers connecting to it are dropped, and the cluster address
+/// only takes peers once it's set.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ClientListener {
+    /// Address to listen on, e.g. `0.0.0.0:8443`
+    pub bind: String,
+    /// TLS of the listener, independent from the peer TLS. Connections are plain without it
+    /// and speak the JSON client protocol, with it they pick a protocol by ALPN and
+    /// `require_client_auth` decides whether clients need a certificate signed by its CA.
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+    /// Relay `raftor-admin` connections to the admin API, only negotiable over TLS
+    #[serde(default)]
+    pub admin: bool,
+}
+
+fn default_tls_server_name() -> String {
+    "raftor".to_owned()
+}
+
+fn default_require_client_auth() -> bool {
+    true
+}
+
+fn default_verify_peer_id() -> bool {
+    true
+}
+
+fn default_tls_handshake_timeout_ms() -> u64 {
+    10_000
+}
+
+/// Raft election and heartbeat timings, in milliseconds.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct RaftTimings {
+    #[serde(default = "default_election_timeout_min")]
+    pub election_timeout_min: u64,
+    #[serde(default = "default_election_timeout_max")]
+    pub election_timeout_max: u64,
+    /// Must be well below the minimum election timeout
+    #[serde(default = "default_heartbeat_interval")]
+    pub heartbeat_interval: u64,
+}
+
+/// Delay before raft starts on a node, in milliseconds: a random part and a part growing with
+/// the position of the node in the node list, which staggers the first election timeouts.
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct StartupJitter {
+    /// Upper bound of the random delay
+    #[serde(default)]
+    pub max_jitter_ms: u64,
+    /// Delay added per node listed before this one
+    #[serde(default)]
+    pub stagger_ms: u64,
+}
+
+/// Followers record when they last heard from their leader, a follower restarting within an
+/// election timeout of it waits for that leader before starting raft.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct FastRestart {
+    /// Minimum time between two writes of the leader contact
+    #[serde(default = "default_contact_persist_ms")]
+    pub persist_interval_ms: u64,
+    /// Longest wait for the leader to reconnect before starting raft anyway
+    #[serde(default = "default_restart_hold_ms")]
+    pub max_hold_ms: u64,
+}
+
+/// Answers of `POST /query` on the gateway up to `inline_max_bytes` in a single page come
+/// back whole, larger ones are streamed a page of `page_items` items at a time.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ReadStreaming {
+    #[serde(default = "default_page_items")]
+    pub page_items: usize,
+    #[serde(default = "default_inline_max_bytes")]
+    pub inline_max_bytes: usize,
+}
+
+impl Default for ReadStreaming {
+    fn default() -> ReadStreaming {
+        ReadStreaming {
+            page_items: default_page_items(),
+            inline_max_bytes: default_inline_max_bytes(),
+        }
+    }
+}
+
+/// Messages each actor queues before senders waiting for an answer wait for room: the
+/// `Network` actors, the `Node` dialing each peer, the `NodeSession` of each accepted
+/// connection and the raft actors.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct MailboxCapacities {
+    #[serde(default = "default_network_mailbox")]
+    pub network: usize,
+    #[serde(default = "default_peer_mailbox")]
+    pub node: usize,
+    #[serde(default = "default_peer_mailbox")]
+    pub session: usize,
+    #[serde(default = "default_raft_mailbox")]
+    pub raft: usize,
+}
+
+impl MailboxCapacities {
+    pub fn of(&self, mailbox: Mailbox) -> usize {
+        match mailbox {
+            Mailbox::Network => self.network,
+            Mailbox::Node => self.node,
+            Mailbox::Session => self.session,
+            Mailbox::Raft => self.raft,
+        }
+    }
+}
+
+impl Default for MailboxCapacities {
+    fn default() -> MailboxCapacities {
+        MailboxCapacities {
+            network: default_network_mailbox(),
+            node: default_peer_mailbox(),
+            session: default_peer_mailbox(),
+            raft: default_raft_mailbox(),
+        }
+    }
+}
+
+/// How long dialing a peer may take, in milliseconds.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct DialTimeouts {
+    /// Resolving the host name of the peer
+    #[serde(default = "default_resolve_timeout_ms")]
+    pub resolve_timeout_ms: u64,
+    /// Establishing the connection, including the TLS handshake
+    #[serde(default = "default_connect_timeout_ms")]
+    pub connect_timeout_ms: u64,
+}
+
+/// The process running a node locks its data dir, a standby takes the node over once the
+/// process died.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct WarmStandby {
+    /// How often the process running the node checks it wasn't fenced off its data dir
+    #[serde(default = "default_standby_fence_check_ms")]
+    pub fence_check_ms: u64,
+    /// How often the standby tries the lock and reads what was written to the data dir
+    #[serde(default = "default_standby_poll_ms")]
+    pub poll_ms: u64,
+}
+
+/// A node starting from a persisted log reads it `batch_entries` at a time, decoding each
+/// batch on up to `threads` threads, and reports its progress in the logs and through
+/// `GET /cluster/ready`.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct LogReplayConfig {
+    #[serde(default = "default_replay_batch_entries")]
+    pub batch_entries: usize,
+    #[serde(default = "default_replay_threads")]
+    pub threads: usize,
+}
+
+impl Default for LogReplayConfig {
+    fn default() -> LogReplayConfig {
+        LogReplayConfig {
+            batch_entries: default_replay_batch_entries(),
+            threads: default_replay_threads(),
+        }
+    }
+}
+
+fn default_replay_batch_entries() -> usize {
+    10_000
+}
+
+fn default_replay_threads() -> usize {
+    4
+}
+
+/// Checks of the environment run before the node starts.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct StartupChecks {
+    /// Refuse to start when a check fails instead of warning
+    #[serde(default)]
+    pub enforce: bool,
+    /// Synced writes probing the latency of the data dir
+    #[serde(default = "default_disk_probes")]
+    pub disk_probes: u32,
+}
+
+impl Default for StartupChecks {
+    fn default() -> StartupChecks {
+        StartupChecks {
+            enforce: false,
+            disk_probes: default_disk_probes(),
+        }
+    }
+}
+
+/// Integration with the service manager running the node.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ServiceIntegration {
+    /// Shut the node down through `Shutdown` on SIGTERM, SIGINT or a Windows service stop
+    /// instead of stopping it right away
+    #[serde(default = "default_clean_stop")]
+    pub clean_stop: bool,
+    /// How often the status of the node is sent to systemd
+    #[serde(default = "default_service_status_interval_ms")]
+    pub status_interval_ms: u64,
+}
+
+impl Default for WarmStandby {
+    fn default() -> WarmStandby {
+        WarmStandby {
+            fence_check_ms: default_standby_fence_check_ms(),
+            poll_ms: default_standby_poll_ms(),
+        }
+    }
+}
+
+impl Default for DialTimeouts {
+    fn default() -> DialTimeouts {
+        DialTimeouts {
+            resolve_timeout_ms: default_resolve_timeout_ms(),
+            connect_timeout_ms: default_connect_timeout_ms(),
+        }
+    }
+}
+
+impl Default for RaftTimings {
+    fn default() -> RaftTimings {
+        RaftTimings {
+            election_timeout_min: default_election_timeout_min(),
+            election_timeout_max: default_election_timeout_max(),
+            heartbeat_interval: default_heartbeat_interval(),
+        }
+    }
+}
+
+fn default_election_timeout_min() -> u64 {
+    3000
+}
+
+fn default_election_timeout_max() -> u64 {
+    5000
+}
+
+fn default_heartbeat_interval() -> u64 {
+    300
+}
+
+fn default_metrics_retention_secs() -> u64 {
+    3600
+}
+
+fn default_membership_confirm_timeout_ms() -> u64 {
+    30000
+}
+
+fn default_contact_persist_ms() -> u64 {
+    1000
+}
+
+fn default_restart_hold_ms() -> u64 {
+    10000
+}
+
+fn default_bootstrap_delay_ms() -> u64 {
+    5000
+}
+
+fn default_max_pending_proposals() -> usize {
+    256
+}
+
+fn default_max_queued_proposals() -> usize {
+    1024
+}
+
+fn default_max_queued_clients() -> usize {
+    1024
+}
+
+/// Bounds of the proposals in flight, grown by one per window of proposals committed within
+/// the target latency and halved when commits get slower.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct AdaptiveWindow {
+    #[serde(default = "default_target_commit_latency_ms")]
+    pub target_latency_ms: u64,
+    #[serde(default = "default_min_pending_proposals")]
+    pub min_pending: usize,
+    #[serde(default = "default_max_adaptive_pending_proposals")]
+    pub max_pending: usize,
+}
+
+fn default_target_commit_latency_ms() -> u64 {
+    50
+}
+
+fn default_min_pending_proposals() -> usize {
+    1
+}
+
+fn default_max_adaptive_pending_proposals() -> usize {
+    4096
+}
+
+fn default_dump_dir() -> String {
+    "dumps".to_owned()
+}
+
+fn default_backup_retention() -> usize {
+    7
+}
+
+fn default_snapshot_retention() -> usize {
+    1
+}
+
+fn default_projection_checkpoint_entries() -> u64 {
+    100
+}
+
+fn default_snapshot_chunk_size() -> u64 {
+    10000
+}
+
+fn default_group_heartbeat_window_ms() -> u64 {
+    10
+}
+
+fn default_dns_cache_ttl_secs() -> u64 {
+    30
+}
+
+fn default_page_items() -> usize {
+    256
+}
+
+fn default_network_mailbox() -> usize {
+    1024
+}
+
+fn default_peer_mailbox() -> usize {
+    256
+}
+
+fn default_raft_mailbox() -> usize {
+    1024
+}
+
+fn default_inline_max_bytes() -> usize {
+    256 * 1024
+}
+
+fn default_resolve_timeout_ms() -> u64 {
+    2000
+}
+
+fn default_connect_timeout_ms() -> u64 {
+    5000
+}
+
+fn default_disk_probes() -> u32 {
+    5
+}
+
+fn default_clean_stop() -> bool {
+    true
+}
+
+fn default_service_status_interval_ms() -> u64 {
+    5000
+}
+
+fn default_standby_fence_check_ms() -> u64 {
+    1000
+}
+
+fn default_standby_poll_ms() -> u64 {
+    200
+}
+
+fn default_crash_reports() -> bool {
+    true
+}
+
+/// OpenTelemetry exporter settings, only used with the `otel` feature.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct TelemetryConfig {
+    /// OTLP collector endpoint, e.g. `http://127.0.0.1:4317`
+    pub otlp_endpoint: String,
+    #[serde(default = "default_service_name")]
+    pub service_name: String,
+}
+
+fn default_service_name() -> String {
+    "raftor".to_owned()
+}
+
+/// Splits the voters into a core, which syncs every log write to disk before acknowledging
+/// it, and async-ack voters, which acknowledge writes still buffered by the OS. Their appends
+/// are faster but lost if the machine, not only the process, goes down.
+///
+/// An entry is only durable once a core voter holds it, so the storage of an async-ack voter
+/// buffers its writes only while fewer async-ack voters than a majority are members: every
+/// majority committing an entry then holds a core voter. It syncs like the core otherwise.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct CommitQuorum {
+    /// Cluster addresses of the async-ack voters, among `nodes`
+    pub async_ack: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct CompactionConfig {
+    /// Applied entries since the last snapshot before compaction is due.
+    #[serde(default = "default_logs_since_last")]
+    pub logs_since_last: u64,
+    /// UTC time windows (`HH:MM-HH:MM`) compaction may run in, any time if empty.
+    #[serde(default)]
+    pub windows: Vec<String>,
+    /// Defer compaction while more entries per second than this are appended.
+    #[serde(default)]
+    pub max_entries_per_sec: Option<u64>,
+}
+
+fn default_logs_since_last() -> u64 {
+    5000
+}
+
+/// Lets the leader write a fresh snapshot when a follower needs entries which were already
+/// compacted, or lags more than `max_follower_lag` entries behind, so it catches up from a
+/// recent snapshot rather than a stale one or a long replay of the log.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct SnapshotTrigger {
+    #[serde(default = "default_max_follower_lag")]
+    pub max_follower_lag: u64,
+    /// Followers are checked this often.
+    #[serde(default = "default_trigger_check_secs")]
+    pub check_secs: u64,
+    /// Least time between two triggered snapshots.
+    #[serde(default = "default_trigger_interval_secs")]
+    pub min_interval_secs: u64,
+}
+
+fn default_max_follower_lag() -> u64 {
+    10000
+}
+
+fn default_trigger_check_secs() -> u64 {
+    5
+}
+
+fn default_trigger_interval_secs() -> u64 {
+    60
+}
+
+/// Lets a follower which finds itself far behind, e.g. after a restart, ask the leader to
+/// catch it up rather than wait for the leader's next reconnection attempt. The leader
+/// reconnects to it right away and snapshots first if it lags more than `snapshot_lag`
+/// entries. Set on every node, the leader ignores requests otherwise.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct CatchUpConfig {
+    /// Entries behind the leader's log past which a follower asks.
+    #[serde(default = "default_catch_up_min_lag")]
+    pub min_lag: u64,
+    #[serde(default = "default_max_follower_lag")]
+    pub snapshot_lag: u64,
+    /// Followers check their lag this often.
+    #[serde(default = "default_catch_up_check_secs")]
+    pub check_secs: u64,
+    /// Least time between two requests of a follower.
+    #[serde(default = "default_catch_up_retry_secs")]
+    pub retry_secs: u64,
+}
+
+/// The cluster leader compares the load of the nodes every `interval_secs` and moves one
+/// group replica, or the leadership of one group, from the busiest node to the least busy
+/// one. A node's load counts its replicas, each weighted by the traffic of its group.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct GroupRebalance {
+    #[serde(default = "default_rebalance_interval_secs")]
+    pub interval_secs: u64,
+    /// Entries per second of a group counting as one more replica.
+    #[serde(default = "default_rebalance_traffic_unit")]
+    pub traffic_unit: u64,
+    /// Load difference between two nodes past which a replica is moved.
+    #[serde(default = "default_rebalance_replica_skew")]
+    pub replica_skew: f64,
+    /// Difference in groups led by two nodes past which a leadership is moved.
+    #[serde(default = "default_rebalance_leader_skew")]
+    pub leader_skew: usize,
+}
+
+fn default_rebalance_interval_secs() -> u64 {
+    60
+}
+
+fn default_rebalance_traffic_unit() -> u64 {
+    100
+}
+
+fn default_rebalance_replica_skew() -> f64 {
+    2.0
+}
+
+fn default_rebalance_leader_skew() -> usize {
+    2
+}
+
+fn default_catch_up_min_lag() -> u64 {
+    1000
+}
+
+fn default_catch_up_check_secs() -> u64 {
+    2
+}
+
+fn default_catch_up_retry_secs() -> u64 {
+    10
+}
+
+/// Which nodes may join the cluster, nodes added through the admin API are only held to
+/// `max_members` and `required_labels`.
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct JoinAdmission {
+    /// Voters and non-voters the cluster may grow to
+    #[serde(default)]
+    pub max_members: Option<usize>,
+    /// Labels a node must carry, with their values
+    #[serde(default)]
+    pub required_labels: BTreeMap<String, String>,
+    /// Hold joins until an operator approves them through the admin API
+    #[serde(default)]
+    pub manual_approval: bool,
+}
+
+/// Bytes the in-memory structures of a node may hold, unlimited when unset. What is over a
+/// limit is shed: log entries are spilled, proposals and peer requests are rejected and the
+/// oldest events are dropped.
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct MemoryLimits {
+    /// Log entries cached in memory, entries over the limit are spilled next to the snapshots
+    #[serde(default)]
+    pub log_cache_bytes: Option<usize>,
+    /// Client proposals accepted and not applied yet
+    #[serde(default)]
+    pub pending_proposal_bytes: Option<usize>,
+    /// Requests sent to peers and waiting for their answer, over all peers
+    #[serde(default)]
+    pub outbound_queue_bytes: Option<usize>,
+    /// Recent cluster events kept for `raftor events`
+    #[serde(default)]
+    pub event_bytes: Option<usize>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct PeerRateLimit {
+    pub frames_per_sec: u64,
+    pub bytes_per_sec: u64,
+    /// How long a session exceeding the limits is suspended.
+    #[serde(default = "default_suspend_secs")]
+    pub suspend_secs: u64,
+}
+
+fn default_suspend_secs() -> u64 {
+    5
+}
+
+/// Range of versions peers must run, both bounds included, e.g. `min = "0.3.0"`.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct PeerVersions {
+    #[serde(default)]
+    pub min: Option<String>,
+    #[serde(default)]
+    pub max: Option<String>,
+}
+
+impl PeerVersions {
+    /// Why a peer announcing `version` is refused, `None` when it's in range.
+    pub fn refusal(&self, version: &str) -> Option<String> {
+        let theirs = parse_version(version);
+        if let Some(ref min) = self.min {
+            if theirs < parse_version(min) {
+                return Some(format!("version {} is below the minimum {}", version, min));
+            }
+        }
+        if let Some(ref max) = self.max {
+            if theirs > parse_version(max) {
+                return Some(format!("version {} is above the maximum {}", version, max));
+            }
+        }
+        None
+    }
+}
+
+/// Numeric parts of a version, pre-release and build suffixes are ignored.
+fn parse_version(version: &str) -> Vec<u64> {
+    version
+        .split(|c| c == '-' || c == '+')
+        .next()
+        .unwrap_or_default()
+        .split('.')
+        .map(|part| part.parse().unwrap_or(0))
+        .collect()
+}
+
+/// Peers reached over low-bandwidth links (e.g. in another region), listed by cluster address
+/// or recognized by a label whose value differs from this node's.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ConstrainedLinks {
+    #[serde(default)]
+    pub peers: Vec<String>,
+    /// Label (e.g. `region`) telling peers apart, those with another value are constrained.
+    #[serde(default)]
+    pub label: Option<String>,
+    /// Bounds of the frames coalescing the messages written to a constrained peer.
+    #[serde(default = "default_constrained_batch_len")]
+    pub max_batch_len: usize,
+    #[serde(default = "default_constrained_batch_bytes")]
+    pub max_batch_bytes: usize,
+    /// Compress the frames, needs the `compression` feature.
+    #[serde(default)]
+    pub compress: bool,
+    /// Rate at which snapshots are streamed to a constrained peer.
+    #[serde(default)]
+    pub snapshot_bytes_per_sec: Option<u64>,
+}
+
+impl ConstrainedLinks {
+    /// Whether the link from `local` to `peer` is constrained.
+    pub fn matches(&self, local: &NodeInfo, peer: &NodeInfo) -> bool {
+        if self.peers.iter().any(|addr| *addr == peer.cluster_addr) {
+            return true;
+        }
+
+        match self.label {
+            Some(ref label) => local.labels.get(label) != peer.labels.get(label),
+            None => false,
+        }
+    }
+}
+
+/// Training of the cluster's compression dictionary by the leader and where it's used.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct CompressionDictionary {
+    /// Recent proposals kept as training samples, the dictionary is trained once there are
+    /// this many.
+    pub sample_entries: usize,
+    /// Only proposals up to this size are sampled, larger ones compress well on their own.
+    pub max_entry_bytes: usize,
+    /// Size of the trained dictionary.
+    pub dict_bytes: usize,
+    /// Train a new dictionary after this many more proposals, following the workload.
+    pub retrain_entries: u64,
+    /// Zstd compression level.
+    pub level: i32,
+    /// Compress the frames sent to peers with the dictionary.
+    pub wire: bool,
+    /// Compress the entries of the log store with the dictionary.
+    pub storage: bool,
+}
+
+impl Default for CompressionDictionary {
+    fn default() -> Self {
+        CompressionDictionary {
+            sample_entries: 1000,
+            max_entry_bytes: 1024,
+            dict_bytes: 16 * 1024,
+            retrain_entries: 100000,
+            level: 3,
+            wire: true,
+            storage: true,
+        }
+    }
+}
+
+fn default_constrained_batch_len() -> usize {
+    8
+}
+
+fn default_constrained_batch_bytes() -> usize {
+    64 * 1024
+}
+
+/// Where the tie-breaking lease of a two node cluster is held, set exactly one of them.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ArbitrationConfig {
+    /// Lease file on a disk shared by both nodes.
+    #[serde(default)]
+    pub lease_file: Option<String>,
+    /// External tie-breaker service granting the lease over HTTP.
+    #[serde(default)]
+    pub tiebreaker_url: Option<String>,
+    #[serde(default = "default_lease_secs")]
+    pub lease_secs: u64,
+}
+
+fn default_lease_secs() -> u64 {
+    10
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct FederationConfig {
+    pub cluster_name: String,
+    /// Public address of a meta-cluster node.
+    pub meta_host: String,
+    /// Namespaces owned by this cluster.
+    #[serde(default)]
+    pub namespaces: Vec<String>,
+    #[serde(default = "default_announce_secs")]
+    pub announce_secs: u64,
+    /// Bearer token of the meta-cluster, see `FederationRegistryConfig::token`
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+fn default_announce_secs() -> u64 {
+    30
+}
+
+/// Registry of a meta-cluster, see `federation`.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct FederationRegistryConfig {
+    /// Bearer token clusters announce themselves with on `PUT /federation/clusters`,
+    /// announcements are refused while it's unset
+    #[serde(default)]
+    pub token: Option<String>,
+    /// Clusters which didn't announce themselves for this long are left out of lookups
+    #[serde(default = "default_record_ttl_secs")]
+    pub record_ttl_secs: u64,
+}
+
+fn default_record_ttl_secs() -> u64 {
+    90
+}
+
+impl Default for FederationRegistryConfig {
+    fn default() -> Self {
+        FederationRegistryConfig {
+            token: None,
+            record_ttl_secs: default_record_ttl_secs(),
+        }
+    }
+}
+
+/// Constraint on which nodes may form the raft membership, based on node labels.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(tag = "rule")]
+pub enum PlacementRule {
+    /// Members must carry at least `min` distinct values of `label`, e.g. span 2 zones.
+    Spread { label: String, min: usize },
+    /// Nodes labelled `label = value` must never be members.
+    Exclude { label: String, value: String },
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct HedgeConfig {
+    /// Percentile of recent read latencies after which a second replica is asked.
+    #[serde(default = "default_hedge_percentile")]
+    pub percentile: f64,
+    #[serde(default = "default_hedge_min_delay_ms")]
+    pub min_delay_ms: u64,
+}
+
+/// Every node of the cluster needs the same settings.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct SnapshotTransferConfig {
+    /// Key chunks are signed with, and encrypted with when `encrypt` is set.
+    pub secret: String,
+    #[serde(default)]
+    pub encrypt: bool,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct DescriptorConfig {
+    /// Descriptors are signed with HMAC-SHA256 when set, clients need the same secret.
+    #[serde(default)]
+    pub secret: Option<String>,
+    /// How long clients may use a cached descriptor before refreshing it.
+    #[serde(default = "default_descriptor_ttl_secs")]
+    pub ttl_secs: u64,
+}
+
+fn default_descriptor_ttl_secs() -> u64 {
+    60
+}
+
+fn default_hedge_percentile() -> f64 {
+    0.95
+}
+
+fn default_hedge_min_delay_ms() -> u64 {
+    10
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> ConfigSchema {
+        ConfigSchema::new("127.0.0.1:9000".to_owned(), vec![])
+    }
+
+    fn tls(peers: Vec<(&str, PeerTlsOverride)>) -> TlsConfig {
+        TlsConfig {
+            cert: "node.pem".to_owned(),
+            key: "node.key".to_owned(),
+            ca: "ca.pem".to_owned(),
+            server_name: default_tls_server_name(),
+            require_client_auth: true,
+            verify_peer_id: true,
+            handshake_timeout_ms: default_tls_handshake_timeout_ms(),
+            reload_check_secs: None,
+            peers: peers.into_iter().map(|(addr, peer)| (addr.to_owned(), peer)).collect(),
+        }
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        assert_eq!(config().validate(), Ok(()));
+    }
+
+    #[test]
+    fn a_node_without_pending_proposals_is_rejected() {
+        let mut zero_proposals = config();
+        zero_proposals.max_pending_proposals = 0;
+        assert!(zero_proposals.validate().unwrap_err().contains("max_pending_proposals"));
+
+        let mut zero_queue = config();
+        zero_queue.max_queued_clients = 0;
+        assert!(zero_queue.validate().unwrap_err().contains("max_queued_clients"));
+    }
+
+    #[test]
+    fn handshakes_need_a_timeout() {
+        let mut zero_handshake = config();
+        zero_handshake.tls = Some(TlsConfig {
+            handshake_timeout_ms: 0,
+            ..tls(vec![])
+        });
+        assert!(zero_handshake.validate().unwrap_err().contains("handshake_timeout_ms"));
+    }
+
+    #[test]
+    fn peer_overrides_must_make_sense() {
+        let plaintext = || PeerTlsOverride {
+            ca: None,
+            server_name: None,
+            plaintext: true,
+        };
+
+        let mut config = config();
+        config.tls = Some(tls(vec![("10.0.0.1:8000", plaintext())]));
+        assert_eq!(config.validate(), Ok(()));
+
+        config.tls = Some(tls(vec![("node-1:8000", plaintext())]));
+        assert!(config.validate().unwrap_err().contains("must be an IP address"));
+
+        config.tls = Some(tls(vec![(
+            "10.0.0.1:8000",
+            PeerTlsOverride {
+                ca: Some("other.pem".to_owned()),
+                ..plaintext()
+            },
+        )]));
+        assert!(config.validate().unwrap_err().contains("is plaintext"));
+    }
+
+    #[test]
+    fn lower_priorities_time_out_later() {
+        let mut config = config().with_raft_timings(RaftTimings {
+            election_timeout_min: 1000,
+            election_timeout_max: 2000,
+            heartbeat_interval: 100,
+        });
+        assert_eq!(config.election_timeouts("a"), (1000, 2000));
+
+        config.leadership_priority.insert("a".to_owned(), 4);
+        config.leadership_priority.insert("b".to_owned(), 1);
+        assert_eq!(config.election_timeouts("a"), (1000, 2000));
+        assert_eq!(config.election_timeouts("b"), (1750, 2750));
+        assert_eq!(config.election_timeouts("c"), (2000, 3000));
+    }
+
+    #[test]
+    fn cluster_metadata_needs_names() {
+        let mut metadata = ClusterMetadata {
+            name: "prod".to_owned(),
+            labels: BTreeMap::new(),
+        };
+        assert_eq!(metadata.invalid(), None);
+
+        metadata.labels.insert(" ".to_owned(), "x".to_owned());
+        assert!(metadata.invalid().is_some());
+        metadata.name = String::new();
+        assert_eq!(metadata.invalid(), Some("the cluster name is empty".to_owned()));
+    }
 }
@@ -0,0 +1,185 @@
+//! Reference cases of the JSON client protocol, run against a node with
+//! `raftor conformance <json client addr>`. Clients written for other languages are expected
+//! to produce and accept exactly the frames exercised here.
+
+use serde_json::{json, Value};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use crate::json_protocol::{ErrorCode, JsonReply, JsonResponse, JSON_PROTOCOL_VERSION};
+
+/// How long a case waits for its answer
+const CASE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Session the reference proposals are made in
+const SESSION: &str = "conformance";
+
+enum Expect {
+    Ok(fn(&Value) -> bool),
+    Error(ErrorCode),
+}
+
+struct Case {
+    name: &'static str,
+    request: String,
+    /// Id the answer must carry
+    id: Option<u64>,
+    expect: Expect,
+}
+
+/// Cases in the order they're sent on one connection, the first ones precede the handshake.
+/// `hello` carries the client token when the node needs one.
+fn cases(token: Option<&str>) -> Vec<Case> {
+    vec![
+        Case {
+            name: "request before hello",
+            request: json!({"id": 1, "op": "status"}).to_string(),
+            id: Some(1),
+            expect: Expect::Error(ErrorCode::HandshakeRequired),
+        },
+        Case {
+            name: "unsupported version",
+            request: json!({"id": 2, "op": "hello", "version": 0}).to_string(),
+            id: Some(2),
+            expect: Expect::Error(ErrorCode::UnsupportedVersion),
+        },
+        Case {
+            name: "hello",
+            request: json!({"id": 3, "op": "hello", "version": JSON_PROTOCOL_VERSION, "token": token}).to_string(),
+            id: Some(3),
+            expect: Expect::Ok(|ok| ok["version"] == json!(JSON_PROTOCOL_VERSION) && ok["node"].is_u64()),
+        },
+        Case {
+            name: "malformed line",
+            request: "{\"op\": ".to_owned(),
+            id: None,
+            expect: Expect::Error(ErrorCode::BadRequest),
+        },
+        Case {
+            name: "unknown op",
+            request: json!({"id": 4, "op": "frobnicate"}).to_string(),
+            id: Some(4),
+            expect: Expect::Error(ErrorCode::BadRequest),
+        },
+        Case {
+            name: "status",
+            request: json!({"id": 5, "op": "status"}).to_string(),
+            id: Some(5),
+            expect: Expect::Ok(|ok| ok["node"].is_u64() && ok["nodes"].is_array()),
+        },
+        Case {
+            name: "propose",
+            request: json!({"id": 6, "op": "propose", "session": SESSION, "seq": 1, "data": {"App": []}}).to_string(),
+            id: Some(6),
+            expect: Expect::Ok(|ok| ok["index"].as_u64().map_or(false, |index| index > 0)),
+        },
+        Case {
+            name: "read with session",
+            request: json!({"id": 7, "op": "read", "key": "conformance", "session": SESSION}).to_string(),
+            id: Some(7),
+            expect: Expect::Ok(|ok| ok["node"].is_u64() && ok["addr"].is_string() && ok["watermark"].as_u64().map_or(false, |index| index > 0)),
+        },
+        Case {
+            name: "read without session",
+            request: json!({"id": 8, "op": "read", "key": "conformance"}).to_string(),
+            id: Some(8),
+            expect: Expect::Ok(|ok| ok["node"].is_u64() && ok["watermark"].is_null()),
+        },
+        Case {
+            name: "propose a command of the node",
+            request: json!({"id": 9, "op": "propose", "data": {"Add": 42}}).to_string(),
+            id: Some(9),
+            expect: Expect::Error(ErrorCode::BadRequest),
+        },
+    ]
+}
+
+/// Run the reference cases against the node as the client of `token`, returns whether all
+/// passed.
+pub fn run(addr: &str, token: Option<&str>) -> bool {
+    let stream = match TcpStream::connect(addr) {
+        Ok(stream) => stream,
+        Err(err) => {
+            println!("[conformance] can't connect to {}: {}", addr, err);
+            return false;
+        }
+    };
+
+    let mut passed = 0;
+    let cases = cases(token);
+    let total = cases.len();
+    let mut conn = match Connection::new(stream) {
+        Ok(conn) => conn,
+        Err(err) => {
+            println!("[conformance] can't set up the connection to {}: {}", addr, err);
+            return false;
+        }
+    };
+
+    for case in cases {
+        let outcome = conn.call(&case.request).map(|res| check(&case, &res));
+        let ok = match outcome {
+            Ok(Ok(())) => true,
+            Ok(Err(reason)) => {
+                println!("[conformance] {:<24} FAIL ({})", case.name, reason);
+                false
+            }
+            Err(err) => {
+                println!("[conformance] {:<24} FAIL ({})", case.name, err);
+                false
+            }
+        };
+        if ok {
+            println!("[conformance] {:<24} PASS", case.name);
+            passed += 1;
+        }
+    }
+
+    println!("[conformance] {}/{} cases passed", passed, total);
+    passed == total
+}
+
+fn check(case: &Case, res: &JsonResponse) -> Result<(), String> {
+    if res.id != case.id {
+        return Err(format!("answered id {:?}, expected {:?}", res.id, case.id));
+    }
+
+    match (&case.expect, &res.reply) {
+        (Expect::Ok(valid), JsonReply::Ok(value)) if valid(value) => Ok(()),
+        (Expect::Ok(_), JsonReply::Ok(value)) => Err(format!("unexpected answer {}", value)),
+        (Expect::Error(code), JsonReply::Error(err)) if err.code == *code => Ok(()),
+        (_, JsonReply::Error(err)) => Err(format!("error {:?}", err.code)),
+        (Expect::Error(code), JsonReply::Ok(_)) => Err(format!("answered ok, expected {:?}", code)),
+    }
+}
+
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Connection {
+    fn new(stream: TcpStream) -> io::Result<Connection> {
+        stream.set_read_timeout(Some(CASE_TIMEOUT))?;
+
+        Ok(Connection {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+        })
+    }
+
+    /// Send one line and read the answer, cases are sent one at a time so answers come in
+    /// order.
+    fn call(&mut self, request: &str) -> io::Result<JsonResponse> {
+        self.writer.write_all(request.as_bytes())?;
+        self.writer.write_all(b"\n")?;
+
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed"));
+        }
+
+        serde_json::from_str(&line).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+}
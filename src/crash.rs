@@ -0,0 +1,290 @@
+//! Crash handling: a panic hook flushing the durable stores of a node and writing a crash
+//! report to its data dir, and a marker telling the next start whether the node stopped cleanly.
+
+use actix_raft::{storage::HardState, NodeId};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Write};
+use std::panic::{self, PanicInfo};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::network::NodeStatus;
+use crate::raft::timing::now_millis;
+
+/// File in the data dir holding how the node last stopped
+const MARKER_FILE: &str = "shutdown";
+
+/// How the node stopped, as recorded in the marker of its data dir.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ShutdownState {
+    /// Running, a node finding this on start was killed or lost power
+    Running,
+    Clean,
+    Crashed,
+}
+
+impl ShutdownState {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ShutdownState::Running => "running",
+            ShutdownState::Clean => "clean",
+            ShutdownState::Crashed => "crashed",
+        }
+    }
+
+    fn parse(state: &str) -> Option<ShutdownState> {
+        match state.trim() {
+            "running" => Some(ShutdownState::Running),
+            "clean" => Some(ShutdownState::Clean),
+            "crashed" => Some(ShutdownState::Crashed),
+            _ => None,
+        }
+    }
+}
+
+/// Written as `crash-<node>-<unix millis>.json` to the data dir when the node panics.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CrashReport {
+    pub node: NodeId,
+    /// Unix time in milliseconds
+    pub at: u64,
+    pub thread: Option<String>,
+    pub message: String,
+    /// `file:line:column` of the panic
+    pub location: Option<String>,
+    pub current_term: u64,
+    pub voted_for: Option<NodeId>,
+    pub last_log_index: u64,
+    pub last_applied: u64,
+    /// Peers connected when the node panicked
+    pub active_peers: Vec<NodeId>,
+    pub peers: BTreeMap<NodeId, NodeStatus>,
+    /// Errors flushing the durable stores, empty when everything was flushed
+    pub flush_errors: Vec<String>,
+    pub backtrace: String,
+}
+
+/// Flushes buffered writes of a durable store.
+pub type Flusher = Box<dyn Fn() -> io::Result<()> + Send + Sync>;
+
+#[derive(Default)]
+struct Recorded {
+    current_term: u64,
+    voted_for: Option<NodeId>,
+    last_log_index: u64,
+    last_applied: u64,
+    peers: BTreeMap<NodeId, NodeStatus>,
+}
+
+/// State of a node kept up to date by its storage and network for the crash report, and
+/// the flushers run before it's written.
+pub struct CrashRecorder {
+    node: NodeId,
+    dir: Option<PathBuf>,
+    recorded: Mutex<Recorded>,
+    flushers: Mutex<Vec<Flusher>>,
+}
+
+pub type CrashState = Arc<CrashRecorder>;
+
+impl CrashRecorder {
+    /// Reports and the shutdown marker are kept in `data_dir`, without one the report is only
+    /// logged.
+    pub fn new(node: NodeId, data_dir: Option<&str>) -> CrashState {
+        Arc::new(CrashRecorder {
+            node: node,
+            dir: data_dir.map(PathBuf::from),
+            recorded: Mutex::new(Recorded::default()),
+            flushers: Mutex::new(Vec::new()),
+        })
+    }
+
+    pub fn record_hard_state(&self, hs: &HardState) {
+        let mut recorded = self.recorded.lock().unwrap();
+        recorded.current_term = hs.current_term;
+        recorded.voted_for = hs.voted_for;
+    }
+
+    pub fn record_last_log_index(&self, index: u64) {
+        self.recorded.lock().unwrap().last_log_index = index;
+    }
+
+    pub fn record_applied(&self, index: u64) {
+        self.recorded.lock().unwrap().last_applied = index;
+    }
+
+    pub fn record_peer(&self, peer: NodeId, status: NodeStatus) {
+        self.recorded.lock().unwrap().peers.insert(peer, status);
+    }
+
+    pub fn forget_peer(&self, peer: NodeId) {
+        self.recorded.lock().unwrap().peers.remove(&peer);
+    }
+
+    /// Run the flusher before writing the crash report.
+    pub fn add_flusher(&self, flusher: Flusher) {
+        self.flushers.lock().unwrap().push(flusher);
+    }
+
+    /// Install the panic hook and mark the node running, returns how it stopped before. The
+    /// previously installed hook still runs after the report is written.
+    pub fn install(state: &CrashState) -> Option<ShutdownState> {
+        let previous = state.read_marker();
+        state.write_marker(ShutdownState::Running);
+
+        let recorder = state.clone();
+        let next = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            recorder.crashed(info);
+            next(info);
+        }));
+
+        previous
+    }
+
+    /// Mark the node as stopped cleanly, once it drained and handed leadership over.
+    pub fn mark_clean(&self) {
+        self.write_marker(ShutdownState::Clean);
+    }
+
+    fn crashed(&self, info: &PanicInfo) {
+        let flush_errors = self.flush();
+        let report = self.report(info, flush_errors);
+        error!("CRITICAL: node {} panicked: {}", self.node, report.message);
+
+        if let Some(ref dir) = self.dir {
+            let path = dir.join(format!("crash-{}-{}.json", self.node, report.at));
+            let res = serde_json::to_vec_pretty(&report)
+                .map_err(|err| io::Error::new(io::ErrorKind::Other, err))
+                .and_then(|data| fs::write(&path, data));
+            match res {
+                Ok(_) => error!("Crash report of node {} written to {:?}", self.node, path),
+                Err(err) => error!("Error writing the crash report to {:?}: {}", path, err),
+            }
+        }
+
+        self.write_marker(ShutdownState::Crashed);
+    }
+
+    fn flush(&self) -> Vec<String> {
+        // the panicking thread may be the one holding the lock
+        let flushers = match self.flushers.try_lock() {
+            Ok(flushers) => flushers,
+            Err(_) => return vec!["flushers locked by the panicking thread".to_owned()],
+        };
+
+        flushers
+            .iter()
+            .filter_map(|flush| flush().err())
+            .map(|err| err.to_string())
+            .collect()
+    }
+
+    fn report(&self, info: &PanicInfo, flush_errors: Vec<String>) -> CrashReport {
+        let message = match info.payload().downcast_ref::<&str>() {
+            Some(message) => message.to_string(),
+            None => match info.payload().downcast_ref::<String>() {
+                Some(message) => message.clone(),
+                None => "Box<Any>".to_owned(),
+            },
+        };
+
+        let mut report = CrashReport {
+            node: self.node,
+            at: now_millis(),
+            thread: std::thread::current().name().map(|name| name.to_owned()),
+            message: message,
+            location: info.location().map(|location| location.to_string()),
+            current_term: 0,
+            voted_for: None,
+            last_log_index: 0,
+            last_applied: 0,
+            active_peers: Vec::new(),
+            peers: BTreeMap::new(),
+            flush_errors: flush_errors,
+            backtrace: format!("{:?}", backtrace::Backtrace::new()),
+        };
+
+        if let Ok(recorded) = self.recorded.try_lock() {
+            report.current_term = recorded.current_term;
+            report.voted_for = recorded.voted_for;
+            report.last_log_index = recorded.last_log_index;
+            report.last_applied = recorded.last_applied;
+            report.active_peers = recorded
+                .peers
+                .iter()
+                .filter(|(_, status)| **status == NodeStatus::Connected)
+                .map(|(peer, _)| *peer)
+                .collect();
+            report.peers = recorded.peers.clone();
+        }
+
+        report
+    }
+
+    fn marker_path(&self) -> Option<PathBuf> {
+        self.dir.as_ref().map(|dir| dir.join(MARKER_FILE))
+    }
+
+    fn read_marker(&self) -> Option<ShutdownState> {
+        self.marker_path()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .and_then(|state| ShutdownState::parse(&state))
+    }
+
+    fn write_marker(&self, state: ShutdownState) {
+        if let Some(path) = self.marker_path() {
+            if let Err(err) = write_synced(&path, state.as_str().as_bytes()) {
+                error!("Error marking node {} {}: {}", self.node, state.as_str(), err);
+            }
+        }
+    }
+}
+
+fn write_synced(path: &Path, data: &[u8]) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut file = fs::File::create(path)?;
+    file.write_all(data)?;
+    file.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_marker_tells_how_the_node_stopped() {
+        let dir = tempfile::tempdir().unwrap();
+        let recorder = CrashRecorder::new(1, dir.path().to_str());
+        assert_eq!(recorder.read_marker(), None);
+
+        recorder.write_marker(ShutdownState::Running);
+        assert_eq!(recorder.read_marker(), Some(ShutdownState::Running));
+        recorder.mark_clean();
+        assert_eq!(recorder.read_marker(), Some(ShutdownState::Clean));
+
+        fs::write(dir.path().join(MARKER_FILE), "garbage").unwrap();
+        assert_eq!(recorder.read_marker(), None);
+    }
+
+    #[test]
+    fn a_node_without_a_data_dir_keeps_no_marker() {
+        let recorder = CrashRecorder::new(1, None);
+        recorder.mark_clean();
+        assert_eq!(recorder.read_marker(), None);
+    }
+
+    #[test]
+    fn flush_errors_are_collected() {
+        let recorder = CrashRecorder::new(1, None);
+        recorder.add_flusher(Box::new(|| Ok(())));
+        recorder.add_flusher(Box::new(|| Err(io::Error::new(io::ErrorKind::Other, "disk gone"))));
+
+        assert_eq!(recorder.flush(), vec!["disk gone".to_owned()]);
+    }
+}
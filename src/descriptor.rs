@@ -0,0 +1,196 @@
+//! Compact description of a cluster for client bootstrap.
+//!
+//! Nodes serve a signed descriptor of the cluster (members, their public addresses, leader
+//! and term) which clients cache on disk. A restarting client uses its cached descriptor while
+//! it is fresh and otherwise refreshes it from the known members, falling back to the seeds.
+
+use actix_raft::NodeId;
+use actix_web::client::Client;
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha2::Sha256;
+use futures::future::{self, Future};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::raft::timing::now_millis;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DescriptorMember {
+    pub id: NodeId,
+    pub public_addr: String,
+    /// Cordoned members are tried last
+    #[serde(default)]
+    pub cordoned: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ClusterDescriptor {
+    pub members: Vec<DescriptorMember>,
+    pub leader: Option<NodeId>,
+    pub term: u64,
+    /// Unix time in milliseconds the descriptor was issued at
+    pub issued_at: u64,
+    /// How long clients may use the descriptor without refreshing it
+    pub ttl_secs: u64,
+    /// Hex encoded HMAC-SHA256 of the other fields, when the cluster has a descriptor secret
+    pub signature: Option<String>,
+}
+
+impl ClusterDescriptor {
+    /// The signed content, every field but the signature.
+    fn payload(&self) -> Vec<u8> {
+        let unsigned = ClusterDescriptor {
+            signature: None,
+            ..self.clone()
+        };
+        serde_json::to_vec(&unsigned).unwrap_or_default()
+    }
+
+    fn mac(&self, secret: &str) -> String {
+        let mut hmac = Hmac::new(Sha256::new(), secret.as_bytes());
+        hmac.input(&self.payload());
+
+        hmac.result().code().iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    pub fn sign(&mut self, secret: &str) {
+        self.signature = Some(self.mac(secret));
+    }
+
+    pub fn verify(&self, secret: &str) -> bool {
+        self.signature.as_ref().map(|signature| *signature == self.mac(secret)).unwrap_or(false)
+    }
+
+    pub fn is_stale(&self) -> bool {
+        now_millis() > self.issued_at + self.ttl_secs * 1000
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Option<ClusterDescriptor> {
+        let content = fs::read(path).ok()?;
+        serde_json::from_slice(&content).ok()
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
+        fs::write(path, serde_json::to_vec(self)?)
+    }
+
+    /// Public addresses of the members, the leader first and the cordoned ones last.
+    fn addresses(&self) -> Vec<String> {
+        let mut members = self.members.clone();
+        members.sort_by_key(|member| (Some(member.id) != self.leader, member.cordoned));
+        members.into_iter().map(|member| member.public_addr).collect()
+    }
+}
+
+fn fetch(addr: String, secret: Option<String>) -> Box<dyn Future<Item = ClusterDescriptor, Error = ()>> {
+    Box::new(
+        Client::default()
+            .get(format!("http://{}/cluster/descriptor", addr))
+            .send()
+            .map_err(move |err| debug!("Failed to fetch the cluster descriptor from {}: {:?}", addr, err))
+            .and_then(|mut res| res.json::<ClusterDescriptor>().map_err(|_| ()))
+            .and_then(move |descriptor| match secret {
+                Some(ref secret) if !descriptor.verify(secret) => {
+                    error!("Rejecting cluster descriptor with an invalid signature");
+                    Err(())
+                }
+                _ => Ok(descriptor),
+            }),
+    )
+}
+
+/// Get a descriptor of the cluster, from the cache while it's fresh, otherwise from the cached
+/// members and then the seeds, one at a time. A fetched descriptor replaces the cache.
+pub fn bootstrap(
+    seeds: Vec<String>,
+    cache: PathBuf,
+    secret: Option<String>,
+) -> impl Future<Item = ClusterDescriptor, Error = ()> {
+    let cached = ClusterDescriptor::load(&cache)
+        .filter(|descriptor| secret.as_ref().map(|secret| descriptor.verify(secret)).unwrap_or(true));
+
+    let mut candidates = Vec::new();
+    if let Some(ref descriptor) = cached {
+        if !descriptor.is_stale() {
+            return future::Either::A(future::ok(descriptor.clone()));
+        }
+        candidates.extend(descriptor.addresses());
+    }
+    candidates.extend(seeds.into_iter().filter(|seed| !candidates.contains(seed)).collect::<Vec<_>>());
+
+    let fetched = candidates.into_iter().fold(
+        Box::new(future::err(())) as Box<dyn Future<Item = ClusterDescriptor, Error = ()>>,
+        move |acc, addr| {
+            let secret = secret.clone();
+            Box::new(acc.or_else(move |_| fetch(addr, secret)))
+        },
+    );
+
+    future::Either::B(fetched.map(move |descriptor| {
+        if let Err(err) = descriptor.save(&cache) {
+            warn!("Failed to cache the cluster descriptor in {:?}: {}", cache, err);
+        }
+        descriptor
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn member(id: NodeId, cordoned: bool) -> DescriptorMember {
+        DescriptorMember {
+            id: id,
+            public_addr: format!("10.0.0.{}:8000", id),
+            cordoned: cordoned,
+        }
+    }
+
+    fn descriptor() -> ClusterDescriptor {
+        ClusterDescriptor {
+            members: vec![member(1, true), member(2, false), member(3, false)],
+            leader: Some(3),
+            term: 4,
+            issued_at: now_millis(),
+            ttl_secs: 60,
+            signature: None,
+        }
+    }
+
+    #[test]
+    fn a_signature_covers_every_field() {
+        let mut descriptor = descriptor();
+        assert!(!descriptor.verify("secret"));
+
+        descriptor.sign("secret");
+        assert!(descriptor.verify("secret"));
+        assert!(!descriptor.verify("other"));
+
+        descriptor.leader = Some(2);
+        assert!(!descriptor.verify("secret"));
+    }
+
+    #[test]
+    fn the_leader_is_tried_first_and_cordoned_members_last() {
+        assert_eq!(descriptor().addresses(), vec!["10.0.0.3:8000", "10.0.0.2:8000", "10.0.0.1:8000"]);
+    }
+
+    #[test]
+    fn a_cached_descriptor_is_used_until_it_is_stale() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = dir.path().join("descriptor.json");
+
+        let mut descriptor = descriptor();
+        descriptor.save(&cache).unwrap();
+        let loaded = bootstrap(vec![], cache.clone(), None).wait().unwrap();
+        assert_eq!(loaded.term, 4);
+
+        // Stale and nowhere to refresh it from
+        descriptor.issued_at -= 61 * 1000;
+        descriptor.save(&cache).unwrap();
+        assert!(descriptor.is_stale());
+        assert!(bootstrap(vec![], cache, None).wait().is_err());
+    }
+}
@@ -0,0 +1,312 @@
+//! Zstd dictionaries for clusters proposing many small, similar entries, with the `dictionary`
+//! feature. Such entries barely compress on their own, against a dictionary trained from
+//! recent ones they shrink severalfold.
+//!
+//! The leader samples the proposals it takes and, once it has `sample_entries` of them, trains
+//! a dictionary and proposes it as `MemoryStorageData::SetDictionary`. Every node installs it
+//! when applying the entry, so the whole cluster compresses with the same dictionary. A
+//! dictionary is identified by a checksum of its contents, compressed data names the one it
+//! was compressed with and is read with it while newer ones are trained. Installed
+//! dictionaries are kept for the life of the process.
+
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fmt;
+#[cfg(not(feature = "dictionary"))]
+use std::io;
+
+use crate::config::CompressionDictionary;
+
+/// A trained dictionary.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct Dictionary {
+    pub id: u32,
+    pub data: Vec<u8>,
+}
+
+impl Dictionary {
+    pub fn new(data: Vec<u8>) -> Dictionary {
+        Dictionary {
+            id: checksum(&data),
+            data: data,
+        }
+    }
+
+    /// Whether the id matches the contents, a dictionary received from a peer must.
+    pub fn is_valid(&self) -> bool {
+        self.id == checksum(&self.data)
+    }
+}
+
+impl fmt::Debug for Dictionary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Dictionary({:08x}, {} bytes)", self.id, self.data.len())
+    }
+}
+
+/// FNV-1a, stable across builds and platforms unlike the std hashers.
+fn checksum(data: &[u8]) -> u32 {
+    data.iter().fold(0x811c9dc5, |hash: u32, byte| (hash ^ u32::from(*byte)).wrapping_mul(0x01000193))
+}
+
+/// Recent small proposals of the leader, the training set of the next dictionary.
+pub struct Sampler {
+    config: CompressionDictionary,
+    samples: VecDeque<Vec<u8>>,
+    /// Proposals sampled since the last dictionary was trained
+    since_trained: u64,
+    trained: bool,
+}
+
+impl Sampler {
+    pub fn new(config: CompressionDictionary) -> Sampler {
+        Sampler {
+            samples: VecDeque::with_capacity(config.sample_entries),
+            config: config,
+            since_trained: 0,
+            trained: false,
+        }
+    }
+
+    /// Sample an encoded proposal, answers whether a dictionary is due.
+    pub fn observe(&mut self, entry: &[u8]) -> bool {
+        if entry.is_empty() || entry.len() > self.config.max_entry_bytes {
+            return false;
+        }
+
+        if self.samples.len() == self.config.sample_entries {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(entry.to_vec());
+        self.since_trained += 1;
+
+        let due = if self.trained { self.config.retrain_entries } else { self.config.sample_entries as u64 };
+        self.samples.len() == self.config.sample_entries && self.since_trained >= due
+    }
+
+    /// The samples to train a dictionary from, the next one is due after `retrain_entries`.
+    pub fn take(&mut self) -> Vec<Vec<u8>> {
+        self.since_trained = 0;
+        self.trained = true;
+        self.samples.iter().cloned().collect()
+    }
+
+    /// Train again once the samples are renewed, after a failed training or proposal.
+    pub fn retry(&mut self) {
+        self.since_trained = 0;
+        self.trained = false;
+    }
+
+    pub fn dict_bytes(&self) -> usize {
+        self.config.dict_bytes
+    }
+}
+
+#[cfg(feature = "dictionary")]
+pub use self::enabled::*;
+
+#[cfg(feature = "dictionary")]
+mod enabled {
+    use lazy_static::lazy_static;
+    use std::collections::HashMap;
+    use std::io::{self, Read, Write};
+    use std::sync::{Arc, RwLock};
+
+    use super::Dictionary;
+
+    struct Installed {
+        dictionaries: HashMap<u32, Arc<Vec<u8>>>,
+        current: Option<u32>,
+        level: i32,
+    }
+
+    lazy_static! {
+        static ref INSTALLED: RwLock<Installed> = RwLock::new(Installed {
+            dictionaries: HashMap::new(),
+            current: None,
+            level: 3,
+        });
+    }
+
+    /// Train a dictionary of at most `max_bytes` from the samples.
+    pub fn train(samples: &[Vec<u8>], max_bytes: usize) -> io::Result<Dictionary> {
+        zstd::dict::from_samples(samples, max_bytes).map(Dictionary::new)
+    }
+
+    /// Compress with the current dictionary from now on.
+    pub fn install(dictionary: &Dictionary) {
+        let mut installed = INSTALLED.write().unwrap();
+        installed.dictionaries.insert(dictionary.id, Arc::new(dictionary.data.clone()));
+        if installed.current != Some(dictionary.id) {
+            info!("Compressing with dictionary {:?}", dictionary);
+            installed.current = Some(dictionary.id);
+        }
+    }
+
+    /// Make the dictionary available to decompress what was compressed with it, e.g. the
+    /// dictionaries of a recovered log store.
+    pub fn register(dictionary: &Dictionary) {
+        INSTALLED
+            .write()
+            .unwrap()
+            .dictionaries
+            .entry(dictionary.id)
+            .or_insert_with(|| Arc::new(dictionary.data.clone()));
+    }
+
+    pub fn set_level(level: i32) {
+        INSTALLED.write().unwrap().level = level;
+    }
+
+    /// The current dictionary, `None` until one is installed.
+    pub fn current() -> Option<Dictionary> {
+        let installed = INSTALLED.read().unwrap();
+        let id = installed.current?;
+        installed.dictionaries.get(&id).map(|data| Dictionary {
+            id: id,
+            data: data.to_vec(),
+        })
+    }
+
+    /// Compress with the current dictionary, answers its id and the compressed data.
+    pub fn compress(buf: &[u8]) -> io::Result<Option<(u32, Vec<u8>)>> {
+        let (id, data, level) = {
+            let installed = INSTALLED.read().unwrap();
+            match installed.current {
+                Some(id) => (id, installed.dictionaries[&id].clone(), installed.level),
+                None => return Ok(None),
+            }
+        };
+
+        let mut encoder = zstd::stream::write::Encoder::with_dictionary(Vec::with_capacity(buf.len()), level, &data)?;
+        encoder.write_all(buf)?;
+        encoder.finish().map(|compressed| Some((id, compressed)))
+    }
+
+    /// Decompress data compressed with dictionary `id`, up to `limit` bytes.
+    pub fn decompress(id: u32, buf: &[u8], limit: usize) -> io::Result<Vec<u8>> {
+        let data = INSTALLED.read().unwrap().dictionaries.get(&id).cloned().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("data compressed with unknown dictionary {:08x}", id))
+        })?;
+
+        let mut body = Vec::with_capacity(buf.len() * 4);
+        zstd::stream::read::Decoder::with_dictionary(buf, &data)?
+            .take(limit as u64 + 1)
+            .read_to_end(&mut body)?;
+
+        if body.len() > limit {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("decompressed data exceeds the limit of {} bytes", limit),
+            ));
+        }
+        Ok(body)
+    }
+}
+
+#[cfg(not(feature = "dictionary"))]
+pub fn train(_samples: &[Vec<u8>], _max_bytes: usize) -> io::Result<Dictionary> {
+    Err(io::Error::new(io::ErrorKind::Other, "raftor was built without the `dictionary` feature"))
+}
+
+#[cfg(not(feature = "dictionary"))]
+pub fn install(dictionary: &Dictionary) {
+    warn!("Ignoring dictionary {:?}, raftor was built without the `dictionary` feature", dictionary);
+}
+
+#[cfg(not(feature = "dictionary"))]
+pub fn register(_dictionary: &Dictionary) {}
+
+#[cfg(not(feature = "dictionary"))]
+pub fn set_level(_level: i32) {}
+
+#[cfg(not(feature = "dictionary"))]
+pub fn current() -> Option<Dictionary> {
+    None
+}
+
+#[cfg(not(feature = "dictionary"))]
+pub fn compress(_buf: &[u8]) -> io::Result<Option<(u32, Vec<u8>)>> {
+    Ok(None)
+}
+
+#[cfg(not(feature = "dictionary"))]
+pub fn decompress(id: u32, _buf: &[u8], _limit: usize) -> io::Result<Vec<u8>> {
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("data compressed with dictionary {:08x} but raftor was built without the `dictionary` feature", id),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sampler() -> Sampler {
+        Sampler::new(CompressionDictionary {
+            sample_entries: 3,
+            max_entry_bytes: 8,
+            retrain_entries: 2,
+            ..CompressionDictionary::default()
+        })
+    }
+
+    #[test]
+    fn dictionaries_are_identified_by_their_contents() {
+        let dictionary = Dictionary::new(b"dictionary".to_vec());
+        assert!(dictionary.is_valid());
+        assert_eq!(dictionary.id, Dictionary::new(b"dictionary".to_vec()).id);
+
+        let tampered = Dictionary {
+            id: dictionary.id,
+            data: b"dictionarz".to_vec(),
+        };
+        assert!(!tampered.is_valid());
+    }
+
+    #[test]
+    fn a_dictionary_is_due_once_there_are_enough_samples() {
+        let mut sampler = sampler();
+        assert!(!sampler.observe(b""));
+        assert!(!sampler.observe(b"too large to sample"));
+        assert!(!sampler.observe(b"a"));
+        assert!(!sampler.observe(b"b"));
+        assert!(sampler.observe(b"c"));
+        assert_eq!(sampler.take(), vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()]);
+
+        // The next one follows after `retrain_entries`, from the most recent samples
+        assert!(!sampler.observe(b"d"));
+        assert!(sampler.observe(b"e"));
+        assert_eq!(sampler.take(), vec![b"c".to_vec(), b"d".to_vec(), b"e".to_vec()]);
+    }
+
+    #[test]
+    fn a_failed_training_waits_for_renewed_samples() {
+        let mut sampler = sampler();
+        for entry in &[b"a", b"b", b"c"] {
+            sampler.observe(*entry);
+        }
+        sampler.take();
+        sampler.retry();
+
+        assert!(!sampler.observe(b"d"));
+        assert!(!sampler.observe(b"e"));
+        assert!(sampler.observe(b"f"));
+    }
+
+    #[cfg(feature = "dictionary")]
+    #[test]
+    fn data_is_read_back_with_the_dictionary_it_was_compressed_with() {
+        let dictionary = Dictionary::new(b"{\"key\":\"user-\",\"value\":\"".repeat(8));
+        register(&dictionary);
+
+        let data = b"{\"key\":\"user-1\",\"value\":\"a\"}".to_vec();
+        let mut encoder = zstd::stream::write::Encoder::with_dictionary(Vec::new(), 3, &dictionary.data).unwrap();
+        std::io::Write::write_all(&mut encoder, &data).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        assert_eq!(decompress(dictionary.id, &compressed, 1024).unwrap(), data);
+        assert!(decompress(dictionary.id, &compressed, 4).is_err());
+        assert!(decompress(dictionary.id ^ 1, &compressed, 1024).is_err());
+    }
+}
@@ -0,0 +1,222 @@
+use actix::prelude::*;
+use actix_raft::NodeId;
+use actix_web::client::{Client, ClientRequest};
+use actix_web::http::header;
+use futures::future;
+use std::time::{Duration, Instant};
+use tokio::timer::Delay;
+
+use crate::network::{ClusterStatus, ProposeError};
+
+/// How often the nodes are asked for their leader while waiting for the new one
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A failover drill against a running cluster.
+#[derive(Clone, Debug)]
+pub struct DrillConfig {
+    /// Admin addresses of the nodes, the leader must be among them
+    pub targets: Vec<String>,
+    /// Time allowed from the leader stepping down until every node follows a new leader
+    pub sla: Duration,
+    /// Bearer token of the admin listeners, when they're protected by one
+    pub admin_token: Option<String>,
+}
+
+impl DrillConfig {
+    pub fn new(targets: Vec<String>) -> DrillConfig {
+        DrillConfig {
+            targets: targets,
+            sla: Duration::from_secs(10),
+            admin_token: None,
+        }
+    }
+}
+
+/// Leader and term of a node, as it answered `/cluster/status`.
+#[derive(Clone, Debug)]
+struct Observed {
+    node: NodeId,
+    leader: Option<NodeId>,
+    term: u64,
+}
+
+/// Forces the leader to step down through its admin API and checks every node follows a new
+/// leader within the SLA, reporting how long each phase took.
+pub struct FailoverDrill {
+    config: DrillConfig,
+    client: Client,
+    started: Instant,
+    old_leader: Option<(NodeId, u64)>,
+}
+
+impl FailoverDrill {
+    pub fn new(config: DrillConfig) -> FailoverDrill {
+        FailoverDrill {
+            config: config,
+            client: Client::default(),
+            started: Instant::now(),
+            old_leader: None,
+        }
+    }
+
+    /// The request with the admin token, if any.
+    fn authorized(&self, request: ClientRequest) -> ClientRequest {
+        match self.config.admin_token {
+            Some(ref token) => request.header(header::AUTHORIZATION, format!("Bearer {}", token)),
+            None => request,
+        }
+    }
+
+    /// What every target answered, `None` for targets which didn't.
+    fn observe(&self) -> impl Future<Item = Vec<Option<Observed>>, Error = ()> {
+        let requests = self
+            .config
+            .targets
+            .iter()
+            .map(|target| {
+                self.authorized(self.client.get(format!("http://{}/cluster/status", target)))
+                    .send()
+                    .map_err(|_| ())
+                    .and_then(|mut res| res.json::<Result<ClusterStatus, ()>>().map_err(|_| ()))
+                    .then(|res| -> Result<Option<Observed>, ()> {
+                        Ok(match res {
+                            Ok(Ok(status)) => status.raft.map(|raft| Observed {
+                                node: status.node,
+                                leader: raft.leader,
+                                term: raft.term,
+                            }),
+                            _ => None,
+                        })
+                    })
+            })
+            .collect::<Vec<_>>();
+
+        future::join_all(requests)
+    }
+
+    fn finish(&mut self, passed: bool, ctx: &mut Context<Self>) {
+        println!("[drill] {}", if passed { "passed" } else { "FAILED" });
+        ctx.stop();
+        System::current().stop_with_code(if passed { 0 } else { 1 });
+    }
+
+    fn step_down(&mut self, target: String, ctx: &mut Context<Self>) {
+        let request = self
+            .authorized(self.client.post(format!("http://{}/cluster/transfer-leader", target)))
+            .send()
+            .map_err(|err| err.to_string())
+            .and_then(|mut res| {
+                let success = res.status().is_success();
+                res.body().map_err(|err| err.to_string()).and_then(move |body| {
+                    if success {
+                        serde_json::from_slice::<NodeId>(&body).map_err(|err| err.to_string())
+                    } else {
+                        Err(serde_json::from_slice::<ProposeError>(&body)
+                            .map(|err| format!("{:?}", err))
+                            .unwrap_or_else(|_| String::from_utf8_lossy(&body).into_owned()))
+                    }
+                })
+            });
+
+        self.started = Instant::now();
+
+        fut::wrap_future::<_, Self>(request)
+            .then(|res, act, ctx| {
+                let elapsed = Instant::now().duration_since(act.started);
+
+                match res {
+                    Ok(successor) => {
+                        println!("[drill] leader stepped down in {:?}, node {} took over", elapsed, successor);
+                        act.converge(successor, ctx);
+                    }
+                    Err(err) => {
+                        println!("[drill] the leader didn't step down: {}", err);
+                        act.finish(false, ctx);
+                    }
+                }
+                fut::ok(())
+            })
+            .wait(ctx);
+    }
+
+    /// Poll the nodes until all that answer follow `successor` in a later term.
+    fn converge(&mut self, successor: NodeId, ctx: &mut Context<Self>) {
+        let elapsed = Instant::now().duration_since(self.started);
+        if elapsed > self.config.sla {
+            println!("[drill] nodes didn't follow node {} within the SLA of {:?}", successor, self.config.sla);
+            return self.finish(false, ctx);
+        }
+
+        let old_term = self.old_leader.map(|(_, term)| term).unwrap_or(0);
+
+        fut::wrap_future::<_, Self>(self.observe())
+            .then(move |observed, act, ctx| {
+                let observed = observed.unwrap_or_default().into_iter().flatten().collect::<Vec<_>>();
+                let converged = !observed.is_empty()
+                    && observed.iter().all(|node| node.leader == Some(successor) && node.term > old_term);
+
+                if converged {
+                    let elapsed = Instant::now().duration_since(act.started);
+                    let term = observed.iter().map(|node| node.term).max().unwrap_or(0);
+                    println!(
+                        "[drill] {} nodes follow node {} in term {} after {:?} (SLA {:?})",
+                        observed.len(),
+                        successor,
+                        term,
+                        elapsed,
+                        act.config.sla
+                    );
+                    act.finish(elapsed <= act.config.sla, ctx);
+                } else {
+                    ctx.run_later(POLL_INTERVAL, move |act, ctx| act.converge(successor, ctx));
+                }
+                fut::ok(())
+            })
+            .wait(ctx);
+    }
+}
+
+impl Actor for FailoverDrill {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        println!("[drill] failover drill against {}", self.config.targets.join(", "));
+
+        fut::wrap_future::<_, Self>(self.observe())
+            .then(|observed, act, ctx| {
+                let observed = observed.unwrap_or_default();
+                let leader = act
+                    .config
+                    .targets
+                    .iter()
+                    .zip(observed.iter())
+                    .filter_map(|(target, node)| node.as_ref().map(|node| (target, node)))
+                    .find(|(_, node)| node.leader == Some(node.node))
+                    .map(|(target, node)| (target.clone(), node.clone()));
+
+                match leader {
+                    Some((target, leader)) => {
+                        println!("[drill] node {} at {} leads in term {}", leader.node, target, leader.term);
+                        act.old_leader = Some((leader.node, leader.term));
+                        act.step_down(target, ctx);
+                    }
+                    None => {
+                        println!("[drill] none of the nodes is the leader, list the admin address of every node");
+                        act.finish(false, ctx);
+                    }
+                }
+                fut::ok(())
+            })
+            .wait(ctx);
+
+        // a node which doesn't answer at all would hold the drill forever
+        let timeout = self.config.sla * 2 + Duration::from_secs(5);
+        fut::wrap_future::<_, Self>(Delay::new(Instant::now() + timeout))
+            .then(|_, act, ctx| {
+                println!("[drill] gave up after {:?}", Instant::now().duration_since(act.started));
+                act.finish(false, ctx);
+                fut::ok(())
+            })
+            .spawn(ctx);
+    }
+}
@@ -0,0 +1,132 @@
+//! Errors shared across the crate, message specific errors convert into them.
+
+use actix::MailboxError;
+use actix_raft::NodeId;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+use crate::network::{ProposeError, ReadError, RetryReport};
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum RaftorError {
+    /// The node is neither connected nor in the address book
+    UnknownPeer(NodeId),
+    /// The peer is known but its connection failed before it answered
+    PeerUnreachable(NodeId),
+    /// Only the leader can do this, retry on the hinted node
+    NotLeader { leader_hint: Option<NodeId> },
+    /// A message or its answer couldn't be encoded or decoded
+    Serialization(String),
+    Timeout,
+    /// The retry budget of a proposal ran out before a leader took it, it may still be
+    /// committed if the last attempt reached the leader
+    Exhausted(RetryReport),
+    Failed(String),
+}
+
+impl fmt::Display for RaftorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RaftorError::UnknownPeer(id) => write!(f, "unknown peer {}", id),
+            RaftorError::PeerUnreachable(id) => write!(f, "peer {} is unreachable", id),
+            RaftorError::NotLeader { leader_hint: Some(leader) } => write!(f, "not the leader, the leader is {}", leader),
+            RaftorError::NotLeader { leader_hint: None } => write!(f, "not the leader, no leader is known"),
+            RaftorError::Serialization(err) => write!(f, "serialization failed: {}", err),
+            RaftorError::Timeout => write!(f, "timed out"),
+            RaftorError::Exhausted(report) => write!(
+                f,
+                "the proposal wasn't committed after {} attempts in {}ms",
+                report.attempts.len(),
+                report.elapsed_ms
+            ),
+            RaftorError::Failed(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for RaftorError {}
+
+impl From<MailboxError> for RaftorError {
+    fn from(err: MailboxError) -> Self {
+        match err {
+            MailboxError::Timeout => RaftorError::Timeout,
+            MailboxError::Closed => RaftorError::Failed("the actor is gone".to_owned()),
+        }
+    }
+}
+
+impl From<serde_json::Error> for RaftorError {
+    fn from(err: serde_json::Error) -> Self {
+        RaftorError::Serialization(err.to_string())
+    }
+}
+
+impl From<ProposeError> for RaftorError {
+    fn from(err: ProposeError) -> Self {
+        match err {
+            ProposeError::NotLeader { leader_hint } => RaftorError::NotLeader { leader_hint: leader_hint },
+            ProposeError::Rejected => RaftorError::Failed("the proposal was rejected".to_owned()),
+            ProposeError::Storage(err) => RaftorError::Failed(format!("the proposal was lost, {}", err)),
+            ProposeError::Failed => RaftorError::Failed("the proposal failed".to_owned()),
+            ProposeError::Exhausted(report) => RaftorError::Exhausted(report),
+        }
+    }
+}
+
+impl From<ReadError> for RaftorError {
+    fn from(err: ReadError) -> Self {
+        match err {
+            ReadError::NotLeader { leader_hint } => RaftorError::NotLeader { leader_hint: leader_hint },
+            ReadError::NoQuorum | ReadError::Timeout => RaftorError::Timeout,
+            ReadError::Failed => RaftorError::Failed("the read failed".to_owned()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::ProposeAttempt;
+
+    #[test]
+    fn leader_hints_survive_the_conversion() {
+        let err = RaftorError::from(ProposeError::NotLeader { leader_hint: Some(3) });
+        assert_eq!(err, RaftorError::NotLeader { leader_hint: Some(3) });
+        assert_eq!(err.to_string(), "not the leader, the leader is 3");
+
+        let err = RaftorError::from(ReadError::NotLeader { leader_hint: None });
+        assert_eq!(err.to_string(), "not the leader, no leader is known");
+    }
+
+    #[test]
+    fn exhausted_budgets_report_their_attempts() {
+        let report = RetryReport {
+            attempts: vec![
+                ProposeAttempt {
+                    target: None,
+                    at_ms: 0,
+                    error: ProposeError::NotLeader { leader_hint: None },
+                    timed_out: false,
+                },
+                ProposeAttempt {
+                    target: Some(2),
+                    at_ms: 150,
+                    error: ProposeError::NotLeader { leader_hint: Some(3) },
+                    timed_out: false,
+                },
+            ],
+            elapsed_ms: 400,
+        };
+        assert_eq!(report.leader_hint(), Some(3));
+
+        let err = RaftorError::from(ProposeError::Exhausted(report));
+        assert_eq!(err.to_string(), "the proposal wasn't committed after 2 attempts in 400ms");
+    }
+
+    #[test]
+    fn timeouts_are_told_apart() {
+        assert_eq!(RaftorError::from(MailboxError::Timeout), RaftorError::Timeout);
+        assert_eq!(RaftorError::from(ReadError::NoQuorum), RaftorError::Timeout);
+        assert_ne!(RaftorError::from(MailboxError::Closed), RaftorError::Timeout);
+    }
+}
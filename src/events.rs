@@ -0,0 +1,308 @@
+//! Cluster events observed by a node and the `raftor events` observer tailing them.
+
+use actix::prelude::*;
+use actix_raft::NodeId;
+use actix_web::client::Client;
+use futures::Future;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::memory::{MemoryAccount, MemoryArea, MemoryCounters};
+use crate::network::NodeStatus;
+use crate::raft::group::GroupId;
+use crate::raft::timing::now_millis;
+
+/// Events kept by a node, the oldest are dropped first
+const MAX_EVENTS: usize = 1024;
+
+/// How often `raftor events --follow` asks for new events
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EventKind {
+    LeaderChanged { term: u64, leader: Option<NodeId> },
+    MembershipChanged { members: Vec<NodeId>, non_voters: Vec<NodeId> },
+    /// A membership change stalled syncing non-voters was aborted, keeping the members
+    MembershipAborted { members: Vec<NodeId>, abandoned: Vec<NodeId> },
+    SnapshotCreated { index: u64, term: u64 },
+    SnapshotInstalled { index: u64, term: u64 },
+    PeerStatus { peer: NodeId, status: NodeStatus },
+    /// A replica of a group, or its leadership, moving between nodes, see `rebalance`
+    ShardMove { group: GroupId, from: NodeId, to: NodeId, leadership: bool, progress: MoveProgress },
+    /// Something an operator should look at
+    Alert { message: String },
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MoveProgress {
+    Started,
+    Completed,
+    Failed,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ClusterEvent {
+    /// Position in the events of the node, starting at 1
+    pub seq: u64,
+    /// Unix time in milliseconds
+    pub at: u64,
+    /// Node which observed the event
+    pub node: NodeId,
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+impl fmt::Display for ClusterEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} node {}: ", self.at, self.node)?;
+
+        match self.kind {
+            EventKind::LeaderChanged { term, leader: Some(leader) } => write!(f, "leader {} elected in term {}", leader, term),
+            EventKind::LeaderChanged { term, leader: None } => write!(f, "no leader in term {}", term),
+            EventKind::MembershipChanged { ref members, ref non_voters } => {
+                write!(f, "members {:?}, non voters {:?}", members, non_voters)
+            }
+            EventKind::MembershipAborted { ref members, ref abandoned } => {
+                write!(f, "membership change aborted keeping {:?}, abandoning {:?}", members, abandoned)
+            }
+            EventKind::SnapshotCreated { index, term } => write!(f, "snapshot created through {} (term {})", index, term),
+            EventKind::SnapshotInstalled { index, term } => write!(f, "snapshot installed through {} (term {})", index, term),
+            EventKind::PeerStatus { peer, ref status } => write!(f, "peer {} {:?}", peer, status),
+            EventKind::ShardMove { group, from, to, leadership: false, progress } => {
+                write!(f, "replica of group {} moving from {} to {} {:?}", group, from, to, progress)
+            }
+            EventKind::ShardMove { group, from, to, leadership: true, progress } => {
+                write!(f, "leadership of group {} moving from {} towards {} {:?}", group, from, to, progress)
+            }
+            EventKind::Alert { ref message } => write!(f, "ALERT {}", message),
+        }
+    }
+}
+
+struct Events {
+    node: NodeId,
+    next_seq: u64,
+    events: VecDeque<ClusterEvent>,
+    memory: MemoryAccount,
+}
+
+fn event_size(event: &ClusterEvent) -> usize {
+    serde_json::to_vec(event).map(|data| data.len()).unwrap_or(0)
+}
+
+/// Recent events of this node, shared by the actors observing them.
+#[derive(Clone)]
+pub struct EventLog(Arc<Mutex<Events>>);
+
+impl EventLog {
+    pub fn new(node: NodeId) -> EventLog {
+        EventLog(Arc::new(Mutex::new(Events {
+            node: node,
+            next_seq: 1,
+            events: VecDeque::new(),
+            memory: Arc::new(MemoryCounters::default()),
+        })))
+    }
+
+    /// Account the kept events to the memory of the node, the oldest are dropped over its
+    /// `event_bytes` limit.
+    pub fn set_memory(&self, memory: MemoryAccount) {
+        let mut inner = self.0.lock().unwrap();
+
+        let kept = inner.events.iter().map(event_size).sum();
+        inner.memory.shrink(MemoryArea::Events, kept);
+        memory.grow(MemoryArea::Events, kept);
+        inner.memory = memory;
+    }
+
+    pub fn record(&self, kind: EventKind) {
+        let mut inner = self.0.lock().unwrap();
+
+        let event = ClusterEvent {
+            seq: inner.next_seq,
+            at: now_millis(),
+            node: inner.node,
+            kind: kind,
+        };
+        inner.next_seq += 1;
+
+        let size = event_size(&event);
+        while !inner.events.is_empty() {
+            let full = inner.events.len() >= MAX_EVENTS;
+            if !full && !inner.memory.exceeds(MemoryArea::Events, size) {
+                break;
+            }
+
+            let dropped = inner.events.pop_front().unwrap();
+            inner.memory.shrink(MemoryArea::Events, event_size(&dropped));
+            if !full {
+                inner.memory.shed(MemoryArea::Events);
+            }
+        }
+        inner.memory.grow(MemoryArea::Events, size);
+        inner.events.push_back(event);
+    }
+
+    pub fn alert(&self, message: String) {
+        self.record(EventKind::Alert { message: message });
+    }
+
+    /// Events recorded after `seq`, as many as are still kept.
+    pub fn since(&self, seq: u64) -> Vec<ClusterEvent> {
+        let inner = self.0.lock().unwrap();
+        inner.events.iter().filter(|event| event.seq > seq).cloned().collect()
+    }
+}
+
+/// Options of `raftor events`.
+#[derive(Clone, Debug)]
+pub struct ObserverConfig {
+    /// Public address of the node to observe
+    pub target: String,
+    /// Keep polling for new events instead of exiting once printed
+    pub follow: bool,
+    /// Print events as JSON lines
+    pub json: bool,
+}
+
+/// Prints the events of a node, polling it for new ones when following.
+pub struct Observer {
+    config: ObserverConfig,
+    client: Client,
+    last_seq: u64,
+}
+
+impl Observer {
+    pub fn new(config: ObserverConfig) -> Observer {
+        Observer {
+            config: config,
+            client: Client::default(),
+            last_seq: 0,
+        }
+    }
+
+    fn poll(&mut self, ctx: &mut Context<Self>) {
+        let request = self
+            .client
+            .get(format!("http://{}/admin/events?from={}", self.config.target, self.last_seq))
+            .send()
+            .map_err(|err| err.to_string())
+            .and_then(|mut res| res.json::<Vec<ClusterEvent>>().map_err(|err| err.to_string()));
+
+        fut::wrap_future::<_, Self>(request)
+            .then(|res, act, ctx| {
+                match res {
+                    Ok(events) => {
+                        if events.first().map(|event| event.seq > act.last_seq + 1).unwrap_or(false) && act.last_seq > 0 {
+                            eprintln!("[events] missed events {}..{}", act.last_seq + 1, events[0].seq - 1);
+                        }
+
+                        for event in events {
+                            act.last_seq = event.seq;
+                            if act.config.json {
+                                println!("{}", serde_json::to_string(&event).unwrap());
+                            } else {
+                                println!("{}", event);
+                            }
+                        }
+                    }
+                    Err(err) => eprintln!("[events] failed to reach {}: {}", act.config.target, err),
+                }
+
+                if act.config.follow {
+                    ctx.run_later(POLL_INTERVAL, |act, ctx| act.poll(ctx));
+                } else {
+                    System::current().stop();
+                }
+
+                fut::ok(())
+            })
+            .spawn(ctx);
+    }
+}
+
+impl Actor for Observer {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        self.poll(ctx);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::MemoryLimits;
+
+    fn alerts(events: &[ClusterEvent]) -> Vec<String> {
+        events
+            .iter()
+            .map(|event| match event.kind {
+                EventKind::Alert { ref message } => message.clone(),
+                _ => panic!("unexpected event {}", event),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn events_are_numbered_from_one() {
+        let log = EventLog::new(1);
+        log.alert("a".to_owned());
+        log.alert("b".to_owned());
+        log.alert("c".to_owned());
+
+        let events = log.since(0);
+        assert_eq!(events.iter().map(|event| event.seq).collect::<Vec<_>>(), vec![1, 2, 3]);
+        assert_eq!(alerts(&log.since(1)), vec!["b", "c"]);
+        assert!(log.since(3).is_empty());
+    }
+
+    #[test]
+    fn the_oldest_events_are_dropped() {
+        let log = EventLog::new(1);
+        for i in 0..MAX_EVENTS + 2 {
+            log.alert(i.to_string());
+        }
+
+        let events = log.since(0);
+        assert_eq!(events.len(), MAX_EVENTS);
+        assert_eq!(events[0].seq, 3);
+    }
+
+    #[test]
+    fn events_over_the_memory_limit_are_shed() {
+        let log = EventLog::new(1);
+        log.alert("a".to_owned());
+        let size = event_size(&log.since(0)[0]);
+
+        let memory = MemoryCounters::new(MemoryLimits {
+            event_bytes: Some(size * 2),
+            ..MemoryLimits::default()
+        });
+        log.set_memory(memory.clone());
+        assert_eq!(memory.used(MemoryArea::Events), size);
+
+        log.alert("b".to_owned());
+        log.alert("c".to_owned());
+        assert_eq!(alerts(&log.since(0)), vec!["b", "c"]);
+        assert_eq!(memory.used(MemoryArea::Events), size * 2);
+        assert_eq!(memory.usage().iter().find(|usage| usage.area == "events").unwrap().shed, 1);
+    }
+
+    #[test]
+    fn events_serialize_with_their_type() {
+        let log = EventLog::new(2);
+        log.record(EventKind::LeaderChanged { term: 3, leader: Some(2) });
+        let event = log.since(0).remove(0);
+
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["type"], "leader_changed");
+        assert_eq!(json["leader"], 2);
+        assert!(event.to_string().ends_with("node 2: leader 2 elected in term 3"));
+    }
+}
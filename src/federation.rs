@@ -0,0 +1,167 @@
+//! Registry of raftor clusters kept by a designated meta-cluster.
+//!
+//! Every federated cluster periodically announces its name, members, leader and the
+//! namespaces it owns to the meta-cluster, which replicates the records through its own log.
+//! Clients ask any meta-cluster node which cluster owns a namespace. Announcements carry the
+//! bearer token of the meta-cluster, and a record is stamped with the time the leader of the
+//! meta-cluster proposed it: clusters which stopped announcing themselves drop out of lookups
+//! once the record is `record_ttl_secs` old, whatever their own clocks say.
+
+use actix::prelude::*;
+use actix_raft::NodeId;
+use actix_web::client::Client;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::config::{FederationConfig, NodeInfo};
+use crate::network::{GetRaftMetrics, Network};
+use crate::raft::timing::now_millis;
+
+/// What a cluster announces about itself.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ClusterRecord {
+    pub name: String,
+    pub members: Vec<NodeInfo>,
+    pub leader: Option<NodeId>,
+    pub namespaces: Vec<String>,
+    /// Unix time in milliseconds the meta-cluster registered the announcement at, the time
+    /// the announcer sends is replaced
+    pub updated_at: u64,
+}
+
+/// Clusters known to the meta-cluster, rebuilt from its applied entries.
+#[derive(Default)]
+pub struct FederationRegistry {
+    clusters: BTreeMap<String, ClusterRecord>,
+}
+
+impl FederationRegistry {
+    /// Register an announcement committed at `at`, the unix time in milliseconds the leader
+    /// of the meta-cluster stamped the entry with.
+    pub fn register(&mut self, mut record: ClusterRecord, at: u64) {
+        record.updated_at = at;
+        self.clusters.insert(record.name.clone(), record);
+    }
+
+    /// The cluster owning the given namespace among those which announced themselves since
+    /// `since`, the most recent announcement wins when two clusters claim the same namespace.
+    pub fn lookup(&self, namespace: &str, since: u64) -> Option<&ClusterRecord> {
+        self.clusters
+            .values()
+            .filter(|record| record.updated_at >= since)
+            .filter(|record| record.namespaces.iter().any(|ns| ns == namespace))
+            .max_by_key(|record| record.updated_at)
+    }
+
+    /// Clusters which announced themselves since `since`.
+    pub fn clusters(&self, since: u64) -> Vec<ClusterRecord> {
+        self.clusters.values().filter(|record| record.updated_at >= since).cloned().collect()
+    }
+}
+
+/// Announces the local cluster to the meta-cluster.
+pub struct Federation {
+    config: FederationConfig,
+    members: Vec<NodeInfo>,
+    net: Addr<Network>,
+    client: Client,
+}
+
+impl Federation {
+    pub fn new(config: FederationConfig, members: Vec<NodeInfo>, net: Addr<Network>) -> Federation {
+        Federation {
+            config: config,
+            members: members,
+            net: net,
+            client: Client::default(),
+        }
+    }
+
+    fn announce(&mut self, ctx: &mut Context<Self>) {
+        fut::wrap_future::<_, Self>(self.net.send(GetRaftMetrics))
+            .map_err(|_, _, _| ())
+            .and_then(|metrics, act, _| {
+                let metrics = match metrics {
+                    Ok(Some(metrics)) => metrics,
+                    _ => return fut::Either::A(fut::ok(())),
+                };
+
+                // only the leader speaks for its cluster
+                if metrics.current_leader != Some(metrics.id) {
+                    return fut::Either::A(fut::ok(()));
+                }
+
+                let record = ClusterRecord {
+                    name: act.config.cluster_name.clone(),
+                    members: act.members.clone(),
+                    leader: metrics.current_leader,
+                    namespaces: act.config.namespaces.clone(),
+                    updated_at: now_millis(),
+                };
+
+                let request = act.client.put(format!("http://{}/federation/clusters", act.config.meta_host));
+                let request = match act.config.token {
+                    Some(ref token) => request.bearer_auth(token),
+                    None => request,
+                };
+
+                fut::Either::B(
+                    fut::wrap_future::<_, Self>(request.send_json(&record))
+                        .map_err(|err, _, _| error!("Failed to announce cluster to the federation: {:?}", err))
+                        .map(|res, _, _| {
+                            if !res.status().is_success() {
+                                error!("The federation refused the announcement of this cluster: {}", res.status());
+                            }
+                        }),
+                )
+            })
+            .spawn(ctx);
+    }
+}
+
+impl Actor for Federation {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        let interval = std::time::Duration::from_secs(self.config.announce_secs);
+        ctx.run_interval(interval, |act, ctx| act.announce(ctx));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(name: &str, namespaces: &[&str], updated_at: u64) -> ClusterRecord {
+        ClusterRecord {
+            name: name.to_owned(),
+            members: Vec::new(),
+            leader: None,
+            namespaces: namespaces.iter().map(|ns| ns.to_string()).collect(),
+            updated_at: updated_at,
+        }
+    }
+
+    #[test]
+    fn announced_times_are_replaced() {
+        let mut registry = FederationRegistry::default();
+        // a clock far ahead doesn't keep the record of the announcer alive
+        registry.register(record("eu-1", &["rooms-eu"], u64::max_value()), 1_000);
+
+        assert_eq!(registry.lookup("rooms-eu", 0).map(|record| record.updated_at), Some(1_000));
+        assert_eq!(registry.lookup("rooms-eu", 1_001), None);
+        assert!(registry.clusters(1_001).is_empty());
+    }
+
+    #[test]
+    fn latest_live_claim_wins() {
+        let mut registry = FederationRegistry::default();
+        registry.register(record("eu-1", &["rooms"], 0), 1_000);
+        registry.register(record("eu-2", &["rooms"], 0), 2_000);
+
+        assert_eq!(registry.lookup("rooms", 0).map(|record| record.name.as_str()), Some("eu-2"));
+        registry.register(record("eu-1", &["rooms"], 0), 3_000);
+        assert_eq!(registry.lookup("rooms", 2_500).map(|record| record.name.as_str()), Some("eu-1"));
+        assert_eq!(registry.clusters(2_500).len(), 1);
+    }
+}
@@ -0,0 +1,612 @@
+//! Versioned JSON-over-TCP client protocol for thin clients outside Rust.
+//!
+//! Every frame is one JSON object on its own line. Clients open with
+//! `{"id": 1, "op": "hello", "version": 1}` and may pipeline requests after it, answers carry
+//! the id of their request and may arrive out of order. Every answer carries the cluster epoch
+//! of the node, clients refresh what they cached about the cluster once it moved:
+//!
+//! ```text
+//! {"id": 2, "op": "propose", "session": "c-17", "seq": 3, "data": {"App": [42]}}
+//! {"id": 2, "epoch": 12, "ok": {"index": 17}}
+//! {"id": 3, "op": "read", "key": "alice", "session": "c-17"}
+//! {"id": 3, "epoch": 12, "ok": {"node": 1234, "addr": "10.0.0.2:8080", "watermark": 17}}
+//! {"id": 4, "op": "status"}
+//! {"id": 5, "op": "propose", "data": {"App": [42]}}
+//! {"id": 5, "epoch": 12, "error": {"code": "not_leader", "leader": 5678, "leader_addr": "10.0.0.3:8080"}}
+//! ```
+//!
+//! `data` is a command of the application state machine as encoded by `app::command`, here the
+//! MessagePack encoding of `42`. Other commands, e.g. of the ring, are the nodes' own and
+//! answered with `bad_request`.
+//!
+//! Proposals are admitted as the client of the connection. On a node with `client_tokens`,
+//! `hello` carries the token of the client, e.g. `"token": "s3cret"`, and is answered with
+//! `unauthorized` without a known one. Without tokens every JSON client is `json`.
+//!
+//! A request may carry a routing key in `route`. With a `Router` set on the node, proposals
+//! whose key routes to a raft group are proposed to that group directly:
+//!
+//! ```text
+//! {"id": 6, "route": "tenant-7", "op": "propose", "data": {"App": [42]}}
+//! {"id": 6, "epoch": 12, "ok": {"index": 3}}
+//! ```
+//!
+//! Lines which aren't a request are answered with `bad_request` and the id they carried, if
+//! any. See `conformance` for the reference cases every implementation must pass.
+
+use actix::prelude::*;
+use actix_raft::{
+    messages::{ClientError, ClientPayloadResponse},
+    NodeId,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::atomic::Ordering;
+use tokio::codec::{FramedRead, LinesCodec};
+use tokio::io::{AsyncRead, WriteHalf};
+use tokio::net::TcpListener;
+
+use crate::network::{GetClusterStatus, GetNode, GetNodes, GetRaftMetrics, Network, PeerStream};
+use crate::network::ProposeError;
+use crate::raft::group::{GroupId, GroupProposal, RaftGroups, SharedRouter};
+use crate::raft::{affinity, app::ClientCommand, storage::{ClusterEpoch, MemoryStorageError}, ClientProposal, GetClientSession, RaftClient};
+use crate::utils;
+
+/// Version spoken by this node, clients announce theirs in `hello`
+pub const JSON_PROTOCOL_VERSION: u32 = 1;
+
+/// Longest line accepted, longer ones close the connection
+const MAX_LINE_BYTES: usize = 1024 * 1024;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct JsonRequest {
+    pub id: u64,
+    /// Application routing key, picks the raft group of a proposal through the `Router`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub route: Option<String>,
+    #[serde(flatten)]
+    pub op: JsonOp,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum JsonOp {
+    /// Must come first, answered with `Hello`. The token names the client of the connection
+    /// when the node has `client_tokens`.
+    Hello {
+        version: u32,
+        #[serde(default)]
+        token: Option<String>,
+    },
+    /// Propose a command, answered with `Proposed` once applied. The session and sequence
+    /// number make its idempotency key, as with the HTTP gateway.
+    Propose {
+        data: ClientCommand,
+        #[serde(default)]
+        session: Option<String>,
+        #[serde(default)]
+        seq: Option<u64>,
+    },
+    /// Find the node owning a key, answered with `Located`
+    Read {
+        key: String,
+        /// Client session whose writes the read must see
+        #[serde(default)]
+        session: Option<String>,
+    },
+    /// Answered with the `ClusterStatus` of the node
+    Status,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct JsonResponse {
+    /// `None` when the request carried no id
+    pub id: Option<u64>,
+    /// Cluster epoch of the node when it answered, see `ClusterEpoch`
+    #[serde(default)]
+    pub epoch: u64,
+    #[serde(flatten)]
+    pub reply: JsonReply,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum JsonReply {
+    Ok(serde_json::Value),
+    Error(JsonError),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The line isn't a request of this version
+    BadRequest,
+    /// A request came before `hello`
+    HandshakeRequired,
+    /// `hello` carried no token of the node's `client_tokens`
+    Unauthorized,
+    UnsupportedVersion,
+    /// The proposal couldn't be forwarded to the leader, retry on the hinted node
+    NotLeader,
+    /// The command was rejected by a validator or the state machine
+    Rejected,
+    /// The storage of the leader faulted, the command was lost. Carries the cause and the
+    /// index whose append failed.
+    StorageFault,
+    /// No node owns the key
+    NotFound,
+    Unavailable,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct JsonError {
+    pub code: ErrorCode,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub leader: Option<NodeId>,
+    /// Public address of the leader, as in the redirects of the HTTP gateway
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub leader_addr: Option<String>,
+    /// Log index whose append failed, with `storage_fault`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub index: Option<u64>,
+}
+
+impl JsonError {
+    fn new(code: ErrorCode) -> JsonError {
+        JsonError {
+            code: code,
+            message: None,
+            leader: None,
+            leader_addr: None,
+            index: None,
+        }
+    }
+
+    fn with_message<M: ToString>(mut self, message: M) -> JsonError {
+        self.message = Some(message.to_string());
+        self
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Hello {
+    pub version: u32,
+    pub node: NodeId,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Proposed {
+    pub index: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Located {
+    pub node: NodeId,
+    pub addr: String,
+    /// Index of the last write of the session, when the read named one
+    pub watermark: Option<u64>,
+}
+
+/// Actors the sessions answer requests with.
+#[derive(Clone)]
+pub struct JsonHandles {
+    pub id: NodeId,
+    pub raft: Addr<RaftClient>,
+    pub app_net: Addr<Network>,
+    pub cluster_net: Addr<Network>,
+    pub groups: Addr<RaftGroups>,
+    pub router: SharedRouter,
+    pub epoch: ClusterEpoch,
+    /// Bearer tokens of the clients, see `ConfigSchema::client_tokens`
+    pub client_tokens: BTreeMap<String, String>,
+}
+
+/// Accepts JSON client connections, see `json_client_addr`. Without an address it only takes
+/// the connections the cluster network accepts over TLS.
+pub struct JsonListener {
+    addr: Option<String>,
+    handles: JsonHandles,
+}
+
+impl JsonListener {
+    pub fn new(addr: Option<String>, handles: JsonHandles) -> JsonListener {
+        JsonListener {
+            addr: addr,
+            handles: handles,
+        }
+    }
+}
+
+impl Actor for JsonListener {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        let addr = match self.addr {
+            Some(ref addr) => addr.clone(),
+            None => return,
+        };
+        let listener = match addr.parse().map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err)).and_then(|addr| TcpListener::bind(&addr)) {
+            Ok(listener) => listener,
+            Err(err) => {
+                error!("Can't serve JSON clients on {}: {}", addr, err);
+                ctx.stop();
+                return;
+            }
+        };
+
+        info!("Serving JSON clients on {}", addr);
+        ctx.add_message_stream(listener.incoming().map_err(|_| ()).map(|stream| JsonConnect(PeerStream::Plain(stream))));
+    }
+}
+
+/// A client connection, accepted on `json_client_addr` or on the TLS port of the cluster
+/// network with the `raftor-client` ALPN protocol.
+#[derive(Message)]
+pub struct JsonConnect(pub PeerStream);
+
+impl Handler<JsonConnect> for JsonListener {
+    type Result = ();
+
+    fn handle(&mut self, msg: JsonConnect, _ctx: &mut Context<Self>) {
+        let handles = self.handles.clone();
+
+        JsonSession::create(move |ctx| {
+            let (r, w) = msg.0.split();
+            JsonSession::add_stream(FramedRead::new(r, LinesCodec::new_with_max_length(MAX_LINE_BYTES)), ctx);
+            JsonSession {
+                framed: actix::io::FramedWrite::new(w, LinesCodec::new(), ctx),
+                handles: handles,
+                version: None,
+                client: None,
+            }
+        });
+    }
+}
+
+/// A JSON client connection.
+struct JsonSession {
+    framed: actix::io::FramedWrite<WriteHalf<PeerStream>, LinesCodec>,
+    handles: JsonHandles,
+    /// Version agreed in `hello`
+    version: Option<u32>,
+    /// Client the token of `hello` named, proposals are admitted as it
+    client: Option<String>,
+}
+
+impl Actor for JsonSession {
+    type Context = Context<Self>;
+}
+
+impl actix::io::WriteHandler<io::Error> for JsonSession {}
+
+impl StreamHandler<String, io::Error> for JsonSession {
+    fn handle(&mut self, line: String, ctx: &mut Context<Self>) {
+        if line.trim().is_empty() {
+            return;
+        }
+
+        let req = match serde_json::from_str::<JsonRequest>(&line) {
+            Ok(req) => req,
+            Err(err) => {
+                // answer with the id when the line carried one
+                let id = serde_json::from_str::<serde_json::Value>(&line)
+                    .ok()
+                    .and_then(|value| value.get("id").and_then(|id| id.as_u64()));
+                return self.reply(id, Err(JsonError::new(ErrorCode::BadRequest).with_message(err)));
+            }
+        };
+        let id = req.id;
+        let group = self.route(req.route.as_ref());
+
+        match (req.op, self.version) {
+            (JsonOp::Hello { version, token }, _) => {
+                if version != JSON_PROTOCOL_VERSION {
+                    let err = JsonError::new(ErrorCode::UnsupportedVersion)
+                        .with_message(format!("this node speaks version {}", JSON_PROTOCOL_VERSION));
+                    return self.reply(Some(id), Err(err));
+                }
+                let client = match utils::token_client(token.as_ref().map(String::as_str), &self.handles.client_tokens, "json") {
+                    Some(client) => client,
+                    None => return self.reply(Some(id), Err(JsonError::new(ErrorCode::Unauthorized))),
+                };
+                self.version = Some(version);
+                self.client = Some(client);
+                let hello = Hello {
+                    version: version,
+                    node: self.handles.id,
+                };
+                self.reply(Some(id), Ok(json(&hello)));
+            }
+            (_, None) => self.reply(Some(id), Err(JsonError::new(ErrorCode::HandshakeRequired))),
+            (JsonOp::Propose { data, session, seq }, Some(_)) => {
+                let client = self.client.clone().unwrap_or_default();
+                match group {
+                    Some(group) => self.propose_to_group(id, group, client, data, session, seq, ctx),
+                    None => self.propose(id, client, data, session, seq, ctx),
+                }
+            }
+            (JsonOp::Read { key, session }, Some(_)) => self.read(id, key, session, ctx),
+            (JsonOp::Status, Some(_)) => {
+                fut::wrap_future::<_, Self>(self.handles.cluster_net.send(GetClusterStatus))
+                    .then(move |res, act, _| {
+                        match res {
+                            Ok(Ok(status)) => act.reply(Some(id), Ok(json(&status))),
+                            _ => act.reply(Some(id), Err(JsonError::new(ErrorCode::Unavailable))),
+                        }
+                        fut::ok(())
+                    })
+                    .spawn(ctx);
+            }
+        }
+    }
+}
+
+impl JsonSession {
+    fn reply(&mut self, id: Option<u64>, res: Result<serde_json::Value, JsonError>) {
+        let reply = match res {
+            Ok(value) => JsonReply::Ok(value),
+            Err(err) => JsonReply::Error(err),
+        };
+        let response = JsonResponse {
+            id: id,
+            epoch: self.handles.epoch.load(Ordering::SeqCst),
+            reply: reply,
+        };
+        match serde_json::to_string(&response) {
+            Ok(line) => self.framed.write(line),
+            Err(err) => error!("Error encoding a JSON client response: {}", err),
+        }
+    }
+
+    /// Group owning the routing key, `None` without a key or router or for the cluster raft.
+    fn route(&self, key: Option<&String>) -> Option<GroupId> {
+        let key = key?;
+        let router = self.handles.router.read().unwrap();
+        router.as_ref()?.route(key)
+    }
+
+    fn propose(&mut self, id: u64, client: String, data: ClientCommand, session: Option<String>, seq: Option<u64>, ctx: &mut Context<Self>) {
+        fut::wrap_future::<_, Self>(self.handles.raft.send(proposal(client, data, session, seq)))
+        .then(move |res, act: &mut Self, _| {
+            let (hint, code) = match res {
+                Ok(Ok(ClientPayloadResponse::Applied { index, .. })) | Ok(Ok(ClientPayloadResponse::Committed { index })) => {
+                    act.reply(Some(id), Ok(json(&Proposed { index: index })));
+                    return fut::Either::A(fut::ok(()));
+                }
+                Ok(Err(ClientError::Application(err))) => {
+                    act.reply(Some(id), Err(application_error(err)));
+                    return fut::Either::A(fut::ok(()));
+                }
+                Ok(Err(ClientError::ForwardToLeader { leader, .. })) => (leader, ErrorCode::NotLeader),
+                _ => (None, ErrorCode::Unavailable),
+            };
+
+            // point the client at the leader if we know it
+            fut::Either::B(act.leader_hint(hint).then(move |leader, act: &mut Self, _| {
+                act.reply(Some(id), Err(leader_error(code, leader.unwrap_or(None))));
+                fut::ok(())
+            }))
+        })
+        .spawn(ctx);
+    }
+
+    /// Propose to a raft group of this node, which forwards to the group leader if it isn't.
+    fn propose_to_group(
+        &mut self,
+        id: u64,
+        group: GroupId,
+        client: String,
+        data: ClientCommand,
+        session: Option<String>,
+        seq: Option<u64>,
+        ctx: &mut Context<Self>,
+    ) {
+        let msg = GroupProposal {
+            group: group,
+            proposal: proposal(client, data, session, seq),
+        };
+
+        fut::wrap_future::<_, Self>(self.handles.groups.send(msg))
+            .then(move |res, act: &mut Self, _| {
+                let leader = match res {
+                    Ok(Ok(index)) => {
+                        act.reply(Some(id), Ok(json(&Proposed { index: index })));
+                        return fut::Either::A(fut::ok(()));
+                    }
+                    Ok(Err(ProposeError::Rejected)) => {
+                        act.reply(Some(id), Err(JsonError::new(ErrorCode::Rejected)));
+                        return fut::Either::A(fut::ok(()));
+                    }
+                    Ok(Err(ProposeError::Storage(err))) => {
+                        act.reply(Some(id), Err(application_error(err)));
+                        return fut::Either::A(fut::ok(()));
+                    }
+                    Ok(Err(ProposeError::NotLeader { leader_hint: Some(leader) })) => leader,
+                    Ok(Err(ProposeError::NotLeader { leader_hint: None })) => {
+                        let err = JsonError::new(ErrorCode::NotLeader).with_message(format!("group {} has no leader", group));
+                        act.reply(Some(id), Err(err));
+                        return fut::Either::A(fut::ok(()));
+                    }
+                    _ => {
+                        act.reply(Some(id), Err(JsonError::new(ErrorCode::Unavailable)));
+                        return fut::Either::A(fut::ok(()));
+                    }
+                };
+
+                // the group leader isn't the cluster leader, only look its address up
+                fut::Either::B(fut::wrap_future::<_, Self>(act.handles.cluster_net.send(GetNodes)).then(move |nodes, act: &mut Self, _| {
+                    let addr = nodes
+                        .ok()
+                        .and_then(|nodes| nodes.ok())
+                        .and_then(|nodes| nodes.get(&leader).map(|info| info.public_addr.clone()));
+                    act.reply(Some(id), Err(leader_error(ErrorCode::NotLeader, Some((leader, addr)))));
+                    fut::ok(())
+                }))
+            })
+            .spawn(ctx);
+    }
+
+    fn read(&mut self, id: u64, key: String, session: Option<String>, ctx: &mut Context<Self>) {
+        let watermark: Box<dyn Future<Item = Option<u64>, Error = ()>> = match session {
+            Some(session) => Box::new(
+                self.handles
+                    .raft
+                    .send(GetClientSession(session))
+                    .map_err(|_| ())
+                    .and_then(|res| res.map(|session| session.map(|session| session.watermark))),
+            ),
+            None => Box::new(futures::future::ok(None)),
+        };
+        let locate = self.handles.app_net.send(GetNode(key));
+
+        fut::wrap_future::<_, Self>(watermark.join(locate.map_err(|_| ())))
+            .then(move |res, act, _| {
+                match res {
+                    Ok((watermark, Ok((node, addr)))) => {
+                        let located = Located {
+                            node: node,
+                            addr: addr,
+                            watermark: watermark,
+                        };
+                        act.reply(Some(id), Ok(json(&located)));
+                    }
+                    Ok((_, Err(_))) => act.reply(Some(id), Err(JsonError::new(ErrorCode::NotFound))),
+                    Err(_) => act.reply(Some(id), Err(JsonError::new(ErrorCode::Unavailable))),
+                }
+                fut::ok(())
+            })
+            .spawn(ctx);
+    }
+
+    /// Id and public address of the leader, the hinted one or the one this node knows of.
+    fn leader_hint(&self, hint: Option<NodeId>) -> impl ActorFuture<Actor = Self, Item = Option<(NodeId, Option<String>)>, Error = ()> {
+        let own = self.handles.id;
+
+        fut::wrap_future::<_, Self>(self.handles.cluster_net.send(GetRaftMetrics).join(self.handles.cluster_net.send(GetNodes)))
+            .map(move |(metrics, nodes), _, _| {
+                let leader = hint
+                    .or_else(|| metrics.ok().and_then(|metrics| metrics).and_then(|metrics| metrics.current_leader))
+                    .filter(|leader| *leader != own)?;
+                let addr = nodes.ok().and_then(|nodes| nodes.get(&leader).map(|info| info.public_addr.clone()));
+                Some((leader, addr))
+            })
+            .map_err(|_, _, _| ())
+    }
+}
+
+/// Proposal of a client, keyed by its session and sequence number when it named them.
+fn proposal(client: String, data: ClientCommand, session: Option<String>, seq: Option<u64>) -> ClientProposal {
+    let key = match (session, seq) {
+        (Some(session), Some(seq)) => Some(affinity::session_key(&session, seq)),
+        _ => None,
+    };
+
+    ClientProposal {
+        client: client,
+        key: key,
+        data: data.into(),
+        deadline_ms: None,
+    }
+}
+
+/// A storage fault losing the command, or its rejection.
+fn application_error(err: MemoryStorageError) -> JsonError {
+    if err.fault.is_none() {
+        return JsonError::new(ErrorCode::Rejected).with_message(&err);
+    }
+
+    let mut json_err = JsonError::new(ErrorCode::StorageFault).with_message(&err);
+    json_err.index = err.index;
+    json_err
+}
+
+fn leader_error(code: ErrorCode, leader: Option<(NodeId, Option<String>)>) -> JsonError {
+    let mut err = JsonError::new(code);
+    if let Some((leader, addr)) = leader {
+        err.leader = Some(leader);
+        err.leader_addr = addr;
+    }
+    err
+}
+
+fn json<T: Serialize>(value: &T) -> serde_json::Value {
+    serde_json::to_value(value).unwrap_or(serde_json::Value::Null)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raft::storage::StorageFault;
+    use serde_json::json;
+
+    #[test]
+    fn requests_parse_as_documented() {
+        let request = serde_json::from_str::<JsonRequest>(
+            r#"{"id": 2, "op": "propose", "session": "c-17", "seq": 3, "data": {"App": [42]}}"#,
+        )
+        .unwrap();
+        match request.op {
+            JsonOp::Propose { data, session, seq } => {
+                assert_eq!(seq, Some(3));
+                assert_eq!(session, Some("c-17".to_owned()));
+                assert_eq!(data, ClientCommand::App(vec![42]));
+            }
+            op => panic!("unexpected {:?}", op),
+        }
+
+        // only application commands can be proposed
+        assert!(serde_json::from_str::<JsonRequest>(r#"{"id": 5, "op": "propose", "data": {"Add": 42}}"#).is_err());
+
+        match serde_json::from_str::<JsonRequest>(r#"{"id": 1, "op": "hello", "version": 1, "token": "s3cret"}"#).unwrap().op {
+            JsonOp::Hello { version, token } => assert_eq!((version, token), (1, Some("s3cret".to_owned()))),
+            op => panic!("unexpected {:?}", op),
+        }
+        assert!(serde_json::from_str::<JsonRequest>(r#"{"id": 4, "op": "status"}"#).is_ok());
+        assert!(serde_json::from_str::<JsonRequest>(r#"{"id": 7, "op": "drop_table"}"#).is_err());
+    }
+
+    #[test]
+    fn requests_name_the_group_they_are_routed_to() {
+        let request = serde_json::from_str::<JsonRequest>(r#"{"id": 6, "route": "tenant-7", "op": "propose", "data": {"App": [42]}}"#).unwrap();
+        assert_eq!(request.route.as_ref().map(String::as_str), Some("tenant-7"));
+        match request.op {
+            JsonOp::Propose { data, .. } => assert_eq!(data, ClientCommand::App(vec![42])),
+            op => panic!("unexpected {:?}", op),
+        }
+    }
+
+    #[test]
+    fn errors_serialize_as_documented() {
+        let response = JsonResponse {
+            id: Some(5),
+            epoch: 12,
+            reply: JsonReply::Error(leader_error(ErrorCode::NotLeader, Some((5678, Some("10.0.0.3:8080".to_owned()))))),
+        };
+        assert_eq!(
+            serde_json::to_value(&response).unwrap(),
+            json!({"id": 5, "epoch": 12, "error": {"code": "not_leader", "leader": 5678, "leader_addr": "10.0.0.3:8080"}})
+        );
+    }
+
+    #[test]
+    fn storage_faults_are_told_from_rejections() {
+        let rejected = application_error(MemoryStorageError::default());
+        assert_eq!((rejected.code, rejected.index), (ErrorCode::Rejected, None));
+
+        let faulted = application_error(MemoryStorageError::faulted(StorageFault::DiskFull, Some(17)));
+        assert_eq!((faulted.code, faulted.index), (ErrorCode::StorageFault, Some(17)));
+    }
+
+    #[test]
+    fn sessions_make_idempotency_keys() {
+        let data = ClientCommand::App(vec![1]);
+        let keyed = proposal("json".to_owned(), data.clone(), Some("c-17".to_owned()), Some(3));
+        assert_eq!(keyed.key, Some(affinity::session_key("c-17", 3)));
+
+        assert_eq!(proposal("json".to_owned(), data.clone(), Some("c-17".to_owned()), None).key, None);
+        assert_eq!(proposal("json".to_owned(), data, None, Some(3)).key, None);
+    }
+}
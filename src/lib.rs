@@ -1,12 +1,40 @@
 #[macro_use]
 extern crate log;
 
+#[cfg(feature = "arbitration")]
+pub mod arbitration;
+pub mod audit;
+pub mod bench;
+pub mod clock;
 pub mod config;
+pub mod conformance;
+pub mod crash;
 pub mod data;
+pub mod descriptor;
+pub mod dictionary;
+pub mod drill;
+pub mod error;
+pub mod events;
+pub mod federation;
 pub mod hash_ring;
+pub mod json_protocol;
+pub mod linearizability;
+pub mod locks;
+pub mod mailbox;
+pub mod memory;
+pub mod nemesis;
 pub mod network;
+pub mod preflight;
+pub mod prelude;
 pub mod raft;
 pub mod raftor;
+pub mod runtime;
+pub mod selftest;
 pub mod server;
+pub mod simulation;
+pub mod service;
 pub mod session;
+pub mod standby;
+pub mod telemetry;
 pub mod utils;
+pub mod watch;
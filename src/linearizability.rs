@@ -0,0 +1,324 @@
+//! Linearizability checker for the histories recorded by the nemesis.
+//!
+//! The hash ring is modelled as a set of keys: `add` and `remove` write a key's membership and
+//! `read` observes it. Keys are independent so every key is checked on its own, searching
+//! for an order of the operations consistent with both their real time order and the model,
+//! as in Wing and Gong's algorithm with Lowe's memoization of the visited states. Failed
+//! operations never took effect and are left out, operations with an unknown outcome may be
+//! placed anywhere after their invocation or left out.
+
+use actix_raft::NodeId;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+
+/// Upper bound on the states visited for a key before giving up
+const MAX_VISITED: usize = 200_000;
+
+#[derive(Deserialize)]
+struct Event {
+    process: String,
+    #[serde(rename = "type")]
+    kind: String,
+    f: String,
+    value: (NodeId, Option<bool>),
+    time: u64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Command {
+    Add,
+    Remove,
+    Read(bool),
+}
+
+#[derive(Clone, Debug)]
+struct Op {
+    command: Command,
+    invoke: u64,
+    /// None when the outcome is unknown
+    complete: Option<u64>,
+}
+
+impl Op {
+    /// Apply the operation to the membership of its key, none when the model forbids it.
+    fn step(&self, member: bool) -> Option<bool> {
+        match self.command {
+            Command::Add => Some(true),
+            Command::Remove => Some(false),
+            Command::Read(observed) if observed == member => Some(member),
+            Command::Read(_) => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, PartialEq)]
+pub enum Verdict {
+    Linearizable,
+    NotLinearizable,
+    /// The search gave up before reaching a verdict
+    Unknown,
+}
+
+/// Outcome of checking a history.
+#[derive(Clone, Debug, Serialize)]
+pub struct CheckReport {
+    pub operations: usize,
+    pub keys: usize,
+    pub verdict: Verdict,
+    /// Keys whose operations can't be linearized
+    pub failed_keys: Vec<NodeId>,
+    pub unknown_keys: Vec<NodeId>,
+}
+
+/// Check the history written by the nemesis to `path`.
+pub fn check_file<P: AsRef<Path>>(path: P) -> io::Result<CheckReport> {
+    let mut events = Vec::new();
+
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        // skip the fault events, they don't follow the operation format
+        if let Ok(event) = serde_json::from_str::<Event>(&line) {
+            if event.process != "nemesis" {
+                events.push(event);
+            }
+        }
+    }
+
+    check(events)
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Pair the invocations with their completions and check every key.
+fn check(events: Vec<Event>) -> io::Result<CheckReport> {
+    let mut invoked: HashMap<String, Event> = HashMap::new();
+    let mut keys: BTreeMap<NodeId, Vec<Op>> = BTreeMap::new();
+
+    for event in events {
+        if event.kind == "invoke" {
+            invoked.insert(event.process.clone(), event);
+            continue;
+        }
+
+        let invocation = invoked
+            .remove(&event.process)
+            .ok_or_else(|| invalid(format!("{} completed without an invocation", event.process)))?;
+
+        let command = match (event.f.as_str(), event.value.1) {
+            ("add", _) => Command::Add,
+            ("remove", _) => Command::Remove,
+            ("read", Some(member)) => Command::Read(member),
+            // a read without a result tells nothing
+            ("read", None) => continue,
+            (f, _) => return Err(invalid(format!("unknown operation {}", f))),
+        };
+
+        let complete = match event.kind.as_str() {
+            "ok" => Some(event.time),
+            "info" => None,
+            // never took effect
+            _ => continue,
+        };
+
+        keys.entry(invocation.value.0).or_insert_with(Vec::new).push(Op {
+            command: command,
+            invoke: invocation.time,
+            complete: complete,
+        });
+    }
+
+    // operations still running when the history ended may have taken effect
+    for (_, invocation) in invoked {
+        let command = match invocation.f.as_str() {
+            "add" => Command::Add,
+            "remove" => Command::Remove,
+            _ => continue,
+        };
+
+        keys.entry(invocation.value.0).or_insert_with(Vec::new).push(Op {
+            command: command,
+            invoke: invocation.time,
+            complete: None,
+        });
+    }
+
+    let mut report = CheckReport {
+        operations: keys.values().map(|ops| ops.len()).sum(),
+        keys: keys.len(),
+        verdict: Verdict::Linearizable,
+        failed_keys: Vec::new(),
+        unknown_keys: Vec::new(),
+    };
+
+    for (key, mut ops) in keys {
+        ops.sort_by_key(|op| op.invoke);
+
+        match check_key(&ops) {
+            Verdict::Linearizable => (),
+            Verdict::NotLinearizable => report.failed_keys.push(key),
+            Verdict::Unknown => report.unknown_keys.push(key),
+        }
+    }
+
+    report.verdict = if !report.failed_keys.is_empty() {
+        Verdict::NotLinearizable
+    } else if !report.unknown_keys.is_empty() {
+        Verdict::Unknown
+    } else {
+        Verdict::Linearizable
+    };
+
+    Ok(report)
+}
+
+/// Search for a linearization of the operations of one key, sorted by invocation. Keys start
+/// out of the ring.
+fn check_key(ops: &[Op]) -> Verdict {
+    let mut visited = HashSet::new();
+    let mut stack = vec![(vec![false; ops.len()], false)];
+
+    while let Some((done, member)) = stack.pop() {
+        // every operation known to have completed is linearized
+        if ops.iter().zip(done.iter()).all(|(op, done)| *done || op.complete.is_none()) {
+            return Verdict::Linearizable;
+        }
+
+        if !visited.insert((done.clone(), member)) {
+            continue;
+        }
+        if visited.len() > MAX_VISITED {
+            return Verdict::Unknown;
+        }
+
+        // the next operation must have been invoked before every pending one completed
+        let deadline = ops
+            .iter()
+            .zip(done.iter())
+            .filter(|(_, done)| !**done)
+            .filter_map(|(op, _)| op.complete)
+            .min()
+            .unwrap_or(u64::max_value());
+
+        for (i, op) in ops.iter().enumerate() {
+            if op.invoke > deadline {
+                break;
+            }
+            if done[i] {
+                continue;
+            }
+
+            if let Some(member) = op.step(member) {
+                let mut next = done.clone();
+                next[i] = true;
+                stack.push((next, member));
+            }
+        }
+    }
+
+    Verdict::NotLinearizable
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    /// An event of `process` on key 1.
+    fn event(process: &str, kind: &str, f: &str, member: Option<bool>, time: u64) -> Event {
+        Event {
+            process: process.to_owned(),
+            kind: kind.to_owned(),
+            f: f.to_owned(),
+            value: (1, member),
+            time: time,
+        }
+    }
+
+    #[test]
+    fn a_read_sees_the_completed_writes() {
+        let report = check(vec![
+            event("a", "invoke", "add", None, 1),
+            event("a", "ok", "add", None, 2),
+            event("b", "invoke", "read", None, 3),
+            event("b", "ok", "read", Some(true), 4),
+        ])
+        .unwrap();
+        assert_eq!((report.operations, report.keys), (2, 1));
+        assert_eq!(report.verdict, Verdict::Linearizable);
+
+        let report = check(vec![
+            event("a", "invoke", "add", None, 1),
+            event("a", "ok", "add", None, 2),
+            event("b", "invoke", "read", None, 3),
+            event("b", "ok", "read", Some(false), 4),
+        ])
+        .unwrap();
+        assert_eq!(report.verdict, Verdict::NotLinearizable);
+        assert_eq!(report.failed_keys, vec![1]);
+    }
+
+    #[test]
+    fn concurrent_operations_may_take_effect_in_either_order() {
+        let report = check(vec![
+            event("a", "invoke", "add", None, 1),
+            event("b", "invoke", "read", None, 2),
+            event("b", "ok", "read", Some(false), 3),
+            event("a", "ok", "add", None, 4),
+        ])
+        .unwrap();
+        assert_eq!(report.verdict, Verdict::Linearizable);
+    }
+
+    #[test]
+    fn unknown_outcomes_may_or_may_not_have_taken_effect() {
+        for member in &[true, false] {
+            let report = check(vec![
+                event("a", "invoke", "add", None, 1),
+                event("a", "info", "add", None, 2),
+                event("b", "invoke", "read", None, 3),
+                event("b", "ok", "read", Some(*member), 4),
+            ])
+            .unwrap();
+            assert_eq!(report.verdict, Verdict::Linearizable);
+        }
+
+        // a failed add never took effect
+        let report = check(vec![
+            event("a", "invoke", "add", None, 1),
+            event("a", "fail", "add", None, 2),
+            event("b", "invoke", "read", None, 3),
+            event("b", "ok", "read", Some(true), 4),
+        ])
+        .unwrap();
+        assert_eq!(report.verdict, Verdict::NotLinearizable);
+    }
+
+    #[test]
+    fn completions_need_an_invocation() {
+        assert!(check(vec![event("a", "ok", "add", None, 2)]).is_err());
+    }
+
+    #[test]
+    fn histories_are_read_from_json_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history.jsonl");
+        let mut file = File::create(&path).unwrap();
+        writeln!(file, r#"{{"process":"a","type":"invoke","f":"add","value":[7,null],"time":1}}"#).unwrap();
+        writeln!(file, r#"{{"process":"nemesis","type":"info","f":"partition","value":"1|2,3","time":2}}"#).unwrap();
+        writeln!(file).unwrap();
+        writeln!(file, r#"{{"process":"a","type":"ok","f":"add","value":[7,null],"time":3}}"#).unwrap();
+
+        let report = check_file(&path).unwrap();
+        assert_eq!((report.operations, report.keys), (1, 1));
+        assert_eq!(report.verdict, Verdict::Linearizable);
+    }
+}
@@ -0,0 +1,133 @@
+//! Distributed locks replicated through the log, with fencing tokens.
+//!
+//! Acquiring a lock hands out a fencing token derived from the term and index of the entry
+//! which granted it. Tokens only ever grow, so a resource protected by the lock can remember
+//! the highest token it has seen and reject operations carrying an older one, or ask the
+//! cluster whether a token still belongs to the current holder.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::raft::storage::MemoryStorageData;
+
+/// Fencing token of a lock acquisition, ordered by term then index.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FencingToken {
+    pub term: u64,
+    pub index: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct LockState {
+    pub holder: String,
+    pub token: FencingToken,
+}
+
+/// Current holders of the locks, rebuilt from the applied entries.
+#[derive(Default)]
+pub struct LockTable {
+    locks: BTreeMap<String, LockState>,
+}
+
+impl LockTable {
+    /// Apply a lock command of the entry at `index` in `term`.
+    pub fn apply(&mut self, index: u64, term: u64, data: &MemoryStorageData) {
+        match data {
+            MemoryStorageData::AcquireLock { name, holder } => {
+                self.acquire(name, holder, FencingToken { term: term, index: index })
+            }
+            MemoryStorageData::ReleaseLock { name, holder } => self.release(name, holder),
+            _ => (),
+        }
+    }
+
+    /// Grant the lock to `holder` unless someone else holds it. Re-acquiring a held lock hands
+    /// out a new token.
+    pub fn acquire(&mut self, name: &str, holder: &str, token: FencingToken) {
+        match self.locks.get(name) {
+            Some(lock) if lock.holder != holder => {
+                debug!("Lock {} is held by {}, not granting it to {}", name, lock.holder, holder)
+            }
+            _ => {
+                self.locks.insert(
+                    name.to_owned(),
+                    LockState {
+                        holder: holder.to_owned(),
+                        token: token,
+                    },
+                );
+            }
+        }
+    }
+
+    /// Release the lock if `holder` holds it.
+    pub fn release(&mut self, name: &str, holder: &str) {
+        if self.locks.get(name).map(|lock| lock.holder == holder).unwrap_or(false) {
+            self.locks.remove(name);
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&LockState> {
+        self.locks.get(name)
+    }
+
+    pub fn clear(&mut self) {
+        self.locks.clear();
+    }
+
+    /// Whether the token is the one of the current holder of the lock.
+    pub fn is_fresh(&self, name: &str, token: FencingToken) -> bool {
+        self.locks.get(name).map(|lock| lock.token == token).unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn acquire(name: &str, holder: &str) -> MemoryStorageData {
+        MemoryStorageData::AcquireLock {
+            name: name.to_owned(),
+            holder: holder.to_owned(),
+        }
+    }
+
+    fn release(name: &str, holder: &str) -> MemoryStorageData {
+        MemoryStorageData::ReleaseLock {
+            name: name.to_owned(),
+            holder: holder.to_owned(),
+        }
+    }
+
+    #[test]
+    fn a_held_lock_isnt_granted_to_someone_else() {
+        let mut table = LockTable::default();
+        table.apply(1, 1, &acquire("jobs", "a"));
+        table.apply(2, 1, &acquire("jobs", "b"));
+        assert_eq!(table.get("jobs").unwrap().holder, "a");
+
+        // Only the holder releases it
+        table.apply(3, 1, &release("jobs", "b"));
+        assert!(table.get("jobs").is_some());
+        table.apply(4, 1, &release("jobs", "a"));
+        assert!(table.get("jobs").is_none());
+
+        table.apply(5, 2, &acquire("jobs", "b"));
+        assert_eq!(table.get("jobs").unwrap().holder, "b");
+    }
+
+    #[test]
+    fn tokens_grow_with_every_acquisition() {
+        let mut table = LockTable::default();
+        table.apply(7, 1, &acquire("jobs", "a"));
+        let first = table.get("jobs").unwrap().token;
+
+        table.apply(3, 2, &acquire("jobs", "a"));
+        let second = table.get("jobs").unwrap().token;
+
+        assert!(second > first);
+        assert!(table.is_fresh("jobs", second));
+        assert!(!table.is_fresh("jobs", first));
+        assert!(!table.is_fresh("other", second));
+    }
+}
@@ -0,0 +1,138 @@
+//! Mailbox capacities of the actors on the hot path, and how often their mailboxes were full.
+//!
+//! Senders waiting for an answer wait for room in a full mailbox. Messages sent without
+//! waiting are handed over with `deliver`, which queues them anyway and counts the mailbox
+//! as full, so a capacity too small for the load shows in the metrics rather than as lost
+//! messages.
+
+use actix::dev::{SendError, ToEnvelope};
+use actix::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::config::MailboxCapacities;
+
+/// The actors whose mailboxes are sized from the config.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mailbox {
+    Network,
+    Node,
+    Session,
+    Raft,
+}
+
+impl Mailbox {
+    pub const ALL: [Mailbox; 4] = [Mailbox::Network, Mailbox::Node, Mailbox::Session, Mailbox::Raft];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Mailbox::Network => "network",
+            Mailbox::Node => "node",
+            Mailbox::Session => "session",
+            Mailbox::Raft => "raft",
+        }
+    }
+
+    fn full(self) -> &'static AtomicU64 {
+        &FULL[self as usize]
+    }
+}
+
+static FULL: [AtomicU64; 4] = [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)];
+
+/// Capacity of a kind of mailbox and the times it was found full.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MailboxStats {
+    pub actor: String,
+    pub capacity: usize,
+    pub full: u64,
+}
+
+/// Stats of every kind of mailbox of this process.
+pub fn stats(capacities: &MailboxCapacities) -> Vec<MailboxStats> {
+    Mailbox::ALL
+        .iter()
+        .map(|mailbox| MailboxStats {
+            actor: mailbox.name().to_owned(),
+            capacity: capacities.of(*mailbox),
+            full: mailbox.full().load(Ordering::Relaxed),
+        })
+        .collect()
+}
+
+/// Send a message without waiting for an answer, counting the mailbox as full when it is.
+pub fn deliver<A, M>(addr: &Addr<A>, msg: M, mailbox: Mailbox)
+where
+    A: Actor + Handler<M>,
+    A::Context: ToEnvelope<A, M>,
+    M: Message + Send + 'static,
+    M::Result: Send,
+{
+    match addr.try_send(msg) {
+        Ok(()) => (),
+        Err(SendError::Full(msg)) => {
+            mailbox.full().fetch_add(1, Ordering::Relaxed);
+            addr.do_send(msg);
+        }
+        Err(SendError::Closed(_)) => (),
+    }
+}
+
+/// `deliver` to a recipient.
+pub fn deliver_to<M>(recipient: &Recipient<M>, msg: M, mailbox: Mailbox)
+where
+    M: Message + Send + 'static,
+    M::Result: Send,
+{
+    match recipient.try_send(msg) {
+        Ok(()) => (),
+        Err(SendError::Full(msg)) => {
+            mailbox.full().fetch_add(1, Ordering::Relaxed);
+            let _ = recipient.do_send(msg);
+        }
+        Err(SendError::Closed(_)) => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Never started, so whatever is sent stays in its mailbox.
+    struct Idle;
+
+    impl Actor for Idle {
+        type Context = Context<Self>;
+    }
+
+    struct Ping;
+
+    impl Message for Ping {
+        type Result = ();
+    }
+
+    impl Handler<Ping> for Idle {
+        type Result = ();
+
+        fn handle(&mut self, _msg: Ping, _ctx: &mut Context<Self>) {}
+    }
+
+    #[test]
+    fn a_full_mailbox_is_counted() {
+        let mut ctx = Context::<Idle>::new();
+        ctx.set_mailbox_capacity(1);
+        let addr = ctx.address();
+
+        let full = Mailbox::Node.full().load(Ordering::Relaxed);
+        for _ in 0..4 {
+            deliver(&addr, Ping, Mailbox::Node);
+        }
+        assert!(Mailbox::Node.full().load(Ordering::Relaxed) > full);
+
+        let stats = stats(&MailboxCapacities::default());
+        assert_eq!(
+            stats.iter().map(|stats| stats.actor.as_str()).collect::<Vec<_>>(),
+            vec!["network", "node", "session", "raft"]
+        );
+    }
+}
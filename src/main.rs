@@ -2,29 +2,57 @@ use actix::prelude::*;
 use actix_cors::Cors;
 use actix_files as fs;
 use actix_web::{
+    dev::Service,
     http::header, middleware::Logger, web, App, Error, HttpRequest, HttpResponse, HttpServer,
     Responder,
     error
 };
 use actix_web_actors::ws;
+use bytes::Bytes;
 use config;
-use futures::Future;
+use futures::{stream, Future, Stream};
+use rmp_serde as rmps;
 use serde::Deserialize;
-use std::collections::HashMap;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
 use std::env;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use actix_raft::NodeId;
+use actix_raft::messages::{ClientError, ClientPayloadResponse};
 
 
 use raftor::{
-    config::{ConfigSchema, NodeInfo},
+    audit,
+    bench::{Bench, BenchConfig},
+    config::{ClusterMetadata, ConfigSchema, NodeInfo, ReadStreaming},
+    conformance,
+    drill::{DrillConfig, FailoverDrill},
+    events::{Observer, ObserverConfig},
+    federation::ClusterRecord,
+    linearizability::{self, Verdict},
+    locks::FencingToken,
+    nemesis::{Nemesis, NemesisConfig},
+    preflight,
     hash_ring,
-    network::{GetNode, GetNodes, GetClusterState, Network},
-    raftor::Raftor,
+    network::{AbortAddressMigration, AddMember, DeltaCursor, Bootstrap, PrepareMembershipChange, ConfirmMembershipChange, ConfirmError, DebugPeer, GetAddressMigration, MigrateNextAddress, StartAddressMigration, GetClusterDescriptor, GetClusterMetadata, GetEvents, GetLeadershipHistory, GetNode, GetClusterStatus, GetTopology, GetPendingJoins, ApproveJoin, RejectJoin, RequestJoin, JoinStatus, JoinError, GetNodeMetrics, GetNodes, GetNodesDelta, Shutdown, ReloadTls, TransferLeadership, GetNodeStatus, GetClusterState, GetRaftMetrics, GetEgressLimit, SetEgressLimit, Network, ReadBarrier, ValidateMembershipChange, WaitForApplied, render_prometheus},
+    raftor::{CreateSupportBundle, GetLearners, GetNodeInfo, Raftor, Rejoin},
+    runtime::Runtime,
+    selftest::{self, SelfTest},
     server::{self, Server},
     session::Session,
+    standby,
     utils,
-    raft::{RaftClient, ChangeRaftClusterConfig},
+    watch::ProgressWatcher,
+    raft::{
+        affinity,
+        app::ClientCommand,
+        backup::BackupError,
+        recovery::{LogReplay, ReplayPhase},
+        group::{ChangeGroupMembership, CheckpointError, CreateGroup, ExportGroupCheckpoint, GetGroupMetrics, GetHeartbeatStats, GroupCheckpoint, GroupId, ImportGroupCheckpoint, RaftGroups, RemoveGroup},
+        storage::{ClusterEpoch, MemoryStorageData, OpenBlob}, RaftClient, ChangeRaftClusterConfig, ClientProposal,
+        AcquireLock, ArchiveLog, CreateBackup, ExportError, ExportState, FindCluster, GetClientSession, GetCommitTimings, GetPendingProposals, InspectLog, GetStorageInfo, GetWriteMetrics, ListClusters, ListProjections, Maintenance, QueryAppPage, ResetProjection, QueryLocalState, ReleaseLock, RestoreBackup, VacuumStorage, ValidateFencingToken,
+    },
 };
 
 fn index_route(
@@ -42,101 +70,1823 @@ fn index_route(
 
 fn join_cluster_route(
     node_id: web::Json<NodeId>,
+    srv: web::Data<Arc<ServerData>>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    println!("got join request with id {:#?}", node_id);
+
+    srv.cluster_net
+        .send(RequestJoin(node_id.into_inner()))
+        .map_err(Error::from)
+        .and_then(|res| match res {
+            Ok(JoinStatus::Added) => Ok(HttpResponse::Ok().json(JoinStatus::Added)),
+            Ok(JoinStatus::Pending) => Ok(HttpResponse::Accepted().json(JoinStatus::Pending)),
+            Err(JoinError::Refused(reason)) => Ok(HttpResponse::Forbidden().json(reason)),
+            Err(JoinError::Propose(err)) => Ok(HttpResponse::Conflict().json(err)),
+        })
+}
+
+fn bootstrap_route(srv: web::Data<Arc<ServerData>>) -> HttpResponse {
+    srv.cluster_net.do_send(Bootstrap);
+    HttpResponse::Ok().json(())
+}
+
+fn add_member_route(
+    member: web::Json<(NodeId, NodeInfo)>,
+    srv: web::Data<Arc<ServerData>>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let (id, info) = member.into_inner();
+
+    srv.cluster_net
+        .send(AddMember(id, info))
+        .map_err(Error::from)
+        .and_then(|res| match res {
+            Ok(_) => Ok(HttpResponse::Ok().json(())),
+            Err(err) => Ok(HttpResponse::Conflict().json(err)),
+        })
+}
+
+/// Prepare the removal of a node, it's proposed once confirmed like any prepared change.
+fn prepare_removal_route(
+    id: web::Path<NodeId>,
+    srv: web::Data<Arc<ServerData>>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    srv.cluster_net
+        .send(PrepareMembershipChange(Vec::new(), vec![id.into_inner()]))
+        .map_err(Error::from)
+        .and_then(|res| match res {
+            Ok(prepared) => Ok(HttpResponse::Ok().json(prepared)),
+            Err(_) => Ok(HttpResponse::ServiceUnavailable().finish()),
+        })
+}
+
+fn validate_membership_route(
+    change: web::Json<ChangeRaftClusterConfig>,
+    srv: web::Data<Arc<ServerData>>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let change = change.into_inner();
+
+    srv.cluster_net
+        .send(ValidateMembershipChange(change.0, change.1))
+        .map_err(Error::from)
+        .and_then(|res| Ok(HttpResponse::Ok().json(res)))
+}
+
+fn prepare_membership_route(
+    change: web::Json<ChangeRaftClusterConfig>,
+    srv: web::Data<Arc<ServerData>>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let change = change.into_inner();
+
+    srv.cluster_net
+        .send(PrepareMembershipChange(change.0, change.1))
+        .map_err(Error::from)
+        .and_then(|res| match res {
+            Ok(prepared) => Ok(HttpResponse::Ok().json(prepared)),
+            Err(_) => Ok(HttpResponse::ServiceUnavailable().finish()),
+        })
+}
+
+fn confirm_membership_route(
+    token: web::Path<u64>,
+    srv: web::Data<Arc<ServerData>>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    srv.cluster_net
+        .send(ConfirmMembershipChange(token.into_inner()))
+        .map_err(Error::from)
+        .and_then(|res| match res {
+            Ok(_) => Ok(HttpResponse::Ok().json(())),
+            Err(ConfirmError::Unknown) => Ok(HttpResponse::NotFound().json(ConfirmError::Unknown)),
+            Err(ConfirmError::Expired) => Ok(HttpResponse::Gone().json(ConfirmError::Expired)),
+            Err(err) => Ok(HttpResponse::Conflict().json(err)),
+        })
+}
+
+fn export_route(
+    path: web::Json<String>,
+    srv: web::Data<Arc<ServerData>>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    srv.raft
+        .send(ExportState(path.into_inner()))
+        .map_err(Error::from)
+        .and_then(|res| match res {
+            Ok(info) => Ok(HttpResponse::Ok().json(info)),
+            Err(err @ ExportError::InvalidPath) => Ok(HttpResponse::BadRequest().json(err)),
+            Err(err) => Ok(HttpResponse::InternalServerError().json(err)),
+        })
+}
+
+fn projections_route(srv: web::Data<Arc<ServerData>>) -> impl Future<Item = HttpResponse, Error = Error> {
+    srv.raft
+        .send(ListProjections)
+        .map_err(Error::from)
+        .and_then(|res| Ok(HttpResponse::Ok().json(res)))
+}
+
+fn rebuild_projection_route(
+    name: web::Path<String>,
+    srv: web::Data<Arc<ServerData>>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    srv.raft
+        .send(ResetProjection(name.into_inner()))
+        .map_err(Error::from)
+        .and_then(|res| match res {
+            Ok(index) => Ok(HttpResponse::Ok().json(index)),
+            Err(_) => Ok(HttpResponse::NotFound().json(())),
+        })
+}
+
+fn truncate_log_route(
+    index: web::Json<u64>,
+    srv: web::Data<Arc<ServerData>>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    srv.raft
+        .send(ArchiveLog(index.into_inner()))
+        .map_err(Error::from)
+        .and_then(|res| match res {
+            Ok(index) => Ok(HttpResponse::Ok().json(index)),
+            Err(_) => Ok(HttpResponse::Conflict().finish()),
+        })
+}
+
+fn vacuum_route(
+    pause_ms: web::Json<u64>,
+    srv: web::Data<Arc<ServerData>>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    srv.raft
+        .send(VacuumStorage(pause_ms.into_inner()))
+        .map_err(Error::from)
+        .and_then(|res| match res {
+            Ok(_) => Ok(HttpResponse::Accepted().finish()),
+            Err(err) => Ok(HttpResponse::Conflict().json(err)),
+        })
+}
+
+fn vacuum_progress_route(srv: web::Data<Arc<ServerData>>) -> impl Future<Item = HttpResponse, Error = Error> {
+    srv.raft
+        .send(GetStorageInfo)
+        .map_err(Error::from)
+        .and_then(|res| match res {
+            Ok(stats) => Ok(HttpResponse::Ok().json(stats.vacuum)),
+            Err(_) => Ok(HttpResponse::NotFound().finish()),
+        })
+}
+
+fn maintenance_route(
+    enabled: web::Json<bool>,
+    srv: web::Data<Arc<ServerData>>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    srv.raft
+        .send(Maintenance(enabled.into_inner()))
+        .map_err(Error::from)
+        .and_then(|res| match res {
+            Ok(applied) => Ok(HttpResponse::Ok().json(applied)),
+            Err(_) => Ok(HttpResponse::Conflict().finish()),
+        })
+}
+
+fn debug_peer_route(
+    debug: web::Json<(NodeId, bool)>,
+    srv: web::Data<Arc<ServerData>>,
+) -> HttpResponse {
+    let (id, enabled) = debug.into_inner();
+
+    srv.cluster_net.do_send(DebugPeer(id, enabled));
+    srv.net.do_send(DebugPeer(id, enabled));
+    HttpResponse::Ok().json(())
+}
+
+/// Propose an application command, membership, freezes and the other commands of the node
+/// itself have routes of their own.
+fn propose_route(
+    data: web::Json<MemoryStorageData>,
+    srv: web::Data<Arc<ServerData>>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let data = match data.into_inner() {
+        data @ MemoryStorageData::App(_) => data,
+        data => {
+            let reason = format!("only application commands can be proposed, not {}", data.kind());
+            return futures::future::Either::A(futures::future::ok(HttpResponse::BadRequest().json(reason)));
+        }
+    };
+
+    let proposal = ClientProposal {
+        client: "admin".to_owned(),
+        key: None,
+        data: data,
+        deadline_ms: None,
+    };
+
+    futures::future::Either::B(srv.raft.send(proposal).map_err(Error::from).and_then(|res| match res {
+        Ok(ClientPayloadResponse::Applied { index, .. })
+        | Ok(ClientPayloadResponse::Committed { index }) => Ok(HttpResponse::Ok().json(index)),
+        Err(ClientError::Application(ref err)) if err.fault.is_some() => Ok(HttpResponse::InsufficientStorage().json(err)),
+        Err(_) => Ok(HttpResponse::ServiceUnavailable().finish()),
+    }))
+}
+
+fn freeze_route(
+    frozen: web::Json<bool>,
+    srv: web::Data<Arc<ServerData>>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let proposal = ClientProposal {
+        client: "admin".to_owned(),
+        key: None,
+        data: MemoryStorageData::Freeze(frozen.into_inner()),
+        deadline_ms: None,
+    };
+
+    srv.raft
+        .send(proposal)
+        .map_err(Error::from)
+        .and_then(|res| match res {
+            Ok(ClientPayloadResponse::Applied { index, .. })
+            | Ok(ClientPayloadResponse::Committed { index }) => Ok(HttpResponse::Ok().json(index)),
+            Err(_) => Ok(HttpResponse::ServiceUnavailable().finish()),
+        })
+}
+
+fn acquire_lock_route(
+    name: web::Path<String>,
+    holder: web::Json<String>,
+    srv: web::Data<Arc<ServerData>>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    srv.raft
+        .send(AcquireLock {
+            name: name.into_inner(),
+            holder: holder.into_inner(),
+        })
+        .map_err(Error::from)
+        .and_then(|res| match res {
+            Ok(token) => Ok(HttpResponse::Ok().json(token)),
+            Err(_) => Ok(HttpResponse::Conflict().finish()),
+        })
+}
+
+fn release_lock_route(
+    name: web::Path<String>,
+    holder: web::Json<String>,
+    srv: web::Data<Arc<ServerData>>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    srv.raft
+        .send(ReleaseLock {
+            name: name.into_inner(),
+            holder: holder.into_inner(),
+        })
+        .map_err(Error::from)
+        .and_then(|res| match res {
+            Ok(_) => Ok(HttpResponse::Ok().finish()),
+            Err(_) => Ok(HttpResponse::ServiceUnavailable().finish()),
+        })
+}
+
+fn validate_token_route(
+    name: web::Path<String>,
+    token: web::Json<FencingToken>,
+    srv: web::Data<Arc<ServerData>>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    srv.raft
+        .send(ValidateFencingToken(name.into_inner(), token.into_inner()))
+        .map_err(Error::from)
+        .and_then(|res| Ok(HttpResponse::Ok().json(res)))
+}
+
+fn register_cluster_route(
+    req: HttpRequest,
+    record: web::Json<ClusterRecord>,
+    srv: web::Data<Arc<ServerData>>,
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    let authorization = req.headers().get(header::AUTHORIZATION).and_then(|value| value.to_str().ok());
+    let authorized = match srv.federation_token {
+        Some(ref token) => utils::bearer_authorized(authorization, Some(token)),
+        None => false,
+    };
+    if !authorized {
+        return Box::new(futures::future::ok(HttpResponse::Unauthorized().finish()));
+    }
+
+    let record = record.into_inner();
+    let proposal = ClientProposal {
+        client: record.name.clone(),
+        key: None,
+        data: MemoryStorageData::RegisterCluster(record),
+        deadline_ms: None,
+    };
+
+    Box::new(srv.raft.send(proposal).map_err(Error::from).and_then(|res| match res {
+        Ok(_) => Ok(HttpResponse::Ok().json(())),
+        Err(_) => Ok(HttpResponse::ServiceUnavailable().finish()),
+    }))
+}
+
+fn clusters_route(
+    srv: web::Data<Arc<ServerData>>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    srv.raft
+        .send(ListClusters)
+        .map_err(Error::from)
+        .and_then(|res| Ok(HttpResponse::Ok().json(res)))
+}
+
+fn lookup_cluster_route(
+    namespace: web::Path<String>,
+    srv: web::Data<Arc<ServerData>>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    srv.raft
+        .send(FindCluster(namespace.into_inner()))
+        .map_err(Error::from)
+        .and_then(|res| Ok(HttpResponse::Ok().json(res)))
+}
+
+fn node_info_route(
+    srv: web::Data<Arc<ServerData>>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    srv.raftor
+        .send(GetNodeInfo)
+        .map_err(Error::from)
+        .and_then(|res| Ok(HttpResponse::Ok().json(res)))
+}
+
+fn rejoin_route(srv: web::Data<Arc<ServerData>>) -> impl Future<Item = HttpResponse, Error = Error> {
+    srv.raftor
+        .send(Rejoin)
+        .map_err(Error::from)
+        .and_then(|res| match res {
+            Ok(()) => Ok(HttpResponse::Ok().finish()),
+            Err(_) => Ok(HttpResponse::Conflict().finish()),
+        })
+}
+
+/// Reload the TLS certificates of both networks from their files, see `ReloadTls`.
+fn reload_tls_route(srv: web::Data<Arc<ServerData>>) -> impl Future<Item = HttpResponse, Error = Error> {
+    srv.cluster_net
+        .send(ReloadTls)
+        .join(srv.net.send(ReloadTls))
+        .map_err(Error::from)
+        .map(|res| match res {
+            (Ok(false), Ok(false)) => HttpResponse::NotFound().body("TLS isn't configured"),
+            (Ok(_), Ok(_)) => HttpResponse::Ok().finish(),
+            (Err(err), _) | (_, Err(err)) => HttpResponse::UnprocessableEntity().body(err),
+        })
+}
+
+fn epoch_route(srv: web::Data<Arc<ServerData>>) -> HttpResponse {
+    HttpResponse::Ok().json(srv.epoch())
+}
+
+/// Ready unless the node is replaying its persisted log, answers the progress of the replay.
+fn ready_route(srv: web::Data<Arc<ServerData>>) -> HttpResponse {
+    let status = srv.replay.status();
+    match status.phase {
+        ReplayPhase::Replaying => HttpResponse::ServiceUnavailable().json(status),
+        _ => HttpResponse::Ok().json(status),
+    }
+}
+
+fn learners_route(srv: web::Data<Arc<ServerData>>) -> impl Future<Item = HttpResponse, Error = Error> {
+    srv.raftor
+        .send(GetLearners)
+        .map_err(Error::from)
+        .map(|learners| HttpResponse::Ok().json(learners))
+}
+
+fn support_bundle_route(
+    path: web::Json<String>,
+    srv: web::Data<Arc<ServerData>>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    srv.raftor
+        .send(CreateSupportBundle(path.into_inner().into()))
+        .map_err(Error::from)
+        .and_then(|res| match res {
+            Ok(path) => Ok(HttpResponse::Ok().json(path)),
+            Err(_) => Ok(HttpResponse::InternalServerError().finish()),
+        })
+}
+
+fn metrics_route(srv: web::Data<Arc<ServerData>>) -> impl Future<Item = HttpResponse, Error = Error> {
+    srv.cluster_net
+        .send(GetNodeMetrics)
+        .join(srv.net.send(GetNodeMetrics))
+        .map_err(Error::from)
+        .and_then(|(cluster, app)| {
+            let metrics = vec![cluster, app].into_iter().filter_map(|res| res.ok()).collect::<Vec<_>>();
+
+            Ok(HttpResponse::Ok()
+                .content_type("text/plain; version=0.0.4")
+                .body(render_prometheus(&metrics)))
+        })
+}
+
+#[derive(Deserialize)]
+struct EgressLimit {
+    bytes_per_sec: Option<u64>,
+}
+
+fn egress_limit_route(srv: web::Data<Arc<ServerData>>) -> impl Future<Item = HttpResponse, Error = Error> {
+    srv.cluster_net
+        .send(GetEgressLimit)
+        .map_err(Error::from)
+        .map(|stats| HttpResponse::Ok().json(stats))
+}
+
+fn set_egress_limit_route(
+    limit: web::Json<EgressLimit>,
+    srv: web::Data<Arc<ServerData>>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    srv.cluster_net
+        .send(SetEgressLimit(limit.bytes_per_sec))
+        .map_err(Error::from)
+        .map(|stats| HttpResponse::Ok().json(stats))
+}
+
+#[derive(Deserialize)]
+struct AuditSwitch {
+    enabled: bool,
+}
+
+fn audit_route() -> HttpResponse {
+    HttpResponse::Ok().json(audit::report())
+}
+
+fn audit_switch_route(switch: web::Json<AuditSwitch>) -> HttpResponse {
+    audit::set_enabled(switch.enabled);
+    HttpResponse::Ok().json(audit::report())
+}
+
+fn audit_reset_route() -> HttpResponse {
+    audit::reset();
+    HttpResponse::Ok().json(audit::report())
+}
+
+#[derive(Deserialize)]
+struct LocalStateQuery {
+    /// Query of the application state machine, in its JSON form
+    #[serde(default)]
+    query: Option<Value>,
+}
+
+fn local_state_route(
+    body: web::Json<LocalStateQuery>,
+    srv: web::Data<Arc<ServerData>>,
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    let query = match body.into_inner().query.map(|query| rmps::to_vec_named(&query)).transpose() {
+        Ok(query) => query,
+        Err(_) => return Box::new(futures::future::ok(HttpResponse::BadRequest().finish())),
+    };
+
+    Box::new(
+        srv.raft
+            .send(QueryLocalState::new(move |state| {
+                let app = query.map(|query| match state.query_app(&query) {
+                    Some(Ok(res)) => rmps::from_slice::<Value>(&res).map_err(|err| err.to_string()),
+                    Some(Err(err)) => Err(err),
+                    None => Err("no application state machine".to_owned()),
+                });
+
+                serde_json::json!({
+                    "cordoned": state.cordoned(),
+                    "app": app.map(|res| match res {
+                        Ok(value) => serde_json::json!({ "ok": value }),
+                        Err(err) => serde_json::json!({ "error": err }),
+                    }),
+                })
+            }))
+            .map_err(Error::from)
+            .map(|res| match res {
+                Ok(read) => HttpResponse::Ok().json(read),
+                Err(_) => HttpResponse::ServiceUnavailable().finish(),
+            }),
+    )
+}
+
+fn cpu_route() -> HttpResponse {
+    let mut usage = HashMap::new();
+    usage.insert("cpu_millis", utils::process_cpu_millis());
+
+    HttpResponse::Ok().json(usage)
+}
+
+#[derive(Deserialize)]
+struct TimingsQuery {
+    from: Option<u64>,
+    limit: Option<usize>,
+}
+
+fn timings_route(
+    query: web::Query<TimingsQuery>,
+    srv: web::Data<Arc<ServerData>>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    srv.raft
+        .send(GetCommitTimings {
+            from: query.from.unwrap_or(0),
+            limit: query.limit.unwrap_or(100),
+        })
+        .map_err(Error::from)
+        .and_then(|res| Ok(HttpResponse::Ok().json(res)))
+}
+
+fn log_route(
+    query: web::Query<TimingsQuery>,
+    srv: web::Data<Arc<ServerData>>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    srv.raft
+        .send(InspectLog {
+            from: query.from.unwrap_or(0),
+            limit: query.limit.unwrap_or(100),
+        })
+        .map_err(Error::from)
+        .and_then(|res| match res {
+            Ok(entries) => Ok(HttpResponse::Ok().json(entries)),
+            Err(_) => Ok(HttpResponse::NotFound().finish()),
+        })
+}
+
+fn pending_proposals_route(srv: web::Data<Arc<ServerData>>) -> impl Future<Item = HttpResponse, Error = Error> {
+    srv.raft
+        .send(GetPendingProposals)
+        .map_err(Error::from)
+        .and_then(|res| Ok(HttpResponse::Ok().json(res)))
+}
+
+fn write_metrics_route(srv: web::Data<Arc<ServerData>>) -> impl Future<Item = HttpResponse, Error = Error> {
+    srv.raft
+        .send(GetWriteMetrics)
+        .map_err(Error::from)
+        .and_then(|res| Ok(HttpResponse::Ok().json(res)))
+}
+
+#[derive(Deserialize)]
+struct HistoryQuery {
+    from: Option<u64>,
+}
+
+fn leadership_history_route(
+    query: web::Query<HistoryQuery>,
+    srv: web::Data<Arc<ServerData>>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    srv.cluster_net
+        .send(GetLeadershipHistory(query.from.unwrap_or(0)))
+        .map_err(Error::from)
+        .and_then(|res| Ok(HttpResponse::Ok().json(res)))
+}
+
+#[derive(Deserialize)]
+struct EventsQuery {
+    from: Option<u64>,
+}
+
+fn events_route(
+    query: web::Query<EventsQuery>,
+    srv: web::Data<Arc<ServerData>>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    srv.cluster_net
+        .send(GetEvents(query.from.unwrap_or(0)))
+        .map_err(Error::from)
+        .and_then(|res| Ok(HttpResponse::Ok().json(res.unwrap_or_default())))
+}
+
+fn address_migration_route(srv: web::Data<Arc<ServerData>>) -> impl Future<Item = HttpResponse, Error = Error> {
+    srv.cluster_net
+        .send(GetAddressMigration)
+        .map_err(Error::from)
+        .and_then(|res| Ok(HttpResponse::Ok().json(res)))
+}
+
+fn start_address_migration_route(
+    plan: web::Json<Vec<(NodeId, NodeInfo)>>,
+    srv: web::Data<Arc<ServerData>>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    srv.cluster_net
+        .send(StartAddressMigration(plan.into_inner()))
+        .map_err(Error::from)
+        .and_then(|res| match res {
+            Ok(migration) => Ok(HttpResponse::Ok().json(migration)),
+            Err(_) => Ok(HttpResponse::Conflict().finish()),
+        })
+}
+
+fn migrate_next_address_route(srv: web::Data<Arc<ServerData>>) -> impl Future<Item = HttpResponse, Error = Error> {
+    srv.cluster_net
+        .send(MigrateNextAddress)
+        .map_err(Error::from)
+        .and_then(|res| match res {
+            Ok(migration) => Ok(HttpResponse::Ok().json(migration)),
+            Err(_) => Ok(HttpResponse::Conflict().finish()),
+        })
+}
+
+fn abort_address_migration_route(srv: web::Data<Arc<ServerData>>) -> HttpResponse {
+    srv.cluster_net.do_send(AbortAddressMigration);
+    HttpResponse::Ok().json(())
+}
+
+fn members_route(
+    req: HttpRequest,
+    stream: web::Payload,
+    srv: web::Data<Arc<ServerData>>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let room_id = req.match_info().get("room_id").unwrap_or("");
+
+    srv.server
+        .send(server::GetMembers {
+            room_id: room_id.to_owned(),
+        })
+        .map_err(Error::from)
+        .and_then(|res| Ok(HttpResponse::Ok().json(res)))
+}
+
+#[derive(Deserialize)]
+struct DeltaQuery {
+    epoch: Option<u64>,
+    version: Option<u64>,
+}
+
+fn nodes_delta_route(
+    query: web::Query<DeltaQuery>,
+    srv: web::Data<Arc<ServerData>>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let since = match (query.epoch, query.version) {
+        (Some(epoch), Some(version)) => Some(DeltaCursor { epoch, version }),
+        _ => None,
+    };
+
+    srv.net
+        .send(GetNodesDelta(since))
+        .map_err(Error::from)
+        .and_then(|delta| Ok(HttpResponse::Ok().json(delta)))
+}
+
+fn nodes_route(
+    req: HttpRequest,
+    stream: web::Payload,
+    srv: web::Data<Arc<ServerData>>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    srv.net
+        .send(GetNodes)
+        .map_err(Error::from)
+        .and_then(|res| Ok(HttpResponse::Ok().json(res)))
+}
+
+fn node_status_route(srv: web::Data<Arc<ServerData>>) -> impl Future<Item = HttpResponse, Error = Error> {
+    srv.cluster_net
+        .send(GetNodeStatus)
+        .map_err(Error::from)
+        .and_then(|res| Ok(HttpResponse::Ok().json(res)))
+}
+
+fn state_route(
+    req: HttpRequest,
+    stream: web::Payload,
+    srv: web::Data<Arc<ServerData>>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    // the cluster network follows the raft membership
+    srv.cluster_net
+        .send(GetClusterState)
+        .map_err(Error::from)
+        .and_then(|res| Ok(HttpResponse::Ok().json(res)))
+}
+
+fn descriptor_route(srv: web::Data<Arc<ServerData>>) -> impl Future<Item = HttpResponse, Error = Error> {
+    srv.cluster_net
+        .send(GetClusterDescriptor)
+        .map_err(Error::from)
+        .and_then(|res| match res {
+            Ok(descriptor) => Ok(HttpResponse::Ok().json(descriptor)),
+            Err(_) => Ok(HttpResponse::NotFound().finish()),
+        })
+}
+
+fn room_route(
+    req: HttpRequest,
+    stream: web::Payload,
+    srv: web::Data<Arc<ServerData>>,
+) -> HttpResponse {
+    let room_id = req.match_info().get("room_id").unwrap_or("");
+
+    srv.server.do_send(server::CreateRoom {
+        room_id: room_id.to_owned(),
+    });
+    HttpResponse::Ok().body("room created")
+}
+
+fn ws_route(
+    req: HttpRequest,
+    stream: web::Payload,
+    srv: web::Data<Arc<ServerData>>,
+) -> Result<HttpResponse, Error> {
+    let uid = req.match_info().get("uid").unwrap_or("");
+
+    ws::start(Session::new(uid, "main", srv.server.clone()), &req, stream)
+}
+
+fn progress_route(
     req: HttpRequest,
     stream: web::Payload,
     srv: web::Data<Arc<ServerData>>,
-) ->  HttpResponse {
+) -> Result<HttpResponse, Error> {
+    ws::start(ProgressWatcher::new(srv.cluster_net.clone()), &req, stream)
+}
 
-    println!("got join request with id {:#?}", node_id);
-    srv.raft.do_send(ChangeRaftClusterConfig(vec![*node_id], vec![]));
-    HttpResponse::Ok().json(()) // <- send json response
+#[derive(Deserialize)]
+struct WaitQuery {
+    /// Milliseconds to wait for, 30 seconds if not given
+    timeout_ms: Option<u64>,
 }
 
-fn members_route(
+fn wait_applied_route(
+    index: web::Path<u64>,
+    query: web::Query<WaitQuery>,
+    srv: web::Data<Arc<ServerData>>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let timeout = std::time::Duration::from_millis(query.timeout_ms.unwrap_or(30_000));
+
+    srv.cluster_net
+        .send(WaitForApplied(index.into_inner(), timeout))
+        .map_err(Error::from)
+        .and_then(|res| match res {
+            Ok(progress) => Ok(HttpResponse::Ok().json(progress)),
+            Err(err) => Ok(HttpResponse::RequestTimeout().json(err)),
+        })
+}
+
+fn read_barrier_route(srv: web::Data<Arc<ServerData>>) -> impl Future<Item = HttpResponse, Error = Error> {
+    srv.cluster_net
+        .send(ReadBarrier)
+        .map_err(Error::from)
+        .and_then(|res| match res {
+            Ok(index) => Ok(HttpResponse::Ok().json(index)),
+            Err(err) => Ok(HttpResponse::Conflict().json(err)),
+        })
+}
+
+/// Routes used by chat clients, data members don't serve them.
+fn client_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/").route(web::get().to(|| {
+        HttpResponse::Found()
+            .header("LOCATION", "/static/index.html")
+            .finish()
+    })))
+    .service(web::resource("/node/{uid}").to_async(index_route))
+    .service(web::resource("/room/{room_id}").to_async(room_route))
+    .service(web::resource("/members/{room_id}").to_async(members_route))
+    .service(web::resource("/ws/{uid}").to_async(ws_route))
+    // static resources
+    .service(fs::Files::new("/static/", "static/"));
+}
+
+#[derive(Deserialize)]
+struct GatewayProposal {
+    /// Only application commands, other data answers `400`
+    data: ClientCommand,
+    /// Client session and sequence number of the proposal, a proposal resent through any
+    /// gateway with the same ones is applied once
+    #[serde(default)]
+    session: Option<String>,
+    #[serde(default)]
+    seq: Option<u64>,
+    /// How long the client waits for the proposal, it isn't replicated once that passed
+    #[serde(default)]
+    timeout_ms: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct GatewayRead {
+    key: String,
+    /// Client session whose writes the read must see
+    #[serde(default)]
+    session: Option<String>,
+}
+
+fn gateway_propose_route(
     req: HttpRequest,
-    stream: web::Payload,
+    proposal: web::Json<GatewayProposal>,
     srv: web::Data<Arc<ServerData>>,
 ) -> impl Future<Item = HttpResponse, Error = Error> {
-    let room_id = req.match_info().get("room_id").unwrap_or("");
+    let client = match gateway_client(&req, &srv) {
+        Some(client) => client,
+        None => return futures::future::Either::A(futures::future::ok(HttpResponse::Unauthorized().finish())),
+    };
+    let proposal = proposal.into_inner();
+    let srv = srv.get_ref().clone();
+    let key = match (proposal.session, proposal.seq) {
+        (Some(session), Some(seq)) => Some(affinity::session_key(&session, seq)),
+        _ => None,
+    };
 
-    srv.server
-        .send(server::GetMembers {
-            room_id: room_id.to_owned(),
+    let proposal = ClientProposal {
+        client: client,
+        key: key,
+        data: proposal.data.into(),
+        deadline_ms: proposal.timeout_ms,
+    };
+    futures::future::Either::B(gateway_propose(srv, proposal, "/propose"))
+}
+
+/// Client the bearer token of a gateway request names, see `client_tokens`.
+fn gateway_client(req: &HttpRequest, srv: &ServerData) -> Option<String> {
+    let authorization = req.headers().get(header::AUTHORIZATION).and_then(|value| value.to_str().ok());
+    utils::bearer_client(authorization, &srv.client_tokens, "gateway")
+}
+
+/// Propose through the raft client of this node, answering the index the proposal was
+/// committed at.
+fn gateway_propose(srv: Arc<ServerData>, proposal: ClientProposal, path: &'static str) -> impl Future<Item = HttpResponse, Error = Error> {
+    srv.raft
+        .send(proposal)
+        .map_err(Error::from)
+        .and_then(move |res| match res {
+            Ok(ClientPayloadResponse::Applied { index, .. })
+            | Ok(ClientPayloadResponse::Committed { index }) => futures::future::Either::A(futures::future::ok(
+                HttpResponse::Ok().header("X-Raftor-Epoch", srv.epoch().to_string()).json(index),
+            )),
+            Err(ClientError::Application(ref err)) if err.fault.is_some() => {
+                futures::future::Either::A(futures::future::ok(HttpResponse::InsufficientStorage().json(err)))
+            }
+            Err(ClientError::Application(ref err)) if err.expired => {
+                futures::future::Either::A(futures::future::ok(HttpResponse::GatewayTimeout().json(err)))
+            }
+            // the proposal couldn't be forwarded, point the caller at the leader if we know it
+            Err(_) => futures::future::Either::B(leader_redirect(&srv, path)),
+        })
+}
+
+#[derive(Deserialize)]
+struct GatewayBlob {
+    #[serde(default)]
+    session: Option<String>,
+    #[serde(default)]
+    seq: Option<u64>,
+    /// How long the client waits for the proposal once the body is received
+    #[serde(default)]
+    timeout_ms: Option<u64>,
+}
+
+/// Stream an application command too large for one log entry, e.g. a bulk import, into a blob
+/// of this node and propose a reference to it. The body is the command as encoded by
+/// `app::command`, it's spilled to disk as it arrives rather than held in memory.
+fn gateway_propose_blob_route(
+    req: HttpRequest,
+    body: web::Payload,
+    params: web::Query<GatewayBlob>,
+    srv: web::Data<Arc<ServerData>>,
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    let client = match gateway_client(&req, &srv) {
+        Some(client) => client,
+        None => return Box::new(futures::future::ok(HttpResponse::Unauthorized().finish())),
+    };
+    let params = params.into_inner();
+    let srv = srv.get_ref().clone();
+    let key = match (params.session, params.seq) {
+        (Some(session), Some(seq)) => Some(affinity::session_key(&session, seq)),
+        _ => None,
+    };
+
+    Box::new(
+        srv.raft
+            .send(OpenBlob)
+            .map_err(Error::from)
+            .and_then(|res| res.map_err(error::ErrorInternalServerError))
+            .and_then(move |spill| {
+                body.map_err(Error::from).fold(spill, |mut spill, chunk| {
+                    web::block(move || spill.write(&chunk).map(|_| spill)).map_err(Error::from)
+                })
+            })
+            .and_then(|spill| web::block(move || spill.finish()).map_err(Error::from))
+            .and_then(move |blob| {
+                let proposal = ClientProposal {
+                    client: client,
+                    key: key,
+                    data: MemoryStorageData::Blob(blob),
+                    deadline_ms: params.timeout_ms,
+                };
+                gateway_propose(srv, proposal, "/propose/blob")
+            })
+    )
+}
+
+#[derive(Deserialize)]
+struct GatewayQuery {
+    /// Wait for the writes committed before the query to be applied first
+    #[serde(default)]
+    linearizable: bool,
+}
+
+/// Query the application state machine of this node, the query and its answer are JSON.
+/// Answers which fit in one page under the inline size come back as a JSON array, larger ones
+/// are streamed as newline delimited JSON: the next page is only fetched once the client
+/// drained the previous one, so the gateway holds at most a page of the answer.
+fn gateway_query_route(
+    query: web::Json<Value>,
+    params: web::Query<GatewayQuery>,
+    srv: web::Data<Arc<ServerData>>,
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    let query = match rmps::to_vec_named(&query.into_inner()) {
+        Ok(query) => query,
+        Err(_) => return Box::new(futures::future::ok(HttpResponse::BadRequest().finish())),
+    };
+    let raft = srv.raft.clone();
+    let limit = srv.read_streaming.page_items.max(1);
+    let inline_max_bytes = srv.read_streaming.inline_max_bytes;
+
+    let ready = if params.linearizable {
+        futures::future::Either::A(srv.cluster_net.send(ReadBarrier).map_err(Error::from).map(|res| res.is_ok()))
+    } else {
+        futures::future::Either::B(futures::future::ok(true))
+    };
+
+    Box::new(ready.and_then(move |ready| {
+        if !ready {
+            return futures::future::Either::A(futures::future::ok(HttpResponse::ServiceUnavailable().finish()));
+        }
+
+        futures::future::Either::B(query_page(&raft, query.clone(), None, limit).map(move |page| {
+            let (items, cursor) = match page {
+                Some(page) => page,
+                None => return HttpResponse::BadRequest().finish(),
+            };
+
+            let bytes = items.iter().map(Vec::len).sum::<usize>();
+            if cursor.is_none() && bytes <= inline_max_bytes {
+                let body = format!("[{}]", items.iter().map(|item| String::from_utf8_lossy(item)).collect::<Vec<_>>().join(","));
+                return HttpResponse::Ok().content_type("application/json").body(body);
+            }
+
+            let first = stream::once(Ok(ndjson(items)));
+            let rest = stream::unfold(cursor, move |cursor| {
+                let raft = raft.clone();
+                let query = query.clone();
+                cursor.map(move |cursor| {
+                    query_page(&raft, query, Some(cursor), limit).and_then(|page| match page {
+                        Some((items, cursor)) => Ok((ndjson(items), cursor)),
+                        // cut the transfer short, the client sees the response end abnormally
+                        None => Err(error::ErrorInternalServerError("query page failed")),
+                    })
+                })
+            });
+            HttpResponse::Ok().content_type("application/x-ndjson").streaming(first.chain(rest))
+        }))
+    }))
+}
+
+/// A page of the answer to a query with its items as JSON, `None` if the query failed.
+fn query_page(
+    raft: &Addr<RaftClient>,
+    query: Vec<u8>,
+    cursor: Option<Vec<u8>>,
+    limit: usize,
+) -> impl Future<Item = Option<(Vec<Vec<u8>>, Option<Vec<u8>>)>, Error = Error> {
+    raft.send(QueryAppPage {
+        query: query,
+        cursor: cursor,
+        limit: limit,
+    })
+    .map_err(Error::from)
+    .map(|res| {
+        let page = res.ok()?;
+        let items = page
+            .items
+            .iter()
+            .map(|item| rmps::from_slice::<Value>(item).ok().and_then(|item| serde_json::to_vec(&item).ok()))
+            .collect::<Option<Vec<_>>>()?;
+        Some((items, page.cursor))
+    })
+}
+
+fn ndjson(items: Vec<Vec<u8>>) -> Bytes {
+    let mut body = Vec::with_capacity(items.iter().map(|item| item.len() + 1).sum());
+    for item in items {
+        body.extend_from_slice(&item);
+        body.push(b'\n');
+    }
+    Bytes::from(body)
+}
+
+/// Redirect to the given path on the leader, or answer unavailable when there is none.
+fn leader_redirect(srv: &ServerData, path: &'static str) -> impl Future<Item = HttpResponse, Error = Error> {
+    srv.cluster_net
+        .send(GetRaftMetrics)
+        .join(srv.cluster_net.send(GetNodes))
+        .map_err(Error::from)
+        .map(move |(metrics, nodes)| {
+            // never redirect to ourselves
+            let addr = metrics
+                .ok()
+                .and_then(|metrics| metrics)
+                .and_then(|metrics| metrics.current_leader.filter(|leader| *leader != metrics.id))
+                .and_then(|leader| nodes.ok().and_then(|nodes| nodes.get(&leader).cloned()))
+                .map(|info| info.public_addr);
+
+            match addr {
+                Some(addr) => HttpResponse::TemporaryRedirect()
+                    .header(header::LOCATION, format!("http://{}{}", addr, path))
+                    .finish(),
+                None => HttpResponse::ServiceUnavailable().finish(),
+            }
         })
+}
+
+fn gateway_read_route(
+    query: web::Query<GatewayRead>,
+    srv: web::Data<Arc<ServerData>>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let query = query.into_inner();
+    let net = srv.net.clone();
+    let epoch = srv.epoch();
+
+    // a session's reads see its writes, whichever gateway they went through
+    let watermark = match query.session {
+        Some(session) => futures::future::Either::A(srv.raft.send(GetClientSession(session)).map_err(Error::from).map(
+            |res| match res {
+                Ok(session) => Ok(session.map(|session| session.watermark)),
+                Err(_) => Err(()),
+            },
+        )),
+        None => futures::future::Either::B(futures::future::ok(Ok(None))),
+    };
+
+    watermark.and_then(move |watermark| match watermark {
+        Ok(watermark) => futures::future::Either::A(net.send(GetNode(query.key)).map_err(Error::from).map(
+            move |res| match res {
+                Ok((node_id, addr)) => {
+                    let mut res = HttpResponse::Ok();
+                    res.header("X-Raftor-Epoch", epoch.to_string());
+                    if let Some(watermark) = watermark {
+                        res.header("X-Raftor-Watermark", watermark.to_string());
+                    }
+                    res.json((node_id, addr))
+                }
+                Err(_) => HttpResponse::NotFound().finish(),
+            },
+        )),
+        Err(_) => futures::future::Either::B(futures::future::ok(HttpResponse::ServiceUnavailable().finish())),
+    })
+}
+
+/// JSON gateway for services which don't implement the wire protocol.
+fn admin_status_route(srv: web::Data<Arc<ServerData>>) -> impl Future<Item = HttpResponse, Error = Error> {
+    srv.cluster_net
+        .send(GetClusterStatus)
         .map_err(Error::from)
         .and_then(|res| Ok(HttpResponse::Ok().json(res)))
 }
 
-fn nodes_route(
-    req: HttpRequest,
-    stream: web::Payload,
+#[derive(Deserialize)]
+struct TopologyQuery {
+    /// `json`, the default, or `dot`
+    format: Option<String>,
+}
+
+fn topology_route(
+    query: web::Query<TopologyQuery>,
     srv: web::Data<Arc<ServerData>>,
 ) -> impl Future<Item = HttpResponse, Error = Error> {
-    srv.net
-        .send(GetNodes)
+    let dot = query.format.as_ref().map(|format| format == "dot").unwrap_or(false);
+
+    srv.cluster_net
+        .send(GetTopology)
+        .map_err(Error::from)
+        .and_then(move |res| match res {
+            Ok(topology) if dot => Ok(HttpResponse::Ok().content_type("text/vnd.graphviz").body(topology.to_dot())),
+            Ok(topology) => Ok(HttpResponse::Ok().json(topology)),
+            Err(_) => Ok(HttpResponse::InternalServerError().finish()),
+        })
+}
+
+fn admin_add_node_route(
+    info: web::Json<NodeInfo>,
+    srv: web::Data<Arc<ServerData>>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let info = info.into_inner();
+    let id = utils::node_id(&info);
+
+    srv.cluster_net
+        .send(AddMember(id, info))
+        .map_err(Error::from)
+        .and_then(move |res| match res {
+            Ok(_) => Ok(HttpResponse::Ok().json(id)),
+            Err(err) => Ok(HttpResponse::Conflict().json(err)),
+        })
+}
+
+fn admin_transfer_leader_route(srv: web::Data<Arc<ServerData>>) -> impl Future<Item = HttpResponse, Error = Error> {
+    srv.cluster_net
+        .send(TransferLeadership)
+        .map_err(Error::from)
+        .and_then(|res| match res {
+            Ok(leader) => Ok(HttpResponse::Ok().json(leader)),
+            Err(err) => Ok(HttpResponse::Conflict().json(err)),
+        })
+}
+
+fn admin_shutdown_route(srv: web::Data<Arc<ServerData>>) -> impl Future<Item = HttpResponse, Error = Error> {
+    let app_net = srv.net.clone();
+
+    srv.cluster_net
+        .send(Shutdown)
+        .and_then(move |_| app_net.send(Shutdown))
+        .map_err(Error::from)
+        .and_then(|_| {
+            // exit once the answer went out
+            Arbiter::spawn(
+                raftor::clock::delay(std::time::Duration::from_millis(500))
+                    .then(|_| {
+                        System::current().stop();
+                        Ok(())
+                    }),
+            );
+            Ok(HttpResponse::Ok().finish())
+        })
+}
+
+fn cordon_route(
+    id: web::Path<NodeId>,
+    srv: web::Data<Arc<ServerData>>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    propose_cordon(id.into_inner(), true, srv)
+}
+
+fn uncordon_route(
+    id: web::Path<NodeId>,
+    srv: web::Data<Arc<ServerData>>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    propose_cordon(id.into_inner(), false, srv)
+}
+
+fn propose_cordon(
+    node: NodeId,
+    cordoned: bool,
+    srv: web::Data<Arc<ServerData>>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let proposal = ClientProposal {
+        client: "admin".to_owned(),
+        key: None,
+        data: MemoryStorageData::Cordon {
+            node: node,
+            cordoned: cordoned,
+        },
+        deadline_ms: None,
+    };
+
+    srv.raft
+        .send(proposal)
+        .map_err(Error::from)
+        .and_then(|res| match res {
+            Ok(ClientPayloadResponse::Applied { index, .. })
+            | Ok(ClientPayloadResponse::Committed { index }) => Ok(HttpResponse::Ok().json(index)),
+            Err(_) => Ok(HttpResponse::ServiceUnavailable().finish()),
+        })
+}
+
+fn pending_joins_route(srv: web::Data<Arc<ServerData>>) -> impl Future<Item = HttpResponse, Error = Error> {
+    srv.cluster_net
+        .send(GetPendingJoins)
         .map_err(Error::from)
         .and_then(|res| Ok(HttpResponse::Ok().json(res)))
 }
 
-fn state_route(
-    req: HttpRequest,
-    stream: web::Payload,
+fn cluster_metadata_route(srv: web::Data<Arc<ServerData>>) -> impl Future<Item = HttpResponse, Error = Error> {
+    srv.cluster_net
+        .send(GetClusterMetadata)
+        .map_err(Error::from)
+        .and_then(|res| match res {
+            Some(metadata) => Ok(HttpResponse::Ok().json(metadata)),
+            None => Ok(HttpResponse::NotFound().finish()),
+        })
+}
+
+fn set_cluster_metadata_route(
+    metadata: web::Json<ClusterMetadata>,
     srv: web::Data<Arc<ServerData>>,
 ) -> impl Future<Item = HttpResponse, Error = Error> {
-    srv.net
-        .send(GetClusterState)
+    let metadata = metadata.into_inner();
+    if let Some(reason) = metadata.invalid() {
+        return futures::future::Either::A(futures::future::ok(HttpResponse::BadRequest().json(reason)));
+    }
+
+    let proposal = ClientProposal {
+        client: "admin".to_owned(),
+        key: None,
+        data: MemoryStorageData::SetClusterMetadata(metadata),
+        deadline_ms: None,
+    };
+
+    futures::future::Either::B(srv.raft.send(proposal).map_err(Error::from).and_then(|res| match res {
+        Ok(ClientPayloadResponse::Applied { index, .. })
+        | Ok(ClientPayloadResponse::Committed { index }) => Ok(HttpResponse::Ok().json(index)),
+        Err(_) => Ok(HttpResponse::ServiceUnavailable().finish()),
+    }))
+}
+
+fn approve_join_route(
+    id: web::Path<NodeId>,
+    srv: web::Data<Arc<ServerData>>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    srv.cluster_net
+        .send(ApproveJoin(id.into_inner()))
+        .map_err(Error::from)
+        .and_then(|res| match res {
+            Ok(status) => Ok(HttpResponse::Ok().json(status)),
+            Err(JoinError::Refused(reason)) => Ok(HttpResponse::Forbidden().json(reason)),
+            Err(JoinError::Propose(err)) => Ok(HttpResponse::Conflict().json(err)),
+        })
+}
+
+fn reject_join_route(
+    id: web::Path<NodeId>,
+    srv: web::Data<Arc<ServerData>>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    srv.cluster_net
+        .send(RejectJoin(id.into_inner()))
+        .map_err(Error::from)
+        .and_then(|pending| {
+            if pending {
+                Ok(HttpResponse::Ok().json(()))
+            } else {
+                Ok(HttpResponse::NotFound().json(()))
+            }
+        })
+}
+
+fn groups_route(srv: web::Data<Arc<ServerData>>) -> impl Future<Item = HttpResponse, Error = Error> {
+    srv.groups
+        .send(GetGroupMetrics)
         .map_err(Error::from)
         .and_then(|res| Ok(HttpResponse::Ok().json(res)))
 }
 
-fn room_route(
-    req: HttpRequest,
-    stream: web::Payload,
+fn group_heartbeats_route(srv: web::Data<Arc<ServerData>>) -> impl Future<Item = HttpResponse, Error = Error> {
+    srv.groups
+        .send(GetHeartbeatStats)
+        .map_err(Error::from)
+        .map(|stats| HttpResponse::Ok().json(stats))
+}
+
+#[derive(Deserialize)]
+struct NewGroup {
+    group: GroupId,
+    members: Vec<NodeId>,
+}
+
+fn create_group_route(
+    group: web::Json<NewGroup>,
     srv: web::Data<Arc<ServerData>>,
-) -> HttpResponse {
-    let room_id = req.match_info().get("room_id").unwrap_or("");
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let group = group.into_inner();
 
-    srv.server.do_send(server::CreateRoom {
-        room_id: room_id.to_owned(),
-    });
-    HttpResponse::Ok().body("room created")
+    srv.groups
+        .send(CreateGroup { group: group.group, members: group.members })
+        .map_err(Error::from)
+        .and_then(|res| match res {
+            Ok(_) => Ok(HttpResponse::Ok().json(())),
+            Err(err) => Ok(HttpResponse::Conflict().json(err)),
+        })
 }
 
-fn ws_route(
-    req: HttpRequest,
-    stream: web::Payload,
+fn remove_group_route(
+    group: web::Path<GroupId>,
     srv: web::Data<Arc<ServerData>>,
-) -> Result<HttpResponse, Error> {
-    let uid = req.match_info().get("uid").unwrap_or("");
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    srv.groups
+        .send(RemoveGroup(group.into_inner()))
+        .map_err(Error::from)
+        .and_then(|res| match res {
+            Ok(_) => Ok(HttpResponse::Ok().json(())),
+            Err(err) => Ok(HttpResponse::Conflict().json(err)),
+        })
+}
 
-    ws::start(Session::new(uid, "main", srv.server.clone()), &req, stream)
+#[derive(Deserialize)]
+struct GroupMembersChange {
+    #[serde(default)]
+    add: Vec<NodeId>,
+    #[serde(default)]
+    remove: Vec<NodeId>,
+}
+
+fn group_members_route(
+    group: web::Path<GroupId>,
+    change: web::Json<GroupMembersChange>,
+    srv: web::Data<Arc<ServerData>>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let change = change.into_inner();
+
+    srv.groups
+        .send(ChangeGroupMembership { group: group.into_inner(), add: change.add, remove: change.remove })
+        .map_err(Error::from)
+        .and_then(|res| match res {
+            Ok(_) => Ok(HttpResponse::Ok().json(())),
+            Err(err) => Ok(HttpResponse::Conflict().json(err)),
+        })
+}
+
+fn export_group_route(
+    group: web::Path<GroupId>,
+    srv: web::Data<Arc<ServerData>>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    srv.groups
+        .send(ExportGroupCheckpoint(group.into_inner()))
+        .map_err(Error::from)
+        .and_then(|res| match res {
+            Ok(checkpoint) => Ok(HttpResponse::Ok().json(checkpoint)),
+            Err(err) => Ok(HttpResponse::Conflict().json(err)),
+        })
+}
+
+#[derive(Deserialize)]
+struct GroupImport {
+    checkpoint: GroupCheckpoint,
+    members: Vec<NodeId>,
+}
+
+fn import_group_route(
+    import: web::Json<GroupImport>,
+    srv: web::Data<Arc<ServerData>>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let import = import.into_inner();
+
+    srv.groups
+        .send(ImportGroupCheckpoint { checkpoint: import.checkpoint, members: import.members })
+        .map_err(Error::from)
+        .and_then(|res| match res {
+            Ok(imported) => Ok(HttpResponse::Ok().json(imported)),
+            Err(err @ CheckpointError::Corrupt) | Err(err @ CheckpointError::Unsealed) => Ok(HttpResponse::BadRequest().json(err)),
+            Err(err) => Ok(HttpResponse::Conflict().json(err)),
+        })
+}
+
+fn create_backup_route(srv: web::Data<Arc<ServerData>>) -> impl Future<Item = HttpResponse, Error = Error> {
+    srv.raft
+        .send(CreateBackup)
+        .map_err(Error::from)
+        .and_then(|res| match res {
+            Ok(manifest) => Ok(HttpResponse::Ok().json(manifest)),
+            Err(err @ BackupError::Io(_)) => Ok(HttpResponse::InternalServerError().json(err)),
+            Err(err) => Ok(HttpResponse::Conflict().json(err)),
+        })
+}
+
+fn restore_backup_route(
+    name: web::Json<String>,
+    srv: web::Data<Arc<ServerData>>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    // replacing the state of the cluster is only open to token holders
+    if !srv.admin_auth {
+        return futures::future::Either::A(futures::future::ok(HttpResponse::Forbidden().finish()));
+    }
+
+    futures::future::Either::B(srv.raft
+        .send(RestoreBackup(name.into_inner()))
+        .map_err(Error::from)
+        .and_then(|res| match res {
+            Ok(restored) => Ok(HttpResponse::Ok().json(restored)),
+            Err(err @ BackupError::Corrupt(_)) => Ok(HttpResponse::BadRequest().json(err)),
+            Err(err @ BackupError::Io(_)) => Ok(HttpResponse::InternalServerError().json(err)),
+            Err(err) => Ok(HttpResponse::Conflict().json(err)),
+        }))
+}
+
+fn admin_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/cluster/status").route(web::get().to_async(admin_status_route)))
+        .service(web::resource("/cluster/topology").route(web::get().to_async(topology_route)))
+        .service(web::resource("/cluster/nodes").route(web::post().to_async(admin_add_node_route)))
+        .service(web::resource("/cluster/nodes/{id}").route(web::delete().to_async(prepare_removal_route)))
+        .service(
+            web::resource("/cluster/nodes/{id}/cordon")
+                .route(web::put().to_async(cordon_route))
+                .route(web::delete().to_async(uncordon_route)),
+        )
+        .service(web::resource("/cluster/backups").route(web::post().to_async(create_backup_route)))
+        .service(web::resource("/cluster/restore").route(web::post().to_async(restore_backup_route)))
+        .service(web::resource("/admin/export").route(web::put().to_async(export_route)))
+        .service(web::resource("/cluster/transfer-leader").route(web::post().to_async(admin_transfer_leader_route)))
+        .service(web::resource("/cluster/shutdown").route(web::post().to_async(admin_shutdown_route)))
+        .service(web::resource("/admin/propose").route(web::put().to_async(propose_route)))
+        .service(web::resource("/admin/tls/reload").route(web::post().to_async(reload_tls_route)))
+        .service(web::resource("/admin/support-bundle").route(web::put().to_async(support_bundle_route)))
+        .service(web::resource("/admin/truncate-log").route(web::put().to_async(truncate_log_route)))
+        .service(web::resource("/admin/maintenance").route(web::put().to_async(maintenance_route)))
+        .service(web::resource("/admin/debug-peer").route(web::put().to(debug_peer_route)))
+        .service(web::resource("/admin/freeze").route(web::put().to_async(freeze_route)))
+        .service(
+            web::resource("/admin/address-migration")
+                .route(web::get().to_async(address_migration_route))
+                .route(web::put().to_async(start_address_migration_route))
+                .route(web::delete().to(abort_address_migration_route)),
+        )
+        .service(web::resource("/admin/address-migration/next").route(web::put().to_async(migrate_next_address_route)))
+        .service(web::resource("/cluster/members").route(web::put().to_async(add_member_route)))
+        .service(
+            web::resource("/admin/vacuum")
+                .route(web::get().to_async(vacuum_progress_route))
+                .route(web::put().to_async(vacuum_route)),
+        )
+        .service(
+            web::resource("/admin/audit")
+                .route(web::get().to(audit_route))
+                .route(web::put().to(audit_switch_route))
+                .route(web::delete().to(audit_reset_route)),
+        )
+        .service(web::resource("/admin/local-state").route(web::post().to_async(local_state_route)))
+        .service(
+            web::resource("/admin/egress-limit")
+                .route(web::get().to_async(egress_limit_route))
+                .route(web::put().to_async(set_egress_limit_route)),
+        )
+        .service(web::resource("/cluster/changes").route(web::post().to_async(prepare_membership_route)))
+        .service(web::resource("/cluster/changes/{token}/confirm").route(web::post().to_async(confirm_membership_route)))
+        .service(web::resource("/admin/projections/{name}/rebuild").route(web::post().to_async(rebuild_projection_route)))
+        .service(web::resource("/admin/rejoin").route(web::post().to_async(rejoin_route)))
+        .service(web::resource("/cluster/joins").route(web::get().to_async(pending_joins_route)))
+        .service(web::resource("/cluster/learners").route(web::get().to_async(learners_route)))
+        .service(web::resource("/cluster/epoch").route(web::get().to(epoch_route)))
+        .service(
+            web::resource("/cluster/metadata")
+                .route(web::get().to_async(cluster_metadata_route))
+                .route(web::put().to_async(set_cluster_metadata_route)),
+        )
+        .service(
+            web::resource("/cluster/joins/{id}")
+                .route(web::put().to_async(approve_join_route))
+                .route(web::delete().to_async(reject_join_route)),
+        )
+        .service(
+            web::resource("/groups")
+                .route(web::get().to_async(groups_route))
+                .route(web::post().to_async(create_group_route)),
+        )
+        .service(web::resource("/groups/import").route(web::post().to_async(import_group_route)))
+        .service(web::resource("/groups/heartbeats").route(web::get().to_async(group_heartbeats_route)))
+        .service(web::resource("/groups/{id}").route(web::delete().to_async(remove_group_route)))
+        .service(web::resource("/groups/{id}/members").route(web::put().to_async(group_members_route)))
+        .service(web::resource("/groups/{id}/checkpoint").route(web::post().to_async(export_group_route)));
+}
+
+fn gateway_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/propose").route(web::post().to_async(gateway_propose_route)))
+        .service(web::resource("/propose/blob").route(web::post().to_async(gateway_propose_blob_route)))
+        .service(web::resource("/read").route(web::get().to_async(gateway_read_route)))
+        .service(web::resource("/query").route(web::post().to_async(gateway_query_route)));
 }
 
 struct ServerData {
     server: Addr<Server>,
     net: Addr<Network>,
+    cluster_net: Addr<Network>,
     raft: Addr<RaftClient>,
+    groups: Addr<RaftGroups>,
+    raftor: Addr<Raftor>,
+    read_streaming: ReadStreaming,
+    epoch: ClusterEpoch,
+    replay: LogReplay,
+    /// Whether the admin listener requires a token
+    admin_auth: bool,
+    /// Token of `PUT /federation/clusters`, announcements are refused without one
+    federation_token: Option<String>,
+    /// Bearer tokens of the gateway clients, see `client_tokens`
+    client_tokens: BTreeMap<String, String>,
+}
+
+impl ServerData {
+    /// Cluster epoch of this node, sent along client responses, see `ClusterEpoch`.
+    fn epoch(&self) -> u64 {
+        self.epoch.load(Ordering::SeqCst)
+    }
+}
+
+/// Run the log store conformance kit against the built-in stores, returns whether they all
+/// pass.
+#[cfg(feature = "persistent")]
+fn storage_testkit() -> bool {
+    use raftor::raft::durable::{LogStore, RocksLogStore};
+    use raftor::raft::storage::testkit;
+
+    let report = testkit::run(|dir| Ok(Box::new(RocksLogStore::open(dir)?) as Box<dyn LogStore>));
+    println!("{}", report);
+    report.passed()
+}
+
+#[cfg(not(feature = "persistent"))]
+fn storage_testkit() -> bool {
+    eprintln!("No log store built in, build with --features persistent");
+    false
 }
 
 fn main() {
     env_logger::init();
 
-    let sys = System::new("raftor");
+    let args: Vec<String> = env::args().collect();
+
+    if args.get(1).map(|arg| arg.as_str()) == Some("selftest") {
+        let base_port = match args.get(2).and_then(|arg| arg.parse().ok()).or_else(selftest::free_base_port) {
+            Some(port) => port,
+            None => {
+                eprintln!("No free ports for a local cluster, pass a base port");
+                std::process::exit(1);
+            }
+        };
+        let sys = System::new("raftor-selftest");
+        SelfTest::new(base_port).start();
+
+        std::process::exit(if sys.run().is_ok() { 0 } else { 1 });
+    }
+
+    if args.get(1).map(|arg| arg.as_str()) == Some("nemesis") {
+        let sys = System::new("raftor-nemesis");
+        let mut config = NemesisConfig::new(args.get(3).cloned().unwrap_or("history.jsonl".to_owned()).into());
+
+        if let Some(secs) = args.get(2).and_then(|arg| arg.parse().ok()) {
+            config.duration = std::time::Duration::from_secs(secs);
+        }
+        if let Some(seed) = args.get(4).and_then(|arg| arg.parse().ok()) {
+            config.seed = seed;
+        }
+
+        let base_port = match selftest::free_base_port() {
+            Some(port) => port,
+            None => {
+                eprintln!("No free ports for a local cluster");
+                std::process::exit(1);
+            }
+        };
+
+        match Nemesis::new(base_port, config) {
+            Ok(nemesis) => {
+                nemesis.start();
+            }
+            Err(err) => {
+                eprintln!("Failed to create the history file: {}", err);
+                std::process::exit(1);
+            }
+        }
+
+        std::process::exit(if sys.run().is_ok() { 0 } else { 1 });
+    }
+
+    if args.get(1).map(|arg| arg.as_str()) == Some("check") {
+        let history = args.get(2).cloned().unwrap_or("history.jsonl".to_owned());
+
+        match linearizability::check_file(&history) {
+            Ok(report) => {
+                println!("{}", serde_json::to_string_pretty(&report).unwrap());
+                std::process::exit(if report.verdict == Verdict::Linearizable { 0 } else { 1 });
+            }
+            Err(err) => {
+                eprintln!("Failed to check {}: {}", history, err);
+                std::process::exit(2);
+            }
+        }
+    }
+
+    if args.get(1).map(|arg| arg.as_str()) == Some("events") {
+        let sys = System::new("raftor-events");
+        let flags = args.iter().skip(2).filter(|arg| arg.starts_with("--")).collect::<Vec<_>>();
+        let target = args
+            .iter()
+            .skip(2)
+            .find(|arg| !arg.starts_with("--"))
+            .cloned()
+            .unwrap_or("127.0.0.1:8080".to_owned());
+
+        Observer::new(ObserverConfig {
+            target: target,
+            follow: flags.iter().any(|flag| *flag == "--follow"),
+            json: flags.iter().any(|flag| *flag == "--json"),
+        })
+        .start();
+
+        let _ = sys.run();
+        return;
+    }
 
+    if args.get(1).map(|arg| arg.as_str()) == Some("bench") {
+        let sys = System::new("raftor-bench");
+        let mut positional = Vec::new();
+        let mut admin = None;
+        let mut admin_token = None;
+        let mut rest = args.iter().skip(2);
+        while let Some(arg) = rest.next() {
+            match arg.as_str() {
+                "--admin" => admin = rest.next().cloned(),
+                "--admin-token" => admin_token = rest.next().cloned(),
+                arg => positional.push(arg.to_owned()),
+            }
+        }
+
+        let mut config = BenchConfig::new(positional.get(0).cloned().unwrap_or("127.0.0.1:8080".to_owned()));
+        if let Some(admin) = admin {
+            config.admin = admin;
+        }
+        config.admin_token = admin_token;
+
+        if let Some(requests) = positional.get(1).and_then(|arg| arg.parse().ok()) {
+            config.requests = std::cmp::max(requests, 1);
+        }
+        if let Some(concurrency) = positional.get(2).and_then(|arg| arg.parse().ok()) {
+            config.concurrency = std::cmp::max(concurrency, 1);
+        }
+        if let Some(read_ratio) = positional.get(3).and_then(|arg| arg.parse().ok()) {
+            config.read_ratio = read_ratio;
+        }
+
+        Bench::new(config).start();
+
+        let _ = sys.run();
+        return;
+    }
+
+    if args.get(1).map(|arg| arg.as_str()) == Some("conformance") {
+        let addr = args.get(2).cloned().unwrap_or("127.0.0.1:7070".to_owned());
+        let token = args.get(3).map(String::as_str);
+
+        std::process::exit(if conformance::run(&addr, token) { 0 } else { 1 });
+    }
+
+    if args.get(1).map(|arg| arg.as_str()) == Some("storage-testkit") {
+        std::process::exit(if storage_testkit() { 0 } else { 1 });
+    }
+
+    if args.get(1).map(|arg| arg.as_str()) == Some("start") && args.iter().any(|arg| arg == "--check") {
+        std::process::exit(check_config(&args));
+    }
+
+    if args.get(1).map(|arg| arg.as_str()) == Some("drill") {
+        if args.get(2).map(|arg| arg.as_str()) != Some("failover") {
+            eprintln!("Usage: raftor drill failover ADMIN_ADDRESS... [--sla-ms MILLIS] [--admin-token TOKEN] [--yes]");
+            std::process::exit(2);
+        }
+
+        let mut config = DrillConfig::new(Vec::new());
+        let mut confirmed = false;
+        let mut rest = args.iter().skip(3);
+        while let Some(arg) = rest.next() {
+            match arg.as_str() {
+                "--yes" => confirmed = true,
+                "--sla-ms" => {
+                    if let Some(millis) = rest.next().and_then(|arg| arg.parse().ok()) {
+                        config.sla = std::time::Duration::from_millis(millis);
+                    }
+                }
+                "--admin-token" => config.admin_token = rest.next().cloned(),
+                target => config.targets.push(target.to_owned()),
+            }
+        }
+        if config.targets.is_empty() {
+            config.targets.push("127.0.0.1:9080".to_owned());
+        }
+
+        if !confirmed {
+            println!("The leader among {} will step down, continue? [y/N]", config.targets.join(", "));
+            let mut answer = String::new();
+            let _ = std::io::stdin().read_line(&mut answer);
+            if answer.trim() != "y" && answer.trim() != "yes" {
+                println!("Drill canceled");
+                return;
+            }
+        }
+
+        let sys = System::new("raftor-drill");
+        FailoverDrill::new(config).start();
+
+        std::process::exit(if sys.run().is_ok() { 0 } else { 1 });
+    }
+
+    #[cfg(all(windows, feature = "win-service"))]
+    {
+        if args.iter().any(|arg| arg == "--windows-service") {
+            if let Err(err) = raftor::service::windows::run("raftor", run_node) {
+                eprintln!("Failed to run as a Windows service: {}", err);
+                std::process::exit(1);
+            }
+            return;
+        }
+    }
+
+    std::process::exit(run_node());
+}
+
+/// Validate the configuration of the node on the given addresses without starting it, returns
+/// the exit code: 0 when every check passed or warned, 1 when one failed, 2 when the
+/// configuration doesn't load.
+fn check_config(args: &[String]) -> i32 {
+    let addrs = args.iter().skip(2).filter(|arg| !arg.starts_with("--")).collect::<Vec<_>>();
+    if addrs.len() != 3 {
+        eprintln!("Usage: raftor start --check CLUSTER_ADDRESS APP_ADDRESS PUBLIC_ADDRESS [--probe-peers] [--json]");
+        return 2;
+    }
+
+    let config = match ConfigSchema::load() {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("Invalid configuration: {}", err);
+            return 2;
+        }
+    };
+    let info = Raftor::node_info(&config, addrs[0], addrs[1], addrs[2]);
+    let report = preflight::dry_run(&config, &info, args.iter().any(|arg| arg == "--probe-peers"));
+
+    if args.iter().any(|arg| arg == "--json") {
+        println!("{}", serde_json::to_string_pretty(&report).unwrap());
+    } else {
+        print!("{}", report);
+    }
+
+    if report.failures().is_empty() {
+        0
+    } else {
+        1
+    }
+}
+
+/// Run the node until it stops, returns the exit code of the process.
+fn run_node() -> i32 {
     let args: Vec<String> = env::args().collect();
+
+    // a warm standby waits for the process running the node to go before starting it
+    let standby_lock = if args.iter().any(|arg| arg == "--standby") {
+        let config = ConfigSchema::load().unwrap();
+        let data_dir = match config.data_dir {
+            Some(ref data_dir) => std::path::PathBuf::from(data_dir),
+            None => {
+                eprintln!("A warm standby needs the `data_dir` of the node to take over");
+                std::process::exit(2);
+            }
+        };
+
+        match standby::wait_for_takeover(&data_dir, &config.warm_standby) {
+            Ok(lock) => Some(lock),
+            Err(err) => {
+                eprintln!("Failed to take over {:?}: {}", data_dir, err);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        None
+    };
+
+    let runtime = Runtime::new("raftor");
+
     let public_address = args[3].as_str();
 
-    let mut raftor = Raftor::new();
+    let mut raftor = match standby_lock {
+        Some(lock) => Raftor::supervised_standby(&runtime, lock),
+        None => Raftor::supervised(&runtime),
+    };
+    let serves_clients = raftor.serves_clients();
+    let http_gateway = raftor.http_gateway();
+    let read_streaming = raftor.read_streaming();
+    let admin_addr = raftor.admin_addr();
+    let admin_token = raftor.admin_token();
+    let federation_token = raftor.federation_token();
+    let client_tokens = raftor.client_tokens();
+    let stop_signals = raftor.handles_stop_signals();
+
+    let server = raftor.server.clone();
+    let net = raftor.app_net.clone();
+    let cluster_net = raftor.cluster_net.clone();
+    let raft = raftor.raft.clone();
+    let groups = raftor.groups.clone();
+    let epoch = raftor.epoch();
+    let replay = raftor.log_replay();
 
     let state = Arc::new(ServerData {
-        server: raftor.server.clone(),
-        net: raftor.app_net.clone(),
-        raft: raftor.raft.clone(),
+        server: server,
+        net: net,
+        cluster_net: cluster_net,
+        raft: raft,
+        groups: groups,
+        raftor: raftor.start(),
+        read_streaming: read_streaming,
+        epoch: epoch,
+        replay: replay,
+        admin_auth: admin_token.is_some(),
+        federation_token: federation_token,
+        client_tokens: client_tokens,
     });
 
-    let _ = raftor.start();
+    if let Some(admin_addr) = admin_addr {
+        let state = state.clone();
+
+        let server = HttpServer::new(move || {
+            let admin_token = admin_token.clone();
 
-    HttpServer::new(move || {
+            App::new()
+                .wrap_fn(move |req, srv| {
+                    let authorization = req.headers().get(header::AUTHORIZATION).and_then(|value| value.to_str().ok());
+                    if utils::bearer_authorized(authorization, admin_token.as_ref().map(String::as_str)) {
+                        futures::future::Either::A(srv.call(req))
+                    } else {
+                        futures::future::Either::B(futures::future::ok(req.into_response(HttpResponse::Unauthorized().finish())))
+                    }
+                })
+                .wrap(Logger::default())
+                .data(state.clone())
+                .configure(admin_routes)
+        });
+        // the node is shut down cleanly by its service manager
+        let server = if stop_signals { server.disable_signals() } else { server };
+        server.bind(admin_addr).unwrap().start();
+    }
+
+    let server = HttpServer::new(move || {
         App::new()
             .wrap(
                 Cors::new()
@@ -147,24 +1897,45 @@ fn main() {
             )
             .wrap(Logger::default())
             .data(state.clone())
-            .service(web::resource("/").route(web::get().to(|| {
-                HttpResponse::Found()
-                    .header("LOCATION", "/static/index.html")
-                    .finish()
-            })))
-            .service(web::resource("/node/{uid}").to_async(index_route))
+            .configure(|cfg| if serves_clients { client_routes(cfg) })
+            .configure(|cfg| if http_gateway { gateway_routes(cfg) })
             .service(web::resource("/cluster/nodes").to_async(nodes_route))
+            .service(web::resource("/cluster/nodes/delta").to_async(nodes_delta_route))
             .service(web::resource("/cluster/state").to_async(state_route))
+            .service(web::resource("/cluster/status").to_async(node_status_route))
+            .service(web::resource("/cluster/descriptor").to_async(descriptor_route))
+            .service(web::resource("/cluster/topology").to_async(topology_route))
+            .service(web::resource("/cluster/learners").to_async(learners_route))
+            .service(web::resource("/cluster/epoch").to(epoch_route))
+            .service(web::resource("/cluster/ready").to(ready_route))
+            .service(web::resource("/cluster/progress").to_async(progress_route))
+            .service(web::resource("/cluster/progress/wait/{index}").to_async(wait_applied_route))
+            .service(web::resource("/cluster/read-barrier").to_async(read_barrier_route))
             .service(web::resource("/cluster/join").route(web::put().to_async(join_cluster_route)))
-            .service(web::resource("/room/{room_id}").to_async(room_route))
-            .service(web::resource("/members/{room_id}").to_async(members_route))
-            .service(web::resource("/ws/{uid}").to_async(ws_route))
-            // static resources
-            .service(fs::Files::new("/static/", "static/"))
-    })
-    .bind(public_address)
-    .unwrap()
-    .start();
+            .service(web::resource("/cluster/bootstrap").route(web::put().to(bootstrap_route)))
+            .service(web::resource("/cluster/validate").route(web::put().to_async(validate_membership_route)))
+            .service(web::resource("/admin/projections").to_async(projections_route))
+            .service(web::resource("/locks/{name}/acquire").route(web::put().to_async(acquire_lock_route)))
+            .service(web::resource("/locks/{name}/release").route(web::put().to_async(release_lock_route)))
+            .service(web::resource("/locks/{name}/validate").route(web::put().to_async(validate_token_route)))
+            .service(web::resource("/admin/timings").to_async(timings_route))
+            .service(web::resource("/admin/log").to_async(log_route))
+            .service(web::resource("/admin/pending-proposals").to_async(pending_proposals_route))
+            .service(web::resource("/admin/write-metrics").to_async(write_metrics_route))
+            .service(web::resource("/admin/leadership-history").to_async(leadership_history_route))
+            .service(web::resource("/admin/events").to_async(events_route))
+            .service(web::resource("/admin/cpu").to(cpu_route))
+            .service(web::resource("/metrics").to_async(metrics_route))
+            .service(web::resource("/admin/info").to_async(node_info_route))
+            .service(
+                web::resource("/federation/clusters")
+                    .route(web::get().to_async(clusters_route))
+                    .route(web::put().to_async(register_cluster_route)),
+            )
+            .service(web::resource("/federation/lookup/{namespace}").to_async(lookup_cluster_route))
+    });
+    let server = if stop_signals { server.disable_signals() } else { server };
+    server.bind(public_address).unwrap().start();
 
-    let _ = sys.run();
+    runtime.run()
 }
@@ -0,0 +1,156 @@
+//! Accounting of the bytes held by the in-memory structures of a node.
+
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use crate::config::MemoryLimits;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MemoryArea {
+    LogCache,
+    PendingProposals,
+    OutboundQueues,
+    Events,
+}
+
+impl MemoryArea {
+    pub const ALL: [MemoryArea; 4] = [
+        MemoryArea::LogCache,
+        MemoryArea::PendingProposals,
+        MemoryArea::OutboundQueues,
+        MemoryArea::Events,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            MemoryArea::LogCache => "log_cache",
+            MemoryArea::PendingProposals => "pending_proposals",
+            MemoryArea::OutboundQueues => "outbound_queues",
+            MemoryArea::Events => "events",
+        }
+    }
+
+    fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+/// Bytes held and shed by area, shared by the structures of a node. Sizes are those of the
+/// serialized data, which is close enough to what the structures hold to bound them.
+pub struct MemoryCounters {
+    limits: MemoryLimits,
+    used: [AtomicUsize; 4],
+    shed: [AtomicU64; 4],
+}
+
+pub type MemoryAccount = Arc<MemoryCounters>;
+
+impl MemoryCounters {
+    pub fn new(limits: MemoryLimits) -> MemoryAccount {
+        Arc::new(MemoryCounters {
+            limits: limits,
+            used: Default::default(),
+            shed: Default::default(),
+        })
+    }
+
+    pub fn limit(&self, area: MemoryArea) -> Option<usize> {
+        match area {
+            MemoryArea::LogCache => self.limits.log_cache_bytes,
+            MemoryArea::PendingProposals => self.limits.pending_proposal_bytes,
+            MemoryArea::OutboundQueues => self.limits.outbound_queue_bytes,
+            MemoryArea::Events => self.limits.event_bytes,
+        }
+    }
+
+    pub fn used(&self, area: MemoryArea) -> usize {
+        self.used[area.index()].load(Ordering::Relaxed)
+    }
+
+    pub fn grow(&self, area: MemoryArea, bytes: usize) {
+        self.used[area.index()].fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn shrink(&self, area: MemoryArea, bytes: usize) {
+        self.used[area.index()].fetch_sub(bytes, Ordering::Relaxed);
+    }
+
+    /// Whether `bytes` more would take the area over its limit.
+    pub fn exceeds(&self, area: MemoryArea, bytes: usize) -> bool {
+        match self.limit(area) {
+            Some(limit) => self.used(area) + bytes > limit,
+            None => false,
+        }
+    }
+
+    /// Count something shed to stay within the limit of an area.
+    pub fn shed(&self, area: MemoryArea) {
+        self.shed[area.index()].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn usage(&self) -> Vec<MemoryUsage> {
+        MemoryArea::ALL
+            .iter()
+            .map(|area| MemoryUsage {
+                area: area.name().to_owned(),
+                bytes: self.used(*area),
+                limit: self.limit(*area),
+                shed: self.shed[area.index()].load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+}
+
+impl Default for MemoryCounters {
+    fn default() -> Self {
+        MemoryCounters {
+            limits: MemoryLimits::default(),
+            used: Default::default(),
+            shed: Default::default(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MemoryUsage {
+    pub area: String,
+    pub bytes: usize,
+    pub limit: Option<usize>,
+    /// Log entries spilled, proposals or requests rejected, or events dropped over the limit
+    pub shed: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn areas_over_their_limit_are_told_apart() {
+        let memory = MemoryCounters::new(MemoryLimits {
+            outbound_queue_bytes: Some(100),
+            ..MemoryLimits::default()
+        });
+
+        memory.grow(MemoryArea::OutboundQueues, 60);
+        assert!(!memory.exceeds(MemoryArea::OutboundQueues, 40));
+        assert!(memory.exceeds(MemoryArea::OutboundQueues, 41));
+        // areas without a limit never exceed it
+        assert!(!memory.exceeds(MemoryArea::LogCache, usize::max_value() / 2));
+
+        memory.shrink(MemoryArea::OutboundQueues, 60);
+        assert!(!memory.exceeds(MemoryArea::OutboundQueues, 100));
+    }
+
+    #[test]
+    fn usage_reports_every_area() {
+        let memory = MemoryCounters::new(MemoryLimits::default());
+        memory.grow(MemoryArea::Events, 10);
+        memory.shed(MemoryArea::Events);
+
+        let usage = memory.usage();
+        assert_eq!(usage.len(), MemoryArea::ALL.len());
+        let events = usage.iter().find(|usage| usage.area == "events").unwrap();
+        assert_eq!((events.bytes, events.limit, events.shed), (10, None, 1));
+    }
+}
@@ -0,0 +1,480 @@
+//! Fault injection against a local test cluster, in the spirit of Jepsen's nemesis.
+//!
+//! A workload of hash ring adds and removes runs against a local three node cluster while
+//! faults are injected on a schedule: network partitions, clock jumps, crashes and disk
+//! errors. Every operation is recorded in a history file as JSON lines, an `invoke` event when
+//! it's sent followed by `ok`, `fail` or `info` (the outcome is unknown) once it completes, in
+//! the format the usual linearizability checkers expect. The history is checked with
+//! `linearizability::check_file` at the end of the run. Runs are reproducible from their seed.
+//!
+//! Reads commit an empty batch through the node as a barrier, wait for the node to apply it
+//! and then look the key up in its state machine.
+
+use actix::prelude::*;
+use actix_raft::{
+    messages::{ClientError, ClientPayloadResponse},
+    NodeId,
+};
+use serde::Serialize;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::linearizability::{self, Verdict};
+use crate::network::{SetPartition, WaitForApplied};
+use crate::raft::{
+    storage::{MemoryStorageData, StorageFault, StorageFaulted},
+    ClientProposal, IsRingMember, SkewClock,
+};
+use crate::raftor::Kill;
+use crate::selftest::{local_cluster, TestNode};
+
+/// How long an operation may take before its outcome is recorded as unknown
+const OP_TIMEOUT: Duration = Duration::from_secs(5);
+/// Number of hash ring keys the workload writes to
+const KEYS: u64 = 8;
+/// Largest clock jump injected, in milliseconds
+const MAX_CLOCK_JUMP_MS: i64 = 60_000;
+
+/// Schedule of a nemesis run.
+#[derive(Clone, Debug)]
+pub struct NemesisConfig {
+    pub duration: Duration,
+    pub fault_interval: Duration,
+    pub op_interval: Duration,
+    pub seed: u64,
+    /// File the history is written to
+    pub history: PathBuf,
+}
+
+impl NemesisConfig {
+    pub fn new(history: PathBuf) -> NemesisConfig {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(1);
+
+        NemesisConfig {
+            duration: Duration::from_secs(60),
+            fault_interval: Duration::from_secs(5),
+            op_interval: Duration::from_millis(50),
+            seed: seed,
+            history: history,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OpKind {
+    Invoke,
+    Ok,
+    Fail,
+    /// The operation may or may not have taken effect
+    Info,
+}
+
+/// One line of the history.
+#[derive(Debug, Serialize)]
+pub struct HistoryEvent {
+    /// Client process, or `nemesis` for injected faults
+    pub process: String,
+    #[serde(rename = "type")]
+    pub kind: OpKind,
+    pub f: String,
+    /// `[key, member]` of operations, the membership only known for completed reads
+    pub value: serde_json::Value,
+    /// Nanoseconds since the start of the run
+    pub time: u64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub enum Fault {
+    /// Cut the node off from the rest of the cluster
+    Partition(NodeId),
+    ClockJump(NodeId, i64),
+    Crash(NodeId),
+    DiskError(NodeId),
+}
+
+/// Xorshift generator, so a run can be replayed from its seed.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        Rng(seed.max(1))
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+}
+
+/// Runs a workload against a local cluster while injecting faults, recording the history.
+pub struct Nemesis {
+    config: NemesisConfig,
+    nodes: Vec<TestNode>,
+    /// Current process of every node's client and whether it has an operation in flight. A
+    /// process whose outcome is unknown is retired and replaced by a new one.
+    processes: Vec<(usize, bool)>,
+    rng: Rng,
+    history: BufWriter<File>,
+    started: Instant,
+    /// Partition or clock jump to revert on the next fault tick
+    active: Option<Fault>,
+    /// Nodes crashed or with a failed disk
+    down: Vec<NodeId>,
+    finishing: bool,
+    counts: [usize; 3],
+}
+
+impl Nemesis {
+    pub fn new(base_port: u16, config: NemesisConfig) -> io::Result<Nemesis> {
+        let history = BufWriter::new(File::create(&config.history)?);
+        let nodes = local_cluster(base_port);
+        let processes = (0..nodes.len()).map(|i| (i, false)).collect();
+
+        Ok(Nemesis {
+            rng: Rng::new(config.seed),
+            config: config,
+            nodes: nodes,
+            processes: processes,
+            history: history,
+            started: Instant::now(),
+            active: None,
+            down: Vec::new(),
+            finishing: false,
+            counts: [0; 3],
+        })
+    }
+
+    fn record(&mut self, process: String, kind: OpKind, f: &str, value: serde_json::Value) {
+        let event = HistoryEvent {
+            process: process,
+            kind: kind,
+            f: f.to_owned(),
+            value: value,
+            time: Instant::now().duration_since(self.started).as_nanos() as u64,
+        };
+
+        let res = serde_json::to_writer(&mut self.history, &event)
+            .map_err(io::Error::from)
+            .and_then(|_| self.history.write_all(b"\n"));
+
+        if let Err(err) = res {
+            error!("Failed to write the nemesis history {:?}: {}", self.config.history, err);
+        }
+    }
+
+    fn invoke(&mut self, ctx: &mut Context<Self>) {
+        if self.finishing {
+            return;
+        }
+
+        let idle = (0..self.nodes.len())
+            .filter(|i| self.nodes[*i].alive && !self.processes[*i].1)
+            .collect::<Vec<_>>();
+        if idle.is_empty() {
+            return;
+        }
+
+        let i = idle[self.rng.below(idle.len() as u64) as usize];
+        let key = 1 + self.rng.below(KEYS);
+        let f = match self.rng.below(3) {
+            0 => "add",
+            1 => "remove",
+            _ => "read",
+        };
+
+        let process = self.processes[i].0;
+        self.processes[i].1 = true;
+        self.record(process.to_string(), OpKind::Invoke, f, serde_json::json!([key, null]));
+
+        let client = format!("nemesis-{}", process);
+        let raft = self.nodes[i].raft.clone();
+
+        if f == "read" {
+            let net = self.nodes[i].net.clone();
+            let barrier = ClientProposal {
+                client: client,
+                key: None,
+                data: MemoryStorageData::Batch(Vec::new()),
+                deadline_ms: None,
+            };
+
+            // reads have no effect, whatever went wrong they failed
+            let read = raft
+                .send(barrier)
+                .timeout(OP_TIMEOUT)
+                .map_err(|_| ())
+                .and_then(|res| match res {
+                    Ok(ClientPayloadResponse::Applied { index, .. })
+                    | Ok(ClientPayloadResponse::Committed { index }) => Ok(index),
+                    Err(_) => Err(()),
+                })
+                .and_then(move |index| net.send(WaitForApplied(index, OP_TIMEOUT)).map_err(|_| ()).and_then(|res| res.map_err(|_| ())))
+                .and_then(move |_| raft.send(IsRingMember(key)).map_err(|_| ()))
+                .and_then(|res| res);
+
+            fut::wrap_future::<_, Self>(read)
+                .then(move |res, act, _| {
+                    match res {
+                        Ok(member) => act.complete(i, process, OpKind::Ok, f, serde_json::json!([key, member])),
+                        Err(_) => act.complete(i, process, OpKind::Fail, f, serde_json::json!([key, null])),
+                    }
+                    fut::ok(())
+                })
+                .spawn(ctx);
+            return;
+        }
+
+        let proposal = ClientProposal {
+            client: client,
+            key: None,
+            data: if f == "add" { MemoryStorageData::Add(key) } else { MemoryStorageData::Remove(key) },
+            deadline_ms: None,
+        };
+
+        fut::wrap_future::<_, Self>(raft.send(proposal).timeout(OP_TIMEOUT))
+            .then(move |res, act, _| {
+                let kind = match res {
+                    Ok(Ok(_)) => OpKind::Ok,
+                    // never appended to the log
+                    Ok(Err(ClientError::ForwardToLeader { .. })) | Ok(Err(ClientError::Application(_))) => {
+                        OpKind::Fail
+                    }
+                    Ok(Err(ClientError::Internal)) | Err(_) => OpKind::Info,
+                };
+
+                act.complete(i, process, kind, f, serde_json::json!([key, null]));
+                fut::ok(())
+            })
+            .spawn(ctx);
+    }
+
+    /// Record the outcome of the operation of the process of node `i`, retiring the process
+    /// when the outcome is unknown.
+    fn complete(&mut self, i: usize, process: usize, kind: OpKind, f: &str, value: serde_json::Value) {
+        self.record(process.to_string(), kind, f, value);
+        self.counts[kind as usize - 1] += 1;
+
+        let n = self.nodes.len();
+        let slot = &mut self.processes[i];
+        slot.1 = false;
+        if kind == OpKind::Info {
+            slot.0 += n;
+        }
+    }
+
+    /// Revert the active fault, or inject a new one.
+    fn fault(&mut self) {
+        if self.finishing {
+            return;
+        }
+
+        if self.active.is_some() {
+            return self.heal();
+        }
+
+        let alive = self
+            .nodes
+            .iter()
+            .filter(|node| node.alive && !self.down.contains(&node.id))
+            .map(|node| node.id)
+            .collect::<Vec<_>>();
+        if alive.is_empty() {
+            return;
+        }
+
+        let victim = alive[self.rng.below(alive.len() as u64) as usize];
+        // crashes and disk errors are permanent, keep a quorum healthy
+        let can_fail = (self.down.len() + 1) * 2 < self.nodes.len();
+
+        let fault = match self.rng.below(4) {
+            0 => Fault::Partition(victim),
+            1 => {
+                let jump = 1 + self.rng.below(MAX_CLOCK_JUMP_MS as u64) as i64;
+                Fault::ClockJump(victim, if self.rng.below(2) == 0 { jump } else { -jump })
+            }
+            2 if can_fail => Fault::Crash(victim),
+            3 if can_fail => Fault::DiskError(victim),
+            _ => Fault::Partition(victim),
+        };
+
+        self.inject(fault);
+    }
+
+    fn inject(&mut self, fault: Fault) {
+        println!("[nemesis] start {:?}", fault);
+        self.record("nemesis".to_owned(), OpKind::Info, "start", serde_json::json!(fault));
+
+        match fault {
+            Fault::Partition(victim) => {
+                let others = self.nodes.iter().map(|node| node.id).filter(|id| *id != victim).collect::<Vec<_>>();
+                for node in &self.nodes {
+                    let partition = if node.id == victim { others.clone() } else { vec![victim] };
+                    node.net.do_send(SetPartition(partition));
+                }
+                self.active = Some(fault);
+            }
+            Fault::ClockJump(victim, offset) => {
+                if let Some(node) = self.nodes.iter().find(|node| node.id == victim) {
+                    node.raft.do_send(SkewClock(offset));
+                }
+                self.active = Some(fault);
+            }
+            Fault::Crash(victim) => {
+                if let Some(node) = self.nodes.iter_mut().find(|node| node.id == victim) {
+                    node.raftor.do_send(Kill);
+                    node.alive = false;
+                }
+                self.down.push(victim);
+            }
+            Fault::DiskError(victim) => {
+                if let Some(node) = self.nodes.iter().find(|node| node.id == victim) {
+                    node.raft.do_send(StorageFaulted(StorageFault::Io("injected by nemesis".to_owned()), None));
+                }
+                self.down.push(victim);
+            }
+        }
+    }
+
+    fn heal(&mut self) {
+        let fault = match self.active.take() {
+            Some(fault) => fault,
+            None => return,
+        };
+
+        println!("[nemesis] stop {:?}", fault);
+        self.record("nemesis".to_owned(), OpKind::Info, "stop", serde_json::json!(fault));
+
+        match fault {
+            Fault::Partition(_) => {
+                for node in self.nodes.iter().filter(|node| node.alive) {
+                    node.net.do_send(SetPartition(Vec::new()));
+                }
+            }
+            Fault::ClockJump(victim, _) => {
+                if let Some(node) = self.nodes.iter().find(|node| node.id == victim) {
+                    node.raft.do_send(SkewClock(0));
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn tick(&mut self, ctx: &mut Context<Self>) {
+        if !self.finishing && Instant::now().duration_since(self.started) >= self.config.duration {
+            // let the operations in flight complete before stopping
+            self.heal();
+            self.finishing = true;
+        }
+
+        if self.finishing {
+            if self.processes.iter().all(|(_, busy)| !busy) {
+                self.finish(ctx);
+            }
+            return;
+        }
+
+        self.invoke(ctx);
+    }
+
+    fn finish(&mut self, ctx: &mut Context<Self>) {
+        if let Err(err) = self.history.flush() {
+            error!("Failed to write the nemesis history {:?}: {}", self.config.history, err);
+        }
+
+        println!(
+            "[nemesis] {} ok, {} failed, {} unknown, history written to {:?} (seed {})",
+            self.counts[0], self.counts[1], self.counts[2], self.config.history, self.config.seed
+        );
+
+        let linearizable = match linearizability::check_file(&self.config.history) {
+            Ok(report) => {
+                println!(
+                    "[nemesis] {:?}: {} operations on {} keys, failed keys {:?}, undecided keys {:?}",
+                    report.verdict, report.operations, report.keys, report.failed_keys, report.unknown_keys
+                );
+                report.verdict == Verdict::Linearizable
+            }
+            Err(err) => {
+                println!("[nemesis] Failed to check the history: {}", err);
+                false
+            }
+        };
+
+        ctx.stop();
+        System::current().stop_with_code(if linearizable { 0 } else { 1 });
+    }
+}
+
+impl Actor for Nemesis {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        println!(
+            "[nemesis] Starting local 3 node cluster, running for {:?} with seed {}",
+            self.config.duration, self.config.seed
+        );
+        self.started = Instant::now();
+
+        ctx.run_interval(self.config.op_interval, |act, ctx| act.tick(ctx));
+        ctx.run_interval(self.config.fault_interval, |act, _| act.fault());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn runs_replay_from_their_seed() {
+        let (mut a, mut b) = (Rng::new(42), Rng::new(42));
+        let draws = (0..16).map(|_| a.below(KEYS)).collect::<Vec<_>>();
+        assert_eq!(draws, (0..16).map(|_| b.below(KEYS)).collect::<Vec<_>>());
+        assert!(draws.iter().all(|key| *key < KEYS));
+
+        // a zero seed would only ever draw zeros
+        assert_ne!(Rng::new(0).next(), 0);
+    }
+
+    #[test]
+    fn the_history_is_readable_by_the_checker() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history.jsonl");
+        let mut file = File::create(&path).unwrap();
+
+        let events = vec![
+            ("0", OpKind::Invoke, "add", json!([3, null]), 1),
+            ("nemesis", OpKind::Info, "partition", json!(2), 2),
+            ("0", OpKind::Ok, "add", json!([3, null]), 3),
+            ("1", OpKind::Invoke, "read", json!([3, null]), 4),
+            ("1", OpKind::Ok, "read", json!([3, true]), 5),
+        ];
+        for (process, kind, f, value, time) in events {
+            let event = HistoryEvent {
+                process: process.to_owned(),
+                kind: kind,
+                f: f.to_owned(),
+                value: value,
+                time: time,
+            };
+            writeln!(file, "{}", serde_json::to_string(&event).unwrap()).unwrap();
+        }
+        drop(file);
+
+        let report = linearizability::check_file(&path).unwrap();
+        assert_eq!(report.operations, 2);
+        assert_eq!(report.verdict, Verdict::Linearizable);
+    }
+}
@@ -3,105 +3,587 @@ use byteorder::{BigEndian, ByteOrder};
 use bytes::{BufMut, BytesMut};
 use serde::{Deserialize, Serialize};
 use serde_json as json;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 use tokio::codec::{Decoder, Encoder};
 
 use crate::config::NodeInfo;
+use crate::error::RaftorError;
+use crate::network::compress::{self, Compression, FLAGS};
+use crate::network::metrics::{FrameTally, Plane, Traffic};
+use crate::raft::hooks::Hooks;
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Version announced to peers when joining
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Highest version of the peer protocol this node speaks. Both ends of a session agree on the
+/// highest version they share before anything else is written, see `negotiate`.
+pub const PROTOCOL_VERSION: u16 = 2;
+/// Oldest version of the peer protocol this node speaks
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+/// First version of the peer protocol carrying several messages in `NodeRequest::Batch` frames
+pub const BATCH_VERSION: u16 = 2;
+
+/// Opens the hello of both ends of a session. Its value as a length prefix exceeds
+/// `MAX_FRAME_LEN`, so it's never mistaken for a frame.
+const HELLO_MAGIC: &[u8; 4] = b"RFTR";
+/// Magic and the version range of the client
+const HELLO_LEN: usize = 8;
+/// Magic and the version picked by the server, 0 when there is none
+const HELLO_REPLY_LEN: usize = 6;
+
+/// Version of a session whose client speaks versions `min` to `max`, the highest both ends
+/// speak.
+pub fn negotiate(min: u16, max: u16) -> Option<u16> {
+    let version = max.min(PROTOCOL_VERSION);
+    if version >= min.max(MIN_PROTOCOL_VERSION) {
+        Some(version)
+    } else {
+        None
+    }
+}
+
+/// Raft log of a node as announced when joining, tells a restarting cluster its members
+/// already have data and a new node that it should wait to be added.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct LogState {
+    pub last_log_index: u64,
+    pub last_log_term: u64,
+    /// Whether the node recovered persisted state on start
+    pub has_data: bool,
+}
+
+/// Log state of the local node, shared with the node actors announcing it.
+pub type SharedLogState = Arc<RwLock<LogState>>;
+
+/// Answer of a peer to a `NodeRequest::Message`.
+pub type RemoteResult = Result<Vec<u8>, RaftorError>;
+
+/// Frames of a peer session, from the node which connected. Payloads are the MessagePack
+/// encoding of the carried message, see `remote::encode_payload`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum NodeRequest {
     Ping,
-    Join(NodeId, NodeInfo),
+    /// Join(node_id, info, version, resumption token of the previous session, log state)
+    Join(NodeId, NodeInfo, String, Option<String>, LogState),
     /// Message(msg_id, type_id, payload)
-    Message(u64, String, String),
+    Message(u64, String, Vec<u8>),
     /// Dispatch(type_id, payload)
-    Dispatch(String, String),
+    Dispatch(String, Vec<u8>),
+    /// Messages and dispatches written to the peer at once, handled in order, from
+    /// `BATCH_VERSION` on
+    Batch(Vec<NodeRequest>),
+    /// The peer shuts down, its session is dropped without waiting for it to resume
+    Leave,
+    /// Hello(min, max) protocol versions the node speaks, written first and raw, see `put_hello`
+    Hello(u16, u16),
 }
+
+/// Frames of a peer session, from the node which accepted it.
 #[derive(Serialize, Deserialize, Debug)]
 pub enum NodeResponse {
     Ping,
-    Joined,
-    /// Result(msg_id, payload)
-    Result(u64, String),
+    /// Joined(resumption token)
+    Joined(String),
+    /// Result(msg_id, payload of the answer or why the peer couldn't answer)
+    Result(u64, RemoteResult),
+    /// Hello(version) picked for the session, 0 when none is shared, written raw
+    Hello(u16),
+}
+
+/// Peers whose sessions are switched to verbose JSON for debugging.
+pub type DebugLinks = Arc<RwLock<HashSet<NodeId>>>;
+
+/// Requests of a session awaiting their result, past this they're forgotten and their
+/// results count toward the data plane
+const MAX_AWAITED_RESULTS: usize = 4096;
+
+/// Debug switch of one peer session, shared by the read and write halves of its codec, which
+/// also counts the frames of the session and holds its protocol version.
+#[derive(Clone)]
+pub struct LinkDebug {
+    /// Id of the peer, 0 until it joined
+    peer: Arc<AtomicU64>,
+    /// Protocol version of the session, 0 until negotiated
+    version: Arc<AtomicUsize>,
+    links: DebugLinks,
+    traffic: Traffic,
+    /// Plane of the requests awaiting a result, results only carry the message id
+    awaited: Arc<Mutex<HashMap<u64, Plane>>>,
+    /// Redacts the commands of logged frames
+    hooks: Hooks,
+}
+
+impl LinkDebug {
+    pub fn new(peer: Option<NodeId>, links: DebugLinks, traffic: Traffic, hooks: Hooks) -> LinkDebug {
+        LinkDebug {
+            peer: Arc::new(AtomicU64::new(peer.unwrap_or(0))),
+            version: Arc::new(AtomicUsize::new(0)),
+            links: links,
+            traffic: traffic,
+            awaited: Arc::new(Mutex::new(HashMap::new())),
+            hooks: hooks,
+        }
+    }
+
+    /// Messages of a request frame by plane, remembering the plane of the messages awaiting
+    /// a result.
+    pub fn request_tally(&self, req: &NodeRequest) -> FrameTally {
+        let mut tally = FrameTally::default();
+        self.tally_request(req, &mut tally);
+        tally
+    }
+
+    fn tally_request(&self, req: &NodeRequest, tally: &mut FrameTally) {
+        match req {
+            NodeRequest::Message(msg_id, type_id, _) => {
+                let plane = Plane::of(type_id);
+                let mut awaited = self.awaited.lock().unwrap();
+                if awaited.len() >= MAX_AWAITED_RESULTS {
+                    awaited.clear();
+                }
+                awaited.insert(*msg_id, plane);
+                tally.add(plane);
+            }
+            NodeRequest::Dispatch(type_id, _) => tally.add(Plane::of(type_id)),
+            NodeRequest::Batch(requests) => {
+                for req in requests.iter() {
+                    self.tally_request(req, tally);
+                }
+            }
+            NodeRequest::Ping | NodeRequest::Join(..) | NodeRequest::Leave | NodeRequest::Hello(..) => tally.add(Plane::Control),
+        }
+    }
+
+    /// Messages of a response frame by plane, a result is on the plane of its request.
+    pub fn response_tally(&self, res: &NodeResponse) -> FrameTally {
+        let mut tally = FrameTally::default();
+        match res {
+            NodeResponse::Result(msg_id, _) => {
+                let plane = self.awaited.lock().unwrap().remove(msg_id);
+                tally.add(plane.unwrap_or(Plane::Data));
+            }
+            NodeResponse::Ping | NodeResponse::Joined(_) | NodeResponse::Hello(_) => tally.add(Plane::Control),
+        }
+        tally
+    }
+
+    pub fn sent(&self, tally: FrameTally, bytes: usize) {
+        self.traffic.sent(&tally, bytes);
+        let peer = self.peer.load(Ordering::Relaxed);
+        if peer != 0 {
+            self.traffic.peer_bytes(peer, bytes, 0);
+        }
+    }
+
+    pub fn received(&self, tally: FrameTally, bytes: usize) {
+        self.traffic.received(&tally, bytes);
+        let peer = self.peer.load(Ordering::Relaxed);
+        if peer != 0 {
+            self.traffic.peer_bytes(peer, 0, bytes);
+        }
+    }
+
+    /// Learn the peer of an inbound session from its join request.
+    pub fn observe(&self, req: &NodeRequest) {
+        if let NodeRequest::Join(id, ..) = req {
+            self.peer.store(*id, Ordering::Relaxed);
+        }
+    }
+
+    /// Protocol version of the session, 0 until negotiated.
+    pub fn version(&self) -> u16 {
+        self.version.load(Ordering::Relaxed) as u16
+    }
+
+    fn set_version(&self, version: u16) {
+        self.version.store(version as usize, Ordering::Relaxed);
+    }
+
+    /// Whether the session carries `NodeRequest::Batch` frames.
+    pub fn batches(&self) -> bool {
+        self.version() >= BATCH_VERSION
+    }
+
+    pub fn enabled(&self) -> bool {
+        let peer = self.peer.load(Ordering::Relaxed);
+        peer != 0 && self.links.read().unwrap().contains(&peer)
+    }
+
+    /// Log a frame of a debugged session, with its payloads decoded and its commands redacted.
+    pub fn log<T: Serialize>(&self, direction: &str, frame: &T) {
+        if self.enabled() {
+            let mut frame = json::to_value(frame).unwrap_or_default();
+            decode_payloads(&mut frame);
+            self.hooks.read().unwrap().redact_json(&mut frame);
+
+            info!("peer {} {} {}", self.peer.load(Ordering::Relaxed), direction, frame);
+        }
+    }
+}
+
+/// Replace the payloads of a frame in its JSON form, arrays of bytes, with the messages they
+/// carry.
+fn decode_payloads(frame: &mut json::Value) {
+    let fields = match frame {
+        json::Value::Object(variant) => match variant.iter_mut().next() {
+            Some((_, json::Value::Array(fields))) => fields,
+            _ => return,
+        },
+        _ => return,
+    };
+
+    for field in fields.iter_mut() {
+        // a result carries its payload when it's `Ok`
+        let field = match field {
+            json::Value::Object(result) if result.contains_key("Ok") => result.get_mut("Ok").unwrap(),
+            field => field,
+        };
+        match field {
+            // the requests of a batch
+            json::Value::Array(items) if items.iter().all(json::Value::is_object) => items.iter_mut().for_each(decode_payloads),
+            json::Value::Array(_) => {
+                let payload = json::from_value::<Vec<u8>>(field.take()).unwrap_or_default();
+                *field = rmp_serde::from_slice::<json::Value>(&payload).unwrap_or(json::Value::Null);
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Largest frame accepted from or sent to a peer
+pub const MAX_FRAME_LEN: usize = 64 * 1024 * 1024;
+
+fn invalid<E: std::fmt::Display>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+}
+
+/// Split a hello of `len` bytes off the buffer, once it's there.
+fn split_hello(src: &mut BytesMut, len: usize) -> io::Result<Option<BytesMut>> {
+    if src.len() < HELLO_MAGIC.len() {
+        return Ok(None);
+    }
+    if &src[..HELLO_MAGIC.len()] != &HELLO_MAGIC[..] {
+        return Err(invalid("the peer didn't negotiate a protocol version, it runs a release older than the binary peer protocol"));
+    }
+    if src.len() < len {
+        return Ok(None);
+    }
+
+    src.split_to(HELLO_MAGIC.len());
+    Ok(Some(src.split_to(len - HELLO_MAGIC.len())))
+}
+
+/// Read the hello opening a session on the node which accepted it.
+pub(crate) fn decode_hello(src: &mut BytesMut) -> io::Result<Option<NodeRequest>> {
+    Ok(split_hello(src, HELLO_LEN)?.map(|buf| NodeRequest::Hello(BigEndian::read_u16(&buf[..2]), BigEndian::read_u16(&buf[2..]))))
+}
+
+/// Read the answer to the hello on the node which connected, and switch the session to the
+/// version picked.
+pub(crate) fn decode_hello_reply(debug: &LinkDebug, src: &mut BytesMut) -> io::Result<Option<NodeResponse>> {
+    let version = match split_hello(src, HELLO_REPLY_LEN)? {
+        Some(buf) => BigEndian::read_u16(&buf),
+        None => return Ok(None),
+    };
+    if version != 0 && negotiate(version, version) != Some(version) {
+        return Err(invalid(format!("the peer picked protocol version {} which this node doesn't speak", version)));
+    }
+
+    debug.set_version(version);
+    Ok(Some(NodeResponse::Hello(version)))
 }
 
-pub struct NodeCodec;
+/// Write a hello, raw so every version reads it.
+pub(crate) fn put_hello(debug: &LinkDebug, versions: &[u16], dst: &mut BytesMut) {
+    dst.reserve(HELLO_MAGIC.len() + versions.len() * 2);
+    dst.put(&HELLO_MAGIC[..]);
+    for version in versions {
+        dst.put_u16_be(*version);
+    }
+    // the answer of the node which accepted the session switches it to the version picked
+    if let [version] = versions {
+        debug.set_version(*version);
+    }
+}
+
+/// Refuse batch frames on a session which didn't negotiate them.
+pub(crate) fn check_request(debug: &LinkDebug, req: &NodeRequest) -> io::Result<()> {
+    match req {
+        NodeRequest::Batch(_) if !debug.batches() => {
+            Err(invalid(format!("batch frame on a session of protocol version {}", debug.version())))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn frame_too_large(len: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("frame of {} bytes exceeds the limit of {} bytes", len, MAX_FRAME_LEN),
+    )
+}
+
+/// Split a u32 length prefixed frame off the buffer, inflating it when it was compressed.
+fn split_frame(src: &mut BytesMut) -> io::Result<Option<BytesMut>> {
+    if src.len() < 4 {
+        return Ok(None);
+    }
+
+    let prefix = BigEndian::read_u32(src.as_ref());
+    let size = (prefix & !FLAGS) as usize;
+    if size > MAX_FRAME_LEN {
+        return Err(frame_too_large(size));
+    }
+
+    if src.len() >= size + 4 {
+        src.split_to(4);
+        let buf = src.split_to(size);
+        if prefix & FLAGS != 0 {
+            Ok(Some(BytesMut::from(compress::inflate(prefix & FLAGS, &buf)?)))
+        } else {
+            Ok(Some(buf))
+        }
+    } else {
+        src.reserve(size + 4 - src.len());
+        Ok(None)
+    }
+}
+
+fn put_frame(compression: Compression, buf: &[u8], dst: &mut BytesMut) -> io::Result<()> {
+    if buf.len() > MAX_FRAME_LEN {
+        return Err(frame_too_large(buf.len()));
+    }
+
+    let (buf, flag) = compress::deflate(compression, buf);
+    dst.reserve(buf.len() + 4);
+    dst.put_u32_be(buf.len() as u32 | flag);
+    dst.put(&buf[..]);
+
+    Ok(())
+}
+
+/// Codec of an inbound session, frames are bincode encoded.
+pub struct NodeCodec {
+    debug: LinkDebug,
+    /// Whether the hello of the peer was read
+    greeted: bool,
+}
+
+impl NodeCodec {
+    pub fn new(debug: LinkDebug) -> NodeCodec {
+        NodeCodec { debug: debug, greeted: false }
+    }
+}
 
 // Client -> Server transport
 impl Decoder for NodeCodec {
     type Item = NodeRequest;
-    type Error = std::io::Error;
+    type Error = io::Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        let size = {
-            if src.len() < 2 {
-                return Ok(None);
+        let available = src.len();
+        let req = if self.greeted {
+            match split_frame(src)? {
+                Some(buf) => bincode::deserialize::<NodeRequest>(&buf).map_err(invalid)?,
+                None => return Ok(None),
+            }
+        } else {
+            match decode_hello(src)? {
+                Some(hello) => {
+                    self.greeted = true;
+                    hello
+                }
+                None => return Ok(None),
             }
-            BigEndian::read_u16(src.as_ref()) as usize
         };
 
-        if src.len() >= size + 2 {
-            src.split_to(2);
-            let buf = src.split_to(size);
-            Ok(Some(json::from_slice::<NodeRequest>(&buf)?))
-        } else {
-            Ok(None)
-        }
+        check_request(&self.debug, &req)?;
+        self.debug.observe(&req);
+        self.debug.received(self.debug.request_tally(&req), available - src.len());
+        self.debug.log("received", &req);
+        Ok(Some(req))
     }
 }
 
 impl Encoder for NodeCodec {
     type Item = NodeResponse;
-    type Error = std::io::Error;
+    type Error = io::Error;
 
     fn encode(&mut self, msg: NodeResponse, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        let msg = json::to_string(&msg).unwrap();
-        let msg_ref: &[u8] = msg.as_ref();
-
-        dst.reserve(msg_ref.len() + 2);
-        dst.put_u16_be(msg_ref.len() as u16);
-        dst.put(msg_ref);
-
+        let tally = self.debug.response_tally(&msg);
+        self.debug.log("sent", &msg);
+        let written = dst.len();
+        match msg {
+            NodeResponse::Hello(version) => put_hello(&self.debug, &[version], dst),
+            msg => put_frame(Compression::Off, &bincode::serialize(&msg).map_err(invalid)?, dst)?,
+        }
+        self.debug.sent(tally, dst.len() - written);
         Ok(())
     }
 }
 
-pub struct ClientNodeCodec;
+/// Codec of an outbound session, compressing the requests written to constrained links.
+pub struct ClientNodeCodec {
+    debug: LinkDebug,
+    compression: Compression,
+    /// Whether the answer to the hello of this node was read
+    greeted: bool,
+}
+
+impl ClientNodeCodec {
+    pub fn new(debug: LinkDebug, compression: Compression) -> ClientNodeCodec {
+        ClientNodeCodec { debug: debug, compression: compression, greeted: false }
+    }
+}
 
 // Server -> Client transport
 impl Decoder for ClientNodeCodec {
     type Item = NodeResponse;
-    type Error = std::io::Error;
+    type Error = io::Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        let size = {
-            if src.len() < 2 {
-                return Ok(None);
+        let available = src.len();
+        let res = if self.greeted {
+            match split_frame(src)? {
+                Some(buf) => bincode::deserialize::<NodeResponse>(&buf).map_err(invalid)?,
+                None => return Ok(None),
+            }
+        } else {
+            match decode_hello_reply(&self.debug, src)? {
+                Some(hello) => {
+                    self.greeted = true;
+                    hello
+                }
+                None => return Ok(None),
             }
-            BigEndian::read_u16(src.as_ref()) as usize
         };
 
-        if src.len() >= size + 2 {
-            src.split_to(2);
-            let buf = src.split_to(size);
-            Ok(Some(json::from_slice::<NodeResponse>(&buf)?))
-        } else {
-            Ok(None)
-        }
+        self.debug.received(self.debug.response_tally(&res), available - src.len());
+        self.debug.log("received", &res);
+        Ok(Some(res))
     }
 }
 
 impl Encoder for ClientNodeCodec {
     type Item = NodeRequest;
-    type Error = std::io::Error;
+    type Error = io::Error;
 
     fn encode(&mut self, msg: NodeRequest, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        let msg = json::to_string(&msg).unwrap();
-        let msg_ref: &[u8] = msg.as_ref();
+        let tally = self.debug.request_tally(&msg);
+        self.debug.log("sent", &msg);
+        let written = dst.len();
+        match msg {
+            NodeRequest::Hello(min, max) => put_hello(&self.debug, &[min, max], dst),
+            msg => put_frame(self.compression, &bincode::serialize(&msg).map_err(invalid)?, dst)?,
+        }
+        self.debug.sent(tally, dst.len() - written);
+        Ok(())
+    }
+}
 
-        dst.reserve(msg_ref.len() + 2);
-        dst.put_u16_be(msg_ref.len() as u16);
-        dst.put(msg_ref);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raft::hooks::HookChain;
 
-        Ok(())
+    fn debug() -> LinkDebug {
+        LinkDebug::new(None, DebugLinks::default(), Traffic::default(), HookChain::new())
+    }
+
+    /// Write the frames of `requests` and read them back, the way an inbound session would.
+    fn exchange(requests: Vec<NodeRequest>) -> (LinkDebug, io::Result<Vec<NodeRequest>>) {
+        let server = debug();
+        let mut client = ClientNodeCodec::new(debug(), Compression::Off);
+        let mut codec = NodeCodec::new(server.clone());
+
+        let mut buf = BytesMut::new();
+        for req in requests {
+            client.encode(req, &mut buf).unwrap();
+        }
+        let mut decoded = Vec::new();
+        loop {
+            match codec.decode(&mut buf) {
+                Ok(Some(req)) => decoded.push(req),
+                Ok(None) => return (server, Ok(decoded)),
+                Err(err) => return (server, Err(err)),
+            }
+        }
+    }
+
+    #[test]
+    fn negotiates_the_highest_shared_version() {
+        assert_eq!(negotiate(1, 2), Some(2));
+        assert_eq!(negotiate(1, 7), Some(PROTOCOL_VERSION));
+        assert_eq!(negotiate(1, 1), Some(1));
+        assert_eq!(negotiate(PROTOCOL_VERSION + 1, PROTOCOL_VERSION + 2), None);
+        assert_eq!(negotiate(0, 0), None);
+    }
+
+    #[test]
+    fn frames_follow_the_hello() {
+        let body = vec![0x92, 0x01, 0x02];
+        let (_, decoded) = exchange(vec![
+            NodeRequest::Hello(MIN_PROTOCOL_VERSION, PROTOCOL_VERSION),
+            NodeRequest::Message(7, "VoteRequest".to_owned(), body.clone()),
+        ]);
+        let decoded = decoded.unwrap();
+
+        match &decoded[..] {
+            [NodeRequest::Hello(1, 2), NodeRequest::Message(7, type_id, payload)] => {
+                assert_eq!(type_id, "VoteRequest");
+                assert_eq!(payload, &body);
+            }
+            other => panic!("unexpected frames {:?}", other),
+        }
+    }
+
+    #[test]
+    fn sessions_without_a_hello_are_refused() {
+        let mut codec = NodeCodec::new(debug());
+        let mut buf = BytesMut::new();
+        put_frame(Compression::Off, br#"{"Ping":null}"#, &mut buf).unwrap();
+
+        assert!(codec.decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn batches_wait_for_their_version() {
+        let batch = NodeRequest::Batch(vec![NodeRequest::Dispatch("a".to_owned(), vec![1])]);
+
+        let (_, decoded) = exchange(vec![NodeRequest::Hello(1, 1), batch.clone()]);
+        assert!(decoded.is_err());
+
+        // the answer of the server switches the session to the version picked
+        let (server, _) = exchange(vec![NodeRequest::Hello(1, 2)]);
+        let mut buf = BytesMut::new();
+        NodeCodec::new(server.clone()).encode(NodeResponse::Hello(2), &mut buf).unwrap();
+        assert!(server.batches());
+
+        let client = debug();
+        let hello = decode_hello_reply(&client, &mut buf).unwrap();
+        assert!(matches!(hello, Some(NodeResponse::Hello(2))));
+        assert!(client.batches());
+    }
+
+    #[test]
+    fn results_carry_the_error_of_the_peer() {
+        let mut server = NodeCodec::new(debug());
+        let mut client = ClientNodeCodec::new(debug(), Compression::Off);
+
+        let mut buf = BytesMut::new();
+        server.encode(NodeResponse::Hello(PROTOCOL_VERSION), &mut buf).unwrap();
+        server.encode(NodeResponse::Result(3, Err(RaftorError::NotLeader { leader_hint: Some(2) })), &mut buf).unwrap();
+        server.encode(NodeResponse::Result(4, Ok(Vec::new())), &mut buf).unwrap();
+
+        assert!(matches!(client.decode(&mut buf).unwrap(), Some(NodeResponse::Hello(_))));
+        match client.decode(&mut buf).unwrap() {
+            Some(NodeResponse::Result(3, Err(err))) => assert_eq!(err, RaftorError::NotLeader { leader_hint: Some(2) }),
+            other => panic!("unexpected frame {:?}", other),
+        }
+        // an empty answer is an answer, e.g. to a message whose result is `()`
+        assert!(matches!(client.decode(&mut buf).unwrap(), Some(NodeResponse::Result(4, Ok(ref body))) if body.is_empty()));
     }
 }
@@ -0,0 +1,169 @@
+//! Deflate compression of the frames sent over constrained links, with the `compression`
+//! feature. Compressed frames have the high bit of their length prefix set, which frames never
+//! reach uncompressed, so every node reads them whether or not it compresses its own.
+//!
+//! Frames compressed with the cluster's dictionary (see `dictionary`) have the next bit set
+//! instead, their body starts with the id of the dictionary.
+
+use std::borrow::Cow;
+use std::io;
+
+#[cfg(feature = "compression")]
+use std::io::{Read, Write};
+#[cfg(feature = "compression")]
+use flate2::{read::DeflateDecoder, write::DeflateEncoder};
+
+use crate::dictionary;
+use crate::network::codec::MAX_FRAME_LEN;
+
+/// Flag of the length prefix of a compressed frame
+pub const COMPRESSED: u32 = 1 << 31;
+/// Flag of the length prefix of a frame compressed with a dictionary
+pub const DICTIONARY: u32 = 1 << 30;
+/// Flags of the length prefix, the rest is the length of the frame
+pub const FLAGS: u32 = COMPRESSED | DICTIONARY;
+/// Frames smaller than this aren't worth compressing
+const MIN_COMPRESSED_LEN: usize = 512;
+/// Frames smaller than this aren't worth compressing even with a dictionary
+const MIN_DICTIONARY_LEN: usize = 32;
+
+/// Compression of the frames written to a peer.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compression {
+    Off,
+    Deflate,
+    /// With the current dictionary of the cluster, frames go uncompressed until there's one
+    Dictionary,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression::Off
+    }
+}
+
+/// Body of a frame and the flag to set on its length prefix, compressed when that makes it
+/// smaller.
+pub fn deflate(compression: Compression, buf: &[u8]) -> (Cow<[u8]>, u32) {
+    if compression == Compression::Dictionary {
+        return compress_with_dictionary(buf);
+    }
+    if compression == Compression::Off || buf.len() < MIN_COMPRESSED_LEN {
+        return (Cow::Borrowed(buf), 0);
+    }
+
+    match compress(buf) {
+        Ok(Some(compressed)) if compressed.len() < buf.len() => (Cow::Owned(compressed), COMPRESSED),
+        Ok(_) => (Cow::Borrowed(buf), 0),
+        Err(err) => {
+            warn!("Sending a frame uncompressed, compressing it failed: {}", err);
+            (Cow::Borrowed(buf), 0)
+        }
+    }
+}
+
+fn compress_with_dictionary(buf: &[u8]) -> (Cow<[u8]>, u32) {
+    if buf.len() < MIN_DICTIONARY_LEN {
+        return (Cow::Borrowed(buf), 0);
+    }
+
+    match dictionary::compress(buf) {
+        Ok(Some((id, compressed))) if compressed.len() + 4 < buf.len() => {
+            let mut body = Vec::with_capacity(compressed.len() + 4);
+            body.extend_from_slice(&id.to_be_bytes());
+            body.extend_from_slice(&compressed);
+            (Cow::Owned(body), DICTIONARY)
+        }
+        Ok(_) => (Cow::Borrowed(buf), 0),
+        Err(err) => {
+            warn!("Sending a frame uncompressed, compressing it with the dictionary failed: {}", err);
+            (Cow::Borrowed(buf), 0)
+        }
+    }
+}
+
+#[cfg(feature = "compression")]
+fn compress(buf: &[u8]) -> io::Result<Option<Vec<u8>>> {
+    // links are slow enough for the strongest level to pay off
+    let mut encoder = DeflateEncoder::new(Vec::with_capacity(buf.len() / 2), flate2::Compression::best());
+    encoder.write_all(buf)?;
+    encoder.finish().map(Some)
+}
+
+#[cfg(not(feature = "compression"))]
+fn compress(_buf: &[u8]) -> io::Result<Option<Vec<u8>>> {
+    Ok(None)
+}
+
+/// Body of a frame with the given flags of its length prefix, bounded by the frame size limit.
+pub fn inflate(flags: u32, buf: &[u8]) -> io::Result<Vec<u8>> {
+    if flags & DICTIONARY == 0 {
+        return inflate_deflated(buf);
+    }
+
+    if buf.len() < 4 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "frame compressed with a dictionary lacks its id"));
+    }
+    let mut id = [0; 4];
+    id.copy_from_slice(&buf[..4]);
+    dictionary::decompress(u32::from_be_bytes(id), &buf[4..], MAX_FRAME_LEN)
+}
+
+#[cfg(feature = "compression")]
+fn inflate_deflated(buf: &[u8]) -> io::Result<Vec<u8>> {
+    let mut body = Vec::with_capacity(buf.len() * 2);
+    DeflateDecoder::new(buf).take(MAX_FRAME_LEN as u64 + 1).read_to_end(&mut body)?;
+
+    if body.len() > MAX_FRAME_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("compressed frame exceeds the limit of {} bytes", MAX_FRAME_LEN),
+        ));
+    }
+    Ok(body)
+}
+
+#[cfg(not(feature = "compression"))]
+fn inflate_deflated(_buf: &[u8]) -> io::Result<Vec<u8>> {
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "compressed frame received but raftor was built without the `compression` feature",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn small_frames_go_uncompressed() {
+        let buf = vec![0; MIN_COMPRESSED_LEN - 1];
+        let (body, flags) = deflate(Compression::Deflate, &buf);
+        assert_eq!(flags, 0);
+        assert_eq!(&body[..], &buf[..]);
+
+        let (_, flags) = deflate(Compression::Off, &vec![0; 4096]);
+        assert_eq!(flags, 0);
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn compressed_frames_inflate_to_their_body() {
+        let buf = b"AppendEntriesRequest".repeat(100);
+        let (body, flags) = deflate(Compression::Deflate, &buf);
+        assert_eq!(flags, COMPRESSED);
+        assert!(body.len() < buf.len());
+        assert_eq!(inflate(flags, &body).unwrap(), buf);
+    }
+
+    #[cfg(not(feature = "compression"))]
+    #[test]
+    fn compressed_frames_are_refused_without_the_feature() {
+        assert!(inflate(COMPRESSED, &[0; 16]).is_err());
+    }
+
+    #[test]
+    fn dictionary_frames_carry_their_id() {
+        assert!(inflate(COMPRESSED | DICTIONARY, &[0; 3]).is_err());
+    }
+}
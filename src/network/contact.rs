@@ -0,0 +1,125 @@
+use actix_raft::NodeId;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::clock;
+use crate::config::FastRestart;
+use crate::raft::timing::now_millis;
+
+/// File in the data dir holding when the node last heard from its leader
+const CONTACT_FILE: &str = "leader_contact";
+
+/// When a follower last heard from its leader.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct LeaderContact {
+    pub leader: NodeId,
+    pub term: u64,
+    /// Unix time in milliseconds
+    pub at: u64,
+}
+
+/// Writes the leader contact of a follower to the data dir, at most once per persist
+/// interval unless the leader or term changed.
+pub struct ContactLog {
+    path: PathBuf,
+    interval: Duration,
+    written: Option<(LeaderContact, Instant)>,
+    /// Contact found on start, left by the previous run
+    recovered: Option<LeaderContact>,
+    max_hold: Duration,
+}
+
+impl ContactLog {
+    pub fn open(data_dir: &Path, config: &FastRestart) -> ContactLog {
+        let path = data_dir.join(CONTACT_FILE);
+        let recovered = fs::read(&path)
+            .ok()
+            .and_then(|data| serde_json::from_slice(&data).ok());
+
+        ContactLog {
+            path: path,
+            interval: Duration::from_millis(config.persist_interval_ms),
+            written: None,
+            recovered: recovered,
+            max_hold: Duration::from_millis(config.max_hold_ms),
+        }
+    }
+
+    /// Record hearing from the leader of the term.
+    pub fn heard(&mut self, leader: NodeId, term: u64) {
+        let due = match self.written {
+            Some((ref contact, at)) => {
+                contact.leader != leader || contact.term != term || clock::elapsed(at) >= self.interval
+            }
+            None => true,
+        };
+        if !due {
+            return;
+        }
+
+        let contact = LeaderContact {
+            leader: leader,
+            term: term,
+            at: now_millis(),
+        };
+        match self.write(&contact) {
+            Ok(_) => self.written = Some((contact, clock::now())),
+            Err(err) => warn!("Error recording the contact with leader {}: {}", leader, err),
+        }
+    }
+
+    /// Leader the previous run heard from within the window, and how long to wait for it.
+    /// The window is widened by the persist interval, the contact may be that much older
+    /// than the last heartbeat.
+    pub fn hold(&self, window: Duration) -> Option<(NodeId, Duration)> {
+        let contact = self.recovered?;
+        let elapsed = Duration::from_millis(now_millis().saturating_sub(contact.at));
+
+        if elapsed <= window + self.interval {
+            Some((contact.leader, self.max_hold))
+        } else {
+            None
+        }
+    }
+
+    fn write(&self, contact: &LeaderContact) -> io::Result<()> {
+        let data = serde_json::to_vec(contact).map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+        fs::write(&self.path, data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> FastRestart {
+        FastRestart {
+            persist_interval_ms: 1000,
+            max_hold_ms: 3000,
+        }
+    }
+
+    #[test]
+    fn a_recent_contact_holds_the_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = ContactLog::open(dir.path(), &config());
+        assert_eq!(log.hold(Duration::from_secs(5)), None);
+        log.heard(2, 4);
+
+        let restarted = ContactLog::open(dir.path(), &config());
+        assert_eq!(restarted.hold(Duration::from_secs(5)), Some((2, Duration::from_secs(3))));
+    }
+
+    #[test]
+    fn an_old_contact_is_ignored() {
+        let dir = tempfile::tempdir().unwrap();
+        let contact = LeaderContact { leader: 2, term: 4, at: now_millis().saturating_sub(60_000) };
+        fs::write(dir.path().join(CONTACT_FILE), serde_json::to_vec(&contact).unwrap()).unwrap();
+
+        let restarted = ContactLog::open(dir.path(), &config());
+        assert_eq!(restarted.hold(Duration::from_secs(5)), None);
+    }
+}
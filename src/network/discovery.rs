@@ -0,0 +1,323 @@
+use actix_raft::NodeId;
+use actix_web::{client::Client, web};
+use futures::{future, Future};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::net::ToSocketAddrs;
+use std::sync::{Arc, Mutex};
+
+use crate::config::{DiscoveryBackend, NodeInfo};
+use crate::raft::timing::now_millis;
+
+/// Removed nodes remembered for peers syncing deltas, those further behind get the full list
+const MAX_TOMBSTONES: usize = 1024;
+
+/// Finds the nodes of the cluster, asked periodically by both networks.
+pub trait Discovery: Send + Sync {
+    fn discover(&self) -> Box<dyn Future<Item = Vec<NodeInfo>, Error = String>>;
+}
+
+pub type PeerDiscovery = Arc<dyn Discovery>;
+
+pub fn from_config(backend: &DiscoveryBackend) -> PeerDiscovery {
+    match backend {
+        DiscoveryBackend::Dns { host, cluster_port, app_port, public_port } => Arc::new(DnsDiscovery {
+            host: host.clone(),
+            cluster_port: *cluster_port,
+            app_port: *app_port,
+            public_port: *public_port,
+        }),
+        DiscoveryBackend::Gossip { seeds } => Arc::new(GossipDiscovery::new(seeds.clone())),
+    }
+}
+
+/// Every address a host name resolves to is a node listening on the same ports, e.g. the
+/// pods behind a headless Kubernetes service.
+pub struct DnsDiscovery {
+    pub host: String,
+    pub cluster_port: u16,
+    pub app_port: u16,
+    pub public_port: u16,
+}
+
+impl Discovery for DnsDiscovery {
+    fn discover(&self) -> Box<dyn Future<Item = Vec<NodeInfo>, Error = String>> {
+        let host = self.host.clone();
+        let (cluster_port, app_port, public_port) = (self.cluster_port, self.app_port, self.public_port);
+
+        Box::new(
+            web::block(move || (host.as_str(), 0).to_socket_addrs())
+                .map_err(|err| format!("resolving peers failed: {}", err))
+                .map(move |addrs| {
+                    addrs
+                        .map(|addr| NodeInfo {
+                            cluster_addr: format!("{}:{}", addr.ip(), cluster_port),
+                            app_addr: format!("{}:{}", addr.ip(), app_port),
+                            public_addr: format!("{}:{}", addr.ip(), public_port),
+                            id: None,
+                            labels: Default::default(),
+                            cluster: None,
+                        })
+                        .collect()
+                }),
+        )
+    }
+}
+
+/// Where a peer is in the address book of a node: the node's epoch, a new one every time it
+/// starts, and the version of the last change the peer saw.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct DeltaCursor {
+    pub epoch: u64,
+    pub version: u64,
+}
+
+/// Changes to the address book of a node since a cursor, or the whole book (`full`) when the
+/// cursor is of another epoch or too far behind.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MembershipDelta {
+    pub cursor: DeltaCursor,
+    pub full: bool,
+    pub changed: HashMap<NodeId, NodeInfo>,
+    pub removed: Vec<NodeId>,
+}
+
+/// Versioned address book of a node, every change gets the next version so peers fetch only
+/// the changes past the version they saw.
+pub struct MembershipLog {
+    epoch: u64,
+    version: u64,
+    nodes: HashMap<NodeId, (u64, NodeInfo)>,
+    /// Version each removed node was removed at
+    tombstones: BTreeMap<u64, NodeId>,
+    /// Oldest version deltas can be answered from, older cursors get the full book
+    horizon: u64,
+}
+
+impl MembershipLog {
+    pub fn new() -> MembershipLog {
+        MembershipLog {
+            epoch: now_millis(),
+            version: 0,
+            nodes: HashMap::new(),
+            tombstones: BTreeMap::new(),
+            horizon: 0,
+        }
+    }
+
+    /// Record the changes between the known nodes and the book.
+    pub fn sync(&mut self, nodes: &HashMap<NodeId, NodeInfo>) {
+        for (id, info) in nodes.iter() {
+            if self.nodes.get(id).map_or(true, |(_, known)| known != info) {
+                self.version += 1;
+                self.nodes.insert(*id, (self.version, info.clone()));
+            }
+        }
+
+        let removed = self.nodes.keys().filter(|id| !nodes.contains_key(id)).cloned().collect::<Vec<_>>();
+        for id in removed {
+            self.version += 1;
+            self.nodes.remove(&id);
+            self.tombstones.insert(self.version, id);
+        }
+
+        while self.tombstones.len() > MAX_TOMBSTONES {
+            let oldest = *self.tombstones.keys().next().unwrap();
+            self.tombstones.remove(&oldest);
+            self.horizon = oldest;
+        }
+    }
+
+    pub fn delta(&self, since: Option<DeltaCursor>) -> MembershipDelta {
+        let cursor = DeltaCursor {
+            epoch: self.epoch,
+            version: self.version,
+        };
+        let since = match since {
+            Some(since) if since.epoch == self.epoch && since.version >= self.horizon && since.version <= self.version => since.version,
+            _ => {
+                return MembershipDelta {
+                    cursor: cursor,
+                    full: true,
+                    changed: self.nodes.iter().map(|(id, (_, info))| (*id, info.clone())).collect(),
+                    removed: Vec::new(),
+                }
+            }
+        };
+
+        MembershipDelta {
+            cursor: cursor,
+            full: false,
+            changed: self
+                .nodes
+                .iter()
+                .filter(|(_, (version, _))| *version > since)
+                .map(|(id, (_, info))| (*id, info.clone()))
+                .collect(),
+            removed: self.tombstones.range(since + 1..).map(|(_, id)| *id).collect(),
+        }
+    }
+}
+
+/// What a node knows of the address book of a seed.
+struct SeedBook {
+    cursor: DeltaCursor,
+    nodes: HashMap<NodeId, NodeInfo>,
+}
+
+/// Asks seed nodes (their public addresses) for the nodes they know, seeds which don't answer
+/// are skipped. Every seed only sends the changes since the version this node last saw of its
+/// address book, seeds which don't keep one are asked for their full list.
+pub struct GossipDiscovery {
+    pub seeds: Vec<String>,
+    books: Arc<Mutex<HashMap<String, SeedBook>>>,
+}
+
+impl GossipDiscovery {
+    pub fn new(seeds: Vec<String>) -> GossipDiscovery {
+        GossipDiscovery {
+            seeds: seeds,
+            books: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+}
+
+/// The nodes a seed knows, following its deltas.
+fn seed_nodes(client: &Client, seed: String, books: Arc<Mutex<HashMap<String, SeedBook>>>) -> impl Future<Item = Vec<NodeInfo>, Error = String> {
+    let url = match books.lock().unwrap().get(&seed) {
+        Some(book) => format!(
+            "http://{}/cluster/nodes/delta?epoch={}&version={}",
+            seed, book.cursor.epoch, book.cursor.version
+        ),
+        None => format!("http://{}/cluster/nodes/delta", seed),
+    };
+    let full_list = client.get(format!("http://{}/cluster/nodes", seed));
+
+    client
+        .get(url)
+        .send()
+        .map_err(|err| err.to_string())
+        .and_then(|mut res| {
+            let supported = res.status().is_success();
+            res.json::<MembershipDelta>()
+                .map_err(|err| err.to_string())
+                .map(move |delta| if supported { Some(delta) } else { None })
+                .or_else(|_| Ok::<_, String>(None))
+        })
+        .and_then(move |delta| -> Box<dyn Future<Item = Vec<NodeInfo>, Error = String>> {
+            match delta {
+                Some(delta) => {
+                    let mut books = books.lock().unwrap();
+                    let book = books.entry(seed).or_insert_with(|| SeedBook {
+                        cursor: delta.cursor,
+                        nodes: HashMap::new(),
+                    });
+                    if delta.full {
+                        book.nodes.clear();
+                    }
+                    for id in delta.removed.iter() {
+                        book.nodes.remove(id);
+                    }
+                    book.nodes.extend(delta.changed);
+                    book.cursor = delta.cursor;
+                    Box::new(future::ok(book.nodes.values().cloned().collect()))
+                }
+                // a seed without an address book log
+                None => Box::new(
+                    full_list
+                        .send()
+                        .map_err(|err| err.to_string())
+                        .and_then(|mut res| res.json::<Result<HashMap<NodeId, NodeInfo>, ()>>().map_err(|err| err.to_string()))
+                        .map(|nodes| nodes.map(|nodes| nodes.into_iter().map(|(_, info)| info).collect()).unwrap_or_default()),
+                ),
+            }
+        })
+}
+
+impl Discovery for GossipDiscovery {
+    fn discover(&self) -> Box<dyn Future<Item = Vec<NodeInfo>, Error = String>> {
+        let client = Client::default();
+
+        let requests = self
+            .seeds
+            .iter()
+            .map(|seed| {
+                let seed = seed.clone();
+
+                seed_nodes(&client, seed.clone(), self.books.clone()).then(move |res| -> Result<Vec<NodeInfo>, String> {
+                    match res {
+                        Ok(nodes) => Ok(nodes),
+                        Err(err) => {
+                            debug!("Seed {} didn't answer: {}", seed, err);
+                            Ok(Vec::new())
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        Box::new(future::join_all(requests).map(|nodes| nodes.into_iter().flatten().collect()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(addr: &str) -> NodeInfo {
+        NodeInfo {
+            cluster_addr: addr.to_owned(),
+            app_addr: String::new(),
+            public_addr: String::new(),
+            id: None,
+            labels: Default::default(),
+            cluster: None,
+        }
+    }
+
+    fn book(nodes: &[(NodeId, &str)]) -> HashMap<NodeId, NodeInfo> {
+        nodes.iter().map(|(id, addr)| (*id, info(addr))).collect()
+    }
+
+    #[test]
+    fn deltas_carry_the_changes_since_the_cursor() {
+        let mut log = MembershipLog::new();
+        log.sync(&book(&[(1, "a:1"), (2, "b:1")]));
+        let cursor = log.delta(None).cursor;
+
+        log.sync(&book(&[(1, "a:2"), (3, "c:1")]));
+        let delta = log.delta(Some(cursor));
+        assert!(!delta.full);
+        let mut changed = delta.changed.keys().cloned().collect::<Vec<_>>();
+        changed.sort();
+        assert_eq!(changed, vec![1, 3]);
+        assert_eq!(delta.removed, vec![2]);
+
+        // nothing changed since the latest cursor
+        let delta = log.delta(Some(delta.cursor));
+        assert!(delta.changed.is_empty() && delta.removed.is_empty());
+    }
+
+    #[test]
+    fn cursors_of_another_epoch_get_the_whole_book() {
+        let mut log = MembershipLog::new();
+        log.sync(&book(&[(1, "a:1")]));
+
+        let stale = DeltaCursor { epoch: log.epoch + 1, version: 1 };
+        let delta = log.delta(Some(stale));
+        assert!(delta.full);
+        assert_eq!(delta.changed.len(), 1);
+    }
+
+    #[test]
+    fn cursors_behind_the_tombstones_get_the_whole_book() {
+        let mut log = MembershipLog::new();
+        log.sync(&book(&[(1, "a:1")]));
+        let cursor = log.delta(None).cursor;
+
+        for id in 2..(MAX_TOMBSTONES as NodeId + 4) {
+            log.sync(&book(&[(1, "a:1"), (id, "b:1")]));
+        }
+        assert!(log.delta(Some(cursor)).full);
+    }
+}
@@ -0,0 +1,187 @@
+//! Failure domains the commits of the leader were acknowledged in.
+//!
+//! A domain is the value of a node label, e.g. `zone`. Whenever the acknowledgements of its
+//! followers move the commit index, the leader counts the domains of the voters holding the
+//! newly committed entries. A stretched cluster whose commits keep being satisfied by the
+//! voters of a single domain loses them with that domain, although its voters span several.
+
+use actix_raft::NodeId;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+
+use crate::config::{FailureDomainConfig, NodeInfo};
+
+/// Failure domains of the commits seen by this node while leading.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct FailureDomainStats {
+    /// Label whose values are the domains
+    pub label: String,
+    /// Domains the voters span
+    pub voter_domains: usize,
+    /// Domains holding the last commit
+    pub commit_domains: usize,
+    pub commit_index: u64,
+    /// Times the commit index moved
+    pub commits: u64,
+    /// Commits held in a single domain while the voters span several
+    pub single_domain_commits: u64,
+}
+
+/// What changed with a commit, for the leader to warn about.
+pub(crate) enum DomainChange {
+    /// Commits started being held in a single domain
+    SingleDomain { domain: String, voter_domains: usize },
+    /// Commits span several domains again
+    Spread { domains: usize },
+}
+
+pub(crate) struct DomainTracker {
+    config: FailureDomainConfig,
+    stats: FailureDomainStats,
+    /// Whether the last commit was held in a single domain out of several
+    single_domain: bool,
+}
+
+impl DomainTracker {
+    pub(crate) fn new(config: FailureDomainConfig) -> DomainTracker {
+        DomainTracker {
+            stats: FailureDomainStats {
+                label: config.label.clone(),
+                ..FailureDomainStats::default()
+            },
+            config: config,
+            single_domain: false,
+        }
+    }
+
+    pub(crate) fn stats(&self) -> FailureDomainStats {
+        self.stats.clone()
+    }
+
+    /// A new leader starts counting from its own commits.
+    pub(crate) fn reset(&mut self) {
+        self.stats.commit_index = 0;
+        self.single_domain = false;
+    }
+
+    /// Domain of a node, nodes without the label are a domain of their own.
+    fn domain(&self, id: NodeId, info: Option<&NodeInfo>) -> String {
+        info.and_then(|info| info.labels.get(&self.config.label).cloned())
+            .unwrap_or_else(|| format!("node-{}", id))
+    }
+
+    /// Update with the acknowledgements of the voters, the leader holding every entry it sent.
+    /// Returns the change to warn about when the commit index moved.
+    pub(crate) fn observe(
+        &mut self,
+        leader: NodeId,
+        voters: &[NodeId],
+        matched: &HashMap<NodeId, u64>,
+        info: &dyn Fn(NodeId) -> Option<NodeInfo>,
+    ) -> Option<DomainChange> {
+        if voters.is_empty() {
+            return None;
+        }
+
+        let acked = |id: &NodeId| if *id == leader { u64::max_value() } else { matched.get(id).cloned().unwrap_or(0) };
+        let mut acks = voters.iter().map(acked).collect::<Vec<_>>();
+        acks.sort_unstable_by(|a, b| b.cmp(a));
+        let commit = acks[voters.len() / 2];
+        if commit <= self.stats.commit_index {
+            return None;
+        }
+
+        let domains = voters
+            .iter()
+            .map(|id| self.domain(*id, info(*id).as_ref()))
+            .collect::<Vec<_>>();
+        let voter_domains = domains.iter().collect::<BTreeSet<_>>().len();
+        let holding = voters
+            .iter()
+            .zip(domains.iter())
+            .filter(|(id, _)| acked(id) >= commit)
+            .map(|(_, domain)| domain)
+            .collect::<BTreeSet<_>>();
+
+        self.stats.voter_domains = voter_domains;
+        self.stats.commit_domains = holding.len();
+        self.stats.commit_index = commit;
+        self.stats.commits += 1;
+
+        let single_domain = holding.len() == 1 && voter_domains > 1;
+        if single_domain {
+            self.stats.single_domain_commits += 1;
+        }
+
+        let change = match (self.single_domain, single_domain) {
+            (false, true) if self.config.warn_single_domain => holding.iter().next().map(|domain| DomainChange::SingleDomain {
+                domain: (*domain).clone(),
+                voter_domains: voter_domains,
+            }),
+            (true, false) if self.config.warn_single_domain => Some(DomainChange::Spread { domains: holding.len() }),
+            _ => None,
+        };
+        self.single_domain = single_domain;
+        change
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn zoned(zone: &str) -> NodeInfo {
+        let mut labels = BTreeMap::new();
+        labels.insert("zone".to_owned(), zone.to_owned());
+        NodeInfo {
+            cluster_addr: String::new(),
+            app_addr: String::new(),
+            public_addr: String::new(),
+            id: None,
+            labels: labels,
+            cluster: None,
+        }
+    }
+
+    fn tracker() -> DomainTracker {
+        DomainTracker::new(FailureDomainConfig {
+            label: "zone".to_owned(),
+            warn_single_domain: true,
+        })
+    }
+
+    /// Nodes 1 and 2 in zone a, 3 in zone b
+    fn zones(id: NodeId) -> Option<NodeInfo> {
+        Some(zoned(if id == 3 { "b" } else { "a" }))
+    }
+
+    #[test]
+    fn commits_held_in_one_zone_are_reported() {
+        let mut tracker = tracker();
+        let matched = vec![(2, 5), (3, 1)].into_iter().collect::<HashMap<_, _>>();
+
+        match tracker.observe(1, &[1, 2, 3], &matched, &zones) {
+            Some(DomainChange::SingleDomain { domain, voter_domains }) => {
+                assert_eq!((domain.as_str(), voter_domains), ("a", 2));
+            }
+            _ => panic!("single domain commit not reported"),
+        }
+        // the commit index didn't move
+        assert!(tracker.observe(1, &[1, 2, 3], &matched, &zones).is_none());
+
+        let matched = vec![(2, 5), (3, 8)].into_iter().collect::<HashMap<_, _>>();
+        assert!(matches!(tracker.observe(1, &[1, 2, 3], &matched, &zones), Some(DomainChange::Spread { domains: 2 })));
+        let stats = tracker.stats();
+        assert_eq!((stats.commit_index, stats.commits, stats.single_domain_commits), (8, 2, 1));
+    }
+
+    #[test]
+    fn unlabelled_nodes_are_a_domain_of_their_own() {
+        let mut tracker = tracker();
+        let matched = vec![(2, 5)].into_iter().collect::<HashMap<_, _>>();
+
+        assert!(tracker.observe(1, &[1, 2, 3], &matched, &|_| None).is_none());
+        assert_eq!((tracker.stats().voter_domains, tracker.stats().commit_domains), (3, 2));
+    }
+}
@@ -0,0 +1,279 @@
+//! The actors `Network` talks to, behind handles which accept doubles.
+//!
+//! `Network` reaches the raft client through a `RaftHandle`, its peers through `PeerSender`s and
+//! their sessions through `Recipient<CloseSession>`. In production they wrap the `RaftClient`,
+//! `Node` and `NodeSession` actors; a test can build them from any actor handling the same
+//! messages, e.g. one recording what it's sent, and exercise the network logic without a
+//! cluster. Peer doubles are handed the messages JSON-encoded, as they'd be written on the wire.
+
+use actix::prelude::*;
+use futures::future;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::network::{
+    node::{GetPeerInfo, Leave, Reconnect, Rehandshake},
+    remote::{self, RemoteMessage, SendRemoteMessage},
+    Node, PeerInfo,
+};
+use crate::raft::{
+    ClientProposal, GetPendingProposals, GetWriteMetrics, LastLogIndex, LeaderIsolated, ProposeMembership, QueryApp, RaftClient, RemoveNode,
+    StopRaft,
+};
+
+/// The raft client of the node, as seen by `Network`.
+#[derive(Clone)]
+pub struct RaftHandle {
+    pub proposals: Recipient<ClientProposal>,
+    pub memberships: Recipient<ProposeMembership>,
+    pub removals: Recipient<RemoveNode>,
+    pub isolation: Recipient<LeaderIsolated>,
+    pub stop: Recipient<StopRaft>,
+    pub last_log_index: Recipient<LastLogIndex>,
+    pub queries: Recipient<QueryApp>,
+    pub pending_proposals: Recipient<GetPendingProposals>,
+    pub write_metrics: Recipient<GetWriteMetrics>,
+}
+
+impl RaftHandle {
+    /// Handle to any actor standing in for the raft client.
+    pub fn of<A>(addr: Addr<A>) -> RaftHandle
+    where
+        A: Actor<Context = Context<A>>
+            + Handler<ClientProposal>
+            + Handler<ProposeMembership>
+            + Handler<RemoveNode>
+            + Handler<LeaderIsolated>
+            + Handler<StopRaft>
+            + Handler<LastLogIndex>
+            + Handler<QueryApp>
+            + Handler<GetPendingProposals>
+            + Handler<GetWriteMetrics>,
+    {
+        RaftHandle {
+            proposals: addr.clone().recipient(),
+            memberships: addr.clone().recipient(),
+            removals: addr.clone().recipient(),
+            isolation: addr.clone().recipient(),
+            stop: addr.clone().recipient(),
+            last_log_index: addr.clone().recipient(),
+            queries: addr.clone().recipient(),
+            pending_proposals: addr.clone().recipient(),
+            write_metrics: addr.recipient(),
+        }
+    }
+}
+
+impl From<Addr<RaftClient>> for RaftHandle {
+    fn from(addr: Addr<RaftClient>) -> RaftHandle {
+        RaftHandle::of(addr)
+    }
+}
+
+/// A remote message handed to a peer double, `body` is the encoded message, see
+/// `remote::encode_payload`. The double answers with the encoded result, an error fails the
+/// request as a dropped connection would.
+pub struct EncodedRequest {
+    pub type_id: String,
+    pub body: Vec<u8>,
+}
+
+impl Message for EncodedRequest {
+    type Result = Result<Vec<u8>, ()>;
+}
+
+#[derive(Clone)]
+enum Peer {
+    Node(Addr<Node>),
+    Double {
+        requests: Recipient<EncodedRequest>,
+        leave: Recipient<Leave>,
+        reconnect: Recipient<Reconnect>,
+        info: Recipient<GetPeerInfo>,
+    },
+}
+
+/// The connection to a peer, as seen by `Network`.
+#[derive(Clone)]
+pub struct PeerSender(Peer);
+
+impl PeerSender {
+    /// A peer double, remote messages reach it as `EncodedRequest`s.
+    pub fn double<A>(addr: Addr<A>) -> PeerSender
+    where
+        A: Actor<Context = Context<A>> + Handler<EncodedRequest> + Handler<Leave> + Handler<Reconnect> + Handler<GetPeerInfo>,
+    {
+        PeerSender(Peer::Double {
+            requests: addr.clone().recipient(),
+            leave: addr.clone().recipient(),
+            reconnect: addr.clone().recipient(),
+            info: addr.recipient(),
+        })
+    }
+
+    /// The node actor behind the sender, `None` for doubles.
+    pub fn node(&self) -> Option<&Addr<Node>> {
+        match self.0 {
+            Peer::Node(ref addr) => Some(addr),
+            Peer::Double { .. } => None,
+        }
+    }
+
+    /// Send a message to the peer and wait for its answer.
+    pub fn send<M>(&self, msg: M) -> Box<dyn Future<Item = M::Result, Error = MailboxError>>
+    where
+        M: RemoteMessage + 'static,
+        M::Result: Send + Serialize + DeserializeOwned,
+    {
+        match self.0 {
+            Peer::Node(ref addr) => Box::new(addr.send(SendRemoteMessage(msg))),
+            Peer::Double { ref requests, .. } => {
+                let body = match remote::encode_payload(&msg) {
+                    Ok(body) => body,
+                    Err(_) => return Box::new(future::err(MailboxError::Closed)),
+                };
+
+                Box::new(
+                    requests
+                        .send(EncodedRequest { type_id: M::type_id().to_owned(), body: body })
+                        .and_then(|res| {
+                            res.ok()
+                                .and_then(|body| remote::decode_payload::<M::Result>(&body).ok())
+                                .ok_or(MailboxError::Closed)
+                        }),
+                )
+            }
+        }
+    }
+
+    /// Tell the peer this node leaves.
+    pub fn leave(&self) {
+        match self.0 {
+            Peer::Node(ref addr) => addr.do_send(Leave),
+            Peer::Double { ref leave, .. } => {
+                let _ = leave.do_send(Leave);
+            }
+        }
+    }
+
+    /// Reconnect to the peer right away.
+    pub fn reconnect(&self) {
+        match self.0 {
+            Peer::Node(ref addr) => addr.do_send(Reconnect),
+            Peer::Double { ref reconnect, .. } => {
+                let _ = reconnect.do_send(Reconnect);
+            }
+        }
+    }
+
+    /// Re-establish the connection to the peer, doubles have no connection to re-establish.
+    pub fn rehandshake(&self) {
+        if let Some(node) = self.node() {
+            node.do_send(Rehandshake);
+        }
+    }
+
+    pub fn info(&self) -> Box<dyn Future<Item = PeerInfo, Error = MailboxError>> {
+        match self.0 {
+            Peer::Node(ref addr) => Box::new(addr.send(GetPeerInfo)),
+            Peer::Double { ref info, .. } => Box::new(info.send(GetPeerInfo)),
+        }
+    }
+}
+
+impl From<Addr<Node>> for PeerSender {
+    fn from(addr: Addr<Node>) -> PeerSender {
+        PeerSender(Peer::Node(addr))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::{NodeState, PeerStats};
+    use serde::Deserialize;
+
+    /// Peer double answering `Shout`s and counting what else it's told.
+    #[derive(Default)]
+    struct Double {
+        types: Vec<String>,
+        leaves: usize,
+        reconnects: usize,
+    }
+
+    impl Actor for Double {
+        type Context = Context<Self>;
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct Shout(String);
+
+    impl Message for Shout {
+        type Result = String;
+    }
+
+    impl RemoteMessage for Shout {
+        fn type_id() -> &'static str {
+            "Shout"
+        }
+    }
+
+    impl Handler<EncodedRequest> for Double {
+        type Result = Result<Vec<u8>, ()>;
+
+        fn handle(&mut self, msg: EncodedRequest, _: &mut Self::Context) -> Self::Result {
+            self.types.push(msg.type_id);
+            let shout = remote::decode_payload::<Shout>(&msg.body).map_err(|_| ())?;
+            remote::encode_payload(&shout.0.to_uppercase()).map_err(|_| ())
+        }
+    }
+
+    impl Handler<Leave> for Double {
+        type Result = ();
+
+        fn handle(&mut self, _: Leave, _: &mut Self::Context) {
+            self.leaves += 1;
+        }
+    }
+
+    impl Handler<Reconnect> for Double {
+        type Result = ();
+
+        fn handle(&mut self, _: Reconnect, _: &mut Self::Context) {
+            self.reconnects += 1;
+        }
+    }
+
+    impl Handler<GetPeerInfo> for Double {
+        type Result = MessageResult<GetPeerInfo>;
+
+        fn handle(&mut self, _: GetPeerInfo, _: &mut Self::Context) -> Self::Result {
+            MessageResult(PeerInfo {
+                id: 2,
+                state: NodeState::Connected,
+                address: format!("{:?} {} {}", self.types, self.leaves, self.reconnects),
+                connected_since: None,
+                last_error: None,
+                stats: PeerStats::default(),
+            })
+        }
+    }
+
+    #[test]
+    fn doubles_are_sent_encoded_messages() {
+        let mut sys = System::new("handles");
+        let (answer, info) = sys
+            .block_on(future::lazy(|| {
+                let peer = PeerSender::double(Double::default().start());
+                assert!(peer.node().is_none());
+
+                peer.leave();
+                peer.reconnect();
+                peer.rehandshake();
+                peer.send(Shout("hi".to_owned())).join(peer.info())
+            }))
+            .unwrap();
+
+        assert_eq!(answer, "HI");
+        assert_eq!(info.address, r#"["Shout"] 1 1"#);
+    }
+}
@@ -0,0 +1,72 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use crate::config::HedgeConfig;
+
+/// Number of recent read latencies the hedge delay is computed from
+const WINDOW: usize = 256;
+
+/// Tracks recent replica read latencies to decide when a read is hedged.
+pub struct HedgePolicy {
+    config: HedgeConfig,
+    latencies: VecDeque<u64>,
+}
+
+impl HedgePolicy {
+    pub fn new(config: HedgeConfig) -> HedgePolicy {
+        HedgePolicy {
+            config: config,
+            latencies: VecDeque::with_capacity(WINDOW),
+        }
+    }
+
+    pub fn record(&mut self, latency: Duration) {
+        if self.latencies.len() == WINDOW {
+            self.latencies.pop_front();
+        }
+        self.latencies.push_back(latency.as_millis() as u64);
+    }
+
+    /// How long to wait for the first replica before asking a second one, the configured
+    /// percentile of recent latencies but never less than the configured minimum.
+    pub fn delay(&self) -> Duration {
+        let mut sorted = self.latencies.iter().cloned().collect::<Vec<_>>();
+        sorted.sort();
+
+        let percentile = if sorted.is_empty() {
+            0
+        } else {
+            sorted[((sorted.len() - 1) as f64 * self.config.percentile) as usize]
+        };
+
+        Duration::from_millis(std::cmp::max(percentile, self.config.min_delay_ms))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_are_hedged_at_the_percentile() {
+        let mut hedge = HedgePolicy::new(HedgeConfig { percentile: 0.9, min_delay_ms: 5 });
+        assert_eq!(hedge.delay(), Duration::from_millis(5));
+
+        for ms in 1..=100 {
+            hedge.record(Duration::from_millis(ms));
+        }
+        assert_eq!(hedge.delay(), Duration::from_millis(90));
+    }
+
+    #[test]
+    fn old_latencies_leave_the_window() {
+        let mut hedge = HedgePolicy::new(HedgeConfig { percentile: 0.5, min_delay_ms: 0 });
+        for _ in 0..WINDOW {
+            hedge.record(Duration::from_millis(1000));
+        }
+        for _ in 0..WINDOW {
+            hedge.record(Duration::from_millis(10));
+        }
+        assert_eq!(hedge.delay(), Duration::from_millis(10));
+    }
+}
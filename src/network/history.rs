@@ -0,0 +1,169 @@
+use actix_raft::{NodeId, RaftMetrics};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+
+use crate::raft::timing::now_millis;
+
+/// How many leadership events are kept in memory
+const MAX_EVENTS: usize = 1000;
+
+/// A term change or leadership transition as observed by this node.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LeadershipEvent {
+    /// Unix time in milliseconds
+    pub at: u64,
+    /// Node which observed the transition
+    pub node: NodeId,
+    pub term: u64,
+    pub leader: Option<NodeId>,
+    pub previous_term: u64,
+    pub previous_leader: Option<NodeId>,
+    /// Why this node stepped down or the leader changed, when known
+    pub reason: Option<String>,
+}
+
+/// Term and leader transitions of this node, appended as JSON lines to the history file if
+/// one is configured so they survive restarts.
+pub struct LeadershipHistory {
+    path: Option<PathBuf>,
+    events: VecDeque<LeadershipEvent>,
+    last: Option<(u64, Option<NodeId>)>,
+    reason: Option<String>,
+}
+
+impl LeadershipHistory {
+    pub fn new() -> Self {
+        LeadershipHistory {
+            path: None,
+            events: VecDeque::new(),
+            last: None,
+            reason: None,
+        }
+    }
+
+    /// Persist events to the given file, loading the events recorded there before.
+    pub fn persist_to(&mut self, path: PathBuf) {
+        if let Ok(content) = fs::read_to_string(&path) {
+            for line in content.lines() {
+                match serde_json::from_str::<LeadershipEvent>(line) {
+                    Ok(event) => self.push(event),
+                    Err(err) => warn!("Skipping malformed leadership history line in {:?}: {}", path, err),
+                }
+            }
+        }
+        self.last = self.events.back().map(|event| (event.term, event.leader));
+        self.path = Some(path);
+    }
+
+    /// Attach a reason to the next recorded transition.
+    pub fn note(&mut self, reason: &str) {
+        self.reason = Some(reason.to_owned());
+    }
+
+    /// Record a transition if the term or the leader changed since the last metrics.
+    pub fn observe(&mut self, metrics: &RaftMetrics) {
+        let current = (metrics.current_term, metrics.current_leader);
+        let (previous_term, previous_leader) = match self.last.replace(current) {
+            Some(last) if last == current => return,
+            Some(last) => last,
+            None => (0, None),
+        };
+
+        let event = LeadershipEvent {
+            at: now_millis(),
+            node: metrics.id,
+            term: metrics.current_term,
+            leader: metrics.current_leader,
+            previous_term: previous_term,
+            previous_leader: previous_leader,
+            reason: self.reason.take(),
+        };
+
+        if let Some(ref path) = self.path {
+            let line = serde_json::to_string(&event).unwrap_or_default();
+            let res = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut file| writeln!(file, "{}", line));
+
+            if let Err(err) = res {
+                error!("Failed to append to the leadership history {:?}: {}", path, err);
+            }
+        }
+
+        self.push(event);
+    }
+
+    fn push(&mut self, event: LeadershipEvent) {
+        self.events.push_back(event);
+        while self.events.len() > MAX_EVENTS {
+            self.events.pop_front();
+        }
+    }
+
+    /// Events from the given unix time in milliseconds on, oldest first.
+    pub fn since(&self, from: u64) -> Vec<LeadershipEvent> {
+        self.events.iter().filter(|event| event.at >= from).cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_raft::messages::MembershipConfig;
+    use actix_raft::metrics::State;
+
+    fn metrics(term: u64, leader: Option<NodeId>) -> RaftMetrics {
+        RaftMetrics {
+            id: 1,
+            state: State::Follower,
+            current_term: term,
+            last_log_index: 0,
+            last_applied: 0,
+            current_leader: leader,
+            membership_config: MembershipConfig {
+                is_in_joint_consensus: false,
+                members: vec![1, 2, 3],
+                non_voters: Vec::new(),
+                removing: Vec::new(),
+            },
+        }
+    }
+
+    #[test]
+    fn only_transitions_are_recorded() {
+        let mut history = LeadershipHistory::new();
+        history.observe(&metrics(1, None));
+        history.observe(&metrics(1, None));
+        history.note("leader lease expired");
+        history.observe(&metrics(2, Some(2)));
+
+        let events = history.since(0);
+        assert_eq!(events.len(), 2);
+        assert_eq!((events[1].previous_term, events[1].previous_leader), (1, None));
+        assert_eq!(events[1].reason.as_ref().map(String::as_str), Some("leader lease expired"));
+        assert_eq!(events[0].reason, None);
+    }
+
+    #[test]
+    fn persisted_events_survive_restarts() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("leadership");
+
+        let mut history = LeadershipHistory::new();
+        history.persist_to(path.clone());
+        history.observe(&metrics(1, Some(1)));
+
+        let mut restarted = LeadershipHistory::new();
+        restarted.persist_to(path);
+        // the term and leader didn't change across the restart
+        restarted.observe(&metrics(1, Some(1)));
+        assert_eq!(restarted.since(0).len(), 1);
+        restarted.observe(&metrics(2, Some(3)));
+        assert_eq!(restarted.since(0)[1].previous_leader, Some(1));
+    }
+}
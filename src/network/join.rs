@@ -0,0 +1,88 @@
+use actix_raft::NodeId;
+
+use crate::config::{ClusterMetadata, JoinAdmission, NodeInfo};
+
+/// Why a node may not join, `None` when the policy admits it. Nodes without known info carry
+/// no labels, nodes announcing no cluster metadata aren't checked against it.
+pub fn refusal(policy: &JoinAdmission, metadata: Option<&ClusterMetadata>, info: Option<&NodeInfo>, members: &[NodeId]) -> Option<String> {
+    if let (Some(metadata), Some(announced)) = (metadata, info.and_then(|info| info.cluster.as_ref())) {
+        if announced != metadata {
+            return Some(format!(
+                "the node carries the metadata of cluster {}, this cluster is {}",
+                announced.name, metadata.name
+            ));
+        }
+    }
+
+    if let Some(max) = policy.max_members {
+        if members.len() >= max {
+            return Some(format!("the cluster already has {} of at most {} members", members.len(), max));
+        }
+    }
+
+    let missing = policy
+        .required_labels
+        .iter()
+        .filter(|(name, value)| info.and_then(|info| info.labels.get(*name)) != Some(*value))
+        .map(|(name, value)| format!("{} = {}", name, value))
+        .collect::<Vec<_>>();
+
+    if missing.is_empty() {
+        None
+    } else {
+        Some(format!("the node isn't labelled {}", missing.join(", ")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(labels: &[(&str, &str)], cluster: Option<&str>) -> NodeInfo {
+        NodeInfo {
+            cluster_addr: String::new(),
+            app_addr: String::new(),
+            public_addr: String::new(),
+            id: None,
+            labels: labels.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+            cluster: cluster.map(metadata),
+        }
+    }
+
+    fn metadata(name: &str) -> ClusterMetadata {
+        ClusterMetadata {
+            name: name.to_owned(),
+            labels: Default::default(),
+        }
+    }
+
+    #[test]
+    fn nodes_of_another_cluster_are_refused() {
+        let policy = JoinAdmission::default();
+        let prod = metadata("prod");
+
+        assert!(refusal(&policy, Some(&prod), Some(&info(&[], Some("staging"))), &[1]).is_some());
+        assert!(refusal(&policy, Some(&prod), Some(&info(&[], Some("prod"))), &[1]).is_none());
+        // nodes announcing no metadata aren't checked against it
+        assert!(refusal(&policy, Some(&prod), Some(&info(&[], None)), &[1]).is_none());
+    }
+
+    #[test]
+    fn the_policy_bounds_members_and_requires_labels() {
+        let mut policy = JoinAdmission {
+            max_members: Some(2),
+            ..JoinAdmission::default()
+        };
+        assert!(refusal(&policy, None, None, &[1]).is_none());
+        assert!(refusal(&policy, None, None, &[1, 2]).is_some());
+
+        policy.max_members = None;
+        policy.required_labels.insert("zone".to_owned(), "a".to_owned());
+        assert_eq!(
+            refusal(&policy, None, Some(&info(&[("zone", "b")], None)), &[1]),
+            Some("the node isn't labelled zone = a".to_owned())
+        );
+        assert!(refusal(&policy, None, Some(&info(&[("zone", "a")], None)), &[1]).is_none());
+        assert!(refusal(&policy, None, None, &[1]).is_some());
+    }
+}
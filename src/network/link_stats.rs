@@ -0,0 +1,175 @@
+use actix_raft::NodeId;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::clock;
+use crate::network::TrafficCounters;
+use crate::raft::timing::now_millis;
+
+/// File in the data dir holding the cumulative statistics of the links to the peers
+const LINK_STATS_FILE: &str = "peer_stats";
+
+/// How often the statistics are written to the data dir
+const PERSIST_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Health of the link to a peer accumulated over the runs of this node.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct LinkTotals {
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub reconnects: u64,
+    /// Unix time in milliseconds the peer was last seen connected
+    pub last_seen: Option<u64>,
+    /// Unix time in milliseconds the statistics of the peer started at
+    pub since: u64,
+}
+
+/// Link statistics of the peers, those of the previous runs are recovered from the data dir
+/// and added to the counters of this run.
+pub struct LinkStats {
+    path: Option<PathBuf>,
+    /// Totals as of the end of the previous run
+    previous: HashMap<NodeId, LinkTotals>,
+    /// Reconnects and last seen of this run, the bytes are counted by the traffic counters
+    current: HashMap<NodeId, LinkTotals>,
+    written: Instant,
+}
+
+impl LinkStats {
+    /// Statistics of this run only.
+    pub fn new() -> LinkStats {
+        LinkStats {
+            path: None,
+            previous: HashMap::new(),
+            current: HashMap::new(),
+            written: clock::now(),
+        }
+    }
+
+    /// Statistics recovered from and written to the data dir.
+    pub fn open(data_dir: &Path) -> LinkStats {
+        let path = data_dir.join(LINK_STATS_FILE);
+        let previous = match fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|err| {
+                warn!("Ignoring the unreadable peer statistics in {:?}: {}", path, err);
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new(),
+        };
+
+        LinkStats {
+            path: Some(path),
+            previous: previous,
+            current: HashMap::new(),
+            written: clock::now(),
+        }
+    }
+
+    /// The connection to the peer dropped, it was seen until now.
+    pub fn reconnected(&mut self, id: NodeId) {
+        let totals = self.current.entry(id).or_insert_with(|| LinkTotals {
+            since: now_millis(),
+            ..LinkTotals::default()
+        });
+        totals.reconnects += 1;
+        totals.last_seen = Some(now_millis());
+    }
+
+    pub fn forget(&mut self, id: NodeId) {
+        self.previous.remove(&id);
+        self.current.remove(&id);
+    }
+
+    /// Cumulative statistics of every peer, the connected ones are seen now.
+    pub fn totals(&self, traffic: &TrafficCounters, connected: &[NodeId]) -> HashMap<NodeId, LinkTotals> {
+        let now = now_millis();
+        let mut totals = self.previous.clone();
+
+        for (id, bytes) in traffic.peer_totals() {
+            let peer = totals.entry(id).or_insert_with(|| LinkTotals {
+                since: now,
+                ..LinkTotals::default()
+            });
+            peer.bytes_sent += bytes.sent;
+            peer.bytes_received += bytes.received;
+        }
+        for (id, current) in self.current.iter() {
+            let peer = totals.entry(*id).or_insert_with(|| LinkTotals {
+                since: current.since,
+                ..LinkTotals::default()
+            });
+            peer.reconnects += current.reconnects;
+            peer.last_seen = current.last_seen.max(peer.last_seen);
+        }
+        for id in connected {
+            if let Some(peer) = totals.get_mut(id) {
+                peer.last_seen = Some(now);
+            }
+        }
+
+        totals
+    }
+
+    /// Write the statistics once the persist interval elapsed since they were last written.
+    pub fn persist_due(&mut self, traffic: &TrafficCounters, connected: &[NodeId]) {
+        if clock::elapsed(self.written) >= PERSIST_INTERVAL {
+            self.persist(traffic, connected);
+        }
+    }
+
+    pub fn persist(&mut self, traffic: &TrafficCounters, connected: &[NodeId]) {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return,
+        };
+
+        self.written = clock::now();
+        if let Err(err) = write(path, &self.totals(traffic, connected)) {
+            warn!("Error writing the peer statistics to {:?}: {}", path, err);
+        }
+    }
+}
+
+fn write(path: &Path, totals: &HashMap<NodeId, LinkTotals>) -> io::Result<()> {
+    let data = serde_json::to_vec(totals).map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+    // written aside and renamed, a crash while writing leaves the previous statistics
+    let partial = path.with_extension("partial");
+    fs::write(&partial, data)?;
+    fs::rename(&partial, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn totals_add_this_run_to_the_previous_ones() {
+        let dir = tempfile::tempdir().unwrap();
+        let traffic = TrafficCounters::default();
+        traffic.peer_bytes(2, 100, 50);
+
+        let mut stats = LinkStats::open(dir.path());
+        stats.reconnected(2);
+        stats.persist(&traffic, &[]);
+
+        let restarted = LinkStats::open(dir.path());
+        let traffic = TrafficCounters::default();
+        traffic.peer_bytes(2, 10, 5);
+        let totals = restarted.totals(&traffic, &[2]);
+        let peer = &totals[&2];
+        assert_eq!((peer.bytes_sent, peer.bytes_received, peer.reconnects), (110, 55, 1));
+        assert!(peer.last_seen.is_some());
+    }
+
+    #[test]
+    fn forgotten_peers_are_dropped() {
+        let mut stats = LinkStats::new();
+        stats.reconnected(2);
+        stats.forget(2);
+        assert!(stats.totals(&TrafficCounters::default(), &[]).is_empty());
+    }
+}
@@ -0,0 +1,461 @@
+use actix::prelude::*;
+use actix_raft::{NodeId, RaftMetrics};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::mailbox::MailboxStats;
+use crate::network::FailureDomainStats;
+use crate::memory::MemoryUsage;
+use crate::raft::app::{AppMetric, AppMetricValue};
+use crate::raft::io_stats::IoStats;
+use crate::raft::CommandStats;
+use crate::raft::timing::LatencyHistogram;
+
+/// Peer messages which keep the cluster together, every other message is on the data plane
+const CONTROL_MESSAGES: &[&str] = &["VoteRequest", "ChangeRaftClusterConfig", "ProposeMembership"];
+
+/// Whether a peer message carries replication and client traffic or keeps the cluster
+/// together, telling saturation from volume apart from saturation from instability.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Plane {
+    /// Log replication, snapshots, client proposals and reads, application messages
+    Data,
+    /// Votes, membership changes, joins, leaves and pings
+    Control,
+}
+
+impl Plane {
+    /// Plane of a remote message by its type id.
+    pub fn of(type_id: &str) -> Plane {
+        if CONTROL_MESSAGES.contains(&type_id) {
+            Plane::Control
+        } else {
+            Plane::Data
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Plane::Data => "data",
+            Plane::Control => "control",
+        }
+    }
+}
+
+/// Messages of one frame by plane, a batch may carry both.
+#[derive(Debug, Default)]
+pub struct FrameTally {
+    pub data: u64,
+    pub control: u64,
+}
+
+impl FrameTally {
+    pub fn add(&mut self, plane: Plane) {
+        match plane {
+            Plane::Data => self.data += 1,
+            Plane::Control => self.control += 1,
+        }
+    }
+
+    /// The bytes of a frame carrying any data plane message count toward the data plane.
+    fn plane(&self) -> Plane {
+        if self.data > 0 {
+            Plane::Data
+        } else {
+            Plane::Control
+        }
+    }
+}
+
+#[derive(Default)]
+struct PlaneCounters {
+    messages_sent: AtomicU64,
+    messages_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+}
+
+impl PlaneCounters {
+    fn stats(&self) -> PlaneStats {
+        PlaneStats {
+            messages_sent: self.messages_sent.load(Ordering::Relaxed),
+            messages_received: self.messages_received.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Frames exchanged with peers and reconnects of a network, shared by its peer sessions.
+#[derive(Default)]
+pub struct TrafficCounters {
+    messages_sent: AtomicU64,
+    messages_received: AtomicU64,
+    reconnects: AtomicU64,
+    data: PlaneCounters,
+    control: PlaneCounters,
+    /// Bytes sent to and received from each peer over all its sessions
+    peers: Mutex<HashMap<NodeId, PeerBytes>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub struct PeerBytes {
+    pub sent: u64,
+    pub received: u64,
+}
+
+pub type Traffic = Arc<TrafficCounters>;
+
+impl TrafficCounters {
+    fn plane(&self, plane: Plane) -> &PlaneCounters {
+        match plane {
+            Plane::Data => &self.data,
+            Plane::Control => &self.control,
+        }
+    }
+
+    /// Count a frame written to a peer, `bytes` as sent on the wire.
+    pub fn sent(&self, tally: &FrameTally, bytes: usize) {
+        self.messages_sent.fetch_add(1, Ordering::Relaxed);
+        self.data.messages_sent.fetch_add(tally.data, Ordering::Relaxed);
+        self.control.messages_sent.fetch_add(tally.control, Ordering::Relaxed);
+        self.plane(tally.plane()).bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Count a frame read from a peer, `bytes` as received on the wire.
+    pub fn received(&self, tally: &FrameTally, bytes: usize) {
+        self.messages_received.fetch_add(1, Ordering::Relaxed);
+        self.data.messages_received.fetch_add(tally.data, Ordering::Relaxed);
+        self.control.messages_received.fetch_add(tally.control, Ordering::Relaxed);
+        self.plane(tally.plane()).bytes_received.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Count the bytes of a frame exchanged with a known peer.
+    pub fn peer_bytes(&self, peer: NodeId, sent: usize, received: usize) {
+        let mut peers = self.peers.lock().unwrap();
+        let bytes = peers.entry(peer).or_default();
+        bytes.sent += sent as u64;
+        bytes.received += received as u64;
+    }
+
+    pub fn peer_totals(&self) -> HashMap<NodeId, PeerBytes> {
+        self.peers.lock().unwrap().clone()
+    }
+
+    pub fn forget_peer(&self, peer: NodeId) {
+        self.peers.lock().unwrap().remove(&peer);
+    }
+
+    pub fn reconnected(&self) {
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> TrafficStats {
+        TrafficStats {
+            messages_sent: self.messages_sent.load(Ordering::Relaxed),
+            messages_received: self.messages_received.load(Ordering::Relaxed),
+            reconnects: self.reconnects.load(Ordering::Relaxed),
+            data: self.data.stats(),
+            control: self.control.stats(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct TrafficStats {
+    /// Frames, a batch of messages is one frame
+    pub messages_sent: u64,
+    pub messages_received: u64,
+    pub reconnects: u64,
+    pub data: PlaneStats,
+    pub control: PlaneStats,
+}
+
+/// Messages and bytes of one plane, the messages of batches are counted one by one.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct PlaneStats {
+    pub messages_sent: u64,
+    pub messages_received: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+/// Raft state of a node as of its last metrics.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RaftState {
+    pub state: String,
+    pub term: u64,
+    pub last_log_index: u64,
+    pub last_applied: u64,
+    pub leader: Option<NodeId>,
+    pub members: Vec<NodeId>,
+    pub non_voters: Vec<NodeId>,
+}
+
+impl<'a> From<&'a RaftMetrics> for RaftState {
+    fn from(metrics: &'a RaftMetrics) -> Self {
+        RaftState {
+            state: format!("{:?}", metrics.state),
+            term: metrics.current_term,
+            last_log_index: metrics.last_log_index,
+            last_applied: metrics.last_applied,
+            leader: metrics.current_leader,
+            members: metrics.membership_config.members.clone(),
+            non_voters: metrics.membership_config.non_voters.clone(),
+        }
+    }
+}
+
+/// Metrics of one network of a node, published to `SubscribeMetrics` subscribers with every
+/// raft metrics update of the cluster network.
+#[derive(Message, Serialize, Debug, Clone)]
+pub struct NodeMetrics {
+    pub node: NodeId,
+    /// `cluster` or `app`
+    pub network: String,
+    /// Only known on the cluster network of raft members
+    pub raft: Option<RaftState>,
+    pub traffic: TrafficStats,
+    /// Proposals accepted by this node by command kind, only on the cluster network
+    pub writes: BTreeMap<String, CommandStats>,
+    /// Bytes held by the in-memory structures of the node, shared by both networks
+    pub memory: Vec<MemoryUsage>,
+    /// Metrics reported by the application state machine, only on the cluster network
+    pub app: Vec<AppMetric>,
+    /// Mailboxes of the actors of the node, only on the cluster network
+    pub mailboxes: Vec<MailboxStats>,
+    /// Linearizable reads served as the leader, only on the cluster network
+    pub read_index: ReadIndexStats,
+    /// IO of the storage layer, only on the cluster network
+    pub io: IoStats,
+    /// Failure domains of the commits while leading, only on the cluster network when configured
+    pub failure_domains: Option<FailureDomainStats>,
+}
+
+/// Leadership confirmation rounds of the linearizable reads served by a leader, reads arriving
+/// during a round share the next one.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ReadIndexStats {
+    pub reads: u64,
+    pub rounds: u64,
+}
+
+/// Render metrics in the Prometheus text exposition format.
+pub fn render_prometheus(metrics: &[NodeMetrics]) -> String {
+    let mut out = String::new();
+
+    for m in metrics {
+        let labels = format!("node=\"{}\",network=\"{}\"", m.node, m.network);
+
+        let _ = writeln!(out, "raftor_peer_messages_sent_total{{{}}} {}", labels, m.traffic.messages_sent);
+        let _ = writeln!(out, "raftor_peer_messages_received_total{{{}}} {}", labels, m.traffic.messages_received);
+        let _ = writeln!(out, "raftor_peer_reconnects_total{{{}}} {}", labels, m.traffic.reconnects);
+
+        for (plane, stats) in [(Plane::Data, &m.traffic.data), (Plane::Control, &m.traffic.control)].iter() {
+            let labels = format!("{},plane=\"{}\"", labels, plane.name());
+
+            let _ = writeln!(out, "raftor_peer_plane_messages_sent_total{{{}}} {}", labels, stats.messages_sent);
+            let _ = writeln!(out, "raftor_peer_plane_messages_received_total{{{}}} {}", labels, stats.messages_received);
+            let _ = writeln!(out, "raftor_peer_plane_bytes_sent_total{{{}}} {}", labels, stats.bytes_sent);
+            let _ = writeln!(out, "raftor_peer_plane_bytes_received_total{{{}}} {}", labels, stats.bytes_received);
+        }
+
+        if let Some(ref raft) = m.raft {
+            let labels = format!("node=\"{}\"", m.node);
+            let is_leader = if raft.leader == Some(m.node) { 1 } else { 0 };
+
+            let _ = writeln!(out, "raftor_raft_term{{{}}} {}", labels, raft.term);
+            let _ = writeln!(out, "raftor_raft_last_log_index{{{}}} {}", labels, raft.last_log_index);
+            let _ = writeln!(out, "raftor_raft_last_applied{{{}}} {}", labels, raft.last_applied);
+            let _ = writeln!(out, "raftor_raft_is_leader{{{}}} {}", labels, is_leader);
+            let _ = writeln!(out, "raftor_raft_members{{{}}} {}", labels, raft.members.len());
+            let _ = writeln!(out, "raftor_raft_non_voters{{{}}} {}", labels, raft.non_voters.len());
+        }
+
+        // both networks report the memory of the node
+        if m.network == "cluster" {
+            for usage in m.memory.iter() {
+                let labels = format!("node=\"{}\",area=\"{}\"", m.node, usage.area);
+
+                let _ = writeln!(out, "raftor_memory_bytes{{{}}} {}", labels, usage.bytes);
+                if let Some(limit) = usage.limit {
+                    let _ = writeln!(out, "raftor_memory_limit_bytes{{{}}} {}", labels, limit);
+                }
+                let _ = writeln!(out, "raftor_memory_shed_total{{{}}} {}", labels, usage.shed);
+            }
+        }
+
+        for (command, stats) in m.writes.iter() {
+            let labels = format!("node=\"{}\",command=\"{}\"", m.node, command);
+
+            let _ = writeln!(out, "raftor_proposals_total{{{}}} {}", labels, stats.proposals);
+            let _ = writeln!(out, "raftor_proposals_failed_total{{{}}} {}", labels, stats.failed);
+            let _ = writeln!(out, "raftor_proposal_bytes_total{{{}}} {}", labels, stats.bytes);
+            write_histogram(&mut out, "raftor_proposal_latency_ms", &labels, &stats.latency);
+        }
+
+        if m.network == "cluster" {
+            let labels = format!("node=\"{}\"", m.node);
+
+            let _ = writeln!(out, "raftor_read_index_requests_total{{{}}} {}", labels, m.read_index.reads);
+            let _ = writeln!(out, "raftor_read_index_rounds_total{{{}}} {}", labels, m.read_index.rounds);
+
+            let io = &m.io;
+            let writes = [
+                ("log", io.log_writes, io.log_bytes_written),
+                ("snapshot", io.snapshot_writes, io.snapshot_bytes_written),
+            ];
+            let reads = [
+                ("log", io.log_reads, io.log_bytes_read),
+                ("snapshot", io.snapshot_reads, io.snapshot_bytes_read),
+            ];
+            for (kind, ops, bytes) in writes.iter() {
+                let labels = format!("{},kind=\"{}\"", labels, kind);
+                let _ = writeln!(out, "raftor_storage_write_ops_total{{{}}} {}", labels, ops);
+                let _ = writeln!(out, "raftor_storage_bytes_written_total{{{}}} {}", labels, bytes);
+            }
+            for (kind, ops, bytes) in reads.iter() {
+                let labels = format!("{},kind=\"{}\"", labels, kind);
+                let _ = writeln!(out, "raftor_storage_read_ops_total{{{}}} {}", labels, ops);
+                let _ = writeln!(out, "raftor_storage_bytes_read_total{{{}}} {}", labels, bytes);
+            }
+            let _ = writeln!(out, "raftor_storage_payload_bytes_total{{{}}} {}", labels, io.payload_bytes);
+            if let Some(amplification) = io.write_amplification {
+                let _ = writeln!(out, "raftor_storage_write_amplification{{{}}} {}", labels, amplification);
+            }
+
+            if let Some(ref domains) = m.failure_domains {
+                let labels = format!("{},label=\"{}\"", labels, domains.label);
+                let _ = writeln!(out, "raftor_voter_failure_domains{{{}}} {}", labels, domains.voter_domains);
+                let _ = writeln!(out, "raftor_commit_failure_domains{{{}}} {}", labels, domains.commit_domains);
+                let _ = writeln!(out, "raftor_commit_advances_total{{{}}} {}", labels, domains.commits);
+                let _ = writeln!(out, "raftor_single_domain_commits_total{{{}}} {}", labels, domains.single_domain_commits);
+            }
+        }
+
+        for stats in m.mailboxes.iter() {
+            let labels = format!("node=\"{}\",actor=\"{}\"", m.node, stats.actor);
+
+            let _ = writeln!(out, "raftor_mailbox_capacity{{{}}} {}", labels, stats.capacity);
+            let _ = writeln!(out, "raftor_mailbox_full_total{{{}}} {}", labels, stats.full);
+        }
+
+        for metric in m.app.iter() {
+            let labels = format!("node=\"{}\",command=\"{}\"", m.node, metric.command.replace('"', "\\\""));
+            let name = metric_name(&metric.name);
+
+            match metric.value {
+                AppMetricValue::Counter(count) => {
+                    let _ = writeln!(out, "raftor_app_{}_total{{{}}} {}", name, labels, count);
+                }
+                AppMetricValue::Gauge(value) => {
+                    let _ = writeln!(out, "raftor_app_{}{{{}}} {}", name, labels, value);
+                }
+            }
+        }
+    }
+
+    out
+}
+
+/// Name of an application metric with the characters Prometheus doesn't allow replaced.
+fn metric_name(name: &str) -> String {
+    name.chars().map(|c| if c.is_ascii_alphanumeric() || c == '_' { c } else { '_' }).collect()
+}
+
+/// Write a histogram, its buckets are cumulative in Prometheus.
+fn write_histogram(out: &mut String, name: &str, labels: &str, histogram: &LatencyHistogram) {
+    let mut cumulative = 0;
+
+    for (bound, count) in histogram.buckets.iter() {
+        cumulative += count;
+        let le = bound.map(|bound| bound.to_string()).unwrap_or("+Inf".to_owned());
+        let _ = writeln!(out, "{}_bucket{{{},le=\"{}\"}} {}", name, labels, le, cumulative);
+    }
+
+    let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, histogram.sum_ms);
+    let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, histogram.count);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metrics(network: &str) -> NodeMetrics {
+        NodeMetrics {
+            node: 1,
+            network: network.to_owned(),
+            raft: None,
+            traffic: TrafficStats::default(),
+            writes: BTreeMap::new(),
+            memory: Vec::new(),
+            app: Vec::new(),
+            mailboxes: Vec::new(),
+            read_index: ReadIndexStats::default(),
+            io: IoStats::default(),
+            failure_domains: None,
+        }
+    }
+
+    #[test]
+    fn frames_count_toward_their_plane() {
+        assert_eq!(Plane::of("VoteRequest"), Plane::Control);
+        assert_eq!(Plane::of("AppendEntriesRequest"), Plane::Data);
+
+        let traffic = TrafficCounters::default();
+        let mut batch = FrameTally::default();
+        batch.add(Plane::Control);
+        batch.add(Plane::Data);
+        traffic.sent(&batch, 100);
+        let mut vote = FrameTally::default();
+        vote.add(Plane::Control);
+        traffic.sent(&vote, 10);
+
+        let stats = traffic.stats();
+        assert_eq!(stats.messages_sent, 2);
+        assert_eq!((stats.data.messages_sent, stats.data.bytes_sent), (1, 100));
+        assert_eq!((stats.control.messages_sent, stats.control.bytes_sent), (2, 10));
+    }
+
+    #[test]
+    fn app_metric_names_are_sanitized() {
+        assert_eq!(metric_name("rooms.created-per-sec"), "rooms_created_per_sec");
+    }
+
+    #[test]
+    fn only_the_cluster_network_reports_the_node() {
+        let mut cluster = metrics("cluster");
+        cluster.memory.push(MemoryUsage {
+            area: "events".to_owned(),
+            bytes: 10,
+            limit: None,
+            shed: 0,
+        });
+        let mut app = cluster.clone();
+        app.network = "app".to_owned();
+
+        let out = render_prometheus(&[cluster, app]);
+        assert_eq!(out.matches("raftor_memory_bytes{node=\"1\",area=\"events\"} 10").count(), 1);
+        assert_eq!(out.matches("raftor_read_index_requests_total").count(), 1);
+        assert!(out.contains("raftor_peer_plane_bytes_sent_total{node=\"1\",network=\"app\",plane=\"control\"} 0"));
+    }
+
+    #[test]
+    fn histograms_are_cumulative() {
+        let histogram = LatencyHistogram {
+            buckets: vec![(Some(1), 2), (Some(10), 3), (None, 1)],
+            count: 6,
+            sum_ms: 40,
+        };
+        let mut out = String::new();
+        write_histogram(&mut out, "latency_ms", "node=\"1\"", &histogram);
+
+        assert!(out.contains("latency_ms_bucket{node=\"1\",le=\"10\"} 5"));
+        assert!(out.contains("latency_ms_bucket{node=\"1\",le=\"+Inf\"} 6"));
+        assert!(out.contains("latency_ms_count{node=\"1\"} 6"));
+    }
+}
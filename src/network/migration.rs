@@ -0,0 +1,111 @@
+use actix_raft::NodeId;
+use serde::{Deserialize, Serialize};
+
+use crate::config::NodeInfo;
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum MigrationStepState {
+    Pending,
+    /// The new address was validated and is being proposed
+    InProgress,
+    /// The new address is in the replicated address book at the given log index
+    Applied(u64),
+    /// The step can be retried once the cause is fixed
+    Failed(String),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MigrationStep {
+    pub id: NodeId,
+    pub from: NodeInfo,
+    pub to: NodeInfo,
+    pub state: MigrationStepState,
+}
+
+/// Migration of the peer addresses of the cluster, one node at a time.
+///
+/// Every step checks that the node migrated by the previous step reconnected on its new
+/// address and that the next node is reachable on its new one before its address is changed
+/// in the replicated address book.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AddressMigration {
+    pub steps: Vec<MigrationStep>,
+}
+
+impl AddressMigration {
+    pub fn new(steps: Vec<MigrationStep>) -> Self {
+        AddressMigration { steps: steps }
+    }
+
+    /// Index of the next step to run, none once every step is applied.
+    pub fn next(&self) -> Option<usize> {
+        self.steps.iter().position(|step| match step.state {
+            MigrationStepState::Applied(_) => false,
+            _ => true,
+        })
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.next().is_none()
+    }
+
+    pub fn in_progress(&self) -> bool {
+        self.steps.iter().any(|step| step.state == MigrationStepState::InProgress)
+    }
+
+    /// The step applied before the given one, its node must be reachable on its new address
+    /// before moving on.
+    pub fn previous(&self, step: usize) -> Option<&MigrationStep> {
+        step.checked_sub(1).map(|previous| &self.steps[previous])
+    }
+
+    pub fn set_state(&mut self, step: usize, state: MigrationStepState) {
+        if let Some(step) = self.steps.get_mut(step) {
+            step.state = state;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(addr: &str) -> NodeInfo {
+        NodeInfo {
+            cluster_addr: addr.to_owned(),
+            app_addr: String::new(),
+            public_addr: String::new(),
+            id: None,
+            labels: Default::default(),
+            cluster: None,
+        }
+    }
+
+    fn step(id: NodeId) -> MigrationStep {
+        MigrationStep {
+            id: id,
+            from: info("old:8000"),
+            to: info("new:8000"),
+            state: MigrationStepState::Pending,
+        }
+    }
+
+    #[test]
+    fn steps_run_one_after_the_other() {
+        let mut migration = AddressMigration::new(vec![step(1), step(2)]);
+        assert_eq!(migration.next(), Some(0));
+        assert!(migration.previous(0).is_none());
+
+        migration.set_state(0, MigrationStepState::InProgress);
+        assert!(migration.in_progress());
+        migration.set_state(0, MigrationStepState::Applied(10));
+        assert_eq!(migration.next(), Some(1));
+        assert_eq!(migration.previous(1).map(|step| step.id), Some(1));
+
+        // a failed step is retried
+        migration.set_state(1, MigrationStepState::Failed("unreachable".to_owned()));
+        assert_eq!(migration.next(), Some(1));
+        migration.set_state(1, MigrationStepState::Applied(12));
+        assert!(migration.is_finished());
+    }
+}
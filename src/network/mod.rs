@@ -1,14 +1,55 @@
 mod codec;
+mod compress;
+mod contact;
+mod discovery;
+mod domains;
 mod network;
+mod handles;
+mod hedge;
+mod join;
+mod link_stats;
+mod history;
+mod metrics;
+mod migration;
 mod node;
+mod placement;
+mod progress;
+mod resolver;
+#[cfg(feature = "proto")]
+pub mod proto;
 mod recipient;
-pub mod remote;
+pub(crate) mod remote;
+mod replay;
 mod session;
+mod throttle;
+mod tls;
+mod topology;
+mod transition;
+mod witness;
 
-pub use self::codec::{ClientNodeCodec, NodeCodec, NodeRequest, NodeResponse};
+pub use self::compress::Compression;
+pub use self::codec::{DebugLinks, LinkDebug, LogState, NodeRequest, NodeResponse, SharedLogState, VERSION};
+#[cfg(not(feature = "proto"))]
+pub use self::codec::{ClientNodeCodec, NodeCodec};
+#[cfg(feature = "proto")]
+pub use self::proto::{ProtoClientNodeCodec as ClientNodeCodec, ProtoNodeCodec as NodeCodec};
 pub use self::network::{
-    DiscoverNodes, DistributeMessage, GetCurrentLeader, GetNode, GetNodeAddr, GetNodeById, Network, PeerConnected, DistributeAndWait, NodeDisconnect, RestoreNode, GetNodes, GetClusterState, SetClusterState, NetworkState, Handshake,
+    AwaitLeader, DiscoverNodes, DistributeMessage, GetCurrentLeader, GetNode, GetNodeAddr, GetNodeById, Network, DistributeAndWait, GetNodes, GetNodesDelta, GetClusterState, SetClusterState, NetworkState, GetRaftMetrics, StepDown, SubscribeProgress, WaitForApplied, LeaderCommit, MembershipReport, ValidateMembershipChange, PrepareMembershipChange, PreparedChange, ConfirmMembershipChange, ConfirmError, Broadcast, PeerError, ScatterGather, GetPeer, GetPeers, HedgedRead, DebugPeer, GetClusterDescriptor, GetLeadershipHistory, AddressChanged, CordonChanged, MetadataChanged, GetClusterMetadata, StartAddressMigration, MigrateNextAddress, GetAddressMigration, AbortAddressMigration, ClientRequest, ProposeError, RetryBudget, SetEgressLimit, GetEgressLimit, RetryReport, ProposeAttempt, AddMember, RemoveMember, SetPartition, NodeStatus, GetNodeStatus, Formation, ClientRead, ReadBarrier, ReadIndex, ReadConsistency, ReadError, GetEvents, Bootstrap, GetNodeMetrics, SubscribeMetrics, ClusterStatus, GetClusterStatus, GetFollowerProgress, MemberStatus, TransferLeadership, ReloadTls, Shutdown, Halt, RequestJoin, JoinStatus, JoinError, PendingJoin, GetPendingJoins, ApproveJoin, RejectJoin, ServeClients, FetchBlob, GetTopology,
 };
-pub use self::node::Node;
-pub use self::recipient::{HandlerRegistry, Provider, RemoteMessageHandler};
-pub use self::session::NodeSession;
+pub(crate) use self::network::{Handshake, PeerCatchingUp, PeerConnected, PeerReconnecting, RestoreNode, SessionClosed, SetLease};
+pub use self::handles::{EncodedRequest, PeerSender, RaftHandle};
+pub use self::history::LeadershipEvent;
+pub use self::throttle::{EgressLimiter, EgressStats};
+pub use self::domains::FailureDomainStats;
+pub use self::metrics::{render_prometheus, FrameTally, NodeMetrics, Plane, PlaneStats, RaftState, ReadIndexStats, Traffic, TrafficCounters, TrafficStats};
+pub use self::link_stats::LinkTotals;
+pub use self::topology::{Topology, TopologyLink, TopologyNode};
+pub use self::migration::{AddressMigration, MigrationStep, MigrationStepState};
+pub use self::node::{GetPeerInfo, Leave, Node, NodeState, PeerHandle, PeerInfo, PeerStats, Reconnect};
+pub use self::progress::ClusterProgress;
+pub use self::discovery::{DeltaCursor, Discovery, DnsDiscovery, GossipDiscovery, MembershipDelta, PeerDiscovery};
+pub use self::resolver::{CachingResolver, PeerResolver, Resolver, SystemResolver};
+pub use self::recipient::{HandlerRegistry, Provider, RemoteMessageHandler, APP_MESSAGE_PREFIX};
+pub use self::remote::RemoteMessage;
+pub use self::session::{CloseSession, NodeSession};
+pub use self::tls::{PeerStream, PeerTls, Protocol};
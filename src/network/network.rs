@@ -1,31 +1,117 @@
 use actix::prelude::*;
-use actix_web::client::Client;
-use actix_raft::{NodeId, RaftMetrics};
+use actix_web::{client::Client, error::BlockingError, web};
+use actix_raft::{
+    messages::{ClientError, ClientPayloadResponse},
+    NodeId, RaftMetrics,
+};
+use crypto::util::fixed_time_eq;
 use log::debug;
 use serde::{de::DeserializeOwned, Serialize, Deserialize};
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io;
+use std::path::Path;
 use std::sync::{Arc, RwLock};
 use std::time::{Duration, Instant};
 use tokio::codec::FramedRead;
 use tokio::io::AsyncRead;
 use tokio::net::{TcpListener, TcpStream};
-use tokio::timer::Delay;
+use tokio::sync::oneshot;
+use tokio::timer::Timeout;
 
+use crate::clock;
 use crate::network::{
-    remote::{RemoteMessage, SendRemoteMessage, DispatchMessage},
-    HandlerRegistry, Node, NodeCodec, NodeSession,
+    hedge::HedgePolicy,
+    join,
+    history::{LeadershipEvent, LeadershipHistory},
+    migration::{AddressMigration, MigrationStep, MigrationStepState},
+    handles::{PeerSender, RaftHandle},
+    node::LinkTuning,
+    session::CloseSession,
+    placement,
+    progress::{self, ClusterProgress, ProgressSubscribers},
+    replay::ResponseCache,
+    throttle::{EgressLimiter, EgressStats, InboundThrottle},
+    contact::ContactLog,
+    link_stats::{LinkStats, LinkTotals},
+    discovery::{self, DeltaCursor, MembershipDelta, MembershipLog, PeerDiscovery},
+    tls::{self, Protocol},
+    compress::Compression,
+    domains::{DomainChange, DomainTracker},
+    transition::{Stalled, TransitionWatch},
+    witness::Witness,
+    remote::{self, RemoteMessage, SendRemoteMessage, DispatchMessage},
+    CachingResolver, DebugLinks, HandlerRegistry, LinkDebug, LogState, Node, NodeMetrics, PeerResolver, RaftState, ReadIndexStats, Topology, SystemResolver, Traffic, NodeCodec, NodeSession, NodeState, PeerHandle, PeerStream, PeerTls, SharedLogState, VERSION,
 };
 
-use crate::config::{ConfigSchema, NodeInfo, NetworkType};
+use crate::crash::CrashState;
+use crate::config::{BootstrapPolicy, CatchUpConfig, ClientListener, ClusterMetadata, ConfigSchema, ConstrainedLinks, DescriptorConfig, DialTimeouts, JoinAdmission, NodeInfo, NetworkType, PeerRateLimit, PeerVersions, PlacementRule, MailboxCapacities};
+use crate::descriptor::{ClusterDescriptor, DescriptorMember};
+use crate::json_protocol::JsonConnect;
+use crate::mailbox::{self, Mailbox};
+use crate::dictionary;
+use crate::error::RaftorError;
+use crate::events::{ClusterEvent, EventKind, EventLog};
 use crate::hash_ring::RingType;
+use crate::memory::{MemoryAccount, MemoryCounters};
+use crate::raft::blob::{BlobRef, Spill};
+use crate::raft::durable::voter_configs;
+use crate::raft::hooks::{HookChain, Hooks};
+use crate::raft::io_stats::{self, IoStats};
+use crate::raft::timing::now_millis;
+use crate::raft::transfer::SnapshotSealer;
+use crate::runtime::{ExitHandle, ExitReason};
 use crate::raft::{
     storage::{self, *},
-    RaftClient,
+    ClientProposal,
+    ProposeMembership,
+    RequestCatchUp,
     RemoveNode,
     AddNode,
+    GetWriteMetrics,
+    LeaderIsolated,
+    GetPendingProposals,
+    QueryApp,
+    StopRaft,
 };
 use crate::server;
-use crate::utils::generate_node_id;
+use crate::utils;
+
+/// How long an unknown RPC target stays quiet after being reported
+const UNKNOWN_TARGET_TTL: Duration = Duration::from_secs(30);
+
+/// How long a node being migrated has to accept a connection on its new address
+const MIGRATION_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a closed peer session can be resumed before the peer is disconnected
+const RESUME_GRACE: Duration = Duration::from_secs(5);
+/// Time between two peer connections re-established after the TLS certificates were reloaded
+const TLS_RECYCLE_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How often a linearizable read checks whether a quorum confirmed the leadership
+const READ_INDEX_POLL: Duration = Duration::from_millis(50);
+/// How long a linearizable read waits for this node to apply its read index
+const READ_APPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often a forwarded proposal is retried while no leader can take it
+const PROPOSAL_RETRY_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Election timeouts a leader waits after handing leadership to a node of higher priority
+/// before trying again
+const PRIORITY_TRANSFER_BACKOFF: u32 = 10;
+
+/// How long a shutdown waits for the accepted proposals to be applied
+const SHUTDOWN_DRAIN: Duration = Duration::from_secs(10);
+
+/// How long a peer has to hand over a chunk of a blob before the next one is asked
+const BLOB_CHUNK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// State of a closed peer session kept for its resumption.
+struct SuspendedSession {
+    token: String,
+    throttle: Option<InboundThrottle>,
+    responses: ResponseCache,
+    expiry: SpawnHandle,
+}
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub enum NetworkState {
@@ -40,23 +126,162 @@ pub struct Network {
     address: Option<String>,
     discovery_host: String,
     peers: Vec<String>,
-    nodes: BTreeMap<NodeId, Addr<Node>>,
+    nodes: BTreeMap<NodeId, PeerSender>,
     nodes_connected: Vec<NodeId>,
+    /// Connection status of the registered peers
+    statuses: HashMap<NodeId, NodeStatus>,
+    /// Nodes listed in the config
+    configured: Vec<NodeId>,
+    bootstrap_policy: BootstrapPolicy,
+    bootstrap_delay: Duration,
+    manual_bootstrap: bool,
+    /// Started as a single node cluster, the state follows the raft membership
+    single_node: bool,
+    /// Set by `Bootstrap` to let a manually bootstrapped cluster form
+    bootstrap_requested: bool,
+    election_timeout_min: Duration,
     pub isolated_nodes: Vec<NodeId>,
     nodes_info: HashMap<NodeId, NodeInfo>,
+    /// Versioned copy of `nodes_info` peers sync deltas of
+    membership_log: MembershipLog,
+    peer_versions: HashMap<NodeId, String>,
     server: Option<Addr<server::Server>>,
     state: NetworkState,
     metrics: Option<RaftMetrics>,
-    sessions: BTreeMap<NodeId, Addr<NodeSession>>,
+    progress: ProgressSubscribers,
+    sessions: BTreeMap<NodeId, Recipient<CloseSession>>,
     ring: RingType,
-    raft: Addr<RaftClient>,
+    raft: RaftHandle,
     registry: Arc<RwLock<HandlerRegistry>>,
     info: NodeInfo,
     join_mode: bool,
+    /// Log state of this node, announced to the peers
+    log: SharedLogState,
+    /// Log state the peers announced when joining
+    peer_logs: HashMap<NodeId, LogState>,
+    peer_rate_limit: Option<PeerRateLimit>,
+    peer_version_range: Option<PeerVersions>,
+    /// Peers refused for their version and the version they announced, no connection is
+    /// opened to them until they come back with one in range
+    fenced_peers: BTreeMap<NodeId, String>,
+    constrained_links: Option<ConstrainedLinks>,
+    /// Compress the frames sent to peers with the cluster's dictionary
+    dictionary_wire: bool,
+    dial_timeouts: DialTimeouts,
+    /// Outbound bandwidth of the node, shared with the other network
+    egress: EgressLimiter,
+    placement: Vec<PlacementRule>,
+    /// Failure domains of the commits, on the cluster network when configured
+    domains: Option<DomainTracker>,
+    hedge: Option<HedgePolicy>,
+    next_replica: usize,
+    /// RPC targets without a registered node and when they were last reported
+    unknown_targets: HashMap<NodeId, Instant>,
+    suspended_sessions: HashMap<NodeId, SuspendedSession>,
+    debug_links: DebugLinks,
+    /// Redacts the commands of debugged frames
+    hooks: Hooks,
+    descriptor: Option<DescriptorConfig>,
+    /// How long a leader may fail to reach its quorum before stepping down
+    leader_check: Option<Duration>,
+    /// How long a membership change may take before it's aborted
+    membership_change_timeout: Option<Duration>,
+    /// Membership changes prepared and awaiting confirmation, by token
+    staged_changes: BTreeMap<u64, StagedChange>,
+    next_change_token: u64,
+    /// How long a prepared membership change awaits confirmation
+    membership_confirm_timeout: Duration,
+    /// Membership change in progress, see `transition`
+    transition: TransitionWatch,
+    /// Leadership priority of nodes by cluster address
+    leadership_priority: BTreeMap<String, u32>,
+    /// When leadership was last handed to a node of higher priority
+    priority_transfer: Option<Instant>,
+    /// When raft RPCs to a peer last succeeded
+    peer_contact: HashMap<NodeId, Instant>,
+    /// Last log index each follower is known to hold, only tracked on the leader
+    peer_matched: HashMap<NodeId, u64>,
+    /// Last index each follower acknowledged to this leader and the term of that entry
+    peer_acked: HashMap<NodeId, (u64, u64)>,
+    /// Commit index a leader told this node about, see `LeaderCommit`
+    leader_commit: u64,
+    /// Nodes which left the raft membership and when, their peer state is reported stale
+    departed: HashMap<NodeId, Instant>,
+    metrics_retention: Duration,
+    catch_up: Option<CatchUpConfig>,
+    /// Members which don't campaign for leadership and are routed to last, as applied
+    cordoned: BTreeSet<NodeId>,
+    /// Last log index of the leader as of its last answer to a catch-up request
+    leader_log: Option<(NodeId, u64)>,
+    catch_up_requested: Option<Instant>,
+    leader_since: Option<Instant>,
+    /// Whether this node isolated itself as a leader cut off from its quorum
+    self_isolated: bool,
+    /// Whether the storage faulted under the `StepDown` policy, the node then follows other
+    /// leaders but cuts itself off whenever it leads
+    storage_faulted: bool,
+    history: LeadershipHistory,
+    /// Seals snapshot chunks sent to peers
+    pub(crate) snapshot_sealer: Option<SnapshotSealer>,
+    migration: Option<AddressMigration>,
+    /// Peers isolated by `SetPartition`
+    partitioned: Vec<NodeId>,
+    events: EventLog,
+    traffic: Traffic,
+    metrics_subscribers: Vec<Recipient<NodeMetrics>>,
+    /// Whether this node stepped aside as leader for a `TransferLeadership`
+    transferring: bool,
+    /// Set by `Shutdown`, connections are refused from then on
+    shutting_down: bool,
+    resolver: PeerResolver,
+    /// Idempotency keys of forwarded proposals are `<node>-<started at>-<seq>`
+    started_at: u64,
+    proposal_seq: u64,
+    join_admission: JoinAdmission,
+    memory: MemoryAccount,
+    /// Encrypts the peer connections of this network
+    tls: Option<PeerTls>,
+    /// Takes JSON client connections accepted on the TLS port, see `ServeClients`
+    clients: Option<Recipient<JsonConnect>>,
+    /// Admin API the admin connections accepted on the TLS port are relayed to
+    admin_addr: Option<String>,
+    /// Listener for client and admin connections apart from the peer one, cluster only
+    client_listener: Option<ClientListener>,
+    client_tls: Option<PeerTls>,
+    /// How often the TLS files are checked for changes, see `ReloadTls`
+    tls_reload_check: Option<Duration>,
+    /// Bytes of a blob asked from a peer at once
+    blob_chunk_size: u64,
+    mailboxes: MailboxCapacities,
+    /// Linearizable reads waiting for the next leadership confirmation round
+    read_waiters: Vec<oneshot::Sender<Result<u64, ReadError>>>,
+    /// Whether a leadership confirmation round is in flight
+    read_round: bool,
+    read_stats: ReadIndexStats,
+    discovery: Option<PeerDiscovery>,
+    discovery_interval: Duration,
+    /// Whether the leader adds discovered nodes to the cluster
+    auto_admit: bool,
+    /// Joins awaiting approval and when they were requested, unix time in milliseconds
+    pending_joins: BTreeMap<NodeId, u64>,
+    /// Peer statuses kept for the crash report, marked clean on `Shutdown`
+    crash: Option<CrashState>,
+    /// Stops the process once this network stops fatally
+    exit: Option<ExitHandle>,
+    /// Why this network stopped, when it stopped itself
+    stop_reason: Option<ExitReason>,
+    /// When this node last heard from its leader, kept in the data dir for fast restarts
+    contact: Option<ContactLog>,
+    /// Cumulative statistics of the links to the peers, kept across restarts on the cluster
+    /// network
+    link_stats: LinkStats,
+    election_timeout_max: Duration,
+    /// Tie-breaker lease of a two node cluster, set once arbitration renewed it
+    witness: Option<Witness>,
 }
 
 impl Network {
-    pub fn new(id: NodeId, ring: RingType, registry: Arc<RwLock<HandlerRegistry>>, net_type: NetworkType, raft: Addr<RaftClient>, discovery_host: String, info: NodeInfo) -> Network {
+    pub fn new(id: NodeId, ring: RingType, registry: Arc<RwLock<HandlerRegistry>>, net_type: NetworkType, raft: RaftHandle, discovery_host: String, info: NodeInfo, events: EventLog) -> Network {
         Network {
             id: id,
             address: None,
@@ -65,26 +290,557 @@ impl Network {
             peers: Vec::new(),
             nodes: BTreeMap::new(),
             nodes_connected: Vec::new(),
+            statuses: HashMap::new(),
+            configured: Vec::new(),
+            bootstrap_policy: BootstrapPolicy::default(),
+            bootstrap_delay: Duration::from_secs(5),
+            manual_bootstrap: false,
+            single_node: false,
+            bootstrap_requested: false,
+            election_timeout_min: Duration::from_millis(3000),
             isolated_nodes: Vec::new(),
             nodes_info: HashMap::new(),
+            membership_log: MembershipLog::new(),
+            peer_versions: HashMap::new(),
             server: None,
             state: NetworkState::Initialized,
             metrics: None,
+            progress: ProgressSubscribers::new(),
             sessions: BTreeMap::new(),
             ring: ring,
             raft: raft,
             registry: registry,
             info: info,
             join_mode: false,
+            log: Arc::new(RwLock::new(LogState::default())),
+            peer_logs: HashMap::new(),
+            peer_rate_limit: None,
+            peer_version_range: None,
+            fenced_peers: BTreeMap::new(),
+            constrained_links: None,
+            dictionary_wire: false,
+            dial_timeouts: DialTimeouts::default(),
+            egress: EgressLimiter::default(),
+            placement: Vec::new(),
+            domains: None,
+            hedge: None,
+            next_replica: 0,
+            unknown_targets: HashMap::new(),
+            suspended_sessions: HashMap::new(),
+            debug_links: DebugLinks::default(),
+            hooks: HookChain::new(),
+            descriptor: None,
+            leader_check: None,
+            membership_change_timeout: None,
+            staged_changes: BTreeMap::new(),
+            // tokens of a restarted node don't confirm changes prepared before
+            next_change_token: now_millis(),
+            membership_confirm_timeout: Duration::from_millis(30000),
+            transition: TransitionWatch::default(),
+            leadership_priority: BTreeMap::new(),
+            priority_transfer: None,
+            peer_contact: HashMap::new(),
+            peer_matched: HashMap::new(),
+            peer_acked: HashMap::new(),
+            leader_commit: 0,
+            departed: HashMap::new(),
+            metrics_retention: Duration::from_secs(3600),
+            catch_up: None,
+            cordoned: BTreeSet::new(),
+            leader_log: None,
+            catch_up_requested: None,
+            leader_since: None,
+            self_isolated: false,
+            storage_faulted: false,
+            history: LeadershipHistory::new(),
+            snapshot_sealer: None,
+            migration: None,
+            partitioned: Vec::new(),
+            events: events,
+            traffic: Traffic::default(),
+            metrics_subscribers: Vec::new(),
+            transferring: false,
+            shutting_down: false,
+            resolver: Arc::new(CachingResolver::new(SystemResolver::new(Duration::from_secs(30)))),
+            started_at: now_millis(),
+            proposal_seq: 0,
+            join_admission: JoinAdmission::default(),
+            memory: Arc::new(MemoryCounters::default()),
+            tls: None,
+            clients: None,
+            admin_addr: None,
+            client_listener: None,
+            client_tls: None,
+            tls_reload_check: None,
+            blob_chunk_size: 1024 * 1024,
+            mailboxes: MailboxCapacities::default(),
+            read_waiters: Vec::new(),
+            read_round: false,
+            read_stats: ReadIndexStats::default(),
+            discovery: None,
+            discovery_interval: Duration::from_secs(30),
+            auto_admit: false,
+            pending_joins: BTreeMap::new(),
+            crash: None,
+            exit: None,
+            stop_reason: None,
+            contact: None,
+            link_stats: LinkStats::new(),
+            election_timeout_max: Duration::from_millis(0),
+            witness: None,
         }
     }
 
     pub fn configure(&mut self, config: ConfigSchema) {
+        self.peer_rate_limit = config.peer_rate_limit;
+        self.peer_version_range = config.peer_versions;
+        if config.constrained_links.as_ref().map_or(false, |links| links.compress) && !cfg!(feature = "compression") {
+            warn!("Constrained links are sent uncompressed, raftor was built without the `compression` feature");
+        }
+        self.constrained_links = config.constrained_links;
+        if let Some(ref dict) = config.compression_dictionary {
+            if !cfg!(feature = "dictionary") {
+                warn!("Entries are compressed without a dictionary, raftor was built without the `dictionary` feature");
+            }
+            dictionary::set_level(dict.level);
+            self.dictionary_wire = dict.wire;
+        }
+        self.dial_timeouts = config.dial_timeouts;
+        self.placement = config.placement;
+        if self.net_type == NetworkType::Cluster {
+            self.domains = config.failure_domains.map(DomainTracker::new);
+        }
+        self.hedge = config.hedged_reads.map(HedgePolicy::new);
+        self.descriptor = config.descriptor;
+        self.snapshot_sealer = config.snapshot_transfer.as_ref().map(SnapshotSealer::new);
+        if let Some(path) = config.leadership_history_file {
+            self.history.persist_to(path.into());
+        }
+        self.election_timeout_min = Duration::from_millis(config.raft_timings.election_timeout_min);
+        self.election_timeout_max = Duration::from_millis(config.raft_timings.election_timeout_max);
+        self.metrics_retention = Duration::from_secs(config.metrics_retention_secs);
+        self.membership_change_timeout = config.membership_change_timeout_secs.map(Duration::from_secs);
+        self.membership_confirm_timeout = Duration::from_millis(config.membership_confirm_timeout_ms);
+        self.leadership_priority = config.leadership_priority.clone();
+        if let (NetworkType::Cluster, Some(fast_restart)) = (&self.net_type, &config.fast_restart) {
+            match config.data_dir {
+                Some(ref data_dir) => self.contact = Some(ContactLog::open(Path::new(data_dir), fast_restart)),
+                None => warn!("Fast restarts need a `data_dir` to record the leader contact in"),
+            }
+        }
+        if let (NetworkType::Cluster, Some(ref data_dir)) = (&self.net_type, &config.data_dir) {
+            self.link_stats = LinkStats::open(Path::new(data_dir));
+        }
+        self.leader_check = config
+            .leader_check_timeouts
+            .map(|timeouts| self.election_timeout_min * timeouts as u32);
+        self.bootstrap_policy = config.bootstrap_policy;
+        self.bootstrap_delay = config.bootstrap_delay();
+        self.manual_bootstrap = config.manual_bootstrap;
+        self.single_node = config.single_node;
+        if self.single_node {
+            self.bootstrap_delay = Duration::from_secs(0);
+        }
+        self.join_admission = config.join_admission;
+        self.catch_up = config.catch_up;
+        self.tls = config
+            .tls
+            .as_ref()
+            .map(|tls| PeerTls::from_config(tls).unwrap_or_else(|err| panic!("Peer TLS can't be set up: {}", err)));
+        if self.net_type == NetworkType::Cluster {
+            self.admin_addr = config.admin_addr.clone();
+            self.client_tls = config.client_listener.as_ref().and_then(|listener| listener.tls.as_ref()).map(|tls| {
+                PeerTls::from_config(tls).unwrap_or_else(|err| panic!("Client listener TLS can't be set up: {}", err))
+            });
+            self.client_listener = config.client_listener.clone();
+        }
+        let client_tls = config.client_listener.as_ref().and_then(|listener| listener.tls.as_ref());
+        self.tls_reload_check = config
+            .tls
+            .iter()
+            .chain(client_tls)
+            .filter_map(|tls| tls.reload_check_secs)
+            .min()
+            .map(Duration::from_secs);
+        self.blob_chunk_size = config.snapshot_chunk_size.max(1);
+        self.mailboxes = config.mailboxes.clone();
+        if let Some(ref discovery) = config.discovery {
+            self.discovery = Some(discovery::from_config(&discovery.backend));
+            self.discovery_interval = Duration::from_secs(discovery.interval_secs.max(1));
+            self.auto_admit = discovery.auto_admit;
+        }
+        if let Some(ref data_dir) = config.data_dir {
+            self.log.write().unwrap().has_data = has_persisted_state(Path::new(data_dir));
+        }
         let nodes = config.nodes;
 
         for node in nodes.iter() {
-            let id = generate_node_id(node.cluster_addr.as_str());
+            let id = utils::node_id(node);
             self.nodes_info.insert(id, node.clone());
+            self.configured.push(id);
+        }
+    }
+
+    /// Resolve peer addresses through a resolver shared with the other network.
+    pub fn set_resolver(&mut self, resolver: PeerResolver) {
+        self.resolver = resolver;
+    }
+
+    /// Find peers through another discovery backend, e.g. one asking a service registry.
+    pub fn set_discovery(&mut self, discovery: PeerDiscovery) {
+        self.discovery = Some(discovery);
+    }
+
+    /// Share the hooks of the node, its redactor masks the commands of debugged frames.
+    pub fn set_hooks(&mut self, hooks: Hooks) {
+        self.hooks = hooks;
+    }
+
+    /// Account the pending requests of the peers to the memory of the node.
+    pub fn set_memory(&mut self, memory: MemoryAccount) {
+        self.memory = memory;
+    }
+
+    /// Pace the frames sent to the peers with the egress limit of the node.
+    pub fn set_egress_limiter(&mut self, egress: EgressLimiter) {
+        self.egress = egress;
+    }
+
+    /// Report the peer statuses in crash reports, and mark the node stopped cleanly once
+    /// `Shutdown` completes. Set on the cluster network only.
+    pub fn set_crash_state(&mut self, crash: CrashState) {
+        self.crash = Some(crash);
+    }
+
+    /// Stop the process with a code telling why once this network stops, unless it was shut
+    /// down.
+    pub fn set_exit_handle(&mut self, exit: ExitHandle) {
+        self.exit = Some(exit);
+    }
+
+    /// Connected peers forming the cluster under the bootstrap policy, none while the
+    /// policy waits for more configured peers.
+    fn formation_members(&self) -> Option<Vec<NodeId>> {
+        if self.single_node {
+            info!("Forming a single node cluster with node {}", self.id);
+            return Some(vec![self.id]);
+        }
+
+        let (configured, unknown): (Vec<NodeId>, Vec<NodeId>) = self
+            .nodes_connected
+            .iter()
+            .cloned()
+            .partition(|id| *id == self.id || self.configured.contains(id));
+        let missing = self
+            .configured
+            .iter()
+            .filter(|id| !configured.contains(id))
+            .collect::<Vec<_>>();
+
+        if !missing.is_empty() {
+            warn!("Configured peers {:?} haven't connected", missing);
+        }
+
+        if !unknown.is_empty() {
+            if self.bootstrap_policy == BootstrapPolicy::Open {
+                warn!("Peers {:?} aren't listed under [[nodes]], forming the cluster with them", unknown);
+            } else {
+                warn!(
+                    "Leaving peers {:?} which aren't listed under [[nodes]] out of the cluster (bootstrap_policy {:?})",
+                    unknown, self.bootstrap_policy
+                );
+            }
+        }
+
+        let members = match self.bootstrap_policy {
+            BootstrapPolicy::Open => self.nodes_connected.clone(),
+            BootstrapPolicy::Strict => configured,
+            BootstrapPolicy::QuorumOfConfigured => {
+                let quorum = self.configured.len() / 2 + 1;
+                if configured.len() < quorum {
+                    info!(
+                        "Waiting for {} of the {} configured peers to connect before forming the cluster, {} connected",
+                        quorum, self.configured.len(), configured.len()
+                    );
+                    return None;
+                }
+                configured
+            }
+        };
+
+        info!("Forming the cluster with {:?} (bootstrap_policy {:?})", members, self.bootstrap_policy);
+        Some(members)
+    }
+
+    /// How this node enters the cluster, from its own log state and the ones announced by
+    /// the connected peers.
+    fn formation(&self) -> Formation {
+        if self.log.read().unwrap().has_data {
+            info!("Node {} recovered persisted state, restarting without bootstrapping", self.id);
+            return Formation::Restart;
+        }
+
+        let with_data = self
+            .peer_logs
+            .iter()
+            .filter(|(id, log)| log.has_data && self.nodes_connected.contains(id))
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+
+        if !with_data.is_empty() {
+            info!("Peers {:?} already have data, joining the existing cluster", with_data);
+            return Formation::Join;
+        }
+
+        if self.join_mode {
+            Formation::Join
+        } else {
+            Formation::Bootstrap
+        }
+    }
+
+    /// A raft RPC sent to the peer at `sent_at` succeeded. Answers may arrive out of order, the
+    /// latest request answered counts.
+    pub(crate) fn peer_reached(&mut self, id: NodeId, sent_at: Instant) {
+        let contact = self.peer_contact.entry(id).or_insert(sent_at);
+        *contact = (*contact).max(sent_at);
+    }
+
+    /// The follower answered it holds the log through `index`.
+    /// A follower acknowledged the entries up to `index`, of term `entry_term`, appended by
+    /// this leader in its current term.
+    pub(crate) fn peer_acked(&mut self, id: NodeId, index: u64, entry_term: u64) {
+        self.peer_acked.insert(id, (index, entry_term));
+        self.publish_progress();
+    }
+
+    /// Publish the progress of this node, with the commit index the leader computes or the
+    /// followers were told about.
+    fn publish_progress(&mut self) {
+        let metrics = match self.metrics {
+            Some(ref metrics) => metrics,
+            None => return,
+        };
+        let mut progress = ClusterProgress::from(metrics);
+        let commit_index = match metrics.current_leader == Some(self.id) {
+            true => self.commit_index(),
+            false => Some(self.leader_commit),
+        };
+        progress.commit_index = progress.commit_index.max(commit_index.unwrap_or(0));
+
+        self.progress.publish(progress);
+    }
+
+    /// Commit index of this node, known while it's the leader, see `progress::quorum_commit`.
+    pub(crate) fn commit_index(&self) -> Option<u64> {
+        let metrics = self.metrics.as_ref().filter(|metrics| metrics.current_leader == Some(self.id))?;
+        let configs = voter_configs(&metrics.membership_config);
+        let acked = &self.peer_acked;
+
+        progress::quorum_commit(&configs, self.id, metrics.last_log_index, metrics.current_term, |id| acked.get(&id).cloned())
+    }
+
+    pub(crate) fn peer_matched(&mut self, id: NodeId, index: u64) {
+        self.peer_matched.insert(id, index);
+        if let Some(ref mut witness) = self.witness {
+            witness.matched(index);
+        }
+        self.check_commit_domains();
+    }
+
+    /// Count the failure domains holding the entries the acknowledgements just committed.
+    fn check_commit_domains(&mut self) {
+        let voters = match self.metrics {
+            Some(ref metrics) if metrics.current_leader == Some(self.id) => metrics.membership_config.members.clone(),
+            _ => return,
+        };
+        let (id, own_info, nodes_info) = (self.id, &self.info, &self.nodes_info);
+        let info = |node: NodeId| if node == id { Some(own_info.clone()) } else { nodes_info.get(&node).cloned() };
+
+        let change = match self.domains {
+            Some(ref mut tracker) => tracker.observe(id, &voters, &self.peer_matched, &info),
+            None => return,
+        };
+        match change {
+            Some(DomainChange::SingleDomain { domain, voter_domains }) => {
+                let message = format!("commits are held in the single failure domain {} out of {}", domain, voter_domains);
+                warn!("Leader {}: {}", self.id, message);
+                self.events.alert(message);
+            }
+            Some(DomainChange::Spread { domains }) => {
+                info!("Leader {}: commits span {} failure domains again", self.id, domains);
+            }
+            None => (),
+        }
+    }
+
+    /// Ask the leader to catch this follower up once its log is `min_lag` entries behind the
+    /// leader's, as announced when the leader joined or answered the last request. A node
+    /// which restarted and hasn't heard from a leader yet asks every member, the leader among
+    /// them answers.
+    fn check_catch_up(&mut self, ctx: &mut Context<Self>) {
+        let config = match self.catch_up {
+            Some(ref config) => config.clone(),
+            None => return,
+        };
+
+        if self.catch_up_requested.map(|at| clock::elapsed(at) < Duration::from_secs(config.retry_secs)).unwrap_or(false) {
+            return;
+        }
+
+        let last_log_index = self.log.read().unwrap().last_log_index;
+        let leader = self.metrics.as_ref().and_then(|metrics| metrics.current_leader);
+        let targets = match leader {
+            Some(leader) if leader == self.id => return,
+            Some(leader) => {
+                let announced = self.peer_logs.get(&leader).map(|log| log.last_log_index);
+                let answered = self.leader_log.filter(|(id, _)| *id == leader).map(|(_, index)| index);
+                match announced.max(answered) {
+                    Some(index) if index.saturating_sub(last_log_index) >= config.min_lag => vec![leader],
+                    _ => return,
+                }
+            }
+            None if self.log.read().unwrap().has_data => self.raft_members().into_iter().filter(|id| *id != self.id).collect(),
+            None => return,
+        };
+
+        let nodes = targets
+            .into_iter()
+            .filter_map(|id| self.nodes.get(&id).map(|node| (id, node.clone())))
+            .collect::<Vec<_>>();
+        if nodes.is_empty() {
+            return;
+        }
+
+        info!("Node {} at index {} asks {:?} to catch it up", self.id, last_log_index, nodes.iter().map(|(id, _)| *id).collect::<Vec<_>>());
+        self.catch_up_requested = Some(clock::now());
+
+        for (id, node) in nodes {
+            let request = RequestCatchUp {
+                node: self.id,
+                last_log_index: last_log_index,
+            };
+
+            fut::wrap_future::<_, Self>(node.send(request))
+                .map_err(|_, _, _| ())
+                .and_then(|res, _, _| fut::result(res))
+                .map(move |caught_up, act, _| {
+                    match caught_up.snapshot_index {
+                        Some(index) => info!("Leader {} snapshotted through index {} to catch node {} up", id, index, act.id),
+                        None => debug!("Leader {} is catching node {} up to index {}", id, act.id, caught_up.last_log_index),
+                    }
+                    act.leader_log = Some((id, caught_up.last_log_index));
+                })
+                .spawn(ctx);
+        }
+    }
+
+    /// Step down when leading without reaching a quorum of followers for longer than the
+    /// configured check period: stop sending raft RPCs so the others elect a new leader and
+    /// refuse proposals until a newer term demotes this node.
+    fn check_leader_quorum(&mut self, metrics: &RaftMetrics) {
+        let period = match self.leader_check {
+            Some(period) => period,
+            None => return,
+        };
+
+        if metrics.current_leader != Some(self.id) {
+            self.leader_since = None;
+
+            if self.self_isolated {
+                info!("Node {} is no longer leader, rejoining raft", self.id);
+                self.self_isolated = false;
+                self.isolated_nodes.retain(|id| *id != self.id);
+                let _ = self.raft.isolation.do_send(LeaderIsolated(false));
+            }
+            return;
+        }
+
+        // already stepped down, by this check or after a storage fault
+        if self.isolated_nodes.contains(&self.id) {
+            return;
+        }
+
+        let leader_since = *self.leader_since.get_or_insert_with(clock::now);
+        let members = &metrics.membership_config.members;
+        let reached = members
+            .iter()
+            .filter(|id| {
+                **id == self.id || {
+                    let contact = self.peer_contact.get(id).cloned().unwrap_or(leader_since);
+                    clock::elapsed(contact.max(leader_since)) < period
+                }
+            })
+            .count();
+
+        if reached * 2 <= members.len() {
+            error!(
+                "Leader {} reached {} of {} members in the last {:?}, stepping down",
+                self.id, reached, members.len(), period
+            );
+            self.self_isolated = true;
+            self.isolated_nodes.push(self.id);
+            self.history.note("leader lost its quorum");
+            self.events.alert(format!(
+                "leader {} reached {} of {} members in the last {:?}, stepped down",
+                self.id, reached, members.len(), period
+            ));
+            let _ = self.raft.isolation.do_send(LeaderIsolated(true));
+        }
+    }
+
+    /// Keep a node whose storage faulted from leading: cut it off while it leads so the others
+    /// elect another leader, and lift the cut once one does. An isolated follower would keep
+    /// campaigning on ever higher terms, which would only depose the new leader.
+    fn check_step_down(&mut self, metrics: &RaftMetrics) {
+        if !self.storage_faulted {
+            return;
+        }
+
+        let isolated = self.isolated_nodes.contains(&self.id);
+        match metrics.current_leader {
+            Some(leader) if leader == self.id && !isolated => {
+                println!("Node {} stepping down from raft", self.id);
+                self.isolated_nodes.push(self.id);
+                self.history.note("stepped down after a storage fault");
+            }
+            Some(leader) if leader != self.id && isolated && !self.self_isolated => {
+                info!("Node {} follows leader {} after stepping down, rejoining raft", self.id, leader);
+                self.isolated_nodes.retain(|id| *id != self.id);
+            }
+            _ => (),
+        }
+    }
+
+    /// Deal with a membership change which took longer than the configured timeout, see
+    /// `transition`: a change still syncing non-voters is aborted by stepping aside, one in
+    /// joint consensus is reported.
+    fn check_membership_transition(&mut self, metrics: &RaftMetrics, ctx: &mut Context<Self>) {
+        let timeout = match self.membership_change_timeout {
+            Some(timeout) => timeout,
+            None => return,
+        };
+        let leading = metrics.current_leader == Some(self.id);
+
+        match self.transition.observe(&metrics.membership_config, leading, clock::now(), timeout) {
+            Some(Stalled::Abort { members, abandoned }) => {
+                warn!("Membership change stalled for {:?} syncing {:?}, stepping aside to abort it", timeout, abandoned);
+                self.history.note("membership change aborted");
+                self.events.record(EventKind::MembershipAborted {
+                    members: members.clone(),
+                    abandoned: abandoned.clone(),
+                });
+                self.events.alert(format!("membership change stalled for {:?}, aborted keeping {:?}", timeout, members));
+                ctx.notify(TransferLeadership);
+            }
+            Some(Stalled::Joint { members }) => {
+                warn!("Membership change stalled for {:?} in joint consensus with {:?}", timeout, members);
+                self.events.alert(format!(
+                    "membership change stalled for {:?} in joint consensus, it completes once {:?} form a quorum",
+                    timeout, members
+                ));
+            }
+            None => (),
         }
     }
 
@@ -100,21 +856,77 @@ impl Network {
             NetworkType::Cluster => info.cluster_addr.clone(),
         };
 
-        if peer_addr == *network_address {
+        if peer_addr == *network_address || self.fenced_peers.contains_key(&id) {
             return ();
         }
 
         self.restore_node(id); // restore node if needed
 
+        self.unknown_targets.remove(&id);
+
         if !self.nodes.contains_key(&id) {
-            let node = Node::new(id, local_id, peer_addr, addr, net_type, self.info.clone()).start();
-            self.nodes.insert(id, node);
+            let node = Node::new(id, local_id, peer_addr, addr, net_type, self.info.clone(), self.link_debug(Some(id)), self.log.clone(), self.resolver.clone(), self.memory.clone(), self.tls.clone())
+                .with_link(self.link_tuning(&info))
+                .with_dial_timeouts(&self.dial_timeouts)
+                .with_egress(self.egress.clone())
+                .with_mailbox_capacity(self.mailboxes.node)
+                .start();
+            self.nodes.insert(id, node.into());
+            self.set_status(id, NodeStatus::Reconnecting);
+        }
+    }
+
+    /// Update the connection status of a peer, status changes on the cluster network are
+    /// recorded as events.
+    fn set_status(&mut self, id: NodeId, status: NodeStatus) {
+        let previous = self.statuses.insert(id, status.clone());
+        if let Some(ref crash) = self.crash {
+            crash.record_peer(id, status.clone());
+        }
+
+        if self.net_type == NetworkType::Cluster && previous.as_ref() != Some(&status) {
+            self.events.record(EventKind::PeerStatus { peer: id, status: status });
         }
     }
 
     /// get a node from the network by its id
     pub fn get_node(&self, id: NodeId) -> Option<&Addr<Node>> {
-        self.nodes.get(&id)
+        self.nodes.get(&id).and_then(PeerSender::node)
+    }
+
+    /// Register the connection to a peer, e.g. a double standing in for its node actor.
+    pub fn set_peer(&mut self, id: NodeId, peer: PeerSender) {
+        self.nodes.insert(id, peer);
+    }
+
+    /// Register the session of a peer, closed when the network stops.
+    pub fn set_session(&mut self, id: NodeId, session: Recipient<CloseSession>) {
+        self.sessions.insert(id, session);
+    }
+
+    /// Connection status of a peer, unknown peers are disconnected.
+    pub fn node_status(&self, id: NodeId) -> NodeStatus {
+        self.statuses.get(&id).cloned().unwrap_or(NodeStatus::Disconnected)
+    }
+
+    /// Handle an RPC to a node which isn't registered (yet), e.g. while a membership change
+    /// races with discovery. The node is connected when its address is known, otherwise the
+    /// target is remembered so repeated RPCs don't flood the logs.
+    pub(crate) fn unknown_target(&mut self, id: NodeId, ctx: &mut Context<Self>) {
+        if let Some(info) = self.nodes_info.get(&id).cloned() {
+            debug!("Connecting to node {} found in the address book", id);
+            self.register_node(id, &info, ctx.address());
+            return;
+        }
+
+        let now = clock::now();
+        match self.unknown_targets.get(&id) {
+            Some(seen) if now.duration_since(*seen) < UNKNOWN_TARGET_TTL => (),
+            _ => {
+                warn!("Dropping RPC: {}", RaftorError::UnknownPeer(id));
+                self.unknown_targets.insert(id, now);
+            }
+        }
     }
 
     pub fn bind(&mut self, address: &str) {
@@ -147,10 +959,14 @@ impl Handler<NodeDisconnect> for Network {
     type Result = ();
 
     fn handle(&mut self, msg: NodeDisconnect, ctx: &mut Context<Self>) {
+        crate::audit_message!(NodeDisconnect);
         let id = msg.0;
         self.isolated_nodes.push(id);
         self.nodes_info.remove(&id);
         self.nodes.remove(&id);
+        self.sessions.remove(&id);
+        self.nodes_connected.retain(|node| *node != id);
+        self.set_status(id, NodeStatus::Disconnected);
 
         if self.net_type != NetworkType::Cluster {
             return ();
@@ -163,7 +979,7 @@ impl Handler<NodeDisconnect> for Network {
                 let leader = res.unwrap();
 
                 if leader == id {
-                    fut::wrap_future::<_, Self>(Delay::new(Instant::now() + Duration::from_secs(1)))
+                    fut::wrap_future::<_, Self>(clock::delay(Duration::from_secs(1)))
                         .map_err(|_, _, _| ())
                         .and_then(|_, act, ctx| {
                             ctx.notify(msg);
@@ -174,7 +990,7 @@ impl Handler<NodeDisconnect> for Network {
                 }
 
                 if leader == act.id {
-                    Arbiter::spawn(act.raft.send(RemoveNode(id))
+                    Arbiter::spawn(act.raft.removals.send(RemoveNode(id))
                                    .map_err(|_| ())
                                    .and_then(|res| {
                                        futures::future::ok(())
@@ -186,15 +1002,215 @@ impl Handler<NodeDisconnect> for Network {
     }
 }
 
+/// Switch the sessions with a peer to verbose JSON and log their frames, or back to normal.
 #[derive(Message)]
-pub struct Handshake(pub NodeId, pub NodeInfo);
+pub struct DebugPeer(pub NodeId, pub bool);
 
-impl Handler<Handshake> for Network {
+impl Handler<DebugPeer> for Network {
+    type Result = ();
+
+    fn handle(&mut self, msg: DebugPeer, _ctx: &mut Context<Self>) {
+        crate::audit_message!(DebugPeer);
+        let mut links = self.debug_links.write().unwrap();
+
+        if msg.1 {
+            info!("Debugging the sessions with node {}", msg.0);
+            links.insert(msg.0);
+        } else {
+            links.remove(&msg.0);
+        }
+    }
+}
+
+/// A peer session closed, the peer is disconnected unless it resumes the session in time.
+#[derive(Message)]
+pub struct SessionClosed {
+    pub id: NodeId,
+    pub token: Option<String>,
+    pub throttle: Option<InboundThrottle>,
+    pub responses: ResponseCache,
+}
+
+impl Handler<SessionClosed> for Network {
     type Result = ();
 
-    fn handle(&mut self, msg: Handshake, ctx: &mut Context<Self>) {
+    fn handle(&mut self, msg: SessionClosed, ctx: &mut Context<Self>) {
+        crate::audit_message!(SessionClosed);
+        let id = msg.id;
+
+        let token = match msg.token {
+            Some(token) => token,
+            None => return ctx.notify(NodeDisconnect(id)),
+        };
+
+        let expiry = ctx.run_later(RESUME_GRACE, move |act, ctx| {
+            act.suspended_sessions.remove(&id);
+            ctx.notify(NodeDisconnect(id));
+        });
+
+        self.suspended_sessions.insert(id, SuspendedSession {
+            token: token,
+            throttle: msg.throttle,
+            responses: msg.responses,
+            expiry: expiry,
+        });
+    }
+}
+
+/// Session state handed to a peer session once joined.
+pub struct Joined {
+    pub token: String,
+    /// Inbound credits of the resumed session, if it was resumed
+    pub throttle: Option<InboundThrottle>,
+    /// Recent answers of the resumed session
+    pub responses: Option<ResponseCache>,
+}
+
+/// A peer joined, fails when it claims the id of another node.
+///
+/// Handshake(node_id, info, version, resumption token, log state)
+pub struct Handshake(pub NodeId, pub NodeInfo, pub String, pub Option<String>, pub LogState);
+
+impl Message for Handshake {
+    type Result = Result<Joined, ()>;
+}
+
+impl Handler<Handshake> for Network {
+    type Result = Result<Joined, ()>;
+
+    fn handle(&mut self, msg: Handshake, ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(Handshake);
+        let known = if msg.0 == self.id {
+            Some(&self.info)
+        } else {
+            self.nodes_info.get(&msg.0)
+        };
+
+        if let Some(known) = known {
+            if known.cluster_addr != msg.1.cluster_addr {
+                error!(
+                    "CRITICAL: node id collision, {} claims id {} already used by {} (local node {} at {}), \
+                     assign distinct ids or change the node id strategy",
+                    msg.1.cluster_addr, msg.0, known.cluster_addr, self.id, self.info.cluster_addr
+                );
+                return Err(());
+            }
+        }
+
+        if let Some(reason) = self.peer_version_range.as_ref().and_then(|range| range.refusal(&msg.2)) {
+            if self.fenced_peers.insert(msg.0, msg.2.clone()).is_none() {
+                error!("Refusing node {} at {}: {}", msg.0, msg.1.cluster_addr, reason);
+            }
+            // drop our own connection to it too
+            if let Some(node) = self.nodes.remove(&msg.0) {
+                node.leave();
+            }
+            self.sessions.remove(&msg.0);
+            return Err(());
+        }
+        if self.fenced_peers.remove(&msg.0).is_some() {
+            info!("Node {} is back with version {}", msg.0, msg.2);
+        }
+
         self.nodes_info.insert(msg.0, msg.1.clone());
+        self.peer_versions.insert(msg.0, msg.2.clone());
+        self.peer_logs.insert(msg.0, msg.4);
         self.register_node(msg.0, &msg.1, ctx.address().clone());
+
+        // resume the previous session of the peer if it presents its token in time
+        let resumable = match (self.suspended_sessions.get(&msg.0), msg.3) {
+            (Some(suspended), Some(ref token)) => fixed_time_eq(suspended.token.as_bytes(), token.as_bytes()),
+            _ => false,
+        };
+
+        if resumable {
+            let suspended = self.suspended_sessions.remove(&msg.0).unwrap();
+            ctx.cancel_future(suspended.expiry);
+            debug!("Node {} resumed its session", msg.0);
+
+            return Ok(Joined {
+                token: suspended.token,
+                throttle: suspended.throttle,
+                responses: Some(suspended.responses),
+            });
+        }
+
+        // a new session replaces a suspended one, the peer is connected again
+        if let Some(suspended) = self.suspended_sessions.remove(&msg.0) {
+            ctx.cancel_future(suspended.expiry);
+        }
+
+        Ok(Joined {
+            token: utils::random_token(),
+            throttle: None,
+            responses: None,
+        })
+    }
+}
+
+/// Drop raft traffic with the given peers, replacing the previous partition, an empty list
+/// heals it.
+#[derive(Message)]
+pub struct SetPartition(pub Vec<NodeId>);
+
+impl Handler<SetPartition> for Network {
+    type Result = ();
+
+    fn handle(&mut self, msg: SetPartition, _ctx: &mut Context<Self>) {
+        crate::audit_message!(SetPartition);
+        let (local_id, partitioned) = (self.id, &self.partitioned);
+        self.isolated_nodes.retain(|id| !partitioned.contains(id));
+        self.isolated_nodes.extend(msg.0.iter().filter(|id| **id != local_id));
+        self.partitioned = msg.0;
+    }
+}
+
+/// Outcome of a tie-breaker lease renewal, resolves to whether the lease must be kept solo
+/// because entries only this node holds were acknowledged in place of its peer.
+pub struct SetLease(pub bool);
+
+impl Message for SetLease {
+    type Result = bool;
+}
+
+impl Handler<SetLease> for Network {
+    type Result = bool;
+
+    fn handle(&mut self, msg: SetLease, _ctx: &mut Context<Self>) -> bool {
+        crate::audit_message!(SetLease);
+        let witness = self.witness.get_or_insert_with(Witness::default);
+
+        if witness.set_held(msg.0) {
+            if msg.0 {
+                info!("Node {} acquired the tie-breaker lease", self.id);
+                self.history.note("acquired the tie-breaker lease");
+            } else {
+                info!("Node {} lost the tie-breaker lease, stepping down", self.id);
+                self.history.note("lost the tie-breaker lease");
+            }
+        }
+        witness.solo()
+    }
+}
+
+impl Network {
+    /// Whether raft RPCs from `source` to `target` are dropped: either node is isolated, or
+    /// this node sends them without holding the tie-breaker lease.
+    pub(crate) fn cut_off(&self, source: NodeId, target: NodeId) -> bool {
+        self.isolated_nodes.contains(&target)
+            || self.isolated_nodes.contains(&source)
+            || (source == self.id && self.witness.as_ref().map(|witness| !witness.held()).unwrap_or(false))
+    }
+
+    /// The tie-breaker lease, when it votes in place of `target`.
+    pub(crate) fn witness_for(&mut self, target: NodeId) -> Option<&mut Witness> {
+        let members = match self.metrics {
+            Some(ref metrics) => metrics.membership_config.members.clone(),
+            None => return None,
+        };
+        let (id, connected) = (self.id, self.nodes_connected.contains(&target));
+
+        self.witness.as_mut().filter(|witness| witness.answers(id, &members, target, connected))
     }
 }
 
@@ -205,11 +1221,36 @@ impl Handler<RestoreNode> for Network {
     type Result = ();
 
     fn handle(&mut self, msg: RestoreNode, ctx: &mut Context<Self>) {
+        crate::audit_message!(RestoreNode);
         let id = msg.0;
         self.restore_node(id);
     }
 }
 
+/// Cut the local node off from outgoing raft traffic after a storage fault.
+///
+/// A leader can no longer send heartbeats and a follower can no longer campaign, so the rest
+/// of the cluster elects a new leader while this node keeps receiving entries. The cut is
+/// lifted once another node leads and put back whenever this one does, see
+/// `check_step_down`.
+#[derive(Message)]
+pub struct StepDown;
+
+impl Handler<StepDown> for Network {
+    type Result = ();
+
+    fn handle(&mut self, _: StepDown, _ctx: &mut Context<Self>) {
+        crate::audit_message!(StepDown);
+        self.transferring = false;
+        self.storage_faulted = true;
+        if !self.isolated_nodes.contains(&self.id) {
+            println!("Node {} stepping down from raft", self.id);
+            self.isolated_nodes.push(self.id);
+            self.history.note("stepped down after a storage fault");
+        }
+    }
+}
+
 pub struct GetClusterState;
 
 impl Message for GetClusterState {
@@ -220,6 +1261,7 @@ impl Handler<GetClusterState> for Network {
     type Result = Result<NetworkState, ()>;
 
     fn handle(&mut self, _: GetClusterState, ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(GetClusterState);
         Ok(self.state.clone())
     }
 }
@@ -231,6 +1273,7 @@ impl Handler<SetClusterState> for Network {
     type Result = ();
 
     fn handle(&mut self, msg: SetClusterState, ctx: &mut Context<Self>) {
+        crate::audit_message!(SetClusterState);
         self.state = msg.0;
     }
 }
@@ -245,33 +1288,96 @@ impl Handler<GetNodes> for Network {
     type Result = Result<HashMap<NodeId, NodeInfo>, ()>;
 
     fn handle(&mut self, _: GetNodes, ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(GetNodes);
         let nodes = self.nodes_info.clone();
         Ok(nodes)
     }
 }
 
+/// Changes to the nodes this node knows since the cursor a peer last saw, see
+/// `GossipDiscovery`.
+pub struct GetNodesDelta(pub Option<DeltaCursor>);
+
+impl Message for GetNodesDelta {
+    type Result = MembershipDelta;
+}
+
+impl Handler<GetNodesDelta> for Network {
+    type Result = MessageResult<GetNodesDelta>;
+
+    fn handle(&mut self, msg: GetNodesDelta, _: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(GetNodesDelta);
+        self.membership_log.sync(&self.nodes_info);
+        MessageResult(self.membership_log.delta(msg.0))
+    }
+}
+
+/// How a node enters the cluster.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Formation {
+    /// No node has data yet, initialize raft with the discovered members
+    Bootstrap,
+    /// The cluster already exists, wait to be added by its leader
+    Join,
+    /// This node recovered its log, raft resumes from it without initializing
+    Restart,
+}
+
 pub struct DiscoverNodes;
 
 impl Message for DiscoverNodes {
-    type Result = Result<(Vec<NodeId>, bool), ()>;
+    type Result = Result<(Vec<NodeId>, Formation), ()>;
 }
 
 impl Handler<DiscoverNodes> for Network {
-    type Result = ResponseActFuture<Self, (Vec<NodeId>, bool), ()>;
+    type Result = ResponseActFuture<Self, (Vec<NodeId>, Formation), ()>;
 
     fn handle(&mut self, _: DiscoverNodes, _: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(DiscoverNodes);
         Box::new(
-            fut::wrap_future::<_, Self>(Delay::new(Instant::now() + Duration::from_secs(5)))
+            fut::wrap_future::<_, Self>(clock::delay(self.bootstrap_delay))
                 .map_err(|_, _, _| ())
-                .and_then(|_, act: &mut Network, _| fut::result(Ok((act.nodes_connected.clone(), act.join_mode)))),
+                .and_then(|_, act: &mut Network, ctx| {
+                    let ready = act.formation_members().and_then(|members| match act.formation() {
+                        Formation::Bootstrap if act.manual_bootstrap && !act.single_node && !act.bootstrap_requested => {
+                            info!("Waiting for PUT /cluster/bootstrap to bootstrap the cluster with {:?}", members);
+                            None
+                        }
+                        formation => Some((members, formation)),
+                    });
+
+                    match ready {
+                        Some(res) => fut::Either::A(fut::ok(res)),
+                        None => fut::Either::B(
+                            fut::wrap_future::<_, Self>(ctx.address().send(DiscoverNodes))
+                                .map_err(|_, _, _| ())
+                                .and_then(|res, _, _| fut::result(res)),
+                        ),
+                    }
+                }),
         )
     }
 }
 
+/// Let a node configured with `manual_bootstrap` bootstrap the cluster with the peers
+/// connected by then.
+#[derive(Message)]
+pub struct Bootstrap;
+
+impl Handler<Bootstrap> for Network {
+    type Result = ();
+
+    fn handle(&mut self, _: Bootstrap, _ctx: &mut Context<Self>) {
+        crate::audit_message!(Bootstrap);
+        self.bootstrap_requested = true;
+    }
+}
+
 impl Actor for Network {
     type Context = Context<Self>;
 
     fn started(&mut self, ctx: &mut Context<Self>) {
+        ctx.set_mailbox_capacity(self.mailboxes.network);
         let network_address = self.address.as_ref().unwrap().clone();
 
         let cluster_state_route = format!("http://{}/cluster/state", self.discovery_host.as_str());
@@ -281,12 +1387,28 @@ impl Actor for Network {
         println!("Local node id: {}", self.id);
 
         self.listen(ctx);
+        if self.stop_reason.is_some() {
+            return;
+        }
         self.nodes_connected.push(self.id);
 
-        let mut client = Client::default();
+        if self.discovery.is_some() {
+            self.discover_peers(ctx);
+            ctx.run_interval(self.discovery_interval, |act, ctx| act.discover_peers(ctx));
+        }
 
-        fut::wrap_future::<_, Self>(client.get(cluster_state_route).send())
-            .map_err(|_, _, _| ())
+        if let (NetworkType::Cluster, Some(catch_up)) = (&self.net_type, &self.catch_up) {
+            ctx.run_interval(Duration::from_secs(catch_up.check_secs.max(1)), |act, ctx| act.check_catch_up(ctx));
+        }
+
+        if let Some(interval) = self.tls_reload_check {
+            ctx.run_interval(interval, |act, ctx| act.check_tls_files(ctx));
+        }
+
+        let mut client = Client::default();
+
+        fut::wrap_future::<_, Self>(client.get(cluster_state_route).send())
+            .map_err(|_, _, _| ())
             .and_then(move |res, _, _| {
                 let mut res = res;
                 fut::wrap_future::<_, Self>(res.body()).then(move |resp, _, _| {
@@ -294,7 +1416,7 @@ impl Actor for Network {
                         let state = serde_json::from_slice::<Result<NetworkState, ()>>(&body)
                             .unwrap().unwrap();
 
-                        if state == NetworkState::Cluster {
+                        if state != NetworkState::Initialized {
                             // TODO:: Send register command to cluster
                             return fut::Either::A(fut::wrap_future::<_, Self>(client.get(cluster_nodes_route).send())
                                                   .map_err(|e, _, _| println!("HTTP Cluster Error {:?}", e))
@@ -322,8 +1444,11 @@ impl Actor for Network {
                     fut::Either::B(fut::ok(()))
                 })
             })
-            .map_err(|e, _, _| println!("HTTP Cluster Error {:?}", e))
-            .and_then(move |_, act, ctx| {
+            .then(move |res, act, ctx| {
+                if let Err(e) = res {
+                    println!("HTTP Cluster Error {:?}", e);
+                }
+
                 let nodes = act.nodes_info.clone();
 
                 for (id, info) in &nodes {
@@ -334,17 +1459,112 @@ impl Actor for Network {
             })
             .spawn(ctx);
     }
+
+    fn stopped(&mut self, _: &mut Context<Self>) {
+        let reason = match self.stop_reason {
+            _ if self.shutting_down => ExitReason::Shutdown,
+            Some(reason) => reason,
+            None => ExitReason::NetworkStopped,
+        };
+
+        self.link_stats.persist(&self.traffic, &self.nodes_connected);
+        if let Some(ref exit) = self.exit {
+            exit.exit(reason);
+        }
+    }
+}
+
+impl Network {
+    /// Connect to the nodes found by the discovery backend.
+    fn discover_peers(&mut self, ctx: &mut Context<Self>) {
+        let discovery = match self.discovery {
+            Some(ref discovery) => discovery.clone(),
+            None => return,
+        };
+
+        fut::wrap_future::<_, Self>(discovery.discover())
+            .then(|res, act, ctx| {
+                match res {
+                    Ok(nodes) => {
+                        for info in nodes {
+                            act.discovered(info, ctx);
+                        }
+                    }
+                    Err(err) => warn!("Peer discovery failed: {}", err),
+                }
+                fut::ok(())
+            })
+            .spawn(ctx);
+    }
+
+    fn discovered(&mut self, info: NodeInfo, ctx: &mut Context<Self>) {
+        let id = utils::node_id(&info);
+        if id == self.id || info.cluster_addr == self.info.cluster_addr {
+            return;
+        }
+
+        if !self.nodes_info.contains_key(&id) {
+            info!("Discovered node {} at {}", id, info.cluster_addr);
+            self.nodes_info.insert(id, info.clone());
+            self.register_node(id, &info, ctx.address());
+        }
+
+        // the leader adds discovered nodes like nodes joining through `/cluster/join`
+        let leader = self.metrics.as_ref().map(|metrics| metrics.current_leader == Some(self.id)).unwrap_or(false);
+        if self.auto_admit
+            && leader
+            && self.net_type == NetworkType::Cluster
+            && !self.raft_members().contains(&id)
+            && !self.pending_joins.contains_key(&id)
+        {
+            ctx.address().do_send(RequestJoin(id));
+        }
+    }
+}
+
+/// Listener a connection was accepted on.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Listener {
+    /// The cluster or app address
+    Peer,
+    /// `client_listener`
+    Client,
 }
 
 #[derive(Message)]
-struct NodeConnect(TcpStream);
+struct NodeConnect(TcpStream, Listener);
 
 impl Network {
     fn listen(&mut self, ctx: &mut Context<Self>) {
         let server_addr = self.address.as_ref().unwrap().as_str().parse().unwrap();
-        let listener = TcpListener::bind(&server_addr).unwrap();
+        let listener = match TcpListener::bind(&server_addr) {
+            Ok(listener) => listener,
+            Err(err) => {
+                error!("Node {} can't listen on {}: {}", self.id, server_addr, err);
+                self.stop_reason = Some(ExitReason::ListenFailed);
+                ctx.stop();
+                return;
+            }
+        };
+
+        ctx.add_message_stream(listener.incoming().map_err(|_| ()).map(|stream| NodeConnect(stream, Listener::Peer)));
+
+        let bind = match self.client_listener {
+            Some(ref client_listener) => client_listener.bind.clone(),
+            None => return,
+        };
+        let listener = match bind.parse().map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err)).and_then(|addr| TcpListener::bind(&addr)) {
+            Ok(listener) => listener,
+            Err(err) => {
+                error!("Node {} can't listen for clients on {}: {}", self.id, bind, err);
+                self.stop_reason = Some(ExitReason::ListenFailed);
+                ctx.stop();
+                return;
+            }
+        };
 
-        ctx.add_message_stream(listener.incoming().map_err(|_| ()).map(NodeConnect));
+        info!("Node {} takes client connections on {}", self.id, bind);
+        ctx.add_message_stream(listener.incoming().map_err(|_| ()).map(|stream| NodeConnect(stream, Listener::Client)));
     }
 }
 
@@ -352,23 +1572,152 @@ impl Handler<NodeConnect> for Network {
     type Result = ();
 
     fn handle(&mut self, msg: NodeConnect, ctx: &mut Context<Self>) {
+        crate::audit_message!(NodeConnect);
+        if self.shutting_down {
+            return;
+        }
+
+        let (stream, listener) = (msg.0, msg.1);
+        let peer = stream.peer_addr().map(|addr| addr.to_string()).unwrap_or_default();
+        let tls = match listener {
+            Listener::Peer => &self.tls,
+            Listener::Client => &self.client_tls,
+        };
+
+        // sessions start once the peer completed the handshake, peers without a valid
+        // certificate are dropped here
+        fut::wrap_future::<_, Self>(tls::accept(tls, stream))
+            .then(move |res, act, ctx| {
+                match res {
+                    Ok(stream) => act.dispatch_connection(stream, peer, listener, ctx),
+                    Err(err) => warn!("Dropping connection from {}, TLS handshake failed: {}", peer, err),
+                }
+                fut::ok(())
+            })
+            .spawn(ctx);
+    }
+}
+
+/// Take the JSON client connections the cluster network accepts on its TLS port.
+#[derive(Message)]
+pub struct ServeClients(pub Recipient<JsonConnect>);
+
+impl Handler<ServeClients> for Network {
+    type Result = ();
+
+    fn handle(&mut self, msg: ServeClients, _ctx: &mut Context<Self>) {
+        crate::audit_message!(ServeClients);
+        self.clients = Some(msg.0);
+    }
+}
+
+impl Network {
+    fn link_debug(&self, peer: Option<NodeId>) -> LinkDebug {
+        LinkDebug::new(peer, self.debug_links.clone(), self.traffic.clone(), self.hooks.clone())
+    }
+
+    /// Tuning of the link to a peer, constrained links get their own.
+    fn link_tuning(&self, peer: &NodeInfo) -> LinkTuning {
+        let mut tuning = match self.constrained_links {
+            Some(ref links) if links.matches(&self.info, peer) => {
+                info!("Link to {} is constrained", peer.cluster_addr);
+                LinkTuning::constrained(links)
+            }
+            _ => LinkTuning::default(),
+        };
+        if self.dictionary_wire {
+            tuning.compression = Compression::Dictionary;
+        }
+        tuning
+    }
+
+    /// Hand an accepted connection to the protocol it negotiated by ALPN. With a client
+    /// listener, peers only connect to the peer one and clients to the client one, where plain
+    /// connections are clients.
+    fn dispatch_connection(&mut self, stream: PeerStream, peer: String, listener: Listener, ctx: &mut Context<Self>) {
+        let protocol = match listener {
+            Listener::Peer => stream.protocol(),
+            Listener::Client => stream.negotiated().unwrap_or(Protocol::Client),
+        };
+
+        let refusal = match (listener, protocol) {
+            (Listener::Client, Protocol::Peer) => Some("peers connect to the cluster address"),
+            (Listener::Client, Protocol::Admin) if !self.client_listener.as_ref().map_or(false, |listener| listener.admin) => {
+                Some("the client listener doesn't relay the admin API")
+            }
+            (Listener::Peer, Protocol::Client) | (Listener::Peer, Protocol::Admin) if self.client_listener.is_some() => {
+                Some("clients connect to the client listener")
+            }
+            _ => None,
+        };
+        if let Some(reason) = refusal {
+            warn!("Dropping {:?} connection from {}, {}", protocol, peer, reason);
+            return;
+        }
+
+        match protocol {
+            Protocol::Peer => self.start_session(stream, ctx),
+            Protocol::Client => match self.clients {
+                Some(ref clients) => {
+                    let _ = clients.do_send(JsonConnect(stream));
+                }
+                None => warn!("Dropping client connection from {}, JSON clients aren't served on this node", peer),
+            },
+            Protocol::Admin => match self.admin_addr {
+                Some(ref admin_addr) => relay_admin(stream, admin_addr, peer),
+                None => warn!("Dropping admin connection from {}, the admin API isn't served on this node", peer),
+            },
+        }
+    }
+
+    fn start_session(&mut self, stream: PeerStream, ctx: &mut Context<Self>) {
         let addr = ctx.address();
         let registry = self.registry.clone();
         let net_type = self.net_type.clone();
+        let throttle = self.peer_rate_limit.as_ref().map(InboundThrottle::new);
+        let debug = self.link_debug(None);
+        let capacity = self.mailboxes.session;
 
         NodeSession::create(move |ctx| {
-            let (r, w) = msg.0.split();
-            NodeSession::add_stream(FramedRead::new(r, NodeCodec), ctx);
+            ctx.set_mailbox_capacity(capacity);
+            let (r, w) = stream.split();
+            NodeSession::add_stream(FramedRead::new(r, NodeCodec::new(debug.clone())), ctx);
             NodeSession::new(
-                actix::io::FramedWrite::new(w, NodeCodec, ctx),
+                actix::io::FramedWrite::new(w, NodeCodec::new(debug), ctx),
                 addr,
                 registry,
-                net_type
+                net_type,
+                throttle,
             )
         });
     }
 }
 
+/// Relay an admin connection accepted on the TLS port to the admin API.
+fn relay_admin(stream: PeerStream, admin_addr: &str, peer: String) {
+    let addr = match admin_addr.parse() {
+        Ok(addr) => addr,
+        Err(err) => {
+            warn!("Dropping admin connection from {}, {} is not a valid address: {}", peer, admin_addr, err);
+            return;
+        }
+    };
+
+    Arbiter::spawn(
+        TcpStream::connect(&addr)
+            .and_then(move |admin| {
+                let (client_r, client_w) = stream.split();
+                let (admin_r, admin_w) = admin.split();
+                tokio::io::copy(client_r, admin_w)
+                    .map(|_| ())
+                    .select(tokio::io::copy(admin_r, client_w).map(|_| ()))
+                    .map(|_| ())
+                    .map_err(|(err, _)| err)
+            })
+            .map_err(move |err| debug!("Admin connection from {} closed: {}", peer, err)),
+    );
+}
+
 pub struct GetNodeAddr(pub String);
 
 impl Message for GetNodeAddr {
@@ -379,16 +1728,13 @@ impl Handler<GetNodeAddr> for Network {
     type Result = ResponseActFuture<Self, Addr<Node>, ()>;
 
     fn handle(&mut self, msg: GetNodeAddr, ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(GetNodeAddr);
         let res = fut::wrap_future(ctx.address().send(GetNode(msg.0)))
             .map_err(|_, _: &mut Network, _| println!("GetNodeAddr Error"))
             .and_then(|res, act, _| {
-                if let Ok(info) = res {
-                    let id = info.0;
-                    let node = act.nodes.get(&id).unwrap();
-
-                    fut::result(Ok(node.clone()))
-                } else {
-                    fut::result(Err(()))
+                match res {
+                    Ok((id, _)) => fut::result(act.get_node(id).cloned().ok_or(())),
+                    Err(_) => fut::result(Err(())),
                 }
             });
 
@@ -396,6 +1742,43 @@ impl Handler<GetNodeAddr> for Network {
     }
 }
 
+/// Get handles to every peer this node manages a connection to.
+pub struct GetPeers;
+
+impl Message for GetPeers {
+    type Result = Vec<PeerHandle>;
+}
+
+impl Handler<GetPeers> for Network {
+    type Result = MessageResult<GetPeers>;
+
+    fn handle(&mut self, _: GetPeers, _ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(GetPeers);
+        MessageResult(
+            self.nodes
+                .iter()
+                .filter_map(|(id, node)| node.node().map(|node| PeerHandle::new(*id, node.clone())))
+                .collect(),
+        )
+    }
+}
+
+/// Get a handle to the given peer.
+pub struct GetPeer(pub NodeId);
+
+impl Message for GetPeer {
+    type Result = Option<PeerHandle>;
+}
+
+impl Handler<GetPeer> for Network {
+    type Result = Option<PeerHandle>;
+
+    fn handle(&mut self, msg: GetPeer, _ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(GetPeer);
+        self.get_node(msg.0).map(|node| PeerHandle::new(msg.0, node.clone()))
+    }
+}
+
 pub struct GetNodeById(pub NodeId);
 
 impl Message for GetNodeById {
@@ -406,6 +1789,7 @@ impl Handler<GetNodeById> for Network {
     type Result = Result<Addr<Node>, ()>;
 
     fn handle(&mut self, msg: GetNodeById, ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(GetNodeById);
         if let Some(ref node) = self.get_node(msg.0) {
             Ok((*node).clone())
         } else {
@@ -441,11 +1825,15 @@ where
     type Result = ();
 
     fn handle(&mut self, msg: DistributeMessage<M>, ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(DistributeMessage<M>);
         let ring = self.ring.read().unwrap();
-        let node_id = ring.get_node(msg.0.clone()).unwrap();
+        let node_id = match ring.get_node(msg.0.clone()) {
+            Some(node_id) => node_id,
+            None => return,
+        };
 
         if let Some(ref node) = self.get_node(*node_id) {
-            node.do_send(DispatchMessage(msg.1))
+            mailbox::deliver(node, DispatchMessage(msg.1), Mailbox::Node)
         }
     }
 }
@@ -458,8 +1846,12 @@ where
     type Result = Response<M::Result, ()>;
 
     fn handle(&mut self, msg: DistributeAndWait<M>, ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(DistributeAndWait<M>);
         let ring = self.ring.read().unwrap();
-        let node_id = ring.get_node(msg.0.clone()).unwrap();
+        let node_id = match ring.get_node(msg.0.clone()) {
+            Some(node_id) => node_id,
+            None => return Response::fut(futures::future::err(())),
+        };
 
         if let Some(ref node) = self.get_node(*node_id) {
             let fut = node
@@ -474,6 +1866,236 @@ where
     }
 }
 
+/// Why a peer did not answer a broadcast.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub enum PeerError {
+    /// The peer did not answer within the timeout.
+    Timeout,
+    /// The peer's session failed or was closed before answering.
+    Failed,
+    /// There is no connection to the node.
+    NotConnected,
+}
+
+impl From<MailboxError> for PeerError {
+    fn from(err: MailboxError) -> Self {
+        match err {
+            MailboxError::Timeout => PeerError::Timeout,
+            MailboxError::Closed => PeerError::Failed,
+        }
+    }
+}
+
+/// Send a message to every connected peer and collect their answers, peers which don't answer
+/// within `timeout` are reported as `PeerError::Timeout`.
+pub struct Broadcast<M>
+where
+    M: RemoteMessage + Clone + 'static,
+    M::Result: Send + Serialize + DeserializeOwned,
+{
+    pub msg: M,
+    pub timeout: Duration,
+}
+
+impl<M> Message for Broadcast<M>
+where
+    M: RemoteMessage + Clone + 'static,
+    M::Result: Send + Serialize + DeserializeOwned,
+{
+    type Result = Result<Vec<(NodeId, Result<M::Result, PeerError>)>, ()>;
+}
+
+impl<M> Handler<Broadcast<M>> for Network
+where
+    M: RemoteMessage + Clone + 'static,
+    M::Result: Send + Serialize + DeserializeOwned,
+{
+    type Result = Response<Vec<(NodeId, Result<M::Result, PeerError>)>, ()>;
+
+    fn handle(&mut self, msg: Broadcast<M>, _ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(Broadcast<M>);
+        let timeout = msg.timeout;
+
+        let requests = self
+            .nodes
+            .iter()
+            .filter(|(id, _)| !self.isolated_nodes.contains(id))
+            .map(|(id, node)| {
+                let id = *id;
+
+                node.send(SendRemoteMessage(msg.msg.clone()))
+                    .timeout(timeout)
+                    .then(move |res| Ok((id, res.map_err(PeerError::from))))
+            })
+            .collect::<Vec<_>>();
+
+        Response::fut(futures::future::join_all(requests))
+    }
+}
+
+/// Fan a read-only query out to all nodes, or to `nodes` only, including this one, and
+/// collect their local answers. Nodes which don't answer before `deadline` are reported as
+/// `PeerError::Timeout` while the answers of the others are still returned.
+///
+/// The query runs through the handler registry on every node, so `M` must be registered.
+pub struct ScatterGather<M>
+where
+    M: RemoteMessage + Clone + 'static,
+    M::Result: Send + Serialize + DeserializeOwned,
+{
+    pub msg: M,
+    pub nodes: Option<Vec<NodeId>>,
+    pub deadline: Duration,
+}
+
+impl<M> Message for ScatterGather<M>
+where
+    M: RemoteMessage + Clone + 'static,
+    M::Result: Send + Serialize + DeserializeOwned,
+{
+    type Result = Result<Vec<(NodeId, Result<M::Result, PeerError>)>, ()>;
+}
+
+impl<M> Handler<ScatterGather<M>> for Network
+where
+    M: RemoteMessage + Clone + 'static,
+    M::Result: Send + Serialize + DeserializeOwned,
+{
+    type Result = Response<Vec<(NodeId, Result<M::Result, PeerError>)>, ()>;
+
+    fn handle(&mut self, msg: ScatterGather<M>, _ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(ScatterGather<M>);
+        let deadline = msg.deadline;
+        let targets = msg.nodes.unwrap_or_else(|| {
+            let mut nodes = self.nodes.keys().cloned().collect::<Vec<_>>();
+            nodes.push(self.id);
+            nodes
+        });
+
+        let requests = targets
+            .into_iter()
+            .map(|id| -> Box<dyn Future<Item = _, Error = ()>> {
+                if id == self.id {
+                    return Box::new(self.query_local(msg.msg.clone(), deadline).then(move |res| Ok((id, res))));
+                }
+
+                match self.nodes.get(&id) {
+                    Some(node) if !self.isolated_nodes.contains(&id) => Box::new(
+                        Timeout::new(node.send(msg.msg.clone()), deadline)
+                            .then(move |res| Ok((id, res.map_err(|err| err.into_inner().map(PeerError::from).unwrap_or(PeerError::Timeout))))),
+                    ),
+                    _ => Box::new(futures::future::ok((id, Err(PeerError::NotConnected)))),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        Response::fut(futures::future::join_all(requests))
+    }
+}
+
+impl Network {
+    /// Answer a query on this node through the handler registry, the way a peer would.
+    fn query_local<M>(&self, msg: M, deadline: Duration) -> Box<dyn Future<Item = M::Result, Error = PeerError>>
+    where
+        M: RemoteMessage + 'static,
+        M::Result: Send + Serialize + DeserializeOwned,
+    {
+        let (tx, rx) = oneshot::channel();
+        let registry = self.registry.read().unwrap();
+
+        match (registry.get(M::type_id()), remote::encode_payload(&msg)) {
+            (Some(handler), Ok(body)) => handler.handle(body, tx),
+            _ => return Box::new(futures::future::err(PeerError::Failed)),
+        }
+
+        Box::new(
+            Timeout::new(rx, deadline)
+                .map_err(|err| if err.is_elapsed() { PeerError::Timeout } else { PeerError::Failed })
+                .and_then(|res| res.and_then(|body| remote::decode_payload::<M::Result>(&body)).map_err(|_| PeerError::Failed)),
+        )
+    }
+}
+
+/// Stale read answered by a single replica, `nodes` restricts the replicas asked.
+///
+/// With `hedged_reads` configured a second replica is asked as well when the first one has
+/// not answered within a percentile of recent read latencies, the first answer wins.
+pub struct HedgedRead<M>
+where
+    M: RemoteMessage + Clone + 'static,
+    M::Result: Send + Serialize + DeserializeOwned,
+{
+    pub msg: M,
+    pub nodes: Option<Vec<NodeId>>,
+}
+
+impl<M> Message for HedgedRead<M>
+where
+    M: RemoteMessage + Clone + 'static,
+    M::Result: Send + Serialize + DeserializeOwned,
+{
+    type Result = Result<M::Result, PeerError>;
+}
+
+impl<M> Handler<HedgedRead<M>> for Network
+where
+    M: RemoteMessage + Clone + 'static,
+    M::Result: Send + Serialize + DeserializeOwned,
+{
+    type Result = ResponseActFuture<Self, M::Result, PeerError>;
+
+    fn handle(&mut self, msg: HedgedRead<M>, _ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(HedgedRead<M>);
+        let (cordoned, mut candidates): (Vec<_>, Vec<_>) = self
+            .nodes
+            .iter()
+            .filter(|(id, _)| !self.isolated_nodes.contains(id))
+            .filter(|(id, _)| msg.nodes.as_ref().map(|nodes| nodes.contains(id)).unwrap_or(true))
+            .partition(|(id, _)| self.cordoned.contains(*id));
+        // cordoned replicas only serve reads no other replica can
+        if candidates.is_empty() {
+            candidates = cordoned;
+        }
+        let candidates = candidates.into_iter().map(|(_, node)| node.clone()).collect::<Vec<_>>();
+
+        if candidates.is_empty() {
+            return Box::new(fut::err(PeerError::NotConnected));
+        }
+
+        // spread reads over the replicas
+        self.next_replica = self.next_replica.wrapping_add(1);
+        let first = candidates[self.next_replica % candidates.len()].clone();
+        let second = candidates[(self.next_replica + 1) % candidates.len()].clone();
+
+        let mut requests: Vec<Box<dyn Future<Item = M::Result, Error = PeerError>>> = vec![Box::new(
+            first.send(SendRemoteMessage(msg.msg.clone())).map_err(PeerError::from),
+        )];
+
+        if let Some(ref hedge) = self.hedge {
+            if candidates.len() > 1 {
+                let m = msg.msg;
+                requests.push(Box::new(
+                    clock::delay(hedge.delay())
+                        .map_err(|_| PeerError::Failed)
+                        .and_then(move |_| second.send(SendRemoteMessage(m)).map_err(PeerError::from)),
+                ));
+            }
+        }
+
+        let started = clock::now();
+
+        Box::new(
+            fut::wrap_future::<_, Self>(futures::future::select_ok(requests))
+                .map(move |(res, _), act, _| {
+                    if let Some(ref mut hedge) = act.hedge {
+                        hedge.record(clock::now().duration_since(started));
+                    }
+                    res
+                }),
+        )
+    }
+}
+
 pub struct GetNode(pub String);
 
 impl Message for GetNode {
@@ -484,13 +2106,17 @@ impl Handler<GetNode> for Network {
     type Result = Result<(NodeId, String), ()>;
 
     fn handle(&mut self, msg: GetNode, ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(GetNode);
         let ring = self.ring.read().unwrap();
-        let node_id = ring.get_node(msg.0).unwrap();
+        let node_id = ring.get_node(msg.0).ok_or(())?;
 
         let default = NodeInfo {
             public_addr: "".to_owned(),
             app_addr: "".to_owned(),
             cluster_addr: "".to_owned(),
+            id: None,
+            labels: Default::default(),
+            cluster: None,
         };
 
         let node = self.nodes_info.get(node_id).unwrap_or(&default);
@@ -498,6 +2124,17 @@ impl Handler<GetNode> for Network {
     }
 }
 
+/// Connection status of a peer.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum NodeStatus {
+    Connected,
+    /// The peer was disconnected from the cluster, after its session or connection stayed
+    /// down past the grace period
+    Disconnected,
+    /// The connection is down and being retried with backoff
+    Reconnecting,
+}
+
 #[derive(Message)]
 pub struct PeerConnected(pub NodeId);
 
@@ -505,7 +2142,66 @@ impl Handler<PeerConnected> for Network {
     type Result = ();
 
     fn handle(&mut self, msg: PeerConnected, _ctx: &mut Context<Self>) {
-        self.nodes_connected.push(msg.0);
+        crate::audit_message!(PeerConnected);
+        if !self.nodes_connected.contains(&msg.0) {
+            self.nodes_connected.push(msg.0);
+        }
+        self.set_status(msg.0, NodeStatus::Connected);
+    }
+}
+
+/// A follower asked the leader to catch it up from `last_log_index`: reconnect to it right
+/// away rather than after the backoff.
+#[derive(Message)]
+pub struct PeerCatchingUp {
+    pub id: NodeId,
+    pub last_log_index: u64,
+}
+
+impl Handler<PeerCatchingUp> for Network {
+    type Result = ();
+
+    fn handle(&mut self, msg: PeerCatchingUp, _ctx: &mut Context<Self>) {
+        crate::audit_message!(PeerCatchingUp);
+        self.peer_matched(msg.id, msg.last_log_index);
+        if let Some(node) = self.nodes.get(&msg.id) {
+            node.reconnect();
+        }
+    }
+}
+
+/// The connection to a peer dropped or timed out, its node actor is reconnecting.
+#[derive(Message)]
+pub struct PeerReconnecting(pub NodeId);
+
+impl Handler<PeerReconnecting> for Network {
+    type Result = ();
+
+    fn handle(&mut self, msg: PeerReconnecting, _ctx: &mut Context<Self>) {
+        crate::audit_message!(PeerReconnecting);
+        // ignore a node actor replaced since, e.g. after an address change
+        if self.nodes.contains_key(&msg.0) {
+            self.nodes_connected.retain(|id| *id != msg.0);
+            self.set_status(msg.0, NodeStatus::Reconnecting);
+            self.traffic.reconnected();
+            self.link_stats.reconnected(msg.0);
+        }
+    }
+}
+
+/// Connection status of every peer this node knows of.
+pub struct GetNodeStatus;
+
+impl Message for GetNodeStatus {
+    type Result = Result<HashMap<NodeId, NodeStatus>, ()>;
+}
+
+impl Handler<GetNodeStatus> for Network {
+    type Result = Result<HashMap<NodeId, NodeStatus>, ()>;
+
+    fn handle(&mut self, _: GetNodeStatus, _ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(GetNodeStatus);
+        Ok(self.statuses.clone())
     }
 }
 
@@ -519,12 +2215,13 @@ impl Handler<GetCurrentLeader> for Network {
     type Result = ResponseActFuture<Self, NodeId, ()>;
 
     fn handle(&mut self, msg: GetCurrentLeader, ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(GetCurrentLeader);
         if let Some(ref mut metrics) = self.metrics {
             if let Some(leader) = metrics.current_leader {
                 Box::new(fut::result(Ok(leader)))
             } else {
                 Box::new(
-                    fut::wrap_future::<_, Self>(Delay::new(Instant::now() + Duration::from_secs(1)))
+                    fut::wrap_future::<_, Self>(clock::delay(Duration::from_secs(1)))
                         .map_err(|_, _, _| ())
                         .and_then(|_, _, ctx| {
                             fut::wrap_future::<_, Self>(ctx.address().send(msg))
@@ -535,7 +2232,7 @@ impl Handler<GetCurrentLeader> for Network {
             }
         } else {
             Box::new(
-                    fut::wrap_future::<_, Self>(Delay::new(Instant::now() + Duration::from_secs(1)))
+                    fut::wrap_future::<_, Self>(clock::delay(Duration::from_secs(1)))
                         .map_err(|_, _, _| ())
                         .and_then(|_, _, ctx| {
                             fut::wrap_future::<_, Self>(ctx.address().send(msg))
@@ -548,18 +2245,2285 @@ impl Handler<GetCurrentLeader> for Network {
 }
 
 
-//////////////////////////////////////////////////////////////////////////////
-// RaftMetrics ///////////////////////////////////////////////////////////////
+/// Fetch a blob this node misses from its peers in chunks, trying them in turn, into the blob
+/// dir under `snapshot_dir`. Fails when no peer hands over a blob matching its id.
+pub struct FetchBlob {
+    pub blob: BlobRef,
+    pub snapshot_dir: String,
+}
 
-impl Handler<RaftMetrics> for Network {
-    type Result = ();
+impl Message for FetchBlob {
+    type Result = Result<(), ()>;
+}
 
-    fn handle(&mut self, msg: RaftMetrics, _: &mut Context<Self>) -> Self::Result {
-        debug!("Metrics: node={} state={:?} leader={:?} term={} index={} applied={} cfg={{join={} members={:?} non_voters={:?} removing={:?}}}",
-               msg.id, msg.state, msg.current_leader, msg.current_term, msg.last_log_index, msg.last_applied,
-               msg.membership_config.is_in_joint_consensus, msg.membership_config.members,
-               msg.membership_config.non_voters, msg.membership_config.removing,
-        );
-        self.metrics = Some(msg);
+impl Handler<FetchBlob> for Network {
+    type Result = Response<(), ()>;
+
+    fn handle(&mut self, msg: FetchBlob, _ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(FetchBlob);
+        info!("Node {} fetches blob {} of {} bytes", self.id, msg.blob.id, msg.blob.size);
+        let peers = self.nodes.values().cloned().collect();
+        Response::fut(fetch_blob(peers, msg.snapshot_dir, msg.blob, self.blob_chunk_size))
+    }
+}
+
+fn fetch_blob(mut peers: Vec<PeerSender>, snapshot_dir: String, blob: BlobRef, chunk_size: u64) -> Box<dyn Future<Item = (), Error = ()>> {
+    if peers.is_empty() {
+        return Box::new(futures::future::err(()));
+    }
+    let peer = peers.remove(0);
+    let (dir, next) = (snapshot_dir.clone(), blob.clone());
+
+    Box::new(
+        fetch_blob_from(peer, snapshot_dir, blob, chunk_size)
+            .or_else(move |_| fetch_blob(peers, dir, next, chunk_size)),
+    )
+}
+
+fn fetch_blob_from(peer: PeerSender, snapshot_dir: String, blob: BlobRef, chunk_size: u64) -> Box<dyn Future<Item = (), Error = ()>> {
+    let spill = match Spill::create(&snapshot_dir) {
+        Ok(spill) => spill,
+        Err(err) => {
+            error!("Can't write blob {}: {}", blob.id, err);
+            return Box::new(futures::future::err(()));
+        }
+    };
+    let expected = blob.clone();
+
+    let chunks = futures::future::loop_fn(spill, move |mut spill| {
+        let request = GetBlobChunk {
+            id: blob.id.clone(),
+            offset: spill.size(),
+            len: chunk_size,
+        };
+        let size = blob.size;
+
+        Timeout::new(peer.send(request), BLOB_CHUNK_TIMEOUT).then(move |res| {
+            let chunk = match res {
+                Ok(Ok(chunk)) => chunk,
+                _ => return Err(()),
+            };
+            if let Err(err) = spill.write(&chunk.data) {
+                error!("Can't write blob: {}", err);
+                return Err(());
+            }
+
+            match (chunk.done || spill.size() >= size, chunk.data.is_empty()) {
+                (true, _) => Ok(futures::future::Loop::Break(spill)),
+                // the peer has less of the blob than it's supposed to be
+                (false, true) => Err(()),
+                (false, false) => Ok(futures::future::Loop::Continue(spill)),
+            }
+        })
+    });
+
+    Box::new(chunks.and_then(move |spill| match spill.finish() {
+        Ok(ref blob) if *blob == expected => Ok(()),
+        // kept under the id of what it holds, which is harmless
+        Ok(blob) => {
+            warn!("Fetched blob {} doesn't match its id, got {}", expected.id, blob.id);
+            Err(())
+        }
+        Err(err) => {
+            error!("Can't write blob {}: {}", expected.id, err);
+            Err(())
+        }
+    }))
+}
+
+/// Change the egress limit of the node in bytes per second, `None` lifts it. Answers the
+/// limit in effect and how much frames were held back.
+pub struct SetEgressLimit(pub Option<u64>);
+
+impl Message for SetEgressLimit {
+    type Result = EgressStats;
+}
+
+impl Handler<SetEgressLimit> for Network {
+    type Result = MessageResult<SetEgressLimit>;
+
+    fn handle(&mut self, msg: SetEgressLimit, _ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(SetEgressLimit);
+        info!("Node {} egress limit set to {:?} bytes per second", self.id, msg.0);
+        self.egress.set_rate(msg.0);
+        MessageResult(self.egress.stats())
+    }
+}
+
+pub struct GetEgressLimit;
+
+impl Message for GetEgressLimit {
+    type Result = EgressStats;
+}
+
+impl Handler<GetEgressLimit> for Network {
+    type Result = MessageResult<GetEgressLimit>;
+
+    fn handle(&mut self, _: GetEgressLimit, _ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(GetEgressLimit);
+        MessageResult(self.egress.stats())
+    }
+}
+
+
+pub struct GetRaftMetrics;
+
+impl Message for GetRaftMetrics {
+    type Result = Result<Option<RaftMetrics>, ()>;
+}
+
+impl Handler<GetRaftMetrics> for Network {
+    type Result = Result<Option<RaftMetrics>, ()>;
+
+    fn handle(&mut self, _: GetRaftMetrics, _ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(GetRaftMetrics);
+        Ok(self.metrics.clone())
+    }
+}
+
+/// Why a `ClientRequest` wasn't committed.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum ProposeError {
+    /// The leader is unknown or unreachable from this node, retry on the hinted node
+    NotLeader { leader_hint: Option<NodeId> },
+    /// Rejected by a validator, a hook or the storage
+    Rejected,
+    /// The storage of the leader faulted, the proposal wasn't appended at the index
+    Storage(MemoryStorageError),
+    Failed,
+    /// The retry budget ran out before a leader took the proposal. It may still be committed
+    /// if the last attempt reached the leader.
+    Exhausted(RetryReport),
+}
+
+/// How long and how often a `ClientRequest` is retried while the leader can't take it.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+pub struct RetryBudget {
+    /// Deadline of the proposal from when it's sent, attempts are cut off at it
+    pub timeout_ms: u64,
+    /// Most attempts, unlimited within the deadline if not set
+    pub max_attempts: Option<u32>,
+}
+
+impl RetryBudget {
+    pub fn within(timeout: Duration) -> RetryBudget {
+        RetryBudget {
+            timeout_ms: timeout.as_millis() as u64,
+            max_attempts: None,
+        }
+    }
+
+    pub fn attempts(mut self, max_attempts: u32) -> RetryBudget {
+        self.max_attempts = Some(max_attempts);
+        self
+    }
+}
+
+/// Attempts a `ClientRequest` made before its budget ran out.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct RetryReport {
+    pub attempts: Vec<ProposeAttempt>,
+    pub elapsed_ms: u64,
+}
+
+impl RetryReport {
+    /// Leader hinted by the last attempt.
+    pub fn leader_hint(&self) -> Option<NodeId> {
+        match self.attempts.last().map(|attempt| &attempt.error) {
+            Some(ProposeError::NotLeader { leader_hint }) => *leader_hint,
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ProposeAttempt {
+    /// Node the proposal was sent to, `None` while no leader was known
+    pub target: Option<NodeId>,
+    /// Milliseconds into the budget the attempt was made at
+    pub at_ms: u64,
+    pub error: ProposeError,
+    /// The deadline passed before the target answered
+    pub timed_out: bool,
+}
+
+/// Propose a command from any node: it's sent to the current leader, through its peer
+/// connection when this node is a follower, and answered with the index it was committed at.
+///
+/// While the leader can't be reached, e.g. it died before answering, the proposal is retried
+/// against the next leader within the retry budget, by default for three election timeouts.
+/// Retries carry the same idempotency key, so a proposal the old leader committed anyway is
+/// applied once. Once the budget ran out the attempts are answered in
+/// `RaftorError::Exhausted`.
+#[derive(Clone)]
+pub struct ClientRequest {
+    pub client: String,
+    pub data: MemoryStorageData,
+    pub budget: Option<RetryBudget>,
+}
+
+impl Message for ClientRequest {
+    type Result = Result<u64, RaftorError>;
+}
+
+impl Handler<ClientRequest> for Network {
+    type Result = ResponseActFuture<Self, u64, RaftorError>;
+
+    fn handle(&mut self, msg: ClientRequest, _ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(ClientRequest);
+        let leader = self.metrics.as_ref().and_then(|metrics| metrics.current_leader);
+        let budget = msg.budget.unwrap_or_else(|| RetryBudget::within(self.election_timeout_min * 3));
+        let started = clock::now();
+        let retries = Retries {
+            started: started,
+            deadline: started + Duration::from_millis(budget.timeout_ms),
+            max_attempts: budget.max_attempts,
+            report: RetryReport::default(),
+        };
+
+        self.proposal_seq += 1;
+        let proposal = ClientProposal {
+            client: msg.client,
+            key: Some(format!("{}-{}-{}", self.id, self.started_at, self.proposal_seq)),
+            data: msg.data,
+            deadline_ms: None,
+        };
+
+        Box::new(self.propose_until(leader, proposal, retries).map_err(|err, _, _| RaftorError::from(err)))
+    }
+}
+
+/// Budget of a `ClientRequest` being retried, and its attempts so far.
+struct Retries {
+    started: Instant,
+    deadline: Instant,
+    max_attempts: Option<u32>,
+    report: RetryReport,
+}
+
+impl Retries {
+    fn exhausted(&self) -> bool {
+        clock::now() >= self.deadline
+            || self
+                .max_attempts
+                .map_or(false, |max| self.report.attempts.len() >= max as usize)
+    }
+
+    fn into_error(mut self) -> ProposeError {
+        self.report.elapsed_ms = clock::elapsed(self.started).as_millis() as u64;
+        ProposeError::Exhausted(self.report)
+    }
+}
+
+impl Network {
+    /// Propose to the given leader, following its hint or waiting for the next leader when it
+    /// can't take the proposal, until the budget runs out.
+    fn propose_until(
+        &self,
+        leader: Option<NodeId>,
+        proposal: ClientProposal,
+        mut retries: Retries,
+    ) -> Box<dyn ActorFuture<Actor = Self, Item = u64, Error = ProposeError>> {
+        let retry = proposal.clone();
+        let at_ms = clock::elapsed(retries.started).as_millis() as u64;
+        let now = clock::now();
+        let remaining = if retries.deadline > now { retries.deadline - now } else { Duration::from_millis(0) };
+
+        Box::new(self.propose_to(leader, proposal, remaining).then(move |res, act: &mut Self, _| match res {
+            Err((err @ ProposeError::NotLeader { .. }, timed_out)) => {
+                let leader_hint = match err {
+                    ProposeError::NotLeader { leader_hint } => leader_hint,
+                    _ => None,
+                };
+                retries.report.attempts.push(ProposeAttempt {
+                    target: leader,
+                    at_ms: at_ms,
+                    error: err,
+                    timed_out: timed_out,
+                });
+                if retries.exhausted() {
+                    return fut::Either::B(fut::Either::B(fut::err(retries.into_error())));
+                }
+
+                // the leader may have moved since the metrics were last updated
+                if leader_hint.is_some() && leader_hint != leader {
+                    return fut::Either::A(act.propose_until(leader_hint, retry, retries));
+                }
+
+                fut::Either::B(fut::Either::A(
+                    fut::wrap_future::<_, Self>(clock::delay(PROPOSAL_RETRY_INTERVAL))
+                        .map_err(|_, _, _| ProposeError::Failed)
+                        .and_then(move |_, act: &mut Self, _| {
+                            let leader = act.metrics.as_ref().and_then(|metrics| metrics.current_leader);
+                            act.propose_until(leader, retry, retries)
+                        }),
+                ))
+            }
+            res => fut::Either::B(fut::Either::B(fut::result(res.map_err(|(err, _)| err)))),
+        }))
+    }
+
+    /// Propose to the given leader within the timeout, errors tell whether it timed out.
+    fn propose_to(
+        &self,
+        leader: Option<NodeId>,
+        mut proposal: ClientProposal,
+        timeout: Duration,
+    ) -> Box<dyn ActorFuture<Actor = Self, Item = u64, Error = (ProposeError, bool)>> {
+        // the hops after this one don't work on the proposal past the deadline
+        proposal.deadline_ms = Some(timeout.as_millis() as u64);
+
+        let res: Box<dyn Future<Item = _, Error = MailboxError>> = match leader {
+            Some(leader) if leader == self.id => Box::new(self.raft.proposals.send(proposal)),
+            Some(leader) => match self.nodes.get(&leader) {
+                Some(node) => node.send(proposal),
+                None => return Box::new(fut::err((ProposeError::NotLeader { leader_hint: Some(leader) }, false))),
+            },
+            None => return Box::new(fut::err((ProposeError::NotLeader { leader_hint: None }, false))),
+        };
+
+        Box::new(fut::wrap_future(Timeout::new(res, timeout)).then(move |res, _, _| {
+            fut::result(match res {
+                Ok(Ok(ClientPayloadResponse::Applied { index, .. }))
+                | Ok(Ok(ClientPayloadResponse::Committed { index })) => Ok(index),
+                Ok(Err(ClientError::ForwardToLeader { leader, .. })) => Err((ProposeError::NotLeader { leader_hint: leader }, false)),
+                Ok(Err(ClientError::Application(ref err))) if err.fault.is_some() => Err((ProposeError::Storage(err.clone()), false)),
+                // dropped by the leader once the deadline passed, it's out of budget
+                Ok(Err(ClientError::Application(ref err))) if err.expired => Err((ProposeError::NotLeader { leader_hint: leader }, true)),
+                Ok(Err(ClientError::Application(_))) => Err((ProposeError::Rejected, false)),
+                Ok(Err(_)) => Err((ProposeError::Failed, false)),
+                // the leader didn't answer in time or can't be reached from here, let the
+                // caller try it directly
+                Err(err) => Err((ProposeError::NotLeader { leader_hint: leader }, err.is_elapsed())),
+            })
+        }))
+    }
+}
+
+/// Consistency level of a `ClientRead`.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum ReadConsistency {
+    /// Answered by this node from what it applied so far, may miss recent writes
+    Stale,
+    /// Answered by the leader once a quorum confirmed its leadership and it applied every
+    /// entry committed when the read arrived
+    Linearizable,
+}
+
+/// Why a `ClientRead` wasn't answered.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum ReadError {
+    /// Linearizable reads are served by the leader, retry on the hinted node
+    NotLeader { leader_hint: Option<NodeId> },
+    /// A quorum didn't confirm the leadership within an election timeout
+    NoQuorum,
+    /// The node didn't apply the entries the read has to see in time
+    Timeout,
+    Failed,
+}
+
+/// Query the application state machine, see `QueryApp` for the encoding of the query and
+/// its answer.
+pub struct ClientRead {
+    pub query: Vec<u8>,
+    pub consistency: ReadConsistency,
+}
+
+impl Message for ClientRead {
+    type Result = Result<Vec<u8>, RaftorError>;
+}
+
+impl Handler<ClientRead> for Network {
+    type Result = ResponseActFuture<Self, Vec<u8>, RaftorError>;
+
+    fn handle(&mut self, msg: ClientRead, ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(ClientRead);
+        let query = QueryApp(msg.query);
+
+        if msg.consistency == ReadConsistency::Stale {
+            return Box::new(self.query_app(query).map_err(|err, _, _| RaftorError::from(err)));
+        }
+
+        Box::new(
+            self.read_index(ctx)
+                .and_then(|read_index, act: &mut Self, _| {
+                    fut::wrap_future::<_, Self>(act.progress.wait_for(read_index, READ_APPLY_TIMEOUT))
+                })
+                .and_then(move |_, act: &mut Self, _| act.query_app(query))
+                .map_err(|err, _, _| RaftorError::from(err)),
+        )
+    }
+}
+
+/// Index a leader serves linearizable reads from: its commit index, answered once a quorum
+/// confirmed it was still the leader. Sent to the leader by followers taking a `ReadBarrier`.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ReadIndex;
+
+impl Message for ReadIndex {
+    type Result = Result<u64, ReadError>;
+}
+
+impl Handler<ReadIndex> for Network {
+    type Result = ResponseActFuture<Self, u64, ReadError>;
+
+    fn handle(&mut self, _msg: ReadIndex, ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(ReadIndex);
+        self.read_index(ctx)
+    }
+}
+
+/// Resolve once this node applied every entry committed when the barrier was taken, so every
+/// write acknowledged before is visible in its state. The leader is asked for its read index,
+/// through the peer connection on followers, and the answer is the index waited for.
+pub struct ReadBarrier;
+
+impl Message for ReadBarrier {
+    type Result = Result<u64, ReadError>;
+}
+
+impl Handler<ReadBarrier> for Network {
+    type Result = ResponseActFuture<Self, u64, ReadError>;
+
+    fn handle(&mut self, _msg: ReadBarrier, ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(ReadBarrier);
+        let leader = self.metrics.as_ref().and_then(|metrics| metrics.current_leader);
+
+        let read_index: Box<dyn ActorFuture<Actor = Self, Item = u64, Error = ReadError>> = match leader {
+            Some(leader) if leader == self.id => self.read_index(ctx),
+            Some(leader) => match self.nodes.get(&leader) {
+                Some(node) => Box::new(
+                    fut::wrap_future(node.send(ReadIndex))
+                        .then(|res, _, _| fut::result(res.unwrap_or(Err(ReadError::Failed)))),
+                ),
+                None => return Box::new(fut::err(ReadError::NotLeader { leader_hint: Some(leader) })),
+            },
+            None => return Box::new(fut::err(ReadError::NotLeader { leader_hint: None })),
+        };
+
+        Box::new(read_index.and_then(|read_index, act: &mut Self, _| {
+            fut::wrap_future::<_, Self>(act.progress.wait_for(read_index, READ_APPLY_TIMEOUT))
+                .map(move |_, _, _| read_index)
+        }))
+    }
+}
+
+impl Network {
+    /// Read index of this node, which must be the leader. Reads arriving while a leadership
+    /// confirmation round is in flight wait for the next one, which answers all of them.
+    fn read_index(&mut self, ctx: &mut Context<Self>) -> Box<dyn ActorFuture<Actor = Self, Item = u64, Error = ReadError>> {
+        let leader = self.metrics.as_ref().and_then(|metrics| metrics.current_leader);
+        if leader != Some(self.id) {
+            return Box::new(fut::err(ReadError::NotLeader { leader_hint: leader }));
+        }
+
+        let (tx, rx) = oneshot::channel();
+        self.read_waiters.push(tx);
+        self.read_stats.reads += 1;
+        if !self.read_round {
+            self.start_read_round(ctx);
+        }
+
+        Box::new(fut::wrap_future(rx).then(|res, _, _| fut::result(res.unwrap_or(Err(ReadError::Failed)))))
+    }
+
+    /// Confirm the leadership once for every read waiting, and start the next round once done
+    /// if reads arrived in the meantime.
+    fn start_read_round(&mut self, ctx: &mut Context<Self>) {
+        let waiters = std::mem::replace(&mut self.read_waiters, Vec::new());
+        self.read_round = true;
+        self.read_stats.rounds += 1;
+
+        // the read index is taken after the reads of the round arrived, so it covers every
+        // write committed before any of them
+        let since = clock::now();
+        let deadline = since + self.election_timeout_min;
+
+        ctx.spawn(
+            self.confirm_leadership(since, deadline)
+                .then(move |res, act: &mut Self, ctx| {
+                    for waiter in waiters {
+                        let _ = waiter.send(res.clone());
+                    }
+
+                    act.read_round = false;
+                    if !act.read_waiters.is_empty() {
+                        act.start_read_round(ctx);
+                    }
+                    fut::ok(())
+                }),
+        );
+    }
+
+    fn query_app(&self, query: QueryApp) -> Box<dyn ActorFuture<Actor = Self, Item = Vec<u8>, Error = ReadError>> {
+        Box::new(
+            fut::wrap_future(self.raft.queries.send(query))
+                .then(|res, _, _| fut::result(res.unwrap_or(Err(())).map_err(|_| ReadError::Failed))),
+        )
+    }
+
+    /// Resolve once a quorum of the members answered raft RPCs sent after `since` without a
+    /// newer term, which confirms this node was still the leader when the read arrived, with
+    /// the commit index as of then. A new leader first commits an entry of its term.
+    fn confirm_leadership(
+        &self,
+        since: Instant,
+        deadline: Instant,
+    ) -> Box<dyn ActorFuture<Actor = Self, Item = u64, Error = ReadError>> {
+        let metrics = match self.metrics {
+            Some(ref metrics) if metrics.current_leader == Some(self.id) => metrics,
+            ref metrics => {
+                let leader_hint = metrics.as_ref().and_then(|metrics| metrics.current_leader);
+                return Box::new(fut::err(ReadError::NotLeader { leader_hint: leader_hint }));
+            }
+        };
+
+        if contacted_since(self.id, &metrics.membership_config.members, &self.peer_contact, since) {
+            if let Some(commit_index) = self.commit_index() {
+                return Box::new(fut::ok(commit_index));
+            }
+        }
+
+        if clock::now() >= deadline {
+            return Box::new(fut::err(ReadError::NoQuorum));
+        }
+
+        Box::new(
+            fut::wrap_future::<_, Self>(clock::delay(READ_INDEX_POLL))
+                .map_err(|_, _, _| ReadError::Failed)
+                .and_then(move |_, act: &mut Self, _| act.confirm_leadership(since, deadline)),
+        )
+    }
+}
+
+/// Grow the cluster with a running node: connect to it and propose it as a member through the
+/// leader. It joins as a non-voter and becomes a voter once it caught up with the log, the
+/// answer comes once the change is committed.
+pub struct AddMember(pub NodeId, pub NodeInfo);
+
+impl Message for AddMember {
+    type Result = Result<(), RaftorError>;
+}
+
+impl Handler<AddMember> for Network {
+    type Result = ResponseActFuture<Self, (), RaftorError>;
+
+    fn handle(&mut self, msg: AddMember, ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(AddMember);
+        let (id, info) = (msg.0, msg.1);
+
+        if let Some(reason) = join::refusal(&self.join_admission, self.info.cluster.as_ref(), Some(&info), &self.raft_members()) {
+            warn!("Refusing to add node {}: {}", id, reason);
+            return Box::new(fut::err(RaftorError::Failed(reason)));
+        }
+
+        if id != self.id {
+            self.nodes_info.insert(id, info.clone());
+            if self.address.is_some() {
+                self.register_node(id, &info, ctx.address());
+            }
+        }
+
+        Box::new(self.propose_membership(ProposeMembership(vec![id], vec![])).map_err(|err, _, _| RaftorError::from(err)))
+    }
+}
+
+/// Shrink the cluster, the connections to the node are dropped once its removal is committed.
+pub struct RemoveMember(pub NodeId);
+
+impl Message for RemoveMember {
+    type Result = Result<(), RaftorError>;
+}
+
+impl Handler<RemoveMember> for Network {
+    type Result = ResponseActFuture<Self, (), RaftorError>;
+
+    fn handle(&mut self, msg: RemoveMember, _ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(RemoveMember);
+        let id = msg.0;
+
+        Box::new(
+            self.propose_membership(ProposeMembership(vec![], vec![id]))
+                .map(move |_, act: &mut Self, _| act.forget_member(id))
+                .map_err(|err, _, _| RaftorError::from(err)),
+        )
+    }
+}
+
+impl Network {
+    /// Drop the connection and state of a node whose removal was committed.
+    fn forget_member(&mut self, id: NodeId) {
+        if id != self.id {
+            self.nodes.remove(&id);
+            self.nodes_info.remove(&id);
+            self.sessions.remove(&id);
+            self.peer_contact.remove(&id);
+            self.statuses.remove(&id);
+        }
+    }
+}
+
+/// Outcome of a `RequestJoin`.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum JoinStatus {
+    /// The node was proposed as a member and the change is committed
+    Added,
+    /// The join awaits approval through `ApproveJoin`
+    Pending,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum JoinError {
+    /// The join admission policy doesn't let the node in
+    Refused(String),
+    Propose(ProposeError),
+}
+
+/// A node asking to be made a member, admitted under the `join_admission` config.
+pub struct RequestJoin(pub NodeId);
+
+impl Message for RequestJoin {
+    type Result = Result<JoinStatus, JoinError>;
+}
+
+impl Handler<RequestJoin> for Network {
+    type Result = ResponseActFuture<Self, JoinStatus, JoinError>;
+
+    fn handle(&mut self, msg: RequestJoin, _ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(RequestJoin);
+        let id = msg.0;
+
+        if self.raft_members().contains(&id) {
+            return Box::new(fut::ok(JoinStatus::Added));
+        }
+
+        if let Some(reason) = join::refusal(&self.join_admission, self.info.cluster.as_ref(), self.nodes_info.get(&id), &self.raft_members()) {
+            warn!("Refusing the join of node {}: {}", id, reason);
+            return Box::new(fut::err(JoinError::Refused(reason)));
+        }
+
+        if self.join_admission.manual_approval {
+            info!("Join of node {} awaits approval", id);
+            self.pending_joins.entry(id).or_insert_with(now_millis);
+            return Box::new(fut::ok(JoinStatus::Pending));
+        }
+
+        self.admit(id)
+    }
+}
+
+/// A join awaiting approval.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PendingJoin {
+    pub id: NodeId,
+    pub info: Option<NodeInfo>,
+    /// Unix time in milliseconds
+    pub requested_at: u64,
+}
+
+pub struct GetPendingJoins;
+
+impl Message for GetPendingJoins {
+    type Result = Result<Vec<PendingJoin>, ()>;
+}
+
+impl Handler<GetPendingJoins> for Network {
+    type Result = Result<Vec<PendingJoin>, ()>;
+
+    fn handle(&mut self, _: GetPendingJoins, _ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(GetPendingJoins);
+        Ok(self
+            .pending_joins
+            .iter()
+            .map(|(id, requested_at)| PendingJoin {
+                id: *id,
+                info: self.nodes_info.get(id).cloned(),
+                requested_at: *requested_at,
+            })
+            .collect())
+    }
+}
+
+/// Let a pending join in, the policy limits are checked again against the current members.
+pub struct ApproveJoin(pub NodeId);
+
+impl Message for ApproveJoin {
+    type Result = Result<JoinStatus, JoinError>;
+}
+
+impl Handler<ApproveJoin> for Network {
+    type Result = ResponseActFuture<Self, JoinStatus, JoinError>;
+
+    fn handle(&mut self, msg: ApproveJoin, _ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(ApproveJoin);
+        let id = msg.0;
+
+        if self.pending_joins.remove(&id).is_none() {
+            return Box::new(fut::err(JoinError::Refused(format!("node {} has no pending join", id))));
+        }
+
+        if let Some(reason) = join::refusal(&self.join_admission, self.info.cluster.as_ref(), self.nodes_info.get(&id), &self.raft_members()) {
+            return Box::new(fut::err(JoinError::Refused(reason)));
+        }
+
+        info!("Join of node {} approved", id);
+        self.admit(id)
+    }
+}
+
+/// Drop a pending join, answers whether there was one.
+pub struct RejectJoin(pub NodeId);
+
+impl Message for RejectJoin {
+    type Result = bool;
+}
+
+impl Handler<RejectJoin> for Network {
+    type Result = bool;
+
+    fn handle(&mut self, msg: RejectJoin, _ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(RejectJoin);
+        self.pending_joins.remove(&msg.0).is_some()
+    }
+}
+
+impl Network {
+    /// Voters and non-voters of the current raft config.
+    fn raft_members(&self) -> Vec<NodeId> {
+        match self.metrics {
+            Some(ref metrics) => {
+                let config = &metrics.membership_config;
+                let mut members = config.members.clone();
+                members.extend(config.non_voters.iter().filter(|id| !config.members.contains(id)));
+                members
+            }
+            None => Vec::new(),
+        }
+    }
+
+    fn admit(&self, id: NodeId) -> ResponseActFuture<Self, JoinStatus, JoinError> {
+        Box::new(
+            self.propose_membership(ProposeMembership(vec![id], vec![]))
+                .map(|_, _, _| JoinStatus::Added)
+                .map_err(|err, _, _| JoinError::Propose(err)),
+        )
+    }
+}
+
+/// A node as seen from this one.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MemberStatus {
+    pub id: NodeId,
+    pub cluster_addr: Option<String>,
+    /// `leader`, `voter`, `non_voter`, or `peer` for a connected node outside the raft config
+    pub role: String,
+    /// Connection status, `None` for this node
+    pub status: Option<NodeStatus>,
+    /// Milliseconds since raft RPCs to the node last succeeded, only known on the leader
+    pub last_contact_ms: Option<u64>,
+    /// Log state the node announced when joining
+    pub log: Option<LogState>,
+    /// Last log index the node is known to hold, only known on the leader
+    #[serde(default)]
+    pub matched_index: Option<u64>,
+    /// Whether the node is cordoned, see `MemoryStorageData::Cordon`
+    #[serde(default)]
+    pub cordoned: bool,
+    /// The node left the raft membership, its state is kept until `metrics_retention_secs`
+    /// elapsed
+    #[serde(default)]
+    pub stale: bool,
+    /// Traffic, reconnects and last sighting of the node accumulated over the runs of this
+    /// one, `None` for this node
+    #[serde(default)]
+    pub link: Option<LinkTotals>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ClusterStatus {
+    pub node: NodeId,
+    /// Only known on raft members
+    pub raft: Option<RaftState>,
+    pub nodes: Vec<MemberStatus>,
+}
+
+/// Members, leader, term and the state of every node known to this one.
+pub struct GetClusterStatus;
+
+impl Message for GetClusterStatus {
+    type Result = Result<ClusterStatus, ()>;
+}
+
+impl Handler<GetClusterStatus> for Network {
+    type Result = Result<ClusterStatus, ()>;
+
+    fn handle(&mut self, _: GetClusterStatus, _ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(GetClusterStatus);
+        Ok(self.cluster_status())
+    }
+}
+
+/// The cluster graph as seen from this node, see `Topology`.
+pub struct GetTopology;
+
+impl Message for GetTopology {
+    type Result = Result<Topology, ()>;
+}
+
+impl Handler<GetTopology> for Network {
+    type Result = Result<Topology, ()>;
+
+    fn handle(&mut self, _: GetTopology, _ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(GetTopology);
+        Ok(Topology::from_status(&self.cluster_status()))
+    }
+}
+
+impl Network {
+    fn cluster_status(&self) -> ClusterStatus {
+        let raft = self.metrics.as_ref().map(RaftState::from);
+
+        let mut ids = self.nodes.keys().cloned().collect::<BTreeSet<_>>();
+        ids.insert(self.id);
+        if let Some(ref raft) = raft {
+            ids.extend(raft.members.iter().chain(raft.non_voters.iter()));
+        }
+
+        let mut links = self.link_stats.totals(&self.traffic, &self.nodes_connected);
+        let nodes = ids
+            .into_iter()
+            .map(|id| {
+                let role = match raft {
+                    Some(ref raft) if raft.leader == Some(id) => "leader",
+                    Some(ref raft) if raft.members.contains(&id) => "voter",
+                    Some(ref raft) if raft.non_voters.contains(&id) => "non_voter",
+                    _ => "peer",
+                };
+                let cluster_addr = if id == self.id {
+                    Some(self.info.cluster_addr.clone())
+                } else {
+                    self.nodes_info.get(&id).map(|info| info.cluster_addr.clone())
+                };
+
+                MemberStatus {
+                    id: id,
+                    cluster_addr: cluster_addr,
+                    role: role.to_owned(),
+                    status: self.statuses.get(&id).cloned(),
+                    last_contact_ms: self.peer_contact.get(&id).map(|contact| clock::elapsed(*contact).as_millis() as u64),
+                    log: self.peer_logs.get(&id).cloned(),
+                    matched_index: self.peer_matched.get(&id).cloned(),
+                    cordoned: self.cordoned.contains(&id),
+                    stale: self.departed.contains_key(&id),
+                    link: links.remove(&id),
+                }
+            })
+            .collect();
+
+        ClusterStatus {
+            node: self.id,
+            raft: raft,
+            nodes: nodes,
+        }
+    }
+}
+
+/// Last log index held by each raft member, as far as the leader knows. Fails on other nodes.
+pub struct GetFollowerProgress;
+
+impl Message for GetFollowerProgress {
+    type Result = Result<BTreeMap<NodeId, u64>, ()>;
+}
+
+impl Handler<GetFollowerProgress> for Network {
+    type Result = Result<BTreeMap<NodeId, u64>, ()>;
+
+    fn handle(&mut self, _: GetFollowerProgress, _ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(GetFollowerProgress);
+        let leader = self.metrics.as_ref().map(|metrics| metrics.current_leader == Some(self.id)).unwrap_or(false);
+        if !leader {
+            return Err(());
+        }
+
+        Ok(self
+            .raft_members()
+            .into_iter()
+            .filter(|id| *id != self.id)
+            .filter_map(|id| self.peer_matched.get(&id).map(|index| (id, *index)))
+            .collect())
+    }
+}
+
+/// Read the certificates, keys and CAs of the TLS of this network again, answers whether TLS
+/// is configured. New handshakes use them right away, then the connections to the peers and
+/// the sessions they opened are re-established one at a time, `TLS_RECYCLE_INTERVAL` apart,
+/// so they handshake again without the node losing all of its peers at once. Sessions are
+/// resumed and the pending requests sent again. Files which can't be read or parsed leave
+/// the current certificates in place.
+pub struct ReloadTls;
+
+impl Message for ReloadTls {
+    type Result = Result<bool, String>;
+}
+
+impl Handler<ReloadTls> for Network {
+    type Result = Result<bool, String>;
+
+    fn handle(&mut self, _: ReloadTls, ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(ReloadTls);
+        self.reload_tls(ctx)
+    }
+}
+
+impl Network {
+    fn reload_tls(&mut self, ctx: &mut Context<Self>) -> Result<bool, String> {
+        let mut reloaded = false;
+        for tls in self.tls.iter().chain(self.client_tls.iter()) {
+            tls.reload().map_err(|err| err.to_string())?;
+            reloaded = true;
+        }
+        if !reloaded {
+            return Ok(false);
+        }
+        info!("Node {} reloaded its TLS certificates", self.id);
+
+        // client connections pick the new certificates up as they reconnect
+        if self.tls.is_none() {
+            return Ok(true);
+        }
+
+        let peers = self.nodes.keys().chain(self.sessions.keys()).cloned().collect::<BTreeSet<_>>();
+        for (step, id) in peers.into_iter().enumerate() {
+            ctx.run_later(TLS_RECYCLE_INTERVAL * step as u32, move |act, _| {
+                if let Some(node) = act.nodes.get(&id) {
+                    node.rehandshake();
+                }
+                if let Some(session) = act.sessions.get(&id) {
+                    mailbox::deliver_to(session, CloseSession, Mailbox::Session);
+                }
+            });
+        }
+        Ok(true)
+    }
+
+    /// Reload the TLS files once they changed, e.g. renewed by a certificate manager.
+    fn check_tls_files(&mut self, ctx: &mut Context<Self>) {
+        if !self.tls.iter().chain(self.client_tls.iter()).any(PeerTls::changed) {
+            return;
+        }
+        if let Err(err) = self.reload_tls(ctx) {
+            warn!("Node {} can't reload its TLS certificates, keeping the current ones: {}", self.id, err);
+        }
+    }
+}
+
+/// Hand leadership over to another voter: the leader stops sending raft RPCs so the first
+/// caught up voter to time out takes over, and rejoins as its follower. Answers with the new
+/// leader. actix-raft has no way to start an election on a given node, so the successor can't
+/// be chosen.
+pub struct TransferLeadership;
+
+impl Message for TransferLeadership {
+    type Result = Result<NodeId, ProposeError>;
+}
+
+impl Handler<TransferLeadership> for Network {
+    type Result = ResponseActFuture<Self, NodeId, ProposeError>;
+
+    fn handle(&mut self, _: TransferLeadership, _ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(TransferLeadership);
+        let (leader, voters) = match self.metrics {
+            Some(ref metrics) => (metrics.current_leader, metrics.membership_config.members.len()),
+            None => (None, 0),
+        };
+
+        if leader != Some(self.id) {
+            return Box::new(fut::err(ProposeError::NotLeader { leader_hint: leader }));
+        }
+
+        // no other voter could take over, or this node already stepped down
+        if voters < 2 || self.isolated_nodes.contains(&self.id) {
+            return Box::new(fut::err(ProposeError::Rejected));
+        }
+
+        info!("Leader {} stepping aside to transfer leadership", self.id);
+        self.transferring = true;
+        self.isolated_nodes.push(self.id);
+        let _ = self.raft.isolation.do_send(LeaderIsolated(true));
+        self.history.note("stepped aside to transfer leadership");
+
+        let deadline = clock::now() + self.election_timeout_min * 3;
+
+        Box::new(self.await_successor(deadline).then(|res, act: &mut Self, _| {
+            // unless it stepped down for good meanwhile
+            if act.transferring {
+                act.transferring = false;
+                act.isolated_nodes.retain(|id| *id != act.id);
+                let _ = act.raft.isolation.do_send(LeaderIsolated(false));
+            }
+            fut::result(res)
+        }))
+    }
+}
+
+impl Network {
+    /// Resolve with the leader elected while this node stepped aside.
+    fn await_successor(&self, deadline: Instant) -> Box<dyn ActorFuture<Actor = Self, Item = NodeId, Error = ProposeError>> {
+        match self.metrics.as_ref().and_then(|metrics| metrics.current_leader) {
+            Some(leader) if leader != self.id => return Box::new(fut::ok(leader)),
+            _ => {}
+        }
+
+        if clock::now() >= deadline {
+            warn!("No leader took over from {} in time", self.id);
+            return Box::new(fut::err(ProposeError::Failed));
+        }
+
+        Box::new(
+            fut::wrap_future::<_, Self>(clock::delay(READ_INDEX_POLL))
+                .map_err(|_, _, _| ProposeError::Failed)
+                .and_then(move |_, act: &mut Self, _| act.await_successor(deadline)),
+        )
+    }
+}
+
+/// Leave the cluster before stopping the process: refuse new connections, wait for the
+/// proposals this node accepted to be applied, hand leadership over when leading, tell the
+/// peers so they drop their sessions right away instead of waiting for this node to resume
+/// them, then stop raft, the peer connections and sessions and this network. As with
+/// `TransferLeadership` the successor is the first caught up voter to time out.
+pub struct Shutdown;
+
+impl Message for Shutdown {
+    type Result = Result<(), ()>;
+}
+
+impl Handler<Shutdown> for Network {
+    type Result = ResponseActFuture<Self, (), ()>;
+
+    fn handle(&mut self, _: Shutdown, _ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(Shutdown);
+        info!("Node {} shutting down its {:?} network", self.id, self.net_type);
+        self.shutting_down = true;
+
+        let leading = self.net_type == NetworkType::Cluster
+            && self.metrics.as_ref().and_then(|metrics| metrics.current_leader) == Some(self.id);
+        let deadline = clock::now() + SHUTDOWN_DRAIN;
+
+        Box::new(
+            self.drain_proposals(deadline)
+                .and_then(move |_, act: &mut Self, ctx| {
+                    if !leading {
+                        return fut::Either::A(fut::ok(()));
+                    }
+
+                    act.history.note("shutting down");
+                    fut::Either::B(fut::wrap_future::<_, Self>(ctx.address().send(TransferLeadership)).then(
+                        |res, act: &mut Self, _| {
+                            match res {
+                                Ok(Ok(leader)) => info!("Node {} took over leadership from {}", leader, act.id),
+                                _ => warn!("Node {} stops without handing leadership over", act.id),
+                            }
+                            fut::ok(())
+                        },
+                    ))
+                })
+                .map(|_, act, ctx| {
+                    if let Some(ref crash) = act.crash {
+                        crash.mark_clean();
+                    }
+                    act.stop_all(ctx)
+                }),
+        )
+    }
+}
+
+/// Stop raft, the peer connections and sessions and this network right away, as a crash
+/// would: proposals in flight are dropped and leadership isn't handed over. `Kill` sends it to
+/// nodes whose actors share the arbiter of the system, which it can't stop.
+pub struct Halt;
+
+impl Message for Halt {
+    type Result = ();
+}
+
+impl Handler<Halt> for Network {
+    type Result = ();
+
+    fn handle(&mut self, _: Halt, ctx: &mut Context<Self>) {
+        crate::audit_message!(Halt);
+        info!("Node {} halting its {:?} network", self.id, self.net_type);
+        self.shutting_down = true;
+        self.stop_all(ctx);
+    }
+}
+
+/// Resolves once the leader this node heard from before restarting is connected again, right
+/// away unless the node restarted within an election timeout of hearing from it. Sent before
+/// raft starts, so a follower restarted quickly doesn't stand for election before its leader
+/// reached it.
+pub struct AwaitLeader;
+
+impl Message for AwaitLeader {
+    type Result = Result<(), ()>;
+}
+
+impl Handler<AwaitLeader> for Network {
+    type Result = ResponseActFuture<Self, (), ()>;
+
+    fn handle(&mut self, _: AwaitLeader, _ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(AwaitLeader);
+        let (leader, hold) = match self.contact.as_ref().and_then(|contact| contact.hold(self.election_timeout_max)) {
+            Some(hold) => hold,
+            None => return Box::new(fut::ok(())),
+        };
+
+        info!("Node {} restarted shortly after hearing from leader {}, waiting up to {}ms for it before starting raft", self.id, leader, hold.as_millis());
+        self.wait_for_peer(leader, clock::now() + hold)
+    }
+}
+
+impl Network {
+    /// Resolve once the peer is connected, or at the deadline.
+    fn wait_for_peer(&self, peer: NodeId, deadline: Instant) -> Box<dyn ActorFuture<Actor = Self, Item = (), Error = ()>> {
+        if self.nodes_connected.contains(&peer) {
+            return Box::new(fut::ok(()));
+        }
+
+        if clock::now() >= deadline {
+            warn!("Node {} starts raft without hearing from leader {}", self.id, peer);
+            return Box::new(fut::ok(()));
+        }
+
+        Box::new(
+            fut::wrap_future::<_, Self>(clock::delay(READ_INDEX_POLL))
+                .map_err(|_, _, _| ())
+                .and_then(move |_, act: &mut Self, _| act.wait_for_peer(peer, deadline)),
+        )
+    }
+}
+
+impl Network {
+    /// Resolve once the client proposals accepted by this node were applied, or at the
+    /// deadline.
+    fn drain_proposals(&self, deadline: Instant) -> Box<dyn ActorFuture<Actor = Self, Item = (), Error = ()>> {
+        if self.net_type != NetworkType::Cluster {
+            return Box::new(fut::ok(()));
+        }
+
+        Box::new(
+            fut::wrap_future::<_, Self>(self.raft.pending_proposals.send(GetPendingProposals))
+                .map_err(|_, _, _| ())
+                .and_then(move |res, act: &mut Self, _| {
+                    let pending = res.map(|stats| stats.count).unwrap_or(0);
+
+                    if pending == 0 {
+                        return fut::Either::A(fut::ok(()));
+                    }
+
+                    if clock::now() >= deadline {
+                        warn!("Node {} stops with {} proposals pending", act.id, pending);
+                        return fut::Either::A(fut::ok(()));
+                    }
+
+                    fut::Either::B(
+                        fut::wrap_future::<_, Self>(clock::delay(READ_INDEX_POLL))
+                            .map_err(|_, _, _| ())
+                            .and_then(move |_, act: &mut Self, _| act.drain_proposals(deadline)),
+                    )
+                }),
+        )
+    }
+
+    /// Stop raft, then the peer connections, which tell their peer this node leaves, and the
+    /// peer sessions. Stopping the network closes its listener.
+    fn stop_all(&mut self, ctx: &mut Context<Self>) {
+        if self.net_type == NetworkType::Cluster {
+            let _ = self.raft.stop.do_send(StopRaft);
+        }
+
+        for node in self.nodes.values() {
+            node.leave();
+        }
+
+        for session in self.sessions.values() {
+            mailbox::deliver_to(session, CloseSession, Mailbox::Session);
+        }
+
+        info!("Node {} stopped its {:?} network", self.id, self.net_type);
+        ctx.stop();
+    }
+}
+
+impl Network {
+    /// Propose a membership change on the leader, through its peer connection when this node
+    /// is a follower.
+    fn propose_membership(
+        &self,
+        msg: ProposeMembership,
+    ) -> Box<dyn ActorFuture<Actor = Self, Item = (), Error = ProposeError>> {
+        let leader = self.metrics.as_ref().and_then(|metrics| metrics.current_leader);
+
+        let res: Box<dyn Future<Item = _, Error = MailboxError>> = match leader {
+            Some(leader) if leader == self.id => Box::new(self.raft.memberships.send(msg)),
+            Some(leader) => match self.nodes.get(&leader) {
+                Some(node) => node.send(msg),
+                None => return Box::new(fut::err(ProposeError::NotLeader { leader_hint: Some(leader) })),
+            },
+            None => return Box::new(fut::err(ProposeError::NotLeader { leader_hint: None })),
+        };
+
+        Box::new(fut::wrap_future(res).then(|res, _, _| fut::result(res.unwrap_or(Err(ProposeError::Failed)))))
+    }
+}
+
+/// Subscribe to (term, commit index, applied index) updates of this node.
+#[derive(Message)]
+pub struct SubscribeProgress(pub Recipient<ClusterProgress>);
+
+impl Handler<SubscribeProgress> for Network {
+    type Result = ();
+
+    fn handle(&mut self, msg: SubscribeProgress, _ctx: &mut Context<Self>) {
+        crate::audit_message!(SubscribeProgress);
+        self.progress.subscribe(msg.0);
+    }
+}
+
+/// Resolve once this node has applied the given index, failing with `ReadError::Timeout` if it
+/// didn't within the given time.
+pub struct WaitForApplied(pub u64, pub Duration);
+
+impl Message for WaitForApplied {
+    type Result = Result<ClusterProgress, ReadError>;
+}
+
+impl Handler<WaitForApplied> for Network {
+    type Result = Response<ClusterProgress, ReadError>;
+
+    fn handle(&mut self, msg: WaitForApplied, _ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(WaitForApplied);
+        Response::fut(self.progress.wait_for(msg.0, msg.1))
+    }
+}
+
+/// A leader told this follower entries it holds are committed up to the index.
+#[derive(Message)]
+pub struct LeaderCommit(pub u64);
+
+impl Handler<LeaderCommit> for Network {
+    type Result = ();
+
+    fn handle(&mut self, msg: LeaderCommit, _ctx: &mut Context<Self>) {
+        crate::audit_message!(LeaderCommit);
+        if msg.0 > self.leader_commit {
+            self.leader_commit = msg.0;
+            self.publish_progress();
+        }
+    }
+}
+
+/// Outcome of validating a membership change without proposing it.
+#[derive(Serialize, Debug)]
+pub struct MembershipReport {
+    pub members: Vec<NodeId>,
+    pub resulting_members: Vec<NodeId>,
+    /// Resulting members this node currently can't reach
+    pub unreachable: Vec<NodeId>,
+    /// Whether the reachable resulting members still form a quorum
+    pub quorum_safe: bool,
+    /// Nodes to add which this node has no connection to
+    pub not_connected: Vec<NodeId>,
+    /// Nodes to add which announced a version incompatible with ours, `None` when unknown
+    pub incompatible: Vec<(NodeId, Option<String>)>,
+    /// Placement rules the resulting members would break
+    pub placement_violations: Vec<String>,
+    pub valid: bool,
+}
+
+/// Check whether adding and removing the given nodes is safe, without proposing the change.
+pub struct ValidateMembershipChange(pub Vec<NodeId>, pub Vec<NodeId>);
+
+impl Message for ValidateMembershipChange {
+    type Result = Result<MembershipReport, ()>;
+}
+
+impl Handler<ValidateMembershipChange> for Network {
+    type Result = Result<MembershipReport, ()>;
+
+    fn handle(&mut self, msg: ValidateMembershipChange, _ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(ValidateMembershipChange);
+        self.membership_report(&msg.0, &msg.1).ok_or(())
+    }
+}
+
+impl Network {
+    /// Validate adding and removing the given nodes, `None` before raft reported metrics.
+    fn membership_report(&self, add: &[NodeId], remove: &[NodeId]) -> Option<MembershipReport> {
+        let members = match self.metrics {
+            Some(ref metrics) => metrics.membership_config.members.clone(),
+            None => return None,
+        };
+
+        let mut resulting_members = members
+            .iter()
+            .filter(|id| !remove.contains(id))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for id in add.iter() {
+            if !resulting_members.contains(id) {
+                resulting_members.push(*id);
+            }
+        }
+
+        let reachable = |id: &NodeId| {
+            *id == self.id || (self.nodes.contains_key(id) && !self.isolated_nodes.contains(id))
+        };
+
+        let unreachable = resulting_members
+            .iter()
+            .filter(|id| !reachable(id))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let quorum = resulting_members.len() / 2 + 1;
+        let quorum_safe = resulting_members.len() - unreachable.len() >= quorum;
+
+        let not_connected = add
+            .iter()
+            .filter(|id| !reachable(id))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let incompatible = add
+            .iter()
+            .filter(|id| **id != self.id)
+            .filter_map(|id| match self.peer_versions.get(id) {
+                Some(version) if is_compatible(version) => None,
+                version => Some((*id, version.cloned())),
+            })
+            .collect::<Vec<_>>();
+
+        let labelled = resulting_members
+            .iter()
+            .map(|id| (*id, self.nodes_info.get(id)))
+            .collect::<Vec<_>>();
+        let placement_violations = placement::violations(&self.placement, &labelled);
+
+        let valid = quorum_safe
+            && not_connected.is_empty()
+            && incompatible.is_empty()
+            && placement_violations.is_empty();
+
+        Some(MembershipReport {
+            members: members,
+            resulting_members: resulting_members,
+            unreachable: unreachable,
+            quorum_safe: quorum_safe,
+            not_connected: not_connected,
+            incompatible: incompatible,
+            placement_violations: placement_violations,
+            valid: valid,
+        })
+    }
+}
+
+/// A prepared membership change awaiting confirmation.
+struct StagedChange {
+    add: Vec<NodeId>,
+    remove: Vec<NodeId>,
+    /// Members when the change was prepared, a change to them voids the confirmation
+    members: Vec<NodeId>,
+    /// Unix time in milliseconds
+    expires_at: u64,
+}
+
+/// Projected effect of a prepared membership change.
+#[derive(Serialize, Debug)]
+pub struct PreparedChange {
+    /// Confirms the change with `ConfirmMembershipChange`
+    pub token: u64,
+    pub add: Vec<NodeId>,
+    pub remove: Vec<NodeId>,
+    pub report: MembershipReport,
+    pub quorum_before: usize,
+    pub quorum_after: usize,
+    /// Members the cluster can lose after the change and keep its quorum, counting the
+    /// unreachable ones as lost already. Negative when the quorum is lost.
+    pub fault_tolerance: i64,
+    /// Resulting members this node can't reach, and the removed leader
+    pub at_risk: Vec<NodeId>,
+    /// Unix time in milliseconds the change must be confirmed by
+    pub expires_at: u64,
+}
+
+/// Stage adding and removing the given nodes and report the projected effect, the change is
+/// only proposed once confirmed with the token of the answer within
+/// `membership_confirm_timeout_ms`. Staged on the node it's sent to, which confirms it.
+pub struct PrepareMembershipChange(pub Vec<NodeId>, pub Vec<NodeId>);
+
+impl Message for PrepareMembershipChange {
+    type Result = Result<PreparedChange, ()>;
+}
+
+impl Handler<PrepareMembershipChange> for Network {
+    type Result = Result<PreparedChange, ()>;
+
+    fn handle(&mut self, msg: PrepareMembershipChange, _ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(PrepareMembershipChange);
+        let (add, remove) = (msg.0, msg.1);
+        let report = self.membership_report(&add, &remove).ok_or(())?;
+        let now = now_millis();
+        self.staged_changes.retain(|_, change| change.expires_at > now);
+
+        let leader = self.metrics.as_ref().and_then(|metrics| metrics.current_leader);
+        let mut at_risk = report.unreachable.clone();
+        if let Some(leader) = leader.filter(|leader| remove.contains(leader)) {
+            at_risk.push(leader);
+        }
+        let quorum_after = report.resulting_members.len() / 2 + 1;
+        let reachable = report.resulting_members.len() - report.unreachable.len();
+
+        self.next_change_token += 1;
+        let token = self.next_change_token;
+        let expires_at = now + self.membership_confirm_timeout.as_millis() as u64;
+        warn!(
+            "Membership change {} prepared, adding {:?} and removing {:?}, awaiting confirmation for {:?}",
+            token, add, remove, self.membership_confirm_timeout
+        );
+        self.staged_changes.insert(
+            token,
+            StagedChange {
+                add: add.clone(),
+                remove: remove.clone(),
+                members: sorted(&report.members),
+                expires_at: expires_at,
+            },
+        );
+
+        Ok(PreparedChange {
+            token: token,
+            add: add,
+            remove: remove,
+            quorum_before: report.members.len() / 2 + 1,
+            quorum_after: quorum_after,
+            fault_tolerance: reachable as i64 - quorum_after as i64,
+            at_risk: at_risk,
+            expires_at: expires_at,
+            report: report,
+        })
+    }
+}
+
+fn sorted(members: &[NodeId]) -> Vec<NodeId> {
+    let mut members = members.to_vec();
+    members.sort();
+    members
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum ConfirmError {
+    /// No change was prepared with the token on this node, or it was confirmed already
+    Unknown,
+    /// The change wasn't confirmed within `membership_confirm_timeout_ms`
+    Expired,
+    /// The membership changed since the change was prepared, prepare it again
+    Stale,
+    Propose(ProposeError),
+}
+
+/// Propose a membership change prepared with `PrepareMembershipChange`, answers once it's
+/// committed.
+pub struct ConfirmMembershipChange(pub u64);
+
+impl Message for ConfirmMembershipChange {
+    type Result = Result<(), ConfirmError>;
+}
+
+impl Handler<ConfirmMembershipChange> for Network {
+    type Result = ResponseActFuture<Self, (), ConfirmError>;
+
+    fn handle(&mut self, msg: ConfirmMembershipChange, _ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(ConfirmMembershipChange);
+        let change = match self.staged_changes.remove(&msg.0) {
+            Some(change) => change,
+            None => return Box::new(fut::err(ConfirmError::Unknown)),
+        };
+
+        if change.expires_at <= now_millis() {
+            warn!("Membership change {} wasn't confirmed in time, dropping it", msg.0);
+            return Box::new(fut::err(ConfirmError::Expired));
+        }
+        let members = self.metrics.as_ref().map(|metrics| sorted(&metrics.membership_config.members));
+        if members.as_ref() != Some(&change.members) {
+            warn!("The membership changed since change {} was prepared, dropping it", msg.0);
+            return Box::new(fut::err(ConfirmError::Stale));
+        }
+
+        info!("Membership change {} confirmed, adding {:?} and removing {:?}", msg.0, change.add, change.remove);
+        let removed = change.remove.clone();
+        Box::new(
+            self.propose_membership(ProposeMembership(change.add, change.remove))
+                .map_err(|err, _, _| ConfirmError::Propose(err))
+                .map(move |_, act: &mut Self, _| {
+                    for id in removed {
+                        act.forget_member(id);
+                    }
+                }),
+        )
+    }
+}
+
+/// Peers are compatible when they share our major version, or major and minor before 1.0
+fn is_compatible(version: &str) -> bool {
+    let parse = |v: &str| v.split('.').take(2).map(|part| part.to_owned()).collect::<Vec<_>>();
+    let ours = parse(VERSION);
+    let theirs = parse(version);
+
+    if ours.get(0) != theirs.get(0) {
+        return false;
+    }
+
+    ours.get(0).map(|major| major.as_str()) != Some("0") || ours.get(1) == theirs.get(1)
+}
+
+/// Signed descriptor of the cluster handed to clients for bootstrapping.
+pub struct GetClusterDescriptor;
+
+impl Message for GetClusterDescriptor {
+    type Result = Result<ClusterDescriptor, ()>;
+}
+
+impl Handler<GetClusterDescriptor> for Network {
+    type Result = Result<ClusterDescriptor, ()>;
+
+    fn handle(&mut self, _: GetClusterDescriptor, _ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(GetClusterDescriptor);
+        let config = match self.descriptor {
+            Some(ref config) => config,
+            None => return Err(()),
+        };
+
+        let (ids, leader, term) = match self.metrics {
+            Some(ref metrics) => (
+                metrics.membership_config.members.clone(),
+                metrics.current_leader,
+                metrics.current_term,
+            ),
+            None => (self.nodes_info.keys().cloned().collect(), None, 0),
+        };
+
+        let members = ids
+            .into_iter()
+            .filter_map(|id| {
+                let info = if id == self.id { Some(&self.info) } else { self.nodes_info.get(&id) };
+                info.map(|info| DescriptorMember {
+                    id: id,
+                    public_addr: info.public_addr.clone(),
+                    cordoned: self.cordoned.contains(&id),
+                })
+            })
+            .collect();
+
+        let mut descriptor = ClusterDescriptor {
+            members: members,
+            leader: leader,
+            term: term,
+            issued_at: now_millis(),
+            ttl_secs: config.ttl_secs,
+            signature: None,
+        };
+
+        if let Some(ref secret) = config.secret {
+            descriptor.sign(secret);
+        }
+
+        Ok(descriptor)
+    }
+}
+
+/// Term changes and leadership transitions observed by this node since the given unix time
+/// in milliseconds, oldest first.
+pub struct GetLeadershipHistory(pub u64);
+
+impl Message for GetLeadershipHistory {
+    type Result = Result<Vec<LeadershipEvent>, ()>;
+}
+
+impl Handler<GetLeadershipHistory> for Network {
+    type Result = Result<Vec<LeadershipEvent>, ()>;
+
+    fn handle(&mut self, msg: GetLeadershipHistory, _ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(GetLeadershipHistory);
+        Ok(self.history.since(msg.0))
+    }
+}
+
+/// Nodes cordoned as of the entries applied so far.
+#[derive(Message)]
+pub struct CordonChanged(pub BTreeSet<NodeId>);
+
+impl Handler<CordonChanged> for Network {
+    type Result = ();
+
+    fn handle(&mut self, msg: CordonChanged, ctx: &mut Context<Self>) {
+        crate::audit_message!(CordonChanged);
+        if self.cordoned == msg.0 {
+            return;
+        }
+
+        info!("Cordoned nodes: {:?}", msg.0);
+        self.cordoned = msg.0;
+        self.step_aside_if_cordoned(ctx);
+    }
+}
+
+impl Network {
+    /// Whether the node is cordoned, a cordoned node doesn't ask for votes.
+    pub(crate) fn is_cordoned(&self, id: NodeId) -> bool {
+        self.cordoned.contains(&id)
+    }
+
+    /// Hand leadership over when leading while cordoned, e.g. right after being cordoned.
+    fn step_aside_if_cordoned(&mut self, ctx: &mut Context<Self>) {
+        let leading = self.metrics.as_ref().map(|metrics| metrics.current_leader == Some(self.id)).unwrap_or(false);
+        if leading && self.is_cordoned(self.id) && !self.transferring && !self.isolated_nodes.contains(&self.id) {
+            info!("Leader {} is cordoned, handing leadership over", self.id);
+            ctx.notify(TransferLeadership);
+        }
+    }
+}
+
+impl Network {
+    fn leadership_priority(&self, id: NodeId) -> u32 {
+        let addr = if id == self.id {
+            Some(&self.info.cluster_addr)
+        } else {
+            self.nodes_info.get(&id).map(|info| &info.cluster_addr)
+        };
+        addr.and_then(|addr| self.leadership_priority.get(addr)).cloned().unwrap_or(0)
+    }
+
+    /// Hand leadership over when a connected, caught up voter has a higher priority than this
+    /// leader. Its shorter election timeout usually makes it the successor, a transfer which
+    /// landed elsewhere isn't retried for a while.
+    fn yield_to_priority(&mut self, ctx: &mut Context<Self>) {
+        if self.leadership_priority.is_empty() || self.transferring || self.isolated_nodes.contains(&self.id) {
+            return;
+        }
+        let metrics = match self.metrics {
+            Some(ref metrics) if metrics.current_leader == Some(self.id) => metrics,
+            _ => return,
+        };
+        if self.priority_transfer.map_or(false, |at| clock::elapsed(at) < self.election_timeout_max * PRIORITY_TRANSFER_BACKOFF) {
+            return;
+        }
+
+        let own = self.leadership_priority(self.id);
+        let last_log_index = metrics.last_log_index;
+        let preferred = metrics.membership_config.members.iter().cloned().find(|id| {
+            *id != self.id
+                && self.leadership_priority(*id) > own
+                && !self.is_cordoned(*id)
+                && self.statuses.get(id) == Some(&NodeStatus::Connected)
+                && self.peer_matched.get(id).map_or(false, |matched| *matched >= last_log_index)
+        });
+
+        if let Some(preferred) = preferred {
+            info!("Leader {} hands leadership over to node {} of higher priority", self.id, preferred);
+            self.priority_transfer = Some(clock::now());
+            ctx.notify(TransferLeadership);
+        }
+    }
+}
+
+/// Cluster metadata applied from the log, which this node announces from then on.
+#[derive(Message)]
+pub struct MetadataChanged(pub ClusterMetadata);
+
+impl Handler<MetadataChanged> for Network {
+    type Result = ();
+
+    fn handle(&mut self, msg: MetadataChanged, _ctx: &mut Context<Self>) {
+        crate::audit_message!(MetadataChanged);
+        if self.info.cluster.as_ref() != Some(&msg.0) {
+            info!("Cluster metadata changed, the cluster is named {}", msg.0.name);
+            self.info.cluster = Some(msg.0);
+        }
+    }
+}
+
+/// The cluster metadata known to this node, replicated or from its config.
+pub struct GetClusterMetadata;
+
+impl Message for GetClusterMetadata {
+    type Result = Option<ClusterMetadata>;
+}
+
+impl Handler<GetClusterMetadata> for Network {
+    type Result = Option<ClusterMetadata>;
+
+    fn handle(&mut self, _msg: GetClusterMetadata, _ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(GetClusterMetadata);
+        self.info.cluster.clone()
+    }
+}
+
+/// The replicated address book changed the addresses of a node.
+#[derive(Message)]
+pub struct AddressChanged(pub NodeId, pub NodeInfo);
+
+impl Handler<AddressChanged> for Network {
+    type Result = ();
+
+    fn handle(&mut self, msg: AddressChanged, ctx: &mut Context<Self>) {
+        crate::audit_message!(AddressChanged);
+        let (id, info) = (msg.0, msg.1);
+
+        if id == self.id {
+            if self.info.cluster_addr != info.cluster_addr {
+                warn!("This node moved to {}, restart it listening on its new address", info.cluster_addr);
+            }
+            self.info = info;
+            return;
+        }
+
+        if self.nodes_info.get(&id) == Some(&info) {
+            return;
+        }
+
+        // reconnect to the node on its new address
+        debug!("Node {} moved to {}", id, info.cluster_addr);
+        self.nodes_info.insert(id, info.clone());
+        self.nodes.remove(&id);
+        if self.address.is_some() {
+            self.register_node(id, &info, ctx.address());
+        }
+    }
+}
+
+/// Plan the migration of the given nodes to new addresses, fails while another migration is
+/// underway or when a node is unknown.
+pub struct StartAddressMigration(pub Vec<(NodeId, NodeInfo)>);
+
+impl Message for StartAddressMigration {
+    type Result = Result<AddressMigration, ()>;
+}
+
+impl Handler<StartAddressMigration> for Network {
+    type Result = Result<AddressMigration, ()>;
+
+    fn handle(&mut self, msg: StartAddressMigration, _ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(StartAddressMigration);
+        if self.migration.as_ref().map(|migration| !migration.is_finished()).unwrap_or(false) {
+            warn!("An address migration is already underway");
+            return Err(());
+        }
+
+        let mut steps = Vec::new();
+        for (id, to) in msg.0 {
+            let from = if id == self.id {
+                self.info.clone()
+            } else {
+                match self.nodes_info.get(&id) {
+                    Some(info) => info.clone(),
+                    None => {
+                        warn!("Cannot migrate the address of unknown node {}", id);
+                        return Err(());
+                    }
+                }
+            };
+
+            steps.push(MigrationStep {
+                id: id,
+                from: from,
+                to: to,
+                state: MigrationStepState::Pending,
+            });
+        }
+
+        let migration = AddressMigration::new(steps);
+        self.migration = Some(migration.clone());
+        Ok(migration)
+    }
+}
+
+/// Run the next step of the address migration: check the previously migrated node is back on
+/// its new address, that the next node is reachable on its new one, then change its address in
+/// the replicated address book. A failed step is reported in the migration and can be retried.
+pub struct MigrateNextAddress;
+
+impl Message for MigrateNextAddress {
+    type Result = Result<AddressMigration, ()>;
+}
+
+impl Handler<MigrateNextAddress> for Network {
+    type Result = ResponseActFuture<Self, AddressMigration, ()>;
+
+    fn handle(&mut self, _: MigrateNextAddress, _ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(MigrateNextAddress);
+        let migration = match self.migration {
+            Some(ref migration) if !migration.in_progress() => migration.clone(),
+            _ => return Box::new(fut::err(())),
+        };
+
+        let step = match migration.next() {
+            Some(step) => step,
+            None => return Box::new(fut::ok(migration)),
+        };
+        let target = migration.steps[step].clone();
+
+        let reconnected: Box<dyn Future<Item = (), Error = String>> = match migration.previous(step) {
+            Some(previous) if previous.id != self.id => {
+                let (id, addr) = (previous.id, previous.to.cluster_addr.clone());
+                match self.nodes.get(&id) {
+                    Some(node) => Box::new(
+                        node.info()
+                            .map_err(|err| err.to_string())
+                            .and_then(move |info| {
+                                if info.state == NodeState::Connected && info.address == addr {
+                                    Ok(())
+                                } else {
+                                    Err(format!("node {} has not reconnected on {} yet", id, addr))
+                                }
+                            }),
+                    ),
+                    None => Box::new(futures::future::err(format!("node {} is not connected", id))),
+                }
+            }
+            _ => Box::new(futures::future::ok(())),
+        };
+
+        let addr = target.to.cluster_addr.clone();
+        let resolver = self.resolver.clone();
+        let reachable = web::block(move || {
+            let addrs = resolver.lookup(&addr).map_err(|err| format!("{} could not be resolved: {}", addr, err))?;
+            Ok((addr, addrs[0]))
+        })
+        .map_err(|err| match err {
+            BlockingError::Error(err) => err,
+            BlockingError::Canceled => "resolving the address was canceled".to_owned(),
+        })
+        .and_then(|(addr, socket_addr)| {
+            Timeout::new(TcpStream::connect(&socket_addr), MIGRATION_CONNECT_TIMEOUT)
+                .map(|_| ())
+                .map_err(move |err| format!("{} is unreachable: {:?}", addr, err))
+        });
+
+        let proposal = ClientProposal {
+            client: "admin".to_owned(),
+            key: None,
+            data: MemoryStorageData::SetAddress(target.id, target.to),
+            deadline_ms: None,
+        };
+        let proposals = self.raft.proposals.clone();
+        let proposed = reconnected.and_then(|_| reachable).and_then(move |_| {
+            proposals.send(proposal)
+                .map_err(|err| err.to_string())
+                .and_then(|res| match res {
+                    Ok(ClientPayloadResponse::Applied { index, .. })
+                    | Ok(ClientPayloadResponse::Committed { index }) => Ok(index),
+                    Err(_) => Err("the new address could not be committed".to_owned()),
+                })
+        });
+
+        self.migration.as_mut().unwrap().set_state(step, MigrationStepState::InProgress);
+        Box::new(fut::wrap_future::<_, Self>(proposed).then(move |res, act, _| {
+            let state = match res {
+                Ok(index) => MigrationStepState::Applied(index),
+                Err(err) => {
+                    warn!("Address migration of node {} failed: {}", target.id, err);
+                    MigrationStepState::Failed(err)
+                }
+            };
+
+            if let Some(ref mut migration) = act.migration {
+                migration.set_state(step, state);
+            }
+            fut::result(act.migration.clone().ok_or(()))
+        }))
+    }
+}
+
+/// The current or last address migration.
+pub struct GetAddressMigration;
+
+impl Message for GetAddressMigration {
+    type Result = Result<Option<AddressMigration>, ()>;
+}
+
+impl Handler<GetAddressMigration> for Network {
+    type Result = Result<Option<AddressMigration>, ()>;
+
+    fn handle(&mut self, _: GetAddressMigration, _ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(GetAddressMigration);
+        Ok(self.migration.clone())
+    }
+}
+
+/// Abandon the address migration, the steps already applied stay in the address book.
+#[derive(Message)]
+pub struct AbortAddressMigration;
+
+impl Handler<AbortAddressMigration> for Network {
+    type Result = ();
+
+    fn handle(&mut self, _: AbortAddressMigration, _ctx: &mut Context<Self>) {
+        crate::audit_message!(AbortAddressMigration);
+        self.migration = None;
+    }
+}
+
+/// Events recorded by this node after the given sequence number.
+pub struct GetEvents(pub u64);
+
+impl Message for GetEvents {
+    type Result = Result<Vec<ClusterEvent>, ()>;
+}
+
+impl Handler<GetEvents> for Network {
+    type Result = Result<Vec<ClusterEvent>, ()>;
+
+    fn handle(&mut self, msg: GetEvents, _ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(GetEvents);
+        Ok(self.events.since(msg.0))
+    }
+}
+
+impl Network {
+    /// Record leader and membership changes since the previous metrics.
+    fn record_metrics_events(&self, metrics: &RaftMetrics) {
+        let previous = self.metrics.as_ref();
+
+        if previous.map(|prev| (prev.current_term, prev.current_leader)) != Some((metrics.current_term, metrics.current_leader)) {
+            self.events.record(EventKind::LeaderChanged {
+                term: metrics.current_term,
+                leader: metrics.current_leader,
+            });
+        }
+
+        let membership = &metrics.membership_config;
+        let changed = previous
+            .map(|prev| prev.membership_config.members != membership.members || prev.membership_config.non_voters != membership.non_voters)
+            .unwrap_or(true);
+
+        if changed {
+            self.events.record(EventKind::MembershipChanged {
+                members: membership.members.clone(),
+                non_voters: membership.non_voters.clone(),
+            });
+        }
+    }
+}
+
+/// Metrics of this network, with the raft state and write metrics on the cluster network.
+pub struct GetNodeMetrics;
+
+impl Message for GetNodeMetrics {
+    type Result = Result<NodeMetrics, ()>;
+}
+
+impl Handler<GetNodeMetrics> for Network {
+    type Result = ResponseActFuture<Self, NodeMetrics, ()>;
+
+    fn handle(&mut self, _: GetNodeMetrics, _ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(GetNodeMetrics);
+        self.node_metrics()
+    }
+}
+
+/// Receive the metrics of this network with every raft metrics update.
+#[derive(Message)]
+pub struct SubscribeMetrics(pub Recipient<NodeMetrics>);
+
+impl Handler<SubscribeMetrics> for Network {
+    type Result = ();
+
+    fn handle(&mut self, msg: SubscribeMetrics, _ctx: &mut Context<Self>) {
+        crate::audit_message!(SubscribeMetrics);
+        self.metrics_subscribers.push(msg.0);
+    }
+}
+
+impl Network {
+    fn node_metrics(&self) -> ResponseActFuture<Self, NodeMetrics, ()> {
+        let mut metrics = NodeMetrics {
+            node: self.id,
+            network: match self.net_type {
+                NetworkType::Cluster => "cluster".to_owned(),
+                NetworkType::App => "app".to_owned(),
+            },
+            raft: self.metrics.as_ref().map(RaftState::from),
+            traffic: self.traffic.stats(),
+            writes: BTreeMap::new(),
+            memory: self.memory.usage(),
+            app: Vec::new(),
+            mailboxes: Vec::new(),
+            read_index: ReadIndexStats::default(),
+            io: IoStats::default(),
+            failure_domains: self.domains.as_ref().map(DomainTracker::stats),
+        };
+
+        if self.net_type != NetworkType::Cluster {
+            return Box::new(fut::ok(metrics));
+        }
+        metrics.app = self.hooks.read().unwrap().app_metrics().snapshot();
+        metrics.mailboxes = mailbox::stats(&self.mailboxes);
+        metrics.read_index = self.read_stats.clone();
+        metrics.io = io_stats::stats();
+
+        Box::new(fut::wrap_future::<_, Self>(self.raft.write_metrics.send(GetWriteMetrics)).then(move |res, _, _| {
+            let mut metrics = metrics;
+            metrics.writes = res.ok().and_then(|res| res.ok()).unwrap_or_default();
+            fut::ok(metrics)
+        }))
+    }
+
+    fn publish_metrics(&mut self, ctx: &mut Context<Self>) {
+        if self.metrics_subscribers.is_empty() {
+            return;
+        }
+
+        self.node_metrics()
+            .map(|metrics, act: &mut Self, _| {
+                // drop subscribers which went away
+                act.metrics_subscribers
+                    .retain(|subscriber| subscriber.do_send(metrics.clone()).is_ok());
+            })
+            .spawn(ctx);
+    }
+}
+
+//////////////////////////////////////////////////////////////////////////////
+// RaftMetrics ///////////////////////////////////////////////////////////////
+
+impl Handler<RaftMetrics> for Network {
+    type Result = ();
+
+    fn handle(&mut self, msg: RaftMetrics, ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(RaftMetrics);
+        debug!("Metrics: node={} state={:?} leader={:?} term={} index={} applied={} cfg={{join={} members={:?} non_voters={:?} removing={:?}}}",
+               msg.id, msg.state, msg.current_leader, msg.current_term, msg.last_log_index, msg.last_applied,
+               msg.membership_config.is_in_joint_consensus, msg.membership_config.members,
+               msg.membership_config.non_voters, msg.membership_config.removing,
+        );
+        // what followers held under a previous leadership says nothing about them now
+        if msg.current_leader != Some(self.id) {
+            self.peer_matched.clear();
+            self.peer_acked.clear();
+            if let Some(ref mut tracker) = self.domains {
+                tracker.reset();
+            }
+        }
+        self.check_leader_quorum(&msg);
+        self.check_step_down(&msg);
+        self.check_membership_transition(&msg, ctx);
+        self.history.observe(&msg);
+        self.record_metrics_events(&msg);
+        if self.single_node && self.state != NetworkState::Initialized {
+            self.state = if msg.membership_config.members.len() > 1 {
+                NetworkState::Cluster
+            } else {
+                NetworkState::SingleNode
+            };
+        }
+        {
+            let mut log = self.log.write().unwrap();
+            log.last_log_index = msg.last_log_index;
+            log.last_log_term = msg.current_term;
+        }
+        self.track_departures(&msg);
+        self.prune_departed();
+        self.link_stats.persist_due(&self.traffic, &self.nodes_connected);
+        if let (Some(ref mut contact), Some(leader)) = (&mut self.contact, msg.current_leader) {
+            if leader != self.id {
+                contact.heard(leader, msg.current_term);
+            }
+        }
+        self.metrics = Some(msg);
+        self.publish_progress();
+        self.step_aside_if_cordoned(ctx);
+        self.yield_to_priority(ctx);
+        self.publish_metrics(ctx);
+    }
+}
+
+impl Network {
+    /// Note the nodes which left the membership since the previous metrics, and those which
+    /// came back.
+    fn track_departures(&mut self, msg: &RaftMetrics) {
+        let config = &msg.membership_config;
+        let members = config.members.iter().chain(config.non_voters.iter()).cloned().collect::<BTreeSet<_>>();
+
+        if let Some(ref previous) = self.metrics {
+            let previous = &previous.membership_config;
+            for id in previous.members.iter().chain(previous.non_voters.iter()) {
+                if !members.contains(id) && *id != self.id {
+                    self.departed.entry(*id).or_insert_with(clock::now);
+                }
+            }
+        }
+
+        self.departed.retain(|id, _| !members.contains(id));
+    }
+
+    /// Forget the disconnected nodes which left the membership longer than the retention ago.
+    fn prune_departed(&mut self) {
+        let retention = self.metrics_retention;
+        let expired = self
+            .departed
+            .iter()
+            .filter(|(id, since)| clock::elapsed(**since) >= retention && self.statuses.get(*id) != Some(&NodeStatus::Connected))
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+
+        for id in expired {
+            self.forget_node(id);
+        }
+    }
+
+    fn forget_node(&mut self, id: NodeId) {
+        info!("Node {} prunes the state of departed node {}", self.id, id);
+
+        if let Some(node) = self.nodes.remove(&id) {
+            node.leave();
+        }
+        self.sessions.remove(&id);
+        self.nodes_info.remove(&id);
+        self.nodes_connected.retain(|node| *node != id);
+        self.statuses.remove(&id);
+        self.peer_contact.remove(&id);
+        self.peer_matched.remove(&id);
+        self.peer_acked.remove(&id);
+        self.peer_logs.remove(&id);
+        self.peer_versions.remove(&id);
+        self.unknown_targets.remove(&id);
+        self.suspended_sessions.remove(&id);
+        self.departed.remove(&id);
+        self.link_stats.forget(id);
+        self.traffic.forget_peer(id);
+        if let Some(ref crash) = self.crash {
+            crash.forget_peer(id);
+        }
+    }
+}
+
+/// Whether a previous run left snapshots or a raft log under the data dir.
+fn has_persisted_state(data_dir: &Path) -> bool {
+    let snapshots = std::fs::read_dir(data_dir.join("snapshots"))
+        .map(|mut entries| entries.next().is_some())
+        .unwrap_or(false);
+
+    snapshots || data_dir.join("log").exists()
+}
+
+/// Whether a quorum of `members`, `id` among them, answered raft RPCs sent after `since`.
+/// Contacts are dated by when the request was sent, so a read round only counts answers to
+/// requests sent once its reads arrived.
+fn contacted_since(id: NodeId, members: &[NodeId], contacts: &HashMap<NodeId, Instant>, since: Instant) -> bool {
+    let confirmed = members
+        .iter()
+        .filter(|member| **member == id || contacts.get(member).map(|contact| *contact > since).unwrap_or(false))
+        .count();
+
+    confirmed * 2 > members.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn retries(timeout: Duration, max_attempts: Option<u32>) -> Retries {
+        Retries {
+            started: clock::now(),
+            deadline: clock::now() + timeout,
+            max_attempts: max_attempts,
+            report: RetryReport::default(),
+        }
+    }
+
+    fn attempt() -> ProposeAttempt {
+        ProposeAttempt {
+            target: None,
+            at_ms: 0,
+            error: ProposeError::NotLeader { leader_hint: None },
+            timed_out: false,
+        }
+    }
+
+    #[test]
+    fn retries_stop_at_the_deadline_or_the_attempt_limit() {
+        assert!(retries(Duration::from_secs(0), None).exhausted());
+
+        let mut limited = retries(Duration::from_secs(60), Some(2));
+        limited.report.attempts.push(attempt());
+        assert!(!limited.exhausted());
+        limited.report.attempts.push(attempt());
+        assert!(limited.exhausted());
+
+        match limited.into_error() {
+            ProposeError::Exhausted(report) => assert_eq!(report.attempts.len(), 2),
+            err => panic!("unexpected {:?}", err),
+        }
+    }
+
+    #[test]
+    fn budgets_are_built_from_a_timeout() {
+        let budget = RetryBudget::within(Duration::from_secs(2)).attempts(3);
+        assert_eq!(
+            budget,
+            RetryBudget {
+                timeout_ms: 2000,
+                max_attempts: Some(3),
+            }
+        );
+    }
+
+    #[test]
+    fn peers_of_another_major_version_are_incompatible() {
+        let parts = VERSION.split('.').map(|part| part.parse::<u64>().unwrap()).collect::<Vec<_>>();
+        let (major, minor) = (parts[0], parts[1]);
+
+        assert!(is_compatible(VERSION));
+        assert!(is_compatible(&format!("{}.{}.99", major, minor)));
+        assert!(!is_compatible(&format!("{}.0.0", major + 1)));
+        // before 1.0 minor versions break compatibility too
+        assert_eq!(is_compatible(&format!("{}.{}.0", major, minor + 1)), major != 0);
+    }
+
+    #[test]
+    fn persisted_state_is_a_log_or_snapshots() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(!has_persisted_state(dir.path()));
+
+        std::fs::create_dir(dir.path().join("snapshots")).unwrap();
+        assert!(!has_persisted_state(dir.path()));
+        std::fs::write(dir.path().join("snapshots").join("1-1.snap"), b"").unwrap();
+        assert!(has_persisted_state(dir.path()));
+
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("log")).unwrap();
+        assert!(has_persisted_state(dir.path()));
+    }
+
+    #[test]
+    fn members_are_compared_sorted() {
+        assert_eq!(sorted(&[3, 1, 2]), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn read_rounds_count_requests_sent_after_they_started() {
+        let before = clock::now();
+        let since = before + Duration::from_millis(1);
+        let after = since + Duration::from_millis(1);
+
+        let mut contacts = HashMap::new();
+        contacts.insert(2, before);
+        assert!(!contacted_since(1, &[1, 2, 3], &contacts, since));
+
+        contacts.insert(3, after);
+        assert!(contacted_since(1, &[1, 2, 3], &contacts, since));
+        assert!(!contacted_since(1, &[1, 2, 3, 4, 5], &contacts, since));
     }
 }
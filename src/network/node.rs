@@ -1,44 +1,237 @@
 use actix::prelude::*;
 use actix_raft::NodeId;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::marker::PhantomData;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::codec::FramedRead;
 use tokio::io::{AsyncRead, WriteHalf};
 use tokio::net::TcpStream;
 use tokio::sync::oneshot;
-use log::{debug, info};
+use tokio::timer::Timeout;
+use actix_web::web;
+use log::{debug, error, info, warn};
 
 use serde::{de::DeserializeOwned, Serialize};
 
+use crate::clock;
 use crate::network::{
-    remote::{RemoteMessage, RemoteMessageResult, SendRemoteMessage, DispatchMessage},
-    ClientNodeCodec, Network, NodeRequest, NodeResponse, PeerConnected,
+    remote::{self, RemoteMessage, RemoteMessageResult, SendRemoteMessage, DispatchMessage, OFFLOAD_THRESHOLD},
+    throttle::{EgressLimiter, TokenBucket},
+    codec::{RemoteResult, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION},
+    tls, ClientNodeCodec, Compression, LinkDebug, Network, SharedLogState, NodeRequest, NodeResponse, PeerConnected, PeerReconnecting, PeerResolver, PeerStream, PeerTls, Plane, VERSION,
 };
 
-use crate::config::{NetworkType, NodeInfo};
+use crate::config::{ConstrainedLinks, DialTimeouts, MailboxCapacities, NetworkType, NodeInfo};
+use crate::mailbox::{self, Mailbox};
+use crate::memory::{MemoryAccount, MemoryArea};
+use crate::raft::timing::now_millis;
 
-#[derive(PartialEq)]
-enum NodeState {
+/// First delay before reconnecting to a peer, doubled after every failed attempt
+const RECONNECT_MIN: Duration = Duration::from_millis(500);
+const RECONNECT_MAX: Duration = Duration::from_secs(30);
+/// How long a connected peer may stay silent before the connection is considered dead
+const PEER_TIMEOUT: Duration = Duration::from_secs(10);
+/// Requests awaiting an answer from a peer, further requests fail right away
+const MAX_PENDING_REQUESTS: usize = 4096;
+/// Bounds of the frames coalescing the messages written to a peer in one event loop turn
+const MAX_BATCH_LEN: usize = 64;
+const MAX_BATCH_BYTES: usize = 1024 * 1024;
+
+/// How messages are written to a peer, tuned down for constrained links.
+#[derive(Clone, Debug)]
+pub struct LinkTuning {
+    pub max_batch_len: usize,
+    pub max_batch_bytes: usize,
+    pub compression: Compression,
+    /// Rate at which snapshot chunks are written, unlimited if not set
+    pub snapshot_bytes_per_sec: Option<u64>,
+}
+
+impl Default for LinkTuning {
+    fn default() -> Self {
+        LinkTuning {
+            max_batch_len: MAX_BATCH_LEN,
+            max_batch_bytes: MAX_BATCH_BYTES,
+            compression: Compression::Off,
+            snapshot_bytes_per_sec: None,
+        }
+    }
+}
+
+impl LinkTuning {
+    pub fn constrained(links: &ConstrainedLinks) -> Self {
+        LinkTuning {
+            max_batch_len: std::cmp::max(links.max_batch_len, 1),
+            max_batch_bytes: links.max_batch_bytes,
+            compression: if links.compress { Compression::Deflate } else { Compression::Off },
+            snapshot_bytes_per_sec: links.snapshot_bytes_per_sec,
+        }
+    }
+}
+
+/// Requests in the order they were sent, those after a large one which is still encoded on
+/// the blocking pool are held back until it is written.
+#[derive(Default)]
+struct SendOrder {
+    /// `None` until the request is encoded
+    held: VecDeque<(u64, Option<Option<NodeRequest>>)>,
+}
+
+impl SendOrder {
+    /// A large request starts encoding.
+    fn encoding(&mut self, mid: u64) {
+        self.held.push_back((mid, None));
+    }
+
+    /// An encoded request, returned if nothing sent before it is still encoding.
+    fn encoded(&mut self, mid: u64, request: NodeRequest) -> Option<NodeRequest> {
+        if self.held.is_empty() {
+            return Some(request);
+        }
+        self.held.push_back((mid, Some(Some(request))));
+        None
+    }
+
+    /// A large request finished encoding, `None` if it failed. Returns the requests which may be
+    /// written now, in order, failed ones as `None`.
+    fn finished(&mut self, mid: u64, request: Option<NodeRequest>) -> Vec<(u64, Option<NodeRequest>)> {
+        if let Some(slot) = self.held.iter_mut().find(|(m, _)| *m == mid) {
+            slot.1 = Some(request);
+        }
+
+        let mut ready = Vec::new();
+        while let Some((_, Some(_))) = self.held.front() {
+            if let Some((mid, Some(request))) = self.held.pop_front() {
+                ready.push((mid, request));
+            }
+        }
+        ready
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub enum NodeState {
     Registered,
     Connected,
 }
 
+/// Traffic sent to a peer and answered by it.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct PeerStats {
+    /// Requests awaiting a response
+    pub requests_sent: u64,
+    /// Fire and forget messages
+    pub messages_dispatched: u64,
+    pub responses_received: u64,
+    pub pending: usize,
+}
+
+/// Lifecycle snapshot of a peer.
+#[derive(Serialize, Debug, Clone)]
+pub struct PeerInfo {
+    pub id: NodeId,
+    pub state: NodeState,
+    pub address: String,
+    /// Unix time in milliseconds the current connection was established at
+    pub connected_since: Option<u64>,
+    pub last_error: Option<String>,
+    pub stats: PeerStats,
+}
+
+/// Handle to the actor managing the connection to a peer.
+#[derive(Clone)]
+pub struct PeerHandle {
+    pub id: NodeId,
+    addr: Addr<Node>,
+}
+
+impl PeerHandle {
+    pub(crate) fn new(id: NodeId, addr: Addr<Node>) -> PeerHandle {
+        PeerHandle { id: id, addr: addr }
+    }
+
+    pub fn info(&self) -> impl Future<Item = PeerInfo, Error = MailboxError> {
+        self.addr.send(GetPeerInfo)
+    }
+
+    pub fn addr(&self) -> &Addr<Node> {
+        &self.addr
+    }
+
+    /// Send a message to the peer and wait for its answer.
+    pub fn send<M>(&self, msg: M) -> impl Future<Item = M::Result, Error = MailboxError>
+    where
+        M: RemoteMessage + 'static,
+        M::Result: Send + Serialize + DeserializeOwned,
+    {
+        self.addr.send(SendRemoteMessage(msg))
+    }
+
+    /// Send a message to the peer without waiting for an answer.
+    pub fn dispatch<M>(&self, msg: M)
+    where
+        M: RemoteMessage + 'static,
+        M::Result: Send + Serialize + DeserializeOwned,
+    {
+        mailbox::deliver(&self.addr, DispatchMessage(msg), Mailbox::Node)
+    }
+}
+
 pub struct Node {
     id: NodeId,
     local_id: NodeId,
     mid: u64,
     state: NodeState,
     peer_addr: String,
-    framed: Option<actix::io::FramedWrite<WriteHalf<TcpStream>, ClientNodeCodec>>,
-    requests: HashMap<u64, oneshot::Sender<String>>,
+    framed: Option<actix::io::FramedWrite<WriteHalf<PeerStream>, ClientNodeCodec>>,
+    requests: HashMap<u64, oneshot::Sender<RemoteResult>>,
+    /// Frames of the pending requests, sent again when the session is resumed
+    inflight: HashMap<u64, NodeRequest>,
+    /// Requests pending when the connection was established, sent again once joined
+    unanswered: Vec<u64>,
+    /// Messages written since the last flush, sent together in batch frames
+    outbox: Vec<NodeRequest>,
+    send_order: SendOrder,
+    flush_scheduled: bool,
+    /// Set once this node told the peer it shuts down, the connection isn't resumed anymore
+    leaving: bool,
+    /// Resumption token of the current session
+    token: Option<String>,
+    heartbeat: Option<SpawnHandle>,
+    /// Read half of the current connection
+    stream: Option<SpawnHandle>,
+    /// When the peer last answered on the current connection
+    last_seen: Instant,
+    /// Delay before the next connection attempt
+    backoff: Duration,
+    /// The next connection attempt, while waiting out the backoff
+    retry: Option<SpawnHandle>,
+    resolve_timeout: Duration,
+    /// Bounds the connection and the TLS handshake
+    connect_timeout: Duration,
+    debug: LinkDebug,
     network: Addr<Network>,
     net_type: NetworkType,
     info: NodeInfo,
+    /// Log state announced when joining
+    log: SharedLogState,
+    resolver: PeerResolver,
+    /// Accounts the frames of the pending requests
+    memory: MemoryAccount,
+    tls: Option<PeerTls>,
+    connected_since: Option<u64>,
+    last_error: Option<String>,
+    stats: PeerStats,
+    link: LinkTuning,
+    /// Paces the snapshot chunks written on a constrained link
+    snapshot_bucket: Option<TokenBucket>,
+    /// Paces the data plane frames of every peer of the node
+    egress: EgressLimiter,
+    mailbox_capacity: usize,
 }
 
 impl Node {
-    pub fn new(id: u64, local_id: NodeId, peer_addr: String, network: Addr<Network>, net_type: NetworkType, info: NodeInfo) -> Self {
+    pub fn new(id: u64, local_id: NodeId, peer_addr: String, network: Addr<Network>, net_type: NetworkType, info: NodeInfo, debug: LinkDebug, log: SharedLogState, resolver: PeerResolver, memory: MemoryAccount, tls: Option<PeerTls>) -> Self {
         println!("Regsitering INFO {:#?}", info);
         Node {
             id: id,
@@ -48,12 +241,60 @@ impl Node {
             peer_addr: peer_addr,
             framed: None,
             requests: HashMap::new(),
+            inflight: HashMap::new(),
+            unanswered: Vec::new(),
+            outbox: Vec::new(),
+            send_order: SendOrder::default(),
+            flush_scheduled: false,
+            leaving: false,
+            token: None,
+            heartbeat: None,
+            stream: None,
+            last_seen: clock::now(),
+            backoff: RECONNECT_MIN,
+            retry: None,
+            resolve_timeout: Duration::from_millis(DialTimeouts::default().resolve_timeout_ms),
+            connect_timeout: Duration::from_millis(DialTimeouts::default().connect_timeout_ms),
+            debug: debug,
             network: network,
             net_type: net_type,
             info: info,
+            log: log,
+            resolver: resolver,
+            memory: memory,
+            tls: tls,
+            connected_since: None,
+            last_error: None,
+            stats: PeerStats::default(),
+            link: LinkTuning::default(),
+            snapshot_bucket: None,
+            egress: EgressLimiter::default(),
+            mailbox_capacity: MailboxCapacities::default().node,
         }
     }
 
+    pub fn with_link(mut self, link: LinkTuning) -> Self {
+        self.snapshot_bucket = link.snapshot_bytes_per_sec.map(TokenBucket::new);
+        self.link = link;
+        self
+    }
+
+    pub fn with_egress(mut self, egress: EgressLimiter) -> Self {
+        self.egress = egress;
+        self
+    }
+
+    pub fn with_mailbox_capacity(mut self, capacity: usize) -> Self {
+        self.mailbox_capacity = capacity;
+        self
+    }
+
+    pub fn with_dial_timeouts(mut self, timeouts: &DialTimeouts) -> Self {
+        self.resolve_timeout = Duration::from_millis(timeouts.resolve_timeout_ms);
+        self.connect_timeout = Duration::from_millis(timeouts.connect_timeout_ms);
+        self
+    }
+
     fn connect(&mut self, ctx: &mut Context<Self>) {
         // node is already connected
         if self.state == NodeState::Connected {
@@ -62,22 +303,244 @@ impl Node {
 
         debug!("Connecting to node #{}", self.id);
 
-        let remote_addr = self.peer_addr.as_str().parse().unwrap();
-        let conn = TcpStream::connect(&remote_addr)
-            .map_err(|e| {
-                 println!("Error: {:?}", e);
+        let resolver = self.resolver.clone();
+        let (peer_addr, dialed_addr) = (self.peer_addr.clone(), self.peer_addr.clone());
+        let (tls, peer) = (self.tls.clone(), self.id);
+        let (resolve_timeout, connect_timeout) = (self.resolve_timeout, self.connect_timeout);
+
+        // a timed out lookup keeps its blocking thread until the system resolver gives up, its
+        // answer is dropped. A timed out connection is dropped, closing its socket.
+        let resolved = Timeout::new(web::block(move || resolver.lookup(&peer_addr)), resolve_timeout).map_err(move |err| {
+            if err.is_elapsed() {
+                format!("resolving timed out after {:?}", resolve_timeout)
+            } else {
+                err.into_inner().map_or_else(|| "resolving failed".to_owned(), |err| err.to_string())
+            }
+        });
+
+        fut::wrap_future::<_, Self>(resolved)
+            .and_then(move |addrs, _, _| {
+                let dialed = TcpStream::connect(&addrs[0])
+                    .map_err(|err| err.to_string())
+                    .and_then(move |stream| tls::connect(&tls, stream, peer, &dialed_addr).map_err(|err| format!("TLS handshake failed: {}", err)));
+                fut::wrap_future(Timeout::new(dialed, connect_timeout).map_err(move |err| {
+                    if err.is_elapsed() {
+                        format!("connecting timed out after {:?}", connect_timeout)
+                    } else {
+                        err.into_inner().unwrap_or_else(|| "connecting failed".to_owned())
+                    }
+                }))
             })
-            .map(TcpConnect)
-            .into_stream();
+            .then(|res, act, ctx| {
+                match res {
+                    Ok(stream) => ctx.notify(TcpConnect(stream)),
+                    Err(e) => {
+                        debug!("Failed to connect to node #{}, retrying in {:?}: {}", act.id, act.backoff, e);
+                        // the peer may have moved, look its address up again
+                        act.resolver.invalidate(&act.peer_addr);
+                        act.last_error = Some(e);
+                        act.backoff = std::cmp::min(act.backoff * 2, RECONNECT_MAX);
+                        ctx.notify(Connect);
+                    }
+                }
+                fut::ok(())
+            })
+            .spawn(ctx);
+    }
 
-        ctx.add_message_stream(conn);
+    fn hb(&mut self, ctx: &mut Context<Self>) {
+        if self.heartbeat.is_some() {
+            return;
+        }
+
+        self.heartbeat = Some(ctx.run_interval(Duration::new(1, 0), |act, ctx| {
+            if act.state == NodeState::Connected && clock::elapsed(act.last_seen) > PEER_TIMEOUT {
+                error!("Node #{} didn't answer for {:?}, dropping the connection", act.id, PEER_TIMEOUT);
+                act.last_error = Some("heartbeat timed out".to_owned());
+                return act.disconnected(ctx);
+            }
+
+            if let Some(ref mut framed) = act.framed {
+                framed.write(NodeRequest::Ping);
+            }
+        }));
     }
 
-    fn hb(&self, ctx: &mut Context<Self>) {
-        ctx.run_later(Duration::new(1, 0), |act, ctx| {
-            act.framed.as_mut().unwrap().write(NodeRequest::Ping);
-            act.hb(ctx);
-        });
+    /// The connection dropped, keep pending requests around and let the connect loop resume
+    /// the session.
+    fn disconnected(&mut self, ctx: &mut Context<Self>) {
+        // the writer stops the actor once the leave frame went out
+        if self.leaving {
+            return;
+        }
+
+        if self.state == NodeState::Connected {
+            debug!("Connection to node #{} lost, {} requests pending", self.id, self.requests.len());
+            mailbox::deliver(&self.network, PeerReconnecting(self.id), Mailbox::Network);
+            ctx.notify(Connect);
+        }
+
+        if let Some(stream) = self.stream.take() {
+            ctx.cancel_future(stream);
+        }
+
+        self.state = NodeState::Registered;
+        self.connected_since = None;
+        self.framed = None;
+        // the pending requests among them are sent again once reconnected
+        self.outbox.clear();
+    }
+
+    fn write_request(&mut self, mid: u64, request: NodeRequest, ctx: &mut Context<Self>) {
+        let size = request_size(&request);
+
+        // shed requests while those waiting for an answer hold too much memory, the sender
+        // sees the request fail
+        if self.memory.exceeds(MemoryArea::OutboundQueues, size) {
+            warn!("Dropping request to node #{}, outbound queues are over their memory limit", self.id);
+            self.memory.shed(MemoryArea::OutboundQueues);
+            self.requests.remove(&mid);
+            return;
+        }
+        self.memory.grow(MemoryArea::OutboundQueues, size);
+
+        self.inflight.insert(mid, request.clone());
+
+        // snapshot chunks are held back until the link's snapshot rate allows them
+        let snapshot_delay = match (&request, self.snapshot_bucket.as_mut()) {
+            (NodeRequest::Message(_, type_id, body), Some(bucket)) if type_id == "InstallSnapshotRequest" => {
+                bucket.reserve(body.len() as u64)
+            }
+            _ => Duration::from_secs(0),
+        };
+        // and data plane frames until the egress limit of the node allows them
+        let egress_delay = match request {
+            NodeRequest::Message(_, ref type_id, _) | NodeRequest::Dispatch(ref type_id, _)
+                if Plane::of(type_id) == Plane::Data =>
+            {
+                self.egress.reserve(size as u64)
+            }
+            _ => Duration::from_secs(0),
+        };
+        let delay = snapshot_delay.max(egress_delay);
+
+        if delay > Duration::from_secs(0) {
+            ctx.run_later(delay, move |act, ctx| {
+                // answered or dropped in the meantime
+                if act.inflight.contains_key(&mid) {
+                    act.queue(request, ctx);
+                }
+            });
+        } else {
+            self.queue(request, ctx);
+        }
+    }
+
+    /// Requests sent before a disconnect may not have been answered, send them again once
+    /// joined: a resumed session answers those it handled already from its cache.
+    fn resend_inflight(&mut self) {
+        let framed = match self.framed {
+            Some(ref mut framed) => framed,
+            None => return,
+        };
+
+        let mut unanswered = std::mem::replace(&mut self.unanswered, Vec::new());
+        unanswered.sort();
+        for mid in unanswered {
+            if let Some(request) = self.inflight.get(&mid) {
+                framed.write(request.clone());
+            }
+        }
+    }
+
+    /// Queue a message for the next flush, which runs once the messages already in the mailbox
+    /// were handled.
+    fn queue(&mut self, request: NodeRequest, ctx: &mut Context<Self>) {
+        if self.framed.is_none() {
+            return;
+        }
+
+        self.outbox.push(request);
+        if !self.flush_scheduled {
+            self.flush_scheduled = true;
+            ctx.notify(Flush);
+        }
+    }
+
+    /// Write the queued messages, several messages share a frame.
+    fn flush(&mut self) {
+        self.flush_scheduled = false;
+
+        let framed = match self.framed {
+            Some(ref mut framed) => framed,
+            None => return self.outbox.clear(),
+        };
+
+        let mut batch = Vec::new();
+        let mut batch_bytes = 0;
+        let (max_len, max_bytes) = (self.link.max_batch_len, self.link.max_batch_bytes);
+        // until the peer answered the hello, and on sessions of older versions, one per frame
+        let max_len = if self.debug.batches() { max_len } else { 1 };
+
+        for request in self.outbox.drain(..) {
+            let size = request_size(&request);
+            if !batch.is_empty() && (batch.len() == max_len || batch_bytes + size > max_bytes) {
+                framed.write(batch_frame(std::mem::replace(&mut batch, Vec::new())));
+                batch_bytes = 0;
+            }
+            batch_bytes += size;
+            batch.push(request);
+        }
+
+        if !batch.is_empty() {
+            framed.write(batch_frame(batch));
+        }
+    }
+}
+
+/// A single message is sent as is.
+fn batch_frame(mut batch: Vec<NodeRequest>) -> NodeRequest {
+    if batch.len() == 1 {
+        batch.pop().unwrap()
+    } else {
+        NodeRequest::Batch(batch)
+    }
+}
+
+/// Tell the peer this node shuts down and stop once the queued messages and the leave frame
+/// were written.
+#[derive(Message)]
+pub struct Leave;
+
+impl Handler<Leave> for Node {
+    type Result = ();
+
+    fn handle(&mut self, _: Leave, ctx: &mut Context<Self>) {
+        crate::audit_message!(Leave);
+        self.flush();
+        self.leaving = true;
+
+        match self.framed {
+            Some(ref mut framed) => {
+                framed.write(NodeRequest::Leave);
+                framed.close();
+                // in case the peer doesn't read anymore
+                ctx.run_later(PEER_TIMEOUT, |_, ctx| ctx.stop());
+            }
+            None => ctx.stop(),
+        }
+    }
+}
+
+#[derive(Message)]
+struct Flush;
+
+impl Handler<Flush> for Node {
+    type Result = ();
+
+    fn handle(&mut self, _: Flush, _ctx: &mut Context<Self>) {
+        crate::audit_message!(Flush);
+        self.flush();
     }
 }
 
@@ -85,17 +548,23 @@ impl Actor for Node {
     type Context = Context<Self>;
 
     fn started(&mut self, ctx: &mut Context<Self>) {
+        ctx.set_mailbox_capacity(self.mailbox_capacity);
         ctx.notify(Connect);
     }
 
     fn stopped(&mut self, ctx: &mut Context<Self>) {
         info!("Node #{} disconnected", self.id);
         self.state = NodeState::Registered;
+        self.connected_since = None;
+
+        let queued = self.inflight.values().map(request_size).sum();
+        self.memory.shrink(MemoryArea::OutboundQueues, queued);
+        self.inflight.clear();
     }
 }
 
 #[derive(Message)]
-struct TcpConnect(TcpStream);
+struct TcpConnect(PeerStream);
 
 #[derive(Message)]
 struct Connect;
@@ -104,17 +573,28 @@ impl Handler<TcpConnect> for Node {
     type Result = ();
 
     fn handle(&mut self, msg: TcpConnect, ctx: &mut Context<Self>) {
+        crate::audit_message!(TcpConnect);
         //        println!("Connected to remote node #{}", self.id);
         self.state = NodeState::Connected;
+        self.connected_since = Some(now_millis());
+        self.last_seen = clock::now();
+        self.backoff = RECONNECT_MIN;
         let (r, w) = msg.0.split();
-        Node::add_stream(FramedRead::new(r, ClientNodeCodec), ctx);
-        self.framed = Some(actix::io::FramedWrite::new(w, ClientNodeCodec, ctx));
+        let compression = self.link.compression;
+        self.stream = Some(Node::add_stream(FramedRead::new(r, ClientNodeCodec::new(self.debug.clone(), compression)), ctx));
+        self.framed = Some(actix::io::FramedWrite::new(w, ClientNodeCodec::new(self.debug.clone(), compression), ctx));
 
-        self.network.do_send(PeerConnected(self.id));
-        self.framed
-            .as_mut()
-            .unwrap()
-            .write(NodeRequest::Join(self.local_id, self.info.clone()));
+        mailbox::deliver(&self.network, PeerConnected(self.id), Mailbox::Network);
+        self.unanswered = self.inflight.keys().cloned().collect();
+        // the join reads the same in every version, no need to wait for the answer to the hello
+        self.framed.as_mut().unwrap().write(NodeRequest::Hello(MIN_PROTOCOL_VERSION, PROTOCOL_VERSION));
+        self.framed.as_mut().unwrap().write(NodeRequest::Join(
+            self.local_id,
+            self.info.clone(),
+            VERSION.to_owned(),
+            self.token.clone(),
+            *self.log.read().unwrap(),
+        ));
 
         match self.net_type {
             NetworkType::Cluster => self.hb(ctx),
@@ -130,11 +610,16 @@ where
 {
     type Result = ();
 
-    fn handle(&mut self, msg: DispatchMessage<M>, _ctx: &mut Context<Self>) -> Self::Result {
-        if let Some(ref mut framed) = self.framed {
-            let body = serde_json::to_string::<M>(&msg.0).unwrap();
-            let request = NodeRequest::Dispatch(M::type_id().to_owned(), body);
-            framed.write(request);
+    fn handle(&mut self, msg: DispatchMessage<M>, ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(DispatchMessage<M>);
+        if self.framed.is_some() {
+            match remote::encode_payload(&msg.0) {
+                Ok(body) => {
+                    self.queue(NodeRequest::Dispatch(M::type_id().to_owned(), body), ctx);
+                    self.stats.messages_dispatched += 1;
+                }
+                Err(err) => error!("Dropping {} to node #{}: {}", M::type_id(), self.id, err),
+            }
         }
     }
 }
@@ -146,16 +631,58 @@ where
 {
     type Result = RemoteMessageResult<M>;
 
-    fn handle(&mut self, msg: SendRemoteMessage<M>, _ctx: &mut Context<Self>) -> Self::Result {
-        let (tx, rx) = oneshot::channel::<String>();
+    fn handle(&mut self, msg: SendRemoteMessage<M>, ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(SendRemoteMessage<M>);
+        let (tx, rx) = oneshot::channel::<RemoteResult>();
 
-        if let Some(ref mut framed) = self.framed {
+        // backpressure, a peer which doesn't keep up fails further requests until it answers
+        if self.requests.len() >= MAX_PENDING_REQUESTS {
+            warn!("Dropping {} to node #{}, {} requests are pending", M::type_id(), self.id, self.requests.len());
+        } else if self.framed.is_some() {
             self.mid += 1;
             self.requests.insert(self.mid, tx);
+            self.stats.requests_sent += 1;
+            let mid = self.mid;
 
-            let body = serde_json::to_string::<M>(&msg.0).unwrap();
-            let request = NodeRequest::Message(self.mid, M::type_id().to_owned(), body);
-            framed.write(request);
+            if msg.0.size_hint() > OFFLOAD_THRESHOLD {
+                // encode large payloads on the blocking pool to keep this event loop responsive,
+                // the requests sent meanwhile wait for it so that the peer sees them in order
+                self.send_order.encoding(mid);
+                let m = msg.0;
+                fut::wrap_future::<_, Self>(web::block(move || remote::encode_payload(&m)))
+                    .then(move |res, act, ctx| {
+                        let request = match res {
+                            Ok(body) => Some(NodeRequest::Message(mid, M::type_id().to_owned(), body)),
+                            Err(e) => {
+                                error!("Error encoding remote message: {:?}", e);
+                                None
+                            }
+                        };
+                        for (mid, request) in act.send_order.finished(mid, request) {
+                            match request {
+                                Some(request) => act.write_request(mid, request, ctx),
+                                None => {
+                                    act.requests.remove(&mid);
+                                }
+                            }
+                        }
+                        fut::ok(())
+                    })
+                    .spawn(ctx);
+            } else {
+                match remote::encode_payload(&msg.0) {
+                    Ok(body) => {
+                        let request = NodeRequest::Message(mid, M::type_id().to_owned(), body);
+                        if let Some(request) = self.send_order.encoded(mid, request) {
+                            self.write_request(mid, request, ctx);
+                        }
+                    }
+                    Err(err) => {
+                        error!("Dropping {} to node #{}: {}", M::type_id(), self.id, err);
+                        self.requests.remove(&mid);
+                    }
+                }
+            }
         }
 
         RemoteMessageResult {
@@ -169,27 +696,159 @@ impl Handler<Connect> for Node {
     type Result = ();
 
     fn handle(&mut self, _msg: Connect, ctx: &mut Context<Self>) {
-        ctx.run_later(Duration::new(2, 0), |act, ctx| {
+        crate::audit_message!(Connect);
+        // the next attempt is scheduled once this one failed or the connection dropped
+        self.retry = Some(ctx.run_later(self.backoff, |act, ctx| {
+            act.retry = None;
             act.connect(ctx);
-            ctx.notify(Connect);
-        });
+        }));
     }
 }
 
-impl actix::io::WriteHandler<std::io::Error> for Node {}
+/// Connect now if waiting out the backoff, e.g. when the peer asked to be caught up.
+#[derive(Message)]
+pub struct Reconnect;
+
+impl Handler<Reconnect> for Node {
+    type Result = ();
+
+    fn handle(&mut self, _msg: Reconnect, ctx: &mut Context<Self>) {
+        crate::audit_message!(Reconnect);
+        // no pending attempt while connected or connecting
+        if let Some(retry) = self.retry.take() {
+            ctx.cancel_future(retry);
+            self.backoff = RECONNECT_MIN;
+            self.connect(ctx);
+        }
+    }
+}
+
+/// Drop the connection and connect again right away, e.g. to handshake with reloaded TLS
+/// certificates. The session is resumed and the pending requests are sent again.
+#[derive(Message)]
+pub struct Rehandshake;
+
+impl Handler<Rehandshake> for Node {
+    type Result = ();
+
+    fn handle(&mut self, _msg: Rehandshake, ctx: &mut Context<Self>) {
+        crate::audit_message!(Rehandshake);
+        if self.state != NodeState::Connected {
+            // the next attempt handshakes with the new certificates anyway
+            return;
+        }
+
+        debug!("Re-establishing the connection to node #{}", self.id);
+        self.flush();
+        if let Some(ref mut framed) = self.framed {
+            framed.close();
+        }
+        self.backoff = RECONNECT_MIN;
+        self.disconnected(ctx);
+    }
+}
+
+impl actix::io::WriteHandler<std::io::Error> for Node {
+    fn error(&mut self, err: std::io::Error, ctx: &mut Context<Self>) -> Running {
+        self.last_error = Some(err.to_string());
+        self.disconnected(ctx);
+        Running::Continue
+    }
+}
 
 impl StreamHandler<NodeResponse, std::io::Error> for Node {
     fn handle(&mut self, msg: NodeResponse, _ctx: &mut Context<Self>) {
+        crate::audit_message!(NodeResponse);
+        self.last_seen = clock::now();
+
         match msg {
             NodeResponse::Result(mid, data) => {
+                if let Some(request) = self.inflight.remove(&mid) {
+                    self.memory.shrink(MemoryArea::OutboundQueues, request_size(&request));
+                }
                 if let Some(tx) = self.requests.remove(&mid) {
+                    self.stats.responses_received += 1;
                     let _ = tx.send(data);
                 }
             }
             NodeResponse::Ping => {
                 // println!("Client got Ping from {}", self.id);
             }
-            _ => (),
+            NodeResponse::Hello(0) => {
+                error!("Node #{} speaks no protocol version this node speaks ({} to {})", self.id, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION);
+                self.last_error = Some("no common protocol version".to_owned());
+            }
+            NodeResponse::Hello(version) => debug!("Session to node #{} speaks protocol version {}", self.id, version),
+            NodeResponse::Joined(token) => {
+                self.token = Some(token);
+                self.resend_inflight();
+            }
         }
     }
+
+    fn finished(&mut self, ctx: &mut Context<Self>) {
+        self.disconnected(ctx);
+    }
+}
+
+pub struct GetPeerInfo;
+
+impl Message for GetPeerInfo {
+    type Result = PeerInfo;
+}
+
+impl Handler<GetPeerInfo> for Node {
+    type Result = MessageResult<GetPeerInfo>;
+
+    fn handle(&mut self, _: GetPeerInfo, _ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(GetPeerInfo);
+        let mut stats = self.stats.clone();
+        stats.pending = self.requests.len();
+
+        MessageResult(PeerInfo {
+            id: self.id,
+            state: self.state.clone(),
+            address: self.peer_addr.clone(),
+            connected_since: self.connected_since,
+            last_error: self.last_error.clone(),
+            stats: stats,
+        })
+    }
+}
+
+/// Bytes held by the frame of a pending request.
+fn request_size(request: &NodeRequest) -> usize {
+    match request {
+        NodeRequest::Message(_, type_id, body) | NodeRequest::Dispatch(type_id, body) => type_id.len() + body.len(),
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(mid: u64) -> NodeRequest {
+        NodeRequest::Message(mid, "AppendEntriesRequest".to_owned(), vec![mid as u8])
+    }
+
+    fn mids(ready: Vec<(u64, Option<NodeRequest>)>) -> Vec<(u64, bool)> {
+        ready.into_iter().map(|(mid, request)| (mid, request.is_some())).collect()
+    }
+
+    #[test]
+    fn requests_after_a_large_one_wait_for_its_encoding() {
+        let mut order = SendOrder::default();
+        assert!(order.encoded(1, message(1)).is_some());
+
+        order.encoding(2);
+        assert!(order.encoded(3, message(3)).is_none());
+        order.encoding(4);
+        assert!(order.encoded(5, message(5)).is_none());
+
+        // the second large request finishing first writes nothing
+        assert!(order.finished(4, Some(message(4))).is_empty());
+        assert_eq!(mids(order.finished(2, None)), vec![(2, false), (3, true), (4, true), (5, true)]);
+        assert!(order.encoded(6, message(6)).is_some());
+    }
 }
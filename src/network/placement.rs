@@ -0,0 +1,78 @@
+use actix_raft::NodeId;
+use std::collections::BTreeSet;
+
+use crate::config::{NodeInfo, PlacementRule};
+
+/// Describe every rule broken by the given members, members without known info carry no labels.
+pub fn violations(rules: &[PlacementRule], members: &[(NodeId, Option<&NodeInfo>)]) -> Vec<String> {
+    let label = |info: &Option<&NodeInfo>, label: &str| info.and_then(|info| info.labels.get(label).cloned());
+
+    rules
+        .iter()
+        .filter_map(|rule| match rule {
+            PlacementRule::Spread { label: name, min } => {
+                let values = members
+                    .iter()
+                    .filter_map(|(_, info)| label(info, name))
+                    .collect::<BTreeSet<_>>();
+
+                if values.len() < *min {
+                    Some(format!("members span {} distinct `{}` values, at least {} required", values.len(), name, min))
+                } else {
+                    None
+                }
+            }
+            PlacementRule::Exclude { label: name, value } => {
+                let excluded = members
+                    .iter()
+                    .filter(|(_, info)| label(info, name).as_ref() == Some(value))
+                    .map(|(id, _)| *id)
+                    .collect::<Vec<_>>();
+
+                if excluded.is_empty() {
+                    None
+                } else {
+                    Some(format!("nodes {:?} are labelled `{} = {}` and may not be members", excluded, name, value))
+                }
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn labelled(label: &str, value: &str) -> NodeInfo {
+        let mut labels = BTreeMap::new();
+        labels.insert(label.to_owned(), value.to_owned());
+        NodeInfo {
+            cluster_addr: String::new(),
+            app_addr: String::new(),
+            public_addr: String::new(),
+            id: None,
+            labels: labels,
+            cluster: None,
+        }
+    }
+
+    #[test]
+    fn members_must_spread_over_the_label() {
+        let rules = [PlacementRule::Spread { label: "zone".to_owned(), min: 2 }];
+        let (a, b) = (labelled("zone", "a"), labelled("zone", "b"));
+
+        assert_eq!(violations(&rules, &[(1, Some(&a)), (2, Some(&a)), (3, None)]).len(), 1);
+        assert!(violations(&rules, &[(1, Some(&a)), (2, Some(&b))]).is_empty());
+    }
+
+    #[test]
+    fn excluded_nodes_are_named() {
+        let rules = [PlacementRule::Exclude { label: "disk".to_owned(), value: "hdd".to_owned() }];
+        let (hdd, ssd) = (labelled("disk", "hdd"), labelled("disk", "ssd"));
+
+        let broken = violations(&rules, &[(1, Some(&ssd)), (2, Some(&hdd))]);
+        assert_eq!(broken, vec!["nodes [2] are labelled `disk = hdd` and may not be members".to_owned()]);
+        assert!(violations(&rules, &[(1, Some(&ssd))]).is_empty());
+    }
+}
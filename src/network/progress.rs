@@ -0,0 +1,246 @@
+use actix::prelude::*;
+use actix_raft::{NodeId, RaftMetrics};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tokio::timer::Timeout;
+
+use crate::network::ReadError;
+
+/// Raft progress of a node.
+///
+/// actix-raft does not report the commit index through its metrics, so `commit_index` is
+/// computed from the acknowledgements of the followers on the leader, see `quorum_commit`, and
+/// is the commit index the leader sent along with entries a follower holds on followers.
+#[derive(Message, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ClusterProgress {
+    pub term: u64,
+    pub commit_index: u64,
+    pub applied_index: u64,
+}
+
+impl<'a> From<&'a RaftMetrics> for ClusterProgress {
+    fn from(metrics: &'a RaftMetrics) -> Self {
+        ClusterProgress {
+            term: metrics.current_term,
+            commit_index: metrics.last_applied,
+            applied_index: metrics.last_applied,
+        }
+    }
+}
+
+/// Subscribers to progress updates and callers waiting for an index to be applied.
+pub struct ProgressSubscribers {
+    last: Option<ClusterProgress>,
+    subscribers: Vec<Recipient<ClusterProgress>>,
+    waiters: Vec<(u64, oneshot::Sender<ClusterProgress>)>,
+}
+
+impl ProgressSubscribers {
+    pub fn new() -> Self {
+        ProgressSubscribers {
+            last: None,
+            subscribers: Vec::new(),
+            waiters: Vec::new(),
+        }
+    }
+
+    pub fn subscribe(&mut self, recipient: Recipient<ClusterProgress>) {
+        if let Some(ref last) = self.last {
+            let _ = recipient.do_send(last.clone());
+        }
+        self.subscribers.push(recipient);
+    }
+
+    /// Wait until `index` has been applied. Resolves right away if it already is.
+    pub fn wait(&mut self, index: u64) -> oneshot::Receiver<ClusterProgress> {
+        let (tx, rx) = oneshot::channel();
+
+        match self.last {
+            Some(ref last) if last.applied_index >= index => {
+                let _ = tx.send(last.clone());
+            }
+            _ => self.waiters.push((index, tx)),
+        }
+
+        rx
+    }
+
+    /// Like `wait`, failing with `ReadError::Timeout` once `timeout` passed.
+    pub fn wait_for(&mut self, index: u64, timeout: Duration) -> impl Future<Item = ClusterProgress, Error = ReadError> {
+        Timeout::new(self.wait(index), timeout).map_err(|err| {
+            if err.is_elapsed() {
+                ReadError::Timeout
+            } else {
+                ReadError::Failed
+            }
+        })
+    }
+
+    /// Publish new progress to subscribers and waiters, if it changed.
+    pub fn publish(&mut self, progress: ClusterProgress) {
+        if self.last.as_ref() == Some(&progress) {
+            return;
+        }
+
+        // drop subscribers which went away
+        self.subscribers
+            .retain(|subscriber| subscriber.do_send(progress.clone()).is_ok());
+
+        let waiters = std::mem::replace(&mut self.waiters, Vec::new());
+        for (index, tx) in waiters {
+            if progress.applied_index >= index {
+                let _ = tx.send(progress.clone());
+            } else {
+                self.waiters.push((index, tx));
+            }
+        }
+
+        self.last = Some(progress);
+    }
+}
+
+/// Commit index of a leader: the highest index a majority of every voter configuration holds,
+/// once it's an entry of the current term, earlier entries only commit through one. `matched`
+/// answers the last index a follower acknowledged in this term and the term of that entry,
+/// the leader itself counts with its last log index.
+pub(crate) fn quorum_commit<F>(configs: &[BTreeSet<NodeId>], leader: NodeId, last_log_index: u64, term: u64, matched: F) -> Option<u64>
+where
+    F: Fn(NodeId) -> Option<(u64, u64)>,
+{
+    let mut commit: Option<u64> = None;
+
+    for voters in configs {
+        let acked = voters
+            .iter()
+            .filter(|id| **id != leader)
+            .filter_map(|id| matched(*id))
+            .collect::<Vec<_>>();
+        let mut held = voters
+            .iter()
+            .map(|id| match *id == leader {
+                true => last_log_index,
+                false => matched(*id).map_or(0, |(index, _)| index),
+            })
+            .collect::<Vec<_>>();
+        held.sort_by(|a, b| b.cmp(a));
+
+        let index = match held.get(voters.len() / 2) {
+            Some(index) => *index,
+            None => continue,
+        };
+        // the entries of the leader's term end its log, so the entry at `index` is of this
+        // term once an entry at or before it is; a lone leader has nothing else to go by
+        let current = voters.len() == 1 || acked.iter().any(|(acked, entry_term)| *acked <= index && *entry_term == term);
+        if !current {
+            return None;
+        }
+        commit = Some(commit.map_or(index, |commit| commit.min(index)));
+    }
+
+    commit
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use std::collections::HashMap;
+
+    fn voters(ids: &[NodeId]) -> BTreeSet<NodeId> {
+        ids.iter().cloned().collect()
+    }
+
+    #[test]
+    fn majority_of_the_current_term_commits() {
+        let matched = [(2, (8, 3)), (3, (5, 3))].iter().cloned().collect::<HashMap<_, _>>();
+        let commit = quorum_commit(&[voters(&[1, 2, 3])], 1, 10, 3, |id| matched.get(&id).cloned());
+        assert_eq!(commit, Some(8));
+    }
+
+    #[test]
+    fn entries_of_an_earlier_term_wait() {
+        let matched = [(2, (8, 2)), (3, (5, 2))].iter().cloned().collect::<HashMap<_, _>>();
+        assert_eq!(quorum_commit(&[voters(&[1, 2, 3])], 1, 10, 3, |id| matched.get(&id).cloned()), None);
+    }
+
+    #[test]
+    fn joint_consensus_commits_in_both_configs() {
+        let matched = [(2, (9, 3)), (3, (9, 3)), (4, (6, 3))].iter().cloned().collect::<HashMap<_, _>>();
+        let configs = [voters(&[1, 2, 3]), voters(&[1, 4, 5])];
+        assert_eq!(quorum_commit(&configs, 1, 10, 3, |id| matched.get(&id).cloned()), Some(6));
+    }
+
+    #[test]
+    fn leader_and_one_follower_commit() {
+        let matched = [(2, (10, 3)), (3, (8, 3))].iter().cloned().collect::<HashMap<_, _>>();
+        assert_eq!(quorum_commit(&[voters(&[1, 2, 3])], 1, 10, 3, |id| matched.get(&id).cloned()), Some(10));
+    }
+
+    #[test]
+    fn lone_leader_commits_its_log() {
+        assert_eq!(quorum_commit(&[voters(&[1])], 1, 10, 3, |_| None), Some(10));
+    }
+
+    fn matches() -> impl Strategy<Value = (u64, HashMap<NodeId, (u64, u64)>)> {
+        (0..20u64).prop_flat_map(|last| {
+            let matched = proptest::collection::hash_map(2..7u64, (0..=last, 2..=3u64), 0..6);
+            (Just(last), matched)
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn commits_are_held_by_a_majority_of_every_config(
+            configs in proptest::collection::vec(proptest::collection::btree_set(1..7u64, 1..6), 1..=2),
+            (last, matched) in matches(),
+        ) {
+            let held = |id: NodeId| if id == 1 { last } else { matched.get(&id).map_or(0, |(index, _)| *index) };
+
+            if let Some(commit) = quorum_commit(&configs, 1, last, 3, |id| matched.get(&id).cloned()) {
+                prop_assert!(commit <= last);
+                for voters in configs.iter() {
+                    let holding = voters.iter().filter(|id| held(**id) >= commit).count();
+                    prop_assert!(holding > voters.len() / 2, "{} of {:?} hold {}", holding, voters, commit);
+                }
+            }
+        }
+
+        #[test]
+        fn caught_up_followers_commit_the_log(
+            configs in proptest::collection::vec(proptest::collection::btree_set(1..7u64, 1..6), 1..=2),
+            last in 1..20u64,
+        ) {
+            let commit = quorum_commit(&configs, 1, last, 3, |_| Some((last, 3)));
+            prop_assert_eq!(commit, Some(last));
+        }
+    }
+
+    fn progress(applied: u64) -> ClusterProgress {
+        ClusterProgress { term: 1, commit_index: applied, applied_index: applied }
+    }
+
+    #[test]
+    fn waiting_for_an_index_times_out() {
+        let mut runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
+        let mut subscribers = ProgressSubscribers::new();
+        subscribers.publish(progress(4));
+
+        let waited = runtime.block_on(subscribers.wait_for(5, Duration::from_millis(10)));
+        assert_eq!(waited, Err(ReadError::Timeout));
+
+        let waited = runtime.block_on(subscribers.wait_for(4, Duration::from_millis(10)));
+        assert_eq!(waited, Ok(progress(4)));
+    }
+
+    #[test]
+    fn waiters_resolve_once_applied() {
+        let mut runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
+        let mut subscribers = ProgressSubscribers::new();
+        let waited = subscribers.wait_for(5, Duration::from_secs(5));
+        subscribers.publish(progress(5));
+
+        assert_eq!(runtime.block_on(waited), Ok(progress(5)));
+    }
+}
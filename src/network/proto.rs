@@ -0,0 +1,391 @@
+//! Protobuf encoding of peer sessions, see `proto/raftor.proto`.
+
+use byteorder::{BigEndian, ByteOrder};
+use bytes::{BufMut, BytesMut};
+use prost::Message;
+use serde::Serialize;
+use std::io;
+use tokio::codec::{Decoder, Encoder};
+
+use crate::config::{ClusterMetadata, NodeInfo};
+use crate::network::compress::{self, Compression, FLAGS};
+use crate::error::RaftorError;
+use crate::network::codec;
+use crate::network::remote;
+use crate::network::{LinkDebug, LogState, NodeRequest, NodeResponse};
+
+/// Types generated from `proto/raftor.proto`.
+pub mod pb {
+    include!(concat!(env!("OUT_DIR"), "/raftor.v1.rs"));
+}
+
+use self::pb::{node_request::Request, node_response::Response};
+
+fn invalid<E: std::fmt::Display>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+}
+
+impl From<NodeInfo> for pb::NodeInfo {
+    fn from(info: NodeInfo) -> pb::NodeInfo {
+        pb::NodeInfo {
+            cluster_addr: info.cluster_addr,
+            app_addr: info.app_addr,
+            public_addr: info.public_addr,
+            labels: info.labels.into_iter().collect(),
+            id: info.id.map(|id| pb::NodeId { id: id }),
+            cluster: info.cluster.map(|cluster| pb::ClusterMetadata {
+                name: cluster.name,
+                labels: cluster.labels.into_iter().collect(),
+            }),
+        }
+    }
+}
+
+impl From<pb::NodeInfo> for NodeInfo {
+    fn from(info: pb::NodeInfo) -> NodeInfo {
+        NodeInfo {
+            cluster_addr: info.cluster_addr,
+            app_addr: info.app_addr,
+            public_addr: info.public_addr,
+            id: info.id.map(|id| id.id),
+            labels: info.labels.into_iter().collect(),
+            cluster: info.cluster.map(|cluster| ClusterMetadata {
+                name: cluster.name,
+                labels: cluster.labels.into_iter().collect(),
+            }),
+        }
+    }
+}
+
+impl From<NodeRequest> for pb::NodeRequest {
+    fn from(req: NodeRequest) -> pb::NodeRequest {
+        let request = match req {
+            NodeRequest::Ping => Request::Ping(pb::Ping {}),
+            NodeRequest::Join(id, info, version, token, log) => Request::Join(pb::Join {
+                node_id: id,
+                info: Some(info.into()),
+                version: version,
+                token: token.unwrap_or_default(),
+                last_log_index: log.last_log_index,
+                last_log_term: log.last_log_term,
+                has_data: log.has_data,
+            }),
+            NodeRequest::Message(msg_id, type_id, payload) => Request::Message(pb::Envelope {
+                msg_id: msg_id,
+                type_id: type_id,
+                payload: payload,
+                error: Vec::new(),
+            }),
+            NodeRequest::Dispatch(type_id, payload) => Request::Dispatch(pb::Dispatch {
+                type_id: type_id,
+                payload: payload,
+            }),
+            NodeRequest::Batch(requests) => Request::Batch(pb::Batch {
+                requests: requests.into_iter().map(pb::NodeRequest::from).collect(),
+            }),
+            NodeRequest::Leave => Request::Leave(pb::Leave {}),
+            NodeRequest::Hello(..) => unreachable!("hellos are written raw by the encoder"),
+        };
+
+        pb::NodeRequest { request: Some(request) }
+    }
+}
+
+impl pb::NodeRequest {
+    fn into_request(self) -> io::Result<NodeRequest> {
+        match self.request {
+            Some(Request::Ping(_)) => Ok(NodeRequest::Ping),
+            Some(Request::Join(join)) => {
+                let info = join.info.ok_or_else(|| invalid("join without node info"))?;
+                let token = Some(join.token).filter(|token| !token.is_empty());
+                let log = LogState {
+                    last_log_index: join.last_log_index,
+                    last_log_term: join.last_log_term,
+                    has_data: join.has_data,
+                };
+                Ok(NodeRequest::Join(join.node_id, info.into(), join.version, token, log))
+            }
+            Some(Request::Message(msg)) => Ok(NodeRequest::Message(msg.msg_id, msg.type_id, msg.payload)),
+            Some(Request::Dispatch(msg)) => Ok(NodeRequest::Dispatch(msg.type_id, msg.payload)),
+            Some(Request::Batch(batch)) => Ok(NodeRequest::Batch(
+                batch.requests.into_iter().map(pb::NodeRequest::into_request).collect::<io::Result<_>>()?,
+            )),
+            Some(Request::Leave(_)) => Ok(NodeRequest::Leave),
+            None => Err(invalid("empty request")),
+        }
+    }
+}
+
+impl From<NodeResponse> for pb::NodeResponse {
+    fn from(res: NodeResponse) -> pb::NodeResponse {
+        let response = match res {
+            NodeResponse::Ping => Response::Ping(pb::Ping {}),
+            NodeResponse::Joined(token) => Response::Joined(pb::Joined { token: token }),
+            NodeResponse::Result(msg_id, Ok(payload)) => Response::Result(pb::Envelope {
+                msg_id: msg_id,
+                type_id: String::new(),
+                payload: payload,
+                error: Vec::new(),
+            }),
+            NodeResponse::Result(msg_id, Err(err)) => Response::Result(pb::Envelope {
+                msg_id: msg_id,
+                type_id: String::new(),
+                payload: Vec::new(),
+                error: remote::encode_payload(&err).unwrap_or_default(),
+            }),
+            NodeResponse::Hello(_) => unreachable!("hellos are written raw by the encoder"),
+        };
+
+        pb::NodeResponse { response: Some(response) }
+    }
+}
+
+impl pb::NodeResponse {
+    fn into_response(self) -> io::Result<NodeResponse> {
+        match self.response {
+            Some(Response::Ping(_)) => Ok(NodeResponse::Ping),
+            Some(Response::Joined(joined)) => Ok(NodeResponse::Joined(joined.token)),
+            Some(Response::Result(msg)) if msg.error.is_empty() => Ok(NodeResponse::Result(msg.msg_id, Ok(msg.payload))),
+            Some(Response::Result(msg)) => {
+                let err = remote::decode_payload::<RaftorError>(&msg.error).map_err(invalid)?;
+                Ok(NodeResponse::Result(msg.msg_id, Err(err)))
+            }
+            None => Err(invalid("empty response")),
+        }
+    }
+}
+
+/// Frame formats, a session switched to debugging sends JSON which every node can read.
+const FORMAT_PROTO: u8 = 0;
+const FORMAT_JSON: u8 = 1;
+
+/// Split a u32 length prefixed frame off the buffer, returning its format and body, inflated
+/// when it was compressed.
+fn split_frame(src: &mut BytesMut) -> io::Result<Option<(u8, BytesMut)>> {
+    if src.len() < 5 {
+        return Ok(None);
+    }
+    let prefix = BigEndian::read_u32(src.as_ref());
+    let size = (prefix & !FLAGS) as usize;
+
+    if src.len() >= size + 5 {
+        src.split_to(4);
+        let format = src.split_to(1)[0];
+        let buf = src.split_to(size);
+        if prefix & FLAGS != 0 {
+            Ok(Some((format, BytesMut::from(compress::inflate(prefix & FLAGS, &buf)?))))
+        } else {
+            Ok(Some((format, buf)))
+        }
+    } else {
+        Ok(None)
+    }
+}
+
+fn put_frame(format: u8, compression: Compression, buf: &[u8], dst: &mut BytesMut) {
+    let (buf, flag) = compress::deflate(compression, buf);
+    dst.reserve(buf.len() + 5);
+    dst.put_u32_be(buf.len() as u32 | flag);
+    dst.put_u8(format);
+    dst.put(&buf[..]);
+}
+
+fn encode_proto<T: Message>(msg: T, compression: Compression, dst: &mut BytesMut) -> io::Result<()> {
+    let mut buf = Vec::with_capacity(msg.encoded_len());
+    msg.encode(&mut buf).map_err(invalid)?;
+    put_frame(FORMAT_PROTO, compression, &buf, dst);
+    Ok(())
+}
+
+fn encode_json<T: Serialize>(msg: &T, compression: Compression, dst: &mut BytesMut) -> io::Result<()> {
+    let buf = serde_json::to_vec(msg).map_err(invalid)?;
+    put_frame(FORMAT_JSON, compression, &buf, dst);
+    Ok(())
+}
+
+/// Codec of an inbound session.
+pub struct ProtoNodeCodec {
+    debug: LinkDebug,
+    /// Whether the hello of the peer was read
+    greeted: bool,
+}
+
+impl ProtoNodeCodec {
+    pub fn new(debug: LinkDebug) -> ProtoNodeCodec {
+        ProtoNodeCodec { debug: debug, greeted: false }
+    }
+}
+
+// Client -> Server transport
+impl Decoder for ProtoNodeCodec {
+    type Item = NodeRequest;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let available = src.len();
+        let req = if !self.greeted {
+            match codec::decode_hello(src)? {
+                Some(hello) => {
+                    self.greeted = true;
+                    hello
+                }
+                None => return Ok(None),
+            }
+        } else {
+            match split_frame(src)? {
+                Some((FORMAT_JSON, buf)) => serde_json::from_slice::<NodeRequest>(&buf)?,
+                Some((_, buf)) => pb::NodeRequest::decode(buf.as_ref()).map_err(invalid)?.into_request()?,
+                None => return Ok(None),
+            }
+        };
+
+        codec::check_request(&self.debug, &req)?;
+        self.debug.observe(&req);
+        self.debug.received(self.debug.request_tally(&req), available - src.len());
+        self.debug.log("received", &req);
+        Ok(Some(req))
+    }
+}
+
+impl Encoder for ProtoNodeCodec {
+    type Item = NodeResponse;
+    type Error = io::Error;
+
+    fn encode(&mut self, msg: NodeResponse, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let tally = self.debug.response_tally(&msg);
+        let written = dst.len();
+        match msg {
+            NodeResponse::Hello(version) => codec::put_hello(&self.debug, &[version], dst),
+            msg if self.debug.enabled() => {
+                self.debug.log("sent", &msg);
+                encode_json(&msg, Compression::Off, dst)?;
+            }
+            msg => encode_proto(pb::NodeResponse::from(msg), Compression::Off, dst)?,
+        }
+        self.debug.sent(tally, dst.len() - written);
+        Ok(())
+    }
+}
+
+/// Codec of an outbound session, compressing the requests written to constrained links.
+pub struct ProtoClientNodeCodec {
+    debug: LinkDebug,
+    compression: Compression,
+    /// Whether the answer to the hello of this node was read
+    greeted: bool,
+}
+
+impl ProtoClientNodeCodec {
+    pub fn new(debug: LinkDebug, compression: Compression) -> ProtoClientNodeCodec {
+        ProtoClientNodeCodec { debug: debug, compression: compression, greeted: false }
+    }
+}
+
+// Server -> Client transport
+impl Decoder for ProtoClientNodeCodec {
+    type Item = NodeResponse;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let available = src.len();
+        let res = if !self.greeted {
+            match codec::decode_hello_reply(&self.debug, src)? {
+                Some(hello) => {
+                    self.greeted = true;
+                    hello
+                }
+                None => return Ok(None),
+            }
+        } else {
+            match split_frame(src)? {
+                Some((FORMAT_JSON, buf)) => serde_json::from_slice::<NodeResponse>(&buf)?,
+                Some((_, buf)) => pb::NodeResponse::decode(buf.as_ref()).map_err(invalid)?.into_response()?,
+                None => return Ok(None),
+            }
+        };
+
+        self.debug.received(self.debug.response_tally(&res), available - src.len());
+        self.debug.log("received", &res);
+        Ok(Some(res))
+    }
+}
+
+impl Encoder for ProtoClientNodeCodec {
+    type Item = NodeRequest;
+    type Error = io::Error;
+
+    fn encode(&mut self, msg: NodeRequest, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let tally = self.debug.request_tally(&msg);
+        let written = dst.len();
+        match msg {
+            NodeRequest::Hello(min, max) => codec::put_hello(&self.debug, &[min, max], dst),
+            msg if self.debug.enabled() => {
+                self.debug.log("sent", &msg);
+                encode_json(&msg, self.compression, dst)?;
+            }
+            msg => encode_proto(pb::NodeRequest::from(msg), self.compression, dst)?,
+        }
+        self.debug.sent(tally, dst.len() - written);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info() -> NodeInfo {
+        NodeInfo {
+            cluster_addr: "10.0.0.1:8000".to_owned(),
+            app_addr: "10.0.0.1:9000".to_owned(),
+            public_addr: "10.0.0.1:8080".to_owned(),
+            id: Some(7),
+            labels: vec![("zone".to_owned(), "a".to_owned())].into_iter().collect(),
+            cluster: None,
+        }
+    }
+
+    #[test]
+    fn requests_convert_back() {
+        let log = LogState { last_log_index: 4, last_log_term: 2, has_data: true };
+        let batch = NodeRequest::Batch(vec![
+            NodeRequest::Join(7, info(), 3, None, log),
+            NodeRequest::Message(1, "VoteRequest".to_owned(), vec![1, 2]),
+            NodeRequest::Dispatch("app.shout".to_owned(), vec![3]),
+        ]);
+
+        match pb::NodeRequest::from(batch).into_request().unwrap() {
+            NodeRequest::Batch(requests) => match &requests[..] {
+                [NodeRequest::Join(7, joined, 3, None, joined_log), NodeRequest::Message(1, type_id, payload), NodeRequest::Dispatch(_, _)] => {
+                    assert_eq!(joined, &info());
+                    assert_eq!(joined_log, &log);
+                    assert_eq!((type_id.as_str(), payload.as_slice()), ("VoteRequest", &[1, 2][..]));
+                }
+                requests => panic!("unexpected requests {:?}", requests),
+            },
+            req => panic!("unexpected request {:?}", req),
+        }
+    }
+
+    #[test]
+    fn errors_convert_back() {
+        let res = NodeResponse::Result(3, Err(RaftorError::NotLeader { leader_hint: Some(2) }));
+        match pb::NodeResponse::from(res).into_response().unwrap() {
+            NodeResponse::Result(3, Err(err)) => assert_eq!(err, RaftorError::NotLeader { leader_hint: Some(2) }),
+            res => panic!("unexpected response {:?}", res),
+        }
+        assert!(pb::NodeRequest { request: None }.into_request().is_err());
+    }
+
+    #[test]
+    fn frames_split_once_complete() {
+        let mut buf = BytesMut::new();
+        put_frame(FORMAT_JSON, Compression::Off, b"{}", &mut buf);
+        let mut partial = BytesMut::from(&buf[..buf.len() - 1]);
+        assert!(split_frame(&mut partial).unwrap().is_none());
+
+        let (format, body) = split_frame(&mut buf).unwrap().unwrap();
+        assert_eq!((format, body.as_ref()), (FORMAT_JSON, &b"{}"[..]));
+        assert!(buf.is_empty());
+    }
+}
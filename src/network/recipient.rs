@@ -1,15 +1,21 @@
 use actix::prelude::*;
 use actix::dev::ToEnvelope;
+use actix_web::{error::BlockingError, web};
+use futures::future::{self, Either};
+use log::error;
 use serde::{de::DeserializeOwned, Serialize};
 use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::sync::oneshot::Sender;
 use std::marker::PhantomData;
 
-use crate::network::remote::RemoteMessage;
+use crate::error::RaftorError;
+use crate::network::codec::RemoteResult;
+use crate::network::remote::{decode_payload, encode_payload, RemoteMessage, OFFLOAD_THRESHOLD};
 
 pub trait RemoteMessageHandler: Send + Sync {
-    fn handle(&self, msg: String, sender: Sender<String>);
+    /// Handle an encoded message, `sender` gets the encoded answer or why there is none.
+    fn handle(&self, msg: Vec<u8>, sender: Sender<RemoteResult>);
 }
 
 /// Remote message handler
@@ -31,21 +37,37 @@ where
     A: Actor + Handler<M>,
     A::Context: ToEnvelope<A, M>,
 {
-    fn handle(&self, msg: String, sender: Sender<String>) {
-        let msg = serde_json::from_slice::<M>(msg.as_ref()).unwrap();
-        Arbiter::spawn(self.recipient.send(msg).then(|res| {
-            match res {
-                Ok(res) => {
-                    let body = serde_json::to_string(&res).unwrap();
-                    let _ = sender.send(body);
-                }
-                Err(e) => (),
-            }
-            Ok::<_, ()>(())
-        }))
+    fn handle(&self, msg: Vec<u8>, sender: Sender<RemoteResult>) {
+        let recipient = self.recipient.clone();
+
+        // decode large payloads on the blocking pool to keep the session's event loop responsive
+        let decoded = if msg.len() > OFFLOAD_THRESHOLD {
+            Either::A(web::block(move || decode_payload::<M>(&msg)).map_err(|err| match err {
+                BlockingError::Error(err) => err,
+                BlockingError::Canceled => RaftorError::Failed("decoding was canceled".to_owned()),
+            }))
+        } else {
+            Either::B(future::result(decode_payload::<M>(&msg)))
+        };
+
+        Arbiter::spawn(
+            decoded
+                .and_then(move |msg| recipient.send(msg).map_err(RaftorError::from))
+                .and_then(|res| encode_payload(&res))
+                .then(move |res| {
+                    if let Err(ref err) = res {
+                        error!("Handling {}: {}", M::type_id(), err);
+                    }
+                    let _ = sender.send(res);
+                    Ok::<_, ()>(())
+                }),
+        )
     }
 }
 
+/// Type id prefix reserved for application defined messages, built-in messages never use it.
+pub const APP_MESSAGE_PREFIX: &str = "app.";
+
 pub type Handlers = HashMap<&'static str, Arc<dyn RemoteMessageHandler>>;
 
 pub struct HandlerRegistry {
@@ -70,7 +92,111 @@ impl HandlerRegistry {
             .insert(M::type_id(), Arc::new(Provider { recipient: r, m: PhantomData }));
     }
 
+    /// Register the handler of an application defined message, its type id must start with
+    /// `APP_MESSAGE_PREFIX` and may only be registered once.
+    pub fn register_app<M, A>(&mut self, r: Addr<A>) -> Result<(), String>
+    where
+        M: RemoteMessage + 'static,
+        M::Result: Send + Serialize + DeserializeOwned,
+        A: Actor + Handler<M>,
+        A::Context: ToEnvelope<A, M>,
+    {
+        let type_id = M::type_id();
+
+        if !type_id.starts_with(APP_MESSAGE_PREFIX) {
+            return Err(format!("Application message type id {} must start with {}", type_id, APP_MESSAGE_PREFIX));
+        }
+        if self.handlers.contains_key(type_id) {
+            return Err(format!("A handler for {} is already registered", type_id));
+        }
+
+        self.register::<M, A>(r);
+        Ok(())
+    }
+
     pub fn get(&self, type_id: &str) -> Option<&Arc<dyn RemoteMessageHandler>> {
         self.handlers.get(type_id)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    struct Echo;
+
+    impl Actor for Echo {
+        type Context = Context<Self>;
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct Shout(String);
+
+    impl Message for Shout {
+        type Result = String;
+    }
+
+    impl RemoteMessage for Shout {
+        fn type_id() -> &'static str {
+            "Shout"
+        }
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct AppShout(String);
+
+    impl Message for AppShout {
+        type Result = String;
+    }
+
+    impl RemoteMessage for AppShout {
+        fn type_id() -> &'static str {
+            "app.shout"
+        }
+    }
+
+    impl Handler<Shout> for Echo {
+        type Result = String;
+
+        fn handle(&mut self, msg: Shout, _: &mut Self::Context) -> String {
+            msg.0.to_uppercase()
+        }
+    }
+
+    impl Handler<AppShout> for Echo {
+        type Result = String;
+
+        fn handle(&mut self, msg: AppShout, _: &mut Self::Context) -> String {
+            msg.0.to_uppercase()
+        }
+    }
+
+    #[test]
+    fn app_messages_need_the_prefix_and_register_once() {
+        let echo = Context::<Echo>::new().address();
+        let mut registry = HandlerRegistry::new();
+
+        assert!(registry.register_app::<Shout, _>(echo.clone()).is_err());
+        assert!(registry.register_app::<AppShout, _>(echo.clone()).is_ok());
+        assert!(registry.register_app::<AppShout, _>(echo).is_err());
+        assert!(registry.get("app.shout").is_some());
+        assert!(registry.get("Shout").is_none());
+    }
+
+    #[test]
+    fn handlers_answer_with_the_encoded_result() {
+        let mut sys = System::new("recipient");
+        let answer = sys
+            .block_on(future::lazy(|| {
+                let mut registry = HandlerRegistry::new();
+                registry.register::<Shout, _>(Echo.start());
+
+                let (tx, rx) = tokio::sync::oneshot::channel();
+                registry.get("Shout").unwrap().handle(encode_payload(&Shout("hi".to_owned())).unwrap(), tx);
+                rx
+            }))
+            .unwrap();
+        assert_eq!(decode_payload::<String>(&answer.unwrap()).unwrap(), "HI");
+    }
+}
@@ -6,15 +6,37 @@ use serde::{de::DeserializeOwned, Serialize};
 use std::marker::PhantomData;
 use tokio::sync::oneshot;
 
-use crate::network::Node;
-use crate::raft::{MemRaft, ChangeRaftClusterConfig};
+use crate::error::RaftorError;
+use crate::network::codec::RemoteResult;
+use crate::network::{Node, ReadIndex};
+use crate::raft::{MemRaft, ChangeRaftClusterConfig, ClientProposal, ConfirmBackup, GetClientSession, ProposeMembership, RequestCatchUp};
+use crate::raft::storage::GetBlobChunk;
 use crate::server;
 
+/// Payloads estimated or known to be larger than this are (de)serialized on the blocking
+/// thread pool instead of the actor's event loop.
+pub const OFFLOAD_THRESHOLD: usize = 16 * 1024;
+
+/// Encode a message or answer carried by a peer frame: MessagePack with named fields, so the
+/// serde attributes of application messages keep working.
+pub fn encode_payload<T: Serialize>(msg: &T) -> Result<Vec<u8>, RaftorError> {
+    rmp_serde::to_vec_named(msg).map_err(|err| RaftorError::Serialization(err.to_string()))
+}
+
+pub fn decode_payload<T: DeserializeOwned>(payload: &[u8]) -> Result<T, RaftorError> {
+    rmp_serde::from_slice(payload).map_err(|err| RaftorError::Serialization(err.to_string()))
+}
+
 pub trait RemoteMessage: Message + Send + Sync + Serialize + DeserializeOwned
 where
     Self::Result: Send + Serialize + DeserializeOwned,
 {
     fn type_id() -> &'static str;
+
+    /// Rough estimate of the serialized size, used to decide whether encoding is offloaded.
+    fn size_hint(&self) -> usize {
+        0
+    }
 }
 
 /// SendRemoteMessage(Message)
@@ -43,7 +65,7 @@ where
     M: RemoteMessage + 'static,
     M::Result: Send + Serialize + DeserializeOwned,
 {
-    pub rx: oneshot::Receiver<String>,
+    pub rx: oneshot::Receiver<RemoteResult>,
     pub m: PhantomData<M>,
 }
 
@@ -57,17 +79,21 @@ where
         _: &mut Context<Node>,
         tx: Option<R>,
     ) {
+        // dropping `tx` answers the sender with an error
         Arbiter::spawn(self.rx.map_err(|e| error!("{:?}", e)).and_then(move |msg| {
-            // Raft node has not been initialized yet
-            if msg == "" {
-                return Err(());
+            // an error is why the peer couldn't handle the message, e.g. its raft node isn't initialized yet
+            match msg.and_then(|msg| decode_payload::<M::Result>(&msg)) {
+                Ok(msg) => {
+                    if let Some(tx) = tx {
+                        let _ = tx.send(msg);
+                    }
+                    Ok(())
+                }
+                Err(err) => {
+                    error!("Answer to {}: {}", M::type_id(), err);
+                    Err(())
+                }
             }
-
-            let msg = serde_json::from_slice::<M::Result>(msg.as_ref()).unwrap();
-            if let Some(tx) = tx {
-                let _ = tx.send(msg);
-            }
-            Ok(())
         }));
     }
 }
@@ -80,6 +106,11 @@ impl<D: AppData> RemoteMessage for messages::AppendEntriesRequest<D> {
     fn type_id() -> &'static str {
         "AppendEntriesRequest"
     }
+
+    fn size_hint(&self) -> usize {
+        // entries are small for the built-in data type, assume ~128 bytes each
+        self.entries.len() * 128
+    }
 }
 
 impl RemoteMessage for messages::VoteRequest {
@@ -92,6 +123,11 @@ impl RemoteMessage for messages::InstallSnapshotRequest {
     fn type_id() -> &'static str {
         "InstallSnapshotRequest"
     }
+
+    fn size_hint(&self) -> usize {
+        // binary data is encoded as a JSON array of numbers
+        self.data.len() * 4
+    }
 }
 
 impl<D: AppData, R: AppDataResponse, E: AppError> RemoteMessage
@@ -108,6 +144,48 @@ impl RemoteMessage for ChangeRaftClusterConfig {
     }
 }
 
+impl RemoteMessage for ClientProposal {
+    fn type_id() -> &'static str {
+        "ClientProposal"
+    }
+}
+
+impl RemoteMessage for ProposeMembership {
+    fn type_id() -> &'static str {
+        "ProposeMembership"
+    }
+}
+
+impl RemoteMessage for GetClientSession {
+    fn type_id() -> &'static str {
+        "GetClientSession"
+    }
+}
+
+impl RemoteMessage for ReadIndex {
+    fn type_id() -> &'static str {
+        "ReadIndex"
+    }
+}
+
+impl RemoteMessage for RequestCatchUp {
+    fn type_id() -> &'static str {
+        "RequestCatchUp"
+    }
+}
+
+impl RemoteMessage for ConfirmBackup {
+    fn type_id() -> &'static str {
+        "ConfirmBackup"
+    }
+}
+
+impl RemoteMessage for GetBlobChunk {
+    fn type_id() -> &'static str {
+        "GetBlobChunk"
+    }
+}
+
 /// Impl RemoteMessage for Application Messages
 impl RemoteMessage for server::Join {
     fn type_id() -> &'static str {
@@ -138,3 +216,33 @@ impl RemoteMessage for server::GetMembers {
         "GetMembers"
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Serialize)]
+    struct Before {
+        room: String,
+    }
+
+    #[derive(Deserialize, Debug, PartialEq)]
+    struct After {
+        room: String,
+        #[serde(default)]
+        private: bool,
+    }
+
+    #[test]
+    fn fields_are_matched_by_name() {
+        let payload = encode_payload(&Before { room: "lobby".to_owned() }).unwrap();
+        let decoded = decode_payload::<After>(&payload).unwrap();
+        assert_eq!(decoded, After { room: "lobby".to_owned(), private: false });
+    }
+
+    #[test]
+    fn garbage_is_a_serialization_error() {
+        assert!(matches!(decode_payload::<After>(&[0xc1]), Err(RaftorError::Serialization(_))));
+    }
+}
@@ -0,0 +1,73 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use crate::clock;
+use crate::network::codec::RemoteResult;
+
+/// How long the answer to a request is kept for retransmissions
+const RESPONSE_TTL: Duration = Duration::from_secs(10);
+/// Answers kept per peer session at most
+const MAX_RESPONSES: usize = 1024;
+
+/// Recent answers of a peer session by request id. A peer resuming its session sends its
+/// unanswered requests again, those answered before the disconnect get the cached answer
+/// instead of being handled twice.
+#[derive(Default)]
+pub struct ResponseCache {
+    responses: HashMap<u64, RemoteResult>,
+    order: VecDeque<(Instant, u64)>,
+}
+
+impl ResponseCache {
+    pub fn get(&mut self, mid: u64) -> Option<RemoteResult> {
+        self.expire();
+        self.responses.get(&mid).cloned()
+    }
+
+    pub fn insert(&mut self, mid: u64, response: RemoteResult) {
+        self.expire();
+
+        if self.order.len() == MAX_RESPONSES {
+            if let Some((_, oldest)) = self.order.pop_front() {
+                self.responses.remove(&oldest);
+            }
+        }
+
+        self.order.push_back((clock::now(), mid));
+        self.responses.insert(mid, response);
+    }
+
+    fn expire(&mut self) {
+        while let Some(&(at, mid)) = self.order.front() {
+            if clock::elapsed(at) < RESPONSE_TTL {
+                break;
+            }
+            self.order.pop_front();
+            self.responses.remove(&mid);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn answers_are_replayed_by_request_id() {
+        let mut cache = ResponseCache::default();
+        cache.insert(1, Ok(vec![1]));
+        assert_eq!(cache.get(1).map(|res| res.ok()), Some(Some(vec![1])));
+        assert!(cache.get(2).is_none());
+    }
+
+    #[test]
+    fn the_oldest_answers_are_dropped() {
+        let mut cache = ResponseCache::default();
+        for mid in 0..=MAX_RESPONSES as u64 {
+            cache.insert(mid, Ok(Vec::new()));
+        }
+        assert!(cache.get(0).is_none());
+        assert!(cache.get(1).is_some());
+        assert!(cache.get(MAX_RESPONSES as u64).is_some());
+    }
+}
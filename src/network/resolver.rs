@@ -0,0 +1,172 @@
+use log::warn;
+use std::collections::HashMap;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use crate::clock;
+
+/// Turns peer addresses (`host:port`) into socket addresses.
+pub trait Resolver: Send + Sync {
+    /// Resolve an address, with how long the answer may be cached.
+    fn resolve(&self, addr: &str) -> io::Result<(Vec<SocketAddr>, Duration)>;
+}
+
+/// Resolver of the operating system, which doesn't expose record TTLs so answers are cached
+/// for a fixed time.
+pub struct SystemResolver {
+    ttl: Duration,
+}
+
+impl SystemResolver {
+    pub fn new(ttl: Duration) -> SystemResolver {
+        SystemResolver { ttl: ttl }
+    }
+}
+
+impl Resolver for SystemResolver {
+    fn resolve(&self, addr: &str) -> io::Result<(Vec<SocketAddr>, Duration)> {
+        let addrs = addr.to_socket_addrs()?.collect();
+        Ok((addrs, self.ttl))
+    }
+}
+
+struct CachedAddrs {
+    addrs: Vec<SocketAddr>,
+    expires: Instant,
+}
+
+/// Caches the answers of a resolver until their TTL runs out or a connection to the address
+/// fails, so peers moved to another host through DNS are found again. Shared by the peers of
+/// both networks.
+pub struct CachingResolver {
+    inner: RwLock<Box<dyn Resolver>>,
+    cache: Mutex<HashMap<String, CachedAddrs>>,
+}
+
+pub type PeerResolver = Arc<CachingResolver>;
+
+impl CachingResolver {
+    pub fn new<R: Resolver + 'static>(resolver: R) -> CachingResolver {
+        CachingResolver {
+            inner: RwLock::new(Box::new(resolver)),
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Resolve through another resolver from now on, dropping the cached answers.
+    pub fn replace<R: Resolver + 'static>(&self, resolver: R) {
+        *self.inner.write().unwrap() = Box::new(resolver);
+        self.cache.lock().unwrap().clear();
+    }
+
+    /// Addresses to connect to, blocks while the address is resolved again.
+    pub fn lookup(&self, addr: &str) -> io::Result<Vec<SocketAddr>> {
+        // literal socket addresses don't need resolving
+        if let Ok(socket_addr) = addr.parse() {
+            return Ok(vec![socket_addr]);
+        }
+
+        if let Some(cached) = self.cache.lock().unwrap().get(addr) {
+            if cached.expires > clock::now() {
+                return Ok(cached.addrs.clone());
+            }
+        }
+
+        let resolved = self.inner.read().unwrap().resolve(addr);
+
+        match resolved {
+            Ok((ref addrs, _)) if addrs.is_empty() => {
+                Err(io::Error::new(io::ErrorKind::NotFound, format!("{} has no addresses", addr)))
+            }
+            Ok((addrs, ttl)) => {
+                self.cache.lock().unwrap().insert(
+                    addr.to_owned(),
+                    CachedAddrs {
+                        addrs: addrs.clone(),
+                        expires: clock::now() + ttl,
+                    },
+                );
+                Ok(addrs)
+            }
+            Err(err) => {
+                // keep using the last answer while the resolver is unavailable
+                match self.cache.lock().unwrap().get(addr) {
+                    Some(cached) => {
+                        warn!("Failed to resolve {}, using expired addresses: {}", addr, err);
+                        Ok(cached.addrs.clone())
+                    }
+                    None => Err(err),
+                }
+            }
+        }
+    }
+
+    /// Resolve the address again on the next lookup, after connecting to it failed.
+    pub fn invalidate(&self, addr: &str) {
+        if let Some(cached) = self.cache.lock().unwrap().get_mut(addr) {
+            cached.expires = clock::now();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Answers with a fixed address until told to fail, counting the lookups.
+    struct Fixed {
+        lookups: Arc<AtomicUsize>,
+        failing: Arc<Mutex<bool>>,
+        ttl: Duration,
+    }
+
+    impl Resolver for Fixed {
+        fn resolve(&self, _addr: &str) -> io::Result<(Vec<SocketAddr>, Duration)> {
+            self.lookups.fetch_add(1, Ordering::SeqCst);
+            if *self.failing.lock().unwrap() {
+                return Err(io::Error::new(io::ErrorKind::Other, "resolver down"));
+            }
+            Ok((vec!["10.0.0.1:8000".parse().unwrap()], self.ttl))
+        }
+    }
+
+    fn resolver(ttl: Duration) -> (CachingResolver, Arc<AtomicUsize>, Arc<Mutex<bool>>) {
+        let lookups = Arc::new(AtomicUsize::new(0));
+        let failing = Arc::new(Mutex::new(false));
+        let resolver = CachingResolver::new(Fixed {
+            lookups: lookups.clone(),
+            failing: failing.clone(),
+            ttl: ttl,
+        });
+        (resolver, lookups, failing)
+    }
+
+    #[test]
+    fn answers_are_cached_until_invalidated() {
+        let (resolver, lookups, _) = resolver(Duration::from_secs(3600));
+        resolver.lookup("peer:8000").unwrap();
+        resolver.lookup("peer:8000").unwrap();
+        assert_eq!(lookups.load(Ordering::SeqCst), 1);
+
+        resolver.invalidate("peer:8000");
+        resolver.lookup("peer:8000").unwrap();
+        assert_eq!(lookups.load(Ordering::SeqCst), 2);
+
+        // socket addresses aren't resolved
+        resolver.lookup("127.0.0.1:8000").unwrap();
+        assert_eq!(lookups.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn expired_answers_are_used_while_the_resolver_is_down() {
+        let (resolver, _, failing) = resolver(Duration::from_secs(0));
+        let addrs = resolver.lookup("peer:8000").unwrap();
+
+        *failing.lock().unwrap() = true;
+        assert_eq!(resolver.lookup("peer:8000").unwrap(), addrs);
+        assert!(resolver.lookup("other:8000").is_err());
+    }
+}
@@ -1,14 +1,19 @@
 use actix::prelude::*;
 use actix_raft::NodeId;
-use log::error;
+use log::{debug, error, info, warn};
 use std::sync::{Arc, RwLock};
 use std::time::{Duration, Instant};
 use tokio::io::WriteHalf;
-use tokio::net::TcpStream;
 use tokio::sync::oneshot;
 
-use crate::network::{HandlerRegistry, Network, NodeCodec, NodeRequest, NodeResponse, NodeDisconnect, RestoreNode, Handshake};
+use crate::clock;
+use crate::network::replay::ResponseCache;
+use crate::network::throttle::InboundThrottle;
+use crate::network::codec::{negotiate, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+use crate::network::{HandlerRegistry, Network, NodeCodec, PeerStream, NodeRequest, NodeResponse, RestoreNode, Handshake, SessionClosed};
 use crate::config::NetworkType;
+use crate::error::RaftorError;
+use crate::mailbox::{self, Mailbox};
 use crate::raft::{AddNode, RemoveNode};
 
 // NodeSession
@@ -16,31 +21,40 @@ pub struct NodeSession {
     hb: Instant,
     network: Addr<Network>,
     net_type: NetworkType,
-    framed: actix::io::FramedWrite<WriteHalf<TcpStream>, NodeCodec>,
+    framed: actix::io::FramedWrite<WriteHalf<PeerStream>, NodeCodec>,
     id: Option<NodeId>,
     registry: Arc<RwLock<HandlerRegistry>>,
+    throttle: Option<InboundThrottle>,
+    /// Token the peer presents to resume this session after a brief disconnect
+    token: Option<String>,
+    /// Recent answers, handed to the session resuming this one
+    responses: ResponseCache,
 }
 
 impl NodeSession {
     pub fn new(
-        framed: actix::io::FramedWrite<WriteHalf<TcpStream>, NodeCodec>,
+        framed: actix::io::FramedWrite<WriteHalf<PeerStream>, NodeCodec>,
         network: Addr<Network>,
         registry: Arc<RwLock<HandlerRegistry>>,
         net_type: NetworkType,
+        throttle: Option<InboundThrottle>,
     ) -> NodeSession {
         NodeSession {
-            hb: Instant::now(),
+            hb: clock::now(),
             framed: framed,
             network,
             id: None,
             registry: registry,
             net_type: net_type,
+            throttle: throttle,
+            token: None,
+            responses: ResponseCache::default(),
         }
     }
 
     fn hb(&self, ctx: &mut Context<Self>) {
         ctx.run_interval(Duration::new(1, 0), |act, ctx| {
-            if Instant::now().duration_since(act.hb) > Duration::new(10, 0) {
+            if clock::now().duration_since(act.hb) > Duration::new(10, 0) {
                 println!("Client heartbeat failed, disconnecting!");
                 ctx.stop();
             }
@@ -49,6 +63,11 @@ impl NodeSession {
             act.framed.write(NodeResponse::Ping);
         });
     }
+
+    /// Fail a request without handling it, its sender gets the error right away.
+    fn fail(&mut self, mid: u64, err: RaftorError) {
+        self.framed.write(NodeResponse::Result(mid, Err(err)));
+    }
 }
 
 impl Actor for NodeSession {
@@ -62,7 +81,15 @@ impl Actor for NodeSession {
     }
 
     fn stopped(&mut self, ctx: &mut Context<Self>) {
-        self.network.do_send(NodeDisconnect(self.id.unwrap()));
+        if let Some(id) = self.id {
+            let closed = SessionClosed {
+                id: id,
+                token: self.token.take(),
+                throttle: self.throttle.take(),
+                responses: std::mem::replace(&mut self.responses, ResponseCache::default()),
+            };
+            mailbox::deliver(&self.network, closed, Mailbox::Network);
+        }
     }
 }
 
@@ -70,16 +97,80 @@ impl actix::io::WriteHandler<std::io::Error> for NodeSession {}
 
 impl StreamHandler<NodeRequest, std::io::Error> for NodeSession {
     fn handle(&mut self, msg: NodeRequest, ctx: &mut Context<Self>) {
+        crate::audit_message!(NodeRequest);
+        // the messages of a batch are throttled and handled one by one
+        if let NodeRequest::Batch(batch) = msg {
+            for msg in batch {
+                StreamHandler::<NodeRequest, std::io::Error>::handle(self, msg, ctx);
+            }
+            return;
+        }
+
+        if let Some(ref mut throttle) = self.throttle {
+            let suspended = throttle.is_suspended();
+
+            if !throttle.admit(&msg) {
+                if !suspended {
+                    warn!("Peer session {:?} exceeded its rate limit, suspending", self.id);
+                }
+                // the sender fails the request instead of waiting for its timeout
+                if let NodeRequest::Message(mid, _, _) = msg {
+                    self.fail(mid, RaftorError::Failed("the peer session exceeded its rate limit".to_owned()));
+                }
+                return;
+            }
+        }
+
         match msg {
+            NodeRequest::Hello(min, max) => match negotiate(min, max) {
+                Some(version) => {
+                    debug!("Peer session speaks protocol version {}", version);
+                    self.framed.write(NodeResponse::Hello(version));
+                }
+                None => {
+                    warn!("Refusing a peer speaking protocol versions {} to {}, this node speaks {} to {}", min, max, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION);
+                    self.framed.write(NodeResponse::Hello(0));
+                    self.framed.close();
+                    ctx.stop();
+                }
+            },
             NodeRequest::Ping => {
-                self.hb = Instant::now();
+                self.hb = clock::now();
             }
-            NodeRequest::Join(id, info) =>
+            NodeRequest::Join(id, info, version, token, log) =>
             {
                 self.id = Some(id);
-                self.network.do_send(Handshake(self.id.unwrap(), info));
+
+                fut::wrap_future::<_, Self>(self.network.send(Handshake(id, info, version, token, log)))
+                    .then(|res, act, ctx| {
+                        match res {
+                            Ok(Ok(joined)) => {
+                                // a resumed session keeps the credits it had left
+                                if joined.throttle.is_some() {
+                                    act.throttle = joined.throttle;
+                                }
+                                if let Some(responses) = joined.responses {
+                                    act.responses = responses;
+                                }
+                                act.token = Some(joined.token.clone());
+                                act.framed.write(NodeResponse::Joined(joined.token));
+                            }
+                            // a colliding id or a version out of `peer_versions` is refused, drop the session
+                            Ok(Err(_)) => ctx.stop(),
+                            Err(_) => (),
+                        }
+                        fut::ok(())
+                    })
+                    .spawn(ctx);
             }
             NodeRequest::Message(mid, type_id, body) => {
+                // sent again after the session was resumed, answered already
+                if let Some(res) = self.responses.get(mid) {
+                    debug!("Answering retransmitted request {} from node {:?} from the cache", mid, self.id);
+                    self.framed.write(NodeResponse::Result(mid, res));
+                    return;
+                }
+
                 let (tx, rx) = oneshot::channel();
                 let registry = self.registry.read().unwrap();
 
@@ -89,13 +180,17 @@ impl StreamHandler<NodeRequest, std::io::Error> for NodeSession {
                     fut::wrap_future::<_, Self>(rx)
                         .then(move |res, act, _| {
                             // println!("Got remote message {:?}", res);
-                            match res {
-                                Ok(res) => act.framed.write(NodeResponse::Result(mid, res)),
-                                Err(_) => (),
-                            }
+                            // the handler went away without answering
+                            let res = res.unwrap_or_else(|_| Err(RaftorError::Failed("the request was dropped".to_owned())));
+                            act.responses.insert(mid, res.clone());
+                            act.framed.write(NodeResponse::Result(mid, res));
                             fut::ok(())
                         })
                         .spawn(ctx)
+                } else {
+                    // fail the request right away instead of leaving the sender waiting
+                    warn!("No handler for {} from node {:?}", type_id, self.id);
+                    self.fail(mid, RaftorError::Failed(format!("no handler for {}", type_id)));
                 }
             }
             NodeRequest::Dispatch(type_id, body) => {
@@ -110,6 +205,26 @@ impl StreamHandler<NodeRequest, std::io::Error> for NodeSession {
                         .spawn(ctx)
                 }
             }
+            NodeRequest::Batch(_) => (),
+            NodeRequest::Leave => {
+                info!("Node {:?} is shutting down, closing its session", self.id);
+                // no resumption, the peer is disconnected right away
+                self.token = None;
+                ctx.stop();
+            }
         }
     }
 }
+
+/// Close the session, on shutdown.
+#[derive(Message)]
+pub struct CloseSession;
+
+impl Handler<CloseSession> for NodeSession {
+    type Result = ();
+
+    fn handle(&mut self, _: CloseSession, ctx: &mut Context<Self>) {
+        crate::audit_message!(CloseSession);
+        ctx.stop();
+    }
+}
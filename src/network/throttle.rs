@@ -0,0 +1,234 @@
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::clock;
+use crate::config::PeerRateLimit;
+use crate::network::NodeRequest;
+
+/// A token bucket refilled continuously at `rate` tokens per second, holding at most `rate`.
+pub struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64) -> Self {
+        TokenBucket {
+            rate: rate as f64,
+            tokens: rate as f64,
+            last: clock::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = clock::now();
+        let elapsed = now.duration_since(self.last);
+        let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last = now;
+    }
+
+    /// Whether `amount` tokens can be taken. More than the bucket holds can be taken once it's
+    /// full, going into debt.
+    pub fn has(&mut self, amount: u64) -> bool {
+        self.refill();
+        self.tokens >= (amount as f64).min(self.rate)
+    }
+
+    /// Take `amount` tokens, returns false if not enough are available, see `has`.
+    pub fn take(&mut self, amount: u64) -> bool {
+        if !self.has(amount) {
+            return false;
+        }
+        self.tokens -= amount as f64;
+        true
+    }
+
+    /// Take `amount` tokens, going into debt when not enough are available, returns how long
+    /// to wait until the debt is paid back.
+    pub fn reserve(&mut self, amount: u64) -> Duration {
+        self.refill();
+
+        self.tokens -= amount as f64;
+        if self.tokens >= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            let wait = -self.tokens / self.rate;
+            Duration::new(wait as u64, (wait.fract() * 1e9) as u32)
+        }
+    }
+}
+
+/// Inbound rate limiting of a peer session.
+///
+/// A session exceeding its frame or byte rate is suspended: its frames are dropped until the
+/// suspension expires.
+pub struct InboundThrottle {
+    frames: TokenBucket,
+    bytes: TokenBucket,
+    suspend_for: Duration,
+    suspended_until: Option<Instant>,
+}
+
+impl InboundThrottle {
+    pub fn new(limit: &PeerRateLimit) -> Self {
+        InboundThrottle {
+            frames: TokenBucket::new(limit.frames_per_sec),
+            bytes: TokenBucket::new(limit.bytes_per_sec),
+            suspend_for: Duration::from_secs(limit.suspend_secs),
+            suspended_until: None,
+        }
+    }
+
+    /// Returns whether the frame may be processed. Heartbeats are always let through.
+    pub fn admit(&mut self, msg: &NodeRequest) -> bool {
+        if let NodeRequest::Ping | NodeRequest::Hello(..) = msg {
+            return true;
+        }
+
+        let now = clock::now();
+
+        if let Some(until) = self.suspended_until {
+            if now < until {
+                return false;
+            }
+            self.suspended_until = None;
+        }
+
+        // neither bucket is charged for a refused frame
+        let len = frame_len(msg) as u64;
+        if self.frames.has(1) && self.bytes.has(len) {
+            self.frames.take(1);
+            self.bytes.take(len);
+            return true;
+        }
+
+        self.suspended_until = Some(now + self.suspend_for);
+        false
+    }
+
+    pub fn is_suspended(&self) -> bool {
+        self.suspended_until.map(|until| clock::now() < until).unwrap_or(false)
+    }
+}
+
+/// Approximate size of a frame on the wire
+fn frame_len(msg: &NodeRequest) -> usize {
+    match msg {
+        NodeRequest::Message(_, type_id, body) => type_id.len() + body.len(),
+        NodeRequest::Dispatch(type_id, body) => type_id.len() + body.len(),
+        NodeRequest::Batch(batch) => batch.iter().map(frame_len).sum(),
+        _ => 0,
+    }
+}
+
+/// Outbound bandwidth of a node across all its peer connections, shared by the peers of both
+/// networks and changeable at runtime. Frames are held back until the bucket allows them,
+/// control plane messages (votes, membership changes, pings) are never held back.
+#[derive(Clone, Default)]
+pub struct EgressLimiter {
+    inner: Arc<Mutex<Egress>>,
+}
+
+#[derive(Default)]
+struct Egress {
+    bucket: Option<TokenBucket>,
+    throttled_frames: u64,
+    throttled_ms: u64,
+}
+
+/// Limit of the egress limiter and how much it held frames back.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct EgressStats {
+    /// Unlimited if not set
+    pub bytes_per_sec: Option<u64>,
+    /// Frames held back since the node started
+    pub throttled_frames: u64,
+    /// Total time frames were held back
+    pub throttled_ms: u64,
+}
+
+impl EgressLimiter {
+    pub fn new(bytes_per_sec: Option<u64>) -> EgressLimiter {
+        let limiter = EgressLimiter::default();
+        limiter.set_rate(bytes_per_sec);
+        limiter
+    }
+
+    /// Change the limit, frames already held back keep their delay.
+    pub fn set_rate(&self, bytes_per_sec: Option<u64>) {
+        let mut egress = self.inner.lock().unwrap();
+        egress.bucket = bytes_per_sec.filter(|rate| *rate > 0).map(TokenBucket::new);
+    }
+
+    /// How long to hold back a frame of `bytes` before writing it.
+    pub fn reserve(&self, bytes: u64) -> Duration {
+        let mut egress = self.inner.lock().unwrap();
+        let delay = match egress.bucket {
+            Some(ref mut bucket) => bucket.reserve(bytes),
+            None => return Duration::from_secs(0),
+        };
+
+        if delay > Duration::from_secs(0) {
+            egress.throttled_frames += 1;
+            egress.throttled_ms += delay.as_millis() as u64;
+        }
+        delay
+    }
+
+    pub fn stats(&self) -> EgressStats {
+        let egress = self.inner.lock().unwrap();
+
+        EgressStats {
+            bytes_per_sec: egress.bucket.as_ref().map(|bucket| bucket.rate as u64),
+            throttled_frames: egress.throttled_frames,
+            throttled_ms: egress.throttled_ms,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limit(frames_per_sec: u64, bytes_per_sec: u64) -> PeerRateLimit {
+        PeerRateLimit {
+            frames_per_sec: frames_per_sec,
+            bytes_per_sec: bytes_per_sec,
+            suspend_secs: 5,
+        }
+    }
+
+    fn message(len: usize) -> NodeRequest {
+        NodeRequest::Message(1, String::new(), vec![0; len])
+    }
+
+    #[test]
+    fn frame_larger_than_the_bucket_passes_when_full() {
+        let mut bucket = TokenBucket::new(100);
+        assert!(bucket.take(1000));
+        // in debt until refilled
+        assert!(!bucket.has(1));
+    }
+
+    #[test]
+    fn refused_frame_takes_no_tokens() {
+        let mut throttle = InboundThrottle::new(&limit(2, 100));
+        assert!(throttle.admit(&message(90)));
+        // the byte bucket refuses, the frame token stays available
+        assert!(!throttle.admit(&message(90)));
+        assert!(throttle.frames.has(1));
+    }
+
+    #[test]
+    fn suspended_session_refuses_until_the_suspension_expires() {
+        let mut throttle = InboundThrottle::new(&limit(1, 1000));
+        assert!(throttle.admit(&message(1)));
+        assert!(!throttle.admit(&message(1)));
+        assert!(throttle.is_suspended());
+        assert!(throttle.admit(&NodeRequest::Ping));
+    }
+}
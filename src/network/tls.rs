@@ -0,0 +1,496 @@
+//! TLS of the peer connections, with the `tls` feature.
+//!
+//! The certificates, keys and CAs are read again by `PeerTls::reload`, every clone of a
+//! `PeerTls` shares them so the handshakes which follow use the new ones, established
+//! connections keep theirs until they're re-established.
+//!
+//! `TlsConfig::peers` overrides the CA or the server name of single peers, or has them talk
+//! plaintext. Overrides apply to the connections opened to a peer by its cluster address, and
+//! to those accepted from its IP.
+
+use actix_raft::NodeId;
+use futures::{future, Future, Poll};
+use std::io::{self, Read, Write};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+
+use crate::config::TlsConfig;
+
+#[cfg(feature = "tls")]
+use crate::config::PeerTlsOverride;
+#[cfg(feature = "tls")]
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::BufReader,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+#[cfg(feature = "tls")]
+use tokio::timer::Timeout;
+#[cfg(feature = "tls")]
+use tokio_rustls::{
+    client, server,
+    rustls::{
+        internal::pemfile, AllowAnyAuthenticatedClient, Certificate, ClientConfig, NoClientAuth, PrivateKey, RootCertStore,
+        ServerConfig, Session,
+    },
+    webpki::DNSNameRef,
+    TlsAcceptor, TlsConnector,
+};
+
+/// Protocol of a connection accepted on the TLS port, negotiated by ALPN. Connections which
+/// don't negotiate one, e.g. from peers predating ALPN, speak the peer protocol.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Protocol {
+    Peer,
+    Client,
+    Admin,
+}
+
+impl Protocol {
+    pub const ALL: [Protocol; 3] = [Protocol::Peer, Protocol::Client, Protocol::Admin];
+
+    /// ALPN id of the protocol
+    pub fn alpn(self) -> &'static str {
+        match self {
+            Protocol::Peer => "raftor-peer",
+            Protocol::Client => "raftor-client",
+            Protocol::Admin => "raftor-admin",
+        }
+    }
+
+    pub fn from_alpn(id: &[u8]) -> Option<Protocol> {
+        Protocol::ALL.iter().cloned().find(|protocol| protocol.alpn().as_bytes() == id)
+    }
+}
+
+/// Connection with a peer, encrypted when TLS is configured.
+pub enum PeerStream {
+    Plain(TcpStream),
+    #[cfg(feature = "tls")]
+    Client(client::TlsStream<TcpStream>),
+    #[cfg(feature = "tls")]
+    Server(server::TlsStream<TcpStream>),
+}
+
+pub type Handshake = Box<dyn Future<Item = PeerStream, Error = io::Error>>;
+
+/// Client and server side TLS of the peer connections of a network.
+#[derive(Clone)]
+pub struct PeerTls {
+    #[cfg(feature = "tls")]
+    config: TlsConfig,
+    #[cfg(feature = "tls")]
+    state: Arc<RwLock<TlsState>>,
+}
+
+/// Connector and acceptor built from the files of a `TlsConfig`.
+#[cfg(feature = "tls")]
+struct TlsState {
+    connector: TlsConnector,
+    acceptor: TlsAcceptor,
+    /// Connectors of the peers with a CA of their own, by cluster address
+    peer_connectors: HashMap<String, TlsConnector>,
+    /// Acceptors of the peers with a CA of their own, by IP
+    peer_acceptors: HashMap<IpAddr, TlsAcceptor>,
+    /// Last modification of the files the state was read from
+    modified: Option<SystemTime>,
+}
+
+#[cfg(feature = "tls")]
+impl TlsState {
+    fn load(config: &TlsConfig) -> io::Result<TlsState> {
+        // read first, files replaced while they're read are read again on the next check
+        let modified = files_modified(config);
+        let certs = pemfile::certs(&mut BufReader::new(File::open(&config.cert)?))
+            .map_err(|_| invalid(format!("no certificates in {}", config.cert)))?;
+        let key = load_key(&config.key)?;
+        let roots = load_roots(&config.ca)?;
+
+        let mut peer_connectors = HashMap::new();
+        let mut peer_acceptors = HashMap::new();
+        for (addr, peer) in config.peers.iter() {
+            if let Some(ref ca) = peer.ca {
+                let roots = load_roots(ca)?;
+                peer_connectors.insert(addr.clone(), connector(&certs, &key, roots.clone()));
+                if let Ok(socket) = addr.parse::<SocketAddr>() {
+                    peer_acceptors.insert(socket.ip(), acceptor(config, &certs, &key, roots)?);
+                }
+            }
+        }
+
+        Ok(TlsState {
+            connector: connector(&certs, &key, roots.clone()),
+            acceptor: acceptor(config, &certs, &key, roots)?,
+            peer_connectors: peer_connectors,
+            peer_acceptors: peer_acceptors,
+            modified: modified,
+        })
+    }
+}
+
+#[cfg(feature = "tls")]
+fn connector(certs: &[Certificate], key: &PrivateKey, roots: RootCertStore) -> TlsConnector {
+    let mut client = ClientConfig::new();
+    client.root_store = roots;
+    client.set_single_client_cert(certs.to_vec(), key.clone());
+    client.set_protocols(&[Protocol::Peer.alpn().as_bytes().to_vec()]);
+    TlsConnector::from(Arc::new(client))
+}
+
+#[cfg(feature = "tls")]
+fn acceptor(config: &TlsConfig, certs: &[Certificate], key: &PrivateKey, roots: RootCertStore) -> io::Result<TlsAcceptor> {
+    let mut server = if config.require_client_auth {
+        ServerConfig::new(AllowAnyAuthenticatedClient::new(roots))
+    } else {
+        ServerConfig::new(NoClientAuth::new())
+    };
+    server
+        .set_single_cert(certs.to_vec(), key.clone())
+        .map_err(|err| invalid(format!("{}: {}", config.cert, err)))?;
+    server.set_protocols(&Protocol::ALL.iter().map(|protocol| protocol.alpn().as_bytes().to_vec()).collect::<Vec<_>>());
+    Ok(TlsAcceptor::from(Arc::new(server)))
+}
+
+impl PeerTls {
+    #[cfg(feature = "tls")]
+    pub fn from_config(config: &TlsConfig) -> io::Result<PeerTls> {
+        let overridden = config.peers.values().filter_map(|peer| peer.server_name.as_ref());
+        for name in Some(&config.server_name).into_iter().chain(overridden) {
+            DNSNameRef::try_from_ascii_str(name).map_err(|_| invalid(format!("{} is not a valid server name", name)))?;
+        }
+
+        Ok(PeerTls {
+            config: config.clone(),
+            state: Arc::new(RwLock::new(TlsState::load(config)?)),
+        })
+    }
+
+    #[cfg(not(feature = "tls"))]
+    pub fn from_config(_config: &TlsConfig) -> io::Result<PeerTls> {
+        Err(io::Error::new(io::ErrorKind::Other, "TLS is configured but raftor was built without the `tls` feature"))
+    }
+
+    /// Read the certificates, key and CA again, the handshakes which follow use them. The
+    /// current ones are kept when the files can't be read or parsed.
+    #[cfg(feature = "tls")]
+    pub fn reload(&self) -> io::Result<()> {
+        let state = TlsState::load(&self.config)?;
+        *self.state.write().unwrap() = state;
+        Ok(())
+    }
+
+    #[cfg(not(feature = "tls"))]
+    pub fn reload(&self) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Other, "raftor was built without the `tls` feature"))
+    }
+
+    /// Whether the files were modified since they were last read.
+    #[cfg(feature = "tls")]
+    pub fn changed(&self) -> bool {
+        files_modified(&self.config) != self.state.read().unwrap().modified
+    }
+
+    #[cfg(not(feature = "tls"))]
+    pub fn changed(&self) -> bool {
+        false
+    }
+
+    /// Handshake on a connection to the peer at `addr`, whose certificate must carry its node
+    /// id unless `verify_peer_id` is off or the peer has a server name of its own.
+    #[cfg(feature = "tls")]
+    pub fn connect(&self, stream: TcpStream, peer: NodeId, addr: &str) -> Handshake {
+        if self.config.peers.get(addr).map_or(false, |peer| peer.plaintext) {
+            return Box::new(future::ok(PeerStream::Plain(stream)));
+        }
+
+        let name = connect_server_name(&self.config, peer, addr);
+        let connector = {
+            let state = self.state.read().unwrap();
+            state.peer_connectors.get(addr).unwrap_or(&state.connector).clone()
+        };
+        match DNSNameRef::try_from_ascii_str(&name) {
+            Ok(dns_name) => Box::new(connector.connect(dns_name, stream).map(PeerStream::Client)),
+            Err(_) => Box::new(future::err(invalid(format!("{} is not a valid server name", name)))),
+        }
+    }
+
+    /// Handshake on a connection accepted from a peer, which must present a certificate signed
+    /// by the CA unless client authentication is turned off, within the handshake timeout.
+    #[cfg(feature = "tls")]
+    pub fn accept(&self, stream: TcpStream) -> Handshake {
+        let ip = stream.peer_addr().ok().map(|addr| addr.ip());
+        if ip.and_then(|ip| accepted_override(&self.config, ip)).map_or(false, |peer| peer.plaintext) {
+            return Box::new(future::ok(PeerStream::Plain(stream)));
+        }
+
+        let timeout = Duration::from_millis(self.config.handshake_timeout_ms);
+        let acceptor = {
+            let state = self.state.read().unwrap();
+            ip.and_then(|ip| state.peer_acceptors.get(&ip)).unwrap_or(&state.acceptor).clone()
+        };
+        Box::new(
+            Timeout::new(acceptor.accept(stream), timeout)
+                .map_err(move |err| {
+                    if err.is_elapsed() {
+                        io::Error::new(io::ErrorKind::TimedOut, format!("no handshake within {:?}", timeout))
+                    } else {
+                        err.into_inner().unwrap_or_else(|| io::Error::new(io::ErrorKind::Other, "the handshake timer failed"))
+                    }
+                })
+                .map(PeerStream::Server),
+        )
+    }
+
+    #[cfg(not(feature = "tls"))]
+    pub fn connect(&self, stream: TcpStream, _peer: NodeId, _addr: &str) -> Handshake {
+        Box::new(future::ok(PeerStream::Plain(stream)))
+    }
+
+    #[cfg(not(feature = "tls"))]
+    pub fn accept(&self, stream: TcpStream) -> Handshake {
+        Box::new(future::ok(PeerStream::Plain(stream)))
+    }
+}
+
+/// Secure an outbound connection to `peer` at `addr` when TLS is configured.
+pub fn connect(tls: &Option<PeerTls>, stream: TcpStream, peer: NodeId, addr: &str) -> Handshake {
+    match tls {
+        Some(tls) => tls.connect(stream, peer, addr),
+        None => Box::new(future::ok(PeerStream::Plain(stream))),
+    }
+}
+
+/// Secure an accepted connection when TLS is configured.
+pub fn accept(tls: &Option<PeerTls>, stream: TcpStream) -> Handshake {
+    match tls {
+        Some(tls) => tls.accept(stream),
+        None => Box::new(future::ok(PeerStream::Plain(stream))),
+    }
+}
+
+/// Name the certificate of a peer carries when peers are verified by their node id.
+pub(crate) fn peer_server_name(peer: NodeId, server_name: &str) -> String {
+    format!("node-{}.{}", peer, server_name)
+}
+
+/// Name verified on the certificate of `peer` at `addr`.
+#[cfg(feature = "tls")]
+fn connect_server_name(config: &TlsConfig, peer: NodeId, addr: &str) -> String {
+    match config.peers.get(addr).and_then(|peer| peer.server_name.as_ref()) {
+        Some(name) => name.clone(),
+        None if config.verify_peer_id => peer_server_name(peer, &config.server_name),
+        None => config.server_name.clone(),
+    }
+}
+
+/// Override of the connections accepted from `ip`, only `ca` and `plaintext` ones apply as
+/// the server name is the one of this node.
+#[cfg(feature = "tls")]
+fn accepted_override(config: &TlsConfig, ip: IpAddr) -> Option<&PeerTlsOverride> {
+    config
+        .peers
+        .iter()
+        .filter(|(_, peer)| peer.plaintext || peer.ca.is_some())
+        .find(|(addr, _)| addr.parse::<SocketAddr>().ok().map(|addr| addr.ip()) == Some(ip))
+        .map(|(_, peer)| peer)
+}
+
+/// Latest modification time of the certificate, key and CA files, the CAs of the peers
+/// included, `None` when one can't be read.
+#[cfg(feature = "tls")]
+fn files_modified(config: &TlsConfig) -> Option<SystemTime> {
+    let mut latest = None;
+    let peer_cas = config.peers.values().filter_map(|peer| peer.ca.as_ref());
+    for path in vec![&config.cert, &config.key, &config.ca].into_iter().chain(peer_cas) {
+        let modified = fs::metadata(path).and_then(|metadata| metadata.modified()).ok()?;
+        latest = latest.max(Some(modified));
+    }
+    latest
+}
+
+#[cfg(feature = "tls")]
+fn load_roots(path: &str) -> io::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    roots
+        .add_pem_file(&mut BufReader::new(File::open(path)?))
+        .map_err(|_| invalid(format!("no CA certificates in {}", path)))?;
+    Ok(roots)
+}
+
+#[cfg(feature = "tls")]
+fn load_key(path: &str) -> io::Result<PrivateKey> {
+    let pkcs8 = pemfile::pkcs8_private_keys(&mut BufReader::new(File::open(path)?)).unwrap_or_default();
+    let rsa = pemfile::rsa_private_keys(&mut BufReader::new(File::open(path)?)).unwrap_or_default();
+
+    pkcs8
+        .into_iter()
+        .chain(rsa)
+        .next()
+        .ok_or_else(|| invalid(format!("no private key in {}", path)))
+}
+
+#[cfg(feature = "tls")]
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+impl PeerStream {
+    /// Protocol the connection negotiated, the peer protocol unless it was accepted over TLS
+    /// with another one.
+    pub fn protocol(&self) -> Protocol {
+        self.negotiated().unwrap_or(Protocol::Peer)
+    }
+
+    /// Protocol negotiated by ALPN, if the connection was accepted over TLS with one.
+    pub fn negotiated(&self) -> Option<Protocol> {
+        match self {
+            #[cfg(feature = "tls")]
+            PeerStream::Server(stream) => stream.get_ref().1.get_alpn_protocol().and_then(Protocol::from_alpn),
+            _ => None,
+        }
+    }
+}
+
+impl Read for PeerStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            PeerStream::Plain(stream) => stream.read(buf),
+            #[cfg(feature = "tls")]
+            PeerStream::Client(stream) => stream.read(buf),
+            #[cfg(feature = "tls")]
+            PeerStream::Server(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for PeerStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            PeerStream::Plain(stream) => stream.write(buf),
+            #[cfg(feature = "tls")]
+            PeerStream::Client(stream) => stream.write(buf),
+            #[cfg(feature = "tls")]
+            PeerStream::Server(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            PeerStream::Plain(stream) => stream.flush(),
+            #[cfg(feature = "tls")]
+            PeerStream::Client(stream) => stream.flush(),
+            #[cfg(feature = "tls")]
+            PeerStream::Server(stream) => stream.flush(),
+        }
+    }
+}
+
+impl AsyncRead for PeerStream {}
+
+impl AsyncWrite for PeerStream {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        match self {
+            PeerStream::Plain(stream) => AsyncWrite::shutdown(stream),
+            #[cfg(feature = "tls")]
+            PeerStream::Client(stream) => AsyncWrite::shutdown(stream),
+            #[cfg(feature = "tls")]
+            PeerStream::Server(stream) => AsyncWrite::shutdown(stream),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn peers_are_named_after_their_id() {
+        assert_eq!(peer_server_name(42, "raftor"), "node-42.raftor");
+        assert_ne!(peer_server_name(4, "raftor"), peer_server_name(42, "raftor"));
+    }
+
+    #[cfg(feature = "tls")]
+    fn config(path: &dyn Fn(&str) -> String) -> TlsConfig {
+        TlsConfig {
+            cert: path("cert.pem"),
+            key: path("key.pem"),
+            ca: path("ca.pem"),
+            server_name: "raftor".to_owned(),
+            require_client_auth: true,
+            verify_peer_id: true,
+            handshake_timeout_ms: 10_000,
+            reload_check_secs: None,
+            peers: Default::default(),
+        }
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn missing_files_read_as_changed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name).to_string_lossy().to_string();
+        for name in ["cert.pem", "key.pem", "ca.pem"].iter() {
+            fs::write(path(name), b"").unwrap();
+        }
+        let mut config = config(&path);
+
+        assert!(files_modified(&config).is_some());
+        config.peers.insert(
+            "10.0.0.3:8000".to_owned(),
+            PeerTlsOverride {
+                ca: Some(path("missing.pem")),
+                ..Default::default()
+            },
+        );
+        assert_eq!(files_modified(&config), None);
+        config.peers.clear();
+        config.ca = path("missing.pem");
+        assert_eq!(files_modified(&config), None);
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn peers_override_the_server_name() {
+        let mut config = config(&|name: &str| name.to_owned());
+        config.peers.insert(
+            "legacy.internal:8000".to_owned(),
+            PeerTlsOverride {
+                server_name: Some("legacy.internal".to_owned()),
+                ..Default::default()
+            },
+        );
+
+        assert_eq!(connect_server_name(&config, 3, "legacy.internal:8000"), "legacy.internal");
+        assert_eq!(connect_server_name(&config, 4, "10.0.0.4:8000"), "node-4.raftor");
+        config.verify_peer_id = false;
+        assert_eq!(connect_server_name(&config, 4, "10.0.0.4:8000"), "raftor");
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn accepted_connections_match_peers_by_ip() {
+        let mut config = config(&|name: &str| name.to_owned());
+        config.peers.insert(
+            "10.0.0.3:8000".to_owned(),
+            PeerTlsOverride {
+                plaintext: true,
+                ..Default::default()
+            },
+        );
+        config.peers.insert(
+            "10.0.0.4:8000".to_owned(),
+            PeerTlsOverride {
+                server_name: Some("legacy.internal".to_owned()),
+                ..Default::default()
+            },
+        );
+
+        assert!(accepted_override(&config, "10.0.0.3".parse().unwrap()).map_or(false, |peer| peer.plaintext));
+        // a server name only applies to the connections this node opens
+        assert!(accepted_override(&config, "10.0.0.4".parse().unwrap()).is_none());
+        assert!(accepted_override(&config, "10.0.0.5".parse().unwrap()).is_none());
+    }
+}
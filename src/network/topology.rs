@@ -0,0 +1,230 @@
+//! The cluster as a graph for dashboards: the nodes known to a node with their role and
+//! health, and its links to them with their status and the replication lag, as JSON or in the
+//! Graphviz DOT language.
+
+use actix_raft::NodeId;
+use serde::{Deserialize, Serialize};
+use std::fmt::Write;
+
+use crate::network::{ClusterStatus, NodeStatus};
+
+/// A node of the graph.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TopologyNode {
+    pub id: NodeId,
+    pub cluster_addr: Option<String>,
+    /// `leader`, `voter`, `non_voter` or `peer`, as in `MemberStatus`
+    pub role: String,
+    /// `up`, `reconnecting`, `down`, or `unknown` for a member this node has no connection to
+    pub health: String,
+    pub cordoned: bool,
+    pub stale: bool,
+    /// Entries the node is behind the leader's log, only known on the leader
+    pub lag: Option<u64>,
+}
+
+/// The link from the node the graph was taken on to another one.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TopologyLink {
+    pub from: NodeId,
+    pub to: NodeId,
+    /// Whether the link carries log replication, between the leader and a raft member
+    pub replication: bool,
+    pub status: Option<NodeStatus>,
+    /// Milliseconds since raft RPCs over the link last succeeded, only known on the leader
+    pub last_contact_ms: Option<u64>,
+    pub lag: Option<u64>,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub reconnects: u64,
+}
+
+/// The cluster graph as seen from `node`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Topology {
+    pub node: NodeId,
+    pub leader: Option<NodeId>,
+    pub term: Option<u64>,
+    pub nodes: Vec<TopologyNode>,
+    pub links: Vec<TopologyLink>,
+}
+
+impl Topology {
+    pub fn from_status(status: &ClusterStatus) -> Topology {
+        let leader = status.raft.as_ref().and_then(|raft| raft.leader);
+        let is_leader = leader == Some(status.node);
+        let last_log_index = status.raft.as_ref().map(|raft| raft.last_log_index);
+        let lag = |matched: Option<u64>| match (is_leader, last_log_index, matched) {
+            (true, Some(last), Some(matched)) => Some(last.saturating_sub(matched)),
+            _ => None,
+        };
+
+        let nodes = status
+            .nodes
+            .iter()
+            .map(|member| TopologyNode {
+                id: member.id,
+                cluster_addr: member.cluster_addr.clone(),
+                role: member.role.clone(),
+                health: if member.id == status.node {
+                    "up"
+                } else {
+                    match member.status {
+                        Some(NodeStatus::Connected) => "up",
+                        Some(NodeStatus::Reconnecting) => "reconnecting",
+                        Some(NodeStatus::Disconnected) => "down",
+                        None => "unknown",
+                    }
+                }
+                .to_owned(),
+                cordoned: member.cordoned,
+                stale: member.stale,
+                lag: if member.id == status.node && is_leader { Some(0) } else { lag(member.matched_index) },
+            })
+            .collect();
+
+        let links = status
+            .nodes
+            .iter()
+            .filter(|member| member.id != status.node)
+            .map(|member| {
+                let member_of_raft = member.role != "peer";
+                let link = member.link.clone().unwrap_or_default();
+
+                TopologyLink {
+                    from: status.node,
+                    to: member.id,
+                    replication: member_of_raft && (is_leader || leader == Some(member.id)),
+                    status: member.status.clone(),
+                    last_contact_ms: member.last_contact_ms,
+                    lag: lag(member.matched_index),
+                    bytes_sent: link.bytes_sent,
+                    bytes_received: link.bytes_received,
+                    reconnects: link.reconnects,
+                }
+            })
+            .collect();
+
+        Topology {
+            node: status.node,
+            leader: leader,
+            term: status.raft.as_ref().map(|raft| raft.term),
+            nodes: nodes,
+            links: links,
+        }
+    }
+
+    /// The graph in the DOT language: the leader is drawn bold, unhealthy nodes and links in
+    /// red, replication links solid and other peer connections dashed.
+    pub fn to_dot(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "digraph raftor {{");
+
+        for node in self.nodes.iter() {
+            let mut label = format!("{}\\n{}", node.id, node.role);
+            if let Some(ref addr) = node.cluster_addr {
+                let _ = write!(label, "\\n{}", addr);
+            }
+            if let Some(lag) = node.lag {
+                let _ = write!(label, "\\nlag {}", lag);
+            }
+
+            let color = match node.health.as_str() {
+                "up" => "black",
+                "reconnecting" => "orange",
+                _ => "red",
+            };
+            let style = match (node.role.as_str(), node.stale || node.cordoned) {
+                ("leader", _) => "bold",
+                (_, true) => "dotted",
+                _ => "solid",
+            };
+            let _ = writeln!(out, "  n{} [label=\"{}\", color={}, style={}];", node.id, label, color, style);
+        }
+
+        for link in self.links.iter() {
+            let color = match link.status {
+                Some(NodeStatus::Connected) => "black",
+                Some(NodeStatus::Reconnecting) => "orange",
+                _ => "red",
+            };
+            let style = if link.replication { "solid" } else { "dashed" };
+            let label = link.lag.map(|lag| format!(", label=\"lag {}\"", lag)).unwrap_or_default();
+            let _ = writeln!(out, "  n{} -> n{} [color={}, style={}{}];", link.from, link.to, color, style, label);
+        }
+
+        out.push_str("}\n");
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::{MemberStatus, RaftState};
+
+    fn member(id: NodeId, role: &str, status: Option<NodeStatus>, matched: Option<u64>) -> MemberStatus {
+        MemberStatus {
+            id: id,
+            cluster_addr: None,
+            role: role.to_owned(),
+            status: status,
+            last_contact_ms: None,
+            log: None,
+            matched_index: matched,
+            cordoned: false,
+            stale: false,
+            link: None,
+        }
+    }
+
+    fn status(node: NodeId) -> ClusterStatus {
+        ClusterStatus {
+            node: node,
+            raft: Some(RaftState {
+                state: "Leader".to_owned(),
+                term: 3,
+                last_log_index: 10,
+                last_applied: 10,
+                leader: Some(1),
+                members: vec![1, 2],
+                non_voters: Vec::new(),
+            }),
+            nodes: vec![
+                member(1, "leader", None, None),
+                member(2, "voter", Some(NodeStatus::Reconnecting), Some(7)),
+                member(3, "peer", Some(NodeStatus::Connected), None),
+            ],
+        }
+    }
+
+    #[test]
+    fn the_leader_knows_the_lag_of_its_followers() {
+        let topology = Topology::from_status(&status(1));
+
+        assert_eq!(topology.nodes[0].lag, Some(0));
+        assert_eq!((topology.nodes[1].health.as_str(), topology.nodes[1].lag), ("reconnecting", Some(3)));
+        assert_eq!(topology.links.len(), 2);
+        assert!(topology.links[0].replication);
+        // connections to nodes outside the raft config carry no replication
+        assert!(!topology.links[1].replication);
+    }
+
+    #[test]
+    fn followers_draw_no_lag() {
+        let mut status = status(2);
+        status.nodes[1].status = None;
+        status.nodes[0].status = Some(NodeStatus::Connected);
+        let topology = Topology::from_status(&status);
+
+        assert!(topology.nodes.iter().all(|node| node.lag.is_none()));
+        // only the link to the leader replicates
+        let replication = topology.links.iter().filter(|link| link.replication).map(|link| link.to).collect::<Vec<_>>();
+        assert_eq!(replication, vec![1]);
+
+        let dot = topology.to_dot();
+        assert!(dot.starts_with("digraph raftor {"));
+        assert!(dot.contains("n1 [label=\"1\\nleader\", color=black, style=bold];"));
+        assert!(dot.contains("n2 -> n3 [color=black, style=dashed];"));
+    }
+}
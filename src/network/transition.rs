@@ -0,0 +1,126 @@
+//! Membership changes stalled past `membership_change_timeout_secs`.
+//!
+//! Raft refuses a new membership change while one is in progress, so a stalled change can't be
+//! reverted by proposing the previous members. While the leader still syncs the non-voters
+//! nothing was committed and the change only lives on the leader: it steps aside and its
+//! successor doesn't pick the sync up. Once the joint configuration is in the log the change
+//! can only complete, which it does as soon as the new members form a quorum.
+
+use actix_raft::{messages::MembershipConfig, NodeId};
+use std::collections::BTreeSet;
+use std::time::{Duration, Instant};
+
+/// What the leader does about a change which took longer than the timeout.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Stalled {
+    /// Still syncing non-voters: step aside, the nodes being added are abandoned
+    Abort { members: Vec<NodeId>, abandoned: Vec<NodeId> },
+    /// In joint consensus: the change can't be undone, only reported
+    Joint { members: Vec<NodeId> },
+}
+
+#[derive(Default)]
+pub(crate) struct TransitionWatch {
+    /// Voters of the last uniform configuration without non-voters being synced
+    stable: Vec<NodeId>,
+    /// When the change in progress started, and whether it was reported stalled
+    since: Option<(Instant, bool)>,
+}
+
+impl TransitionWatch {
+    /// Observe the membership of this node, answers once per change what to do about it when
+    /// it stalled while `leading`.
+    pub(crate) fn observe(&mut self, config: &MembershipConfig, leading: bool, now: Instant, timeout: Duration) -> Option<Stalled> {
+        if !config.is_in_joint_consensus && config.non_voters.is_empty() {
+            self.stable = config.members.clone();
+            self.since = None;
+            return None;
+        }
+
+        let (since, reported) = *self.since.get_or_insert((now, false));
+        if reported || now.duration_since(since) < timeout || !leading || self.stable.is_empty() {
+            return None;
+        }
+        self.since = Some((since, true));
+
+        if config.is_in_joint_consensus {
+            return Some(Stalled::Joint { members: config.members.clone() });
+        }
+
+        let abandoned = config
+            .non_voters
+            .iter()
+            .filter(|id| !self.stable.contains(id))
+            .cloned()
+            .collect::<BTreeSet<_>>();
+        Some(Stalled::Abort {
+            members: self.stable.clone(),
+            abandoned: abandoned.into_iter().collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(members: &[NodeId], non_voters: &[NodeId], joint: bool) -> MembershipConfig {
+        MembershipConfig {
+            is_in_joint_consensus: joint,
+            members: members.to_vec(),
+            non_voters: non_voters.to_vec(),
+            removing: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn stalled_sync_is_aborted_once() {
+        let (start, timeout) = (Instant::now(), Duration::from_secs(10));
+        let mut watch = TransitionWatch::default();
+
+        assert_eq!(watch.observe(&config(&[1, 2, 3], &[], false), true, start, timeout), None);
+        assert_eq!(watch.observe(&config(&[1, 2, 3], &[4], false), true, start, timeout), None);
+        assert_eq!(watch.observe(&config(&[1, 2, 3], &[4], false), true, start + Duration::from_secs(5), timeout), None);
+
+        let stalled = watch.observe(&config(&[1, 2, 3], &[4], false), true, start + timeout, timeout);
+        assert_eq!(stalled, Some(Stalled::Abort { members: vec![1, 2, 3], abandoned: vec![4] }));
+        assert_eq!(watch.observe(&config(&[1, 2, 3], &[4], false), true, start + timeout * 2, timeout), None);
+    }
+
+    #[test]
+    fn joint_consensus_is_only_reported() {
+        let (start, timeout) = (Instant::now(), Duration::from_secs(10));
+        let mut watch = TransitionWatch::default();
+
+        watch.observe(&config(&[1, 2, 3], &[], false), true, start, timeout);
+        watch.observe(&config(&[1, 2, 3, 4], &[], true), true, start, timeout);
+
+        let stalled = watch.observe(&config(&[1, 2, 3, 4], &[], true), true, start + timeout, timeout);
+        assert_eq!(stalled, Some(Stalled::Joint { members: vec![1, 2, 3, 4] }));
+    }
+
+    #[test]
+    fn followers_leave_it_to_the_leader() {
+        let (start, timeout) = (Instant::now(), Duration::from_secs(10));
+        let mut watch = TransitionWatch::default();
+
+        watch.observe(&config(&[1, 2, 3], &[], false), false, start, timeout);
+        watch.observe(&config(&[1, 2, 3], &[4], false), false, start, timeout);
+        assert_eq!(watch.observe(&config(&[1, 2, 3], &[4], false), false, start + timeout, timeout), None);
+    }
+
+    #[test]
+    fn completed_change_resets_the_watch() {
+        let (start, timeout) = (Instant::now(), Duration::from_secs(10));
+        let mut watch = TransitionWatch::default();
+
+        watch.observe(&config(&[1, 2, 3], &[], false), true, start, timeout);
+        watch.observe(&config(&[1, 2, 3], &[4], false), true, start, timeout);
+        watch.observe(&config(&[1, 2, 3, 4], &[], false), true, start + timeout, timeout);
+
+        // a later change gets its own timeout
+        let later = start + timeout * 3;
+        assert_eq!(watch.observe(&config(&[1, 2, 3, 4], &[5], false), true, later, timeout), None);
+        assert!(watch.observe(&config(&[1, 2, 3, 4], &[5], false), true, later + timeout, timeout).is_some());
+    }
+}
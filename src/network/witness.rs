@@ -0,0 +1,146 @@
+//! Vote of the tie-breaker in two node clusters, see `crate::arbitration`.
+//!
+//! While this node holds the tie-breaker lease and its peer has no session, the raft RPCs to
+//! the peer are answered in its place: the lease is the vote deciding the tie, so the lone
+//! survivor is elected and commits. The peer can't hold the lease meanwhile, and without it
+//! it neither campaigns nor replicates. The entries acknowledged in its place are only held
+//! by this node until the peer caught up past them, the lease is kept solo until then so the
+//! peer never takes it over without them.
+
+use actix_raft::{messages, NodeId};
+
+use crate::raft::storage::StampedData as Data;
+
+#[derive(Default)]
+pub(crate) struct Witness {
+    /// Whether this node holds the lease
+    held: bool,
+    /// Last index acknowledged in place of the peer, until the peer matched it
+    solo_through: Option<u64>,
+}
+
+impl Witness {
+    /// Record the outcome of a lease renewal, resolves to whether the lease changed hands.
+    pub(crate) fn set_held(&mut self, held: bool) -> bool {
+        let changed = self.held != held;
+        self.held = held;
+        changed
+    }
+
+    pub(crate) fn held(&self) -> bool {
+        self.held
+    }
+
+    /// Whether entries only this node holds were acknowledged in place of the peer.
+    pub(crate) fn solo(&self) -> bool {
+        self.solo_through.is_some()
+    }
+
+    /// Whether the RPCs to `target` are answered in its place: it is the only other member
+    /// and has no session.
+    pub(crate) fn answers(&self, id: NodeId, members: &[NodeId], target: NodeId, connected: bool) -> bool {
+        self.held && !connected && peer_of(id, members) == Some(target)
+    }
+
+    pub(crate) fn append(&mut self, msg: &messages::AppendEntriesRequest<Data>) -> messages::AppendEntriesResponse {
+        let last_sent = msg.prev_log_index + msg.entries.len() as u64;
+        if !msg.entries.is_empty() {
+            self.solo_through = Some(self.solo_through.unwrap_or(0).max(last_sent));
+        }
+        messages::AppendEntriesResponse {
+            term: msg.term,
+            success: true,
+            conflict_opt: None,
+        }
+    }
+
+    pub(crate) fn vote(&self, msg: &messages::VoteRequest) -> messages::VoteResponse {
+        messages::VoteResponse {
+            term: msg.term,
+            vote_granted: true,
+            is_candidate_unknown: false,
+        }
+    }
+
+    pub(crate) fn install_snapshot(&mut self, msg: &messages::InstallSnapshotRequest) -> messages::InstallSnapshotResponse {
+        if msg.done {
+            self.solo_through = Some(self.solo_through.unwrap_or(0).max(msg.last_included_index));
+        }
+        messages::InstallSnapshotResponse { term: msg.term }
+    }
+
+    /// The peer matched the log up to `index` itself.
+    pub(crate) fn matched(&mut self, index: u64) {
+        if self.solo_through.map(|through| index >= through).unwrap_or(false) {
+            self.solo_through = None;
+        }
+    }
+}
+
+/// The peer a two node cluster ties with.
+pub(crate) fn peer_of(id: NodeId, members: &[NodeId]) -> Option<NodeId> {
+    match members {
+        [a, b] if *a == id => Some(*b),
+        [a, b] if *b == id => Some(*a),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn append(prev: u64, entries: usize) -> messages::AppendEntriesRequest<Data> {
+        messages::AppendEntriesRequest {
+            target: 2,
+            term: 3,
+            leader_id: 1,
+            prev_log_index: prev,
+            prev_log_term: 3,
+            entries: (0..entries).map(|_| messages::Entry {
+                index: 0,
+                term: 3,
+                payload: messages::EntryPayload::Blank,
+            }).collect(),
+            leader_commit: prev,
+        }
+    }
+
+    #[test]
+    fn answers_for_a_disconnected_peer_while_held() {
+        let mut witness = Witness::default();
+        assert!(!witness.answers(1, &[1, 2], 2, false));
+
+        witness.set_held(true);
+        assert!(witness.answers(1, &[1, 2], 2, false));
+        assert!(!witness.answers(1, &[1, 2], 2, true));
+        // a third member breaks ties itself
+        assert!(!witness.answers(1, &[1, 2, 3], 2, false));
+    }
+
+    #[test]
+    fn solo_until_the_peer_catches_up() {
+        let mut witness = Witness::default();
+        witness.set_held(true);
+
+        // heartbeats don't make the lease solo
+        assert!(witness.append(&append(4, 0)).success);
+        assert!(!witness.solo());
+
+        witness.append(&append(4, 3));
+        assert!(witness.solo());
+
+        witness.matched(6);
+        assert!(witness.solo());
+        witness.matched(7);
+        assert!(!witness.solo());
+    }
+
+    #[test]
+    fn ties_only_two_members() {
+        assert_eq!(peer_of(1, &[1, 2]), Some(2));
+        assert_eq!(peer_of(2, &[1, 2]), Some(1));
+        assert_eq!(peer_of(1, &[1, 2, 3]), None);
+        assert_eq!(peer_of(3, &[1, 2]), None);
+    }
+}
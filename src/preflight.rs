@@ -0,0 +1,448 @@
+//! Checks of the environment a node starts in: open file limit for the peer count, clock
+//! source, write latency of the data dir and sanity of the configuration. The report is printed
+//! as the startup banner of the node, violations are warned about or refuse the start.
+//!
+//! `raftor start --check` runs them without starting the node, along with checks of its
+//! addresses, TLS material and data dir and optionally of the reachability of its peers.
+
+use actix_raft::NodeId;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::io::Write;
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::config::{ConfigSchema, NodeIdStrategy, NodeInfo};
+use crate::network::{PeerTls, VERSION};
+use crate::utils;
+
+/// Descriptors a node needs besides its peer connections: listeners, log files, the log store
+const BASE_DESCRIPTORS: u64 = 256;
+
+/// Descriptors per peer: a connection each way on the cluster and the app network
+const DESCRIPTORS_PER_PEER: u64 = 4;
+
+/// Size of a probe write, a typical appended entry
+const PROBE_BYTES: usize = 4096;
+
+/// Clock sources reading time slowly or coarsely, delaying timers and skewing timeouts
+const SLOW_CLOCK_SOURCES: &[&str] = &["jiffies", "acpi_pm", "hpet", "refined-jiffies"];
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckStatus {
+    Ok,
+    Warn,
+    Fail,
+    /// Not checkable on this platform or configuration
+    Skipped,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Check {
+    pub name: String,
+    pub status: CheckStatus,
+    pub detail: String,
+}
+
+/// Outcome of the checks of a starting node.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PreflightReport {
+    pub node: NodeId,
+    pub version: String,
+    pub cluster_addr: String,
+    pub checks: Vec<Check>,
+}
+
+impl PreflightReport {
+    pub fn failures(&self) -> Vec<&Check> {
+        self.checks.iter().filter(|check| check.status == CheckStatus::Fail).collect()
+    }
+}
+
+impl fmt::Display for PreflightReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "raftor {} node {} on {}", self.version, self.node, self.cluster_addr)?;
+        for check in &self.checks {
+            let status = match check.status {
+                CheckStatus::Ok => "ok",
+                CheckStatus::Warn => "WARN",
+                CheckStatus::Fail => "FAIL",
+                CheckStatus::Skipped => "skip",
+            };
+            writeln!(f, "[preflight] {:<14} {:<4} {}", check.name, status, check.detail)?;
+        }
+        Ok(())
+    }
+}
+
+/// Run the checks for the node about to start with the configuration.
+pub fn run(config: &ConfigSchema, node: NodeId, info: &NodeInfo) -> PreflightReport {
+    PreflightReport {
+        node: node,
+        version: VERSION.to_owned(),
+        cluster_addr: info.cluster_addr.clone(),
+        checks: vec![
+            open_files(config),
+            clock_source(),
+            disk_latency(config),
+            config_sanity(config),
+        ],
+    }
+}
+
+/// Validate the configuration of a node without starting it, `probe_peers` also connects to
+/// the cluster address of every other listed node.
+pub fn dry_run(config: &ConfigSchema, info: &NodeInfo, probe_peers: bool) -> PreflightReport {
+    let (node, identity) = node_identity(config, info);
+    let mut report = run(config, node, info);
+    report.checks.push(identity);
+    report.checks.push(addresses(config, info));
+    report.checks.push(tls(config));
+    report.checks.push(data_dir(config));
+    report.checks.push(if probe_peers {
+        peers(config, info)
+    } else {
+        check("peers", CheckStatus::Skipped, "not probed, pass --probe-peers".to_owned())
+    });
+    report
+}
+
+fn check(name: &str, status: CheckStatus, detail: String) -> Check {
+    Check {
+        name: name.to_owned(),
+        status: status,
+        detail: detail,
+    }
+}
+
+/// The soft open file limit must cover the connections to every listed peer.
+fn open_files(config: &ConfigSchema) -> Check {
+    let peers = config.nodes.len().saturating_sub(1) as u64;
+    let needed = BASE_DESCRIPTORS + peers * DESCRIPTORS_PER_PEER;
+
+    let limit = match open_files_limit() {
+        Some(limit) => limit,
+        None => return check("open_files", CheckStatus::Skipped, "limit unknown on this platform".to_owned()),
+    };
+    let detail = format!("limit {}, {} needed for {} peers", limit, needed, peers);
+
+    if limit < needed {
+        check("open_files", CheckStatus::Fail, format!("{}, raise `ulimit -n`", detail))
+    } else if limit < needed * 2 {
+        check("open_files", CheckStatus::Warn, format!("{}, little headroom for new peers", detail))
+    } else {
+        check("open_files", CheckStatus::Ok, detail)
+    }
+}
+
+/// Soft limit of open files of this process, `None` if unlimited or unavailable.
+fn open_files_limit() -> Option<u64> {
+    let limits = fs::read_to_string("/proc/self/limits").ok()?;
+    let line = limits.lines().find(|line| line.starts_with("Max open files"))?;
+    line["Max open files".len()..].split_whitespace().next()?.parse().ok()
+}
+
+/// Raft timeouts rely on a fast, fine grained clock.
+fn clock_source() -> Check {
+    let source = match fs::read_to_string("/sys/devices/system/clocksource/clocksource0/current_clocksource") {
+        Ok(source) => source.trim().to_owned(),
+        Err(_) => return check("clock_source", CheckStatus::Skipped, "clock source unknown on this platform".to_owned()),
+    };
+
+    if SLOW_CLOCK_SOURCES.contains(&source.as_str()) {
+        check("clock_source", CheckStatus::Warn, format!("{} is slow or coarse, timeouts may fire late", source))
+    } else {
+        check("clock_source", CheckStatus::Ok, source)
+    }
+}
+
+/// Synced writes to the data dir, raft syncs every append before answering. Slower than a
+/// quarter of the minimum election timeout is a warning, slower than half of it a failure.
+fn disk_latency(config: &ConfigSchema) -> Check {
+    let data_dir = match config.data_dir {
+        Some(ref data_dir) => Path::new(data_dir),
+        None => return check("disk_latency", CheckStatus::Skipped, "no data_dir".to_owned()),
+    };
+    let probes = config.startup_checks.disk_probes.max(1);
+
+    let latency = match probe_writes(data_dir, probes) {
+        Ok(latency) => latency,
+        Err(err) => return check("disk_latency", CheckStatus::Fail, format!("can't write to {:?}: {}", data_dir, err)),
+    };
+    let election_timeout = Duration::from_millis(config.raft_timings.election_timeout_min);
+    let detail = format!("median synced write {:?} in {:?}", latency, data_dir);
+
+    if latency > election_timeout / 2 {
+        check("disk_latency", CheckStatus::Fail, format!("{}, over half the election timeout", detail))
+    } else if latency > election_timeout / 4 {
+        check("disk_latency", CheckStatus::Warn, format!("{}, over a quarter of the election timeout", detail))
+    } else {
+        check("disk_latency", CheckStatus::Ok, detail)
+    }
+}
+
+/// Median duration of synced probe writes to a scratch file of the data dir.
+fn probe_writes(data_dir: &Path, probes: u32) -> std::io::Result<Duration> {
+    fs::create_dir_all(data_dir)?;
+    let path = data_dir.join("preflight.probe");
+    let data = vec![0u8; PROBE_BYTES];
+    let mut latencies = Vec::with_capacity(probes as usize);
+
+    let res = (|| {
+        let mut file = fs::File::create(&path)?;
+        for _ in 0..probes {
+            let start = Instant::now();
+            file.write_all(&data)?;
+            file.sync_data()?;
+            latencies.push(start.elapsed());
+        }
+        Ok(())
+    })();
+    let _ = fs::remove_file(&path);
+    res?;
+
+    latencies.sort();
+    Ok(latencies[latencies.len() / 2])
+}
+
+/// Settings which start but can't work together.
+fn config_sanity(config: &ConfigSchema) -> Check {
+    let timings = &config.raft_timings;
+    let mut problems = Vec::new();
+
+    if timings.election_timeout_min >= timings.election_timeout_max {
+        problems.push(format!(
+            "election_timeout_min {} isn't below election_timeout_max {}",
+            timings.election_timeout_min, timings.election_timeout_max
+        ));
+    }
+    if timings.heartbeat_interval * 2 > timings.election_timeout_min {
+        problems.push(format!(
+            "heartbeat_interval {} is over half of election_timeout_min {}",
+            timings.heartbeat_interval, timings.election_timeout_min
+        ));
+    }
+
+    let mut addrs = HashSet::new();
+    let mut ids = HashSet::new();
+    for node in &config.nodes {
+        if !addrs.insert(node.cluster_addr.as_str()) {
+            problems.push(format!("{} is listed twice", node.cluster_addr));
+        }
+        if let Some(id) = node.id {
+            if !ids.insert(id) {
+                problems.push(format!("id {} is assigned twice", id));
+            }
+        }
+    }
+
+    if problems.is_empty() {
+        check("config", CheckStatus::Ok, format!("{} nodes listed", config.nodes.len()))
+    } else {
+        check("config", CheckStatus::Fail, problems.join("; "))
+    }
+}
+
+/// The id the node would start with, without persisting a generated one.
+fn node_identity(config: &ConfigSchema, info: &NodeInfo) -> (NodeId, Check) {
+    match config.node_id {
+        NodeIdStrategy::Persisted { ref path } if !Path::new(path).exists() => (
+            utils::node_id(info),
+            check("node_id", CheckStatus::Ok, format!("a random id will be generated and kept in {}", path)),
+        ),
+        ref strategy => match utils::local_node_id(strategy, info) {
+            Ok(id) => (id, check("node_id", CheckStatus::Ok, format!("{:?}", strategy))),
+            Err(err) => (utils::node_id(info), check("node_id", CheckStatus::Fail, err)),
+        },
+    }
+}
+
+/// The listeners of the node must be free to bind, the other addresses must resolve.
+fn addresses(config: &ConfigSchema, info: &NodeInfo) -> Check {
+    let mut problems = Vec::new();
+
+    let mut listeners = vec![("cluster", &info.cluster_addr), ("app", &info.app_addr), ("public", &info.public_addr)];
+    if let Some(ref addr) = config.admin_addr {
+        listeners.push(("admin", addr));
+    }
+    if let Some(ref addr) = config.json_client_addr {
+        listeners.push(("json client", addr));
+    }
+    for (name, addr) in &listeners {
+        if let Err(err) = TcpListener::bind(addr.as_str()) {
+            problems.push(format!("{} address {} can't be bound: {}", name, addr, err));
+        }
+    }
+
+    for node in config.nodes.iter().filter(|node| node.cluster_addr != info.cluster_addr) {
+        for addr in &[&node.cluster_addr, &node.app_addr, &node.public_addr] {
+            if let Err(err) = addr.to_socket_addrs() {
+                problems.push(format!("{} doesn't resolve: {}", addr, err));
+            }
+        }
+    }
+
+    if problems.is_empty() {
+        check("addresses", CheckStatus::Ok, format!("{} listeners free, peer addresses resolve", listeners.len()))
+    } else {
+        check("addresses", CheckStatus::Fail, problems.join("; "))
+    }
+}
+
+/// Certificates and key of the peer connections load and match.
+fn tls(config: &ConfigSchema) -> Check {
+    match config.tls {
+        Some(ref tls) => match PeerTls::from_config(tls) {
+            Ok(_) => check("tls", CheckStatus::Ok, format!("certificate {} signed for {}", tls.cert, tls.server_name)),
+            Err(err) => check("tls", CheckStatus::Fail, err.to_string()),
+        },
+        None => check("tls", CheckStatus::Skipped, "not configured".to_owned()),
+    }
+}
+
+/// The data dir must be a directory this process can create files in.
+fn data_dir(config: &ConfigSchema) -> Check {
+    let data_dir = match config.data_dir {
+        Some(ref data_dir) => Path::new(data_dir),
+        None => return check("data_dir", CheckStatus::Skipped, "no data_dir, the node keeps its state in memory".to_owned()),
+    };
+
+    match fs::metadata(data_dir) {
+        Ok(ref metadata) if !metadata.is_dir() => {
+            return check("data_dir", CheckStatus::Fail, format!("{:?} is not a directory", data_dir));
+        }
+        Ok(_) => (),
+        Err(_) => return check("data_dir", CheckStatus::Warn, format!("{:?} doesn't exist yet, it will be created", data_dir)),
+    }
+
+    let path = data_dir.join("check.probe");
+    match fs::write(&path, b"").and_then(|_| fs::remove_file(&path)) {
+        Ok(()) => check("data_dir", CheckStatus::Ok, format!("{:?} is writable", data_dir)),
+        Err(err) => check("data_dir", CheckStatus::Fail, format!("can't write to {:?}: {}", data_dir, err)),
+    }
+}
+
+/// Connect to the other listed nodes within the dial timeouts. Unreachable peers are a
+/// warning, a failure when the reachable nodes including this one aren't a majority.
+fn peers(config: &ConfigSchema, info: &NodeInfo) -> Check {
+    let timeout = Duration::from_millis(config.dial_timeouts.connect_timeout_ms);
+    let others = config.nodes.iter().filter(|node| node.cluster_addr != info.cluster_addr).collect::<Vec<_>>();
+    if others.is_empty() {
+        return check("peers", CheckStatus::Skipped, "no other node listed".to_owned());
+    }
+
+    let unreachable = others
+        .iter()
+        .filter(|node| {
+            let reachable = node
+                .cluster_addr
+                .to_socket_addrs()
+                .map(|mut addrs| addrs.any(|addr| TcpStream::connect_timeout(&addr, timeout).is_ok()))
+                .unwrap_or(false);
+            !reachable
+        })
+        .map(|node| node.cluster_addr.as_str())
+        .collect::<Vec<_>>();
+
+    let nodes = others.len() + 1;
+    let reachable = nodes - unreachable.len();
+    if unreachable.is_empty() {
+        check("peers", CheckStatus::Ok, format!("{} peers reachable", others.len()))
+    } else if reachable <= nodes / 2 {
+        check("peers", CheckStatus::Fail, format!("{} unreachable, {} of {} nodes are no majority", unreachable.join(", "), reachable, nodes))
+    } else {
+        check("peers", CheckStatus::Warn, format!("{} unreachable", unreachable.join(", ")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RaftTimings;
+
+    fn node(port: u16, id: Option<NodeId>) -> NodeInfo {
+        NodeInfo {
+            cluster_addr: format!("127.0.0.1:{}", port),
+            app_addr: format!("127.0.0.1:{}", port + 1),
+            public_addr: format!("127.0.0.1:{}", port + 2),
+            id: id,
+            labels: Default::default(),
+            cluster: None,
+        }
+    }
+
+    fn config(nodes: Vec<NodeInfo>) -> ConfigSchema {
+        ConfigSchema::new("127.0.0.1:9000".to_owned(), nodes)
+    }
+
+    #[test]
+    fn sane_configs_pass() {
+        let check = config_sanity(&config(vec![node(8000, None), node(8010, None)]));
+        assert_eq!(check.status, CheckStatus::Ok);
+    }
+
+    #[test]
+    fn conflicting_settings_fail() {
+        let config = config(vec![node(8000, Some(1)), node(8000, None), node(8010, Some(1))]).with_raft_timings(
+            RaftTimings {
+                election_timeout_min: 300,
+                election_timeout_max: 300,
+                heartbeat_interval: 200,
+            },
+        );
+
+        let check = config_sanity(&config);
+        assert_eq!(check.status, CheckStatus::Fail);
+        for problem in &[
+            "isn't below election_timeout_max",
+            "is over half of election_timeout_min",
+            "127.0.0.1:8000 is listed twice",
+            "id 1 is assigned twice",
+        ] {
+            assert!(check.detail.contains(problem), "{} misses {}", check.detail, problem);
+        }
+    }
+
+    #[test]
+    fn the_data_dir_must_be_writable() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("file");
+        fs::write(&file, b"").unwrap();
+
+        let status = |path: &Path| {
+            let config = config(vec![]).with_data_dir(path.to_str().unwrap().to_owned());
+            data_dir(&config).status
+        };
+        assert_eq!(status(dir.path()), CheckStatus::Ok);
+        assert_eq!(status(&dir.path().join("missing")), CheckStatus::Warn);
+        assert_eq!(status(&file), CheckStatus::Fail);
+        assert_eq!(data_dir(&config(vec![])).status, CheckStatus::Skipped);
+    }
+
+    #[test]
+    fn probe_writes_leave_no_file_behind() {
+        let dir = tempfile::tempdir().unwrap();
+        probe_writes(dir.path(), 3).unwrap();
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn failures_are_reported() {
+        let report = PreflightReport {
+            node: 1,
+            version: VERSION.to_owned(),
+            cluster_addr: "127.0.0.1:8000".to_owned(),
+            checks: vec![
+                check("clock_source", CheckStatus::Ok, "tsc".to_owned()),
+                check("config", CheckStatus::Fail, "broken".to_owned()),
+            ],
+        };
+
+        assert_eq!(report.failures().len(), 1);
+        assert!(report.to_string().contains("[preflight] config         FAIL broken"));
+    }
+}
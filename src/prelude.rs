@@ -0,0 +1,48 @@
+//! Stable public API of raftor.
+//!
+//! Everything an embedding application needs to talk to a running node: the actors, the client
+//! and admin messages they accept, cluster state queries and the error types they return.
+//! Internal transport messages are intentionally not part of the prelude.
+
+pub use crate::config::{ClusterMetadata, ConfigSchema, JoinStrategy, NodeInfo, RaftTimings};
+pub use crate::network::{
+    AbortAddressMigration, AddMember, AddressMigration, ApproveJoin, Bootstrap, Broadcast,
+    CachingResolver, ClientRead, ClientRequest, ClusterProgress, ClusterStatus, ConfirmError,
+    ConfirmMembershipChange, DebugPeer,
+    Discovery, DistributeAndWait, DistributeMessage, DnsDiscovery, Formation,
+    GetAddressMigration, GetClusterDescriptor, GetClusterMetadata, GetClusterState,
+    GetClusterStatus, GetCurrentLeader, GetEvents, GetFollowerProgress, GetLeadershipHistory,
+    GetNode, GetNodeMetrics, GetNodeStatus, GetNodes, GetPeer, GetPeers, GetPendingJoins,
+    GossipDiscovery, HedgedRead, JoinError, JoinStatus, LeadershipEvent, LogState, MemberStatus,
+    MembershipReport, MigrateNextAddress, Network, NetworkState, NodeMetrics, NodeStatus,
+    PeerDiscovery, PeerError, PeerHandle, PeerInfo, PendingJoin, PreparedChange,
+    PrepareMembershipChange, ProposeAttempt, ProposeError,
+    RaftState, ReadBarrier, ReadConsistency, ReadError, RejectJoin, RemoteMessage, RemoveMember,
+    RequestJoin, Resolver, RetryBudget, RetryReport, ScatterGather, SetPartition, Shutdown, StartAddressMigration,
+    SubscribeMetrics, SubscribeProgress, SystemResolver, TrafficStats, TransferLeadership,
+    ValidateMembershipChange, WaitForApplied,
+};
+pub use crate::descriptor::{bootstrap, ClusterDescriptor, DescriptorMember};
+pub use crate::error::RaftorError;
+pub use crate::locks::{FencingToken, LockState};
+pub use crate::memory::{MemoryArea, MemoryUsage};
+pub use crate::raft::group::{
+    ChangeGroupMembership, CreateGroup, GetGroupMetrics, GroupId, GroupProposal, RaftGroups,
+    RemoveGroup, Router,
+};
+pub use crate::raft::affinity::ClientSession;
+pub use crate::raft::hlc::Hlc;
+pub use crate::raft::projection::{Projection, ProjectionStatus};
+pub use crate::raft::storage::{
+    EntryTimingReport, InspectedEntry, MemoryStorageData, MemoryStorageError,
+    MemoryStorageResponse, StampedData, StateDumpInfo, TriggerSnapshot,
+};
+pub use crate::raft::{
+    AcquireLock, AddNode, ArchiveLog, ChangeRaftClusterConfig, ClientProposal, CommandStats,
+    ExportError, ExportState, GetClientSession, GetCommitTimings, GetPendingProposals, GetStorageInfo,
+    GetWriteMetrics, InspectLog, IsRingMember, LastLogIndex, ListProjections, Maintenance,
+    PendingProposalStats, QueryApp, RaftClient, ReleaseLock, RemoveNode, ResetProjection,
+    SkewClock, VacuumStorage,
+    ValidateFencingToken,
+};
+pub use crate::raftor::Raftor;
@@ -0,0 +1,396 @@
+use serde::Serialize;
+use std::collections::{BTreeMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use crate::clock;
+use crate::config::AdaptiveWindow as AdaptiveWindowConfig;
+use crate::raft::timing::LatencyHistogram;
+
+/// Admission control for client proposals.
+///
+/// At most `max_pending` proposals are in flight at once. Proposals over the cap are queued per
+/// client and released in round-robin order between clients, so a single bursty producer can't
+/// monopolize the commit pipeline. A client queues at most `max_queued` proposals and at most
+/// `max_clients` clients have some queued, proposals over either cap are refused.
+pub struct AdmissionQueue<T> {
+    max_pending: usize,
+    max_queued: usize,
+    max_clients: usize,
+    pending: usize,
+    queues: BTreeMap<String, VecDeque<T>>,
+    order: VecDeque<String>,
+}
+
+impl<T> AdmissionQueue<T> {
+    pub fn new(max_pending: usize, max_queued: usize, max_clients: usize) -> Self {
+        AdmissionQueue {
+            max_pending: max_pending,
+            max_queued: max_queued,
+            max_clients: max_clients,
+            pending: 0,
+            queues: BTreeMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Whether a proposal of the client may be admitted, dispatched or queued.
+    pub fn has_room(&self, client: &str) -> bool {
+        match self.queues.get(client) {
+            Some(queue) => queue.len() < self.max_queued,
+            None => self.queues.len() < self.max_clients,
+        }
+    }
+
+    /// Try to admit a proposal. Returns the item back if it may be dispatched right away,
+    /// otherwise it is queued behind the other proposals of the same client. Callers check
+    /// `has_room` first.
+    pub fn admit(&mut self, client: String, item: T) -> Option<T> {
+        if self.pending < self.max_pending && self.queues.is_empty() {
+            self.pending += 1;
+            return Some(item);
+        }
+
+        if !self.queues.contains_key(&client) {
+            self.order.push_back(client.clone());
+        }
+        self.queues.entry(client).or_insert_with(VecDeque::new).push_back(item);
+
+        None
+    }
+
+    /// Mark an in-flight proposal as finished and return the next queued one, if any.
+    pub fn release(&mut self) -> Option<T> {
+        if self.pending > 0 {
+            self.pending -= 1;
+        }
+
+        self.next()
+    }
+
+    /// The next queued proposal, if another one may be in flight.
+    pub fn next(&mut self) -> Option<T> {
+        if self.pending >= self.max_pending {
+            return None;
+        }
+
+        while let Some(client) = self.order.pop_front() {
+            let (item, empty) = match self.queues.get_mut(&client) {
+                Some(queue) => (queue.pop_front(), queue.is_empty()),
+                None => (None, true),
+            };
+
+            if empty {
+                self.queues.remove(&client);
+            } else {
+                self.order.push_back(client);
+            }
+
+            if let Some(item) = item {
+                self.pending += 1;
+                return Some(item);
+            }
+        }
+
+        None
+    }
+
+    /// Change how many proposals may be in flight, proposals already in flight over a lower
+    /// limit finish normally.
+    pub fn set_max_pending(&mut self, max_pending: usize) {
+        self.max_pending = max_pending;
+    }
+
+    pub fn max_pending(&self) -> usize {
+        self.max_pending
+    }
+
+    /// Number of proposals currently in flight.
+    pub fn pending(&self) -> usize {
+        self.pending
+    }
+
+    /// Number of proposals waiting for admission.
+    pub fn queued(&self) -> usize {
+        self.queues.values().map(|q| q.len()).sum()
+    }
+}
+
+/// AIMD control of the proposals in flight.
+///
+/// Every window of proposals committed within the target latency grows the window by one, a
+/// slower commit halves it. The proposals in flight when commits slow down all see the slow
+/// down, so the window is halved at most once per window of commits.
+pub struct AdaptiveWindow {
+    target: Duration,
+    min: usize,
+    max: usize,
+    window: usize,
+    /// Commits within the target since the window last grew
+    fast: usize,
+    /// Commits since the window was last halved
+    since_decrease: usize,
+}
+
+impl AdaptiveWindow {
+    pub fn new(config: &AdaptiveWindowConfig, initial: usize) -> Self {
+        let min = config.min_pending.max(1);
+        let max = config.max_pending.max(min);
+
+        AdaptiveWindow {
+            target: Duration::from_millis(config.target_latency_ms),
+            min: min,
+            max: max,
+            window: initial.max(min).min(max),
+            fast: 0,
+            since_decrease: 0,
+        }
+    }
+
+    pub fn window(&self) -> usize {
+        self.window
+    }
+
+    /// Adjust to the commit latency of a proposal, returns the window.
+    pub fn observe(&mut self, latency: Duration) -> usize {
+        self.since_decrease += 1;
+
+        if latency > self.target {
+            self.fast = 0;
+            if self.since_decrease >= self.window && self.window > self.min {
+                self.window = (self.window / 2).max(self.min);
+                self.since_decrease = 0;
+                debug!("Commit latency {:?} over target, proposal window down to {}", latency, self.window);
+            }
+        } else {
+            self.fast += 1;
+            if self.fast >= self.window && self.window < self.max {
+                self.window += 1;
+                self.fast = 0;
+            }
+        }
+
+        self.window
+    }
+}
+
+/// Client proposals accepted by this node which haven't been applied yet.
+///
+/// actix-raft answers proposals once they are applied and doesn't report the commit index, so
+/// accepted proposals count as uncommitted until their response arrives.
+pub struct PendingProposals {
+    next_id: u64,
+    accepted: BTreeMap<u64, (Instant, usize, &'static str)>,
+    expired: u64,
+}
+
+/// Snapshot of the proposals accepted and not applied yet.
+#[derive(Serialize, Debug, Clone)]
+pub struct PendingProposalStats {
+    /// Accepted proposals, queued or in flight
+    pub count: usize,
+    /// Proposals waiting for admission
+    pub queued: usize,
+    /// Proposals handed to raft, not yet committed and applied
+    pub in_flight: usize,
+    /// Proposals which may be in flight at once, adapted to the commit latency when
+    /// `adaptive_window` is configured
+    pub window: usize,
+    /// Serialized size of the accepted proposals
+    pub bytes: usize,
+    /// Age of the oldest accepted proposal
+    pub oldest_age_ms: u64,
+    /// Proposals dropped because their deadline passed while they were queued
+    pub expired: u64,
+}
+
+impl PendingProposals {
+    pub fn new() -> Self {
+        PendingProposals {
+            next_id: 0,
+            accepted: BTreeMap::new(),
+            expired: 0,
+        }
+    }
+
+    /// Track a newly accepted proposal of the given command kind and size, returns its id.
+    pub fn accept(&mut self, kind: &'static str, bytes: usize) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.accepted.insert(id, (clock::now(), bytes, kind));
+        id
+    }
+
+    /// The proposal got its response, applied or failed. Returns its kind, size and how long
+    /// it took.
+    pub fn finish(&mut self, id: u64) -> Option<(&'static str, usize, Duration)> {
+        self.accepted
+            .remove(&id)
+            .map(|(accepted_at, bytes, kind)| (kind, bytes, clock::elapsed(accepted_at)))
+    }
+
+    /// The proposal was dropped before it was handed to raft, its client stopped waiting.
+    pub fn expire(&mut self, id: u64) -> Option<(&'static str, usize, Duration)> {
+        self.expired += 1;
+        self.finish(id)
+    }
+
+    pub fn stats<T>(&self, admission: &AdmissionQueue<T>) -> PendingProposalStats {
+        // ids are increasing, the first one is the oldest
+        let oldest_age_ms = self
+            .accepted
+            .values()
+            .next()
+            .map(|(accepted_at, _, _)| clock::elapsed(*accepted_at).as_millis() as u64)
+            .unwrap_or(0);
+
+        PendingProposalStats {
+            count: self.accepted.len(),
+            queued: admission.queued(),
+            in_flight: admission.pending(),
+            window: admission.max_pending(),
+            bytes: self.accepted.values().map(|(_, bytes, _)| bytes).sum(),
+            oldest_age_ms: oldest_age_ms,
+            expired: self.expired,
+        }
+    }
+}
+
+/// Proposal counts, sizes and latencies of one command kind.
+#[derive(Serialize, Debug, Clone)]
+pub struct CommandStats {
+    pub proposals: u64,
+    /// Proposals which got an error back instead of being applied
+    pub failed: u64,
+    pub bytes: u64,
+    /// Time from acceptance until the proposal was applied
+    pub latency: LatencyHistogram,
+}
+
+/// Write metrics of the proposals this node accepted, by command kind.
+#[derive(Default)]
+pub struct WriteMetrics {
+    commands: BTreeMap<&'static str, CommandStats>,
+}
+
+impl WriteMetrics {
+    pub fn record(&mut self, kind: &'static str, bytes: usize, latency: Duration, applied: bool) {
+        let stats = self.commands.entry(kind).or_insert_with(|| CommandStats {
+            proposals: 0,
+            failed: 0,
+            bytes: 0,
+            latency: LatencyHistogram::new(),
+        });
+
+        stats.proposals += 1;
+        stats.bytes += bytes as u64;
+        if applied {
+            stats.latency.observe(latency.as_millis() as u64);
+        } else {
+            stats.failed += 1;
+        }
+    }
+
+    pub fn snapshot(&self) -> BTreeMap<String, CommandStats> {
+        self.commands.iter().map(|(kind, stats)| (kind.to_string(), stats.clone())).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn queued_proposals_are_released_round_robin() {
+        let mut queue = AdmissionQueue::new(1, 8, 8);
+        assert_eq!(queue.admit("a".to_owned(), "a1"), Some("a1"));
+        assert_eq!(queue.admit("a".to_owned(), "a2"), None);
+        assert_eq!(queue.admit("a".to_owned(), "a3"), None);
+        assert_eq!(queue.admit("b".to_owned(), "b1"), None);
+        assert_eq!(queue.queued(), 3);
+
+        assert_eq!(queue.release(), Some("a2"));
+        assert_eq!(queue.release(), Some("b1"));
+        assert_eq!(queue.release(), Some("a3"));
+        assert_eq!(queue.release(), None);
+        assert_eq!(queue.pending(), 0);
+    }
+
+    #[test]
+    fn raising_the_cap_lets_queued_proposals_through() {
+        let mut queue = AdmissionQueue::new(1, 8, 8);
+        queue.admit("a".to_owned(), 1);
+        queue.admit("a".to_owned(), 2);
+        assert_eq!(queue.next(), None);
+
+        queue.set_max_pending(2);
+        assert_eq!(queue.next(), Some(2));
+        assert_eq!(queue.pending(), 2);
+    }
+
+    #[test]
+    fn queues_are_capped_per_client_and_in_clients() {
+        let mut queue = AdmissionQueue::new(1, 2, 2);
+        queue.admit("a".to_owned(), "a1");
+        queue.admit("a".to_owned(), "a2");
+        queue.admit("a".to_owned(), "a3");
+        assert!(!queue.has_room("a"));
+
+        queue.admit("b".to_owned(), "b1");
+        assert!(queue.has_room("b"));
+        assert!(!queue.has_room("c"));
+
+        // a client whose queue drained makes room for another
+        assert_eq!(queue.release(), Some("a2"));
+        assert_eq!(queue.release(), Some("b1"));
+        assert!(queue.has_room("c"));
+    }
+
+    #[test]
+    fn the_window_grows_additively_and_halves_once_per_window() {
+        let config = AdaptiveWindowConfig {
+            target_latency_ms: 10,
+            min_pending: 1,
+            max_pending: 4,
+        };
+        let mut window = AdaptiveWindow::new(&config, 2);
+        let (fast, slow) = (Duration::from_millis(1), Duration::from_millis(100));
+
+        window.observe(fast);
+        assert_eq!(window.observe(fast), 3);
+        // the first slow commits of a window halve it
+        assert_eq!(window.observe(slow), 1);
+        assert_eq!(window.observe(slow), 1);
+
+        for _ in 0..10 {
+            window.observe(fast);
+        }
+        assert_eq!(window.window(), 4);
+    }
+
+    #[test]
+    fn proposals_are_tracked_until_they_finish() {
+        let mut pending = PendingProposals::new();
+        let queue = AdmissionQueue::<()>::new(8, 8, 8);
+        let first = pending.accept("Add", 10);
+        let second = pending.accept("Remove", 5);
+
+        let stats = pending.stats(&queue);
+        assert_eq!((stats.count, stats.bytes, stats.window), (2, 15, 8));
+
+        assert_eq!(pending.finish(first).map(|(kind, bytes, _)| (kind, bytes)), Some(("Add", 10)));
+        assert!(pending.expire(second).is_some());
+        assert!(pending.finish(second).is_none());
+        let stats = pending.stats(&queue);
+        assert_eq!((stats.count, stats.expired), (0, 1));
+    }
+
+    #[test]
+    fn failed_writes_are_counted_apart() {
+        let mut metrics = WriteMetrics::default();
+        metrics.record("Add", 10, Duration::from_millis(3), true);
+        metrics.record("Add", 10, Duration::from_millis(3), false);
+
+        let stats = &metrics.snapshot()["Add"];
+        assert_eq!((stats.proposals, stats.failed, stats.bytes, stats.latency.count), (2, 1, 20, 1));
+    }
+}
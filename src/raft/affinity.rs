@@ -0,0 +1,122 @@
+//! Client sessions replicated through the log, shared by every node fronting the cluster.
+//!
+//! A client proposing through a gateway with a session id and a sequence number gets an
+//! idempotency key derived from both, so a proposal resent through another gateway after a
+//! reconnect is applied once. Every node tracks the last sequence number and the index it was
+//! applied at, the read watermark of the session.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+const SESSION_KEY_PREFIX: &str = "session:";
+
+/// Idempotency key of the proposal numbered `seq` of a client session.
+pub fn session_key(session: &str, seq: u64) -> String {
+    format!("{}{}:{}", SESSION_KEY_PREFIX, session, seq)
+}
+
+/// Session id and sequence number of a session key.
+fn parse_session_key(key: &str) -> Option<(&str, u64)> {
+    if !key.starts_with(SESSION_KEY_PREFIX) {
+        return None;
+    }
+
+    let rest = &key[SESSION_KEY_PREFIX.len()..];
+    let split = rest.rfind(':')?;
+    let seq = rest[split + 1..].parse().ok()?;
+    Some((&rest[..split], seq))
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ClientSession {
+    /// Highest sequence number applied
+    pub last_seq: u64,
+    /// Index the last write of the session was applied at, reads must see at least this one
+    pub watermark: u64,
+    /// Unix time in milliseconds the last write of the session was stamped with
+    #[serde(default)]
+    pub updated_at: u64,
+}
+
+/// Client sessions, rebuilt from the applied entries.
+#[derive(Default)]
+pub struct SessionTable {
+    sessions: BTreeMap<String, ClientSession>,
+}
+
+impl SessionTable {
+    /// Note an entry applied at `index` and stamped `at`, entries without a session key are
+    /// ignored.
+    pub fn apply(&mut self, index: u64, at: u64, key: Option<&String>) {
+        let (session, seq) = match key.and_then(|key| parse_session_key(key)) {
+            Some(parsed) => parsed,
+            None => return,
+        };
+
+        let state = self.sessions.entry(session.to_owned()).or_insert(ClientSession {
+            last_seq: 0,
+            watermark: 0,
+            updated_at: 0,
+        });
+        state.last_seq = std::cmp::max(state.last_seq, seq);
+        state.watermark = std::cmp::max(state.watermark, index);
+        state.updated_at = std::cmp::max(state.updated_at, at);
+    }
+
+    /// Forget the sessions whose last write was stamped before `before`, returns how many.
+    pub fn purge(&mut self, before: u64) -> usize {
+        let count = self.sessions.len();
+        self.sessions.retain(|_, state| state.updated_at >= before);
+        count - self.sessions.len()
+    }
+
+    /// Whether some session's last write was stamped before `before`.
+    pub fn stale(&self, before: u64) -> bool {
+        self.sessions.values().any(|state| state.updated_at < before)
+    }
+
+    pub fn get(&self, session: &str) -> Option<&ClientSession> {
+        self.sessions.get(session)
+    }
+
+    pub fn clear(&mut self) {
+        self.sessions.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn session_keys_parse_back() {
+        assert_eq!(parse_session_key(&session_key("gw:1", 42)), Some(("gw:1", 42)));
+        assert_eq!(parse_session_key("user:42"), None);
+        assert_eq!(parse_session_key("session:gw"), None);
+    }
+
+    #[test]
+    fn sessions_keep_their_highest_write() {
+        let mut table = SessionTable::default();
+        table.apply(10, 100, Some(&session_key("a", 2)));
+        // a resent proposal applied again later doesn't move the sequence back
+        table.apply(12, 120, Some(&session_key("a", 1)));
+        table.apply(11, 110, Some(&"user:1".to_owned()));
+        table.apply(13, 130, None);
+
+        assert_eq!(table.get("a"), Some(&ClientSession { last_seq: 2, watermark: 12, updated_at: 120 }));
+    }
+
+    #[test]
+    fn stale_sessions_are_purged() {
+        let mut table = SessionTable::default();
+        table.apply(1, 100, Some(&session_key("old", 1)));
+        table.apply(2, 200, Some(&session_key("new", 1)));
+
+        assert!(table.stale(150));
+        assert!(!table.stale(100));
+        assert_eq!(table.purge(150), 1);
+        assert!(table.get("old").is_none());
+        assert!(!table.stale(150));
+    }
+}
@@ -0,0 +1,390 @@
+use rmp_serde as rmps;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use crate::raft::storage::MemoryStorageData;
+
+/// Application state replicated through the log, next to the hash ring.
+///
+/// Commands are proposed as `MemoryStorageData::App` entries built with `app::command` and
+/// applied on every node in log order. Snapshots hold the applied entries, so when one is
+/// installed the state is `reset` and the commands of the snapshot are applied again. Queries
+/// read the state of the node they're sent to, wait for it to apply an index first to read
+/// your writes.
+pub trait AppStateMachine: Send + 'static {
+    type Command: Serialize + DeserializeOwned;
+    type Query: Serialize + DeserializeOwned;
+    type Response: Serialize + DeserializeOwned;
+
+    /// Apply a committed command, `index` is the log index of its entry. Application metrics
+    /// are reported through `ctx`.
+    fn apply(&mut self, ctx: &mut ApplyContext, index: u64, command: Self::Command);
+
+    fn query(&self, query: &Self::Query) -> Self::Response;
+
+    /// Drop the whole state before it's rebuilt from a snapshot.
+    fn reset(&mut self);
+
+    /// Digest of the whole state, e.g. a hash of it, compared between the current and a
+    /// canary version of the state machine. `None` leaves the canary uncompared.
+    fn digest(&self) -> Option<u64> {
+        None
+    }
+
+    /// Answer a query too large for one response, e.g. a key range or a dump, a page at a
+    /// time: at most `limit` items from `cursor` on, and the cursor of the next page, `None`
+    /// after the last item. Items come in a stable order, the cursor is whatever the state
+    /// machine finds the next page from, e.g. the last key returned. `None` if the query
+    /// can't be paged, it's answered whole by `query` instead.
+    fn query_page(&self, _query: &Self::Query, _cursor: Option<&[u8]>, _limit: usize) -> Option<(Vec<Self::Response>, Option<Vec<u8>>)> {
+        None
+    }
+}
+
+/// Handle passed to `AppStateMachine::apply` to report application metrics, keyed by name
+/// and command type, e.g. `ctx.counter("orders_placed", "PlaceOrder", 1)`. They're served
+/// with the node's metrics as `raftor_app_<name>{command="..."}`.
+pub struct ApplyContext<'a> {
+    metrics: &'a AppMetrics,
+}
+
+impl<'a> ApplyContext<'a> {
+    pub(crate) fn new(metrics: &'a AppMetrics) -> ApplyContext<'a> {
+        ApplyContext { metrics: metrics }
+    }
+
+    /// Add `by` to a counter.
+    pub fn counter(&mut self, name: &str, command: &str, by: u64) {
+        let mut metrics = self.metrics.0.lock().unwrap();
+        let value = metrics.entry((name.to_owned(), command.to_owned())).or_insert(AppMetricValue::Counter(0));
+        match value {
+            AppMetricValue::Counter(count) => *count += by,
+            AppMetricValue::Gauge(_) => warn!("Application metric {} is a gauge, not counting it", name),
+        }
+    }
+
+    /// Set a gauge.
+    pub fn gauge(&mut self, name: &str, command: &str, value: i64) {
+        let mut metrics = self.metrics.0.lock().unwrap();
+        let current = metrics.entry((name.to_owned(), command.to_owned())).or_insert(AppMetricValue::Gauge(0));
+        match current {
+            AppMetricValue::Gauge(gauge) => *gauge = value,
+            AppMetricValue::Counter(_) => warn!("Application metric {} is a counter, not setting it", name),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum AppMetricValue {
+    Counter(u64),
+    Gauge(i64),
+}
+
+/// An application metric as of the last command applied.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AppMetric {
+    pub name: String,
+    pub command: String,
+    pub value: AppMetricValue,
+}
+
+/// Metrics reported by the state machine, shared with the network serving them. Commands
+/// applied again when a snapshot is installed are counted again.
+#[derive(Clone, Default)]
+pub struct AppMetrics(Arc<Mutex<BTreeMap<(String, String), AppMetricValue>>>);
+
+impl AppMetrics {
+    pub fn snapshot(&self) -> Vec<AppMetric> {
+        self.0
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|((name, command), value)| AppMetric {
+                name: name.clone(),
+                command: command.clone(),
+                value: *value,
+            })
+            .collect()
+    }
+}
+
+/// Page of a query answered in pages, see `AppStateMachine::query_page`. Items are MessagePack
+/// encoded with their field names.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AppPage {
+    pub items: Vec<Vec<u8>>,
+    /// Cursor of the next page, `None` after the last one
+    pub cursor: Option<Vec<u8>>,
+}
+
+/// State machine with MessagePack encoded commands, queries and responses, so the storage
+/// doesn't depend on the application types.
+pub(crate) trait ErasedStateMachine: Send {
+    fn apply(&mut self, ctx: &mut ApplyContext, index: u64, command: &[u8]) -> Result<(), String>;
+
+    fn query(&self, query: &[u8]) -> Result<Vec<u8>, String>;
+
+    /// A page of the answer, or the whole answer as the only item of the last page when the
+    /// query can't be paged.
+    fn query_page(&self, query: &[u8], cursor: Option<&[u8]>, limit: usize) -> Result<AppPage, String>;
+
+    fn reset(&mut self);
+
+    fn digest(&self) -> Option<u64>;
+}
+
+struct Erased<S>(S);
+
+impl<S: AppStateMachine> ErasedStateMachine for Erased<S> {
+    fn apply(&mut self, ctx: &mut ApplyContext, index: u64, command: &[u8]) -> Result<(), String> {
+        let command = rmps::from_slice::<S::Command>(command).map_err(|err| err.to_string())?;
+        self.0.apply(ctx, index, command);
+        Ok(())
+    }
+
+    fn query(&self, query: &[u8]) -> Result<Vec<u8>, String> {
+        let query = rmps::from_slice::<S::Query>(query).map_err(|err| err.to_string())?;
+        rmps::to_vec(&self.0.query(&query)).map_err(|err| err.to_string())
+    }
+
+    fn query_page(&self, query: &[u8], cursor: Option<&[u8]>, limit: usize) -> Result<AppPage, String> {
+        let query = rmps::from_slice::<S::Query>(query).map_err(|err| err.to_string())?;
+        let (items, cursor) = match self.0.query_page(&query, cursor, limit) {
+            Some(page) => page,
+            None => (vec![self.0.query(&query)], None),
+        };
+
+        // named fields, so gateways can turn items into JSON objects
+        let items = items
+            .iter()
+            .map(|item| rmps::to_vec_named(item).map_err(|err| err.to_string()))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(AppPage { items: items, cursor: cursor })
+    }
+
+    fn reset(&mut self) {
+        self.0.reset()
+    }
+
+    fn digest(&self) -> Option<u64> {
+        self.0.digest()
+    }
+}
+
+pub(crate) fn erase<S: AppStateMachine>(state_machine: S) -> Box<dyn ErasedStateMachine> {
+    Box::new(Erased(state_machine))
+}
+
+/// How a canary state machine compares to the current one, see `Raftor::set_canary_state_machine`.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct CanaryReport {
+    /// Commands applied to the canary
+    pub applied: u64,
+    /// Digest comparisons made, the last one after applying `last_compared_index`
+    pub compared: u64,
+    pub last_compared_index: Option<u64>,
+    /// Comparisons whose digests differed, and the index of the first one
+    pub divergences: u64,
+    pub diverged_at: Option<u64>,
+    /// Commands the canary couldn't decode while the current version could
+    pub canary_errors: u64,
+}
+
+/// A new version of the state machine applying every command in the shadow of the current
+/// one, their digests are compared every `compare_every` commands.
+pub(crate) struct Canary {
+    state_machine: Box<dyn ErasedStateMachine>,
+    compare_every: u64,
+    report: CanaryReport,
+    /// Metrics of the canary, kept apart from those served
+    metrics: AppMetrics,
+}
+
+impl Canary {
+    pub(crate) fn new<S: AppStateMachine>(state_machine: S, compare_every: u64) -> Canary {
+        Canary {
+            state_machine: erase(state_machine),
+            compare_every: compare_every.max(1),
+            report: CanaryReport::default(),
+            metrics: AppMetrics::default(),
+        }
+    }
+
+    /// Apply the command the current version applied, returns the digests when they differ
+    /// for the first time.
+    pub(crate) fn apply(&mut self, index: u64, command: &[u8], current: &dyn ErasedStateMachine) -> Option<(u64, u64)> {
+        if let Err(err) = self.state_machine.apply(&mut ApplyContext::new(&self.metrics), index, command) {
+            warn!("Canary state machine failed to apply the command at {}: {}", index, err);
+            self.report.canary_errors += 1;
+        }
+        self.report.applied += 1;
+
+        if self.report.applied % self.compare_every != 0 {
+            return None;
+        }
+
+        let (current, canary) = match (current.digest(), self.state_machine.digest()) {
+            (Some(current), Some(canary)) => (current, canary),
+            _ => return None,
+        };
+        self.report.compared += 1;
+        self.report.last_compared_index = Some(index);
+
+        if current == canary {
+            return None;
+        }
+        self.report.divergences += 1;
+        if self.report.diverged_at.is_some() {
+            return None;
+        }
+        self.report.diverged_at = Some(index);
+        Some((current, canary))
+    }
+
+    pub(crate) fn reset(&mut self) {
+        self.state_machine.reset();
+    }
+
+    pub(crate) fn report(&self) -> CanaryReport {
+        self.report.clone()
+    }
+}
+
+/// Encode a command of the application state machine as proposal data.
+pub fn command<S: AppStateMachine>(command: &S::Command) -> Result<MemoryStorageData, String> {
+    rmps::to_vec(command)
+        .map(MemoryStorageData::App)
+        .map_err(|err| err.to_string())
+}
+
+/// Proposal data as clients send it through the HTTP gateway and the JSON protocol, `{"App": [..]}` with the
+/// command encoded by `command`. The ring, membership and the other commands are the node's
+/// own, clients can't propose them.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ClientCommand {
+    App(Vec<u8>),
+}
+
+impl From<ClientCommand> for MemoryStorageData {
+    fn from(command: ClientCommand) -> MemoryStorageData {
+        match command {
+            ClientCommand::App(command) => MemoryStorageData::App(command),
+        }
+    }
+}
+
+/// Encode a query of the application state machine, to send as `QueryApp`.
+pub fn query<S: AppStateMachine>(query: &S::Query) -> Result<Vec<u8>, String> {
+    rmps::to_vec(query).map_err(|err| err.to_string())
+}
+
+/// Decode the answer to a `QueryApp`.
+pub fn response<S: AppStateMachine>(response: &[u8]) -> Result<S::Response, String> {
+    rmps::from_slice(response).map_err(|err| err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sums the commands applied, `scale` times each.
+    struct Sum {
+        total: i64,
+        scale: i64,
+    }
+
+    impl AppStateMachine for Sum {
+        type Command = i64;
+        type Query = ();
+        type Response = i64;
+
+        fn apply(&mut self, ctx: &mut ApplyContext, _index: u64, command: i64) {
+            self.total += command * self.scale;
+            ctx.counter("adds", "Add", 1);
+            ctx.gauge("total", "Add", self.total);
+        }
+
+        fn query(&self, _query: &()) -> i64 {
+            self.total
+        }
+
+        fn reset(&mut self) {
+            self.total = 0;
+        }
+
+        fn digest(&self) -> Option<u64> {
+            Some(self.total as u64)
+        }
+    }
+
+    fn apply(state_machine: &mut dyn ErasedStateMachine, metrics: &AppMetrics, index: u64, command: i64) {
+        let command = match super::command::<Sum>(&command).unwrap() {
+            MemoryStorageData::App(command) => command,
+            data => panic!("not an app command: {:?}", data),
+        };
+        state_machine.apply(&mut ApplyContext::new(metrics), index, &command).unwrap();
+    }
+
+    #[test]
+    fn commands_and_queries_round_trip() {
+        let metrics = AppMetrics::default();
+        let mut state_machine = erase(Sum { total: 0, scale: 1 });
+        apply(&mut *state_machine, &metrics, 1, 3);
+        apply(&mut *state_machine, &metrics, 2, 4);
+
+        let answer = state_machine.query(&query::<Sum>(&()).unwrap()).unwrap();
+        assert_eq!(response::<Sum>(&answer).unwrap(), 7);
+        assert!(state_machine.apply(&mut ApplyContext::new(&metrics), 3, &[0xc1]).is_err());
+    }
+
+    #[test]
+    fn queries_which_cant_be_paged_come_back_whole() {
+        let metrics = AppMetrics::default();
+        let mut state_machine = erase(Sum { total: 0, scale: 1 });
+        apply(&mut *state_machine, &metrics, 1, 3);
+
+        let page = state_machine.query_page(&query::<Sum>(&()).unwrap(), None, 10).unwrap();
+        assert_eq!((page.items.len(), page.cursor), (1, None));
+    }
+
+    #[test]
+    fn state_machines_report_metrics_while_applying() {
+        let metrics = AppMetrics::default();
+        let mut state_machine = erase(Sum { total: 0, scale: 1 });
+        apply(&mut *state_machine, &metrics, 1, 3);
+        apply(&mut *state_machine, &metrics, 2, 4);
+
+        let reported = metrics.snapshot();
+        assert_eq!(reported.len(), 2);
+        assert_eq!(reported[0].value, AppMetricValue::Counter(2));
+        assert_eq!(reported[1].value, AppMetricValue::Gauge(7));
+    }
+
+    #[test]
+    fn the_first_divergence_of_a_canary_is_reported() {
+        let metrics = AppMetrics::default();
+        let mut current = erase(Sum { total: 0, scale: 1 });
+        let mut canary = Canary::new(Sum { total: 0, scale: 2 }, 2);
+
+        let mut divergences = Vec::new();
+        for (index, command) in [(1, 0), (2, 0), (3, 1), (4, 1), (5, 1), (6, 1)].iter() {
+            apply(&mut *current, &metrics, *index, *command);
+            let encoded = rmps::to_vec(command).unwrap();
+            divergences.extend(canary.apply(*index, &encoded, &*current));
+        }
+
+        assert_eq!(divergences, vec![(2, 4)]);
+        let report = canary.report();
+        assert_eq!((report.applied, report.compared, report.divergences, report.diverged_at), (6, 3, 2, Some(4)));
+    }
+
+    #[test]
+    fn clients_propose_application_commands_only() {
+        let command = serde_json::from_str::<ClientCommand>(r#"{"App": [3]}"#).unwrap();
+        assert_eq!(MemoryStorageData::from(command), MemoryStorageData::App(vec![3]));
+
+        assert!(serde_json::from_str::<ClientCommand>(r#"{"Add": 42}"#).is_err());
+        assert!(serde_json::from_str::<ClientCommand>(r#"{"Freeze": true}"#).is_err());
+    }
+}
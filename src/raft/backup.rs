@@ -0,0 +1,252 @@
+//! Cluster-consistent backups.
+//!
+//! The leader proposes a `Backup` marker. Every member applying it dumps its applied state as
+//! of the marker's entry to `<snapshot dir>/backups/<id>/state.json`, so the dumps of all
+//! members cover the same entries and are identical. The leader collects the checksum of each
+//! member's dump and writes the manifest next to its own once they all match. The directory
+//! is the backup set: it's restored into a fresh cluster by proposing the dumped commands to
+//! its leader, in batches small enough to fit a peer frame.
+
+use actix_raft::NodeId;
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use serde::{Deserialize, Serialize};
+use rmp_serde as rmps;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::network::ProposeError;
+use crate::raft::storage::{MemoryStorageData, StateDump};
+use crate::utils;
+
+/// Directory of the backups under the snapshot dir
+pub const BACKUP_DIR: &str = "backups";
+
+/// Encoded size of the commands proposed in one entry of a restore, well under the frame limit
+/// of the peer connections replicating it
+pub const RESTORE_BATCH_BYTES: usize = 4 * 1024 * 1024;
+
+const STATE_FILE: &str = "state.json";
+const MANIFEST_FILE: &str = "manifest.json";
+
+/// The state a member dumped when applying a backup marker.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct BackupPart {
+    /// Index of the marker, the dump covers the entries through it
+    pub index: u64,
+    pub term: u64,
+    pub entries: usize,
+    /// Sha256 of the dump
+    pub checksum: String,
+}
+
+/// Description of a backup set, written by the leader once every member confirmed its dump.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BackupManifest {
+    pub id: String,
+    pub index: u64,
+    pub term: u64,
+    pub entries: usize,
+    pub checksum: String,
+    /// Members which dumped identical state at the index
+    pub members: Vec<NodeId>,
+    /// Directory of the backup set on the leader
+    pub path: String,
+}
+
+/// A backup set restored into the cluster.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RestoredBackup {
+    pub id: String,
+    /// Index the backup was taken at on the source cluster
+    pub source_index: u64,
+    /// Index of the entry restoring it in this cluster
+    pub index: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum BackupError {
+    /// Backups are taken and restored through the leader
+    NotLeader { leader_hint: Option<NodeId> },
+    /// Members which didn't confirm their dump in time
+    Unconfirmed(Vec<NodeId>),
+    /// Members whose dump differs from the leader's
+    Diverged(Vec<NodeId>),
+    /// The backup set is incomplete or doesn't match its manifest
+    Corrupt(String),
+    /// The cluster restored into already holds state
+    NotEmpty,
+    Io(String),
+    Propose(ProposeError),
+}
+
+/// Directory of a backup under the snapshot dir.
+pub fn backup_dir(snapshot_dir: &str, id: &str) -> PathBuf {
+    Path::new(snapshot_dir).join(BACKUP_DIR).join(id)
+}
+
+/// Directory of a backup set copied under the snapshot dir, given by a client. `None` when the
+/// name isn't a directory under the backup dir.
+pub fn restore_dir(snapshot_dir: &str, name: &str) -> Option<PathBuf> {
+    utils::contained_path(Path::new(snapshot_dir).join(BACKUP_DIR), name)
+}
+
+/// Backups to drop so only the `retention` latest ones, by index, are kept.
+pub fn stale(backups: &BTreeMap<String, BackupPart>, retention: usize) -> Vec<String> {
+    let mut ids = backups.iter().map(|(id, part)| (part.index, id.clone())).collect::<Vec<_>>();
+    ids.sort();
+    let excess = ids.len().saturating_sub(retention);
+    ids.into_iter().take(excess).map(|(_, id)| id).collect()
+}
+
+/// Write the dump of a member, replacing a previous one at once.
+pub fn capture(dir: &Path, dump: &StateDump) -> io::Result<BackupPart> {
+    let data = serde_json::to_vec(dump).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    write_file(dir, STATE_FILE, &data)?;
+
+    Ok(BackupPart {
+        index: dump.index,
+        term: dump.term,
+        entries: dump.entries.len(),
+        checksum: digest(&data),
+    })
+}
+
+/// Write the manifest next to the dump of the leader, completing the backup set.
+pub fn seal(dir: &Path, manifest: &BackupManifest) -> io::Result<()> {
+    let data = serde_json::to_vec_pretty(manifest).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    write_file(dir, MANIFEST_FILE, &data)
+}
+
+/// Read a backup set, checking the dump against its manifest.
+pub fn load(dir: &Path) -> Result<(BackupManifest, StateDump), BackupError> {
+    let manifest = fs::read(dir.join(MANIFEST_FILE)).map_err(|err| BackupError::Corrupt(format!("no manifest: {}", err)))?;
+    let manifest = serde_json::from_slice::<BackupManifest>(&manifest).map_err(|err| BackupError::Corrupt(err.to_string()))?;
+
+    let data = fs::read(dir.join(STATE_FILE)).map_err(|err| BackupError::Corrupt(format!("no state: {}", err)))?;
+    if digest(&data) != manifest.checksum {
+        return Err(BackupError::Corrupt("the state doesn't match the checksum of the manifest".to_owned()));
+    }
+    let dump = serde_json::from_slice::<StateDump>(&data).map_err(|err| BackupError::Corrupt(err.to_string()))?;
+    if dump.index != manifest.index {
+        return Err(BackupError::Corrupt(format!("the state ends at {} instead of {}", dump.index, manifest.index)));
+    }
+
+    Ok((manifest, dump))
+}
+
+/// Commands restoring the dump into another cluster. The topology of the source cluster,
+/// freezes and backup markers aren't restored.
+pub fn restore_commands(dump: &StateDump) -> Vec<MemoryStorageData> {
+    dump.entries
+        .iter()
+        .flat_map(|entry| entry.data.commands())
+        .filter(|command| !is_local(command))
+        .cloned()
+        .collect()
+}
+
+/// The commands split in order into batches of at most `max_bytes` encoded, a command larger
+/// than that alone makes a batch.
+pub fn batches(commands: Vec<MemoryStorageData>, max_bytes: usize) -> Vec<Vec<MemoryStorageData>> {
+    let mut batches = Vec::new();
+    let mut batch = Vec::new();
+    let mut bytes = 0;
+    for command in commands {
+        let size = rmps::to_vec(&command).map(|data| data.len()).unwrap_or(0);
+        if !batch.is_empty() && bytes + size > max_bytes {
+            batches.push(std::mem::replace(&mut batch, Vec::new()));
+            bytes = 0;
+        }
+        bytes += size;
+        batch.push(command);
+    }
+    if !batch.is_empty() {
+        batches.push(batch);
+    }
+    batches
+}
+
+/// Whether the command only concerns the cluster it was proposed on.
+pub fn is_local(command: &MemoryStorageData) -> bool {
+    match command {
+        MemoryStorageData::Add(_)
+        | MemoryStorageData::Remove(_)
+        | MemoryStorageData::SetAddress(..)
+        | MemoryStorageData::Cordon { .. }
+        | MemoryStorageData::Freeze(_)
+        | MemoryStorageData::Backup { .. }
+        | MemoryStorageData::Purge { .. } => true,
+        _ => false,
+    }
+}
+
+fn digest(data: &[u8]) -> String {
+    let mut sha = Sha256::new();
+    sha.input(data);
+    sha.result_str()
+}
+
+fn write_file(dir: &Path, name: &str, data: &[u8]) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    let partial = dir.join(format!("{}.partial", name));
+    fs::write(&partial, data)?;
+    fs::rename(&partial, dir.join(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn part(index: u64) -> BackupPart {
+        BackupPart {
+            index: index,
+            term: 1,
+            entries: 0,
+            checksum: String::new(),
+        }
+    }
+
+    #[test]
+    fn stale_backups_are_the_oldest_beyond_retention() {
+        let mut backups = BTreeMap::new();
+        backups.insert("c".to_owned(), part(30));
+        backups.insert("a".to_owned(), part(10));
+        backups.insert("b".to_owned(), part(20));
+
+        assert_eq!(stale(&backups, 2), vec!["a".to_owned()]);
+        assert_eq!(stale(&backups, 3), Vec::<String>::new());
+        assert_eq!(stale(&backups, 0).len(), 3);
+    }
+
+    #[test]
+    fn batches_keep_order_under_the_limit() {
+        let commands = (0..10u8).map(|n| MemoryStorageData::App(vec![n; 100])).collect::<Vec<_>>();
+        let size = rmps::to_vec(&commands[0]).unwrap().len();
+
+        let batches = batches(commands.clone(), size * 3);
+        assert_eq!(batches.iter().map(|batch| batch.len()).collect::<Vec<_>>(), vec![3, 3, 3, 1]);
+        assert_eq!(batches.concat(), commands);
+    }
+
+    #[test]
+    fn oversized_commands_get_a_batch_of_their_own() {
+        let commands = vec![
+            MemoryStorageData::App(vec![0; 10]),
+            MemoryStorageData::App(vec![1; 1000]),
+            MemoryStorageData::App(vec![2; 10]),
+        ];
+
+        let batches = batches(commands, 100);
+        assert_eq!(batches.iter().map(|batch| batch.len()).collect::<Vec<_>>(), vec![1, 1, 1]);
+    }
+
+    #[test]
+    fn restore_dir_stays_under_the_backup_dir() {
+        assert_eq!(restore_dir("snapshots", "backup-1"), Some(Path::new("snapshots/backups/backup-1").to_path_buf()));
+        assert_eq!(restore_dir("snapshots", "/etc"), None);
+        assert_eq!(restore_dir("snapshots", "../log"), None);
+    }
+}
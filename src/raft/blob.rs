@@ -0,0 +1,233 @@
+//! Large client payloads proposed by reference.
+//!
+//! A payload too large for one log entry, e.g. a bulk import, is streamed by the client into a
+//! spill file under `<snapshot dir>/blobs`, named after its sha256 once complete, and a small
+//! `Blob` entry referencing it is proposed instead. Nodes missing the blob when they're about
+//! to apply the entry fetch it from their peers in chunks, as snapshots are sent, and verify
+//! it against its id. The blob holds an application command, encoded as by `app::command`,
+//! applied like an `App` entry. Snapshots inline the blobs they reference, so nodes installing
+//! one don't need them.
+
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use actix_raft::messages::EntryPayload;
+
+use crate::raft::storage::{Entry, MemoryStorageData};
+
+/// Directory of the blobs under the snapshot dir
+pub const BLOB_DIR: &str = "blobs";
+
+static SPILLS: AtomicU64 = AtomicU64::new(0);
+
+/// Reference to a blob, `id` is the sha256 of its content.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct BlobRef {
+    pub id: String,
+    pub size: u64,
+}
+
+/// A chunk of a blob sent to a peer fetching it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BlobChunk {
+    pub data: Vec<u8>,
+    /// Whether the chunk ends the blob
+    pub done: bool,
+}
+
+pub fn blob_dir(snapshot_dir: &str) -> PathBuf {
+    Path::new(snapshot_dir).join(BLOB_DIR)
+}
+
+/// Whether the blob is complete on this node.
+pub fn exists(snapshot_dir: &str, blob: &BlobRef) -> bool {
+    fs::metadata(blob_dir(snapshot_dir).join(&blob.id))
+        .map(|meta| meta.len() == blob.size)
+        .unwrap_or(false)
+}
+
+pub fn read(snapshot_dir: &str, blob: &BlobRef) -> io::Result<Vec<u8>> {
+    fs::read(blob_dir(snapshot_dir).join(&blob.id))
+}
+
+/// Read up to `len` bytes of a blob from `offset` on.
+pub fn read_chunk(snapshot_dir: &str, id: &str, offset: u64, len: u64) -> io::Result<BlobChunk> {
+    // ids are hex digests, anything else could walk out of the blob dir
+    if !id.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} is not a blob id", id)));
+    }
+
+    let mut file = File::open(blob_dir(snapshot_dir).join(id))?;
+    let size = file.metadata()?.len();
+    file.seek(SeekFrom::Start(offset))?;
+
+    let mut data = Vec::new();
+    file.take(len).read_to_end(&mut data)?;
+    Ok(BlobChunk {
+        done: offset + data.len() as u64 >= size,
+        data: data,
+    })
+}
+
+/// The entry with the blobs it references replaced by the commands they hold, for snapshots.
+pub fn inline(snapshot_dir: &str, entry: &Entry) -> io::Result<Entry> {
+    let mut entry = entry.clone();
+
+    if let EntryPayload::Normal(ref mut normal) = entry.payload {
+        inline_command(snapshot_dir, &mut normal.data.data)?;
+    }
+
+    Ok(entry)
+}
+
+fn inline_command(snapshot_dir: &str, command: &mut MemoryStorageData) -> io::Result<()> {
+    match command {
+        MemoryStorageData::Blob(ref blob) => *command = MemoryStorageData::App(read(snapshot_dir, blob)?),
+        MemoryStorageData::Batch(ref mut commands) => {
+            for command in commands.iter_mut() {
+                inline_command(snapshot_dir, command)?;
+            }
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
+/// A blob being written, under a temporary name until it's complete. Dropped before it's
+/// finished, the partial blob is removed.
+pub struct Spill {
+    dir: PathBuf,
+    partial: PathBuf,
+    file: File,
+    sha: Sha256,
+    size: u64,
+}
+
+impl Spill {
+    pub fn create(snapshot_dir: &str) -> io::Result<Spill> {
+        let dir = blob_dir(snapshot_dir);
+        fs::create_dir_all(&dir)?;
+
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0);
+        let partial = dir.join(format!("{}-{}.partial", nanos, SPILLS.fetch_add(1, Ordering::Relaxed)));
+        Ok(Spill {
+            file: File::create(&partial)?,
+            dir: dir,
+            partial: partial,
+            sha: Sha256::new(),
+            size: 0,
+        })
+    }
+
+    pub fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.file.write_all(data)?;
+        self.sha.input(data);
+        self.size += data.len() as u64;
+        Ok(())
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Sync the blob and move it under its id, replacing the same blob if it was written
+    /// before.
+    pub fn finish(mut self) -> io::Result<BlobRef> {
+        self.file.sync_all()?;
+        let blob = BlobRef {
+            id: self.sha.result_str(),
+            size: self.size,
+        };
+        fs::rename(&self.partial, self.dir.join(&blob.id))?;
+        Ok(blob)
+    }
+}
+
+impl Drop for Spill {
+    fn drop(&mut self) {
+        // gone already once finished
+        let _ = fs::remove_file(&self.partial);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_raft::messages::EntryNormal;
+
+    use crate::raft::hlc::Hlc;
+    use crate::raft::storage::StampedData;
+
+    fn spill(snapshot_dir: &str, data: &[u8]) -> BlobRef {
+        let mut spill = Spill::create(snapshot_dir).unwrap();
+        spill.write(&data[..3]).unwrap();
+        spill.write(&data[3..]).unwrap();
+        assert_eq!(spill.size(), data.len() as u64);
+        spill.finish().unwrap()
+    }
+
+    #[test]
+    fn blobs_are_named_after_their_digest() {
+        let dir = tempfile::tempdir().unwrap();
+        let snapshot_dir = dir.path().to_str().unwrap();
+        let blob = spill(snapshot_dir, b"bulk import");
+
+        let mut sha = Sha256::new();
+        sha.input(b"bulk import");
+        assert_eq!(blob.id, sha.result_str());
+        assert!(exists(snapshot_dir, &blob));
+        assert!(!exists(snapshot_dir, &BlobRef { id: blob.id.clone(), size: 1 }));
+
+        let first = read_chunk(snapshot_dir, &blob.id, 0, 4).unwrap();
+        assert_eq!((first.data.as_slice(), first.done), (&b"bulk"[..], false));
+        let last = read_chunk(snapshot_dir, &blob.id, 4, 100).unwrap();
+        assert_eq!((last.data.as_slice(), last.done), (&b" import"[..], true));
+        assert!(read_chunk(snapshot_dir, "../../etc/passwd", 0, 4).is_err());
+    }
+
+    #[test]
+    fn unfinished_spills_leave_nothing_behind() {
+        let dir = tempfile::tempdir().unwrap();
+        let snapshot_dir = dir.path().to_str().unwrap();
+        let mut spill = Spill::create(snapshot_dir).unwrap();
+        spill.write(b"partial").unwrap();
+        drop(spill);
+
+        assert_eq!(fs::read_dir(blob_dir(snapshot_dir)).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn snapshots_inline_the_blobs_of_batches() {
+        let dir = tempfile::tempdir().unwrap();
+        let snapshot_dir = dir.path().to_str().unwrap();
+        let blob = spill(snapshot_dir, b"bulk import");
+        let entry = Entry {
+            index: 1,
+            term: 1,
+            payload: EntryPayload::Normal(EntryNormal {
+                data: StampedData {
+                    hlc: Hlc::default(),
+                    data: MemoryStorageData::Batch(vec![MemoryStorageData::Blob(blob)]),
+                    key: None,
+                },
+            }),
+        };
+
+        match inline(snapshot_dir, &entry).unwrap().payload {
+            EntryPayload::Normal(normal) => match normal.data.data {
+                MemoryStorageData::Batch(ref commands) => match &commands[..] {
+                    [MemoryStorageData::App(command)] => assert_eq!(command.as_slice(), &b"bulk import"[..]),
+                    _ => panic!("blob not inlined"),
+                },
+                _ => panic!("batch not kept"),
+            },
+            _ => panic!("entry not kept"),
+        }
+    }
+}
@@ -4,32 +4,92 @@ use actix_raft::{
     messages::*,
     NodeId, Raft, RaftMetrics,
 };
-use log::debug;
+use log::{debug, error, info, warn};
+use std::collections::BTreeMap;
+use std::io;
 use std::time::{Duration, Instant};
-use std::sync::{Arc, RwLock};
+use std::sync::{atomic::Ordering, Arc, RwLock};
 use serde::{Serialize, Deserialize};
-use tokio::timer::Delay;
-use crate::network::{Network, remote::SendRemoteMessage, DiscoverNodes, GetCurrentLeader, GetNodeById, HandlerRegistry};
+use tokio::sync::oneshot;
+use actix_web::{error::BlockingError, web};
+use crate::clock;
+use crate::config::{ConfigSchema, StorageFaultPolicy};
+use crate::dictionary::{self, Sampler};
+use crate::network::{Network, remote::SendRemoteMessage, DiscoverNodes, LeaderCommit, Formation, GetCurrentLeader, GetFollowerProgress, GetNodeById, GetRaftMetrics, HandlerRegistry, PeerCatchingUp, ProposeError, ReadBarrier, RetryBudget, ReadIndex, StepDown};
+use crate::error::RaftorError;
 use crate::raft::{
-    storage::{MemoryStorageData, MemoryStorageError, MemoryStorageResponse},
+    storage::{EntryTimingReport, ExportStateDump, GetBackupPart, LocateBackup, GetStateDump, SealBackup, GetClusterRecords, GetEntryTimings, HasDebris, GetStorageFault, GetStorageStats, InspectedEntry, LookupClientSession, LookupNamespace, ReadLogEntries, StartVacuum, MemoryStorage, MemoryStorageData, MemoryStorageError, MemoryStorageResponse, StampedData, StateDumpInfo, SetMaintenance, StorageFault, StorageFaulted, StorageStats, TruncateLog, WriteFreeze, ClusterEpoch, GetLock, CheckFencingToken, LookupRingMember, LocalRead, QueryAppState, QueryAppStatePage, QueryLocalState, GetLastLogIndex, GetSinkBatch, TriggerSnapshot, GetProjections, RebuildProjection, GetBlobChunk, OpenBlob},
+    affinity::ClientSession,
+    backup::{self, BackupError, BackupManifest, BackupPart, RestoredBackup},
+    blob::{BlobChunk, Spill},
+    app::AppPage,
+    admission::{AdaptiveWindow, AdmissionQueue, CommandStats, PendingProposalStats, PendingProposals, WriteMetrics},
+    hlc::{HybridClock, SharedClock},
+    hooks::Hooks,
+    projection::ProjectionStatus,
+    sink::{LogSink, Publish},
+    transfer::SnapshotSealer,
     RaftBuilder, MemRaft,
 };
+use crate::crash::CrashState;
+use crate::events::EventLog;
+use crate::memory::{MemoryAccount, MemoryArea};
+use crate::federation::ClusterRecord;
+use crate::locks::FencingToken;
 use crate::hash_ring::RingType;
+use crate::utils;
 use crate::server::Server;
+use crate::runtime::ExitReason;
+use crate::raft::recovery::LogReplay;
 
-type ClientResponseHandler = Result<
+/// How long hash ring changes of the node are retried before they're reported as stuck
+const RING_PROPOSAL_BUDGET: Duration = Duration::from_secs(60);
+
+/// How often a member checks whether it dumped a backup, and how many times before giving up
+const BACKUP_POLL_INTERVAL: Duration = Duration::from_millis(100);
+const BACKUP_CONFIRM_ATTEMPTS: u32 = 100;
+
+pub(crate) type ClientResponseHandler = Result<
     ClientPayloadResponse<MemoryStorageResponse>,
-    ClientError<MemoryStorageData, MemoryStorageResponse, MemoryStorageError>,
+    ClientError<StampedData, MemoryStorageResponse, MemoryStorageError>,
 >;
 
-pub type Payload = ClientPayload<MemoryStorageData, MemoryStorageResponse, MemoryStorageError>;
+pub type Payload = ClientPayload<StampedData, MemoryStorageResponse, MemoryStorageError>;
 
 pub struct RaftClient {
     id: NodeId,
     ring: RingType,
     raft: Option<Addr<MemRaft>>,
+    storage: Option<Addr<MemoryStorage>>,
     registry: Arc<RwLock<HandlerRegistry>>,
     net: Option<Addr<Network>>,
+    admission: AdmissionQueue<QueuedProposal>,
+    proposals: PendingProposals,
+    writes: WriteMetrics,
+    fault: Option<StorageFault>,
+    /// Log index whose append hit the storage fault
+    failed_append: Option<u64>,
+    /// Set while this node is a leader cut off from its quorum
+    isolated: bool,
+    frozen: WriteFreeze,
+    epoch: ClusterEpoch,
+    /// Progress of the replay of the persisted log when raft starts
+    replay: LogReplay,
+    config: ConfigSchema,
+    hooks: Hooks,
+    clock: SharedClock,
+    events: EventLog,
+    memory: MemoryAccount,
+    crash: CrashState,
+    window: Option<AdaptiveWindow>,
+    /// When the lag of a follower last triggered a snapshot
+    snapshot_triggered: Option<Instant>,
+    /// Log sinks the leader publishes applied entries to
+    sinks: Vec<LogSink>,
+    /// Training set of the compression dictionary, see `dictionary`
+    sampler: Option<Sampler>,
+    /// Highest commit index a leader told this follower about for entries it holds
+    leader_commit: u64,
 }
 
 impl Actor for RaftClient {
@@ -39,25 +99,258 @@ impl Actor for RaftClient {
 }
 
 impl RaftClient {
-    pub fn new(id: NodeId, ring: RingType, registry: Arc<RwLock<HandlerRegistry>>) -> RaftClient {
+    pub fn new(id: NodeId, ring: RingType, registry: Arc<RwLock<HandlerRegistry>>, config: ConfigSchema, hooks: Hooks, events: EventLog, memory: MemoryAccount, crash: CrashState) -> RaftClient {
+        let window = config
+            .adaptive_window
+            .as_ref()
+            .map(|window| AdaptiveWindow::new(window, config.max_pending_proposals));
+        let max_pending = window.as_ref().map(AdaptiveWindow::window).unwrap_or(config.max_pending_proposals);
+        let sampler = config.compression_dictionary.clone().map(Sampler::new);
+
         RaftClient {
             id: id,
             ring: ring,
             raft: None,
+            storage: None,
             registry: registry,
             net: None,
+            admission: AdmissionQueue::new(max_pending, config.max_queued_proposals, config.max_queued_clients),
+            proposals: PendingProposals::new(),
+            writes: WriteMetrics::default(),
+            fault: None,
+            failed_append: None,
+            isolated: false,
+            frozen: WriteFreeze::default(),
+            epoch: ClusterEpoch::default(),
+            replay: LogReplay::default(),
+            config: config,
+            hooks: hooks,
+            clock: HybridClock::new(),
+            events: events,
+            memory: memory,
+            crash: crash,
+            window: window,
+            snapshot_triggered: None,
+            sinks: Vec::new(),
+            sampler: sampler,
+            leader_commit: 0,
+        }
+
+    }
+
+    /// The cluster epoch as applied by the storage of this node, see `ClusterEpoch`.
+    pub fn epoch(&self) -> ClusterEpoch {
+        self.epoch.clone()
+    }
+
+    /// Progress of the replay of the persisted log, see `LogReplay`.
+    pub fn log_replay(&self) -> LogReplay {
+        self.replay.clone()
+    }
+
+    /// On the leader, snapshot once the furthest behind follower needs compacted entries or
+    /// lags too far behind, at most once per `min_interval_secs`.
+    fn check_follower_lag(&mut self, ctx: &mut Context<Self>) {
+        let (net, storage, trigger) = match (&self.net, &self.storage, &self.config.snapshot_trigger) {
+            (Some(net), Some(storage), Some(trigger)) => (net.clone(), storage.clone(), trigger.clone()),
+            _ => return,
+        };
+
+        let min_interval = Duration::from_secs(trigger.min_interval_secs);
+        if self.snapshot_triggered.map(|at| clock::elapsed(at) < min_interval).unwrap_or(false) {
+            return;
+        }
+
+        fut::wrap_future::<_, Self>(net.send(GetFollowerProgress))
+            .map_err(|_, _, _| ())
+            .and_then(|res, _, _| fut::result(res))
+            .and_then(move |matched, _, _| match matched.values().min() {
+                Some(&lowest) => fut::Either::A(
+                    fut::wrap_future(storage.send(TriggerSnapshot {
+                        matched: lowest,
+                        max_lag: trigger.max_follower_lag,
+                    }))
+                    .map_err(|_, _, _| ())
+                    .and_then(|res, _, _| fut::result(res.map_err(|_| ()))),
+                ),
+                None => fut::Either::B(fut::ok(None)),
+            })
+            .map(|snapshot, act: &mut Self, _| {
+                if let Some(index) = snapshot {
+                    info!("Node {} snapshotted through index {} for a lagging follower", act.id, index);
+                    act.snapshot_triggered = Some(clock::now());
+                }
+            })
+            .spawn(ctx);
+    }
+
+    /// On the leader, publish the entries applied after the checkpoint of the sink and propose
+    /// the new checkpoint, one batch at a time. A node which isn't leader forgets how far it
+    /// published, the next time it leads it resumes from the replicated checkpoint.
+    fn ship_entries(&mut self, sink: usize, ctx: &mut Context<Self>) {
+        let (net, storage) = match (&self.net, &self.storage) {
+            (Some(net), Some(storage)) => (net.clone(), storage.clone()),
+            _ => return,
+        };
+
+        if self.sinks[sink].shipping {
+            return;
         }
+        self.sinks[sink].shipping = true;
+
+        let name = self.sinks[sink].name.clone();
+        let batch = GetSinkBatch {
+            sink: name.clone(),
+            after: self.sinks[sink].published,
+            limit: self.sinks[sink].batch_entries,
+        };
+        let worker = self.sinks[sink].worker.clone();
+
+        fut::wrap_future::<_, Self>(net.send(GetRaftMetrics))
+            .map_err(|_, _, _| ())
+            .and_then(move |metrics, act, _| {
+                let leading = match metrics {
+                    Ok(Some(metrics)) => metrics.current_leader == Some(act.id),
+                    _ => false,
+                };
+                if !leading {
+                    act.sinks[sink].published = None;
+                    return fut::Either::A(fut::err(()));
+                }
+
+                fut::Either::B(
+                    fut::wrap_future(storage.send(batch))
+                        .map_err(|_, _, _| ())
+                        .and_then(|res, _, _| fut::result(res)),
+                )
+            })
+            .and_then(move |entries, _, _| {
+                let last = match entries.last() {
+                    Some(entry) => entry.index,
+                    None => return fut::Either::A(fut::ok(None)),
+                };
+
+                fut::Either::B(
+                    fut::wrap_future(worker.send(Publish(entries)))
+                        .map_err(|_, _, _| ())
+                        .and_then(move |res, act: &mut Self, _| match res {
+                            Ok(()) => fut::ok(Some(last)),
+                            Err(err) => {
+                                warn!("Node {} failed to publish to log sink {}: {}", act.id, act.sinks[sink].name, err);
+                                fut::err(())
+                            }
+                        }),
+                )
+            })
+            .and_then(move |published, act, ctx| {
+                let index = match published {
+                    Some(index) => index,
+                    None => return fut::Either::A(fut::ok(())),
+                };
+                act.sinks[sink].published = Some(index);
+
+                // a lost checkpoint is covered by the next one, or the entries are published again
+                let checkpoint = ClientProposal {
+                    client: "sink".to_owned(),
+                    key: None,
+                    data: MemoryStorageData::SinkCheckpoint { sink: name, index: index },
+                    deadline_ms: None,
+                };
+                fut::Either::B(
+                    fut::wrap_future(ctx.address().send(checkpoint))
+                        .map_err(|_, _, _| ())
+                        .map(move |res, act: &mut Self, _| {
+                            if res.is_err() {
+                                debug!("Node {} failed to checkpoint log sink {} at {}", act.id, act.sinks[sink].name, index);
+                            }
+                        }),
+                )
+            })
+            .then(move |_, act, _| {
+                act.sinks[sink].shipping = false;
+                fut::ok(())
+            })
+            .spawn(ctx);
+    }
 
+    /// On the leader, train a dictionary from the sampled proposals and propose it to the
+    /// cluster. Training runs on the blocking pool, a failed training or proposal is retried
+    /// once the samples are renewed.
+    fn train_dictionary(&mut self, ctx: &mut Context<Self>) {
+        let (net, sampler) = match (&self.net, &mut self.sampler) {
+            (Some(net), Some(sampler)) => (net.clone(), sampler),
+            _ => return,
+        };
+        let samples = sampler.take();
+        let dict_bytes = sampler.dict_bytes();
+
+        fut::wrap_future::<_, Self>(net.send(GetRaftMetrics))
+            .map_err(|_, _, _| ())
+            .and_then(move |metrics, act, _| {
+                let leading = match metrics {
+                    Ok(Some(metrics)) => metrics.current_leader == Some(act.id),
+                    _ => false,
+                };
+                if !leading {
+                    return fut::Either::A(fut::err(()));
+                }
+
+                fut::Either::B(
+                    fut::wrap_future(web::block(move || dictionary::train(&samples, dict_bytes)))
+                        .map_err(|err, act: &mut Self, _| warn!("Node {} failed to train a compression dictionary: {}", act.id, err)),
+                )
+            })
+            .and_then(|dict, act, ctx| {
+                info!("Node {} trained compression {:?}", act.id, dict);
+                let proposal = ClientProposal {
+                    client: "dictionary".to_owned(),
+                    key: None,
+                    data: MemoryStorageData::SetDictionary(dict),
+                    deadline_ms: None,
+                };
+                fut::wrap_future(ctx.address().send(proposal))
+                    .map_err(|_, _, _| ())
+                    .and_then(|res, act: &mut Self, _| {
+                        if res.is_err() {
+                            debug!("Node {} failed to propose its compression dictionary", act.id);
+                        }
+                        fut::result(res.map(|_| ()).map_err(|_| ()))
+                    })
+            })
+            .map_err(|_, act, _| {
+                if let Some(ref mut sampler) = act.sampler {
+                    sampler.retry();
+                }
+            })
+            .spawn(ctx);
     }
 
     fn register_handlers(&mut self, raft: Addr<MemRaft>, client: Addr<Self>) {
         let mut registry = self.registry.write().unwrap();
 
-        registry.register::<AppendEntriesRequest<MemoryStorageData>, _>(raft.clone());
+        // followers learn the commit index from the appends of the leader
+        registry.register::<AppendEntriesRequest<StampedData>, _>(client.clone());
         registry.register::<VoteRequest, _>(raft.clone());
-        registry.register::<InstallSnapshotRequest, _>(raft.clone());
+        // sealed snapshot chunks are verified before raft sees them
+        if self.config.snapshot_transfer.is_some() {
+            registry.register::<InstallSnapshotRequest, _>(client.clone());
+        } else {
+            registry.register::<InstallSnapshotRequest, _>(raft.clone());
+        }
         registry.register::<ChangeRaftClusterConfig, _>(client.clone());
-        registry.register::<ClientPayload<MemoryStorageData, MemoryStorageResponse, MemoryStorageError>, _>(raft.clone());
+        registry.register::<ProposeMembership, _>(client.clone());
+        registry.register::<ClientProposal, _>(client.clone());
+        registry.register::<GetClientSession, _>(client.clone());
+        registry.register::<ConfirmBackup, _>(client.clone());
+        registry.register::<GetBlobChunk, _>(client.clone());
+        if self.config.catch_up.is_some() {
+            registry.register::<RequestCatchUp, _>(client.clone());
+        }
+        registry.register::<ClientPayload<StampedData, MemoryStorageResponse, MemoryStorageError>, _>(raft.clone());
+        // followers taking a read barrier ask the leader's network for its read index
+        if let Some(ref net) = self.net {
+            registry.register::<ReadIndex, _>(net.clone());
+        }
     }
 }
 
@@ -66,7 +359,7 @@ pub struct InitRaft {
     pub nodes: Vec<NodeId>,
     pub net: Addr<Network>,
     pub server: Addr<Server>,
-    pub join_mode: bool,
+    pub formation: Formation,
 }
 
 #[derive(Message)]
@@ -79,6 +372,7 @@ impl Handler<ChangeRaftClusterConfig> for RaftClient {
     type Result = ();
 
     fn handle(&mut self, msg: ChangeRaftClusterConfig, ctx: &mut Context<Self>) {
+        crate::audit_message!(ChangeRaftClusterConfig);
         let nodes_to_add = msg.0.clone();
         let nodes_to_remove = msg.1.clone();
 
@@ -126,6 +420,55 @@ impl Handler<ChangeRaftClusterConfig> for RaftClient {
     }
 }
 
+/// Change the raft membership on the leader, answering once the change is committed. New
+/// members first catch up with the log as non-voters, raft only makes them voters once they're
+/// in sync. `ProposeMembership(to_add, to_remove)`
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ProposeMembership(pub Vec<NodeId>, pub Vec<NodeId>);
+
+impl Message for ProposeMembership {
+    type Result = Result<(), ProposeError>;
+}
+
+impl Handler<ProposeMembership> for RaftClient {
+    type Result = ResponseActFuture<Self, (), ProposeError>;
+
+    fn handle(&mut self, msg: ProposeMembership, _ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(ProposeMembership);
+        let raft = match self.raft {
+            Some(ref raft) => raft.clone(),
+            None => return Box::new(fut::err(ProposeError::Failed)),
+        };
+        let ProposeMembership(to_add, to_remove) = msg;
+
+        Box::new(
+            fut::wrap_future::<_, Self>(raft.send(ProposeConfigChange::new(to_add.clone(), to_remove.clone())))
+                .map_err(|_, _, _| ProposeError::Failed)
+                .and_then(move |res, _, ctx| {
+                    let res = match res {
+                        Ok(_) | Err(ProposeConfigChangeError::Noop) => Ok(()),
+                        Err(ProposeConfigChangeError::NodeNotLeader(leader)) => {
+                            Err(ProposeError::NotLeader { leader_hint: leader })
+                        }
+                        Err(ProposeConfigChangeError::InoperableConfig) => Err(ProposeError::Rejected),
+                        Err(_) => Err(ProposeError::Failed),
+                    };
+
+                    // keep the hash ring in line with the raft membership
+                    if res.is_ok() {
+                        for id in to_add {
+                            ctx.notify(AddNode(id));
+                        }
+                        for id in to_remove {
+                            ctx.notify(ClientRequest(remove_node(id)));
+                        }
+                    }
+                    fut::result(res)
+                }),
+        )
+    }
+}
+
 #[derive(Message)]
 pub struct RemoveNode(pub NodeId);
 
@@ -133,6 +476,7 @@ impl Handler<AddNode> for RaftClient {
     type Result = ();
 
     fn handle(&mut self, msg: AddNode, ctx: &mut Context<Self>) {
+        crate::audit_message!(AddNode);
         let payload = add_node(msg.0);
         ctx.notify(ClientRequest(payload));
     }
@@ -142,6 +486,7 @@ impl Handler<RemoveNode> for RaftClient {
     type Result = ();
 
     fn handle(&mut self, msg: RemoveNode, ctx: &mut Context<Self>) {
+        crate::audit_message!(RemoveNode);
         let payload = remove_node(msg.0);
         ctx.notify(ClientRequest(payload));
         ctx.notify(ChangeRaftClusterConfig(vec![], vec![msg.0]));
@@ -152,26 +497,64 @@ impl Handler<InitRaft> for RaftClient {
     type Result = ();
 
     fn handle(&mut self, msg: InitRaft, ctx: &mut Context<Self>) {
+        crate::audit_message!(InitRaft);
         let nodes = msg.nodes;
         self.net = Some(msg.net);
         let server = msg.server;
 
-        let nodes = if msg.join_mode {
+        if !self.config.role.is_member() {
+            println!("Node {} is a gateway, forwarding proposals to the cluster", self.id);
+            return ();
+        }
+
+        let nodes = if msg.formation == Formation::Join {
             vec![self.id]
         } else {
             nodes.clone()
         };
 
-        let raft =
-            RaftBuilder::new(self.id, nodes.clone(), self.net.as_ref().unwrap().clone(), self.ring.clone(), server);
+        let started =
+            RaftBuilder::new(self.id, nodes.clone(), self.net.as_ref().unwrap().clone(), self.ring.clone(), server, ctx.address().recipient(), self.hooks.clone(), self.clock.clone(), self.frozen.clone(), self.epoch.clone(), self.events.clone(), self.memory.clone(), self.crash.clone(), self.replay.clone(), &self.config);
+        let (raft, storage) = match started {
+            Ok(started) => started,
+            Err(err) => {
+                // a node missing the log it acknowledged must not take part in raft
+                error!("CRITICAL: node {} can't recover its raft log: {}", self.id, err);
+                self.events.alert(format!("raft log can't be recovered: {}", err));
+                System::current().stop_with_code(ExitReason::StorageFailed.code());
+                return ();
+            }
+        };
         self.register_handlers(raft.clone(), ctx.address().clone());
         self.raft = Some(raft);
+        self.storage = Some(storage);
 
-        if msg.join_mode {
-            return ();
+        if let Some(ref trigger) = self.config.snapshot_trigger {
+            ctx.run_interval(Duration::from_secs(trigger.check_secs), |act, ctx| act.check_follower_lag(ctx));
+        }
+
+        self.sinks = self.config.log_sinks.iter().map(LogSink::start).collect();
+        for (sink, config) in self.config.log_sinks.iter().enumerate() {
+            ctx.run_interval(Duration::from_millis(config.interval_ms), move |act, ctx| act.ship_entries(sink, ctx));
+        }
+
+        match msg.formation {
+            Formation::Join => return (),
+            Formation::Restart => {
+                info!("Node {} resuming raft from its recovered log", self.id);
+                return ();
+            }
+            Formation::Bootstrap => (),
         }
 
-        fut::wrap_future::<_, Self>(Delay::new(Instant::now() + Duration::from_secs(5)))
+        // a single node has no peer to wait for and elects itself as soon as raft is initialized
+        let settle = if self.config.single_node {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs(5)
+        };
+
+        fut::wrap_future::<_, Self>(clock::delay(settle))
             .map_err(|_, _, _| ())
             .and_then(move |_, act, ctx| {
                 fut::wrap_future::<_, Self>(
@@ -183,14 +566,15 @@ impl Handler<InitRaft> for RaftClient {
                     .map_err(|err, _, _| panic!(err))
                     .and_then(|_, _, _| {
                         println!("Inited with config!");
-                        fut::wrap_future::<_, Self>(Delay::new(
-                            Instant::now() + Duration::from_secs(5),
-                        ))
+                        fut::wrap_future::<_, Self>(clock::delay(settle))
                     })
                     .map_err(|_, _, _| ())
                     .and_then(|_, act, ctx| {
-                        let payload = add_node(act.id);
-                        ctx.notify(ClientRequest(payload));
+                        // data members don't own client sessions
+                        if act.config.role.serves_clients() {
+                            let payload = add_node(act.id);
+                            ctx.notify(ClientRequest(payload));
+                        }
                         fut::ok(())
                     })
             })
@@ -198,6 +582,8 @@ impl Handler<InitRaft> for RaftClient {
     }
 }
 
+/// Proposal of the hash ring made by the node itself, proposed through the network within
+/// `RING_PROPOSAL_BUDGET`.
 pub struct ClientRequest(pub MemoryStorageData);
 
 impl Message for ClientRequest {
@@ -208,79 +594,1464 @@ impl Handler<ClientRequest> for RaftClient {
     type Result = ();
 
     fn handle(&mut self, msg: ClientRequest, ctx: &mut Context<Self>) {
-        let entry = EntryNormal {
-            data: msg.0.clone(),
+        crate::audit_message!(ClientRequest);
+        let request = crate::network::ClientRequest {
+            client: node_client(self.id),
+            data: msg.0,
+            budget: Some(RetryBudget::within(RING_PROPOSAL_BUDGET)),
         };
 
-        let payload = Payload::new(entry, ResponseMode::Applied);
-
         ctx.spawn(
-            fut::wrap_future::<_, Self>(self.net.as_ref().unwrap().send(GetCurrentLeader))
-                .map_err(|err, _, _| panic!(err))
-                .and_then(move |res, act, ctx| {
-                    let leader = res.unwrap();
-
-                    if leader == act.id {
-                        if let Some(ref raft) = act.raft {
-                            return fut::Either::A(
-                                fut::wrap_future::<_, Self>(raft.send(payload))
-                                    .map_err(|err, _, _| panic!(err))
-                                    .and_then(|res, act, ctx| {
-                                        fut::ok(handle_client_response(res, ctx, msg))
-                                    }),
-                            );
-                        }
+            fut::wrap_future::<_, Self>(self.net.as_ref().unwrap().send(request))
+                .map_err(|err, _, _| error!("Error proposing a hash ring change: {:?}", err))
+                .map(|res, act, _| match res {
+                    Ok(_) => (),
+                    Err(RaftorError::Exhausted(report)) => {
+                        warn!("Hash ring change not committed after {:?}", report);
+                        act.events.alert(format!(
+                            "hash ring change not committed after {} attempts in {}ms",
+                            report.attempts.len(),
+                            report.elapsed_ms
+                        ));
                     }
-
-                    fut::Either::B(
-                        fut::wrap_future::<_, Self>(act.net.as_ref().unwrap().send(GetNodeById(leader)))
-                            .map_err(move |err, _, _| panic!("Node {} not found", leader))
-                            .and_then(move |node, act, ctx| {
-                                println!("About to do something with node {}", leader);
-                                fut::wrap_future::<_, Self>(
-                                    node.unwrap().send(SendRemoteMessage(payload)),
-                                )
-                                    .map_err(|err, _, _| println!("Error {:?}", err))
-                                    .and_then(|res, act, ctx| {
-                                        fut::ok(handle_client_response(res, ctx, msg))
-                                    })
-                            }),
-                    )
+                    Err(err) => error!("Hash ring change failed: {:?}", err),
                 }),
         );
     }
 }
 
-fn add_node(id: NodeId) -> MemoryStorageData {
-    MemoryStorageData::Add(id)
+/// Client the hash ring proposals of a node are admitted as on the leader, queued apart from
+/// the application clients.
+fn node_client(id: NodeId) -> String {
+    format!("raftor-node-{}", id)
 }
 
-fn remove_node(id: NodeId) -> MemoryStorageData {
-    MemoryStorageData::Remove(id)
+/// A client proposal tagged with the client it originates from, used for admission control on
+/// the leader.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ClientProposal {
+    pub client: String,
+    /// Idempotency key, set when the proposal may be retried against another leader
+    #[serde(default)]
+    pub key: Option<String>,
+    pub data: MemoryStorageData,
+    /// Milliseconds left until the client stops waiting, set by every hop forwarding the
+    /// proposal. The leader drops it instead of replicating it once they ran out.
+    #[serde(default)]
+    pub deadline_ms: Option<u64>,
 }
 
-fn handle_client_response(
-    res: ClientResponseHandler,
-    ctx: &mut Context<RaftClient>,
-    msg: ClientRequest,
-) {
-    match res {
-        Ok(_) => (),
-        Err(err) => match err {
-            ClientError::Internal => {
-                println!("TEST: resending client request.");
-                ctx.notify(msg);
+impl ClientProposal {
+    /// When the client stops waiting, counted from the arrival of the proposal.
+    fn deadline(&self) -> Option<Instant> {
+        self.deadline_ms.map(|ms| clock::now() + Duration::from_millis(ms))
+    }
+}
+
+/// Milliseconds left until the deadline, zero once it passed.
+fn remaining_ms(deadline: Option<Instant>) -> Option<u64> {
+    deadline.map(|deadline| {
+        let now = clock::now();
+        if deadline > now { (deadline - now).as_millis() as u64 } else { 0 }
+    })
+}
+
+impl Message for ClientProposal {
+    type Result = ClientResponseHandler;
+}
+
+/// An accepted proposal, its id in `PendingProposals`, payload, response channel and deadline.
+struct QueuedProposal(u64, Payload, oneshot::Sender<ClientResponseHandler>, Option<Instant>);
+
+impl Handler<ClientProposal> for RaftClient {
+    type Result = ResponseActFuture<
+        Self,
+        ClientPayloadResponse<MemoryStorageResponse>,
+        ClientError<StampedData, MemoryStorageResponse, MemoryStorageError>,
+    >;
+
+    fn handle(&mut self, msg: ClientProposal, ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(ClientProposal);
+        if let Err(err) = self.hooks.read().unwrap().validate(msg.client.as_str(), &msg.data) {
+            return Box::new(fut::err(ClientError::Application(err)));
+        }
+
+        if !self.config.role.is_member() {
+            return self.forward(msg);
+        }
+
+        if msg.deadline_ms == Some(0) {
+            debug!("Dropping proposal from {}, its deadline passed on the way", msg.client);
+            return Box::new(fut::err(ClientError::Application(MemoryStorageError::expired())));
+        }
+        let deadline = msg.deadline();
+
+        if let Some(ref fault) = self.fault {
+            return Box::new(fut::err(ClientError::Application(MemoryStorageError::faulted(fault.clone(), self.failed_append))));
+        }
+
+        // unfreezing is the only write a frozen cluster takes
+        if self.frozen.load(Ordering::SeqCst) && msg.data != MemoryStorageData::Freeze(false) {
+            debug!("Rejecting proposal from {}, cluster writes are frozen", msg.client);
+            return Box::new(fut::err(ClientError::Application(MemoryStorageError::default())));
+        }
+
+        let data = match self.hooks.read().unwrap().pre_propose(msg.client.as_str(), msg.data) {
+            Ok(data) => data,
+            Err(err) => return Box::new(fut::err(ClientError::Application(err))),
+        };
+        let kind = data.kind();
+
+        let data = StampedData {
+            hlc: self.clock.lock().unwrap().now(),
+            data: data,
+            key: msg.key,
+        };
+        let encoded = serde_json::to_vec(&data).unwrap_or_default();
+        let bytes = encoded.len();
+        let payload = Payload::new(EntryNormal { data: data }, ResponseMode::Applied);
+
+        // an isolated leader can't commit, let the client find the next one
+        if self.isolated {
+            return Box::new(fut::err(ClientError::ForwardToLeader {
+                payload: payload,
+                leader: None,
+            }));
+        }
+
+        // shed proposals while the pending ones hold too much memory
+        if self.memory.exceeds(MemoryArea::PendingProposals, bytes) {
+            debug!("Rejecting proposal from {}, pending proposals are over their memory limit", msg.client);
+            self.memory.shed(MemoryArea::PendingProposals);
+            return Box::new(fut::err(ClientError::Application(MemoryStorageError::default())));
+        }
+
+        if !self.admission.has_room(msg.client.as_str()) {
+            debug!("Rejecting proposal from {}, too many proposals are waiting for admission", msg.client);
+            return Box::new(fut::err(ClientError::Application(MemoryStorageError::default())));
+        }
+
+        let id = self.proposals.accept(kind, bytes);
+        self.memory.grow(MemoryArea::PendingProposals, bytes);
+
+        // dictionaries aren't trained from the dictionaries themselves
+        if kind != "set_dictionary" && self.sampler.as_mut().map_or(false, |sampler| sampler.observe(&encoded)) {
+            self.train_dictionary(ctx);
+        }
+        let (tx, rx) = oneshot::channel();
+
+        if let Some(proposal) = self.admission.admit(msg.client, QueuedProposal(id, payload, tx, deadline)) {
+            self.dispatch(proposal, ctx);
+        } else {
+            let stats = self.proposals.stats(&self.admission);
+            debug!(
+                "Client proposal queued, {} pending and {} queued, {} bytes, oldest {}ms",
+                stats.in_flight, stats.queued, stats.bytes, stats.oldest_age_ms
+            );
+        }
+
+        Box::new(
+            fut::wrap_future(rx)
+                .map_err(|_, _, _| ClientError::Internal)
+                .and_then(|res, _, _| fut::result(res)),
+        )
+    }
+}
+
+impl RaftClient {
+    /// Forward a proposal from a gateway to a cluster member, following its leader hint once.
+    fn forward(
+        &mut self,
+        proposal: ClientProposal,
+    ) -> ResponseActFuture<
+        Self,
+        ClientPayloadResponse<MemoryStorageResponse>,
+        ClientError<StampedData, MemoryStorageResponse, MemoryStorageError>,
+    > {
+        let member = self
+            .config
+            .nodes
+            .iter()
+            .map(utils::node_id)
+            .find(|id| *id != self.id);
+        let deadline = proposal.deadline();
+
+        match member {
+            Some(member) => Box::new(self.forward_to(member, proposal.clone(), deadline).then(
+                move |res, act, _| match res {
+                    Err(ClientError::ForwardToLeader { leader: Some(leader), .. }) if leader != member => {
+                        fut::Either::A(act.forward_to(leader, proposal, deadline))
+                    }
+                    res => fut::Either::B(fut::result(res)),
+                },
+            )),
+            None => Box::new(fut::err(ClientError::Internal)),
+        }
+    }
+
+    fn forward_to(
+        &self,
+        target: NodeId,
+        mut proposal: ClientProposal,
+        deadline: Option<Instant>,
+    ) -> Box<
+        dyn ActorFuture<
+            Actor = Self,
+            Item = ClientPayloadResponse<MemoryStorageResponse>,
+            Error = ClientError<StampedData, MemoryStorageResponse, MemoryStorageError>,
+        >,
+    > {
+        let net = match self.net {
+            Some(ref net) => net.clone(),
+            None => return Box::new(fut::err(ClientError::Internal)),
+        };
+        proposal.deadline_ms = remaining_ms(deadline);
+
+        Box::new(
+            fut::wrap_future::<_, Self>(net.send(GetNodeById(target)))
+                .map_err(|_, _, _| ClientError::Internal)
+                .and_then(|node, _, _| fut::result(node.map_err(|_| ClientError::Internal)))
+                .and_then(move |node, _, _| {
+                    fut::wrap_future::<_, Self>(node.send(SendRemoteMessage(proposal)))
+                        .map_err(|_, _, _| ClientError::Internal)
+                        .and_then(|res, _, _| fut::result(res))
+                }),
+        )
+    }
+
+    /// Hand an admitted proposal to raft, releasing its slot once it completes.
+    fn dispatch(&mut self, proposal: QueuedProposal, ctx: &mut Context<Self>) {
+        let QueuedProposal(id, payload, tx, deadline) = proposal;
+
+        // the client stopped waiting while the proposal was queued, don't replicate it
+        if deadline.map_or(false, |deadline| clock::now() >= deadline) {
+            debug!("Dropping queued proposal {}, its deadline passed", id);
+            let _ = tx.send(Err(ClientError::Application(MemoryStorageError::expired())));
+            if let Some((kind, bytes, latency)) = self.proposals.expire(id) {
+                self.memory.shrink(MemoryArea::PendingProposals, bytes);
+                self.writes.record(kind, bytes, latency, false);
             }
-            ClientError::Application(err) => {
-                println!(
-                    "Unexpected application error from client request: {:?}",
-                    err
-                );
+            return self.release(ctx);
+        }
+
+        let raft = match self.raft {
+            Some(ref raft) => raft.clone(),
+            None => {
+                let _ = tx.send(Err(ClientError::Internal));
+                self.finish(id, false);
+                return self.release(ctx);
+            }
+        };
+
+        let dispatched = clock::now();
+
+        fut::wrap_future::<_, Self>(raft.send(payload))
+            .then(|res, act, _| match res.unwrap_or(Err(ClientError::Internal)) {
+                // raft fails proposals whose append hit a storage fault without saying why
+                Err(ClientError::Internal) => fut::Either::A(act.storage_error().then(|err, _, _| {
+                    fut::ok::<_, (), Self>(match err {
+                        Ok(Some(err)) => Err(ClientError::Application(err)),
+                        _ => Err(ClientError::Internal),
+                    })
+                })),
+                res => fut::Either::B(fut::ok(res)),
+            })
+            .then(move |res, act, ctx| {
+                let res = res.unwrap_or(Err(ClientError::Internal));
+                if res.is_ok() {
+                    act.adapt(clock::elapsed(dispatched));
+                }
+                act.finish(id, res.is_ok());
+                let _ = tx.send(res);
+                act.release(ctx);
+                fut::ok(())
+            })
+            .spawn(ctx);
+    }
+
+    /// The storage fault and the index whose append hit it, asked from the storage when the
+    /// fault wasn't reported to this actor yet.
+    fn storage_error(&self) -> Box<dyn ActorFuture<Actor = Self, Item = Option<MemoryStorageError>, Error = ()>> {
+        if let Some(ref fault) = self.fault {
+            return Box::new(fut::ok(Some(MemoryStorageError::faulted(fault.clone(), self.failed_append))));
+        }
+
+        match self.storage {
+            Some(ref storage) => Box::new(fut::wrap_future(storage.send(GetStorageFault)).map_err(|_, _, _| ())),
+            None => Box::new(fut::ok(None)),
+        }
+    }
+
+    fn finish(&mut self, id: u64, applied: bool) {
+        if let Some((kind, bytes, latency)) = self.proposals.finish(id) {
+            self.memory.shrink(MemoryArea::PendingProposals, bytes);
+            self.writes.record(kind, bytes, latency, applied);
+        }
+    }
+
+    fn release(&mut self, ctx: &mut Context<Self>) {
+        if let Some(next) = self.admission.release() {
+            self.dispatch(next, ctx);
+        }
+        // a grown window lets more queued proposals in
+        while let Some(next) = self.admission.next() {
+            self.dispatch(next, ctx);
+        }
+    }
+
+    /// Resize the proposal window to the commit latency, queueing time excluded.
+    fn adapt(&mut self, latency: Duration) {
+        if let Some(ref mut window) = self.window {
+            let size = window.observe(latency);
+            self.admission.set_max_pending(size);
+        }
+    }
+}
+
+impl Handler<StorageFaulted> for RaftClient {
+    type Result = ();
+
+    fn handle(&mut self, msg: StorageFaulted, _ctx: &mut Context<Self>) {
+        crate::audit_message!(StorageFaulted);
+        error!("CRITICAL: node {} rejects proposals after storage fault {:?}", self.id, msg.0);
+        self.events.alert(format!("storage fault {:?}, rejecting proposals", msg.0));
+        self.fault = Some(msg.0);
+        self.failed_append = msg.1;
+
+        if self.config.storage_fault_policy == StorageFaultPolicy::StepDown {
+            if let Some(ref net) = self.net {
+                net.do_send(StepDown);
             }
-            ClientError::ForwardToLeader { .. } => {
-                println!("TEST: received ForwardToLeader error. Updating leader and forwarding.");
-                ctx.notify(msg);
+        }
+    }
+}
+
+/// Number, age and size of the client proposals this node accepted and hasn't applied yet.
+pub struct GetPendingProposals;
+
+impl Message for GetPendingProposals {
+    type Result = Result<PendingProposalStats, ()>;
+}
+
+impl Handler<GetPendingProposals> for RaftClient {
+    type Result = Result<PendingProposalStats, ()>;
+
+    fn handle(&mut self, _: GetPendingProposals, _ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(GetPendingProposals);
+        Ok(self.proposals.stats(&self.admission))
+    }
+}
+
+/// Proposal counts, sizes and latencies of this node by command kind.
+pub struct GetWriteMetrics;
+
+impl Message for GetWriteMetrics {
+    type Result = Result<BTreeMap<String, CommandStats>, ()>;
+}
+
+impl Handler<GetWriteMetrics> for RaftClient {
+    type Result = Result<BTreeMap<String, CommandStats>, ()>;
+
+    fn handle(&mut self, _: GetWriteMetrics, _ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(GetWriteMetrics);
+        Ok(self.writes.snapshot())
+    }
+}
+
+impl Handler<InstallSnapshotRequest> for RaftClient {
+    type Result = ResponseActFuture<Self, InstallSnapshotResponse, ()>;
+
+    fn handle(&mut self, msg: InstallSnapshotRequest, _ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(InstallSnapshotRequest);
+        let (raft, sealer) = match (self.raft.clone(), self.config.snapshot_transfer.as_ref()) {
+            (Some(raft), Some(config)) => (raft, SnapshotSealer::new(config)),
+            _ => return Box::new(fut::err(())),
+        };
+
+        match sealer.open(msg) {
+            Ok(msg) => Box::new(
+                fut::wrap_future(raft.send(msg))
+                    .map_err(|_, _, _| ())
+                    .and_then(|res, _, _| fut::result(res)),
+            ),
+            Err(err) => {
+                error!("CRITICAL: rejecting snapshot chunk, {}", err);
+                Box::new(fut::err(()))
             }
-        },
+        }
+    }
+}
+
+impl Handler<AppendEntriesRequest<StampedData>> for RaftClient {
+    type Result = ResponseActFuture<Self, AppendEntriesResponse, ()>;
+
+    fn handle(&mut self, msg: AppendEntriesRequest<StampedData>, _ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(AppendEntriesRequest<StampedData>);
+        let raft = match self.raft.clone() {
+            Some(raft) => raft,
+            None => return Box::new(fut::err(())),
+        };
+        // only what this follower holds once the append succeeded is committed on it
+        let held = msg.prev_log_index + msg.entries.len() as u64;
+        let commit = msg.leader_commit.min(held);
+
+        Box::new(
+            fut::wrap_future(raft.send(msg))
+                .map_err(|_, _, _| ())
+                .and_then(move |res, act: &mut Self, _| {
+                    if let Ok(ref res) = res {
+                        if res.success && commit > act.leader_commit {
+                            act.leader_commit = commit;
+                            if let Some(ref net) = act.net {
+                                net.do_send(LeaderCommit(commit));
+                            }
+                        }
+                    }
+                    fut::result(res)
+                }),
+        )
+    }
+}
+
+/// Sent by the network when this leader lost its quorum (`true`) and once it stepped down
+/// (`false`), proposals are refused in between.
+#[derive(Message)]
+pub struct LeaderIsolated(pub bool);
+
+impl Handler<LeaderIsolated> for RaftClient {
+    type Result = ();
+
+    fn handle(&mut self, msg: LeaderIsolated, _ctx: &mut Context<Self>) {
+        crate::audit_message!(LeaderIsolated);
+        self.isolated = msg.0;
+    }
+}
+
+/// Stop raft along with this client, on shutdown: the arbiter running them is stopped.
+#[derive(Message)]
+pub struct StopRaft;
+
+impl Handler<StopRaft> for RaftClient {
+    type Result = ();
+
+    fn handle(&mut self, _: StopRaft, ctx: &mut Context<Self>) {
+        crate::audit_message!(StopRaft);
+        info!("Stopping raft on node {}", self.id);
+        self.raft = None;
+        self.storage = None;
+        ctx.stop();
+        Arbiter::current().stop();
+    }
+}
+
+/// Offset the hybrid logical clock of this node from its wall clock, in milliseconds.
+#[derive(Message)]
+pub struct SkewClock(pub i64);
+
+impl Handler<SkewClock> for RaftClient {
+    type Result = ();
+
+    fn handle(&mut self, msg: SkewClock, _ctx: &mut Context<Self>) {
+        crate::audit_message!(SkewClock);
+        warn!("Skewing the clock of node {} by {}ms", self.id, msg.0);
+        self.clock.lock().unwrap().skew(msg.0);
+    }
+}
+
+/// Export the applied state of this node to a portable dump file.
+pub struct ExportState(pub String);
+
+impl Message for ExportState {
+    type Result = Result<StateDumpInfo, ExportError>;
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum ExportError {
+    /// The file name is absolute or leaves the dump dir
+    InvalidPath,
+    Failed,
+}
+
+impl Handler<ExportState> for RaftClient {
+    type Result = Response<StateDumpInfo, ExportError>;
+
+    fn handle(&mut self, msg: ExportState, _ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(ExportState);
+        let path = match utils::contained_path(&self.config.dump_dir, &msg.0) {
+            Some(path) => path,
+            None => return Response::reply(Err(ExportError::InvalidPath)),
+        };
+        if let Some(parent) = path.parent() {
+            if let Err(err) = std::fs::create_dir_all(parent) {
+                error!("Error creating dump dir {:?}. {}", parent, err);
+                return Response::reply(Err(ExportError::Failed));
+            }
+        }
+
+        if let Some(ref storage) = self.storage {
+            Response::fut(
+                storage
+                    .send(ExportStateDump(path))
+                    .map_err(|_| ExportError::Failed)
+                    .and_then(|res| res.map_err(|_| ExportError::Failed)),
+            )
+        } else {
+            Response::reply(Err(ExportError::Failed))
+        }
+    }
+}
+
+/// Take a cluster-consistent backup through the leader: every member dumps its state at the
+/// index of a backup marker and the backup completes once they all confirmed identical
+/// dumps, see `backup`.
+pub struct CreateBackup;
+
+impl Message for CreateBackup {
+    type Result = Result<BackupManifest, BackupError>;
+}
+
+impl Handler<CreateBackup> for RaftClient {
+    type Result = ResponseActFuture<Self, BackupManifest, BackupError>;
+
+    fn handle(&mut self, _: CreateBackup, _ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(CreateBackup);
+        let (net, storage) = match (&self.net, &self.storage) {
+            (Some(net), Some(storage)) => (net.clone(), storage.clone()),
+            _ => return Box::new(fut::err(BackupError::NotLeader { leader_hint: None })),
+        };
+        let id = format!("backup-{}", crate::raft::timing::now_millis());
+        let proposal = ClientProposal {
+            client: "backup".to_owned(),
+            key: Some(id.clone()),
+            data: MemoryStorageData::Backup { id: id.clone() },
+            deadline_ms: None,
+        };
+
+        Box::new(
+            fut::wrap_future::<_, Self>(net.send(GetRaftMetrics))
+                .map_err(|_, _, _| BackupError::NotLeader { leader_hint: None })
+                .and_then(move |metrics, act, ctx| {
+                    let metrics = match metrics {
+                        Ok(Some(metrics)) => metrics,
+                        _ => return fut::Either::A(fut::err(BackupError::NotLeader { leader_hint: None })),
+                    };
+                    if metrics.current_leader != Some(act.id) {
+                        return fut::Either::A(fut::err(BackupError::NotLeader { leader_hint: metrics.current_leader }));
+                    }
+
+                    let members = metrics.membership_config.members.clone();
+                    fut::Either::B(
+                        fut::wrap_future(ctx.address().send(proposal))
+                            .map_err(|_, _, _| BackupError::Propose(ProposeError::Failed))
+                            .and_then(|res, _, _| fut::result(proposed_index(res)))
+                            .map(move |_, _, _| members),
+                    )
+                })
+                .and_then(move |members, act: &mut Self, _| {
+                    let confirmations = members
+                        .into_iter()
+                        .map(|member| act.confirm_backup(member, id.clone()).then(move |res| Ok((member, res.ok()))))
+                        .collect::<Vec<_>>();
+                    fut::wrap_future(futures::future::join_all(confirmations)).map(move |parts, _, _| (id, parts))
+                })
+                .and_then(move |(id, parts): (String, Vec<(NodeId, Option<BackupPart>)>), act, _| {
+                    let own = match parts.iter().find(|(member, _)| *member == act.id) {
+                        Some((_, Some(part))) => part.clone(),
+                        _ => return fut::Either::A(fut::err(BackupError::Unconfirmed(vec![act.id]))),
+                    };
+
+                    let unconfirmed = parts.iter().filter(|(_, part)| part.is_none()).map(|(member, _)| *member).collect::<Vec<_>>();
+                    if !unconfirmed.is_empty() {
+                        return fut::Either::A(fut::err(BackupError::Unconfirmed(unconfirmed)));
+                    }
+                    let diverged = parts
+                        .iter()
+                        .filter(|(_, part)| part.as_ref() != Some(&own))
+                        .map(|(member, _)| *member)
+                        .collect::<Vec<_>>();
+                    if !diverged.is_empty() {
+                        error!("CRITICAL: backup {} at index {} diverged on nodes {:?}", id, own.index, diverged);
+                        return fut::Either::A(fut::err(BackupError::Diverged(diverged)));
+                    }
+
+                    let manifest = BackupManifest {
+                        id: id,
+                        index: own.index,
+                        term: own.term,
+                        entries: own.entries,
+                        checksum: own.checksum,
+                        members: parts.into_iter().map(|(member, _)| member).collect(),
+                        path: String::new(),
+                    };
+                    fut::Either::B(
+                        fut::wrap_future(storage.send(SealBackup(manifest)))
+                            .map_err(|err, _, _| BackupError::Io(err.to_string()))
+                            .and_then(|res, _, _| fut::result(res)),
+                    )
+                }),
+        )
+    }
+}
+
+impl RaftClient {
+    /// The dump a member took for a backup, through its peer connection unless it's this node.
+    fn confirm_backup(&self, member: NodeId, id: String) -> Box<dyn Future<Item = BackupPart, Error = ()>> {
+        if member == self.id {
+            return match self.storage {
+                Some(ref storage) => await_backup(storage.clone(), id, BACKUP_CONFIRM_ATTEMPTS),
+                None => Box::new(futures::future::err(())),
+            };
+        }
+
+        let net = match self.net {
+            Some(ref net) => net.clone(),
+            None => return Box::new(futures::future::err(())),
+        };
+        Box::new(
+            net.send(GetNodeById(member))
+                .map_err(|_| ())
+                .and_then(|node| node)
+                .and_then(move |node| node.send(SendRemoteMessage(ConfirmBackup(id))).map_err(|_| ()))
+                .and_then(|res| res),
+        )
+    }
+}
+
+/// Wait for the storage to have dumped a backup, polling it.
+fn await_backup(storage: Addr<MemoryStorage>, id: String, attempts: u32) -> Box<dyn Future<Item = BackupPart, Error = ()>> {
+    Box::new(storage.send(GetBackupPart(id.clone())).map_err(|_| ()).and_then(move |res| match res {
+        Ok(Some(part)) => Box::new(futures::future::ok(part)) as Box<dyn Future<Item = BackupPart, Error = ()>>,
+        Ok(None) if attempts > 1 => Box::new(
+            clock::delay(BACKUP_POLL_INTERVAL)
+                .map_err(|_| ())
+                .and_then(move |_| await_backup(storage, id, attempts - 1)),
+        ),
+        _ => Box::new(futures::future::err(())),
+    }))
+}
+
+/// The log index a proposal of this node was applied at.
+fn proposed_index(res: ClientResponseHandler) -> Result<u64, BackupError> {
+    match res {
+        Ok(ClientPayloadResponse::Applied { index, .. }) | Ok(ClientPayloadResponse::Committed { index }) => Ok(index),
+        Err(ClientError::ForwardToLeader { leader, .. }) => Err(BackupError::NotLeader { leader_hint: leader }),
+        Err(ClientError::Application(ref err)) if err.fault.is_some() => Err(BackupError::Propose(ProposeError::Storage(err.clone()))),
+        Err(ClientError::Application(_)) => Err(BackupError::Propose(ProposeError::Rejected)),
+        Err(_) => Err(BackupError::Propose(ProposeError::Failed)),
+    }
+}
+
+/// Sent by the leader taking a backup, answered with the dump of this node once it applied
+/// the backup marker.
+#[derive(Serialize, Deserialize)]
+pub struct ConfirmBackup(pub String);
+
+impl Message for ConfirmBackup {
+    type Result = Result<BackupPart, ()>;
+}
+
+impl Handler<ConfirmBackup> for RaftClient {
+    type Result = Response<BackupPart, ()>;
+
+    fn handle(&mut self, msg: ConfirmBackup, _ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(ConfirmBackup);
+        match self.storage {
+            Some(ref storage) => Response::fut(await_backup(storage.clone(), msg.0, BACKUP_CONFIRM_ATTEMPTS)),
+            None => Response::reply(Err(())),
+        }
+    }
+}
+
+impl Handler<GetBlobChunk> for RaftClient {
+    type Result = Response<BlobChunk, ()>;
+
+    fn handle(&mut self, msg: GetBlobChunk, _ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(GetBlobChunk);
+        match self.storage {
+            Some(ref storage) => Response::fut(storage.send(msg).map_err(|_| ()).and_then(|res| res)),
+            None => Response::reply(Err(())),
+        }
+    }
+}
+
+impl Handler<OpenBlob> for RaftClient {
+    type Result = Response<Spill, io::Error>;
+
+    fn handle(&mut self, msg: OpenBlob, _ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(OpenBlob);
+        match self.storage {
+            Some(ref storage) => Response::fut(
+                storage
+                    .send(msg)
+                    .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))
+                    .and_then(|res| res),
+            ),
+            None => Response::reply(Err(io::Error::new(io::ErrorKind::Other, "raft isn't started"))),
+        }
+    }
+}
+
+/// Restore a backup set copied under the backup dir of this node into a fresh cluster, through
+/// its leader. The dumped commands are proposed in batches of `RESTORE_BATCH_BYTES`, a cluster
+/// holding state besides its topology is refused.
+pub struct RestoreBackup(pub String);
+
+impl Message for RestoreBackup {
+    type Result = Result<RestoredBackup, BackupError>;
+}
+
+impl Handler<RestoreBackup> for RaftClient {
+    type Result = ResponseActFuture<Self, RestoredBackup, BackupError>;
+
+    fn handle(&mut self, msg: RestoreBackup, _ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(RestoreBackup);
+        let storage = match self.storage {
+            Some(ref storage) => storage.clone(),
+            None => return Box::new(fut::err(BackupError::NotLeader { leader_hint: None })),
+        };
+        let load = storage
+            .send(LocateBackup(msg.0))
+            .map_err(|err| BackupError::Io(err.to_string()))
+            .and_then(|res| res)
+            .and_then(|path| {
+                web::block(move || backup::load(&path)).map_err(|err| match err {
+                    BlockingError::Error(err) => err,
+                    BlockingError::Canceled => BackupError::Io("reading the backup set was canceled".to_owned()),
+                })
+            });
+
+        Box::new(
+            fut::wrap_future::<_, Self>(load)
+                .and_then(move |(manifest, dump), _, _| {
+                    fut::wrap_future(storage.send(GetStateDump))
+                        .map_err(|err, _, _| BackupError::Io(err.to_string()))
+                        .and_then(move |state, _, _| {
+                            let state = match state {
+                                Ok(state) => state,
+                                Err(()) => return fut::err(BackupError::Io("the state can't be read".to_owned())),
+                            };
+                            // only the topology of the new cluster was applied so far
+                            let fresh = state.entries.iter().all(|entry| entry.data.commands().into_iter().all(backup::is_local));
+                            if fresh {
+                                fut::ok((manifest, dump))
+                            } else {
+                                fut::err(BackupError::NotEmpty)
+                            }
+                        })
+                })
+                .and_then(|(manifest, dump): (BackupManifest, _), _, ctx: &mut Context<Self>| {
+                    // one entry per batch, each keyed so a retried one isn't applied twice
+                    let client = ctx.address();
+                    let checksum = manifest.checksum.clone();
+                    let batches = backup::batches(backup::restore_commands(&dump), backup::RESTORE_BATCH_BYTES);
+                    let proposals = futures::stream::iter_ok(batches.into_iter().enumerate()).fold(0, move |_, (n, batch)| {
+                        let proposal = ClientProposal {
+                            client: "backup".to_owned(),
+                            key: Some(format!("backup/{}/{}", checksum, n)),
+                            data: MemoryStorageData::Batch(batch),
+                            deadline_ms: None,
+                        };
+                        client
+                            .send(proposal)
+                            .map_err(|_| BackupError::Propose(ProposeError::Failed))
+                            .and_then(proposed_index)
+                    });
+
+                    fut::wrap_future(proposals)
+                        .map(move |index, act: &mut Self, _| {
+                            info!("Node {} restored backup {} taken at index {} at index {}", act.id, manifest.id, manifest.index, index);
+                            RestoredBackup {
+                                id: manifest.id,
+                                source_index: manifest.index,
+                                index: index,
+                            }
+                        })
+                }),
+        )
+    }
+}
+
+/// Progress of the projections of this node.
+pub struct ListProjections;
+
+impl Message for ListProjections {
+    type Result = Result<Vec<ProjectionStatus>, ()>;
+}
+
+impl Handler<ListProjections> for RaftClient {
+    type Result = Response<Vec<ProjectionStatus>, ()>;
+
+    fn handle(&mut self, _: ListProjections, _ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(ListProjections);
+        match self.storage {
+            Some(ref storage) => Response::fut(storage.send(GetProjections).map_err(|_| ()).and_then(|res| res)),
+            None => Response::reply(Err(())),
+        }
+    }
+}
+
+/// Reset a projection of this node and rebuild it from the applied state, answers the index
+/// it was rebuilt through.
+pub struct ResetProjection(pub String);
+
+impl Message for ResetProjection {
+    type Result = Result<u64, ()>;
+}
+
+impl Handler<ResetProjection> for RaftClient {
+    type Result = Response<u64, ()>;
+
+    fn handle(&mut self, msg: ResetProjection, _ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(ResetProjection);
+        match self.storage {
+            Some(ref storage) => Response::fut(storage.send(RebuildProjection(msg.0)).map_err(|_| ()).and_then(|res| res)),
+            None => Response::reply(Err(())),
+        }
+    }
+}
+
+/// Inspect the raft log of this node, commands are redacted by the redactor set with
+/// `Raftor::set_redactor`.
+pub struct InspectLog {
+    pub from: u64,
+    pub limit: usize,
+}
+
+impl Message for InspectLog {
+    type Result = Result<Vec<InspectedEntry>, ()>;
+}
+
+impl Handler<InspectLog> for RaftClient {
+    type Result = Response<Vec<InspectedEntry>, ()>;
+
+    fn handle(&mut self, msg: InspectLog, _ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(InspectLog);
+        if let Some(ref storage) = self.storage {
+            Response::fut(
+                storage
+                    .send(ReadLogEntries {
+                        from: msg.from,
+                        limit: msg.limit,
+                    })
+                    .map_err(|_| ())
+                    .and_then(|res| res),
+            )
+        } else {
+            Response::reply(Err(()))
+        }
+    }
+}
+
+/// Get per-entry commit timings and the commit latency histogram of this node.
+pub struct GetCommitTimings {
+    pub from: u64,
+    pub limit: usize,
+}
+
+impl Message for GetCommitTimings {
+    type Result = Result<EntryTimingReport, ()>;
+}
+
+impl Handler<GetCommitTimings> for RaftClient {
+    type Result = Response<EntryTimingReport, ()>;
+
+    fn handle(&mut self, msg: GetCommitTimings, _ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(GetCommitTimings);
+        if let Some(ref storage) = self.storage {
+            Response::fut(
+                storage
+                    .send(GetEntryTimings {
+                        from: msg.from,
+                        limit: msg.limit,
+                    })
+                    .map_err(|_| ())
+                    .and_then(|res| res),
+            )
+        } else {
+            Response::reply(Err(()))
+        }
+    }
+}
+
+fn add_node(id: NodeId) -> MemoryStorageData {
+    MemoryStorageData::Add(id)
+}
+
+fn remove_node(id: NodeId) -> MemoryStorageData {
+    MemoryStorageData::Remove(id)
+}
+
+impl RaftClient {
+    /// Oldest registration of a federated cluster which is still live.
+    fn registered_since(&self) -> u64 {
+        let ttl = self.config.federation_registry.record_ttl_secs * 1000;
+        crate::raft::timing::now_millis().saturating_sub(ttl)
+    }
+}
+
+/// Find the federated cluster owning a namespace, only answered by meta-cluster nodes.
+pub struct FindCluster(pub String);
+
+impl Message for FindCluster {
+    type Result = Result<Option<ClusterRecord>, ()>;
+}
+
+impl Handler<FindCluster> for RaftClient {
+    type Result = Response<Option<ClusterRecord>, ()>;
+
+    fn handle(&mut self, msg: FindCluster, _ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(FindCluster);
+        if let Some(ref storage) = self.storage {
+            Response::fut(storage.send(LookupNamespace(msg.0, self.registered_since())).map_err(|_| ()).and_then(|res| res))
+        } else {
+            Response::reply(Err(()))
+        }
+    }
+}
+
+/// Whether this node applied debris stamped before the given unix time in milliseconds, which a
+/// purge would drop.
+pub struct PurgeDue(pub u64);
+
+impl Message for PurgeDue {
+    type Result = Result<bool, ()>;
+}
+
+impl Handler<PurgeDue> for RaftClient {
+    type Result = Response<bool, ()>;
+
+    fn handle(&mut self, msg: PurgeDue, _ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(PurgeDue);
+        if let Some(ref storage) = self.storage {
+            Response::fut(storage.send(HasDebris(msg.0)).map_err(|_| ()).and_then(|res| res))
+        } else {
+            Response::reply(Err(()))
+        }
+    }
+}
+
+/// Whether a node is on the hash ring of this node, as of the entries it applied. Not a
+/// linearizable read on its own, wait for the node to apply a committed index first.
+pub struct IsRingMember(pub NodeId);
+
+impl Message for IsRingMember {
+    type Result = Result<bool, ()>;
+}
+
+impl Handler<IsRingMember> for RaftClient {
+    type Result = Response<bool, ()>;
+
+    fn handle(&mut self, msg: IsRingMember, _ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(IsRingMember);
+        if let Some(ref storage) = self.storage {
+            Response::fut(storage.send(LookupRingMember(msg.0)).map_err(|_| ()).and_then(|res| res))
+        } else {
+            Response::reply(Err(()))
+        }
+    }
+}
+
+/// Index of the last entry in the log of this node.
+pub struct LastLogIndex;
+
+impl Message for LastLogIndex {
+    type Result = Result<u64, ()>;
+}
+
+impl Handler<LastLogIndex> for RaftClient {
+    type Result = Response<u64, ()>;
+
+    fn handle(&mut self, _: LastLogIndex, _ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(LastLogIndex);
+        if let Some(ref storage) = self.storage {
+            Response::fut(storage.send(GetLastLogIndex).map_err(|_| ()).and_then(|res| res))
+        } else {
+            Response::reply(Err(()))
+        }
+    }
+}
+
+/// Query the application state machine of this node, build the query with `app::query` and
+/// decode the answer with `app::response`. Fails without a state machine.
+pub struct QueryApp(pub Vec<u8>);
+
+impl Message for QueryApp {
+    type Result = Result<Vec<u8>, ()>;
+}
+
+impl Handler<QueryApp> for RaftClient {
+    type Result = Response<Vec<u8>, ()>;
+
+    fn handle(&mut self, msg: QueryApp, _ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(QueryApp);
+        if let Some(ref storage) = self.storage {
+            Response::fut(storage.send(QueryAppState(msg.0)).map_err(|_| ()).and_then(|res| res))
+        } else {
+            Response::reply(Err(()))
+        }
+    }
+}
+
+/// A page of the answer to a query of the application state machine of this node, for
+/// answers too large for one message, see `AppStateMachine::query_page`. Start without a
+/// cursor and ask for the next page with the cursor of the previous one until it's `None`.
+pub struct QueryAppPage {
+    pub query: Vec<u8>,
+    pub cursor: Option<Vec<u8>>,
+    /// Most items of the page
+    pub limit: usize,
+}
+
+impl Message for QueryAppPage {
+    type Result = Result<AppPage, ()>;
+}
+
+impl Handler<QueryAppPage> for RaftClient {
+    type Result = Response<AppPage, ()>;
+
+    fn handle(&mut self, msg: QueryAppPage, _ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(QueryAppPage);
+        let page = QueryAppStatePage {
+            query: msg.query,
+            cursor: msg.cursor,
+            limit: msg.limit,
+        };
+        if let Some(ref storage) = self.storage {
+            Response::fut(storage.send(page).map_err(|_| ()).and_then(|res| res))
+        } else {
+            Response::reply(Err(()))
+        }
+    }
+}
+
+impl<R: Send + 'static> Handler<QueryLocalState<R>> for RaftClient {
+    type Result = Response<LocalRead<R>, ()>;
+
+    fn handle(&mut self, msg: QueryLocalState<R>, _ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(QueryLocalState<R>);
+        if let Some(ref storage) = self.storage {
+            Response::fut(storage.send(msg).map_err(|_| ()).and_then(|res| res))
+        } else {
+            Response::reply(Err(()))
+        }
+    }
+}
+
+/// List the federated clusters known to this meta-cluster node.
+pub struct ListClusters;
+
+impl Message for ListClusters {
+    type Result = Result<Vec<ClusterRecord>, ()>;
+}
+
+impl Handler<ListClusters> for RaftClient {
+    type Result = Response<Vec<ClusterRecord>, ()>;
+
+    fn handle(&mut self, _: ListClusters, _ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(ListClusters);
+        if let Some(ref storage) = self.storage {
+            Response::fut(storage.send(GetClusterRecords(self.registered_since())).map_err(|_| ()).and_then(|res| res))
+        } else {
+            Response::reply(Err(()))
+        }
+    }
+}
+
+/// Get the paths and sizes of this node's storage.
+pub struct GetStorageInfo;
+
+impl Message for GetStorageInfo {
+    type Result = Result<StorageStats, ()>;
+}
+
+impl Handler<GetStorageInfo> for RaftClient {
+    type Result = Response<StorageStats, ()>;
+
+    fn handle(&mut self, _: GetStorageInfo, _ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(GetStorageInfo);
+        if let Some(ref storage) = self.storage {
+            Response::fut(storage.send(GetStorageStats).map_err(|_| ()).and_then(|res| res))
+        } else {
+            Response::reply(Err(()))
+        }
+    }
+}
+
+/// Truncate this node's log through the given applied index once the configured archiver has
+/// copied the entries, answers with the index the log was truncated through.
+pub struct ArchiveLog(pub u64);
+
+impl Message for ArchiveLog {
+    type Result = Result<u64, ()>;
+}
+
+impl Handler<ArchiveLog> for RaftClient {
+    type Result = Response<u64, ()>;
+
+    fn handle(&mut self, msg: ArchiveLog, _ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(ArchiveLog);
+        if let Some(ref storage) = self.storage {
+            Response::fut(
+                storage
+                    .send(TruncateLog(msg.0))
+                    .map_err(|_| ())
+                    .and_then(|res| res.map_err(|_| ())),
+            )
+        } else {
+            Response::reply(Err(()))
+        }
+    }
+}
+
+/// Vacuum the persisted log of this node online, pausing the given number of milliseconds
+/// between steps. The progress is part of the storage info.
+pub struct VacuumStorage(pub u64);
+
+impl Message for VacuumStorage {
+    type Result = Result<(), String>;
+}
+
+impl Handler<VacuumStorage> for RaftClient {
+    type Result = Response<(), String>;
+
+    fn handle(&mut self, msg: VacuumStorage, _ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(VacuumStorage);
+        if let Some(ref storage) = self.storage {
+            Response::fut(
+                storage
+                    .send(StartVacuum(msg.0))
+                    .map_err(|err| err.to_string())
+                    .and_then(|res| res),
+            )
+        } else {
+            Response::reply(Err("raft isn't started on this node".to_owned()))
+        }
+    }
+}
+
+/// Put this follower into maintenance mode, or take it out of it. In maintenance the node keeps
+/// receiving and logging entries but stops applying them, so its state can be backed up or
+/// inspected without removing it from the cluster. Answers with the applied index.
+pub struct Maintenance(pub bool);
+
+impl Message for Maintenance {
+    type Result = Result<u64, ()>;
+}
+
+impl Handler<Maintenance> for RaftClient {
+    type Result = ResponseActFuture<Self, u64, ()>;
+
+    fn handle(&mut self, msg: Maintenance, _ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(Maintenance);
+        let (net, storage) = match (self.net.clone(), self.storage.clone()) {
+            (Some(net), Some(storage)) => (net, storage),
+            _ => return Box::new(fut::err(())),
+        };
+
+        Box::new(
+            fut::wrap_future::<_, Self>(net.send(GetCurrentLeader))
+                .map_err(|_, _, _| ())
+                .and_then(move |leader, act, _| {
+                    // the leader can't stop applying, clients wait on it
+                    if msg.0 && leader == Ok(act.id) {
+                        warn!("Node {} is the leader, refusing maintenance mode", act.id);
+                        return fut::Either::A(fut::err(()));
+                    }
+
+                    fut::Either::B(
+                        fut::wrap_future::<_, Self>(storage.send(SetMaintenance(msg.0)))
+                            .map_err(|_, _, _| ())
+                            .and_then(|res, _, _| fut::result(res.map_err(|_| ()))),
+                    )
+                }),
+        )
+    }
+}
+
+/// Take a lock through the log, answering with the fencing token of the acquisition. Fails
+/// when someone else holds the lock or this node isn't the leader.
+pub struct AcquireLock {
+    pub name: String,
+    pub holder: String,
+}
+
+impl Message for AcquireLock {
+    type Result = Result<FencingToken, ()>;
+}
+
+impl Handler<AcquireLock> for RaftClient {
+    type Result = ResponseActFuture<Self, FencingToken, ()>;
+
+    fn handle(&mut self, msg: AcquireLock, ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(AcquireLock);
+        let storage = match self.storage {
+            Some(ref storage) => storage.clone(),
+            None => return Box::new(fut::err(())),
+        };
+        let proposal = ClientProposal {
+            client: msg.holder.clone(),
+            key: None,
+            data: MemoryStorageData::AcquireLock {
+                name: msg.name.clone(),
+                holder: msg.holder.clone(),
+            },
+            deadline_ms: None,
+        };
+
+        Box::new(
+            fut::wrap_future::<_, Self>(ctx.address().send(proposal))
+                .map_err(|_, _, _| ())
+                .and_then(|res, _, _| match res {
+                    Ok(ClientPayloadResponse::Applied { index, .. }) | Ok(ClientPayloadResponse::Committed { index }) => {
+                        fut::ok(index)
+                    }
+                    Err(_) => fut::err(()),
+                })
+                .and_then(move |index, _, _| {
+                    fut::wrap_future::<_, Self>(storage.send(GetLock(msg.name.clone())))
+                        .map_err(|_, _, _| ())
+                        .and_then(move |lock, _, _| match lock {
+                            // the lock was granted by our entry, not held by someone else
+                            Ok(Some(ref lock)) if lock.holder == msg.holder && lock.token.index == index => {
+                                fut::ok(lock.token)
+                            }
+                            _ => fut::err(()),
+                        })
+                }),
+        )
+    }
+}
+
+/// Release a lock held by `holder` through the log.
+pub struct ReleaseLock {
+    pub name: String,
+    pub holder: String,
+}
+
+impl Message for ReleaseLock {
+    type Result = Result<(), ()>;
+}
+
+impl Handler<ReleaseLock> for RaftClient {
+    type Result = Response<(), ()>;
+
+    fn handle(&mut self, msg: ReleaseLock, ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(ReleaseLock);
+        let proposal = ClientProposal {
+            client: msg.holder.clone(),
+            key: None,
+            data: MemoryStorageData::ReleaseLock {
+                name: msg.name,
+                holder: msg.holder,
+            },
+            deadline_ms: None,
+        };
+
+        Response::fut(
+            ctx.address()
+                .send(proposal)
+                .map_err(|_| ())
+                .and_then(|res| res.map(|_| ()).map_err(|_| ())),
+        )
+    }
+}
+
+/// State of a client session, fresh as of when it was asked: members take a `ReadBarrier`
+/// before looking it up, other nodes ask a member.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct GetClientSession(pub String);
+
+impl Message for GetClientSession {
+    type Result = Result<Option<ClientSession>, ()>;
+}
+
+impl Handler<GetClientSession> for RaftClient {
+    type Result = ResponseActFuture<Self, Option<ClientSession>, ()>;
+
+    fn handle(&mut self, msg: GetClientSession, _ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(GetClientSession);
+        let net = match self.net {
+            Some(ref net) => net.clone(),
+            None => return Box::new(fut::err(())),
+        };
+
+        if !self.config.role.is_member() {
+            let member = self.config.nodes.iter().map(utils::node_id).find(|id| *id != self.id);
+            let member = match member {
+                Some(member) => member,
+                None => return Box::new(fut::err(())),
+            };
+
+            return Box::new(fut::wrap_future(
+                net.send(GetNodeById(member))
+                    .map_err(|_| ())
+                    .and_then(|node| node)
+                    .and_then(move |node| node.send(SendRemoteMessage(msg)).map_err(|_| ()))
+                    .and_then(|res| res),
+            ));
+        }
+
+        let storage = match self.storage {
+            Some(ref storage) => storage.clone(),
+            None => return Box::new(fut::err(())),
+        };
+
+        // the session may have written through another node since this one last applied
+        Box::new(fut::wrap_future(
+            net.send(ReadBarrier)
+                .map_err(|_| ())
+                .and_then(|res| res.map_err(|_| ()))
+                .and_then(move |_| storage.send(LookupClientSession(msg.0)).map_err(|_| ()))
+                .and_then(|res| res),
+        ))
+    }
+}
+
+/// Sent to the leader by a follower far behind, e.g. after a restart, holding the log through
+/// `last_log_index`. Fails on other nodes.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RequestCatchUp {
+    pub node: NodeId,
+    pub last_log_index: u64,
+}
+
+/// How the leader catches the follower up.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CatchUp {
+    /// Last log index of the leader
+    pub last_log_index: u64,
+    /// Index of the snapshot written for the follower, if it lagged past `snapshot_lag`
+    pub snapshot_index: Option<u64>,
+}
+
+impl Message for RequestCatchUp {
+    type Result = Result<CatchUp, ()>;
+}
+
+impl Handler<RequestCatchUp> for RaftClient {
+    type Result = ResponseActFuture<Self, CatchUp, ()>;
+
+    fn handle(&mut self, msg: RequestCatchUp, _ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(RequestCatchUp);
+        let (net, storage, config) = match (&self.net, &self.storage, &self.config.catch_up) {
+            (Some(net), Some(storage), Some(config)) => (net.clone(), storage.clone(), config.clone()),
+            _ => return Box::new(fut::err(())),
+        };
+        let snapshot_storage = storage.clone();
+
+        Box::new(
+            fut::wrap_future::<_, Self>(net.send(GetRaftMetrics))
+                .map_err(|_, _, _| ())
+                .and_then(move |metrics, act, _| {
+                    let leading = match metrics {
+                        Ok(Some(metrics)) => metrics.current_leader == Some(act.id),
+                        _ => false,
+                    };
+                    if !leading {
+                        return fut::Either::A(fut::err(()));
+                    }
+
+                    info!("Node {} asked to be caught up from index {}", msg.node, msg.last_log_index);
+                    net.do_send(PeerCatchingUp {
+                        id: msg.node,
+                        last_log_index: msg.last_log_index,
+                    });
+
+                    fut::Either::B(
+                        fut::wrap_future(snapshot_storage.send(TriggerSnapshot {
+                            matched: msg.last_log_index,
+                            max_lag: config.snapshot_lag,
+                        }))
+                        .then(move |res, act: &mut Self, _| {
+                            let snapshot = match res {
+                                Ok(Ok(snapshot)) => snapshot,
+                                Ok(Err(err)) => {
+                                    warn!("Node {} failed to snapshot for node {}: {}", act.id, msg.node, err);
+                                    None
+                                }
+                                Err(_) => None,
+                            };
+                            if snapshot.is_some() {
+                                act.snapshot_triggered = Some(clock::now());
+                            }
+                            fut::ok(snapshot)
+                        }),
+                    )
+                })
+                .and_then(move |snapshot, _, _| {
+                    fut::wrap_future(storage.send(GetLastLogIndex))
+                        .map_err(|_, _, _| ())
+                        .and_then(|res, _, _| fut::result(res))
+                        .map(move |last_log_index, _, _| CatchUp {
+                            last_log_index: last_log_index,
+                            snapshot_index: snapshot,
+                        })
+                }),
+        )
+    }
+}
+
+/// Whether a fencing token still belongs to the current holder of the lock, as far as this
+/// node has applied. Ask the leader for the most recent answer.
+pub struct ValidateFencingToken(pub String, pub FencingToken);
+
+impl Message for ValidateFencingToken {
+    type Result = Result<bool, ()>;
+}
+
+impl Handler<ValidateFencingToken> for RaftClient {
+    type Result = Response<bool, ()>;
+
+    fn handle(&mut self, msg: ValidateFencingToken, _ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(ValidateFencingToken);
+        if let Some(ref storage) = self.storage {
+            Response::fut(storage.send(CheckFencingToken(msg.0, msg.1)).map_err(|_| ()).and_then(|res| res))
+        } else {
+            Response::reply(Err(()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deadlines_count_from_the_arrival() {
+        let proposal = ClientProposal {
+            client: "billing".to_owned(),
+            key: None,
+            data: add_node(1),
+            deadline_ms: Some(60_000),
+        };
+        let remaining = remaining_ms(proposal.deadline()).unwrap();
+        assert!(remaining > 59_000 && remaining <= 60_000);
+
+        assert_eq!(remaining_ms(None), None);
+        assert_eq!(remaining_ms(Some(clock::now() - Duration::from_millis(10))), Some(0));
+    }
+
+    #[test]
+    fn backups_tell_lost_proposals_from_rejected_ones() {
+        assert_eq!(proposed_index(Ok(ClientPayloadResponse::Committed { index: 7 })).ok(), Some(7));
+
+        match proposed_index(Err(ClientError::Application(MemoryStorageError::faulted(StorageFault::DiskFull, Some(8))))) {
+            Err(BackupError::Propose(ProposeError::Storage(err))) => assert_eq!(err.index, Some(8)),
+            res => panic!("unexpected {:?}", res.map_err(|_| ())),
+        }
+        match proposed_index(Err(ClientError::Application(MemoryStorageError::default()))) {
+            Err(BackupError::Propose(ProposeError::Rejected)) => (),
+            res => panic!("unexpected {:?}", res.map_err(|_| ())),
+        }
+        match proposed_index(Err(ClientError::Internal)) {
+            Err(BackupError::Propose(ProposeError::Failed)) => (),
+            res => panic!("unexpected {:?}", res.map_err(|_| ())),
+        }
+    }
+
+    #[test]
+    fn node_proposals_are_admitted_apart_from_clients() {
+        assert_eq!(node_client(7), "raftor-node-7");
+        assert_eq!(remove_node(7), MemoryStorageData::Remove(7));
     }
 }
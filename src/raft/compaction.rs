@@ -0,0 +1,125 @@
+use crate::clock;
+use crate::config::CompactionConfig;
+
+/// Decides when the storage may write the snapshots raft asks for.
+///
+/// Raft asks once `logs_since_last` entries were applied since the last snapshot, the snapshot
+/// is then deferred until a configured time-of-day window (UTC) and while entries are appended
+/// faster than the configured rate, so snapshot creation doesn't add latency spikes during
+/// peak traffic.
+pub struct CompactionSchedule {
+    /// (start, end) minute of day, `end` may be smaller than `start` for windows past midnight
+    windows: Vec<(u32, u32)>,
+    max_entries_per_sec: Option<u64>,
+}
+
+impl CompactionSchedule {
+    pub fn from_config(config: &CompactionConfig) -> Result<CompactionSchedule, String> {
+        let windows = config
+            .windows
+            .iter()
+            .map(|window| parse_window(window.as_str()))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(CompactionSchedule {
+            windows: windows,
+            max_entries_per_sec: config.max_entries_per_sec,
+        })
+    }
+
+    /// Whether `minute` (of the day) falls into one of the windows. No windows means any time.
+    pub fn in_window(&self, minute: u32) -> bool {
+        if self.windows.is_empty() {
+            return true;
+        }
+
+        self.windows.iter().any(|&(start, end)| {
+            if start <= end {
+                minute >= start && minute < end
+            } else {
+                minute >= start || minute < end
+            }
+        })
+    }
+
+    pub fn should_compact(&self, entries_per_sec: u64, minute: u32) -> bool {
+        if let Some(max) = self.max_entries_per_sec {
+            if entries_per_sec > max {
+                return false;
+            }
+        }
+
+        self.in_window(minute)
+    }
+}
+
+/// Current minute of the day in UTC.
+pub fn minute_of_day_utc() -> u32 {
+    let secs = clock::wall_millis() / 1000;
+
+    ((secs % 86400) / 60) as u32
+}
+
+/// Parse a `HH:MM-HH:MM` window into minutes of the day.
+fn parse_window(window: &str) -> Result<(u32, u32), String> {
+    let parts = window.split('-').collect::<Vec<_>>();
+    if parts.len() != 2 {
+        return Err(format!("Invalid compaction window {}", window));
+    }
+
+    Ok((parse_time(parts[0])?, parse_time(parts[1])?))
+}
+
+fn parse_time(time: &str) -> Result<u32, String> {
+    let parts = time.trim().split(':').collect::<Vec<_>>();
+    let invalid = || format!("Invalid time {}", time);
+
+    if parts.len() != 2 {
+        return Err(invalid());
+    }
+
+    let hours = parts[0].parse::<u32>().map_err(|_| invalid())?;
+    let minutes = parts[1].parse::<u32>().map_err(|_| invalid())?;
+
+    if hours > 23 || minutes > 59 {
+        return Err(invalid());
+    }
+
+    Ok(hours * 60 + minutes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schedule(windows: &[&str], max_entries_per_sec: Option<u64>) -> Result<CompactionSchedule, String> {
+        CompactionSchedule::from_config(&CompactionConfig {
+            logs_since_last: 5000,
+            windows: windows.iter().map(|window| window.to_string()).collect(),
+            max_entries_per_sec: max_entries_per_sec,
+        })
+    }
+
+    #[test]
+    fn windows_may_span_midnight() {
+        let schedule = schedule(&["22:30-02:00"], None).unwrap();
+        assert!(schedule.should_compact(0, 23 * 60));
+        assert!(schedule.should_compact(0, 60));
+        assert!(!schedule.should_compact(0, 2 * 60));
+        assert!(!schedule.should_compact(0, 12 * 60));
+    }
+
+    #[test]
+    fn busy_logs_defer_compaction() {
+        let schedule = schedule(&[], Some(100)).unwrap();
+        assert!(schedule.should_compact(100, 0));
+        assert!(!schedule.should_compact(101, 0));
+    }
+
+    #[test]
+    fn malformed_windows_are_refused() {
+        assert!(schedule(&["24:00-01:00"], None).is_err());
+        assert!(schedule(&["01:00"], None).is_err());
+        assert!(schedule(&["1h-2h"], None).is_err());
+    }
+}
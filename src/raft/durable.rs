@@ -0,0 +1,702 @@
+use actix_raft::{messages::MembershipConfig, storage::HardState, NodeId};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::io;
+
+use crate::crash::Flusher;
+use crate::raft::recovery::LogReplay;
+use crate::raft::storage::Entry;
+
+/// Durable copy of the raft log and hard state, written through by the storage so a restarted
+/// node recovers them instead of resyncing from the leader.
+pub trait LogStore: Send {
+    /// The persisted hard state and log entries, oldest first.
+    fn load(&self) -> io::Result<(Option<HardState>, Vec<Entry>)>;
+
+    /// The persisted hard state alone, read before raft starts.
+    fn hard_state(&self) -> io::Result<Option<HardState>> {
+        self.load().map(|(hs, _)| hs)
+    }
+
+    /// Like `load`, reading the entries `batch` at a time, decoded by up to `threads` threads,
+    /// and reporting the progress to `replay`. Stores reading their log at once report it in
+    /// one step.
+    fn replay(&self, replay: &LogReplay, _batch: usize, _threads: usize) -> io::Result<(Option<HardState>, Vec<Entry>)> {
+        let (hs, entries) = self.load()?;
+        replay.start(entries.len() as u64);
+        replay.advance(entries.len() as u64);
+        Ok((hs, entries))
+    }
+
+    fn save_hard_state(&mut self, hs: &HardState) -> io::Result<()>;
+
+    /// Persist the entries, replacing the ones at the same indexes.
+    fn append(&mut self, entries: &[&Entry]) -> io::Result<()>;
+
+    /// Drop the entries before `index`, once they're covered by a snapshot.
+    fn truncate_before(&mut self, index: u64) -> io::Result<()>;
+
+    /// Drop the entries after `index`, which conflict with the log of the leader.
+    fn truncate_after(&mut self, index: u64) -> io::Result<()>;
+
+    /// Rewrite the store in steps to reclaim the space of dropped entries, the steps run
+    /// concurrently with the writes. Stores which don't fragment have nothing to vacuum.
+    fn vacuum(&self) -> Option<Box<dyn Vacuum>> {
+        None
+    }
+
+    /// Flushes writes the store buffers, run from the panic hook. Stores syncing every write
+    /// before it's acknowledged have nothing to flush.
+    fn flusher(&self) -> Option<Flusher> {
+        None
+    }
+
+    /// Whether log writes are synced to disk before they're acknowledged, see
+    /// `DurabilityPolicy`. The hard state is synced regardless: a voter forgetting its vote
+    /// after a crash could vote twice in the same term. Stores without a choice ignore it.
+    fn set_synced(&mut self, _synced: bool) {}
+}
+
+/// Async-ack voters of a `CommitQuorum`, which decides whether the local node syncs its log
+/// writes.
+#[derive(Clone, Debug)]
+pub struct DurabilityPolicy {
+    pub node: NodeId,
+    pub async_ack: BTreeSet<NodeId>,
+}
+
+impl DurabilityPolicy {
+    /// Whether the local node may acknowledge buffered log writes under the given membership:
+    /// it's an async-ack voter and the async-ack voters can't form a majority on their own. In
+    /// joint consensus entries commit with a majority of both configurations, so neither may
+    /// have an async-ack majority.
+    pub fn buffered(&self, membership: &MembershipConfig) -> bool {
+        if !self.async_ack.contains(&self.node) {
+            return false;
+        }
+
+        voter_configs(membership).iter().all(|voters| {
+            let quorum = voters.len() / 2 + 1;
+            let async_voters = voters.iter().filter(|id| self.async_ack.contains(id)).count();
+            async_voters < quorum
+        })
+    }
+}
+
+/// Voters of the configurations a commit needs a majority of, the old and the new one while
+/// in joint consensus.
+pub(crate) fn voter_configs(membership: &MembershipConfig) -> Vec<BTreeSet<NodeId>> {
+    let old = membership.members.iter().cloned().collect::<BTreeSet<_>>();
+    if !membership.is_in_joint_consensus {
+        return vec![old];
+    }
+
+    let new = old
+        .iter()
+        .filter(|id| !membership.removing.contains(id))
+        .chain(membership.non_voters.iter())
+        .cloned()
+        .collect();
+    vec![old, new]
+}
+
+/// Online rewrite of a log store, see `LogStore::vacuum`.
+pub trait Vacuum: Send {
+    /// Number of steps of the rewrite.
+    fn steps(&self) -> u64;
+
+    /// Rewrite the given step.
+    fn step(&mut self, step: u64) -> io::Result<()>;
+
+    /// Bytes the store takes on disk.
+    fn disk_bytes(&self) -> u64;
+}
+
+/// Progress of the last vacuum, times are unix milliseconds.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct VacuumProgress {
+    pub running: bool,
+    pub steps_done: u64,
+    pub steps: u64,
+    pub bytes_before: u64,
+    pub bytes_after: Option<u64>,
+    pub started_at: Option<u64>,
+    pub finished_at: Option<u64>,
+    pub error: Option<String>,
+}
+
+/// Serialized form of the hard state.
+#[derive(Serialize, Deserialize)]
+struct StoredHardState {
+    current_term: u64,
+    voted_for: Option<NodeId>,
+    membership: MembershipConfig,
+}
+
+impl From<&HardState> for StoredHardState {
+    fn from(hs: &HardState) -> Self {
+        StoredHardState {
+            current_term: hs.current_term,
+            voted_for: hs.voted_for,
+            membership: hs.membership.clone(),
+        }
+    }
+}
+
+impl From<StoredHardState> for HardState {
+    fn from(hs: StoredHardState) -> Self {
+        HardState {
+            current_term: hs.current_term,
+            voted_for: hs.voted_for,
+            membership: hs.membership,
+        }
+    }
+}
+
+#[cfg(feature = "persistent")]
+pub use self::rocks::RocksLogStore;
+
+#[cfg(feature = "persistent")]
+mod rocks {
+    use rmp_serde as rmps;
+    use rocksdb::{Direction, IteratorMode, WriteBatch, WriteOptions, DB};
+    use std::collections::HashSet;
+    use std::io;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    use super::{LogStore, StoredHardState, Vacuum};
+    use crate::crash::Flusher;
+    use crate::dictionary::{self, Dictionary};
+    use crate::raft::io_stats;
+    use crate::raft::recovery::LogReplay;
+    use crate::raft::storage::Entry;
+    use actix_raft::storage::HardState;
+
+    const HARD_STATE_KEY: &[u8] = b"hs";
+    const LOG_PREFIX: &[u8] = b"log/";
+    /// Dictionaries entries were compressed with, kept for as long as the store
+    const DICT_PREFIX: &[u8] = b"dict/";
+    /// First byte of an entry compressed with a dictionary, followed by the big endian id of
+    /// the dictionary. MessagePack encoded entries never start with it.
+    const DICTIONARY_MARKER: u8 = 0;
+    /// Entries smaller than this are stored uncompressed
+    const MIN_COMPRESSED_LEN: usize = 32;
+    /// Log key ranges compacted one at a time by a vacuum
+    const VACUUM_STEPS: u64 = 64;
+
+    fn log_key(index: u64) -> Vec<u8> {
+        let mut key = LOG_PREFIX.to_vec();
+        key.extend_from_slice(&index.to_be_bytes());
+        key
+    }
+
+    fn dict_key(id: u32) -> Vec<u8> {
+        let mut key = DICT_PREFIX.to_vec();
+        key.extend_from_slice(&id.to_be_bytes());
+        key
+    }
+
+    fn to_io<E: ToString>(err: E) -> io::Error {
+        io::Error::new(io::ErrorKind::Other, err.to_string())
+    }
+
+    fn key_index(key: &[u8]) -> u64 {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(&key[LOG_PREFIX.len()..]);
+        u64::from_be_bytes(bytes)
+    }
+
+    /// Decode the values of a batch, spread over up to `threads` threads, in order.
+    fn decode_batch(batch: Vec<Box<[u8]>>, threads: usize) -> io::Result<Vec<Entry>> {
+        if threads <= 1 || batch.len() < threads * 2 {
+            return batch.iter().map(|data| decode(data)).collect();
+        }
+
+        let chunk = (batch.len() + threads - 1) / threads;
+        let mut batch = batch;
+        let mut workers = Vec::with_capacity(threads);
+        while !batch.is_empty() {
+            let rest = batch.split_off(chunk.min(batch.len()));
+            let part = std::mem::replace(&mut batch, rest);
+            workers.push(std::thread::spawn(move || part.iter().map(|data| decode(data)).collect::<io::Result<Vec<_>>>()));
+        }
+
+        let mut entries = Vec::new();
+        for worker in workers {
+            let decoded = worker
+                .join()
+                .map_err(|_| io::Error::new(io::ErrorKind::Other, "a log replay thread panicked"))??;
+            entries.extend(decoded);
+        }
+        Ok(entries)
+    }
+
+    fn decode(data: &[u8]) -> io::Result<Entry> {
+        if data.first() != Some(&DICTIONARY_MARKER) {
+            return rmps::from_slice::<Entry>(data).map_err(to_io);
+        }
+        if data.len() < 5 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "entry compressed with a dictionary lacks its id"));
+        }
+
+        let mut id = [0; 4];
+        id.copy_from_slice(&data[1..5]);
+        let decompressed = dictionary::decompress(u32::from_be_bytes(id), &data[5..], usize::max_value())?;
+        rmps::from_slice::<Entry>(&decompressed).map_err(to_io)
+    }
+
+    /// Log store kept in a RocksDB database, entries are keyed by their big endian index so
+    /// they iterate in log order. Every write is synced before it's acknowledged, unless the
+    /// node is an async-ack voter, whose log writes are buffered. The hard state is always
+    /// synced.
+    pub struct RocksLogStore {
+        db: Arc<DB>,
+        synced: bool,
+        /// Compress entries with the cluster's dictionary once there is one
+        compress: bool,
+        /// Dictionaries already kept in the store
+        dictionaries: HashSet<u32>,
+    }
+
+    impl RocksLogStore {
+        pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+            let db = DB::open_default(path).map_err(to_io)?;
+            let mut dictionaries = HashSet::new();
+            for (_, data) in db
+                .iterator(IteratorMode::From(DICT_PREFIX, Direction::Forward))
+                .take_while(|(key, _)| key.starts_with(DICT_PREFIX))
+            {
+                let dict = rmps::from_slice::<Dictionary>(&data).map_err(to_io)?;
+                dictionary::register(&dict);
+                dictionaries.insert(dict.id);
+            }
+
+            Ok(RocksLogStore {
+                db: Arc::new(db),
+                synced: true,
+                compress: false,
+                dictionaries: dictionaries,
+            })
+        }
+
+        /// Compress entries with the cluster's dictionary, see `dictionary`.
+        pub fn with_dictionary_compression(mut self, compress: bool) -> Self {
+            self.compress = compress;
+            self
+        }
+
+        /// Encoded entry, compressed with the current dictionary when it's worth it. The
+        /// dictionary is added to the batch the first time it's used.
+        fn encode(&mut self, entry: &Entry, batch: &mut WriteBatch) -> io::Result<Vec<u8>> {
+            let data = rmps::to_vec(entry).map_err(to_io)?;
+            if !self.compress || data.len() < MIN_COMPRESSED_LEN {
+                return Ok(data);
+            }
+
+            let (id, compressed) = match dictionary::compress(&data)? {
+                Some(compressed) => compressed,
+                None => return Ok(data),
+            };
+            if compressed.len() + 5 >= data.len() {
+                return Ok(data);
+            }
+            if !self.dictionaries.contains(&id) {
+                match dictionary::current().filter(|dict| dict.id == id) {
+                    Some(dict) => batch.put(dict_key(id), rmps::to_vec(&dict).map_err(to_io)?).map_err(to_io)?,
+                    // replaced in the meantime, the next entry gets the new one
+                    None => return Ok(data),
+                }
+                self.dictionaries.insert(id);
+            }
+
+            let mut stored = Vec::with_capacity(compressed.len() + 5);
+            stored.push(DICTIONARY_MARKER);
+            stored.extend_from_slice(&id.to_be_bytes());
+            stored.extend_from_slice(&compressed);
+            Ok(stored)
+        }
+
+        /// Commit a batch of `bytes` of values, synced to disk first when `sync`.
+        fn write(&self, batch: WriteBatch, bytes: usize, sync: bool) -> io::Result<()> {
+            let mut opts = WriteOptions::default();
+            opts.set_sync(sync);
+            self.db.write_opt(batch, &opts).map_err(to_io)?;
+            io_stats::log_write(bytes);
+            Ok(())
+        }
+
+        fn log_keys(&self) -> impl Iterator<Item = (Box<[u8]>, Box<[u8]>)> + '_ {
+            self.db
+                .iterator(IteratorMode::From(LOG_PREFIX, Direction::Forward))
+                .take_while(|(key, _)| key.starts_with(LOG_PREFIX))
+        }
+
+        /// Indexes of the first and last persisted entries.
+        fn log_bounds(&self) -> Option<(u64, u64)> {
+            let first = self.log_keys().next().map(|(key, _)| key_index(&*key))?;
+            let last = self
+                .db
+                .iterator(IteratorMode::From(&log_key(u64::max_value()), Direction::Reverse))
+                .find(|(key, _)| key.starts_with(LOG_PREFIX))
+                .map(|(key, _)| key_index(&*key))
+                .unwrap_or(first);
+            Some((first, last))
+        }
+    }
+
+    impl LogStore for RocksLogStore {
+        fn load(&self) -> io::Result<(Option<HardState>, Vec<Entry>)> {
+            let hs = self.hard_state()?;
+
+            let mut entries = Vec::new();
+            for (_, data) in self.log_keys() {
+                io_stats::log_read(data.len());
+                entries.push(decode(&data)?);
+            }
+
+            Ok((hs, entries))
+        }
+
+        fn replay(&self, replay: &LogReplay, batch: usize, threads: usize) -> io::Result<(Option<HardState>, Vec<Entry>)> {
+            let hs = self.hard_state()?;
+            // the log has no holes, its bounds tell its length without reading it
+            let total = self.log_bounds().map_or(0, |(first, last)| last - first + 1);
+            replay.start(total);
+
+            let mut entries = Vec::with_capacity(total as usize);
+            let mut keys = self.log_keys();
+            loop {
+                let values = keys.by_ref().take(batch).map(|(_, data)| data).collect::<Vec<_>>();
+                if values.is_empty() {
+                    break;
+                }
+                io_stats::log_read(values.iter().map(|data| data.len()).sum());
+                let decoded = decode_batch(values, threads)?;
+                replay.advance(decoded.len() as u64);
+                entries.extend(decoded);
+            }
+
+            Ok((hs, entries))
+        }
+
+        fn hard_state(&self) -> io::Result<Option<HardState>> {
+            match self.db.get(HARD_STATE_KEY).map_err(to_io)? {
+                Some(data) => Ok(Some(rmps::from_slice::<StoredHardState>(&data).map_err(to_io)?.into())),
+                None => Ok(None),
+            }
+        }
+
+        fn save_hard_state(&mut self, hs: &HardState) -> io::Result<()> {
+            let data = rmps::to_vec(&StoredHardState::from(hs)).map_err(to_io)?;
+            let bytes = data.len();
+            let mut batch = WriteBatch::default();
+            batch.put(HARD_STATE_KEY, data).map_err(to_io)?;
+            // the vote must survive a crash whatever the durability policy
+            self.write(batch, bytes, true)
+        }
+
+        fn append(&mut self, entries: &[&Entry]) -> io::Result<()> {
+            let mut batch = WriteBatch::default();
+            let mut bytes = 0;
+            for entry in entries {
+                let data = self.encode(entry, &mut batch)?;
+                bytes += data.len();
+                batch.put(log_key(entry.index), data).map_err(to_io)?;
+            }
+            self.write(batch, bytes, self.synced)
+        }
+
+        fn truncate_before(&mut self, index: u64) -> io::Result<()> {
+            let end = log_key(index);
+            let mut batch = WriteBatch::default();
+            for (key, _) in self.log_keys().take_while(|(key, _)| **key < *end) {
+                batch.delete(key).map_err(to_io)?;
+            }
+            self.write(batch, 0, self.synced)
+        }
+
+        fn truncate_after(&mut self, index: u64) -> io::Result<()> {
+            let start = log_key(index.saturating_add(1));
+            let mut batch = WriteBatch::default();
+            for (key, _) in self
+                .db
+                .iterator(IteratorMode::From(&start, Direction::Forward))
+                .take_while(|(key, _)| key.starts_with(LOG_PREFIX))
+            {
+                batch.delete(key).map_err(to_io)?;
+            }
+            self.write(batch, 0, self.synced)
+        }
+
+        fn vacuum(&self) -> Option<Box<dyn Vacuum>> {
+            let (first, last) = self.log_bounds().unwrap_or((0, 0));
+
+            Some(Box::new(RocksVacuum {
+                db: self.db.clone(),
+                first: first,
+                width: ((last - first) / VACUUM_STEPS).max(1),
+            }))
+        }
+
+        fn flusher(&self) -> Option<Flusher> {
+            // synced writes are in the WAL already, flushing the memtables spares a crashed node
+            // replaying it and gets buffered ones to disk
+            let db = self.db.clone();
+            Some(Box::new(move || db.flush().map_err(to_io)))
+        }
+
+        fn set_synced(&mut self, synced: bool) {
+            self.synced = synced;
+        }
+    }
+
+    /// Compacts the log key space in index ranges, so truncated entries leave the SST files
+    /// without compacting the whole database at once. The ranges split the log as it was when
+    /// the vacuum started, the last step compacts the remaining keys.
+    struct RocksVacuum {
+        db: Arc<DB>,
+        first: u64,
+        width: u64,
+    }
+
+    impl Vacuum for RocksVacuum {
+        fn steps(&self) -> u64 {
+            VACUUM_STEPS + 1
+        }
+
+        fn step(&mut self, step: u64) -> io::Result<()> {
+            if step == VACUUM_STEPS {
+                // keys before the log, e.g. the hard state, and entries appended since
+                self.db.compact_range::<&[u8], &[u8]>(None, Some(&log_key(self.first)[..]));
+                self.db.compact_range::<&[u8], &[u8]>(Some(&log_key(self.first + self.width * VACUUM_STEPS)[..]), None);
+                return Ok(());
+            }
+
+            let start = log_key(self.first + step * self.width);
+            let end = log_key(self.first + (step + 1) * self.width);
+            self.db.compact_range(Some(&start), Some(&end));
+            Ok(())
+        }
+
+        fn disk_bytes(&self) -> u64 {
+            self.db
+                .property_int_value("rocksdb.total-sst-files-size")
+                .ok()
+                .and_then(|bytes| bytes)
+                .unwrap_or(0)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raft::storage::testkit::{self, LogOp};
+    use proptest::prelude::*;
+    use std::collections::{BTreeMap, HashMap};
+    use std::path::{Path, PathBuf};
+    use std::sync::{Arc, Mutex};
+
+    /// What a `DiskStore` wrote to a directory, outliving the store like a disk.
+    type Disk = Arc<Mutex<HashMap<PathBuf, (Option<HardState>, BTreeMap<u64, Entry>)>>>;
+
+    /// Log store keeping its writes on a shared `Disk`, a store opened again on the same
+    /// directory recovers them.
+    struct DiskStore {
+        disk: Disk,
+        dir: PathBuf,
+    }
+
+    impl DiskStore {
+        fn with<T>(&self, f: impl FnOnce(&mut (Option<HardState>, BTreeMap<u64, Entry>)) -> T) -> T {
+            f(self.disk.lock().unwrap().entry(self.dir.clone()).or_default())
+        }
+    }
+
+    impl LogStore for DiskStore {
+        fn load(&self) -> io::Result<(Option<HardState>, Vec<Entry>)> {
+            Ok(self.with(|(hs, log)| (hs.clone(), log.values().cloned().collect())))
+        }
+
+        fn save_hard_state(&mut self, state: &HardState) -> io::Result<()> {
+            self.with(|(hs, _)| *hs = Some(state.clone()));
+            Ok(())
+        }
+
+        fn append(&mut self, entries: &[&Entry]) -> io::Result<()> {
+            self.with(|(_, log)| {
+                for entry in entries {
+                    log.insert(entry.index, (*entry).clone());
+                }
+            });
+            Ok(())
+        }
+
+        fn truncate_before(&mut self, index: u64) -> io::Result<()> {
+            self.with(|(_, log)| *log = log.split_off(&index));
+            Ok(())
+        }
+
+        fn truncate_after(&mut self, index: u64) -> io::Result<()> {
+            self.with(|(_, log)| {
+                log.split_off(&index.saturating_add(1));
+            });
+            Ok(())
+        }
+    }
+
+    fn open(disk: &Disk, dir: &Path) -> io::Result<Box<dyn LogStore>> {
+        Ok(Box::new(DiskStore { disk: disk.clone(), dir: dir.to_path_buf() }))
+    }
+
+    fn membership(members: &[NodeId]) -> MembershipConfig {
+        MembershipConfig {
+            is_in_joint_consensus: false,
+            members: members.to_vec(),
+            non_voters: Vec::new(),
+            removing: Vec::new(),
+        }
+    }
+
+    fn policy(node: NodeId, async_ack: &[NodeId]) -> DurabilityPolicy {
+        DurabilityPolicy {
+            node: node,
+            async_ack: async_ack.iter().cloned().collect(),
+        }
+    }
+
+    #[test]
+    fn async_ack_minority_buffers() {
+        assert!(policy(3, &[3]).buffered(&membership(&[1, 2, 3])));
+        assert!(policy(4, &[4, 5]).buffered(&membership(&[1, 2, 3, 4, 5])));
+    }
+
+    #[test]
+    fn async_ack_majority_syncs() {
+        assert!(!policy(2, &[2, 3]).buffered(&membership(&[1, 2, 3])));
+        assert!(!policy(1, &[2]).buffered(&membership(&[1, 2, 3])));
+    }
+
+    #[test]
+    fn joint_consensus_counts_both_configs() {
+        // an async-ack minority of both the old and the new voters
+        let mut joint = membership(&[1, 2, 3, 4, 5]);
+        joint.is_in_joint_consensus = true;
+        joint.removing = vec![4, 5];
+        assert!(policy(3, &[3]).buffered(&joint));
+
+        // a minority of the old voters, a majority of the new ones once 4 and 5 leave
+        let shrinking = policy(3, &[2, 3]);
+        assert!(shrinking.buffered(&membership(&[1, 2, 3, 4, 5])));
+        assert!(!shrinking.buffered(&joint));
+
+        // non-voters joining count in the new config
+        let mut joint = membership(&[1, 2, 3]);
+        joint.is_in_joint_consensus = true;
+        joint.non_voters = vec![4, 5];
+        assert!(policy(3, &[3, 4, 5]).buffered(&membership(&[1, 2, 3])));
+        assert!(!policy(3, &[3, 4, 5]).buffered(&joint));
+    }
+
+    #[cfg(feature = "persistent")]
+    #[test]
+    fn restarted_async_ack_voter_keeps_its_vote() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let mut store = RocksLogStore::open(dir.path()).unwrap();
+            store.set_synced(false);
+            store
+                .save_hard_state(&HardState {
+                    current_term: 7,
+                    voted_for: Some(2),
+                    membership: membership(&[1, 2, 3]),
+                })
+                .unwrap();
+            // closed without flushing its memtables, like a crash
+        }
+
+        let store = RocksLogStore::open(dir.path()).unwrap();
+        let hs = store.hard_state().unwrap().expect("hard state to be recovered");
+        assert_eq!(hs.current_term, 7);
+        assert_eq!(hs.voted_for, Some(2));
+    }
+
+    #[test]
+    fn reopened_stores_recover_their_writes() {
+        let disk = Disk::default();
+        testkit::run(|dir| open(&disk, dir)).assert_passed();
+    }
+
+    #[cfg(feature = "persistent")]
+    #[test]
+    fn rocks_store_conforms() {
+        testkit::run(|dir| Ok(Box::new(RocksLogStore::open(dir)?) as Box<dyn LogStore>)).assert_passed();
+    }
+
+    fn log_op() -> impl Strategy<Value = LogOp> {
+        prop_oneof![
+            (1..8u64, 1..5u64).prop_map(|(count, term)| LogOp::Append { count: count, term: term }),
+            (0..6u64, 1..5u64).prop_map(|(back, term)| LogOp::Conflict { back: back, term: term }),
+            (1..8u64).prop_map(|keep| LogOp::Compact { keep: keep }),
+            (1..5u64, proptest::option::of(1..4u64)).prop_map(|(term, voted_for)| LogOp::SaveHardState { term: term, voted_for: voted_for }),
+            Just(LogOp::Reopen),
+        ]
+    }
+
+    fn members() -> impl Strategy<Value = MembershipConfig> {
+        (
+            proptest::collection::btree_set(1..8u64, 1..6),
+            proptest::collection::btree_set(1..8u64, 0..3),
+            proptest::collection::btree_set(1..8u64, 0..3),
+            any::<bool>(),
+        )
+            .prop_map(|(members, non_voters, removing, joint)| MembershipConfig {
+                is_in_joint_consensus: joint,
+                non_voters: non_voters.difference(&members).cloned().collect(),
+                removing: if joint { removing.intersection(&members).cloned().collect() } else { Vec::new() },
+                members: members.into_iter().collect(),
+            })
+    }
+
+    proptest! {
+        #[test]
+        fn stores_load_what_raft_wrote(ops in proptest::collection::vec(log_op(), 1..40)) {
+            let disk = Disk::default();
+            let dir = tempfile::tempdir().unwrap();
+            prop_assert_eq!(testkit::check_model(&mut |dir: &Path| open(&disk, dir), dir.path(), &ops), Ok(()));
+        }
+
+        #[cfg(feature = "persistent")]
+        #[test]
+        fn rocks_store_loads_what_raft_wrote(ops in proptest::collection::vec(log_op(), 1..40)) {
+            let dir = tempfile::tempdir().unwrap();
+            let mut open = |dir: &Path| -> io::Result<Box<dyn LogStore>> { Ok(Box::new(RocksLogStore::open(dir)?)) };
+            prop_assert_eq!(testkit::check_model(&mut open, dir.path(), &ops), Ok(()));
+        }
+
+        #[test]
+        fn buffered_voters_leave_a_synced_majority(membership in members(), async_ack in proptest::collection::btree_set(1..8u64, 0..5), node in 1..8u64) {
+            let policy = policy_for(node, &async_ack);
+            if policy.buffered(&membership) {
+                // every async-ack voter losing its buffered writes leaves a majority of each
+                // configuration which synced them
+                for voters in voter_configs(&membership) {
+                    let synced = voters.iter().filter(|id| !async_ack.contains(id)).count();
+                    prop_assert!(synced > voters.len() / 2, "{:?} of {:?} synced", synced, voters);
+                }
+                // and the other async-ack nodes buffer too
+                for other in async_ack.iter() {
+                    prop_assert!(policy_for(*other, &async_ack).buffered(&membership));
+                }
+            }
+        }
+    }
+
+    fn policy_for(node: NodeId, async_ack: &BTreeSet<NodeId>) -> DurabilityPolicy {
+        DurabilityPolicy {
+            node: node,
+            async_ack: async_ack.clone(),
+        }
+    }
+}
@@ -0,0 +1,1587 @@
+//! Independent raft groups sharing the peer connections of the cluster network.
+//!
+//! Every group has its own log, storage and membership, a subset of the cluster nodes. Its raft
+//! RPCs travel as `GroupRpc` messages over the existing peer sessions and are routed to the
+//! local replica of the group by `RaftGroups`. Groups replicate `MemoryStorageData` commands
+//! of their own, proposals to a group skip the validators, hooks and admission queue of the
+//! cluster raft.
+//!
+//! Heartbeats, appends without entries, of all the groups led by a node are held for
+//! `group_heartbeat_window_ms` and sent to each peer as one `GroupHeartbeats` frame, so idle
+//! traffic grows with the peers rather than with the groups.
+//!
+//! With `group_rebalance` set the cluster leader moves replicas and leadership of the groups
+//! off overloaded nodes, one move per round, see `rebalance`.
+
+use actix::dev::ToEnvelope;
+use actix::prelude::*;
+use actix_raft::{
+    admin::{InitWithConfig, ProposeConfigChange, ProposeConfigChangeError},
+    config::Config,
+    messages::*,
+    NodeId, Raft, RaftMetrics, RaftNetwork,
+};
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use futures::future::{self, Either};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, RwLock,
+};
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+
+use crate::clock;
+use crate::config::ConfigSchema;
+use crate::events::{EventKind, EventLog, MoveProgress};
+use crate::hash_ring;
+use crate::network::{remote::{RemoteMessage, SendRemoteMessage}, GetClusterStatus, GetNodeById, HandlerRegistry, Network, ProposeError, RaftState, ScatterGather};
+use crate::raft::hlc::{Hlc, HybridClock, SharedClock};
+use crate::raft::hooks::HookChain;
+use crate::raft::rebalance::{self, GroupLoad, ShardMove, TrafficSampler};
+use crate::raft::storage::{GetStateDump, MemoryStorage, MemoryStorageData, MemoryStorageError, MemoryStorageResponse, StampedData, StateDump, StateDumpEntry, StorageFaulted, WriteFreeze};
+use crate::raft::{ClientProposal, Payload, RaftBuilder};
+use crate::server::Server;
+
+pub type GroupId = u64;
+
+/// How often an imported group is asked for a leader to restore its checkpoint through
+const RESTORE_RETRY_INTERVAL: Duration = Duration::from_millis(200);
+/// Attempts to restore a chunk of an imported checkpoint before the import fails
+const RESTORE_ATTEMPTS: u32 = 50;
+/// Bytes of commands restored in one message, well below the frame limit
+const RESTORE_CHUNK_BYTES: usize = 4 * 1024 * 1024;
+/// How long the cluster leader waits for the loads of the groups from their leaders
+const REBALANCE_GATHER_DEADLINE: Duration = Duration::from_secs(5);
+/// How often a leader stepping aside checks whether another member took over
+const STEP_ASIDE_POLL: Duration = Duration::from_millis(50);
+
+/// Picks the group owning the routing key of a client frame, so the frame goes to the group
+/// straight away instead of through a dispatcher on the cluster raft. Set with
+/// `Raftor::set_router`.
+pub trait Router: Send + Sync + 'static {
+    /// Group owning the key, `None` for the cluster raft
+    fn route(&self, key: &str) -> Option<GroupId>;
+}
+
+impl<F> Router for F
+where
+    F: Fn(&str) -> Option<GroupId> + Send + Sync + 'static,
+{
+    fn route(&self, key: &str) -> Option<GroupId> {
+        self(key)
+    }
+}
+
+/// Router of a node, shared with its client listeners.
+pub type SharedRouter = Arc<RwLock<Option<Box<dyn Router>>>>;
+
+pub type GroupRaft = Raft<StampedData, MemoryStorageResponse, MemoryStorageError, GroupNetwork, MemoryStorage>;
+
+/// A raft message of a group, routed to the group's replica on the receiving node.
+#[derive(Serialize, Deserialize)]
+pub struct GroupRpc<M> {
+    pub group: GroupId,
+    pub msg: M,
+}
+
+impl<M: Message> Message for GroupRpc<M> {
+    type Result = M::Result;
+}
+
+impl RemoteMessage for GroupRpc<AppendEntriesRequest<StampedData>> {
+    fn type_id() -> &'static str {
+        "GroupAppendEntriesRequest"
+    }
+
+    fn size_hint(&self) -> usize {
+        self.msg.size_hint()
+    }
+}
+
+impl RemoteMessage for GroupRpc<VoteRequest> {
+    fn type_id() -> &'static str {
+        "GroupVoteRequest"
+    }
+}
+
+impl RemoteMessage for GroupRpc<InstallSnapshotRequest> {
+    fn type_id() -> &'static str {
+        "GroupInstallSnapshotRequest"
+    }
+
+    fn size_hint(&self) -> usize {
+        self.msg.size_hint()
+    }
+}
+
+/// Heartbeats of several groups to one peer, answered in the same order.
+#[derive(Serialize, Deserialize)]
+pub struct GroupHeartbeats(pub Vec<GroupRpc<AppendEntriesRequest<StampedData>>>);
+
+impl Message for GroupHeartbeats {
+    type Result = Result<Vec<Result<AppendEntriesResponse, ()>>, ()>;
+}
+
+impl RemoteMessage for GroupHeartbeats {
+    fn type_id() -> &'static str {
+        "GroupHeartbeats"
+    }
+}
+
+/// A heartbeat waiting for the frame to its peer.
+#[derive(Message)]
+struct QueueHeartbeat {
+    peer: NodeId,
+    rpc: GroupRpc<AppendEntriesRequest<StampedData>>,
+    tx: oneshot::Sender<Result<AppendEntriesResponse, ()>>,
+}
+
+/// Heartbeats sent by the groups of this node and the frames they were sent in.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct HeartbeatStats {
+    pub heartbeats: u64,
+    pub frames: u64,
+}
+
+/// Raft network of one group, sends its RPCs through the peer connections of the cluster
+/// network and reports its metrics to `RaftGroups`.
+pub struct GroupNetwork {
+    group: GroupId,
+    net: Addr<Network>,
+    groups: Addr<RaftGroups>,
+    /// Whether heartbeats go through `RaftGroups` to be coalesced
+    coalesce: bool,
+    /// Set while the leader steps aside for `TransferGroupLeadership`, appends fail so the
+    /// other members time out and elect a new leader
+    stepping_aside: Arc<AtomicBool>,
+}
+
+impl Actor for GroupNetwork {
+    type Context = Context<Self>;
+}
+
+impl RaftNetwork<StampedData> for GroupNetwork {}
+
+impl GroupNetwork {
+    fn send<M, R>(&self, target: NodeId, msg: M) -> ResponseActFuture<Self, R, ()>
+    where
+        GroupRpc<M>: RemoteMessage + Message<Result = Result<R, ()>>,
+        R: Send + Serialize + DeserializeOwned + 'static,
+    {
+        let rpc = GroupRpc {
+            group: self.group,
+            msg: msg,
+        };
+
+        Box::new(
+            fut::wrap_future::<_, Self>(self.net.send(GetNodeById(target)))
+                .map_err(|_, _, _| ())
+                .and_then(|node, _, _| fut::result(node))
+                .and_then(move |node, _, _| {
+                    fut::wrap_future(node.send(SendRemoteMessage(rpc)))
+                        .map_err(|_, _, _| ())
+                        .and_then(|res, _, _| fut::result(res))
+                }),
+        )
+    }
+}
+
+impl Handler<AppendEntriesRequest<StampedData>> for GroupNetwork {
+    type Result = ResponseActFuture<Self, AppendEntriesResponse, ()>;
+
+    fn handle(&mut self, msg: AppendEntriesRequest<StampedData>, _ctx: &mut Context<Self>) -> Self::Result {
+        if self.stepping_aside.load(Ordering::SeqCst) {
+            return Box::new(fut::err(()));
+        }
+
+        if !self.coalesce || !msg.entries.is_empty() {
+            return self.send(msg.target, msg);
+        }
+
+        let (tx, rx) = oneshot::channel();
+        self.groups.do_send(QueueHeartbeat {
+            peer: msg.target,
+            rpc: GroupRpc {
+                group: self.group,
+                msg: msg,
+            },
+            tx: tx,
+        });
+        Box::new(fut::wrap_future::<_, Self>(rx).map_err(|_, _, _| ()).and_then(|res, _, _| fut::result(res)))
+    }
+}
+
+impl Handler<VoteRequest> for GroupNetwork {
+    type Result = ResponseActFuture<Self, VoteResponse, ()>;
+
+    fn handle(&mut self, msg: VoteRequest, _ctx: &mut Context<Self>) -> Self::Result {
+        self.send(msg.target, msg)
+    }
+}
+
+impl Handler<InstallSnapshotRequest> for GroupNetwork {
+    type Result = ResponseActFuture<Self, InstallSnapshotResponse, ()>;
+
+    fn handle(&mut self, msg: InstallSnapshotRequest, _ctx: &mut Context<Self>) -> Self::Result {
+        if self.stepping_aside.load(Ordering::SeqCst) {
+            return Box::new(fut::err(()));
+        }
+        self.send(msg.target, msg)
+    }
+}
+
+impl Handler<RaftMetrics> for GroupNetwork {
+    type Result = ();
+
+    fn handle(&mut self, msg: RaftMetrics, _ctx: &mut Context<Self>) {
+        self.groups.do_send(GroupMetrics(self.group, msg));
+    }
+}
+
+/// Local replica of a group, running in its own arbiter so it can be stopped.
+struct Replica {
+    raft: Addr<GroupRaft>,
+    storage: Addr<MemoryStorage>,
+    arbiter: Arbiter,
+    metrics: Option<RaftMetrics>,
+    /// Set once the group is sealed for a move, see `ExportGroupCheckpoint`
+    frozen: WriteFreeze,
+    /// Shared with the `GroupNetwork` of the replica
+    stepping_aside: Arc<AtomicBool>,
+}
+
+/// Hosts the local replicas of the raft groups this node is a member of.
+pub struct RaftGroups {
+    id: NodeId,
+    net: Addr<Network>,
+    server: Addr<Server>,
+    registry: Arc<RwLock<HandlerRegistry>>,
+    events: EventLog,
+    config: ConfigSchema,
+    clock: SharedClock,
+    replicas: HashMap<GroupId, Replica>,
+    /// Heartbeats held for the next frame to each peer
+    heartbeats: HashMap<NodeId, Vec<QueueHeartbeat>>,
+    heartbeat_stats: HeartbeatStats,
+    /// Traffic of the groups seen by the rebalancer of this node while it leads the cluster
+    traffic: TrafficSampler,
+    /// Whether a move of the rebalancer is running, moves don't overlap
+    moving: bool,
+}
+
+impl RaftGroups {
+    pub fn new(id: NodeId, net: Addr<Network>, server: Addr<Server>, registry: Arc<RwLock<HandlerRegistry>>, events: EventLog, config: ConfigSchema) -> RaftGroups {
+        RaftGroups {
+            id: id,
+            net: net,
+            server: server,
+            registry: registry,
+            events: events,
+            config: config,
+            clock: HybridClock::new(),
+            replicas: HashMap::new(),
+            heartbeats: HashMap::new(),
+            heartbeat_stats: HeartbeatStats::default(),
+            traffic: TrafficSampler::default(),
+            moving: false,
+        }
+    }
+
+    /// Start the local replica of a group, initializing its membership when it's created.
+    fn start_replica(&mut self, group: GroupId, members: Vec<NodeId>, init: bool, ctx: &mut Context<Self>) -> io::Result<()> {
+        if self.replicas.contains_key(&group) {
+            return Ok(());
+        }
+
+        let snapshot_dir = replica_dir(self.config.data_dir.as_ref().map(String::as_str), self.id, group)?;
+        // the log and hard state are kept with the `persistent` feature
+        let recovered = match self.config.data_dir {
+            Some(_) => match RaftBuilder::open_log_store(&self.config, snapshot_dir.join("log"))? {
+                Some(store) => {
+                    let log = store.load()?;
+                    Some((store, log))
+                }
+                None => None,
+            },
+            None => None,
+        };
+        let snapshot_dir = snapshot_dir.to_string_lossy().to_string();
+
+        let timings = &self.config.raft_timings;
+        let config = Config::build(snapshot_dir.clone())
+            .election_timeout_min(timings.election_timeout_min)
+            .election_timeout_max(timings.election_timeout_max)
+            .heartbeat_interval(timings.heartbeat_interval)
+            .metrics_rate(Duration::from_secs(1))
+            .snapshot_max_chunk_size(self.config.snapshot_chunk_size)
+            .validate()
+            .expect("Raft config to be created without error.");
+
+        let arbiter = Arbiter::new();
+        let id = self.id;
+        let groups = ctx.address();
+        let (net, server, clock, events) = (self.net.clone(), self.server.clone(), self.clock.clone(), self.events.clone());
+        let version_policy = self.config.snapshot_version_policy.clone();
+        let snapshot_retention = self.config.snapshot_retention;
+        let storage_members = members.clone();
+        let fault_recipient = groups.clone().recipient();
+        let address_recipient = net.clone().recipient();
+        let frozen = WriteFreeze::default();
+        let storage_frozen = frozen.clone();
+
+        let storage = MemoryStorage::start_in_arbiter(&arbiter, move |_| {
+            let storage = MemoryStorage::new(
+                storage_members,
+                snapshot_dir,
+                hash_ring::Ring::new(10),
+                server,
+                fault_recipient,
+                address_recipient,
+                None,
+                None,
+                HookChain::new(),
+                clock,
+                version_policy,
+                storage_frozen,
+                None,
+                snapshot_retention,
+                events,
+            )
+            // the group's own ring isn't the one clients are routed by
+            .without_rebalance();
+            match recovered {
+                Some((store, log)) => storage.with_recovered_log(store, log),
+                None => storage,
+            }
+        });
+        let coalesce = self.config.group_heartbeat_window_ms > 0;
+        let stepping_aside = Arc::new(AtomicBool::new(false));
+        let network_stepping_aside = stepping_aside.clone();
+        let network = GroupNetwork::start_in_arbiter(&arbiter, move |_| GroupNetwork {
+            group: group,
+            net: net,
+            groups: groups,
+            coalesce: coalesce,
+            stepping_aside: network_stepping_aside,
+        });
+        let raft_storage = storage.clone();
+        let raft_mailbox = self.config.mailboxes.raft;
+        let raft = Raft::start_in_arbiter(&arbiter, move |ctx| {
+            ctx.set_mailbox_capacity(raft_mailbox);
+            Raft::new(id, config, network.clone(), raft_storage, network.recipient())
+        });
+
+        info!("Node {} started its replica of group {} with members {:?}", self.id, group, members);
+        if init {
+            raft.do_send(InitWithConfig::new(members));
+        }
+
+        self.replicas.insert(group, Replica {
+            raft: raft,
+            storage: storage,
+            arbiter: arbiter,
+            metrics: None,
+            frozen: frozen,
+            stepping_aside: stepping_aside,
+        });
+        Ok(())
+    }
+
+    /// Start the replicas of the groups whose log was persisted in the data dir, they rejoin
+    /// with the membership of their hard state.
+    fn recover_replicas(&mut self, ctx: &mut Context<Self>) {
+        let data_dir = match self.config.data_dir {
+            Some(ref data_dir) => PathBuf::from(data_dir).join("groups"),
+            None => return,
+        };
+
+        for group in persisted_groups(&data_dir) {
+            info!("Node {} recovering its replica of group {}", self.id, group);
+            if let Err(err) = self.start_replica(group, vec![self.id], false, ctx) {
+                error!("Node {} failed to recover group {}: {}", self.id, group, err);
+            }
+        }
+    }
+
+    fn stop_replica(&mut self, group: GroupId) -> bool {
+        match self.replicas.remove(&group) {
+            Some(replica) => {
+                info!("Node {} stopped its replica of group {}", self.id, group);
+                replica.arbiter.stop();
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn leader(&self, group: GroupId) -> Option<NodeId> {
+        self.replicas
+            .get(&group)
+            .and_then(|replica| replica.metrics.as_ref())
+            .and_then(|metrics| metrics.current_leader)
+    }
+
+    /// Members of a group according to its local replica.
+    fn members(&self, group: GroupId) -> Vec<NodeId> {
+        self.replicas
+            .get(&group)
+            .and_then(|replica| replica.metrics.as_ref())
+            .map(|metrics| metrics.membership_config.members.clone())
+            .unwrap_or_default()
+    }
+
+    /// Propose to the local replica of a group, which is its leader.
+    fn propose(&self, group: GroupId, data: MemoryStorageData, key: Option<String>) -> impl Future<Item = u64, Error = ProposeError> {
+        let data = StampedData {
+            hlc: self.clock.lock().unwrap().now(),
+            data: data,
+            key: key,
+        };
+        self.propose_stamped(group, data)
+    }
+
+    /// Like `propose`, with the entry stamped already.
+    fn propose_stamped(&self, group: GroupId, data: StampedData) -> impl Future<Item = u64, Error = ProposeError> {
+        let payload: Payload = Payload::new(EntryNormal { data: data }, ResponseMode::Applied);
+
+        self.replicas[&group].raft.send(payload).then(|res| match res {
+            Ok(Ok(ClientPayloadResponse::Applied { index, .. }))
+            | Ok(Ok(ClientPayloadResponse::Committed { index })) => Ok(index),
+            Ok(Err(ClientError::ForwardToLeader { leader, .. })) => Err(ProposeError::NotLeader { leader_hint: leader }),
+            Ok(Err(ClientError::Application(ref err))) if err.fault.is_some() => Err(ProposeError::Storage(err.clone())),
+            Ok(Err(ClientError::Application(_))) => Err(ProposeError::Rejected),
+            _ => Err(ProposeError::Failed),
+        })
+    }
+
+    /// Send the heartbeats held for a peer as one frame. Peers which don't know the frame yet,
+    /// during a rolling upgrade, get them one by one.
+    fn flush_heartbeats(&mut self, peer: NodeId, ctx: &mut Context<Self>) {
+        let queued = match self.heartbeats.remove(&peer) {
+            Some(queued) if !queued.is_empty() => queued,
+            _ => return,
+        };
+        self.heartbeat_stats.heartbeats += queued.len() as u64;
+        self.heartbeat_stats.frames += 1;
+
+        let (rpcs, senders): (Vec<_>, Vec<_>) = queued.into_iter().map(|beat| (beat.rpc, beat.tx)).unzip();
+        // heartbeats carry no entries, the fallback copies them field by field
+        let fallback = rpcs
+            .iter()
+            .map(|rpc| GroupRpc {
+                group: rpc.group,
+                msg: AppendEntriesRequest {
+                    target: rpc.msg.target,
+                    term: rpc.msg.term,
+                    leader_id: rpc.msg.leader_id,
+                    prev_log_index: rpc.msg.prev_log_index,
+                    prev_log_term: rpc.msg.prev_log_term,
+                    entries: Vec::new(),
+                    leader_commit: rpc.msg.leader_commit,
+                },
+            })
+            .collect::<Vec<_>>();
+
+        fut::wrap_future::<_, Self>(self.send_to_peer(peer, GroupHeartbeats(rpcs)))
+            .then(move |res, act: &mut Self, _| match res {
+                Ok(Ok(responses)) => {
+                    for (tx, res) in senders.into_iter().zip(responses.into_iter()) {
+                        let _ = tx.send(res);
+                    }
+                    fut::Either::A(fut::ok(()))
+                }
+                _ => {
+                    let sends = fallback.into_iter().zip(senders.into_iter()).map(|(rpc, tx)| {
+                        act.send_to_peer(peer, rpc).then(move |res| {
+                            let _ = tx.send(res.and_then(|res| res));
+                            Ok::<_, ()>(())
+                        })
+                    });
+                    fut::Either::B(fut::wrap_future(future::join_all(sends.collect::<Vec<_>>()).map(|_| ())))
+                }
+            })
+            .spawn(ctx);
+    }
+
+    /// Send a message to the given peer, `Err` when it isn't connected.
+    fn send_to_peer<M>(&self, peer: NodeId, msg: M) -> impl Future<Item = M::Result, Error = ()>
+    where
+        M: RemoteMessage + 'static,
+        M::Result: Send + Serialize + DeserializeOwned,
+    {
+        self.net
+            .send(GetNodeById(peer))
+            .map_err(|_| ())
+            .and_then(|node| node)
+            .and_then(move |node| node.send(SendRemoteMessage(msg)).map_err(|_| ()))
+    }
+}
+
+impl RaftGroups {
+    /// One round of the rebalancer. Every node runs it, only the cluster leader moves
+    /// anything, after gathering the loads of the groups from their leaders.
+    fn rebalance(&mut self, ctx: &mut Context<Self>) {
+        let config = match self.config.group_rebalance {
+            Some(ref config) => config.clone(),
+            None => return,
+        };
+        if self.moving {
+            return;
+        }
+
+        self.moving = true;
+        let (id, net) = (self.id, self.net.clone());
+        let round = fut::wrap_future::<_, Self>(self.net.send(GetClusterStatus))
+            .map_err(|_, _, _| ())
+            .and_then(|res, _, _| fut::result(res))
+            .and_then(move |status, _, _| {
+                let nodes = match status.raft {
+                    Some(ref raft) if raft.leader == Some(id) => raft.members.clone(),
+                    _ => return fut::Either::A(fut::err(())),
+                };
+
+                let gather = ScatterGather {
+                    msg: GetGroupLoads,
+                    nodes: Some(nodes.clone()),
+                    deadline: REBALANCE_GATHER_DEADLINE,
+                };
+                fut::Either::B(
+                    fut::wrap_future(net.send(gather))
+                        .map_err(|_, _, _| ())
+                        .and_then(|res, _, _| fut::result(res))
+                        .map(move |answers, _, _| (nodes, answers)),
+                )
+            })
+            .and_then(move |(nodes, answers), act: &mut Self, _| {
+                // a group two nodes claim to lead after a partition is counted once
+                let loads = answers
+                    .into_iter()
+                    .filter_map(|(_, res)| res.ok())
+                    .filter_map(|res| res.ok())
+                    .flatten()
+                    .map(|load| (load.group, load))
+                    .collect::<BTreeMap<_, _>>()
+                    .into_iter()
+                    .map(|(_, load)| load)
+                    .collect::<Vec<_>>();
+                let rates = act.traffic.sample(&loads, clock::now());
+
+                match rebalance::plan(&config, &nodes, &loads, &rates) {
+                    Some(shard_move) => {
+                        let leader = loads.iter().find(|load| load.group == shard_move.group()).map(|load| load.leader);
+                        fut::Either::A(act.execute_move(shard_move, leader.unwrap_or(id)))
+                    }
+                    None => fut::Either::B(fut::ok(())),
+                }
+            })
+            .then(|_, act: &mut Self, _| {
+                act.moving = false;
+                fut::ok(())
+            });
+        ctx.spawn(round);
+    }
+
+    /// Run a move of the rebalancer through the group's `leader`, recording its progress.
+    fn execute_move(&mut self, shard_move: ShardMove, leader: NodeId) -> Box<dyn ActorFuture<Actor = Self, Item = (), Error = ()>> {
+        let (group, from, to, leadership) = match shard_move {
+            ShardMove::Replica { group, from, to } => (group, from, to, false),
+            ShardMove::Leader { group, from, to } => (group, from, to, true),
+        };
+        info!("Node {} rebalancing groups: {:?}", self.id, shard_move);
+        let events = self.events.clone();
+        let record = move |progress| {
+            events.record(EventKind::ShardMove {
+                group: group,
+                from: from,
+                to: to,
+                leadership: leadership,
+                progress: progress,
+            })
+        };
+        record(MoveProgress::Started);
+
+        let moved: Box<dyn Future<Item = (), Error = String>> = if leadership {
+            Box::new(
+                self.send_to_peer(from, TransferGroupLeadership(group))
+                    .map_err(move |_| format!("node {} is unreachable", from))
+                    .and_then(|res| res.map(|_| ()).map_err(|err| format!("{:?}", err))),
+            )
+        } else {
+            // the added replica catches up as a non-voter, through a snapshot when the log
+            // was compacted, before the joint change removes the old one, which is stopped
+            let change = ChangeGroupMembership {
+                group: group,
+                add: vec![to],
+                remove: vec![from],
+            };
+            let leave = self.send_to_peer(from, LeaveGroup(group));
+            Box::new(
+                self.send_to_peer(leader, change)
+                    .map_err(move |_| format!("leader {} is unreachable", leader))
+                    .and_then(|res| res.map_err(|err| format!("{:?}", err)))
+                    .and_then(move |_| leave.map_err(move |_| format!("node {} didn't leave the group", from))),
+            )
+        };
+
+        Box::new(fut::wrap_future::<_, Self>(moved).then(move |res, act: &mut Self, _| {
+            match res {
+                Ok(()) => {
+                    info!("Node {} moved {:?}", act.id, shard_move);
+                    record(MoveProgress::Completed);
+                }
+                Err(err) => {
+                    warn!("Node {} failed to move {:?}: {}", act.id, shard_move, err);
+                    record(MoveProgress::Failed);
+                }
+            }
+            fut::ok(())
+        }))
+    }
+
+    /// Resolve with the leader elected while this node stepped aside as leader of `group`.
+    fn await_group_successor(&self, group: GroupId, deadline: Instant) -> Box<dyn ActorFuture<Actor = Self, Item = NodeId, Error = ProposeError>> {
+        match self.leader(group) {
+            Some(leader) if leader != self.id => return Box::new(fut::ok(leader)),
+            _ => {}
+        }
+
+        if clock::now() >= deadline {
+            warn!("No member of group {} took over from {} in time", group, self.id);
+            return Box::new(fut::err(ProposeError::Failed));
+        }
+
+        Box::new(
+            fut::wrap_future::<_, Self>(clock::delay(STEP_ASIDE_POLL))
+                .map_err(|_, _, _| ProposeError::Failed)
+                .and_then(move |_, act: &mut Self, _| act.await_group_successor(group, deadline)),
+        )
+    }
+}
+
+impl Actor for RaftGroups {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        let mut registry = self.registry.write().unwrap();
+
+        registry.register::<GroupRpc<AppendEntriesRequest<StampedData>>, _>(ctx.address());
+        registry.register::<GroupRpc<VoteRequest>, _>(ctx.address());
+        registry.register::<GroupRpc<InstallSnapshotRequest>, _>(ctx.address());
+        registry.register::<GroupHeartbeats, _>(ctx.address());
+        registry.register::<JoinGroup, _>(ctx.address());
+        registry.register::<LeaveGroup, _>(ctx.address());
+        registry.register::<GroupProposal, _>(ctx.address());
+        registry.register::<ChangeGroupMembership, _>(ctx.address());
+        registry.register::<ExportGroupCheckpoint, _>(ctx.address());
+        registry.register::<IsServingGroup, _>(ctx.address());
+        registry.register::<RestoreGroupEntries, _>(ctx.address());
+        registry.register::<GetGroupLoads, _>(ctx.address());
+        registry.register::<TransferGroupLeadership, _>(ctx.address());
+        drop(registry);
+
+        self.recover_replicas(ctx);
+
+        if let Some(ref rebalance) = self.config.group_rebalance {
+            ctx.run_interval(Duration::from_secs(rebalance.interval_secs), |act, ctx| act.rebalance(ctx));
+        }
+    }
+}
+
+impl<M, R> Handler<GroupRpc<M>> for RaftGroups
+where
+    M: Message<Result = Result<R, ()>> + Send + 'static,
+    R: Send + 'static,
+    GroupRaft: Handler<M>,
+    <GroupRaft as Actor>::Context: ToEnvelope<GroupRaft, M>,
+{
+    type Result = Response<R, ()>;
+
+    fn handle(&mut self, msg: GroupRpc<M>, _ctx: &mut Context<Self>) -> Self::Result {
+        match self.replicas.get(&msg.group) {
+            Some(replica) => Response::fut(replica.raft.send(msg.msg).map_err(|_| ()).and_then(|res| res)),
+            // not a member of the group (anymore), the sender retries or gives up
+            None => Response::reply(Err(())),
+        }
+    }
+}
+
+impl Handler<QueueHeartbeat> for RaftGroups {
+    type Result = ();
+
+    fn handle(&mut self, msg: QueueHeartbeat, ctx: &mut Context<Self>) {
+        let peer = msg.peer;
+        let queued = self.heartbeats.entry(peer).or_insert_with(Vec::new);
+        queued.push(msg);
+
+        // the first heartbeat of a window schedules the frame
+        if queued.len() == 1 {
+            let window = Duration::from_millis(self.config.group_heartbeat_window_ms);
+            ctx.run_later(window, move |act, ctx| act.flush_heartbeats(peer, ctx));
+        }
+    }
+}
+
+impl Handler<GroupHeartbeats> for RaftGroups {
+    type Result = Response<Vec<Result<AppendEntriesResponse, ()>>, ()>;
+
+    fn handle(&mut self, msg: GroupHeartbeats, _ctx: &mut Context<Self>) -> Self::Result {
+        let beats = msg
+            .0
+            .into_iter()
+            .map(|rpc| match self.replicas.get(&rpc.group) {
+                Some(replica) => Either::A(
+                    replica
+                        .raft
+                        .send(rpc.msg)
+                        .then(|res| Ok(res.map_err(|_| ()).and_then(|res| res))),
+                ),
+                // not a member of the group (anymore), only this heartbeat fails
+                None => Either::B(future::ok(Err(()))),
+            })
+            .collect::<Vec<_>>();
+
+        Response::fut(future::join_all(beats))
+    }
+}
+
+/// Heartbeats sent by the groups of this node and the frames they took.
+pub struct GetHeartbeatStats;
+
+impl Message for GetHeartbeatStats {
+    type Result = HeartbeatStats;
+}
+
+impl Handler<GetHeartbeatStats> for RaftGroups {
+    type Result = MessageResult<GetHeartbeatStats>;
+
+    fn handle(&mut self, _: GetHeartbeatStats, _ctx: &mut Context<Self>) -> Self::Result {
+        MessageResult(self.heartbeat_stats.clone())
+    }
+}
+
+#[derive(Message)]
+struct GroupMetrics(GroupId, RaftMetrics);
+
+impl Handler<GroupMetrics> for RaftGroups {
+    type Result = ();
+
+    fn handle(&mut self, msg: GroupMetrics, _ctx: &mut Context<Self>) {
+        if let Some(replica) = self.replicas.get_mut(&msg.0) {
+            replica.metrics = Some(msg.1);
+        }
+    }
+}
+
+impl Handler<StorageFaulted> for RaftGroups {
+    type Result = ();
+
+    fn handle(&mut self, msg: StorageFaulted, _ctx: &mut Context<Self>) {
+        error!("CRITICAL: the storage of a raft group on node {} faulted: {:?}", self.id, msg.0);
+        self.events.alert(format!("storage of a raft group faulted: {:?}", msg.0));
+    }
+}
+
+/// Create a raft group with the given members, this node included. Every member starts its
+/// replica and the group elects its leader on its own.
+pub struct CreateGroup {
+    pub group: GroupId,
+    pub members: Vec<NodeId>,
+}
+
+impl Message for CreateGroup {
+    type Result = Result<(), ProposeError>;
+}
+
+impl Handler<CreateGroup> for RaftGroups {
+    type Result = ResponseActFuture<Self, (), ProposeError>;
+
+    fn handle(&mut self, msg: CreateGroup, ctx: &mut Context<Self>) -> Self::Result {
+        if !msg.members.contains(&self.id) {
+            return Box::new(fut::err(ProposeError::Rejected));
+        }
+
+        if let Err(err) = self.start_replica(msg.group, msg.members.clone(), true, ctx) {
+            error!("Node {} failed to start group {}: {}", self.id, msg.group, err);
+            return Box::new(fut::err(ProposeError::Failed));
+        }
+
+        let joins = msg
+            .members
+            .iter()
+            .filter(|id| **id != self.id)
+            .map(|id| {
+                let join = JoinGroup {
+                    group: msg.group,
+                    members: msg.members.clone(),
+                    init: true,
+                    sealed: false,
+                };
+                self.send_to_peer(*id, join)
+            })
+            .collect::<Vec<_>>();
+
+        Box::new(
+            fut::wrap_future(futures::future::join_all(joins))
+                .map_err(|_, _, _| ProposeError::Failed)
+                .map(|_, _, _| ()),
+        )
+    }
+}
+
+/// Stop the replicas of a group on all of its members, its data stays on disk.
+pub struct RemoveGroup(pub GroupId);
+
+impl Message for RemoveGroup {
+    type Result = Result<(), ProposeError>;
+}
+
+impl Handler<RemoveGroup> for RaftGroups {
+    type Result = Result<(), ProposeError>;
+
+    fn handle(&mut self, msg: RemoveGroup, _ctx: &mut Context<Self>) -> Self::Result {
+        let members = self.members(msg.0);
+
+        if !self.stop_replica(msg.0) {
+            return Err(ProposeError::Rejected);
+        }
+
+        for id in members.into_iter().filter(|id| *id != self.id) {
+            Arbiter::spawn(self.send_to_peer(id, LeaveGroup(msg.0)).map(|_| ()));
+        }
+        Ok(())
+    }
+}
+
+/// Start the local replica of a group, sent by the node creating the group or adding this
+/// node to it.
+#[derive(Serialize, Deserialize)]
+pub struct JoinGroup {
+    pub group: GroupId,
+    pub members: Vec<NodeId>,
+    /// Whether the group is new, otherwise the replica waits to be added by its leader
+    pub init: bool,
+    /// Whether the group starts sealed, until an imported checkpoint was restored
+    #[serde(default)]
+    pub sealed: bool,
+}
+
+impl Message for JoinGroup {
+    type Result = ();
+}
+
+impl RemoteMessage for JoinGroup {
+    fn type_id() -> &'static str {
+        "JoinGroup"
+    }
+}
+
+impl Handler<JoinGroup> for RaftGroups {
+    type Result = ();
+
+    fn handle(&mut self, msg: JoinGroup, ctx: &mut Context<Self>) {
+        let members = if msg.init { msg.members } else { vec![self.id] };
+        if let Err(err) = self.start_replica(msg.group, members, msg.init, ctx) {
+            error!("Node {} failed to join group {}: {}", self.id, msg.group, err);
+            return;
+        }
+        if msg.sealed {
+            self.replicas[&msg.group].frozen.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct LeaveGroup(pub GroupId);
+
+impl Message for LeaveGroup {
+    type Result = ();
+}
+
+impl RemoteMessage for LeaveGroup {
+    fn type_id() -> &'static str {
+        "LeaveGroup"
+    }
+}
+
+impl Handler<LeaveGroup> for RaftGroups {
+    type Result = ();
+
+    fn handle(&mut self, msg: LeaveGroup, _ctx: &mut Context<Self>) {
+        self.stop_replica(msg.0);
+    }
+}
+
+/// Propose a command to a group from any of its members, answered with the index it was
+/// applied at.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct GroupProposal {
+    pub group: GroupId,
+    pub proposal: ClientProposal,
+}
+
+impl Message for GroupProposal {
+    type Result = Result<u64, ProposeError>;
+}
+
+impl RemoteMessage for GroupProposal {
+    fn type_id() -> &'static str {
+        "GroupProposal"
+    }
+}
+
+impl Handler<GroupProposal> for RaftGroups {
+    type Result = ResponseActFuture<Self, u64, ProposeError>;
+
+    fn handle(&mut self, msg: GroupProposal, _ctx: &mut Context<Self>) -> Self::Result {
+        let leader = match self.leader(msg.group) {
+            Some(leader) => leader,
+            None => return Box::new(fut::err(ProposeError::NotLeader { leader_hint: None })),
+        };
+
+        if leader != self.id {
+            return Box::new(fut::wrap_future(
+                self.send_to_peer(leader, msg)
+                    .then(move |res| res.unwrap_or(Err(ProposeError::NotLeader { leader_hint: Some(leader) }))),
+            ));
+        }
+
+        // a sealed group is being moved, its checkpoint has every write it took
+        if self.replicas[&msg.group].frozen.load(Ordering::SeqCst) {
+            debug!("Rejecting proposal to group {}, it's sealed for a move", msg.group);
+            return Box::new(fut::err(ProposeError::Rejected));
+        }
+
+        Box::new(fut::wrap_future(self.propose(msg.group, msg.proposal.data, msg.proposal.key)))
+    }
+}
+
+/// Change the members of a group through its leader. Added nodes start a replica which
+/// catches up as a non-voter, removed nodes keep theirs until the group is removed.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ChangeGroupMembership {
+    pub group: GroupId,
+    pub add: Vec<NodeId>,
+    pub remove: Vec<NodeId>,
+}
+
+impl Message for ChangeGroupMembership {
+    type Result = Result<(), ProposeError>;
+}
+
+impl RemoteMessage for ChangeGroupMembership {
+    fn type_id() -> &'static str {
+        "ChangeGroupMembership"
+    }
+}
+
+impl Handler<ChangeGroupMembership> for RaftGroups {
+    type Result = ResponseActFuture<Self, (), ProposeError>;
+
+    fn handle(&mut self, msg: ChangeGroupMembership, _ctx: &mut Context<Self>) -> Self::Result {
+        let leader = match self.leader(msg.group) {
+            Some(leader) => leader,
+            None => return Box::new(fut::err(ProposeError::NotLeader { leader_hint: None })),
+        };
+
+        if leader != self.id {
+            return Box::new(fut::wrap_future(
+                self.send_to_peer(leader, msg)
+                    .then(move |res| res.unwrap_or(Err(ProposeError::NotLeader { leader_hint: Some(leader) }))),
+            ));
+        }
+
+        for id in msg.add.iter() {
+            let join = JoinGroup {
+                group: msg.group,
+                members: Vec::new(),
+                init: false,
+            };
+            Arbiter::spawn(self.send_to_peer(*id, join).map(|_| ()));
+        }
+
+        let raft = self.replicas[&msg.group].raft.clone();
+        Box::new(
+            fut::wrap_future(raft.send(ProposeConfigChange::new(msg.add, msg.remove))).then(|res, _, _| {
+                fut::result(match res {
+                    Ok(Ok(_)) | Ok(Err(ProposeConfigChangeError::Noop)) => Ok(()),
+                    Ok(Err(ProposeConfigChangeError::NodeNotLeader(leader))) => Err(ProposeError::NotLeader { leader_hint: leader }),
+                    Ok(Err(ProposeConfigChangeError::InoperableConfig)) => Err(ProposeError::Rejected),
+                    _ => Err(ProposeError::Failed),
+                })
+            }),
+        )
+    }
+}
+
+/// Load of the groups led by a node, gathered by the rebalancer of the cluster leader.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct GetGroupLoads;
+
+impl Message for GetGroupLoads {
+    type Result = Result<Vec<GroupLoad>, ()>;
+}
+
+impl RemoteMessage for GetGroupLoads {
+    fn type_id() -> &'static str {
+        "GetGroupLoads"
+    }
+}
+
+impl Handler<GetGroupLoads> for RaftGroups {
+    type Result = Result<Vec<GroupLoad>, ()>;
+
+    fn handle(&mut self, _: GetGroupLoads, _ctx: &mut Context<Self>) -> Self::Result {
+        let id = self.id;
+        Ok(self
+            .replicas
+            .iter()
+            .filter_map(|(group, replica)| replica.metrics.as_ref().map(|metrics| (*group, metrics)))
+            .filter(|&(_, metrics)| metrics.current_leader == Some(id))
+            .map(|(group, metrics)| GroupLoad {
+                group: group,
+                leader: id,
+                members: metrics.membership_config.members.clone(),
+                last_log_index: metrics.last_log_index,
+            })
+            .collect())
+    }
+}
+
+/// Hand the leadership of a group over to another member: the leader's appends fail so the
+/// first caught up member to time out takes over, see `TransferLeadership` of the cluster
+/// raft. Answers with the new leader.
+#[derive(Serialize, Deserialize)]
+pub struct TransferGroupLeadership(pub GroupId);
+
+impl Message for TransferGroupLeadership {
+    type Result = Result<NodeId, ProposeError>;
+}
+
+impl RemoteMessage for TransferGroupLeadership {
+    fn type_id() -> &'static str {
+        "TransferGroupLeadership"
+    }
+}
+
+impl Handler<TransferGroupLeadership> for RaftGroups {
+    type Result = ResponseActFuture<Self, NodeId, ProposeError>;
+
+    fn handle(&mut self, msg: TransferGroupLeadership, _ctx: &mut Context<Self>) -> Self::Result {
+        let group = msg.0;
+        let leader = self.leader(group);
+        if leader != Some(self.id) {
+            return Box::new(fut::err(ProposeError::NotLeader { leader_hint: leader }));
+        }
+
+        // no other member could take over, or this node is stepping aside already
+        let stepping_aside = self.replicas[&group].stepping_aside.clone();
+        if self.members(group).len() < 2 || stepping_aside.swap(true, Ordering::SeqCst) {
+            return Box::new(fut::err(ProposeError::Rejected));
+        }
+
+        info!("Node {} stepping aside as leader of group {}", self.id, group);
+        let deadline = clock::now() + Duration::from_millis(self.config.raft_timings.election_timeout_max * 3);
+        Box::new(self.await_group_successor(group, deadline).then(move |res, _, _| {
+            stepping_aside.store(false, Ordering::SeqCst);
+            fut::result(res)
+        }))
+    }
+}
+
+/// Raft state of the local replicas, by group.
+pub struct GetGroupMetrics;
+
+impl Message for GetGroupMetrics {
+    type Result = Result<BTreeMap<GroupId, Option<RaftState>>, ()>;
+}
+
+impl Handler<GetGroupMetrics> for RaftGroups {
+    type Result = Result<BTreeMap<GroupId, Option<RaftState>>, ()>;
+
+    fn handle(&mut self, _: GetGroupMetrics, _ctx: &mut Context<Self>) -> Self::Result {
+        Ok(self
+            .replicas
+            .iter()
+            .map(|(group, replica)| (*group, replica.metrics.as_ref().map(RaftState::from)))
+            .collect())
+    }
+}
+
+/// A group's state as of its seal, to move the group to other nodes or another cluster.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct GroupCheckpoint {
+    pub group: GroupId,
+    /// Members of the group when it was sealed
+    pub members: Vec<NodeId>,
+    /// Index of the entry sealing the group, it took no write after it
+    pub sealed_index: u64,
+    /// Applied state machine through the watermark `state.index`
+    pub state: StateDump,
+    /// Sha256 of the fields above
+    pub checksum: String,
+}
+
+impl GroupCheckpoint {
+    fn new(group: GroupId, members: Vec<NodeId>, sealed_index: u64, state: StateDump) -> GroupCheckpoint {
+        let mut checkpoint = GroupCheckpoint {
+            group: group,
+            members: members,
+            sealed_index: sealed_index,
+            state: state,
+            checksum: String::new(),
+        };
+        checkpoint.checksum = checkpoint.digest();
+        checkpoint
+    }
+
+    fn digest(&self) -> String {
+        let mut sha = Sha256::new();
+        sha.input(&self.group.to_be_bytes());
+        for member in self.members.iter() {
+            sha.input(&member.to_be_bytes());
+        }
+        sha.input(&self.sealed_index.to_be_bytes());
+        sha.input(&serde_json::to_vec(&self.state).unwrap_or_default());
+        sha.result_str()
+    }
+
+    /// Whether the state ends with the entry sealing the group.
+    fn is_sealed(&self) -> bool {
+        self.state.index >= self.sealed_index
+            && self
+                .state
+                .entries
+                .iter()
+                .any(|entry| entry.index == self.sealed_index && entry.data == MemoryStorageData::Freeze(true))
+    }
+}
+
+/// Seal a group for a move and export its checkpoint, through the group's leader. Once
+/// sealed the group rejects every proposal, on all of its replicas and future leaders.
+/// Exporting a sealed group again answers the same state.
+#[derive(Serialize, Deserialize)]
+pub struct ExportGroupCheckpoint(pub GroupId);
+
+impl Message for ExportGroupCheckpoint {
+    type Result = Result<GroupCheckpoint, ProposeError>;
+}
+
+impl RemoteMessage for ExportGroupCheckpoint {
+    fn type_id() -> &'static str {
+        "ExportGroupCheckpoint"
+    }
+}
+
+impl Handler<ExportGroupCheckpoint> for RaftGroups {
+    type Result = ResponseActFuture<Self, GroupCheckpoint, ProposeError>;
+
+    fn handle(&mut self, msg: ExportGroupCheckpoint, _ctx: &mut Context<Self>) -> Self::Result {
+        let group = msg.0;
+        let leader = match self.leader(group) {
+            Some(leader) => leader,
+            None => return Box::new(fut::err(ProposeError::NotLeader { leader_hint: None })),
+        };
+
+        if leader != self.id {
+            return Box::new(fut::wrap_future(
+                self.send_to_peer(leader, msg)
+                    .then(move |res| res.unwrap_or(Err(ProposeError::NotLeader { leader_hint: Some(leader) }))),
+            ));
+        }
+
+        let members = self.members(group);
+        let storage = self.replicas[&group].storage.clone();
+        info!("Node {} is sealing group {} for a move", self.id, group);
+
+        Box::new(
+            fut::wrap_future(self.propose(group, MemoryStorageData::Freeze(true), None))
+                .and_then(move |sealed_index, _, _| {
+                    fut::wrap_future(storage.send(GetStateDump))
+                        .map_err(|_, _, _| ProposeError::Failed)
+                        .and_then(|res, _, _| fut::result(res.map_err(|_| ProposeError::Failed)))
+                        .map(move |state, act: &mut Self, _| {
+                            info!("Node {} exported group {} sealed at index {}", act.id, group, sealed_index);
+                            GroupCheckpoint::new(group, members, sealed_index, state)
+                        })
+                }),
+        )
+    }
+}
+
+/// Whether the node hosts a replica of the group which isn't sealed.
+#[derive(Serialize, Deserialize)]
+pub struct IsServingGroup(pub GroupId);
+
+impl Message for IsServingGroup {
+    type Result = bool;
+}
+
+impl RemoteMessage for IsServingGroup {
+    fn type_id() -> &'static str {
+        "IsServingGroup"
+    }
+}
+
+impl Handler<IsServingGroup> for RaftGroups {
+    type Result = bool;
+
+    fn handle(&mut self, msg: IsServingGroup, _ctx: &mut Context<Self>) -> bool {
+        self.replicas
+            .get(&msg.0)
+            .map_or(false, |replica| !replica.frozen.load(Ordering::SeqCst))
+    }
+}
+
+/// Start a group from an exported checkpoint with the given members, this node included.
+/// The group stays sealed until the entries of the checkpoint were proposed to it, in chunks
+/// and with the HLCs they had on the source, once it elected its leader.
+pub struct ImportGroupCheckpoint {
+    pub checkpoint: GroupCheckpoint,
+    pub members: Vec<NodeId>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ImportedCheckpoint {
+    pub group: GroupId,
+    /// Watermark of the checkpoint on the source
+    pub source_index: u64,
+    /// Index the group was opened at in the new group, after the restored entries
+    pub index: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum CheckpointError {
+    /// The checksum doesn't match the contents
+    Corrupt,
+    /// The checkpoint doesn't end with the entry sealing the group
+    Unsealed,
+    /// Members of the source group reachable from this node still serve it, remove the group
+    /// or export it again before importing
+    SourceServing(Vec<NodeId>),
+    /// The group is already hosted by this node, or the members don't include it
+    Rejected,
+    Propose(ProposeError),
+}
+
+impl Message for ImportGroupCheckpoint {
+    type Result = Result<ImportedCheckpoint, CheckpointError>;
+}
+
+impl Handler<ImportGroupCheckpoint> for RaftGroups {
+    type Result = ResponseActFuture<Self, ImportedCheckpoint, CheckpointError>;
+
+    fn handle(&mut self, msg: ImportGroupCheckpoint, _ctx: &mut Context<Self>) -> Self::Result {
+        let checkpoint = msg.checkpoint;
+        let group = checkpoint.group;
+        if checkpoint.digest() != checkpoint.checksum {
+            return Box::new(fut::err(CheckpointError::Corrupt));
+        }
+        if !checkpoint.is_sealed() {
+            return Box::new(fut::err(CheckpointError::Unsealed));
+        }
+        if !msg.members.contains(&self.id) || self.replicas.contains_key(&group) {
+            return Box::new(fut::err(CheckpointError::Rejected));
+        }
+
+        // source members in another cluster are unreachable, they were sealed by the export
+        let checks = checkpoint
+            .members
+            .iter()
+            .filter(|id| **id != self.id)
+            .map(|id| {
+                let id = *id;
+                self.send_to_peer(id, IsServingGroup(group))
+                    .then(move |res| Ok::<_, ()>(if res.unwrap_or(false) { Some(id) } else { None }))
+            })
+            .collect::<Vec<_>>();
+        let members = msg.members;
+
+        Box::new(
+            fut::wrap_future(futures::future::join_all(checks))
+                .map_err(|_, _, _| CheckpointError::Propose(ProposeError::Failed))
+                .and_then(move |serving, act: &mut Self, ctx| {
+                    let serving = serving.into_iter().filter_map(|id| id).collect::<Vec<_>>();
+                    if !serving.is_empty() {
+                        return fut::Either::A(fut::err(CheckpointError::SourceServing(serving)));
+                    }
+                    if act.replicas.contains_key(&group) {
+                        return fut::Either::A(fut::err(CheckpointError::Rejected));
+                    }
+
+                    info!(
+                        "Node {} is importing group {} sealed at index {} with members {:?}",
+                        act.id, group, checkpoint.sealed_index, members
+                    );
+                    // sealed from the start on every member, so no write lands before the restore
+                    if let Err(err) = act.start_replica(group, members.clone(), true, ctx) {
+                        error!("Node {} failed to start imported group {}: {}", act.id, group, err);
+                        return fut::Either::A(fut::err(CheckpointError::Propose(ProposeError::Failed)));
+                    }
+                    act.replicas[&group].frozen.store(true, Ordering::SeqCst);
+                    for id in members.iter().filter(|id| **id != act.id) {
+                        let join = JoinGroup {
+                            group: group,
+                            members: members.clone(),
+                            init: true,
+                            sealed: true,
+                        };
+                        Arbiter::spawn(act.send_to_peer(*id, join).map(|_| ()));
+                    }
+
+                    let source_index = checkpoint.state.index;
+                    let chunks = restore_chunks(group, &checkpoint.checksum, checkpoint.state.entries, act.clock.lock().unwrap().now());
+                    fut::Either::B(
+                        fut::wrap_future(restore(ctx.address(), chunks))
+                            .map_err(|err, _, _| CheckpointError::Propose(err))
+                            .map(move |index, act: &mut Self, _| {
+                                info!("Node {} restored group {} at index {}", act.id, group, index);
+                                ImportedCheckpoint { group, source_index, index }
+                            }),
+                    )
+                }),
+        )
+    }
+}
+
+/// Entries of an imported checkpoint proposed to the group's leader with the HLCs they were
+/// stamped with on the source, in order. Proposed while the group is sealed.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RestoreGroupEntries {
+    pub group: GroupId,
+    /// Idempotency keys of the entries, a retried chunk skips those applied already
+    pub keys: Vec<String>,
+    pub entries: Vec<StampedData>,
+}
+
+impl Message for RestoreGroupEntries {
+    type Result = Result<u64, ProposeError>;
+}
+
+impl RemoteMessage for RestoreGroupEntries {
+    fn type_id() -> &'static str {
+        "RestoreGroupEntries"
+    }
+}
+
+impl Handler<RestoreGroupEntries> for RaftGroups {
+    type Result = ResponseActFuture<Self, u64, ProposeError>;
+
+    fn handle(&mut self, msg: RestoreGroupEntries, _ctx: &mut Context<Self>) -> Self::Result {
+        let leader = match self.leader(msg.group) {
+            Some(leader) => leader,
+            None => return Box::new(fut::err(ProposeError::NotLeader { leader_hint: None })),
+        };
+
+        if leader != self.id {
+            return Box::new(fut::wrap_future(
+                self.send_to_peer(leader, msg)
+                    .then(move |res| res.unwrap_or(Err(ProposeError::NotLeader { leader_hint: Some(leader) }))),
+            ));
+        }
+
+        // later writes to the group are stamped after the restored ones
+        if let Some(hlc) = msg.entries.iter().map(|entry| entry.hlc).max() {
+            self.clock.lock().unwrap().update(hlc);
+        }
+
+        // sent to raft in order, the last answer is the index of the last entry
+        let group = msg.group;
+        let proposals = msg
+            .entries
+            .into_iter()
+            .zip(msg.keys.into_iter())
+            .map(|(mut entry, key)| {
+                entry.key = Some(key);
+                self.propose_stamped(group, entry)
+            })
+            .collect::<Vec<_>>();
+
+        Box::new(fut::wrap_future(
+            futures::future::join_all(proposals).map(|indexes| indexes.last().cloned().unwrap_or(0)),
+        ))
+    }
+}
+
+/// Split the entries of a checkpoint into chunks to restore, between a `Freeze(true)` keeping
+/// the group sealed through restarts and elections and the `Freeze(false)` opening it.
+fn restore_chunks(group: GroupId, checksum: &str, entries: Vec<StateDumpEntry>, now: Hlc) -> Vec<RestoreGroupEntries> {
+    let stamp = |data: MemoryStorageData, hlc: Hlc| StampedData {
+        hlc: hlc,
+        data: data,
+        key: None,
+    };
+    let sealed = entries
+        .into_iter()
+        .filter(|entry| match entry.data {
+            MemoryStorageData::Freeze(_) => false,
+            _ => true,
+        })
+        .map(|entry| stamp(entry.data, entry.hlc));
+    let all = std::iter::once(stamp(MemoryStorageData::Freeze(true), now))
+        .chain(sealed)
+        .chain(std::iter::once(stamp(MemoryStorageData::Freeze(false), now)));
+
+    let mut chunks: Vec<RestoreGroupEntries> = Vec::new();
+    let mut chunk_bytes = 0;
+    for (i, entry) in all.enumerate() {
+        let size = serde_json::to_vec(&entry.data).map(|data| data.len()).unwrap_or(0);
+        // an entry larger than a chunk goes alone, it was in a frame on the source
+        if chunks.is_empty() || (chunk_bytes > 0 && chunk_bytes + size > RESTORE_CHUNK_BYTES) {
+            chunks.push(RestoreGroupEntries {
+                group: group,
+                keys: Vec::new(),
+                entries: Vec::new(),
+            });
+            chunk_bytes = 0;
+        }
+        let chunk = chunks.last_mut().unwrap();
+        // the checksum covers the whole checkpoint, the position the entry in it
+        chunk.keys.push(format!("checkpoint/{}/{}", checksum, i));
+        chunk.entries.push(entry);
+        chunk_bytes += size;
+    }
+    chunks
+}
+
+/// Restore the chunks one after the other, waiting for the group to elect its leader.
+/// Resolves to the index of the last restored entry.
+fn restore(groups: Addr<RaftGroups>, chunks: Vec<RestoreGroupEntries>) -> Box<dyn Future<Item = u64, Error = ProposeError>> {
+    Box::new(futures::stream::iter_ok::<_, ProposeError>(chunks).fold(0, move |_, chunk| restore_chunk(groups.clone(), chunk, RESTORE_ATTEMPTS)))
+}
+
+fn restore_chunk(groups: Addr<RaftGroups>, chunk: RestoreGroupEntries, attempts: u32) -> Box<dyn Future<Item = u64, Error = ProposeError>> {
+    Box::new(groups.send(chunk.clone()).then(move |res| match res {
+        Ok(Err(ProposeError::NotLeader { .. })) if attempts > 1 => {
+            let retry = clock::delay(RESTORE_RETRY_INTERVAL)
+                .then(move |_| restore_chunk(groups, chunk, attempts - 1));
+            Box::new(retry) as Box<dyn Future<Item = u64, Error = ProposeError>>
+        }
+        Ok(res) => Box::new(futures::future::result(res)),
+        Err(_) => Box::new(futures::future::err(ProposeError::Failed)),
+    }))
+}
+
+/// Dir of the snapshots and log of a group's replica. Groups keep them next to those of the
+/// cluster raft, without a data dir in a temp dir of their own which a restart never reuses.
+fn replica_dir(data_dir: Option<&str>, id: NodeId, group: GroupId) -> io::Result<PathBuf> {
+    match data_dir {
+        Some(data_dir) => {
+            let dir = PathBuf::from(data_dir).join("groups").join(group.to_string());
+            fs::create_dir_all(&dir)?;
+            Ok(dir)
+        }
+        None => tempfile::Builder::new()
+            .prefix(&format!("raftor-{}-group-{}-", id, group))
+            .tempdir()
+            .map(|dir| dir.into_path()),
+    }
+}
+
+/// Groups with a persisted log under the `groups` dir of the data dir.
+fn persisted_groups(dir: &Path) -> Vec<GroupId> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+
+    let mut groups = entries
+        .filter_map(Result::ok)
+        .filter(|entry| entry.path().join("log").is_dir())
+        .filter_map(|entry| entry.file_name().to_str().and_then(|name| name.parse::<GroupId>().ok()))
+        .collect::<Vec<_>>();
+    groups.sort();
+    groups
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replica_dir_without_data_dir_is_fresh() {
+        let first = replica_dir(None, 1, 7).unwrap();
+        let second = replica_dir(None, 1, 7).unwrap();
+        assert_ne!(first, second);
+        assert!(first.is_dir() && second.is_dir());
+
+        fs::remove_dir_all(first).unwrap();
+        fs::remove_dir_all(second).unwrap();
+    }
+
+    #[test]
+    fn replica_dir_is_kept_in_the_data_dir() {
+        let data_dir = tempfile::tempdir().unwrap();
+        let path = data_dir.path().to_str().unwrap();
+
+        let dir = replica_dir(Some(path), 1, 7).unwrap();
+        assert_eq!(dir, data_dir.path().join("groups").join("7"));
+        assert_eq!(replica_dir(Some(path), 1, 7).unwrap(), dir);
+    }
+
+    #[test]
+    fn only_groups_with_a_log_are_recovered() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("3").join("log")).unwrap();
+        fs::create_dir_all(dir.path().join("1").join("log")).unwrap();
+        // snapshots only, without the `persistent` feature
+        fs::create_dir_all(dir.path().join("2")).unwrap();
+        fs::create_dir_all(dir.path().join("imports").join("log")).unwrap();
+
+        assert_eq!(persisted_groups(dir.path()), vec![1, 3]);
+        assert!(persisted_groups(&dir.path().join("missing")).is_empty());
+    }
+
+    fn dump_entry(index: u64, physical: u64, data: MemoryStorageData) -> StateDumpEntry {
+        StateDumpEntry {
+            index: index,
+            term: 1,
+            hlc: Hlc { physical: physical, logical: 0 },
+            data: data,
+        }
+    }
+
+    #[test]
+    fn restore_is_fenced_and_keeps_the_hlcs() {
+        let entries = vec![
+            dump_entry(1, 10, MemoryStorageData::Add(2)),
+            dump_entry(2, 20, MemoryStorageData::Add(3)),
+            dump_entry(3, 30, MemoryStorageData::Freeze(true)),
+        ];
+        let now = Hlc { physical: 99, logical: 0 };
+        let chunks = restore_chunks(7, "sum", entries, now);
+        assert_eq!(chunks.len(), 1);
+
+        let restored = chunks[0].entries.iter().map(|entry| (entry.hlc.physical, entry.data.clone())).collect::<Vec<_>>();
+        assert_eq!(restored, vec![
+            (99, MemoryStorageData::Freeze(true)),
+            (10, MemoryStorageData::Add(2)),
+            (20, MemoryStorageData::Add(3)),
+            (99, MemoryStorageData::Freeze(false)),
+        ]);
+        assert_eq!(chunks[0].keys[1], "checkpoint/sum/1");
+    }
+
+    #[test]
+    fn restore_is_chunked_below_the_limit() {
+        // a quarter of a chunk each as JSON
+        let big = vec![1u8; RESTORE_CHUNK_BYTES / 8];
+        let entries = (1..=6)
+            .map(|index| dump_entry(index, index, MemoryStorageData::App(big.clone())))
+            .collect::<Vec<_>>();
+        let chunks = restore_chunks(7, "sum", entries, Hlc::default());
+
+        assert!(chunks.len() >= 2);
+        assert_eq!(chunks.iter().map(|chunk| chunk.entries.len()).sum::<usize>(), 8);
+        for chunk in chunks.iter() {
+            let bytes = chunk.entries.iter().map(|entry| serde_json::to_vec(&entry.data).unwrap().len()).sum::<usize>();
+            assert!(bytes <= RESTORE_CHUNK_BYTES);
+            assert_eq!(chunk.keys.len(), chunk.entries.len());
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use serde::{Deserialize, Serialize};
+use std::cmp;
+use std::sync::{Arc, Mutex};
+
+use crate::raft::timing::now_millis;
+
+/// Hybrid logical clock timestamp, ordered by physical time first.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Hlc {
+    /// Unix time in milliseconds
+    pub physical: u64,
+    pub logical: u32,
+}
+
+/// Hybrid logical clock of a node.
+///
+/// The leader stamps proposals with `now`, every node merges the stamps of the entries it
+/// applies with `update` so its clock never falls behind the timestamps it has seen, even
+/// when its wall clock does.
+#[derive(Default)]
+pub struct HybridClock {
+    last: Hlc,
+    /// Offset applied to the wall clock in milliseconds, only set to inject clock faults
+    skew_ms: i64,
+}
+
+pub type SharedClock = Arc<Mutex<HybridClock>>;
+
+impl HybridClock {
+    pub fn new() -> SharedClock {
+        Arc::new(Mutex::new(HybridClock::default()))
+    }
+
+    /// Jump the wall clock by the given offset in milliseconds, to test how the cluster copes
+    /// with a node whose clock is off.
+    pub fn skew(&mut self, offset_ms: i64) {
+        self.skew_ms = offset_ms;
+    }
+
+    fn physical(&self) -> u64 {
+        (now_millis() as i64 + self.skew_ms).max(0) as u64
+    }
+
+    /// Timestamp a local event.
+    pub fn now(&mut self) -> Hlc {
+        let physical = self.physical();
+
+        self.last = if physical > self.last.physical {
+            Hlc { physical: physical, logical: 0 }
+        } else {
+            Hlc { physical: self.last.physical, logical: self.last.logical + 1 }
+        };
+
+        self.last
+    }
+
+    /// Merge a timestamp received from another node.
+    pub fn update(&mut self, remote: Hlc) -> Hlc {
+        let physical = cmp::max(self.physical(), cmp::max(self.last.physical, remote.physical));
+
+        let logical = if physical == self.last.physical && physical == remote.physical {
+            cmp::max(self.last.logical, remote.logical) + 1
+        } else if physical == self.last.physical {
+            self.last.logical + 1
+        } else if physical == remote.physical {
+            remote.logical + 1
+        } else {
+            0
+        };
+
+        self.last = Hlc { physical: physical, logical: logical };
+        self.last
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn local_timestamps_increase() {
+        let mut clock = HybridClock::default();
+        let first = clock.now();
+        let second = clock.now();
+        assert!(second > first);
+    }
+
+    #[test]
+    fn stamps_from_a_clock_ahead_are_never_fallen_behind() {
+        let mut clock = HybridClock::default();
+        let remote = Hlc { physical: clock.now().physical + 60_000, logical: 7 };
+
+        assert_eq!(clock.update(remote), Hlc { physical: remote.physical, logical: 8 });
+        assert!(clock.now() > remote);
+    }
+
+    #[test]
+    fn skewed_clocks_stay_monotonic() {
+        let mut clock = HybridClock::default();
+        let before = clock.now();
+
+        // a clock jumping back keeps counting from the last timestamp
+        clock.skew(-60_000);
+        assert!(clock.now() > before);
+        assert_eq!(clock.now().physical, before.physical);
+    }
+}
@@ -0,0 +1,289 @@
+use serde_json::Value;
+use std::sync::{Arc, Mutex, RwLock};
+
+use crate::raft::app::{self, AppMetrics, AppStateMachine, Canary, ErasedStateMachine};
+use crate::raft::hlc::Hlc;
+use crate::raft::projection::{Projection, ProjectionSet};
+use crate::raft::storage::{Entry, MemoryStorageData, MemoryStorageError};
+
+/// Middleware around client proposals and applied entries.
+///
+/// Hooks run in registration order. `pre_propose` runs on the node receiving the proposal
+/// before it enters the log and may transform or reject it; `post_apply` runs on every node
+/// once an entry is applied to its state machine.
+pub trait RaftHook: Send + Sync {
+    /// Validate or transform a proposal, returning an error rejects it. Proposals of the HTTP
+    /// gateway and the JSON protocol name the client their token authenticates, see
+    /// `ConfigSchema::client_tokens`.
+    fn pre_propose(&self, _client: &str, data: MemoryStorageData) -> Result<MemoryStorageData, MemoryStorageError> {
+        Ok(data)
+    }
+
+    /// Observe an entry after it was applied, `hlc` is the timestamp the leader stamped it with.
+    /// Called once per command of a batch, with the index of the batch entry.
+    fn post_apply(&self, _index: u64, _hlc: Hlc, _data: &MemoryStorageData) {}
+}
+
+/// Copies log entries to long-term storage before they are truncated from the log.
+pub trait LogArchiver: Send + Sync {
+    /// Archive the given entries, returning once they are safely stored. Returning an error
+    /// keeps them in the log.
+    fn archive(&self, entries: &[Entry]) -> Result<(), String>;
+}
+
+/// Converts snapshots written by a newer state machine version into entries of this version.
+pub trait SnapshotMigrator: Send + Sync {
+    /// `snapshot` is the MessagePack encoded `(version, entries)` pair as written by `version`.
+    fn migrate(&self, version: u32, snapshot: &[u8]) -> Result<Vec<Entry>, String>;
+}
+
+/// Checks a command is well formed, returning the reason when it isn't.
+pub type Validator = Box<dyn Fn(&MemoryStorageData) -> Result<(), String> + Send + Sync>;
+
+/// Masks the sensitive fields of a command wherever entries are shown: debugged peer frames,
+/// the log inspection API and error logs. The log and the state machine keep the command.
+pub type Redactor = Box<dyn Fn(&MemoryStorageData) -> MemoryStorageData + Send + Sync>;
+
+/// Ordered chain of hooks, shared by the raft client and the storage.
+#[derive(Default)]
+pub struct HookChain {
+    hooks: Vec<Box<dyn RaftHook>>,
+    validators: Vec<Validator>,
+    redactor: Option<Redactor>,
+    archiver: Option<Arc<dyn LogArchiver>>,
+    migrator: Option<Arc<dyn SnapshotMigrator>>,
+    state_machine: Option<Arc<Mutex<Box<dyn ErasedStateMachine>>>>,
+    canary: Option<Arc<Mutex<Canary>>>,
+    app_metrics: AppMetrics,
+    projections: Arc<Mutex<ProjectionSet>>,
+}
+
+pub type Hooks = Arc<RwLock<HookChain>>;
+
+impl HookChain {
+    pub fn new() -> Hooks {
+        Arc::new(RwLock::new(HookChain::default()))
+    }
+
+    pub fn add<H: RaftHook + 'static>(&mut self, hook: H) {
+        self.hooks.push(Box::new(hook));
+    }
+
+    /// Add a validator, validators run on whichever node receives a proposal first, gateways
+    /// included, so malformed commands are rejected before they are forwarded or logged.
+    pub fn add_validator<F>(&mut self, validator: F)
+    where
+        F: Fn(&MemoryStorageData) -> Result<(), String> + Send + Sync + 'static,
+    {
+        self.validators.push(Box::new(validator));
+    }
+
+    /// Set the redactor applied to commands before they are shown, it's called once per command
+    /// of a batch.
+    pub fn set_redactor<F>(&mut self, redactor: F)
+    where
+        F: Fn(&MemoryStorageData) -> MemoryStorageData + Send + Sync + 'static,
+    {
+        self.redactor = Some(Box::new(redactor));
+    }
+
+    pub fn redact(&self, data: &MemoryStorageData) -> MemoryStorageData {
+        match (data, &self.redactor) {
+            (MemoryStorageData::Batch(commands), Some(_)) => {
+                MemoryStorageData::Batch(commands.iter().map(|command| self.redact(command)).collect())
+            }
+            (_, Some(redactor)) => redactor(data),
+            (_, None) => data.clone(),
+        }
+    }
+
+    /// Redact the commands of a JSON document, the values of its `data` fields, looking into
+    /// JSON encoded strings such as the payloads of peer frames.
+    pub fn redact_json(&self, value: &mut Value) {
+        if self.redactor.is_none() {
+            return;
+        }
+
+        match value {
+            Value::Object(fields) => {
+                for (name, field) in fields.iter_mut() {
+                    if name == "data" {
+                        if let Ok(data) = serde_json::from_value::<MemoryStorageData>(field.clone()) {
+                            *field = serde_json::to_value(self.redact(&data)).unwrap_or(Value::Null);
+                            continue;
+                        }
+                    }
+                    self.redact_json(field);
+                }
+            }
+            Value::Array(items) => {
+                for item in items.iter_mut() {
+                    self.redact_json(item);
+                }
+            }
+            Value::String(encoded) if encoded.starts_with('{') || encoded.starts_with('[') => {
+                if let Ok(mut nested) = serde_json::from_str::<Value>(encoded) {
+                    self.redact_json(&mut nested);
+                    *encoded = nested.to_string();
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// Set the archiver which must confirm entries before the log prefix can be truncated.
+    pub fn set_archiver<A: LogArchiver + 'static>(&mut self, archiver: A) {
+        self.archiver = Some(Arc::new(archiver));
+    }
+
+    pub fn archiver(&self) -> Option<Arc<dyn LogArchiver>> {
+        self.archiver.clone()
+    }
+
+    /// Set the migrator for snapshots of newer state machine versions, used with the
+    /// `Migrate` snapshot version policy.
+    pub fn set_migrator<M: SnapshotMigrator + 'static>(&mut self, migrator: M) {
+        self.migrator = Some(Arc::new(migrator));
+    }
+
+    pub fn migrator(&self) -> Option<Arc<dyn SnapshotMigrator>> {
+        self.migrator.clone()
+    }
+
+    /// Set the state machine `MemoryStorageData::App` commands are applied to.
+    pub fn set_state_machine<S: AppStateMachine>(&mut self, state_machine: S) {
+        self.state_machine = Some(Arc::new(Mutex::new(app::erase(state_machine))));
+    }
+
+    pub(crate) fn state_machine(&self) -> Option<Arc<Mutex<Box<dyn ErasedStateMachine>>>> {
+        self.state_machine.clone()
+    }
+
+    /// Apply commands to a new version of the state machine in the shadow of the current one.
+    pub fn set_canary<S: AppStateMachine>(&mut self, state_machine: S, compare_every: u64) {
+        self.canary = Some(Arc::new(Mutex::new(Canary::new(state_machine, compare_every))));
+    }
+
+    pub(crate) fn canary(&self) -> Option<Arc<Mutex<Canary>>> {
+        self.canary.clone()
+    }
+
+    /// Metrics the state machine reports while applying commands.
+    pub fn app_metrics(&self) -> AppMetrics {
+        self.app_metrics.clone()
+    }
+
+    /// Add a projection maintained from the applied entries, see `projection`.
+    pub fn add_projection<P: Projection>(&mut self, projection: P) {
+        self.projections.lock().unwrap().add(projection);
+    }
+
+    pub(crate) fn projections(&self) -> Arc<Mutex<ProjectionSet>> {
+        self.projections.clone()
+    }
+
+    pub fn validate(&self, client: &str, data: &MemoryStorageData) -> Result<(), MemoryStorageError> {
+        // every command of a batch must be valid for the batch to be accepted
+        for command in data.commands() {
+            for validator in self.validators.iter() {
+                if let Err(reason) = validator(command) {
+                    warn!("Rejected proposal from {}: {}", client, reason);
+                    return Err(MemoryStorageError::default());
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Run the proposal through every hook, stopping at the first rejection.
+    pub fn pre_propose(&self, client: &str, data: MemoryStorageData) -> Result<MemoryStorageData, MemoryStorageError> {
+        self.hooks
+            .iter()
+            .try_fold(data, |data, hook| hook.pre_propose(client, data))
+    }
+
+    pub fn post_apply(&self, index: u64, hlc: Hlc, data: &MemoryStorageData) {
+        for hook in self.hooks.iter() {
+            hook.post_apply(index, hlc, data);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn lock(holder: &str) -> MemoryStorageData {
+        MemoryStorageData::AcquireLock { name: "a".to_owned(), holder: holder.to_owned() }
+    }
+
+    fn masking() -> HookChain {
+        let mut hooks = HookChain::default();
+        hooks.set_redactor(|data| match data {
+            MemoryStorageData::AcquireLock { name, .. } => MemoryStorageData::AcquireLock { name: name.clone(), holder: "***".to_owned() },
+            data => data.clone(),
+        });
+        hooks
+    }
+
+    #[test]
+    fn redaction_reaches_into_batches_and_encoded_frames() {
+        let hooks = masking();
+        let batch = MemoryStorageData::Batch(vec![lock("alice"), MemoryStorageData::Freeze(true)]);
+        assert_eq!(hooks.redact(&batch), MemoryStorageData::Batch(vec![lock("***"), MemoryStorageData::Freeze(true)]));
+
+        let frame = json!({ "data": lock("alice") }).to_string();
+        let mut value = json!({ "Message": [1, "ClientPayload", frame] });
+        hooks.redact_json(&mut value);
+        assert!(!value.to_string().contains("alice"));
+        assert!(value.to_string().contains("***"));
+
+        // without a redactor commands are shown as they are
+        assert_eq!(HookChain::default().redact(&lock("alice")), lock("alice"));
+    }
+
+    struct RejectFreeze;
+
+    impl RaftHook for RejectFreeze {
+        fn pre_propose(&self, _client: &str, data: MemoryStorageData) -> Result<MemoryStorageData, MemoryStorageError> {
+            match data {
+                MemoryStorageData::Freeze(_) => Err(MemoryStorageError::default()),
+                data => Ok(data),
+            }
+        }
+    }
+
+    struct Unfreeze;
+
+    impl RaftHook for Unfreeze {
+        fn pre_propose(&self, _client: &str, data: MemoryStorageData) -> Result<MemoryStorageData, MemoryStorageError> {
+            match data {
+                MemoryStorageData::Freeze(_) => Ok(MemoryStorageData::Freeze(false)),
+                data => Ok(data),
+            }
+        }
+    }
+
+    #[test]
+    fn hooks_run_in_registration_order() {
+        let mut hooks = HookChain::default();
+        hooks.add(Unfreeze);
+        hooks.add(RejectFreeze);
+        assert!(hooks.pre_propose("c", MemoryStorageData::Freeze(true)).is_err());
+        assert_eq!(hooks.pre_propose("c", lock("alice")).ok(), Some(lock("alice")));
+    }
+
+    #[test]
+    fn batches_are_valid_only_when_every_command_is() {
+        let mut hooks = HookChain::default();
+        hooks.add_validator(|data| match data {
+            MemoryStorageData::AcquireLock { holder, .. } if holder.is_empty() => Err("no holder".to_owned()),
+            _ => Ok(()),
+        });
+
+        assert!(hooks.validate("c", &MemoryStorageData::Batch(vec![lock("alice"), lock("bob")])).is_ok());
+        assert!(hooks.validate("c", &MemoryStorageData::Batch(vec![lock("alice"), lock("")])).is_err());
+    }
+}
@@ -0,0 +1,101 @@
+//! Bytes and operations of the storage layer, and the write amplification they add up to.
+//!
+//! Counted process wide, across the raft groups of the node: writes and reads of the durable
+//! log, including the hard state, of the log cache's spill file and of snapshot files, next to
+//! the bytes of client payload, application commands and blobs, the node applied. The write
+//! amplification is the bytes written per byte of payload; the counters are totals, their
+//! rate is the IOPS and throughput of the node.
+
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+static LOG_WRITES: AtomicU64 = AtomicU64::new(0);
+static LOG_BYTES_WRITTEN: AtomicU64 = AtomicU64::new(0);
+static LOG_READS: AtomicU64 = AtomicU64::new(0);
+static LOG_BYTES_READ: AtomicU64 = AtomicU64::new(0);
+static SNAPSHOT_WRITES: AtomicU64 = AtomicU64::new(0);
+static SNAPSHOT_BYTES_WRITTEN: AtomicU64 = AtomicU64::new(0);
+static SNAPSHOT_READS: AtomicU64 = AtomicU64::new(0);
+static SNAPSHOT_BYTES_READ: AtomicU64 = AtomicU64::new(0);
+static PAYLOAD_BYTES: AtomicU64 = AtomicU64::new(0);
+
+/// IO counters of this process.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct IoStats {
+    pub log_writes: u64,
+    pub log_bytes_written: u64,
+    pub log_reads: u64,
+    pub log_bytes_read: u64,
+    pub snapshot_writes: u64,
+    pub snapshot_bytes_written: u64,
+    pub snapshot_reads: u64,
+    pub snapshot_bytes_read: u64,
+    /// Bytes of client payload applied
+    pub payload_bytes: u64,
+    /// Log and snapshot bytes written per byte of payload, none before any payload was applied
+    pub write_amplification: Option<f64>,
+}
+
+pub fn stats() -> IoStats {
+    let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+    let written = load(&LOG_BYTES_WRITTEN) + load(&SNAPSHOT_BYTES_WRITTEN);
+    let payload = load(&PAYLOAD_BYTES);
+
+    IoStats {
+        log_writes: load(&LOG_WRITES),
+        log_bytes_written: load(&LOG_BYTES_WRITTEN),
+        log_reads: load(&LOG_READS),
+        log_bytes_read: load(&LOG_BYTES_READ),
+        snapshot_writes: load(&SNAPSHOT_WRITES),
+        snapshot_bytes_written: load(&SNAPSHOT_BYTES_WRITTEN),
+        snapshot_reads: load(&SNAPSHOT_READS),
+        snapshot_bytes_read: load(&SNAPSHOT_BYTES_READ),
+        payload_bytes: payload,
+        write_amplification: if payload > 0 { Some(written as f64 / payload as f64) } else { None },
+    }
+}
+
+fn record(ops: &AtomicU64, bytes_counter: &AtomicU64, bytes: usize) {
+    ops.fetch_add(1, Ordering::Relaxed);
+    bytes_counter.fetch_add(bytes as u64, Ordering::Relaxed);
+}
+
+pub fn log_write(bytes: usize) {
+    record(&LOG_WRITES, &LOG_BYTES_WRITTEN, bytes)
+}
+
+pub fn log_read(bytes: usize) {
+    record(&LOG_READS, &LOG_BYTES_READ, bytes)
+}
+
+pub fn snapshot_write(bytes: usize) {
+    record(&SNAPSHOT_WRITES, &SNAPSHOT_BYTES_WRITTEN, bytes)
+}
+
+pub fn snapshot_read(bytes: usize) {
+    record(&SNAPSHOT_READS, &SNAPSHOT_BYTES_READ, bytes)
+}
+
+pub fn payload_applied(bytes: u64) {
+    PAYLOAD_BYTES.fetch_add(bytes, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_add_up_to_the_amplification() {
+        // the counters are process wide, other tests count alongside
+        let before = stats();
+        log_write(100);
+        snapshot_write(50);
+        payload_applied(10);
+
+        let after = stats();
+        assert!(after.log_writes >= before.log_writes + 1);
+        assert!(after.log_bytes_written >= before.log_bytes_written + 100);
+        assert!(after.snapshot_bytes_written >= before.snapshot_bytes_written + 50);
+        assert!(after.write_amplification.is_some());
+    }
+}
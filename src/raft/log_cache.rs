@@ -0,0 +1,314 @@
+use rmp_serde as rmps;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::RangeBounds;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::memory::{MemoryAccount, MemoryArea, MemoryCounters};
+use crate::raft::io_stats;
+use crate::raft::storage::Entry;
+
+/// The raft log with only its most recent entries held in memory.
+///
+/// The hot tail, which replication reads from, stays in memory up to `capacity` entries. Older
+/// entries are spilled to an append-only file and read back from it on demand, so memory use
+/// doesn't grow with the log. Without a capacity the whole log stays in memory. The bytes of
+/// the cached entries are accounted and entries are also spilled over the `log_cache_bytes`
+/// memory limit.
+pub struct LogCache {
+    capacity: Option<usize>,
+    hot: BTreeMap<u64, Arc<Entry>>,
+    /// Serialized size of the cached entries
+    hot_sizes: BTreeMap<u64, usize>,
+    memory: MemoryAccount,
+    /// Offset and length of the spilled entries in the spill file
+    spilled: BTreeMap<u64, (u64, u64)>,
+    path: PathBuf,
+    file: Option<File>,
+    spill_end: u64,
+    hits: u64,
+    misses: u64,
+}
+
+/// Size and hit rate of the log cache.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LogCacheStats {
+    pub capacity: Option<usize>,
+    pub cached_entries: usize,
+    pub spilled_entries: usize,
+    pub spill_bytes: u64,
+    /// Entries read from memory
+    pub hits: u64,
+    /// Entries read back from the spill file
+    pub misses: u64,
+}
+
+impl LogCache {
+    pub fn new(path: PathBuf, capacity: Option<usize>) -> Self {
+        LogCache {
+            // the last entry always stays cached
+            capacity: capacity.map(|capacity| capacity.max(1)),
+            hot: BTreeMap::new(),
+            hot_sizes: BTreeMap::new(),
+            memory: Arc::new(MemoryCounters::default()),
+            spilled: BTreeMap::new(),
+            path: path,
+            file: None,
+            spill_end: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// Account the cached entries to the memory of the node, before any are inserted.
+    pub fn set_memory(&mut self, memory: MemoryAccount) {
+        self.memory = memory;
+    }
+
+    /// Insert an entry, returning the entry it replaced.
+    pub fn insert(&mut self, index: u64, entry: Arc<Entry>) -> Option<Arc<Entry>> {
+        let size = rmps::to_vec(&*entry).map(|data| data.len()).unwrap_or(0);
+        if let Some(previous) = self.hot_sizes.insert(index, size) {
+            self.memory.shrink(MemoryArea::LogCache, previous);
+        }
+        self.memory.grow(MemoryArea::LogCache, size);
+
+        let previous = match self.hot.insert(index, entry) {
+            Some(previous) => Some(previous),
+            None => match self.spilled.remove(&index) {
+                Some(location) => self.read(location).ok().map(Arc::new),
+                None => None,
+            },
+        };
+
+        self.spill();
+        previous
+    }
+
+    /// Entries in the given range, read back from the spill file when they're not cached.
+    pub fn range<R: RangeBounds<u64> + Clone>(&mut self, range: R) -> io::Result<Vec<Arc<Entry>>> {
+        let locations = self
+            .spilled
+            .range(range.clone())
+            .map(|(_, location)| *location)
+            .collect::<Vec<_>>();
+
+        let mut entries = Vec::with_capacity(locations.len());
+        for location in locations {
+            entries.push(Arc::new(self.read(location)?));
+        }
+        self.misses += entries.len() as u64;
+
+        let cached = self.hot.range(range).map(|(_, e)| e.clone()).collect::<Vec<_>>();
+        self.hits += cached.len() as u64;
+        entries.extend(cached);
+
+        Ok(entries)
+    }
+
+    /// Index and term of the last entry.
+    pub fn last(&self) -> Option<(u64, u64)> {
+        self.hot.values().last().map(|e| (e.index, e.term))
+    }
+
+    pub fn len(&self) -> usize {
+        self.hot.len() + self.spilled.len()
+    }
+
+    /// Drop the entries before `index`.
+    pub fn truncate_before(&mut self, index: u64) {
+        self.hot = self.hot.split_off(&index);
+        let kept = self.hot_sizes.split_off(&index);
+        self.memory.shrink(MemoryArea::LogCache, self.hot_sizes.values().sum());
+        self.hot_sizes = kept;
+        self.spilled = self.spilled.split_off(&index);
+
+        // the spill file only ever holds a prefix of the log, start over once it's all dropped
+        if self.spilled.is_empty() && self.spill_end > 0 {
+            if let Some(ref file) = self.file {
+                if let Err(err) = file.set_len(0) {
+                    warn!("Failed to truncate the log spill file {:?}: {}", self.path, err);
+                    return;
+                }
+            }
+            self.spill_end = 0;
+        }
+    }
+
+    /// Drop the entries after `index`. The spill file keeps their bytes until it's emptied.
+    pub fn truncate_after(&mut self, index: u64) {
+        let start = index.saturating_add(1);
+        self.hot.split_off(&start);
+        let dropped = self.hot_sizes.split_off(&start);
+        self.memory.shrink(MemoryArea::LogCache, dropped.values().sum());
+        self.spilled.split_off(&start);
+    }
+
+    /// Term of the entry at `index`, if the log holds it.
+    pub fn term(&mut self, index: u64) -> io::Result<Option<u64>> {
+        if let Some(entry) = self.hot.get(&index) {
+            return Ok(Some(entry.term));
+        }
+        Ok(self.range(index..=index)?.first().map(|entry| entry.term))
+    }
+
+    pub fn stats(&self) -> LogCacheStats {
+        LogCacheStats {
+            capacity: self.capacity,
+            cached_entries: self.hot.len(),
+            spilled_entries: self.spilled.len(),
+            spill_bytes: self.spill_end,
+            hits: self.hits,
+            misses: self.misses,
+        }
+    }
+
+    /// Move the oldest cached entries to the spill file until the cache is within capacity and
+    /// its memory limit.
+    fn spill(&mut self) {
+        loop {
+            let over_capacity = self.capacity.map(|capacity| self.hot.len() > capacity).unwrap_or(false);
+            let over_limit = self.hot.len() > 1 && self.memory.exceeds(MemoryArea::LogCache, 0);
+            if !over_capacity && !over_limit {
+                return;
+            }
+
+            let index = *self.hot.keys().next().unwrap();
+            let entry = self.hot.remove(&index).unwrap();
+
+            match self.write(&entry) {
+                Ok(location) => {
+                    self.spilled.insert(index, location);
+                    if let Some(size) = self.hot_sizes.remove(&index) {
+                        self.memory.shrink(MemoryArea::LogCache, size);
+                    }
+                    if !over_capacity {
+                        self.memory.shed(MemoryArea::LogCache);
+                    }
+                }
+                Err(err) => {
+                    // keep the entry in memory rather than losing it
+                    error!("Failed to spill log entry {} to {:?}: {}", index, self.path, err);
+                    self.hot.insert(index, entry);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn file(&mut self) -> io::Result<&mut File> {
+        if self.file.is_none() {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&self.path)?;
+            self.file = Some(file);
+        }
+
+        Ok(self.file.as_mut().unwrap())
+    }
+
+    fn write(&mut self, entry: &Entry) -> io::Result<(u64, u64)> {
+        let data = rmps::to_vec(entry).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        let offset = self.spill_end;
+
+        let file = self.file()?;
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(&data)?;
+        io_stats::log_write(data.len());
+
+        self.spill_end += data.len() as u64;
+        Ok((offset, data.len() as u64))
+    }
+
+    fn read(&mut self, (offset, len): (u64, u64)) -> io::Result<Entry> {
+        let file = self.file()?;
+        let mut data = vec![0; len as usize];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut data)?;
+        io_stats::log_read(data.len());
+
+        rmps::from_slice::<Entry>(&data).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+}
+
+impl Drop for LogCache {
+    fn drop(&mut self) {
+        self.memory.shrink(MemoryArea::LogCache, self.hot_sizes.values().sum());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_raft::messages::EntryPayload;
+
+    fn blank(index: u64) -> Arc<Entry> {
+        Arc::new(Entry {
+            index: index,
+            term: 1,
+            payload: EntryPayload::Blank,
+        })
+    }
+
+    fn indexes(entries: Vec<Arc<Entry>>) -> Vec<u64> {
+        entries.iter().map(|entry| entry.index).collect()
+    }
+
+    #[test]
+    fn entries_over_capacity_are_read_back_from_the_spill_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = LogCache::new(dir.path().join("spill"), Some(2));
+        for index in 1..=5 {
+            log.insert(index, blank(index));
+        }
+
+        let stats = log.stats();
+        assert_eq!((stats.cached_entries, stats.spilled_entries), (2, 3));
+        assert_eq!(indexes(log.range(2..=4).unwrap()), vec![2, 3, 4]);
+        assert_eq!(log.stats().misses, 2);
+        assert_eq!(log.last(), Some((5, 1)));
+        assert_eq!(log.term(1).unwrap(), Some(1));
+    }
+
+    #[test]
+    fn truncating_drops_spilled_entries_too() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = LogCache::new(dir.path().join("spill"), Some(1));
+        for index in 1..=5 {
+            log.insert(index, blank(index));
+        }
+
+        log.truncate_after(3);
+        assert_eq!(indexes(log.range(..).unwrap()), vec![1, 2, 3]);
+        log.truncate_before(4);
+        assert_eq!(log.len(), 0);
+        // the emptied spill file starts over
+        assert_eq!(log.stats().spill_bytes, 0);
+    }
+
+    #[test]
+    fn entries_over_the_memory_limit_are_spilled() {
+        let dir = tempfile::tempdir().unwrap();
+        let memory = MemoryCounters::new(crate::config::MemoryLimits {
+            log_cache_bytes: Some(1),
+            ..Default::default()
+        });
+        let mut log = LogCache::new(dir.path().join("spill"), None);
+        log.set_memory(memory.clone());
+        for index in 1..=3 {
+            log.insert(index, blank(index));
+        }
+
+        // the last entry always stays cached
+        assert_eq!(log.stats().cached_entries, 1);
+        assert_eq!(indexes(log.range(..).unwrap()), vec![1, 2, 3]);
+        drop(log);
+        assert_eq!(memory.used(MemoryArea::LogCache), 0);
+    }
+}
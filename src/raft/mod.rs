@@ -3,60 +3,168 @@ extern crate actix_raft;
 use actix::prelude::*;
 use actix_raft::{
     config::{Config, SnapshotPolicy},
+    messages::MembershipConfig,
     NodeId, Raft, RaftMetrics,
 };
 
+use crate::config::ConfigSchema;
+use crate::crash::CrashState;
+use crate::events::EventLog;
 use crate::hash_ring::RingType;
+use crate::memory::MemoryAccount;
 use crate::network::Network;
+use crate::raft::recovery::LogReplay;
 use crate::server::{Server};
+use crate::utils;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
 use std::time::Duration;
 use tempfile::tempdir_in;
 
+mod admission;
+pub mod affinity;
+pub mod backup;
+pub mod blob;
+pub(crate) mod compaction;
+pub mod durable;
+pub mod group;
+pub mod log_cache;
+pub mod hlc;
+pub mod app;
+pub mod hooks;
+pub mod io_stats;
+pub mod projection;
+pub mod rebalance;
+pub mod recovery;
+pub mod timing;
+pub(crate) mod transfer;
 pub mod network;
+pub mod sink;
 pub mod storage;
 mod client;
 
 pub use self::{
-    client::{RaftClient, InitRaft, AddNode, RemoveNode, ChangeRaftClusterConfig}
+    admission::{CommandStats, PendingProposalStats},
+    client::{RaftClient, InitRaft, AcquireLock, AddNode, RemoveNode, ArchiveLog, ChangeRaftClusterConfig, CatchUp, ClientProposal, ConfirmBackup, CreateBackup, ExportError, ExportState, FindCluster, GetClientSession, GetCommitTimings, InspectLog, GetPendingProposals, GetStorageInfo, GetWriteMetrics, IsRingMember, LastLogIndex, LeaderIsolated, ListClusters, ListProjections, ResetProjection, Maintenance, Payload, ProposeMembership, PurgeDue, QueryApp, QueryAppPage, ReleaseLock, RequestCatchUp, RestoreBackup, SkewClock, StopRaft, VacuumStorage, ValidateFencingToken},
+    storage::{LocalRead, LocalState, QueryLocalState},
 };
 
-use self::storage::{MemoryStorage, MemoryStorageData, MemoryStorageError, MemoryStorageResponse};
+use self::compaction::CompactionSchedule;
+use self::durable::{DurabilityPolicy, LogStore};
+use self::hlc::SharedClock;
+use self::hooks::Hooks;
+use self::storage::{MemoryStorage, MemoryStorageError, MemoryStorageResponse, StampedData, StorageFaulted, WriteFreeze, ClusterEpoch};
 
 pub type MemRaft =
-    Raft<MemoryStorageData, MemoryStorageResponse, MemoryStorageError, Network, MemoryStorage>;
+    Raft<StampedData, MemoryStorageResponse, MemoryStorageError, Network, MemoryStorage>;
 
 pub struct RaftBuilder;
 
 impl RaftBuilder {
+    /// Start raft and its storage, failing when the data dir can't be set up or the log
+    /// persisted in it can't be recovered, e.g. because it's corrupt.
     pub fn new(
         id: NodeId,
         members: Vec<NodeId>,
         network: Addr<Network>,
         ring: RingType,
         server: Addr<Server>,
-    ) -> Addr<MemRaft> {
+        fault_recipient: Recipient<StorageFaulted>,
+        hooks: Hooks,
+        clock: SharedClock,
+        frozen: WriteFreeze,
+        epoch: ClusterEpoch,
+        events: EventLog,
+        memory: MemoryAccount,
+        crash: CrashState,
+        replay: LogReplay,
+        raftor_config: &ConfigSchema,
+    ) -> io::Result<(Addr<MemRaft>, Addr<MemoryStorage>)> {
         let id = id;
         let raft_members = members.clone();
         let metrics_rate = 1;
-        let temp_dir = tempdir_in("/tmp").expect("Tempdir to be created without error.");
-        let snapshot_dir = temp_dir.path().to_string_lossy().to_string();
+        // a data dir keeps snapshots across restarts, and the log and hard state with the
+        // `persistent` feature
+        let snapshot_dir = match raftor_config.data_dir {
+            Some(ref data_dir) => {
+                let dir = PathBuf::from(data_dir).join("snapshots");
+                fs::create_dir_all(&dir)?;
+                dir.to_string_lossy().to_string()
+            }
+            None => {
+                let temp_dir = tempdir_in("/tmp")?;
+                temp_dir.path().to_string_lossy().to_string()
+            }
+        };
+        // raft asks for snapshots as often as configured, the schedule defers writing them; it
+        // was validated along with the config
+        let schedule = raftor_config.compaction.as_ref().and_then(|compaction| CompactionSchedule::from_config(compaction).ok());
+        let snapshot_policy = match raftor_config.compaction {
+            Some(ref compaction) => SnapshotPolicy::LogsSinceLast(compaction.logs_since_last),
+            None => SnapshotPolicy::default(),
+        };
+
+        let timings = &raftor_config.raft_timings;
+        let (election_timeout_min, election_timeout_max) = Self::election_timeouts(id, raftor_config);
         let config = Config::build(snapshot_dir.clone())
-            .election_timeout_min(3000)
-            .election_timeout_max(5000)
-            .heartbeat_interval(300)
+            .election_timeout_min(election_timeout_min)
+            .election_timeout_max(election_timeout_max)
+            .heartbeat_interval(timings.heartbeat_interval)
             .metrics_rate(Duration::from_secs(metrics_rate))
-            .snapshot_policy(SnapshotPolicy::default())
-            .snapshot_max_chunk_size(10000)
+            .snapshot_policy(snapshot_policy)
+            .snapshot_max_chunk_size(raftor_config.snapshot_chunk_size)
             .validate()
             .expect("Raft config to be created without error.");
 
-        let storage =
-            MemoryStorage::create(move |_| MemoryStorage::new(raft_members, snapshot_dir, ring, server));
+        let timings_file = raftor_config.entry_timings_file.as_ref().map(PathBuf::from);
+        let version_policy = raftor_config.snapshot_version_policy.clone();
+        let log_cache_entries = raftor_config.log_cache_entries;
+        let snapshot_retention = raftor_config.snapshot_retention;
+        let address_recipient = network.clone().recipient();
+        let metadata_recipient = network.clone().recipient();
+        let cordon_recipient = network.clone().recipient();
+        let blob_fetcher = network.clone().recipient();
+        // the log is recovered before the storage starts, so a store which can't be read
+        // fails here instead of taking the storage actor down
+        let recovered = match Self::log_store(raftor_config)? {
+            Some(store) => {
+                let settings = &raftor_config.log_replay;
+                let log = store.replay(&replay, settings.batch_entries, settings.threads)?;
+                Some((store, log))
+            }
+            None => None,
+        };
+        replay.finish();
+        let durability = Self::durability_policy(id, raftor_config);
+        let projection_checkpoint_entries = raftor_config.projection_checkpoint_entries;
+        let backup_retention = raftor_config.backup_retention;
+        let storage = MemoryStorage::create(move |_| {
+            let storage = MemoryStorage::new(raft_members, snapshot_dir, ring, server, fault_recipient, address_recipient, schedule, timings_file, hooks, clock, version_policy, frozen, log_cache_entries, snapshot_retention, events)
+                .with_memory(memory)
+                .with_metadata_recipient(metadata_recipient)
+                .with_cordon_recipient(cordon_recipient)
+                .with_epoch(epoch)
+                .with_blob_fetcher(blob_fetcher)
+                .with_projection_checkpoints(projection_checkpoint_entries)
+                .with_backup_retention(backup_retention)
+                .with_crash_state(crash);
+            let storage = match durability {
+                Some(policy) => storage.with_durability_policy(policy),
+                None => storage,
+            };
+            match recovered {
+                Some((store, log)) => storage.with_recovered_log(store, log),
+                None => storage,
+            }
+        });
 
         let raft_network = network.clone();
         let raft_storage = storage.clone();
 
-        Raft::create(move |_| {
+        let raft_mailbox = raftor_config.mailboxes.raft;
+        let raft = Raft::create(move |ctx| {
+            ctx.set_mailbox_capacity(raft_mailbox);
             Raft::new(
                 id,
                 config,
@@ -64,6 +172,166 @@ impl RaftBuilder {
                 raft_storage,
                 raft_network.recipient(),
             )
+        });
+
+        Ok((raft, storage))
+    }
+
+    /// Election timeouts biased by the leadership priority of this node.
+    fn election_timeouts(id: NodeId, config: &ConfigSchema) -> (u64, u64) {
+        match config.nodes.iter().find(|node| utils::node_id(node) == id) {
+            Some(node) => config.election_timeouts(&node.cluster_addr),
+            None => (config.raft_timings.election_timeout_min, config.raft_timings.election_timeout_max),
+        }
+    }
+
+    /// Ids of the async-ack voters of the configured commit quorum.
+    fn durability_policy(id: NodeId, config: &ConfigSchema) -> Option<DurabilityPolicy> {
+        let quorum = config.commit_quorum.as_ref()?;
+        let async_ack = quorum
+            .async_ack
+            .iter()
+            .map(|addr| match config.nodes.iter().find(|node| node.cluster_addr == *addr) {
+                Some(node) => utils::node_id(node),
+                None => {
+                    warn!("Async-ack voter {} isn't among the configured nodes, assuming a hashed id", addr);
+                    utils::generate_node_id(addr)
+                }
+            })
+            .collect();
+
+        Some(DurabilityPolicy {
+            node: id,
+            async_ack: async_ack,
         })
     }
+
+    /// Membership of the hard state persisted in the data dir, read before raft starts.
+    #[cfg(feature = "persistent")]
+    pub fn persisted_membership(config: &ConfigSchema) -> Option<MembershipConfig> {
+        let path = PathBuf::from(config.data_dir.as_ref()?).join("log");
+        if !path.exists() {
+            return None;
+        }
+
+        // the store is closed again before the storage opens it
+        let hs = durable::RocksLogStore::open(path).and_then(|store| store.hard_state());
+        match hs {
+            Ok(hs) => hs.map(|hs| hs.membership),
+            Err(err) => {
+                warn!("Can't read the persisted membership: {}", err);
+                None
+            }
+        }
+    }
+
+    #[cfg(not(feature = "persistent"))]
+    pub fn persisted_membership(_config: &ConfigSchema) -> Option<MembershipConfig> {
+        None
+    }
+
+    /// Remove the log and snapshots of the data dir, so the node joins again as a new one.
+    pub fn wipe_data_dir(config: &ConfigSchema) -> io::Result<()> {
+        let data_dir = match config.data_dir {
+            Some(ref data_dir) => PathBuf::from(data_dir),
+            None => return Ok(()),
+        };
+
+        for dir in ["log", "snapshots"].iter() {
+            match fs::remove_dir_all(data_dir.join(dir)) {
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => (),
+                res => res?,
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "persistent")]
+    fn log_store(config: &ConfigSchema) -> io::Result<Option<Box<dyn LogStore>>> {
+        match config.data_dir {
+            Some(ref data_dir) => Self::open_log_store(config, PathBuf::from(data_dir).join("log")),
+            None => Ok(None),
+        }
+    }
+
+    #[cfg(not(feature = "persistent"))]
+    fn log_store(config: &ConfigSchema) -> io::Result<Option<Box<dyn LogStore>>> {
+        if config.data_dir.is_some() {
+            warn!("Only snapshots are kept in `data_dir`, raftor was built without the `persistent` feature");
+        }
+        Ok(None)
+    }
+
+    /// Open the log store kept in `dir`, `None` without the `persistent` feature.
+    #[cfg(feature = "persistent")]
+    pub(crate) fn open_log_store(config: &ConfigSchema, dir: PathBuf) -> io::Result<Option<Box<dyn LogStore>>> {
+        let store = durable::RocksLogStore::open(dir)?
+            .with_dictionary_compression(config.compression_dictionary.as_ref().map_or(false, |dict| dict.storage));
+        Ok(Some(Box::new(store)))
+    }
+
+    #[cfg(not(feature = "persistent"))]
+    pub(crate) fn open_log_store(_config: &ConfigSchema, _dir: PathBuf) -> io::Result<Option<Box<dyn LogStore>>> {
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{CommitQuorum, NodeInfo};
+
+    fn node(addr: &str, id: Option<NodeId>) -> NodeInfo {
+        NodeInfo {
+            cluster_addr: addr.to_owned(),
+            app_addr: String::new(),
+            public_addr: String::new(),
+            id: id,
+            labels: Default::default(),
+            cluster: None,
+        }
+    }
+
+    #[test]
+    fn async_ack_voters_are_resolved_to_their_ids() {
+        let mut config = ConfigSchema::new(String::new(), vec![node("10.0.0.1:8000", Some(1)), node("10.0.0.2:8000", Some(2))]);
+        assert!(RaftBuilder::durability_policy(1, &config).is_none());
+
+        config.commit_quorum = Some(CommitQuorum {
+            async_ack: vec!["10.0.0.2:8000".to_owned(), "10.0.0.9:8000".to_owned()],
+        });
+        let policy = RaftBuilder::durability_policy(1, &config).unwrap();
+        assert_eq!(policy.node, 1);
+        assert_eq!(policy.async_ack.len(), 2);
+        assert!(policy.async_ack.contains(&2));
+        // voters missing from the node list are assumed to use a hashed id
+        assert!(policy.async_ack.contains(&utils::generate_node_id("10.0.0.9:8000")));
+    }
+
+    #[test]
+    fn unlisted_nodes_keep_the_configured_timeouts() {
+        let mut config = ConfigSchema::new(String::new(), vec![node("10.0.0.1:8000", Some(1)), node("10.0.0.2:8000", Some(2))]);
+        config.leadership_priority.insert("10.0.0.1:8000".to_owned(), 2);
+
+        let timings = &config.raft_timings;
+        let configured = (timings.election_timeout_min, timings.election_timeout_max);
+        assert_eq!(RaftBuilder::election_timeouts(1, &config), configured);
+        assert_eq!(RaftBuilder::election_timeouts(3, &config), configured);
+        assert!(RaftBuilder::election_timeouts(2, &config).0 > configured.0);
+    }
+
+    #[test]
+    fn wiping_keeps_the_rest_of_the_data_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("log")).unwrap();
+        fs::create_dir_all(dir.path().join("backups")).unwrap();
+
+        let config = ConfigSchema::new(String::new(), vec![]).with_data_dir(dir.path().to_str().unwrap().to_owned());
+        RaftBuilder::wipe_data_dir(&config).unwrap();
+        assert!(!dir.path().join("log").exists());
+        assert!(dir.path().join("backups").exists());
+
+        // already wiped
+        RaftBuilder::wipe_data_dir(&config).unwrap();
+    }
 }
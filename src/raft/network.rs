@@ -2,10 +2,11 @@ use actix::prelude::*;
 use actix_raft::{messages, RaftNetwork};
 use log::error;
 
+use crate::clock;
+use crate::error::RaftorError;
 use crate::network::{remote::SendRemoteMessage, Network};
-use crate::raft::storage::MemoryStorageData as Data;
-
-const ERR_ROUTING_FAILURE: &str = "Failed to send RCP to node target.";
+use crate::raft::storage::StampedData as Data;
+use crate::telemetry::TracedExt;
 
 impl RaftNetwork<Data> for Network {}
 
@@ -15,25 +16,55 @@ impl Handler<messages::AppendEntriesRequest<Data>> for Network {
     fn handle(
         &mut self,
         msg: messages::AppendEntriesRequest<Data>,
-        _ctx: &mut Context<Self>,
+        ctx: &mut Context<Self>,
     ) -> Self::Result {
+        crate::audit_message!(messages::AppendEntriesRequest<Data>);
+        let span = crate::trace_span!("raft_rpc", rpc = "AppendEntries", target = msg.target, entries = msg.entries.len());
         let target_id = msg.target;
+        let term = msg.term;
+        let last_sent = msg.prev_log_index + msg.entries.len() as u64;
+        let last_sent_term = msg.entries.last().map(|entry| entry.term).unwrap_or(msg.prev_log_term);
+        if self.cut_off(msg.leader_id, msg.target) {
+            return Box::new(fut::err(()));
+        }
+        if let Some(witness) = self.witness_for(msg.target) {
+            return Box::new(fut::ok(witness.append(&msg)));
+        }
         if let Some(node) = self.get_node(msg.target) {
 
-            if self.isolated_nodes.contains(&msg.target) || self.isolated_nodes.contains(&msg.leader_id) {
-                return Box::new(fut::err(()));
-            }
-
+            // the answer only tells the peer followed this node when the request was sent
+            let sent_at = clock::now();
             let req = node.send(SendRemoteMessage(msg));
 
             return Box::new(
                 fut::wrap_future(req)
-                    .map_err(move |_, _, _| error!("{} {}", ERR_ROUTING_FAILURE, target_id))
-                    .and_then(|res, _, _| fut::result(res)),
+                    .map_err(move |_, _, _| error!("AppendEntries failed: {}", RaftorError::PeerUnreachable(target_id)))
+                    .and_then(move |res, act: &mut Network, _| {
+                        // a peer on a newer term no longer follows this leader
+                        if res.as_ref().map(|res| res.term <= term).unwrap_or(false) {
+                            act.peer_reached(target_id, sent_at);
+                        }
+                        match res {
+                            Ok(ref res) if res.success => {
+                                act.peer_matched(target_id, last_sent);
+                                act.peer_acked(target_id, last_sent, last_sent_term);
+                            }
+                            // the follower's log ends or diverges before the conflict index
+                            Ok(ref res) => {
+                                if let Some(ref conflict) = res.conflict_opt {
+                                    act.peer_matched(target_id, conflict.index.saturating_sub(1));
+                                }
+                            }
+                            Err(_) => (),
+                        }
+                        fut::result(res)
+                    })
+                    .traced(span),
             );
 
         }
 
+        self.unknown_target(msg.target, ctx);
         Box::new(fut::err(()))
     }
 }
@@ -41,23 +72,33 @@ impl Handler<messages::AppendEntriesRequest<Data>> for Network {
 impl Handler<messages::VoteRequest> for Network {
     type Result = ResponseActFuture<Self, messages::VoteResponse, ()>;
 
-    fn handle(&mut self, msg: messages::VoteRequest, _ctx: &mut Context<Self>) -> Self::Result {
+    fn handle(&mut self, msg: messages::VoteRequest, ctx: &mut Context<Self>) -> Self::Result {
+        crate::audit_message!(messages::VoteRequest);
+        let span = crate::trace_span!("raft_rpc", rpc = "Vote", target = msg.target, term = msg.term);
+        let target_id = msg.target;
+        // a cordoned node never collects the votes to lead
+        if msg.candidate_id == self.id && self.is_cordoned(self.id) {
+            return Box::new(fut::err(()));
+        }
+        if self.cut_off(msg.candidate_id, msg.target) {
+            return Box::new(fut::err(()));
+        }
+        if let Some(witness) = self.witness_for(msg.target) {
+            return Box::new(fut::ok(witness.vote(&msg)));
+        }
         if let Some(node) = self.get_node(msg.target) {
 
-
-            if self.isolated_nodes.contains(&msg.target) || self.isolated_nodes.contains(&msg.candidate_id) {
-                return Box::new(fut::err(()));
-            }
-
             let req = node.send(SendRemoteMessage(msg));
 
             return Box::new(
                 fut::wrap_future(req)
-                    .map_err(|_, _, _| error!("{}", ERR_ROUTING_FAILURE))
-                    .and_then(|res, _, _| fut::result(res)),
+                    .map_err(move |_, _, _| error!("Vote failed: {}", RaftorError::PeerUnreachable(target_id)))
+                    .and_then(|res, _, _| fut::result(res))
+                    .traced(span),
             );
         }
 
+        self.unknown_target(msg.target, ctx);
         Box::new(fut::err(()))
     }
 }
@@ -68,22 +109,41 @@ impl Handler<messages::InstallSnapshotRequest> for Network {
     fn handle(
         &mut self,
         msg: messages::InstallSnapshotRequest,
-        _ctx: &mut Context<Self>,
+        ctx: &mut Context<Self>,
     ) -> Self::Result {
+        crate::audit_message!(messages::InstallSnapshotRequest);
+        let span = crate::trace_span!("raft_rpc", rpc = "InstallSnapshot", target = msg.target, offset = msg.offset);
+        let target_id = msg.target;
+        let installed = if msg.done { Some((msg.last_included_index, msg.last_included_term)) } else { None };
+        if self.cut_off(msg.leader_id, msg.target) {
+            return Box::new(fut::err(()));
+        }
+        if let Some(witness) = self.witness_for(msg.target) {
+            return Box::new(fut::ok(witness.install_snapshot(&msg)));
+        }
         if let Some(node) = self.get_node(msg.target) {
-            if self.isolated_nodes.contains(&msg.target) || self.isolated_nodes.contains(&msg.leader_id) {
-                return Box::new(fut::err(()));
-            }
 
+            let msg = match self.snapshot_sealer {
+                Some(ref sealer) => sealer.seal(msg),
+                None => msg,
+            };
             let req = node.send(SendRemoteMessage(msg));
 
             return Box::new(
                 fut::wrap_future(req)
-                    .map_err(|_, _, _| error!("{}", ERR_ROUTING_FAILURE))
-                    .and_then(|res, _, _| fut::result(res)),
+                    .map_err(move |_, _, _| error!("InstallSnapshot failed: {}", RaftorError::PeerUnreachable(target_id)))
+                    .and_then(move |res, act: &mut Network, _| {
+                        if let (Ok(_), Some((index, term))) = (&res, installed) {
+                            act.peer_matched(target_id, index);
+                            act.peer_acked(target_id, index, term);
+                        }
+                        fut::result(res)
+                    })
+                    .traced(span),
             );
         }
 
+        self.unknown_target(msg.target, ctx);
         Box::new(fut::err(()))
     }
 }
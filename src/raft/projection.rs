@@ -0,0 +1,245 @@
+//! Read models derived from the applied entries.
+//!
+//! A `Projection` is handed every command applied on the node, in log order, and keeps its read
+//! model up to date from them. Its progress is checkpointed to `<snapshot dir>/projections/<name>`
+//! every `projection_checkpoint_entries` entries; after a restart it's only handed the entries
+//! past its checkpoint, so those applied since the last checkpoint may be handed again. When the
+//! state machine is rebuilt from a snapshot, projections behind the snapshot may have missed
+//! compacted entries: they're reset and rebuilt from the snapshot's entries.
+
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use crate::raft::hlc::Hlc;
+use crate::raft::storage::MemoryStorageData;
+
+/// A read model maintained from the applied entries, registered with `Raftor::add_projection`.
+pub trait Projection: Send + 'static {
+    /// Name of the projection, unique on the node, its checkpoint is kept under it.
+    fn name(&self) -> &str;
+
+    /// Apply a command, `index` is the log index of its entry and `hlc` the timestamp the
+    /// leader stamped it with. Called once per command of a batch.
+    fn apply(&mut self, index: u64, hlc: Hlc, data: &MemoryStorageData);
+
+    /// Drop the whole read model before it's rebuilt.
+    fn reset(&mut self);
+
+    /// Make what was applied so far durable, called before the checkpoint is saved. Returning
+    /// an error keeps the previous checkpoint.
+    fn flush(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// Progress of a projection, as shown by `/admin/projections`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ProjectionStatus {
+    pub name: String,
+    /// Index of the last entry handed to the projection
+    pub applied: u64,
+    /// Index the projection resumes after when the node restarts
+    pub checkpoint: u64,
+}
+
+struct Registered {
+    projection: Box<dyn Projection>,
+    applied: u64,
+    checkpoint: u64,
+}
+
+/// The projections of a node, fed by its storage.
+#[derive(Default)]
+pub struct ProjectionSet {
+    projections: Vec<Registered>,
+    /// Where checkpoints are kept, they aren't until the storage opens the set
+    dir: Option<PathBuf>,
+    checkpoint_entries: u64,
+}
+
+impl ProjectionSet {
+    pub fn add<P: Projection>(&mut self, projection: P) {
+        self.projections.push(Registered {
+            projection: Box::new(projection),
+            applied: 0,
+            checkpoint: 0,
+        });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.projections.is_empty()
+    }
+
+    /// Resume every projection after its checkpoint kept in `dir`, and checkpoint them every
+    /// `checkpoint_entries` entries from now on.
+    pub fn open(&mut self, dir: PathBuf, checkpoint_entries: u64) -> io::Result<()> {
+        fs::create_dir_all(&dir)?;
+        for registered in self.projections.iter_mut() {
+            let path = dir.join(registered.projection.name());
+            let checkpoint = match fs::read_to_string(&path) {
+                Ok(checkpoint) => checkpoint
+                    .trim()
+                    .parse::<u64>()
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), err)))?,
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => 0,
+                Err(err) => return Err(err),
+            };
+            info!("Projection {} resumes after index {}", registered.projection.name(), checkpoint);
+            registered.applied = checkpoint;
+            registered.checkpoint = checkpoint;
+        }
+
+        self.dir = Some(dir);
+        self.checkpoint_entries = std::cmp::max(checkpoint_entries, 1);
+        Ok(())
+    }
+
+    /// Hand the commands of an applied entry to the projections which haven't seen it yet.
+    pub fn apply(&mut self, index: u64, hlc: Hlc, commands: &[&MemoryStorageData]) {
+        let (dir, checkpoint_entries) = (self.dir.clone(), self.checkpoint_entries);
+        for registered in self.projections.iter_mut().filter(|registered| registered.applied < index) {
+            for command in commands {
+                registered.projection.apply(index, hlc, command);
+            }
+            registered.applied = index;
+
+            if let Some(ref dir) = dir {
+                if registered.applied - registered.checkpoint >= checkpoint_entries {
+                    registered.save(dir);
+                }
+            }
+        }
+    }
+
+    /// Before the state machine is rebuilt from a snapshot through `last`, reset the
+    /// projections behind it. They're handed the snapshot's entries as they're replayed.
+    pub fn rebuild_through(&mut self, last: u64) {
+        for registered in self.projections.iter_mut().filter(|registered| registered.applied < last) {
+            info!("Rebuilding projection {} from the snapshot through index {}", registered.projection.name(), last);
+            registered.reset();
+        }
+    }
+
+    /// Reset a projection to rebuild it from the entries replayed next, `false` if there's no
+    /// projection with the name.
+    pub fn reset(&mut self, name: &str) -> bool {
+        match self.projections.iter_mut().find(|registered| registered.projection.name() == name) {
+            Some(registered) => {
+                info!("Rebuilding projection {}", name);
+                registered.reset();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Save the checkpoints of every projection, after a rebuild.
+    pub fn checkpoint(&mut self) {
+        if let Some(dir) = self.dir.clone() {
+            for registered in self.projections.iter_mut().filter(|registered| registered.applied != registered.checkpoint) {
+                registered.save(&dir);
+            }
+        }
+    }
+
+    pub fn status(&self) -> Vec<ProjectionStatus> {
+        self.projections
+            .iter()
+            .map(|registered| ProjectionStatus {
+                name: registered.projection.name().to_owned(),
+                applied: registered.applied,
+                checkpoint: registered.checkpoint,
+            })
+            .collect()
+    }
+}
+
+impl Registered {
+    fn reset(&mut self) {
+        self.projection.reset();
+        self.applied = 0;
+    }
+
+    /// Flush the projection and write its checkpoint, replacing the previous one at once.
+    fn save(&mut self, dir: &PathBuf) {
+        let name = self.projection.name().to_owned();
+        if let Err(err) = self.projection.flush() {
+            return warn!("Keeping the checkpoint of projection {} at {}, flushing it failed: {}", name, self.checkpoint, err);
+        }
+
+        let path = dir.join(&name);
+        let partial = dir.join(format!("{}.partial", name));
+        match fs::write(&partial, self.applied.to_string()).and_then(|_| fs::rename(&partial, &path)) {
+            Ok(()) => self.checkpoint = self.applied,
+            Err(err) => warn!("Failed to checkpoint projection {} at {}: {}", name, self.applied, err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    /// Records the indexes it's handed, shared with the test.
+    struct Indexes(Arc<Mutex<Vec<u64>>>);
+
+    impl Projection for Indexes {
+        fn name(&self) -> &str {
+            "indexes"
+        }
+
+        fn apply(&mut self, index: u64, _hlc: Hlc, _data: &MemoryStorageData) {
+            self.0.lock().unwrap().push(index);
+        }
+
+        fn reset(&mut self) {
+            self.0.lock().unwrap().clear();
+        }
+    }
+
+    fn set(dir: &std::path::Path) -> (ProjectionSet, Arc<Mutex<Vec<u64>>>) {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let mut set = ProjectionSet::default();
+        set.add(Indexes(seen.clone()));
+        set.open(dir.to_path_buf(), 2).unwrap();
+        (set, seen)
+    }
+
+    #[test]
+    fn restarts_resume_after_the_checkpoint() {
+        let dir = tempfile::tempdir().unwrap();
+        let command = MemoryStorageData::Freeze(true);
+        let (mut projections, _) = set(dir.path());
+        for index in 1..=3 {
+            projections.apply(index, Hlc::default(), &[&command]);
+        }
+        assert_eq!(projections.status()[0].checkpoint, 2);
+
+        let (mut restarted, seen) = set(dir.path());
+        for index in 1..=3 {
+            restarted.apply(index, Hlc::default(), &[&command]);
+        }
+        // entries past the checkpoint are handed again
+        assert_eq!(*seen.lock().unwrap(), vec![3]);
+    }
+
+    #[test]
+    fn projections_behind_a_snapshot_are_rebuilt() {
+        let dir = tempfile::tempdir().unwrap();
+        let command = MemoryStorageData::Freeze(true);
+        let (mut projections, seen) = set(dir.path());
+        projections.apply(1, Hlc::default(), &[&command]);
+
+        projections.rebuild_through(5);
+        assert!(seen.lock().unwrap().is_empty());
+        for index in 1..=5 {
+            projections.apply(index, Hlc::default(), &[&command]);
+        }
+        projections.checkpoint();
+        assert_eq!(projections.status()[0].checkpoint, 5);
+        assert!(!projections.reset("missing"));
+    }
+}
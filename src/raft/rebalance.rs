@@ -0,0 +1,231 @@
+//! Placement of raft group replicas and leadership across the nodes of the cluster.
+//!
+//! The cluster leader collects the load of every group from its leader, the members, the
+//! leader and the last log index, and derives the traffic of each group from the growth of
+//! its log between two rounds. A node's load sums the weights of the groups it hosts, one for
+//! an idle replica plus one per `traffic_unit` entries per second. Each round moves at most
+//! one replica, the new replica catching up through raft as a non-voter, by snapshot when
+//! the log was compacted, before the old one is removed, or else the leadership of one group.
+
+use actix_raft::NodeId;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::time::Instant;
+
+use crate::config::GroupRebalance;
+use crate::raft::group::GroupId;
+
+/// A group as reported by its leader.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct GroupLoad {
+    pub group: GroupId,
+    pub leader: NodeId,
+    pub members: Vec<NodeId>,
+    pub last_log_index: u64,
+}
+
+/// A move proposed by `plan`.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum ShardMove {
+    /// Add `to` to the members of the group, then remove `from`
+    Replica { group: GroupId, from: NodeId, to: NodeId },
+    /// Have `from` step aside as the leader of the group, `to` is the least busy member which
+    /// may take over, actix-raft can't choose the successor
+    Leader { group: GroupId, from: NodeId, to: NodeId },
+}
+
+impl ShardMove {
+    pub fn group(&self) -> GroupId {
+        match *self {
+            ShardMove::Replica { group, .. } | ShardMove::Leader { group, .. } => group,
+        }
+    }
+}
+
+/// Traffic of the groups, from the last log index seen in the previous round.
+#[derive(Default)]
+pub struct TrafficSampler {
+    previous: HashMap<GroupId, (u64, Instant)>,
+}
+
+impl TrafficSampler {
+    /// Entries per second appended to each group since the previous sample, 0 for groups
+    /// sampled for the first time.
+    pub fn sample(&mut self, loads: &[GroupLoad], now: Instant) -> HashMap<GroupId, f64> {
+        let mut rates = HashMap::new();
+        let mut previous = HashMap::new();
+
+        for load in loads {
+            let rate = match self.previous.get(&load.group) {
+                Some(&(index, at)) if now > at => {
+                    let secs = (now - at).as_millis() as f64 / 1000.0;
+                    load.last_log_index.saturating_sub(index) as f64 / secs
+                }
+                _ => 0.0,
+            };
+            rates.insert(load.group, rate);
+            previous.insert(load.group, (load.last_log_index, now));
+        }
+
+        // groups which are gone are forgotten
+        self.previous = previous;
+        rates
+    }
+}
+
+/// Weight of a group on each of its members.
+fn weight(config: &GroupRebalance, rate: f64) -> f64 {
+    1.0 + rate / config.traffic_unit as f64
+}
+
+/// The move balancing `nodes` best, if any node is loaded more than the skews of `config`
+/// above another. Replicas are balanced before leadership, and the leader of a group never
+/// moves its own replica, its leadership moves first.
+pub fn plan(config: &GroupRebalance, nodes: &[NodeId], loads: &[GroupLoad], rates: &HashMap<GroupId, f64>) -> Option<ShardMove> {
+    if nodes.len() < 2 {
+        return None;
+    }
+
+    let mut load: BTreeMap<NodeId, f64> = nodes.iter().map(|id| (*id, 0.0)).collect();
+    let mut led: BTreeMap<NodeId, usize> = nodes.iter().map(|id| (*id, 0)).collect();
+    for group in loads {
+        let weight = weight(config, rates.get(&group.group).cloned().unwrap_or(0.0));
+        for member in group.members.iter() {
+            if let Some(load) = load.get_mut(member) {
+                *load += weight;
+            }
+        }
+        if let Some(led) = led.get_mut(&group.leader) {
+            *led += 1;
+        }
+    }
+
+    let (busiest, busiest_load) = max_by(&load)?;
+    let (idlest, idlest_load) = min_by(&load)?;
+    let skew = busiest_load - idlest_load;
+    if skew > config.replica_skew {
+        // the heaviest group whose move narrows the gap, moving a weight w changes it by 2w
+        let candidate = loads
+            .iter()
+            .filter(|group| group.members.contains(&busiest) && !group.members.contains(&idlest) && group.leader != busiest)
+            .map(|group| (group.group, weight(config, rates.get(&group.group).cloned().unwrap_or(0.0))))
+            .filter(|&(_, weight)| weight < skew)
+            .fold(None, |best: Option<(GroupId, f64)>, (group, weight)| match best {
+                Some((_, best_weight)) if best_weight >= weight => best,
+                _ => Some((group, weight)),
+            });
+
+        if let Some((group, _)) = candidate {
+            return Some(ShardMove::Replica {
+                group: group,
+                from: busiest,
+                to: idlest,
+            });
+        }
+    }
+
+    let (leading, most) = max_by(&led)?;
+    let (to, least) = min_by(&led)?;
+    if most - least <= config.leader_skew {
+        return None;
+    }
+
+    loads
+        .iter()
+        .filter(|group| group.leader == leading && group.members.contains(&to))
+        .map(|group| ShardMove::Leader {
+            group: group.group,
+            from: leading,
+            to: to,
+        })
+        .next()
+}
+
+/// Node with the highest value, the lowest id on ties.
+fn max_by<V: PartialOrd + Copy>(values: &BTreeMap<NodeId, V>) -> Option<(NodeId, V)> {
+    values
+        .iter()
+        .fold(None, |best: Option<(NodeId, V)>, (id, value)| match best {
+            Some((_, best_value)) if best_value >= *value => best,
+            _ => Some((*id, *value)),
+        })
+}
+
+/// Node with the lowest value, the lowest id on ties.
+fn min_by<V: PartialOrd + Copy>(values: &BTreeMap<NodeId, V>) -> Option<(NodeId, V)> {
+    values
+        .iter()
+        .fold(None, |best: Option<(NodeId, V)>, (id, value)| match best {
+            Some((_, best_value)) if best_value <= *value => best,
+            _ => Some((*id, *value)),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn config() -> GroupRebalance {
+        GroupRebalance {
+            interval_secs: 60,
+            traffic_unit: 100,
+            replica_skew: 2.0,
+            leader_skew: 2,
+        }
+    }
+
+    fn group(group: GroupId, leader: NodeId, members: &[NodeId]) -> GroupLoad {
+        GroupLoad {
+            group: group,
+            leader: leader,
+            members: members.to_vec(),
+            last_log_index: 0,
+        }
+    }
+
+    #[test]
+    fn balanced_nodes_stay_put() {
+        let loads = vec![group(1, 1, &[1, 2]), group(2, 2, &[2, 3]), group(3, 3, &[3, 1])];
+
+        assert_eq!(plan(&config(), &[1, 2, 3], &loads, &HashMap::new()), None);
+    }
+
+    #[test]
+    fn replicas_move_to_the_least_loaded_node() {
+        let loads = vec![group(1, 2, &[1, 2]), group(2, 2, &[1, 2]), group(3, 1, &[1, 2]), group(4, 2, &[1, 2])];
+
+        // node 1 leads group 3, so one of the groups led by node 2 moves
+        assert_eq!(plan(&config(), &[1, 2, 3], &loads, &HashMap::new()), Some(ShardMove::Replica { group: 1, from: 1, to: 3 }));
+    }
+
+    #[test]
+    fn busy_groups_weigh_more() {
+        let loads = vec![group(1, 2, &[1, 2]), group(2, 2, &[1, 2]), group(3, 3, &[3])];
+        let mut rates = HashMap::new();
+        rates.insert(2, 300.0);
+        rates.insert(3, 150.0);
+
+        // node 1 carries 1 + 4 against 2.5 on node 3, moving the busy group would leave
+        // node 3 busier than node 1 was
+        assert_eq!(plan(&config(), &[1, 2, 3], &loads, &rates), Some(ShardMove::Replica { group: 1, from: 1, to: 3 }));
+    }
+
+    #[test]
+    fn leadership_moves_once_replicas_are_balanced() {
+        let loads = vec![group(1, 1, &[1, 2]), group(2, 1, &[1, 2]), group(3, 1, &[1, 2])];
+
+        assert_eq!(plan(&config(), &[1, 2], &loads, &HashMap::new()), Some(ShardMove::Leader { group: 1, from: 1, to: 2 }));
+    }
+
+    #[test]
+    fn traffic_is_sampled_between_rounds() {
+        let mut sampler = TrafficSampler::default();
+        let start = Instant::now();
+        let mut loads = vec![group(1, 1, &[1])];
+
+        assert_eq!(sampler.sample(&loads, start)[&1], 0.0);
+        loads[0].last_log_index = 500;
+        assert_eq!(sampler.sample(&loads, start + Duration::from_secs(5))[&1], 100.0);
+    }
+}
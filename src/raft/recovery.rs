@@ -0,0 +1,160 @@
+//! Progress of the log replay a node runs when it starts from a persisted log.
+//!
+//! Large logs take a while to read back, the store reports the entries it decoded batch by
+//! batch, the progress is logged every `REPORT_INTERVAL` and served by `GET /cluster/ready`,
+//! which answers `503` until the replay is done.
+
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::clock;
+
+/// How often the progress of a replay is logged
+const REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReplayPhase {
+    /// Raft didn't start yet
+    Pending,
+    Replaying,
+    Done,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ReplayStatus {
+    pub phase: ReplayPhase,
+    /// Entries of the persisted log
+    pub total: u64,
+    pub replayed: u64,
+    pub entries_per_sec: f64,
+    /// Seconds left at the current rate, unknown before the first batch
+    pub eta_secs: Option<u64>,
+}
+
+struct Replay {
+    phase: ReplayPhase,
+    total: u64,
+    replayed: u64,
+    started_at: Option<Instant>,
+    reported_at: Option<Instant>,
+}
+
+impl Replay {
+    fn status(&self, now: Instant) -> ReplayStatus {
+        let secs = self.started_at.map_or(0.0, |at| now.saturating_duration_since(at).as_millis() as f64 / 1000.0);
+        let rate = if secs > 0.0 { self.replayed as f64 / secs } else { 0.0 };
+        let eta = match self.phase {
+            ReplayPhase::Replaying if rate > 0.0 => Some((self.total.saturating_sub(self.replayed) as f64 / rate).ceil() as u64),
+            ReplayPhase::Done => Some(0),
+            _ => None,
+        };
+
+        ReplayStatus {
+            phase: self.phase,
+            total: self.total,
+            replayed: self.replayed,
+            entries_per_sec: rate,
+            eta_secs: eta,
+        }
+    }
+}
+
+/// Replay progress of a node, shared by its raft client and the readiness endpoint.
+#[derive(Clone)]
+pub struct LogReplay(Arc<Mutex<Replay>>);
+
+impl Default for LogReplay {
+    fn default() -> Self {
+        LogReplay(Arc::new(Mutex::new(Replay {
+            phase: ReplayPhase::Pending,
+            total: 0,
+            replayed: 0,
+            started_at: None,
+            reported_at: None,
+        })))
+    }
+}
+
+impl LogReplay {
+    /// Start replaying a log of `total` entries.
+    pub fn start(&self, total: u64) {
+        let mut replay = self.0.lock().unwrap();
+        let now = clock::now();
+        replay.phase = ReplayPhase::Replaying;
+        replay.total = total;
+        replay.replayed = 0;
+        replay.started_at = Some(now);
+        replay.reported_at = Some(now);
+        info!("Replaying {} log entries", total);
+    }
+
+    /// Note `entries` more entries replayed, logging the progress now and then.
+    pub fn advance(&self, entries: u64) {
+        let mut replay = self.0.lock().unwrap();
+        let now = clock::now();
+        replay.replayed += entries;
+
+        if replay.reported_at.map_or(true, |at| now.saturating_duration_since(at) >= REPORT_INTERVAL) {
+            replay.reported_at = Some(now);
+            let status = replay.status(now);
+            info!(
+                "Replayed {}/{} log entries, {:.0} entries/s, ETA {}s",
+                status.replayed,
+                status.total,
+                status.entries_per_sec,
+                status.eta_secs.map_or("unknown".to_owned(), |eta| eta.to_string())
+            );
+        }
+    }
+
+    pub fn finish(&self) {
+        let mut replay = self.0.lock().unwrap();
+        if replay.phase == ReplayPhase::Replaying {
+            let status = replay.status(clock::now());
+            info!("Replayed {} log entries at {:.0} entries/s", status.replayed, status.entries_per_sec);
+        }
+        replay.phase = ReplayPhase::Done;
+    }
+
+    pub fn status(&self) -> ReplayStatus {
+        self.0.lock().unwrap().status(clock::now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn eta_follows_the_rate() {
+        let start = Instant::now();
+        let replay = Replay {
+            phase: ReplayPhase::Replaying,
+            total: 1000,
+            replayed: 250,
+            started_at: Some(start),
+            reported_at: None,
+        };
+
+        let status = replay.status(start + Duration::from_secs(5));
+        assert_eq!(status.entries_per_sec, 50.0);
+        assert_eq!(status.eta_secs, Some(15));
+    }
+
+    #[test]
+    fn eta_is_unknown_until_entries_are_replayed() {
+        let start = Instant::now();
+        let replay = Replay {
+            phase: ReplayPhase::Replaying,
+            total: 1000,
+            replayed: 0,
+            started_at: Some(start),
+            reported_at: None,
+        };
+
+        assert_eq!(replay.status(start).eta_secs, None);
+        assert_eq!(replay.status(start + Duration::from_secs(1)).eta_secs, None);
+    }
+}
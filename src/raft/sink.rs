@@ -0,0 +1,235 @@
+//! Shipping of applied entries to external sinks, Kafka topics or NATS subjects.
+//!
+//! The leader publishes the entries applied after the checkpoint of a sink and then proposes
+//! `MemoryStorageData::SinkCheckpoint`, so the next leader resumes where it left off. Entries
+//! published before their checkpoint commits are published again after a failover: delivery
+//! is at least once and consumers dedup by index.
+
+use actix::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::config::{LogSinkConfig, SinkBackend};
+use crate::raft::hlc::Hlc;
+use crate::raft::storage::MemoryStorageData;
+
+/// An applied entry as published to a sink, JSON encoded.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ShippedEntry {
+    pub index: u64,
+    pub term: u64,
+    pub hlc: Hlc,
+    /// Idempotency key of the proposal, if it had one
+    pub key: Option<String>,
+    pub data: MemoryStorageData,
+}
+
+/// Publishes entries to an external system.
+pub trait EntrySink: Send {
+    /// Publish the entries in order, returning once the sink acknowledged all of them.
+    /// Returning an error publishes them again later.
+    fn publish(&mut self, entries: &[ShippedEntry]) -> Result<(), String>;
+}
+
+fn connect(backend: &SinkBackend) -> Result<Box<dyn EntrySink>, String> {
+    match backend {
+        SinkBackend::Kafka { brokers, topic } => connect_kafka(brokers, topic),
+        SinkBackend::Nats { url, subject } => connect_nats(url, subject),
+    }
+}
+
+#[cfg(feature = "kafka-sink")]
+fn connect_kafka(brokers: &str, topic: &str) -> Result<Box<dyn EntrySink>, String> {
+    Ok(Box::new(kafka::KafkaSink::connect(brokers, topic)?))
+}
+
+#[cfg(not(feature = "kafka-sink"))]
+fn connect_kafka(_brokers: &str, _topic: &str) -> Result<Box<dyn EntrySink>, String> {
+    Err("raftor was built without the `kafka-sink` feature".to_owned())
+}
+
+#[cfg(feature = "nats-sink")]
+fn connect_nats(url: &str, subject: &str) -> Result<Box<dyn EntrySink>, String> {
+    Ok(Box::new(nats::NatsSink::connect(url, subject)?))
+}
+
+#[cfg(not(feature = "nats-sink"))]
+fn connect_nats(_url: &str, _subject: &str) -> Result<Box<dyn EntrySink>, String> {
+    Err("raftor was built without the `nats-sink` feature".to_owned())
+}
+
+#[cfg(feature = "kafka-sink")]
+mod kafka {
+    use futures::Future;
+    use rdkafka::config::ClientConfig;
+    use rdkafka::producer::{FutureProducer, FutureRecord};
+
+    use super::{EntrySink, ShippedEntry};
+
+    /// Produces every entry to one topic, keyed by the topic name so they land on a single
+    /// partition in log order.
+    pub struct KafkaSink {
+        producer: FutureProducer,
+        topic: String,
+    }
+
+    impl KafkaSink {
+        pub fn connect(brokers: &str, topic: &str) -> Result<KafkaSink, String> {
+            let producer = ClientConfig::new()
+                .set("bootstrap.servers", brokers)
+                .set("acks", "all")
+                .set("enable.idempotence", "true")
+                .create()
+                .map_err(|err| err.to_string())?;
+
+            Ok(KafkaSink {
+                producer: producer,
+                topic: topic.to_owned(),
+            })
+        }
+    }
+
+    impl EntrySink for KafkaSink {
+        fn publish(&mut self, entries: &[ShippedEntry]) -> Result<(), String> {
+            // queue the whole batch before waiting for the deliveries
+            let mut deliveries = Vec::with_capacity(entries.len());
+            for entry in entries {
+                let payload = serde_json::to_vec(entry).map_err(|err| err.to_string())?;
+                let record = FutureRecord::to(&self.topic).key(&self.topic).payload(&payload);
+                deliveries.push(self.producer.send(record, 0));
+            }
+
+            for delivery in deliveries {
+                match delivery.wait() {
+                    Ok(Ok(_)) => (),
+                    Ok(Err((err, _))) => return Err(err.to_string()),
+                    Err(_) => return Err("delivery canceled".to_owned()),
+                }
+            }
+
+            Ok(())
+        }
+    }
+}
+
+#[cfg(feature = "nats-sink")]
+mod nats {
+    use super::{EntrySink, ShippedEntry};
+
+    /// Publishes every entry to one subject, flushing the connection once per batch.
+    pub struct NatsSink {
+        conn: ::nats::Connection,
+        subject: String,
+    }
+
+    impl NatsSink {
+        pub fn connect(url: &str, subject: &str) -> Result<NatsSink, String> {
+            let conn = ::nats::connect(url).map_err(|err| err.to_string())?;
+
+            Ok(NatsSink {
+                conn: conn,
+                subject: subject.to_owned(),
+            })
+        }
+    }
+
+    impl EntrySink for NatsSink {
+        fn publish(&mut self, entries: &[ShippedEntry]) -> Result<(), String> {
+            for entry in entries {
+                let payload = serde_json::to_vec(entry).map_err(|err| err.to_string())?;
+                self.conn.publish(&self.subject, payload).map_err(|err| err.to_string())?;
+            }
+
+            self.conn.flush().map_err(|err| err.to_string())
+        }
+    }
+}
+
+/// Publishes the batches of a sink off the raft client, connecting on the first batch and
+/// again after a failed one.
+pub(crate) struct SinkWorker {
+    config: LogSinkConfig,
+    sink: Option<Box<dyn EntrySink>>,
+}
+
+impl Actor for SinkWorker {
+    type Context = SyncContext<Self>;
+}
+
+pub(crate) struct Publish(pub Vec<ShippedEntry>);
+
+impl Message for Publish {
+    type Result = Result<(), String>;
+}
+
+impl Handler<Publish> for SinkWorker {
+    type Result = Result<(), String>;
+
+    fn handle(&mut self, msg: Publish, _: &mut Self::Context) -> Self::Result {
+        if self.sink.is_none() {
+            self.sink = Some(connect(&self.config.backend)?);
+        }
+
+        let res = self.sink.as_mut().unwrap().publish(&msg.0);
+        if res.is_err() {
+            self.sink = None;
+        }
+        res
+    }
+}
+
+/// A configured sink and how far this leader shipped it.
+pub(crate) struct LogSink {
+    pub name: String,
+    pub batch_entries: usize,
+    pub worker: Addr<SinkWorker>,
+    /// Last index published by this node since it became leader, ahead of the replicated
+    /// checkpoint until it commits
+    pub published: Option<u64>,
+    /// Whether a batch is being published
+    pub shipping: bool,
+}
+
+impl LogSink {
+    pub fn start(config: &LogSinkConfig) -> LogSink {
+        let worker_config = config.clone();
+
+        LogSink {
+            name: config.name.clone(),
+            batch_entries: config.batch_entries,
+            worker: SyncArbiter::start(1, move || SinkWorker {
+                config: worker_config.clone(),
+                sink: None,
+            }),
+            published: None,
+            shipping: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries_are_shipped_as_json() {
+        let entry = ShippedEntry {
+            index: 7,
+            term: 2,
+            hlc: Hlc { physical: 1000, logical: 1 },
+            key: Some("session:a:1".to_owned()),
+            data: MemoryStorageData::Freeze(true),
+        };
+        let json = serde_json::to_value(&entry).unwrap();
+
+        assert_eq!(json["index"], 7);
+        assert_eq!(json["hlc"]["physical"], 1000);
+        assert_eq!(json["data"]["Freeze"], true);
+    }
+
+    #[cfg(not(feature = "kafka-sink"))]
+    #[test]
+    fn sinks_built_without_their_feature_fail_to_connect() {
+        let backend = SinkBackend::Kafka { brokers: "localhost:9092".to_owned(), topic: "raftor".to_owned() };
+        assert!(connect(&backend).is_err());
+    }
+}
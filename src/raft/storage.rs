@@ -1,12 +1,17 @@
 use std::{
-    collections::BTreeMap,
-    fs::{self, File},
-    io::{Seek, SeekFrom, Write},
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fs::{self, File, OpenOptions},
+    io::{self, Seek, SeekFrom, Write},
     path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::Duration,
 };
 
 use actix::prelude::*;
-use log::{debug, error};
+use log::{debug, error, info, warn};
 use rmp_serde as rmps;
 use serde::{Deserialize, Serialize};
 
@@ -20,19 +25,176 @@ use actix_raft::{
     AppData, AppDataResponse, AppError, NodeId,
 };
 
+use crate::clock;
+use crate::config::{ClusterMetadata, NodeInfo, SnapshotVersionPolicy};
+use crate::crash::CrashState;
+use crate::dictionary::{self, Dictionary};
+use crate::events::{EventKind, EventLog};
+use crate::federation::{ClusterRecord, FederationRegistry};
+use crate::locks::{FencingToken, LockState, LockTable};
 use crate::hash_ring::RingType;
+use crate::network::{AddressChanged, CordonChanged, FetchBlob, MetadataChanged};
+use crate::raft::affinity::{ClientSession, SessionTable};
+use crate::raft::app::{AppPage, ApplyContext, CanaryReport};
+use crate::raft::backup::{self, BackupError, BackupManifest, BackupPart};
+use crate::raft::blob::{self, BlobChunk, BlobRef, Spill};
+use crate::raft::compaction::{minute_of_day_utc, CompactionSchedule};
+use crate::raft::durable::{DurabilityPolicy, LogStore, VacuumProgress};
+use crate::raft::hlc::{Hlc, SharedClock};
+use crate::raft::hooks::{Hooks, LogArchiver, SnapshotMigrator};
+use crate::raft::io_stats::{self, IoStats};
+use crate::memory::MemoryAccount;
+use crate::raft::log_cache::{LogCache, LogCacheStats};
+use crate::raft::projection::ProjectionStatus;
+use crate::raft::sink::ShippedEntry;
+use crate::raft::timing::{now_millis, EntryTiming, EntryTimings, LatencyHistogram};
+use crate::telemetry::TracedExt;
 use crate::server::{Server, Rebalance};
 
-type Entry = RaftEntry<MemoryStorageData>;
+pub mod testkit;
+
+/// Name of the file in the snapshot dir log entries are spilled to
+const LOG_SPILL_FILE: &str = "log.spill";
+
+/// Extension of snapshot files while they're being written, renamed away once complete
+const PARTIAL_SNAPSHOT_EXT: &str = "partial";
+
+/// How often the compaction schedule is evaluated
+const COMPACTION_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Backups kept on disk unless configured otherwise
+const DEFAULT_BACKUP_RETENTION: usize = 7;
+
+/// Version of the state machine data written into snapshots, bump it when `StampedData`
+/// changes incompatibly.
+pub const STATE_MACHINE_VERSION: u32 = 1;
+
+pub type Entry = RaftEntry<StampedData>;
 
 /// The concrete data type used by the `MemoryStorage` system.
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub enum MemoryStorageData {
     Add(NodeId),
     Remove(NodeId),
+    /// Announcement of a federated cluster, only proposed on a meta-cluster.
+    RegisterCluster(ClusterRecord),
+    /// Freeze (`true`) or unfreeze cluster-wide writes, while frozen every other proposal is
+    /// rejected and reads are still served.
+    Freeze(bool),
+    /// Take a lock unless someone else holds it, granting a fencing token.
+    AcquireLock { name: String, holder: String },
+    ReleaseLock { name: String, holder: String },
+    /// Commands applied in order as part of a single entry, all or none of them are logged.
+    Batch(Vec<MemoryStorageData>),
+    /// Change the addresses of a node in the replicated address book, peers reconnect to it
+    /// on its new cluster address.
+    SetAddress(NodeId, NodeInfo),
+    /// Command of the application state machine set with `Raftor::set_state_machine`,
+    /// MessagePack encoded.
+    App(Vec<u8>),
+    /// Rename the cluster and replace its labels, joins announcing other metadata are refused.
+    SetClusterMetadata(ClusterMetadata),
+    /// Entries through `index` were published to the log sink, proposed by the leader
+    /// shipping them.
+    SinkCheckpoint { sink: String, index: u64 },
+    /// Cordon (`true`) or uncordon a member: it stays in the membership but doesn't campaign
+    /// for leadership, and clients are routed to the other nodes first.
+    Cordon { node: NodeId, cordoned: bool },
+    /// Compress with this dictionary from now on, trained and proposed by the leader from
+    /// recent small proposals, see `dictionary`.
+    SetDictionary(Dictionary),
+    /// Take a cluster-consistent backup: every member dumps its state as of this entry, see
+    /// `backup`.
+    Backup { id: String },
+    /// An application command too large for one entry, streamed to a blob and applied once
+    /// fetched, see `blob`.
+    Blob(BlobRef),
+    /// Forget the debris of the entries stamped before this unix time in milliseconds,
+    /// proposed by the leader once it's older than the retention window, see `purge`.
+    Purge { before: u64 },
+}
+
+impl MemoryStorageData {
+    /// Name of the command, used to break down write metrics.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            MemoryStorageData::Add(_) => "add",
+            MemoryStorageData::Remove(_) => "remove",
+            MemoryStorageData::RegisterCluster(_) => "register_cluster",
+            MemoryStorageData::Freeze(_) => "freeze",
+            MemoryStorageData::AcquireLock { .. } => "acquire_lock",
+            MemoryStorageData::ReleaseLock { .. } => "release_lock",
+            MemoryStorageData::Batch(_) => "batch",
+            MemoryStorageData::SetAddress(..) => "set_address",
+            MemoryStorageData::App(_) => "app",
+            MemoryStorageData::SetClusterMetadata(_) => "set_cluster_metadata",
+            MemoryStorageData::SinkCheckpoint { .. } => "sink_checkpoint",
+            MemoryStorageData::Cordon { .. } => "cordon",
+            MemoryStorageData::SetDictionary(_) => "set_dictionary",
+            MemoryStorageData::Backup { .. } => "backup",
+            MemoryStorageData::Blob(_) => "blob",
+            MemoryStorageData::Purge { .. } => "purge",
+        }
+    }
+
+    /// The commands to apply, in order, with batches flattened.
+    pub fn commands(&self) -> Vec<&MemoryStorageData> {
+        match self {
+            MemoryStorageData::Batch(commands) => commands.iter().flat_map(|c| c.commands()).collect(),
+            command => vec![command],
+        }
+    }
+}
+
+/// Whether the cluster is frozen for writes, set by the storage when applying `Freeze` and
+/// checked by the raft client before proposing.
+pub type WriteFreeze = Arc<AtomicBool>;
+
+/// Epoch of the cluster, the index of the last entry applied which changed the leadership,
+/// the blank entry of a new leader, or the membership. Set by the storage, it's the same on
+/// every node once they applied the same entries and only ever grows, clients refresh their
+/// caches and routing tables when it moves.
+pub type ClusterEpoch = Arc<AtomicU64>;
+
+/// Unix time in milliseconds an entry was stamped with by its proposer, 0 for entries of
+/// raft itself.
+fn stamp(e: &Entry) -> u64 {
+    match e.payload {
+        EntryPayload::Normal(ref entry) => entry.data.hlc.physical,
+        _ => 0,
+    }
+}
+
+/// Index of the last replicated entry when one of them replaces an entry of another term:
+/// the entries after it belong to the same abandoned tail and are dropped.
+fn conflicting_tail(log: &mut LogCache, entries: &[Entry]) -> io::Result<Option<u64>> {
+    for e in entries {
+        if log.term(e.index)?.map_or(false, |term| term != e.term) {
+            return Ok(entries.last().map(|last| last.index));
+        }
+    }
+    Ok(None)
+}
+
+fn changes_epoch(e: &Entry) -> bool {
+    match e.payload {
+        EntryPayload::Blank | EntryPayload::ConfigChange(_) => true,
+        _ => false,
+    }
 }
 
-impl AppData for MemoryStorageData {}
+/// A command stamped with the hybrid logical clock of the leader which proposed it, this is
+/// what gets replicated through the log.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct StampedData {
+    pub hlc: Hlc,
+    pub data: MemoryStorageData,
+    /// Idempotency key of a forwarded proposal, a retry of an applied proposal is a no-op
+    #[serde(default)]
+    pub key: Option<String>,
+}
+
+impl AppData for StampedData {}
 
 /// The concrete data type used for responding from the storage engine when applying logs to the state machine.
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
@@ -40,14 +202,45 @@ pub struct MemoryStorageResponse;
 
 impl AppDataResponse for MemoryStorageResponse {}
 
-/// The concrete error type used by the `MemoryStorage` system.
-#[derive(Debug, Serialize, Deserialize)]
-pub struct MemoryStorageError;
+/// The concrete error type used by the `MemoryStorage` system. Proposals lost to a storage
+/// fault carry the fault and the index whose append failed back to their proposer.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct MemoryStorageError {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fault: Option<StorageFault>,
+    /// Log index whose append failed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub index: Option<u64>,
+    /// The deadline of the proposal passed before it was handed to raft, it wasn't appended
+    #[serde(default)]
+    pub expired: bool,
+}
+
+impl MemoryStorageError {
+    pub fn faulted(fault: StorageFault, index: Option<u64>) -> MemoryStorageError {
+        MemoryStorageError {
+            fault: Some(fault),
+            index: index,
+            expired: false,
+        }
+    }
+
+    pub fn expired() -> MemoryStorageError {
+        MemoryStorageError {
+            expired: true,
+            ..MemoryStorageError::default()
+        }
+    }
+}
 
 impl std::fmt::Display for MemoryStorageError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        // TODO: give this something a bit more meaningful.
-        write!(f, "MemoryStorageError")
+        match (&self.fault, self.index) {
+            (Some(fault), Some(index)) => write!(f, "appending log entry {} failed: {:?}", index, fault),
+            (Some(fault), None) => write!(f, "storage faulted: {:?}", fault),
+            _ if self.expired => write!(f, "deadline passed before the proposal was replicated"),
+            _ => write!(f, "rejected"),
+        }
     }
 }
 
@@ -55,6 +248,30 @@ impl std::error::Error for MemoryStorageError {}
 
 impl AppError for MemoryStorageError {}
 
+/// An IO failure which prevents the storage from persisting data durably.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum StorageFault {
+    /// The disk is full (ENOSPC).
+    DiskFull,
+    /// Any other IO error (e.g. EIO).
+    Io(String),
+}
+
+impl StorageFault {
+    pub fn from_io(err: &std::io::Error) -> StorageFault {
+        if err.raw_os_error() == Some(libc::ENOSPC) {
+            StorageFault::DiskFull
+        } else {
+            StorageFault::Io(err.to_string())
+        }
+    }
+}
+
+/// Sent to the fault recipient when the storage hits a `StorageFault`, with the index of the
+/// log entry whose append failed.
+#[derive(Message)]
+pub struct StorageFaulted(pub StorageFault, pub Option<u64>);
+
 /// A concrete implementation of the `RaftStorage` trait.
 ///
 /// This is primarity for testing and demo purposes. In a real application, storing Raft's data
@@ -62,20 +279,79 @@ impl AppError for MemoryStorageError {}
 ///
 /// This storage implementation structures its data as an append-only immutable log. The contents
 /// of the entries given to this storage implementation are not ready or manipulated.
+///
+/// Log entries are shared behind `Arc`s, a snapshot takes a frozen view of the entries it covers
+/// and serializes it on the snapshot actor while proposals keep being appended.
 pub struct MemoryStorage {
     hs: HardState,
-    log: BTreeMap<u64, Entry>,
+    log: LogCache,
     snapshot_data: Option<CurrentSnapshotData>,
     snapshot_dir: String,
     state_machine: BTreeMap<u64, Entry>,
+    /// Index each idempotency key was first applied at, and the stamp of its entry
+    applied_keys: HashMap<String, (u64, u64)>,
     snapshot_actor: Addr<SnapshotActor>,
     ring: RingType,
     server: Addr<Server>,
+    /// Whether the server drops the client sessions the ring moved elsewhere on `Add`
+    rebalance: bool,
+    fault: Option<StorageFault>,
+    /// Log index whose append hit the fault
+    failed_append: Option<u64>,
+    fault_recipient: Recipient<StorageFaulted>,
+    /// Told about address book changes
+    address_recipient: Recipient<AddressChanged>,
+    /// Told about cluster metadata changes
+    metadata_recipient: Option<Recipient<MetadataChanged>>,
+    /// Told about cordoned nodes
+    cordon_recipient: Option<Recipient<CordonChanged>>,
+    /// Fetches the blobs of entries about to be applied from peers
+    blob_fetcher: Option<Recipient<FetchBlob>>,
+    schedule: Option<CompactionSchedule>,
+    appended: u64,
+    /// Entries appended per second over the last `COMPACTION_CHECK_INTERVAL`
+    append_rate: u64,
+    compacting: bool,
+    timings: EntryTimings,
+    timings_file: Option<PathBuf>,
+    hooks: Hooks,
+    clock: SharedClock,
+    federation: FederationRegistry,
+    locks: LockTable,
+    sessions: SessionTable,
+    /// Last index published to each log sink
+    sink_checkpoints: BTreeMap<String, u64>,
+    cordoned: BTreeSet<NodeId>,
+    /// Dumps taken when applying backup markers, by backup id, written by the snapshot actor
+    backups: Arc<Mutex<BTreeMap<String, BackupPart>>>,
+    /// Backups kept on disk, the oldest are removed
+    backup_retention: usize,
+    /// Committed entries held back while in maintenance mode
+    paused: Option<Vec<Entry>>,
+    version_policy: SnapshotVersionPolicy,
+    frozen: WriteFreeze,
+    epoch: ClusterEpoch,
+    /// Superseded snapshots kept on disk besides the current one
+    snapshot_retention: usize,
+    snapshot_gc: SnapshotGcStats,
+    tombstones: TombstoneStats,
+    /// Durable copy of the log and hard state, when configured
+    durable: Option<Box<dyn LogStore>>,
+    /// Async-ack voters, when some voters may acknowledge buffered writes
+    durability: Option<DurabilityPolicy>,
+    /// Whether log writes are acknowledged before they're synced to disk, once the durability
+    /// policy was enforced
+    buffered_writes: Option<bool>,
+    /// Progress of the last vacuum of the log store, updated by its thread
+    vacuum: Arc<RwLock<VacuumProgress>>,
+    events: EventLog,
+    /// Term, log and applied indexes kept for the crash report
+    crash: Option<CrashState>,
 }
 
 impl MemoryStorage {
     /// Create a new instance.
-    pub fn new(members: Vec<NodeId>, snapshot_dir: String, ring: RingType, server: Addr<Server>) -> Self {
+    pub fn new(members: Vec<NodeId>, snapshot_dir: String, ring: RingType, server: Addr<Server>, fault_recipient: Recipient<StorageFaulted>, address_recipient: Recipient<AddressChanged>, schedule: Option<CompactionSchedule>, timings_file: Option<PathBuf>, hooks: Hooks, clock: SharedClock, version_policy: SnapshotVersionPolicy, frozen: WriteFreeze, log_cache_entries: Option<usize>, snapshot_retention: usize, events: EventLog) -> Self {
         let snapshot_dir_pathbuf = std::path::PathBuf::from(snapshot_dir.clone());
         let membership = MembershipConfig {
             members,
@@ -89,16 +365,247 @@ impl MemoryStorage {
                 voted_for: None,
                 membership,
             },
-            log: Default::default(),
+            log: LogCache::new(snapshot_dir_pathbuf.join(LOG_SPILL_FILE), log_cache_entries),
             snapshot_data: None,
             snapshot_dir,
             state_machine: Default::default(),
+            applied_keys: HashMap::new(),
             snapshot_actor: SyncArbiter::start(1, move || {
                 SnapshotActor(snapshot_dir_pathbuf.clone())
             }),
             ring: ring,
             server: server,
+            rebalance: true,
+            fault: None,
+            failed_append: None,
+            fault_recipient: fault_recipient,
+            address_recipient: address_recipient,
+            metadata_recipient: None,
+            cordon_recipient: None,
+            blob_fetcher: None,
+            schedule: schedule,
+            appended: 0,
+            append_rate: 0,
+            compacting: false,
+            timings: EntryTimings::new(),
+            timings_file: timings_file,
+            hooks: hooks,
+            clock: clock,
+            federation: FederationRegistry::default(),
+            locks: LockTable::default(),
+            sessions: SessionTable::default(),
+            sink_checkpoints: BTreeMap::new(),
+            cordoned: BTreeSet::new(),
+            backups: Arc::new(Mutex::new(BTreeMap::new())),
+            backup_retention: DEFAULT_BACKUP_RETENTION,
+            paused: None,
+            version_policy: version_policy,
+            frozen: frozen,
+            epoch: ClusterEpoch::default(),
+            snapshot_retention: snapshot_retention,
+            snapshot_gc: SnapshotGcStats::default(),
+            tombstones: TombstoneStats::default(),
+            durable: None,
+            durability: None,
+            buffered_writes: None,
+            vacuum: Arc::new(RwLock::new(VacuumProgress::default())),
+            events: events,
+            crash: None,
+        }
+    }
+
+    /// Tell the given recipient about the cluster metadata applied from the log.
+    pub fn with_metadata_recipient(mut self, recipient: Recipient<MetadataChanged>) -> Self {
+        self.metadata_recipient = Some(recipient);
+        self
+    }
+
+    /// Keep the client sessions of the server when nodes are added to the ring, for storages
+    /// of a ring the server doesn't route by.
+    pub fn without_rebalance(mut self) -> Self {
+        self.rebalance = false;
+        self
+    }
+
+    /// Keep the given number of backups on disk.
+    pub fn with_backup_retention(mut self, retention: usize) -> Self {
+        self.backup_retention = retention;
+        self
+    }
+
+    /// Publish the cluster epoch through the given handle.
+    pub fn with_epoch(mut self, epoch: ClusterEpoch) -> Self {
+        self.epoch = epoch;
+        self
+    }
+
+    /// Tell the given recipient which nodes are cordoned whenever it changes.
+    pub fn with_cordon_recipient(mut self, recipient: Recipient<CordonChanged>) -> Self {
+        self.cordon_recipient = Some(recipient);
+        self
+    }
+
+    /// Fetch the blobs this node misses through the given recipient before applying them.
+    pub fn with_blob_fetcher(mut self, recipient: Recipient<FetchBlob>) -> Self {
+        self.blob_fetcher = Some(recipient);
+        self
+    }
+
+    /// Resume the projections after their checkpoints kept next to the snapshots, and
+    /// checkpoint them every `checkpoint_entries` entries.
+    pub fn with_projection_checkpoints(self, checkpoint_entries: u64) -> Self {
+        let projections = self.hooks.read().unwrap().projections();
+        let mut projections = projections.lock().unwrap();
+        if !projections.is_empty() {
+            let dir = PathBuf::from(&self.snapshot_dir).join("projections");
+            projections.open(dir, checkpoint_entries).expect("Projection checkpoints to be read without error.");
+        }
+        drop(projections);
+        self
+    }
+
+    /// Account the log entries cached in memory to the memory of the node.
+    pub fn with_memory(mut self, memory: MemoryAccount) -> Self {
+        self.log.set_memory(memory);
+        self
+    }
+
+    /// Keep the crash report up to date with the term and indexes of this storage, and flush
+    /// its log store when the node panics. Set it before the log store.
+    pub fn with_crash_state(mut self, crash: CrashState) -> Self {
+        crash.record_hard_state(&self.hs);
+        self.crash = Some(crash);
+        self
+    }
+
+    /// Let this node acknowledge buffered log writes while it's an async-ack voter the
+    /// membership can spare. Set it before the log store.
+    pub fn with_durability_policy(mut self, policy: DurabilityPolicy) -> Self {
+        self.durability = Some(policy);
+        self
+    }
+
+    /// Recover the hard state and log persisted in the given store, every later change is
+    /// written through to it.
+    pub fn with_log_store(self, store: Box<dyn LogStore>) -> io::Result<Self> {
+        let recovered = store.load()?;
+        Ok(self.with_recovered_log(store, recovered))
+    }
+
+    /// Like `with_log_store`, with the hard state and log already loaded from the store.
+    pub(crate) fn with_recovered_log(mut self, store: Box<dyn LogStore>, (hs, entries): (Option<HardState>, Vec<Entry>)) -> Self {
+        if let Some(hs) = hs {
+            self.hs = hs;
+        }
+
+        info!("Recovered {} log entries at term {}.", entries.len(), self.hs.current_term);
+        for entry in entries {
+            // a compacted log starts with the pointer to the snapshot covering the rest
+            if let EntryPayload::SnapshotPointer(ref pointer) = entry.payload {
+                self.snapshot_data = Some(CurrentSnapshotData {
+                    index: entry.index,
+                    term: entry.term,
+                    membership: self.hs.membership.clone(),
+                    pointer: pointer.clone(),
+                });
+            }
+            self.log.insert(entry.index, Arc::new(entry));
+        }
+
+        if let Some(ref crash) = self.crash {
+            crash.record_hard_state(&self.hs);
+            crash.record_last_log_index(self.log.last().map(|(index, _)| index).unwrap_or(0));
+            if let Some(flusher) = store.flusher() {
+                crash.add_flusher(flusher);
+            }
+        }
+
+        self.durable = Some(store);
+        self.enforce_durability();
+        self
+    }
+
+    /// Sync log writes unless the durability policy lets this node buffer them under the
+    /// current membership, checked again whenever the membership changes.
+    fn enforce_durability(&mut self) {
+        let policy = match self.durability {
+            Some(ref policy) => policy,
+            None => return,
+        };
+        let buffered = policy.buffered(&self.hs.membership);
+        if Some(buffered) == self.buffered_writes {
+            return;
+        }
+
+        if buffered {
+            info!("Acknowledging buffered log writes as an async-ack voter of {:?}", self.hs.membership.members);
+        } else if policy.async_ack.contains(&policy.node) {
+            warn!(
+                "Syncing log writes, async-ack voters {:?} could form a majority of {:?} on their own",
+                policy.async_ack, self.hs.membership.members
+            );
+        }
+
+        if let Some(ref mut store) = self.durable {
+            store.set_synced(!buffered);
+            self.buffered_writes = Some(buffered);
+        }
+    }
+
+    /// Write through to the log store, failing writes are storage faults. `index` is the first
+    /// log index the write appends.
+    fn persist<F>(&mut self, index: Option<u64>, write: F) -> Result<(), MemoryStorageError>
+    where
+        F: FnOnce(&mut dyn LogStore) -> io::Result<()>,
+    {
+        let res = match self.durable {
+            Some(ref mut store) => write(&mut **store),
+            None => return Ok(()),
+        };
+
+        res.map_err(|err| {
+            error!("Error writing to the raft log store. {}", err);
+            let fault = StorageFault::from_io(&err);
+            self.storage_fault(fault.clone(), index);
+            MemoryStorageError::faulted(fault, index)
+        })
+    }
+
+    /// Record the apply time of the given entries, persisting their timings if configured.
+    fn record_applied(&mut self, indexes: Vec<u64>) {
+        if let (Some(crash), Some(index)) = (&self.crash, indexes.last()) {
+            crash.record_applied(*index);
+        }
+
+        let timings = indexes
+            .into_iter()
+            .filter_map(|index| self.timings.applied(index))
+            .collect::<Vec<_>>();
+
+        if let Some(ref path) = self.timings_file {
+            if !timings.is_empty() {
+                self.snapshot_actor.do_send(AppendEntryTimings(path.clone(), timings));
+            }
+        }
+    }
+
+    /// Record a snapshot actor error, escalating storage faults to the fault recipient.
+    fn snapshot_error(&mut self, err: SnapshotError) -> MemoryStorageError {
+        if let SnapshotError::Fault(fault) = err {
+            self.storage_fault(fault.clone(), None);
+            return MemoryStorageError::faulted(fault, None);
+        }
+
+        MemoryStorageError::default()
+    }
+
+    fn storage_fault(&mut self, fault: StorageFault, index: Option<u64>) {
+        if self.fault.is_none() {
+            error!("CRITICAL: storage fault, durability can no longer be guaranteed: {:?}", fault);
+            let _ = self.fault_recipient.do_send(StorageFaulted(fault.clone(), index));
+            self.failed_append = index;
         }
+        self.fault = Some(fault);
     }
 }
 
@@ -106,10 +613,28 @@ impl Actor for MemoryStorage {
     type Context = Context<Self>;
 
     /// Start this actor.
-    fn started(&mut self, _ctx: &mut Self::Context) {}
+    fn started(&mut self, ctx: &mut Self::Context) {
+        // a recovered log starting with a snapshot pointer needs its state machine back before
+        // raft asks for the initial state
+        if let Some(ref snapshot) = self.snapshot_data {
+            let path = PathBuf::from(&snapshot.pointer.path);
+            let rebuild = self
+                .rebuild_state_machine_from_snapshot(ctx, path)
+                .map_err(|_, _, _| error!("Error rebuilding the state machine from the recovered snapshot."));
+            ctx.wait(rebuild);
+        }
+
+        if self.schedule.is_some() {
+            ctx.run_interval(COMPACTION_CHECK_INTERVAL, |act, _| {
+                let elapsed = COMPACTION_CHECK_INTERVAL.as_millis() as u64;
+                act.append_rate = act.appended * 1000 / elapsed;
+                act.appended = 0;
+            });
+        }
+    }
 }
 
-impl RaftStorage<MemoryStorageData, MemoryStorageResponse, MemoryStorageError> for MemoryStorage {
+impl RaftStorage<StampedData, MemoryStorageResponse, MemoryStorageError> for MemoryStorage {
     type Actor = Self;
     type Context = Context<Self>;
 }
@@ -123,8 +648,8 @@ impl Handler<GetInitialState<MemoryStorageError>> for MemoryStorage {
         _: &mut Self::Context,
     ) -> Self::Result {
         Box::new(fut::ok(InitialState {
-            last_log_index: self.log.iter().last().map(|e| *e.0).unwrap_or(0),
-            last_log_term: self.log.iter().last().map(|e| e.1.term).unwrap_or(0),
+            last_log_index: self.log.last().map(|(index, _)| index).unwrap_or(0),
+            last_log_term: self.log.last().map(|(_, term)| term).unwrap_or(0),
             last_applied_log: self.state_machine.iter().last().map(|e| *e.0).unwrap_or(0),
             hard_state: self.hs.clone(),
         }))
@@ -139,128 +664,510 @@ impl Handler<SaveHardState<MemoryStorageError>> for MemoryStorage {
         msg: SaveHardState<MemoryStorageError>,
         _: &mut Self::Context,
     ) -> Self::Result {
+        let res = self.persist(None, |store| store.save_hard_state(&msg.hs));
+        if let Some(ref crash) = self.crash {
+            crash.record_hard_state(&msg.hs);
+        }
         self.hs = msg.hs;
-        Box::new(fut::ok(()))
+        self.enforce_durability();
+        Box::new(fut::result(res))
     }
 }
 
-impl Handler<GetLogEntries<MemoryStorageData, MemoryStorageError>> for MemoryStorage {
+impl Handler<GetLogEntries<StampedData, MemoryStorageError>> for MemoryStorage {
     type Result = ResponseActFuture<Self, Vec<Entry>, MemoryStorageError>;
 
     fn handle(
         &mut self,
-        msg: GetLogEntries<MemoryStorageData, MemoryStorageError>,
+        msg: GetLogEntries<StampedData, MemoryStorageError>,
         _: &mut Self::Context,
     ) -> Self::Result {
-        Box::new(fut::ok(
+        Box::new(fut::result(
             self.log
                 .range(msg.start..msg.stop)
-                .map(|e| e.1.clone())
-                .collect(),
+                .map(|entries| entries.into_iter().map(|e| (*e).clone()).collect())
+                .map_err(|err| {
+                    error!("Error reading spilled log entries. {}", err);
+                    MemoryStorageError::default()
+                }),
         ))
     }
 }
 
-impl Handler<AppendEntryToLog<MemoryStorageData, MemoryStorageError>> for MemoryStorage {
+impl Handler<AppendEntryToLog<StampedData, MemoryStorageError>> for MemoryStorage {
     type Result = ResponseActFuture<Self, (), MemoryStorageError>;
 
     fn handle(
         &mut self,
-        msg: AppendEntryToLog<MemoryStorageData, MemoryStorageError>,
+        msg: AppendEntryToLog<StampedData, MemoryStorageError>,
         _: &mut Self::Context,
     ) -> Self::Result {
-        self.log.insert(msg.entry.index, (*msg.entry).clone());
+        if let Err(err) = self.persist(Some(msg.entry.index), |store| store.append(&[&*msg.entry])) {
+            return Box::new(fut::err(err));
+        }
+        self.log.insert(msg.entry.index, msg.entry.clone());
+        self.timings.appended(msg.entry.index, msg.entry.term);
+        self.appended += 1;
+        if let Some(ref crash) = self.crash {
+            crash.record_last_log_index(msg.entry.index);
+        }
         Box::new(fut::ok(()))
     }
 }
 
-impl Handler<ReplicateToLog<MemoryStorageData, MemoryStorageError>> for MemoryStorage {
+impl Handler<ReplicateToLog<StampedData, MemoryStorageError>> for MemoryStorage {
     type Result = ResponseActFuture<Self, (), MemoryStorageError>;
 
     fn handle(
         &mut self,
-        msg: ReplicateToLog<MemoryStorageData, MemoryStorageError>,
+        msg: ReplicateToLog<StampedData, MemoryStorageError>,
         _: &mut Self::Context,
     ) -> Self::Result {
+        // entries of another term at the same indexes were never committed, they and the
+        // entries following them are dropped
+        let conflict = match conflicting_tail(&mut self.log, &msg.entries) {
+            Ok(conflict) => conflict,
+            Err(err) => {
+                error!("Error reading spilled log entries. {}", err);
+                return Box::new(fut::err(MemoryStorageError::default()));
+            }
+        };
+        let res = self.persist(msg.entries.first().map(|e| e.index), |store| {
+            store.append(&msg.entries.iter().collect::<Vec<_>>())?;
+            conflict.map_or(Ok(()), |last| store.truncate_after(last))
+        });
+        if let Err(err) = res {
+            return Box::new(fut::err(err));
+        }
         msg.entries.iter().for_each(|e| {
-            self.log.insert(e.index, e.clone());
+            self.log.insert(e.index, Arc::new(e.clone()));
+            self.timings.appended(e.index, e.term);
         });
+        if let Some(last) = conflict {
+            self.log.truncate_after(last);
+        }
+        self.appended += msg.entries.len() as u64;
+        if let (Some(crash), Some(last)) = (&self.crash, msg.entries.last()) {
+            crash.record_last_log_index(last.index);
+        }
         Box::new(fut::ok(()))
     }
 }
 
-impl Handler<ApplyEntryToStateMachine<MemoryStorageData, MemoryStorageResponse, MemoryStorageError>>
+impl Handler<ApplyEntryToStateMachine<StampedData, MemoryStorageResponse, MemoryStorageError>>
     for MemoryStorage
 {
     type Result = ResponseActFuture<Self, MemoryStorageResponse, MemoryStorageError>;
 
     fn handle(
         &mut self,
-        msg: ApplyEntryToStateMachine<MemoryStorageData, MemoryStorageResponse, MemoryStorageError>,
+        msg: ApplyEntryToStateMachine<StampedData, MemoryStorageResponse, MemoryStorageError>,
         _ctx: &mut Self::Context,
     ) -> Self::Result {
-        let res = if let Some(old) = self
-            .state_machine
-            .insert(msg.payload.index, (*msg.payload).clone())
-        {
-            println!("Critical error. State machine entires are not allowed to be overwritten. Entry: {:?}", old);
-            Err(MemoryStorageError)
-        } else {
-            if let EntryPayload::Normal(entry) = &msg.payload.payload {
-                let mut ring = self.ring.write().unwrap();
-                match (*entry).data {
-                    MemoryStorageData::Add(node_id) => {
-                        println!("Adding node {}", node_id);
-                        ring.add_node(&node_id);
-                        self.server.do_send(Rebalance)
-                    }
-                    MemoryStorageData::Remove(node_id) => {
-                        println!("Removing node {}", node_id);
-                        ring.remove_node(&node_id)
-                    }
-                }
-            } else {
+        let span = crate::trace_span!("raft_apply", index = msg.payload.index, term = msg.payload.term);
+        self.timings.committed(msg.payload.index);
+        // only a leader applies entries one by one, it can't stay in maintenance
+        if self.paused.is_some() {
+            warn!("Leaving maintenance mode, this node became leader");
+            if let Err(err) = self.resume() {
+                return Box::new(fut::err(err));
             }
+        }
 
-            Ok(MemoryStorageResponse)
-        };
-        Box::new(fut::result(res))
+        let entry = msg.payload;
+        Box::new(self.fetch_blobs(&[&*entry]).and_then(move |_, act: &mut Self, _| {
+            let res = act.apply(&entry).map(|_| {
+                act.record_applied(vec![entry.index]);
+                MemoryStorageResponse
+            });
+            fut::result(res)
+        })
+        .traced(span))
     }
 }
 
-impl Handler<ReplicateToStateMachine<MemoryStorageData, MemoryStorageError>> for MemoryStorage {
+impl Handler<ReplicateToStateMachine<StampedData, MemoryStorageError>> for MemoryStorage {
     type Result = ResponseActFuture<Self, (), MemoryStorageError>;
 
     fn handle(
         &mut self,
-        msg: ReplicateToStateMachine<MemoryStorageData, MemoryStorageError>,
+        msg: ReplicateToStateMachine<StampedData, MemoryStorageError>,
         _ctx: &mut Self::Context,
     ) -> Self::Result {
-        let res = msg.payload.iter().try_for_each(|e| {
-            if let Some(old) = self.state_machine.insert(e.index, e.clone()) {
-                println!("Critical error. State machine entires are not allowed to be overwritten. Entry: {:?}", old);
-                return Err(MemoryStorageError)
+        let span = crate::trace_span!("raft_replicate_apply", entries = msg.payload.len());
+        for e in msg.payload.iter() {
+            self.timings.committed(e.index);
+        }
+        // entries are already in the log, hold them back until maintenance ends
+        if let Some(ref mut pending) = self.paused {
+            pending.extend(msg.payload.iter().cloned());
+            return Box::new(fut::ok(()));
+        }
+
+        let entries = msg.payload;
+        Box::new(self.fetch_blobs(&entries.iter().collect::<Vec<_>>()).and_then(move |_, act: &mut Self, _| {
+            let mut applied = Vec::new();
+            let res = entries.iter().try_for_each(|e| {
+                act.apply(e)?;
+                applied.push(e.index);
+                Ok(())
+            });
+            act.record_applied(applied);
+            fut::result(res)
+        })
+        .traced(span))
+    }
+}
+
+impl MemoryStorage {
+    /// The entry as shown to operators, with its commands redacted.
+    fn inspect(&self, e: &Entry) -> InspectedEntry {
+        let (kind, hlc, data) = match e.payload {
+            EntryPayload::Blank => ("blank", None, None),
+            EntryPayload::Normal(ref entry) => (
+                "normal",
+                Some(entry.data.hlc),
+                Some(self.hooks.read().unwrap().redact(&entry.data.data)),
+            ),
+            EntryPayload::ConfigChange(_) => ("config_change", None, None),
+            EntryPayload::SnapshotPointer(_) => ("snapshot_pointer", None, None),
+        };
+
+        InspectedEntry {
+            index: e.index,
+            term: e.term,
+            kind: kind.to_owned(),
+            hlc: hlc,
+            data: data,
+        }
+    }
+
+    /// Apply a committed entry to the state machine.
+    fn apply(&mut self, e: &Entry) -> Result<(), MemoryStorageError> {
+        if let Some(old) = self.state_machine.insert(e.index, e.clone()) {
+            println!("Critical error. State machine entires are not allowed to be overwritten. Entry: {:?}", self.inspect(&old));
+            return Err(MemoryStorageError::default())
+        }
+        if changes_epoch(e) {
+            self.epoch.fetch_max(e.index, Ordering::SeqCst);
+        }
+        if let EntryPayload::Normal(entry) = &e.payload {
+            if let Some(index) = self.first_applied(e) {
+                debug!("Entry {} retries the proposal applied at {}, skipping it", e.index, index);
+                return Ok(());
             }
-            if let EntryPayload::Normal(entry) = &e.payload {
-                let mut ring = self.ring.write().unwrap();
-                match entry.data {
+            self.sessions.apply(e.index, entry.data.hlc.physical, entry.data.key.as_ref());
+
+            let ring = self.ring.clone();
+            let mut ring = ring.write().unwrap();
+            for command in entry.data.data.commands() {
+                match *command {
                     MemoryStorageData::Add(node_id) => {
                         println!("Adding node {}", node_id);
                         ring.add_node(&node_id);
-                        self.server.do_send(Rebalance)
+                        if self.rebalance {
+                            self.server.do_send(Rebalance)
+                        }
                     }
                     MemoryStorageData::Remove(node_id) => {
                         println!("Removing node {}", node_id);
                         ring.remove_node(&node_id)
                     }
+                    MemoryStorageData::RegisterCluster(ref record) => {
+                        self.federation.register(record.clone(), entry.data.hlc.physical)
+                    }
+                    MemoryStorageData::Freeze(frozen) => {
+                        println!("Cluster writes {}", if frozen { "frozen" } else { "unfrozen" });
+                        self.frozen.store(frozen, Ordering::SeqCst)
+                    }
+                    MemoryStorageData::AcquireLock { .. } | MemoryStorageData::ReleaseLock { .. } => {
+                        self.locks.apply(e.index, e.term, command)
+                    }
+                    MemoryStorageData::SetAddress(id, ref info) => {
+                        println!("Node {} moved to {}", id, info.cluster_addr);
+                        let _ = self.address_recipient.do_send(AddressChanged(id, info.clone()));
+                    }
+                    MemoryStorageData::App(ref command) => {
+                        io_stats::payload_applied(command.len() as u64);
+                        self.apply_app(e.index, command)
+                    }
+                    MemoryStorageData::SetClusterMetadata(ref metadata) => {
+                        println!("Cluster renamed to {}", metadata.name);
+                        self.metadata_changed(metadata)
+                    }
+                    MemoryStorageData::SinkCheckpoint { ref sink, index } => {
+                        let checkpoint = self.sink_checkpoints.entry(sink.clone()).or_insert(0);
+                        *checkpoint = (*checkpoint).max(index);
+                    }
+                    MemoryStorageData::Cordon { node, cordoned } => {
+                        println!("Node {} {}", node, if cordoned { "cordoned" } else { "uncordoned" });
+                        self.cordon(node, cordoned);
+                        self.cordon_changed();
+                    }
+                    MemoryStorageData::SetDictionary(ref dict) => install_dictionary(dict),
+                    MemoryStorageData::Backup { ref id } => self.capture_backup(id),
+                    MemoryStorageData::Blob(ref blob) => {
+                        io_stats::payload_applied(blob.size);
+                        self.apply_blob(e.index, blob)
+                    }
+                    MemoryStorageData::Purge { before } => {
+                        let purged = self.purge(e.index, before);
+                        info!("Purged {} entries, {} idempotency keys and {} client sessions stamped before {}", purged.entries, purged.keys, purged.sessions, before);
+                        self.tombstones.add(before, &purged);
+                    }
+                    MemoryStorageData::Batch(_) => (),
+                }
+            }
+            // hooks may look at the ring
+            drop(ring);
+            self.clock.lock().unwrap().update(entry.data.hlc);
+            let hooks = self.hooks.read().unwrap();
+            for command in entry.data.data.commands() {
+                hooks.post_apply(e.index, entry.data.hlc, command);
+            }
+            hooks.projections().lock().unwrap().apply(e.index, entry.data.hlc, &entry.data.data.commands());
+        }
+
+        Ok(())
+    }
+
+    /// Index the proposal of an entry was already applied at, when the entry is a retry of it.
+    fn first_applied(&mut self, e: &Entry) -> Option<u64> {
+        let key = match e.payload {
+            EntryPayload::Normal(ref entry) => entry.data.key.clone()?,
+            _ => return None,
+        };
+
+        match self.applied_keys.get(&key) {
+            Some((index, _)) if *index < e.index => Some(*index),
+            _ => {
+                self.applied_keys.insert(key, (e.index, stamp(e)));
+                None
+            }
+        }
+    }
+
+    /// Drop the debris of the entries applied before the purge at `index` and stamped before
+    /// `before`: retried proposals, released or superseded lock acquisitions, superseded sink
+    /// checkpoints and earlier purges, with the idempotency keys and the client sessions last
+    /// written before then. Replaying what's left rebuilds the same state, every node purges
+    /// the same entries as it applies the same log.
+    fn purge(&mut self, index: u64, before: u64) -> PurgedDebris {
+        let debris = self
+            .state_machine
+            .range(..index)
+            .filter(|(_, e)| stamp(e) < before && self.is_debris(e))
+            .map(|(index, _)| *index)
+            .collect::<Vec<_>>();
+        for index in debris.iter() {
+            self.state_machine.remove(index);
+        }
+
+        let keys = self.applied_keys.len();
+        self.applied_keys.retain(|_, (_, at)| *at >= before);
+
+        PurgedDebris {
+            entries: debris.len() as u64,
+            keys: (keys - self.applied_keys.len()) as u64,
+            sessions: self.sessions.purge(before) as u64,
+        }
+    }
+
+    /// Whether a purge of what was stamped before `before` would drop anything, besides the
+    /// earlier purges themselves.
+    fn debris_due(&self, before: u64) -> bool {
+        let purge_only = |e: &Entry| match e.payload {
+            EntryPayload::Normal(ref entry) => entry
+                .data
+                .data
+                .commands()
+                .iter()
+                .all(|command| matches!(command, MemoryStorageData::Purge { .. })),
+            _ => false,
+        };
+
+        self.applied_keys.values().any(|(_, at)| *at < before)
+            || self.sessions.stale(before)
+            || self
+                .state_machine
+                .values()
+                .any(|e| stamp(e) < before && !purge_only(e) && self.is_debris(e))
+    }
+
+    /// Whether replaying the entry changes nothing anymore.
+    fn is_debris(&self, e: &Entry) -> bool {
+        let entry = match e.payload {
+            EntryPayload::Normal(ref entry) => entry,
+            _ => return false,
+        };
+        let retried = entry
+            .data
+            .key
+            .as_ref()
+            .and_then(|key| self.applied_keys.get(key))
+            .map(|(index, _)| *index < e.index)
+            .unwrap_or(false);
+        if retried {
+            return true;
+        }
+
+        entry.data.data.commands().iter().all(|command| match command {
+            MemoryStorageData::AcquireLock { name, .. } => {
+                !self.locks.is_fresh(name, FencingToken { term: e.term, index: e.index })
+            }
+            MemoryStorageData::ReleaseLock { .. } | MemoryStorageData::Purge { .. } => true,
+            MemoryStorageData::SinkCheckpoint { sink, index } => {
+                self.sink_checkpoints.get(sink).map(|checkpoint| index < checkpoint).unwrap_or(false)
+            }
+            _ => false,
+        })
+    }
+
+    /// Dump the state applied so far, through the backup marker being applied. The dump is
+    /// written by the snapshot actor so applying goes on meanwhile, the oldest backups beyond
+    /// the retention are removed once it's done. Replaying the marker when rebuilding from a
+    /// snapshot doesn't dump it again.
+    fn capture_backup(&mut self, id: &str) {
+        let dir = backup::backup_dir(&self.snapshot_dir, id);
+        let capture = CaptureBackup(dir.clone(), self.state_dump());
+        let (id, backups, retention) = (id.to_owned(), self.backups.clone(), self.backup_retention);
+        let (snapshot_dir, snapshot_actor) = (self.snapshot_dir.clone(), self.snapshot_actor.clone());
+
+        Arbiter::spawn(self.snapshot_actor.send(capture).then(move |res| {
+            match res {
+                Ok(Ok(part)) => {
+                    info!("Dumped backup {} at index {} to {:?}", id, part.index, dir);
+                    let mut backups = backups.lock().unwrap();
+                    backups.insert(id, part);
+
+                    let stale = backup::stale(&backups, retention);
+                    for id in stale.iter() {
+                        backups.remove(id);
+                    }
+                    if !stale.is_empty() {
+                        info!("Removing backups {:?} beyond the retention of {}", stale, retention);
+                        let dirs = stale.iter().map(|id| backup::backup_dir(&snapshot_dir, id)).collect();
+                        snapshot_actor.do_send(RemoveBackups(dirs));
+                    }
                 }
-            } else {
+                Ok(Err(err)) => error!("Failed to dump backup {} to {:?}: {}", id, dir, err),
+                Err(err) => error!("Error communicating with snapshot actor. {}", err),
+            }
+            Ok(())
+        }));
+    }
+
+    fn cordon(&mut self, node: NodeId, cordoned: bool) {
+        if cordoned {
+            self.cordoned.insert(node);
+        } else {
+            self.cordoned.remove(&node);
+        }
+    }
+
+    fn cordon_changed(&self) {
+        if let Some(ref recipient) = self.cordon_recipient {
+            let _ = recipient.do_send(CordonChanged(self.cordoned.clone()));
+        }
+    }
+
+    fn metadata_changed(&self, metadata: &ClusterMetadata) {
+        if let Some(ref recipient) = self.metadata_recipient {
+            let _ = recipient.do_send(MetadataChanged(metadata.clone()));
+        }
+    }
+
+    /// Apply a command to the application state machine, a command it can't decode is logged
+    /// and skipped on every node alike.
+    fn apply_app(&self, index: u64, command: &[u8]) {
+        // empty commands carry nothing for the state machine, e.g. the writes of `raftor bench`
+        if command.is_empty() {
+            return;
+        }
+
+        let (state_machine, metrics) = {
+            let hooks = self.hooks.read().unwrap();
+            (hooks.state_machine(), hooks.app_metrics())
+        };
+        let state_machine = match state_machine {
+            Some(state_machine) => state_machine,
+            None => return warn!("Skipping application command at {}, no state machine is set", index),
+        };
+
+        let mut current = state_machine.lock().unwrap();
+        if let Err(err) = current.apply(&mut ApplyContext::new(&metrics), index, command) {
+            error!("Failed to apply application command at {}: {}", index, err);
+        }
 
+        let canary = match self.hooks.read().unwrap().canary() {
+            Some(canary) => canary,
+            None => return,
+        };
+        let diverged = canary.lock().unwrap().apply(index, command, &**current);
+        if let Some((digest, canary_digest)) = diverged {
+            error!("Canary state machine diverged at {}: digest {:x}, canary {:x}", index, digest, canary_digest);
+            self.events.alert(format!("canary state machine diverged from the current version at {}", index));
+        }
+    }
+
+    /// Apply the command held by a blob, fetched before the entry was applied.
+    fn apply_blob(&self, index: u64, blob: &BlobRef) {
+        match blob::read(&self.snapshot_dir, blob) {
+            Ok(command) => self.apply_app(index, &command),
+            Err(err) => {
+                error!("CRITICAL: skipping blob {} at {}, it can't be read: {}", blob.id, index, err);
+                self.events.alert(format!("blob {} applied at {} is missing", blob.id, index));
             }
+        }
+    }
+
+    /// Fetch the blobs referenced by the entries which this node doesn't have yet. A blob no
+    /// peer can hand over fails the apply rather than letting this node diverge.
+    fn fetch_blobs(&self, entries: &[&Entry]) -> Box<dyn ActorFuture<Item = (), Error = MemoryStorageError, Actor = Self>> {
+        let missing = entries
+            .iter()
+            .filter_map(|e| match e.payload {
+                EntryPayload::Normal(ref entry) => Some(entry.data.data.commands()),
+                _ => None,
+            })
+            .flatten()
+            .filter_map(|command| match command {
+                MemoryStorageData::Blob(ref blob) if !blob::exists(&self.snapshot_dir, blob) => Some(blob.clone()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let fetcher = match (missing.is_empty(), self.blob_fetcher.as_ref()) {
+            (true, _) | (false, None) => return Box::new(fut::ok(())),
+            (false, Some(fetcher)) => fetcher.clone(),
+        };
+
+        let snapshot_dir = self.snapshot_dir.clone();
+        let fetches = missing.into_iter().map(move |blob| {
+            let id = blob.id.clone();
+            fetcher
+                .send(FetchBlob { blob: blob, snapshot_dir: snapshot_dir.clone() })
+                .then(move |res| match res {
+                    Ok(Ok(())) => Ok(()),
+                    _ => {
+                        error!("CRITICAL: blob {} couldn't be fetched from any peer", id);
+                        Err(MemoryStorageError::default())
+                    }
+                })
+        });
+        Box::new(fut::wrap_future(futures::future::join_all(fetches.collect::<Vec<_>>()).map(|_| ())))
+    }
 
+    /// Leave maintenance mode, applying the entries committed in the meantime.
+    fn resume(&mut self) -> Result<(), MemoryStorageError> {
+        let pending = self.paused.take().unwrap_or_default();
+        let mut applied = Vec::new();
+        let res = pending.iter().try_for_each(|e| {
+            self.apply(e)?;
+            applied.push(e.index);
             Ok(())
         });
-        Box::new(fut::result(res))
+        self.record_applied(applied);
+        res
     }
 }
 
@@ -272,60 +1179,9 @@ impl Handler<CreateSnapshot<MemoryStorageError>> for MemoryStorage {
         msg: CreateSnapshot<MemoryStorageError>,
         _: &mut Self::Context,
     ) -> Self::Result {
-        debug!(
-            "Creating new snapshot under '{}' through index {}.",
-            &self.snapshot_dir, &msg.through
-        );
-        // Serialize snapshot data.
-        let through = msg.through;
-        let entries = self
-            .log
-            .range(0u64..=through)
-            .map(|(_, v)| v.clone())
-            .collect::<Vec<_>>();
-        debug!("Creating snapshot with {} entries.", entries.len());
-        let (index, term) = entries.last().map(|e| (e.index, e.term)).unwrap_or((0, 0));
-        let snapdata = match rmps::to_vec(&entries) {
-            Ok(snapdata) => snapdata,
-            Err(err) => {
-                error!("Error serializing log for creating a snapshot. {}", err);
-                return Box::new(fut::err(MemoryStorageError));
-            }
-        };
-
-        // Create snapshot file and write snapshot data to it.
-        let filename = format!("{}", msg.through);
-        let filepath = std::path::PathBuf::from(self.snapshot_dir.clone()).join(filename);
-        Box::new(
-            fut::wrap_future(
-                self.snapshot_actor
-                    .send(CreateSnapshotWithData(filepath.clone(), snapdata)),
-            )
-            .map_err(|err, _, _| panic!("Error communicating with snapshot actor. {}", err))
-            .and_then(|res, _, _| fut::result(res))
-            // Clean up old log entries which are now part of the new snapshot.
-            .and_then(move |_, act: &mut Self, _| {
-                let path = filepath.to_string_lossy().to_string();
-                debug!("Finished creating snapshot file at {}", &path);
-                act.log = act.log.split_off(&through);
-                let pointer = EntrySnapshotPointer { path };
-                let entry = Entry::new_snapshot_pointer(pointer.clone(), index, term);
-                act.log.insert(through, entry);
-
-                // Cache the most recent snapshot data.
-                let current_snap_data = CurrentSnapshotData {
-                    term,
-                    index,
-                    membership: act.hs.membership.clone(),
-                    pointer,
-                };
-                act.snapshot_data = Some(current_snap_data.clone());
-
-                fut::ok(current_snap_data)
-            }),
-        )
-    }
-}
+        self.scheduled_snapshot(msg.through)
+    }
+}
 
 impl Handler<InstallSnapshot<MemoryStorageError>> for MemoryStorage {
     type Result = ResponseActFuture<Self, (), MemoryStorageError>;
@@ -336,12 +1192,38 @@ impl Handler<InstallSnapshot<MemoryStorageError>> for MemoryStorage {
         _: &mut Self::Context,
     ) -> Self::Result {
         let (index, term) = (msg.index, msg.term);
+        let migrator = self.hooks.read().unwrap().migrator();
+        let install = SyncInstallSnapshot(msg, self.version_policy.clone(), migrator);
         Box::new(
-            fut::wrap_future(self.snapshot_actor.send(SyncInstallSnapshot(msg)))
+            fut::wrap_future(self.snapshot_actor.send(install))
                 .map_err(|err, _, _| panic!("Error communicating with snapshot actor. {}", err))
-                .and_then(|res, _, _| fut::result(res))
+                .and_then(|res, act: &mut Self, _| fut::result(res.map_err(|err| act.snapshot_error(err))))
                 // Snapshot file has been created. Perform final steps of this algorithm.
                 .and_then(move |pointer, act: &mut Self, ctx| {
+                    // entries following the snapshot are kept if the log agrees with it at its
+                    // index, otherwise the whole log is replaced by the snapshot
+                    let retained = match act.log.term(index) {
+                        Ok(previous) => previous == Some(term),
+                        Err(err) => {
+                            error!("Error reading spilled log entries. {}", err);
+                            false
+                        }
+                    };
+
+                    // Update target index with the new snapshot pointer.
+                    let entry = Entry::new_snapshot_pointer(pointer.clone(), index, term);
+                    let persisted = act.persist(None, |store| {
+                        store.truncate_before(index)?;
+                        if !retained {
+                            store.truncate_after(index)?;
+                        }
+                        store.append(&[&entry])
+                    });
+                    if let Err(err) = persisted {
+                        return fut::Either::A(fut::err(err));
+                    }
+                    act.events.record(EventKind::SnapshotInstalled { index: index, term: term });
+
                     // Cache the most recent snapshot data.
                     act.snapshot_data = Some(CurrentSnapshotData {
                         index,
@@ -349,24 +1231,22 @@ impl Handler<InstallSnapshot<MemoryStorageError>> for MemoryStorage {
                         membership: act.hs.membership.clone(),
                         pointer: pointer.clone(),
                     });
+                    act.collect_snapshots(ctx);
 
-                    // Update target index with the new snapshot pointer.
-                    let entry = Entry::new_snapshot_pointer(pointer.clone(), index, term);
-                    act.log = act.log.split_off(&index);
-                    let previous = act.log.insert(index, entry);
+                    act.log.truncate_before(index);
+                    if !retained {
+                        act.log.truncate_after(index);
+                    }
+                    act.log.insert(index, Arc::new(entry));
 
-                    // If there are any logs newer than `index`, then we are done. Else, the state
+                    // If the log agreed with the snapshot, then we are done. Else, the state
                     // machine should be reset, and recreated from the new snapshot.
-                    match &previous {
-                        Some(entry) if entry.index == index && entry.term == term => {
-                            fut::Either::A(fut::ok(()))
-                        }
-                        // There are no newer entries in the log, which means that we need to rebuild
-                        // the state machine. Open the snapshot file read out its entries.
-                        _ => {
-                            let pathbuf = PathBuf::from(pointer.path);
-                            fut::Either::B(act.rebuild_state_machine_from_snapshot(ctx, pathbuf))
-                        }
+                    if retained {
+                        fut::Either::A(fut::ok(()))
+                    } else {
+                        // Open the snapshot file read out its entries.
+                        let pathbuf = PathBuf::from(pointer.path);
+                        fut::Either::B(act.rebuild_state_machine_from_snapshot(ctx, pathbuf))
                     }
                 }),
         )
@@ -387,6 +1267,142 @@ impl Handler<GetCurrentSnapshot<MemoryStorageError>> for MemoryStorage {
 }
 
 impl MemoryStorage {
+    /// Write a snapshot covering the log through the given index and compact the log.
+    fn create_snapshot(
+        &mut self,
+        through: u64,
+    ) -> ResponseActFuture<Self, CurrentSnapshotData, MemoryStorageError> {
+        debug!(
+            "Creating new snapshot under '{}' through index {}.",
+            &self.snapshot_dir, &through
+        );
+        // Freeze the entries covered by the snapshot, only their pointers are copied so writes
+        // are not stalled while the snapshot actor serializes them.
+        let entries = match self.log.range(0u64..=through) {
+            Ok(entries) => entries,
+            Err(err) => {
+                error!("Error reading spilled log entries for a snapshot. {}", err);
+                return Box::new(fut::err(MemoryStorageError::default()));
+            }
+        };
+        let (index, term) = entries.last().map(|e| (e.index, e.term)).unwrap_or((0, 0));
+        let entries = self.snapshot_entries(entries);
+        debug!("Creating snapshot with {} entries.", entries.len());
+
+        // Create snapshot file and write snapshot data to it.
+        let filename = format!("{}", through);
+        let filepath = std::path::PathBuf::from(self.snapshot_dir.clone()).join(filename);
+        Box::new(
+            fut::wrap_future(
+                self.snapshot_actor
+                    .send(CreateSnapshotFromEntries(filepath.clone(), entries)),
+            )
+            .map_err(|err, _, _| panic!("Error communicating with snapshot actor. {}", err))
+            .and_then(|res, act: &mut Self, _| fut::result(res.map_err(|err| act.snapshot_error(err))))
+            // Clean up old log entries which are now part of the new snapshot.
+            .and_then(move |_, act: &mut Self, ctx| {
+                let path = filepath.to_string_lossy().to_string();
+                debug!("Finished creating snapshot file at {}", &path);
+                let pointer = EntrySnapshotPointer { path };
+                let entry = Entry::new_snapshot_pointer(pointer.clone(), index, term);
+                if let Err(err) = act.persist(None, |store| store.truncate_before(through).and_then(|_| store.append(&[&entry]))) {
+                    // the log stays as it was, raft retries the compaction later
+                    act.compacting = false;
+                    return fut::err(err);
+                }
+                act.events.record(EventKind::SnapshotCreated { index: index, term: term });
+                act.log.truncate_before(through);
+                act.log.insert(through, Arc::new(entry));
+
+                // Cache the most recent snapshot data.
+                let current_snap_data = CurrentSnapshotData {
+                    term,
+                    index,
+                    membership: act.hs.membership.clone(),
+                    pointer,
+                };
+                act.snapshot_data = Some(current_snap_data.clone());
+                act.compacting = false;
+                act.collect_snapshots(ctx);
+
+                fut::ok(current_snap_data)
+            }),
+        )
+    }
+
+    /// What a snapshot of the given log entries is made of. The applied entries come from the
+    /// state machine, which the purges drop debris from: the pointer to the previous snapshot
+    /// is replaced by the entries it covers so debris purged since doesn't live on in it, and
+    /// purged entries of the log are left out. Entries not applied yet, e.g. held back by
+    /// maintenance mode, are kept as they are. Replaying the result rebuilds the same state.
+    fn snapshot_entries(&self, log: Vec<Arc<Entry>>) -> Vec<Arc<Entry>> {
+        let applied = self.state_machine.keys().next_back().cloned().unwrap_or(0);
+        let mut entries = Vec::with_capacity(log.len());
+        for e in log {
+            match e.payload {
+                // after a restart the state machine may not hold the previous snapshot, its
+                // pointer is kept then
+                EntryPayload::SnapshotPointer(_) if self.state_machine.range(..=e.index).next().is_some() => {
+                    entries.extend(self.state_machine.range(..=e.index).map(|(_, e)| Arc::new(e.clone())));
+                }
+                _ if e.index <= applied && !self.state_machine.contains_key(&e.index) => (),
+                _ => entries.push(e),
+            }
+        }
+        entries
+    }
+
+    /// Remove superseded snapshots beyond the retention and partially written ones.
+    fn collect_snapshots(&mut self, ctx: &mut Context<Self>) {
+        let current = match self.snapshot_data {
+            Some(ref snapshot) => PathBuf::from(&snapshot.pointer.path),
+            None => return,
+        };
+
+        fut::wrap_future(self.snapshot_actor.send(CollectSnapshots(current, self.snapshot_retention)))
+            .map_err(|err, _, _| error!("Error communicating with snapshot actor. {}", err))
+            .map(|report, act: &mut Self, _| {
+                act.snapshot_gc.runs += 1;
+                act.snapshot_gc.removed_files += report.removed_files;
+                act.snapshot_gc.reclaimed_bytes += report.reclaimed_bytes;
+                act.snapshot_gc.last_run_at = Some(now_millis());
+            })
+            .spawn(ctx);
+    }
+
+    /// Write the snapshot raft asked for once the compaction schedule allows it, checking
+    /// again every `COMPACTION_CHECK_INTERVAL` meanwhile. Raft keeps replicating and doesn't ask
+    /// again until it's answered, a snapshot written for a follower in between answers it.
+    fn scheduled_snapshot(&mut self, through: u64) -> ResponseActFuture<Self, CurrentSnapshotData, MemoryStorageError> {
+        if let Some(ref snapshot) = self.snapshot_data {
+            if snapshot.index >= through {
+                return Box::new(fut::ok(snapshot.clone()));
+            }
+        }
+
+        let due = self
+            .schedule
+            .as_ref()
+            .map_or(true, |schedule| schedule.should_compact(self.append_rate, minute_of_day_utc()));
+        if due && !self.compacting {
+            debug!("Scheduled compaction through index {}, {} entries/s appended.", through, self.append_rate);
+            self.compacting = true;
+            return Box::new(self.create_snapshot(through).map_err(|err, act, _| {
+                act.compacting = false;
+                err
+            }));
+        }
+
+        Box::new(
+            fut::wrap_future::<_, Self>(clock::delay(COMPACTION_CHECK_INTERVAL))
+                .map_err(|err, _, _| {
+                    error!("Error waiting for the compaction schedule. {}", err);
+                    MemoryStorageError::default()
+                })
+                .and_then(move |_, act: &mut Self, _| act.scheduled_snapshot(through)),
+        )
+    }
+
     /// Rebuild the state machine from the specified snapshot.
     fn rebuild_state_machine_from_snapshot(
         &mut self,
@@ -402,10 +1418,75 @@ impl MemoryStorage {
                 act.state_machine.clear();
                 act.state_machine
                     .extend(entries.into_iter().map(|e| (e.index, e)));
+                // entries held back by maintenance mode may now be part of the snapshot
+                let last = act.state_machine.iter().last().map(|e| *e.0).unwrap_or(0);
+                if let Some(ref mut pending) = act.paused {
+                    pending.retain(|e| e.index > last);
+                }
+                act.replay_state_machine(last);
                 fut::ok(())
             })
             .map(|_, _, _| debug!("Finished rebuilding statemachine from snapshot successfully."))
     }
+
+    /// Rebuild the freeze, lock, address book, metadata, sink checkpoint, cordon and application
+    /// state from the entries in the state machine, the last of them at `last`, skipping retried
+    /// proposals like when they were first applied.
+    fn replay_state_machine(&mut self, last: u64) {
+        self.locks.clear();
+        self.sessions.clear();
+        self.sink_checkpoints.clear();
+        self.cordoned.clear();
+        self.applied_keys.clear();
+        if let Some(state_machine) = self.hooks.read().unwrap().state_machine() {
+            state_machine.lock().unwrap().reset();
+        }
+        if let Some(canary) = self.hooks.read().unwrap().canary() {
+            canary.lock().unwrap().reset();
+        }
+        let projections = self.hooks.read().unwrap().projections();
+        let mut projections = projections.lock().unwrap();
+        projections.rebuild_through(last);
+        let mut frozen = false;
+        let entries = self.state_machine.values().cloned().collect::<Vec<_>>();
+        if let Some(e) = entries.iter().rev().find(|e| changes_epoch(e)) {
+            self.epoch.fetch_max(e.index, Ordering::SeqCst);
+        }
+        for e in entries.iter() {
+            if self.first_applied(e).is_some() {
+                continue;
+            }
+            if let EntryPayload::Normal(entry) = &e.payload {
+                projections.apply(e.index, entry.data.hlc, &entry.data.data.commands());
+                self.sessions.apply(e.index, entry.data.hlc.physical, entry.data.key.as_ref());
+                for command in entry.data.data.commands() {
+                    self.locks.apply(e.index, e.term, command);
+                    match *command {
+                        MemoryStorageData::Freeze(freeze) => frozen = freeze,
+                        MemoryStorageData::SetAddress(id, ref info) => {
+                            let _ = self.address_recipient.do_send(AddressChanged(id, info.clone()));
+                        }
+                        MemoryStorageData::App(ref command) => self.apply_app(e.index, command),
+                        MemoryStorageData::Blob(ref blob) => self.apply_blob(e.index, blob),
+                        MemoryStorageData::SetClusterMetadata(ref metadata) => self.metadata_changed(metadata),
+                        MemoryStorageData::SinkCheckpoint { ref sink, index } => {
+                            let checkpoint = self.sink_checkpoints.entry(sink.clone()).or_insert(0);
+                            *checkpoint = (*checkpoint).max(index);
+                        }
+                        MemoryStorageData::Cordon { node, cordoned } => self.cordon(node, cordoned),
+                        MemoryStorageData::SetDictionary(ref dict) => install_dictionary(dict),
+                        MemoryStorageData::Purge { before } => {
+                            self.purge(e.index, before);
+                        }
+                        _ => (),
+                    }
+                }
+            }
+        }
+        projections.checkpoint();
+        self.frozen.store(frozen, Ordering::SeqCst);
+        self.cordon_changed();
+    }
 }
 
 //////////////////////////////////////////////////////////////////////////////////////////////////
@@ -414,11 +1495,44 @@ impl MemoryStorage {
 /// A simple synchronous actor for interfacing with the filesystem for snapshots.
 struct SnapshotActor(std::path::PathBuf);
 
+/// Errors returned by the snapshot actor.
+enum SnapshotError {
+    /// Persisting data failed, the node can no longer guarantee durability.
+    Fault(StorageFault),
+    /// The operation failed for a reason unrelated to the disk.
+    Failed,
+}
+
 impl Actor for SnapshotActor {
     type Context = SyncContext<Self>;
 }
 
-struct SyncInstallSnapshot(InstallSnapshot<MemoryStorageError>);
+/// Write a snapshot streamed from the leader, checking its state machine version against the
+/// policy once complete.
+struct SyncInstallSnapshot(InstallSnapshot<MemoryStorageError>, SnapshotVersionPolicy, Option<Arc<dyn SnapshotMigrator>>);
+
+/// Install a dictionary applied from the log, unless its contents don't match its id.
+fn install_dictionary(dict: &Dictionary) {
+    if dict.is_valid() {
+        dictionary::install(dict)
+    } else {
+        warn!("Ignoring {:?}, its contents don't match its id", dict)
+    }
+}
+
+/// Read the version and entries of a snapshot, snapshots written before they were versioned
+/// are version 0.
+fn read_snapshot(data: &[u8]) -> Result<(u32, Vec<Entry>), rmps::decode::Error> {
+    rmps::from_slice::<(u32, Vec<Entry>)>(data)
+        .or_else(|_| rmps::from_slice::<Vec<Entry>>(data).map(|entries| (0, entries)))
+}
+
+/// Read only the version of a snapshot, its entries may not be readable by this version.
+fn snapshot_version(data: &[u8]) -> u32 {
+    rmps::from_slice::<(u32, serde::de::IgnoredAny)>(data)
+        .map(|(version, _)| version)
+        .unwrap_or(0)
+}
 
 //////////////////////////////////////////////////////////////////////////////
 // CreateSnapshotWithData ////////////////////////////////////////////////////
@@ -426,20 +1540,195 @@ struct SyncInstallSnapshot(InstallSnapshot<MemoryStorageError>);
 struct CreateSnapshotWithData(PathBuf, Vec<u8>);
 
 impl Message for CreateSnapshotWithData {
-    type Result = Result<(), MemoryStorageError>;
+    type Result = Result<(), SnapshotError>;
 }
 
 impl Handler<CreateSnapshotWithData> for SnapshotActor {
-    type Result = Result<(), MemoryStorageError>;
+    type Result = Result<(), SnapshotError>;
 
     fn handle(&mut self, msg: CreateSnapshotWithData, _: &mut Self::Context) -> Self::Result {
-        fs::write(msg.0.clone(), msg.1).map_err(|err| {
-            error!("Error writing snapshot file. {}", err);
-            MemoryStorageError
+        let bytes = msg.1.len();
+        fs::write(msg.0.clone(), msg.1)
+            .map(|_| io_stats::snapshot_write(bytes))
+            .map_err(|err| {
+                error!("Error writing snapshot file. {}", err);
+                SnapshotError::Fault(StorageFault::from_io(&err))
+            })
+    }
+}
+
+//////////////////////////////////////////////////////////////////////////////
+// CreateSnapshotFromEntries /////////////////////////////////////////////////
+
+/// Serialize a frozen view of log entries into a snapshot file.
+struct CreateSnapshotFromEntries(PathBuf, Vec<Arc<Entry>>);
+
+impl Message for CreateSnapshotFromEntries {
+    type Result = Result<(), SnapshotError>;
+}
+
+impl Handler<CreateSnapshotFromEntries> for SnapshotActor {
+    type Result = Result<(), SnapshotError>;
+
+    fn handle(&mut self, msg: CreateSnapshotFromEntries, _: &mut Self::Context) -> Self::Result {
+        // blobs go into the snapshot, a node installing it needs nothing else
+        let snapshot_dir = self.0.to_string_lossy().to_string();
+        let entries = msg
+            .1
+            .iter()
+            .map(|e| blob::inline(&snapshot_dir, e))
+            .collect::<Result<Vec<Entry>, _>>()
+            .map_err(|err| {
+                error!("Error reading a blob for creating a snapshot. {}", err);
+                SnapshotError::Fault(StorageFault::from_io(&err))
+            })?;
+        let snapdata = rmps::to_vec(&(STATE_MACHINE_VERSION, entries)).map_err(|err| {
+            error!("Error serializing log for creating a snapshot. {}", err);
+            SnapshotError::Failed
+        })?;
+
+        // a crash mid-write leaves a partial file behind for the GC instead of a corrupt snapshot
+        let partial = msg.0.with_extension(PARTIAL_SNAPSHOT_EXT);
+        let bytes = snapdata.len();
+        fs::write(&partial, snapdata)
+            .and_then(|_| fs::rename(&partial, &msg.0))
+            .map(|_| io_stats::snapshot_write(bytes))
+            .map_err(|err| {
+                error!("Error writing snapshot file. {}", err);
+                SnapshotError::Fault(StorageFault::from_io(&err))
+            })
+    }
+}
+
+//////////////////////////////////////////////////////////////////////////////
+// ArchiveEntries ////////////////////////////////////////////////////////////
+
+/// Hand entries to the archiver, on the snapshot actor as archiving may block.
+struct ArchiveEntries(Arc<dyn LogArchiver>, Vec<Arc<Entry>>);
+
+impl Message for ArchiveEntries {
+    type Result = Result<(), SnapshotError>;
+}
+
+impl Handler<ArchiveEntries> for SnapshotActor {
+    type Result = Result<(), SnapshotError>;
+
+    fn handle(&mut self, msg: ArchiveEntries, _: &mut Self::Context) -> Self::Result {
+        let entries = msg.1.iter().map(|e| (**e).clone()).collect::<Vec<_>>();
+        msg.0.archive(&entries).map_err(|err| {
+            error!("Archiver failed to copy {} entries. {}", entries.len(), err);
+            SnapshotError::Failed
         })
     }
 }
 
+//////////////////////////////////////////////////////////////////////////////
+// AppendEntryTimings ////////////////////////////////////////////////////////
+
+/// Append entry timings as JSON lines to the given file.
+struct AppendEntryTimings(PathBuf, Vec<EntryTiming>);
+
+impl Message for AppendEntryTimings {
+    type Result = ();
+}
+
+impl Handler<AppendEntryTimings> for SnapshotActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: AppendEntryTimings, _: &mut Self::Context) -> Self::Result {
+        let mut file = match OpenOptions::new().create(true).append(true).open(&msg.0) {
+            Ok(file) => file,
+            Err(err) => {
+                error!("Error opening entry timings file. {}", err);
+                return;
+            }
+        };
+
+        for timing in msg.1 {
+            if let Ok(line) = serde_json::to_string(&timing) {
+                if let Err(err) = writeln!(file, "{}", line) {
+                    error!("Error writing entry timings. {}", err);
+                    return;
+                }
+            }
+        }
+    }
+}
+
+//////////////////////////////////////////////////////////////////////////////
+// CollectSnapshots //////////////////////////////////////////////////////////
+
+/// Remove the snapshot files superseded by the current snapshot, keeping the given number of
+/// the most recent ones, and files left partially written.
+struct CollectSnapshots(PathBuf, usize);
+
+/// Files removed by a snapshot GC pass.
+#[derive(Default)]
+struct SnapshotGcReport {
+    removed_files: u64,
+    reclaimed_bytes: u64,
+}
+
+impl Message for CollectSnapshots {
+    type Result = SnapshotGcReport;
+}
+
+impl Handler<CollectSnapshots> for SnapshotActor {
+    type Result = MessageResult<CollectSnapshots>;
+
+    fn handle(&mut self, msg: CollectSnapshots, _: &mut Self::Context) -> Self::Result {
+        let mut report = SnapshotGcReport::default();
+        let current = msg.0.file_name().and_then(|name| name.to_str()).and_then(|name| name.parse::<u64>().ok());
+        let current = match current {
+            Some(current) => current,
+            None => return MessageResult(report),
+        };
+
+        let entries = match fs::read_dir(&self.0) {
+            Ok(entries) => entries,
+            Err(err) => {
+                warn!("Error listing snapshot dir {:?} for GC. {}", self.0, err);
+                return MessageResult(report);
+            }
+        };
+
+        // snapshots are named after the index they cover, writes happen on this actor so
+        // partial files seen here are leftovers of failed writes
+        let mut superseded = Vec::new();
+        let mut garbage = Vec::new();
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            let path = entry.path();
+            if !path.is_file() {
+                continue;
+            }
+            if path.extension().map(|ext| ext == PARTIAL_SNAPSHOT_EXT).unwrap_or(false) {
+                garbage.push(path);
+            } else if let Some(index) = path.file_name().and_then(|name| name.to_str()).and_then(|name| name.parse::<u64>().ok()) {
+                if index < current {
+                    superseded.push((index, path));
+                }
+            }
+        }
+
+        superseded.sort_by(|a, b| b.0.cmp(&a.0));
+        garbage.extend(superseded.into_iter().skip(msg.1).map(|(_, path)| path));
+
+        for path in garbage {
+            let len = fs::metadata(&path).map(|metadata| metadata.len()).unwrap_or(0);
+            match fs::remove_file(&path) {
+                Ok(_) => {
+                    debug!("Removed snapshot file {:?}.", path);
+                    report.removed_files += 1;
+                    report.reclaimed_bytes += len;
+                }
+                Err(err) => warn!("Error removing snapshot file {:?}. {}", path, err),
+            }
+        }
+
+        MessageResult(report)
+    }
+}
+
 //////////////////////////////////////////////////////////////////////////////
 // DeserializeSnapshot ///////////////////////////////////////////////////////
 
@@ -456,14 +1745,17 @@ impl Handler<DeserializeSnapshot> for SnapshotActor {
         fs::read(msg.0)
             .map_err(|err| {
                 error!("Error reading contents of snapshot file. {}", err);
-                MemoryStorageError
+                MemoryStorageError::default()
             })
             // Deserialize the data of the snapshot file.
             .and_then(|snapdata| {
-                rmps::from_slice::<Vec<Entry>>(snapdata.as_slice()).map_err(|err| {
-                    error!("Error deserializing snapshot contents. {}", err);
-                    MemoryStorageError
-                })
+                io_stats::snapshot_read(snapdata.len());
+                read_snapshot(snapdata.as_slice())
+                    .map(|(_, entries)| entries)
+                    .map_err(|err| {
+                        error!("Error deserializing snapshot contents. {}", err);
+                        MemoryStorageError::default()
+                    })
             })
     }
 }
@@ -472,20 +1764,21 @@ impl Handler<DeserializeSnapshot> for SnapshotActor {
 // SyncInstallSnapshot ///////////////////////////////////////////////////////
 
 impl Message for SyncInstallSnapshot {
-    type Result = Result<EntrySnapshotPointer, MemoryStorageError>;
+    type Result = Result<EntrySnapshotPointer, SnapshotError>;
 }
 
 impl Handler<SyncInstallSnapshot> for SnapshotActor {
-    type Result = Result<EntrySnapshotPointer, MemoryStorageError>;
+    type Result = Result<EntrySnapshotPointer, SnapshotError>;
 
     fn handle(&mut self, msg: SyncInstallSnapshot, _: &mut Self::Context) -> Self::Result {
         let filename = format!("{}", &msg.0.index);
         let filepath = std::path::PathBuf::from(self.0.clone()).join(filename);
+        let partial = filepath.with_extension(PARTIAL_SNAPSHOT_EXT);
 
-        // Create the new snapshot file.
-        let mut snapfile = File::create(&filepath).map_err(|err| {
+        // Create the new snapshot file, under its final name once all chunks are written.
+        let mut snapfile = File::create(&partial).map_err(|err| {
             error!("Error creating new snapshot file. {}", err);
-            MemoryStorageError
+            SnapshotError::Fault(StorageFault::from_io(&err))
         })?;
 
         let chunk_stream = msg
@@ -493,7 +1786,7 @@ impl Handler<SyncInstallSnapshot> for SnapshotActor {
             .stream
             .map_err(|_| {
                 error!("Snapshot chunk stream hit an error in the memory_storage system.");
-                MemoryStorageError
+                SnapshotError::Failed
             })
             .wait();
         let mut did_process_final_chunk = false;
@@ -506,12 +1799,13 @@ impl Handler<SyncInstallSnapshot> for SnapshotActor {
                         "Error seeking to file location for writing snapshot chunk. {}",
                         err
                     );
-                    MemoryStorageError
+                    SnapshotError::Fault(StorageFault::from_io(&err))
                 })?;
             snapfile.write_all(&chunk.data).map_err(|err| {
                 error!("Error writing snapshot chunk to snapshot file. {}", err);
-                MemoryStorageError
+                SnapshotError::Fault(StorageFault::from_io(&err))
             })?;
+            io_stats::snapshot_write(chunk.data.len());
             if chunk.done {
                 did_process_final_chunk = true;
             }
@@ -520,11 +1814,84 @@ impl Handler<SyncInstallSnapshot> for SnapshotActor {
 
         if !did_process_final_chunk {
             error!("Prematurely exiting snapshot chunk stream. Never hit final chunk.");
-            Err(MemoryStorageError)
-        } else {
-            Ok(EntrySnapshotPointer {
-                path: filepath.to_string_lossy().to_string(),
-            })
+            return Err(SnapshotError::Failed);
+        }
+
+        fs::rename(&partial, &filepath).map_err(|err| {
+            error!("Error renaming installed snapshot file. {}", err);
+            SnapshotError::Fault(StorageFault::from_io(&err))
+        })?;
+        self.check_version(&filepath, msg.1, msg.2)?;
+
+        Ok(EntrySnapshotPointer {
+            path: filepath.to_string_lossy().to_string(),
+        })
+    }
+}
+
+impl SnapshotActor {
+    /// Apply the version policy to a snapshot written by a newer state machine version,
+    /// leaving a snapshot this version can install at `path` or failing.
+    fn check_version(
+        &self,
+        path: &PathBuf,
+        policy: SnapshotVersionPolicy,
+        migrator: Option<Arc<dyn SnapshotMigrator>>,
+    ) -> Result<(), SnapshotError> {
+        let snapdata = fs::read(path).map_err(|err| {
+            error!("Error reading installed snapshot. {}", err);
+            SnapshotError::Failed
+        })?;
+        io_stats::snapshot_read(snapdata.len());
+        let version = snapshot_version(&snapdata);
+
+        if version <= STATE_MACHINE_VERSION {
+            return Ok(());
+        }
+
+        match (policy, migrator) {
+            (SnapshotVersionPolicy::Migrate, Some(migrator)) => {
+                let entries = migrator.migrate(version, &snapdata).map_err(|err| {
+                    error!("CRITICAL: failed to migrate snapshot of state machine version {}. {}", version, err);
+                    SnapshotError::Failed
+                })?;
+                let snapdata = rmps::to_vec(&(STATE_MACHINE_VERSION, entries)).map_err(|err| {
+                    error!("Error serializing migrated snapshot. {}", err);
+                    SnapshotError::Failed
+                })?;
+
+                info!("Migrated snapshot from state machine version {} to {}.", version, STATE_MACHINE_VERSION);
+                fs::write(path, snapdata).map_err(|err| {
+                    error!("Error writing migrated snapshot. {}", err);
+                    SnapshotError::Fault(StorageFault::from_io(&err))
+                })
+            }
+            (SnapshotVersionPolicy::Stage, _) => {
+                let staged_dir = self.0.join("staged");
+                let staged = path
+                    .file_name()
+                    .map(|name| staged_dir.join(format!("{}-v{}", name.to_string_lossy(), version)))
+                    .unwrap_or_else(|| staged_dir.join(format!("v{}", version)));
+
+                if let Err(err) = fs::create_dir_all(&staged_dir).and_then(|_| fs::rename(path, &staged)) {
+                    error!("Error staging snapshot at {:?}. {}", staged, err);
+                }
+                error!(
+                    "CRITICAL: snapshot of state machine version {} is newer than {}, staged at {:?} until this node is upgraded.",
+                    version, STATE_MACHINE_VERSION, staged
+                );
+                Err(SnapshotError::Failed)
+            }
+            (policy, _) => {
+                let _ = fs::remove_file(path);
+                error!(
+                    "CRITICAL: rejecting snapshot of state machine version {}, this node runs version {}{}.",
+                    version,
+                    STATE_MACHINE_VERSION,
+                    if policy == SnapshotVersionPolicy::Migrate { " and has no snapshot migrator" } else { "" }
+                );
+                Err(SnapshotError::Failed)
+            }
         }
     }
 }
@@ -557,7 +1924,13 @@ impl Handler<GetCurrentState> for MemoryStorage {
     fn handle(&mut self, _: GetCurrentState, _: &mut Self::Context) -> Self::Result {
         Ok(CurrentStateData {
             hs: self.hs.clone(),
-            log: self.log.clone(),
+            log: self
+                .log
+                .range(..)
+                .map_err(|_| ())?
+                .into_iter()
+                .map(|e| (e.index, (*e).clone()))
+                .collect(),
             snapshot_data: self.snapshot_data.clone(),
             snapshot_dir: self.snapshot_dir.clone(),
             state_machine: self.state_machine.clone(),
@@ -565,21 +1938,1189 @@ impl Handler<GetCurrentState> for MemoryStorage {
     }
 }
 
-pub struct GetNode(pub String);
+/// Where the storage keeps its files and how much it holds.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StorageStats {
+    pub snapshot_dir: String,
+    pub snapshot_bytes: u64,
+    pub timings_file: Option<String>,
+    pub timings_bytes: u64,
+    pub log_entries: usize,
+    pub log_cache: LogCacheStats,
+    pub applied_entries: usize,
+    pub snapshot_gc: SnapshotGcStats,
+    pub vacuum: VacuumProgress,
+    /// Last index published to each log sink
+    pub sink_checkpoints: BTreeMap<String, u64>,
+    pub cordoned: BTreeSet<NodeId>,
+    /// Whether log writes are acknowledged before they're synced, as an async-ack voter
+    pub buffered_writes: bool,
+    /// Comparison with the canary state machine, if one is set
+    pub canary: Option<CanaryReport>,
+    /// IO of the storage layer of the process and its write amplification
+    pub io: IoStats,
+    /// Debris purged from the state machine since this node started
+    pub tombstones: TombstoneStats,
+}
 
-impl Message for GetNode {
-    type Result = Result<NodeId, ()>;
+/// What the snapshot GC removed since this node started.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct SnapshotGcStats {
+    pub runs: u64,
+    pub removed_files: u64,
+    pub reclaimed_bytes: u64,
+    /// Unix time in milliseconds of the last pass
+    pub last_run_at: Option<u64>,
 }
 
-impl Handler<GetNode> for MemoryStorage {
-    type Result = Result<NodeId, ()>;
+/// What the purges applied by this node dropped since it started.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct TombstoneStats {
+    pub purges: u64,
+    pub entries: u64,
+    pub keys: u64,
+    pub sessions: u64,
+    /// Cutoff of the last purge, unix time in milliseconds
+    pub last_before: Option<u64>,
+}
 
-    fn handle(&mut self, msg: GetNode, ctx: &mut Context<Self>) -> Self::Result {
-        let ring = self.ring.read().unwrap();
-        if let Some(node_id) = ring.get_node(msg.0) {
-            Ok(*node_id)
-        } else {
-            Err(())
+impl TombstoneStats {
+    fn add(&mut self, before: u64, purged: &PurgedDebris) {
+        self.purges += 1;
+        self.entries += purged.entries;
+        self.keys += purged.keys;
+        self.sessions += purged.sessions;
+        self.last_before = Some(before);
+    }
+}
+
+struct PurgedDebris {
+    entries: u64,
+    keys: u64,
+    sessions: u64,
+}
+
+/// The fault of the storage and the index whose append hit it, `None` while healthy.
+pub struct GetStorageFault;
+
+impl Message for GetStorageFault {
+    type Result = Option<MemoryStorageError>;
+}
+
+impl Handler<GetStorageFault> for MemoryStorage {
+    type Result = Option<MemoryStorageError>;
+
+    fn handle(&mut self, _: GetStorageFault, _: &mut Self::Context) -> Self::Result {
+        self.fault
+            .clone()
+            .map(|fault| MemoryStorageError::faulted(fault, self.failed_append))
+    }
+}
+
+/// Get the paths and sizes of the storage.
+pub struct GetStorageStats;
+
+impl Message for GetStorageStats {
+    type Result = Result<StorageStats, ()>;
+}
+
+impl Handler<GetStorageStats> for MemoryStorage {
+    type Result = Result<StorageStats, ()>;
+
+    fn handle(&mut self, _: GetStorageStats, _: &mut Self::Context) -> Self::Result {
+        let snapshot_bytes = fs::read_dir(&self.snapshot_dir)
+            .map(|entries| {
+                entries
+                    .filter_map(|entry| entry.ok())
+                    .filter(|entry| entry.file_name() != LOG_SPILL_FILE)
+                    .filter_map(|entry| entry.metadata().ok())
+                    .map(|metadata| metadata.len())
+                    .sum()
+            })
+            .unwrap_or(0);
+
+        let timings_bytes = self
+            .timings_file
+            .as_ref()
+            .and_then(|path| fs::metadata(path).ok())
+            .map(|metadata| metadata.len())
+            .unwrap_or(0);
+
+        Ok(StorageStats {
+            snapshot_dir: self.snapshot_dir.clone(),
+            snapshot_bytes: snapshot_bytes,
+            timings_file: self.timings_file.as_ref().map(|path| path.to_string_lossy().to_string()),
+            timings_bytes: timings_bytes,
+            log_entries: self.log.len(),
+            log_cache: self.log.stats(),
+            applied_entries: self.state_machine.len(),
+            snapshot_gc: self.snapshot_gc.clone(),
+            vacuum: self.vacuum.read().unwrap().clone(),
+            sink_checkpoints: self.sink_checkpoints.clone(),
+            cordoned: self.cordoned.clone(),
+            buffered_writes: self.buffered_writes.unwrap_or(false),
+            canary: self.hooks.read().unwrap().canary().map(|canary| canary.lock().unwrap().report()),
+            io: io_stats::stats(),
+            tombstones: self.tombstones.clone(),
+        })
+    }
+}
+
+/// Rewrite the fragmented log store on a background thread while raft keeps writing to it,
+/// pausing the given number of milliseconds between steps to bound the extra disk load. The
+/// progress is reported in the storage stats.
+pub struct StartVacuum(pub u64);
+
+impl Message for StartVacuum {
+    type Result = Result<(), String>;
+}
+
+impl Handler<StartVacuum> for MemoryStorage {
+    type Result = Result<(), String>;
+
+    fn handle(&mut self, msg: StartVacuum, _: &mut Self::Context) -> Self::Result {
+        if self.vacuum.read().unwrap().running {
+            return Err("a vacuum is already running".to_owned());
+        }
+
+        let mut vacuum = self
+            .durable
+            .as_ref()
+            .and_then(|store| store.vacuum())
+            .ok_or_else(|| "the log store has nothing to vacuum".to_owned())?;
+
+        let pause = Duration::from_millis(msg.0);
+        let progress = self.vacuum.clone();
+        let events = self.events.clone();
+
+        *progress.write().unwrap() = VacuumProgress {
+            running: true,
+            steps: vacuum.steps(),
+            bytes_before: vacuum.disk_bytes(),
+            started_at: Some(now_millis()),
+            ..Default::default()
+        };
+        info!("Vacuuming the log store in {} steps", vacuum.steps());
+
+        std::thread::spawn(move || {
+            let mut error = None;
+
+            for step in 0..vacuum.steps() {
+                if let Err(err) = vacuum.step(step) {
+                    error = Some(err.to_string());
+                    break;
+                }
+                progress.write().unwrap().steps_done = step + 1;
+                std::thread::sleep(pause);
+            }
+
+            let mut progress = progress.write().unwrap();
+            progress.running = false;
+            progress.bytes_after = Some(vacuum.disk_bytes());
+            progress.finished_at = Some(now_millis());
+
+            match error {
+                Some(err) => {
+                    error!("Vacuum of the log store failed: {}", err);
+                    events.alert(format!("vacuum of the log store failed: {}", err));
+                    progress.error = Some(err);
+                }
+                None => info!(
+                    "Vacuumed the log store, {} bytes before, {} after",
+                    progress.bytes_before,
+                    progress.bytes_after.unwrap_or(0)
+                ),
+            }
+        });
+
+        Ok(())
+    }
+}
+
+pub struct GetNode(pub String);
+
+impl Message for GetNode {
+    type Result = Result<NodeId, ()>;
+}
+
+impl Handler<GetNode> for MemoryStorage {
+    type Result = Result<NodeId, ()>;
+
+    fn handle(&mut self, msg: GetNode, ctx: &mut Context<Self>) -> Self::Result {
+        let ring = self.ring.read().unwrap();
+        if let Some(node_id) = ring.get_node(msg.0) {
+            Ok(*node_id)
+        } else {
+            Err(())
+        }
+    }
+}
+
+//////////////////////////////////////////////////////////////////////////////
+// ExportStateDump ///////////////////////////////////////////////////////////
+
+/// A single applied entry as written to a state dump.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StateDumpEntry {
+    pub index: u64,
+    pub term: u64,
+    pub hlc: Hlc,
+    pub data: MemoryStorageData,
+}
+
+/// Portable representation of the state machine contents.
+///
+/// `index` and `term` are the watermark of the last applied entry covered by the dump.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StateDump {
+    pub index: u64,
+    pub term: u64,
+    pub entries: Vec<StateDumpEntry>,
+}
+
+/// Summary of a finished state dump export.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StateDumpInfo {
+    pub path: String,
+    pub index: u64,
+    pub term: u64,
+    pub entries: usize,
+}
+
+/// Export the applied state machine contents as JSON to the given path.
+pub struct ExportStateDump(pub PathBuf);
+
+impl Message for ExportStateDump {
+    type Result = Result<StateDumpInfo, MemoryStorageError>;
+}
+
+impl Handler<ExportStateDump> for MemoryStorage {
+    type Result = ResponseActFuture<Self, StateDumpInfo, MemoryStorageError>;
+
+    fn handle(&mut self, msg: ExportStateDump, _: &mut Self::Context) -> Self::Result {
+        let dump = self.state_dump();
+        let (index, term) = (dump.index, dump.term);
+        let count = dump.entries.len();
+        let data = match serde_json::to_vec_pretty(&dump) {
+            Ok(data) => data,
+            Err(err) => {
+                error!("Error serializing state dump. {}", err);
+                return Box::new(fut::err(MemoryStorageError::default()));
+            }
+        };
+
+        let path = msg.0;
+        Box::new(
+            fut::wrap_future(self.snapshot_actor.send(CreateSnapshotWithData(path.clone(), data)))
+                .map_err(|err, _, _| panic!("Error communicating with snapshot actor. {}", err))
+                .and_then(|res, _, _| fut::result(res.map_err(|_| MemoryStorageError::default())))
+                .map(move |_, _, _| {
+                    let path = path.to_string_lossy().to_string();
+                    debug!("Exported state dump to {} at index {}.", &path, index);
+                    StateDumpInfo {
+                        path,
+                        index,
+                        term,
+                        entries: count,
+                    }
+                }),
+        )
+    }
+}
+
+impl MemoryStorage {
+    fn state_dump(&self) -> StateDump {
+        let (index, term) = self
+            .state_machine
+            .iter()
+            .last()
+            .map(|(_, e)| (e.index, e.term))
+            .unwrap_or((0, 0));
+
+        let entries = self
+            .state_machine
+            .values()
+            .filter_map(|e| match &e.payload {
+                EntryPayload::Normal(entry) => Some(StateDumpEntry {
+                    index: e.index,
+                    term: e.term,
+                    hlc: entry.data.hlc,
+                    data: entry.data.data.clone(),
+                }),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        StateDump { index, term, entries }
+    }
+}
+
+/// The applied state machine contents, as written by `ExportStateDump`.
+pub struct GetStateDump;
+
+impl Message for GetStateDump {
+    type Result = Result<StateDump, ()>;
+}
+
+impl Handler<GetStateDump> for MemoryStorage {
+    type Result = Result<StateDump, ()>;
+
+    fn handle(&mut self, _: GetStateDump, _: &mut Self::Context) -> Self::Result {
+        Ok(self.state_dump())
+    }
+}
+
+//////////////////////////////////////////////////////////////////////////////
+// Backups ///////////////////////////////////////////////////////////////////
+
+/// The dump this node took for a backup, `None` until it applied the marker.
+pub struct GetBackupPart(pub String);
+
+impl Message for GetBackupPart {
+    type Result = Result<Option<BackupPart>, ()>;
+}
+
+impl Handler<GetBackupPart> for MemoryStorage {
+    type Result = Result<Option<BackupPart>, ()>;
+
+    fn handle(&mut self, msg: GetBackupPart, _: &mut Self::Context) -> Self::Result {
+        Ok(self.backups.lock().unwrap().get(&msg.0).cloned())
+    }
+}
+
+/// Directory of a backup set copied under the backup dir of this node, to restore it.
+pub struct LocateBackup(pub String);
+
+impl Message for LocateBackup {
+    type Result = Result<PathBuf, BackupError>;
+}
+
+impl Handler<LocateBackup> for MemoryStorage {
+    type Result = Result<PathBuf, BackupError>;
+
+    fn handle(&mut self, msg: LocateBackup, _: &mut Self::Context) -> Self::Result {
+        backup::restore_dir(&self.snapshot_dir, &msg.0)
+            .ok_or_else(|| BackupError::Corrupt(format!("{} isn't a backup set under the backup dir", msg.0)))
+    }
+}
+
+/// Write the dump of a member for a backup, on the snapshot actor.
+struct CaptureBackup(PathBuf, StateDump);
+
+impl Message for CaptureBackup {
+    type Result = io::Result<BackupPart>;
+}
+
+impl Handler<CaptureBackup> for SnapshotActor {
+    type Result = io::Result<BackupPart>;
+
+    fn handle(&mut self, msg: CaptureBackup, _: &mut Self::Context) -> Self::Result {
+        backup::capture(&msg.0, &msg.1)
+    }
+}
+
+/// Remove the directories of backups beyond the retention.
+struct RemoveBackups(Vec<PathBuf>);
+
+impl Message for RemoveBackups {
+    type Result = ();
+}
+
+impl Handler<RemoveBackups> for SnapshotActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: RemoveBackups, _: &mut Self::Context) {
+        for dir in msg.0 {
+            if let Err(err) = fs::remove_dir_all(&dir) {
+                warn!("Error removing backup {:?}. {}", dir, err);
+            }
+        }
+    }
+}
+
+/// Write the manifest of a backup every member confirmed next to the dump of this node.
+pub struct SealBackup(pub BackupManifest);
+
+impl Message for SealBackup {
+    type Result = Result<BackupManifest, BackupError>;
+}
+
+impl Handler<SealBackup> for MemoryStorage {
+    type Result = Result<BackupManifest, BackupError>;
+
+    fn handle(&mut self, msg: SealBackup, _: &mut Self::Context) -> Self::Result {
+        let mut manifest = msg.0;
+        let dir = backup::backup_dir(&self.snapshot_dir, &manifest.id);
+        manifest.path = dir.to_string_lossy().to_string();
+
+        backup::seal(&dir, &manifest).map_err(|err| BackupError::Io(err.to_string()))?;
+        info!("Backup {} at index {} is complete in {:?}", manifest.id, manifest.index, dir);
+        Ok(manifest)
+    }
+}
+
+//////////////////////////////////////////////////////////////////////////////
+// Blobs /////////////////////////////////////////////////////////////////////
+
+/// Start a blob on this node, the caller writes it and proposes a reference to it once done.
+pub struct OpenBlob;
+
+impl Message for OpenBlob {
+    type Result = io::Result<Spill>;
+}
+
+impl Handler<OpenBlob> for MemoryStorage {
+    type Result = io::Result<Spill>;
+
+    fn handle(&mut self, _: OpenBlob, _: &mut Self::Context) -> Self::Result {
+        Spill::create(&self.snapshot_dir)
+    }
+}
+
+/// A chunk of a blob of this node, asked by a peer fetching it.
+#[derive(Serialize, Deserialize)]
+pub struct GetBlobChunk {
+    pub id: String,
+    pub offset: u64,
+    pub len: u64,
+}
+
+impl Message for GetBlobChunk {
+    type Result = Result<BlobChunk, ()>;
+}
+
+impl Handler<GetBlobChunk> for MemoryStorage {
+    type Result = Result<BlobChunk, ()>;
+
+    fn handle(&mut self, msg: GetBlobChunk, _: &mut Self::Context) -> Self::Result {
+        blob::read_chunk(&self.snapshot_dir, &msg.id, msg.offset, msg.len).map_err(|err| {
+            debug!("Can't hand chunk {} of blob {} over: {}", msg.offset, msg.id, err);
+        })
+    }
+}
+
+//////////////////////////////////////////////////////////////////////////////
+// Projections ///////////////////////////////////////////////////////////////
+
+/// Progress of the projections of this node.
+pub struct GetProjections;
+
+impl Message for GetProjections {
+    type Result = Result<Vec<ProjectionStatus>, ()>;
+}
+
+impl Handler<GetProjections> for MemoryStorage {
+    type Result = Result<Vec<ProjectionStatus>, ()>;
+
+    fn handle(&mut self, _: GetProjections, _: &mut Self::Context) -> Self::Result {
+        Ok(self.hooks.read().unwrap().projections().lock().unwrap().status())
+    }
+}
+
+/// Reset a projection and rebuild it from the applied state machine, answers the index it was
+/// rebuilt through.
+pub struct RebuildProjection(pub String);
+
+impl Message for RebuildProjection {
+    type Result = Result<u64, ()>;
+}
+
+impl Handler<RebuildProjection> for MemoryStorage {
+    type Result = Result<u64, ()>;
+
+    fn handle(&mut self, msg: RebuildProjection, _: &mut Self::Context) -> Self::Result {
+        let projections = self.hooks.read().unwrap().projections();
+        let mut projections = projections.lock().unwrap();
+        if !projections.reset(&msg.0) {
+            return Err(());
+        }
+
+        let mut last = 0;
+        let entries = self.state_machine.values().cloned().collect::<Vec<_>>();
+        for e in entries.iter() {
+            if self.first_applied(e).is_some() {
+                continue;
+            }
+            if let EntryPayload::Normal(entry) = &e.payload {
+                projections.apply(e.index, entry.data.hlc, &entry.data.data.commands());
+                last = e.index;
+            }
+        }
+        projections.checkpoint();
+        Ok(last)
+    }
+}
+
+//////////////////////////////////////////////////////////////////////////////
+// GetEntryTimings ///////////////////////////////////////////////////////////
+
+/// Per-entry append/commit/apply timings and the commit latency histogram of this node.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct EntryTimingReport {
+    pub histogram: LatencyHistogram,
+    pub entries: Vec<EntryTiming>,
+}
+
+/// Get up to `limit` entry timings starting at index `from`.
+pub struct GetEntryTimings {
+    pub from: u64,
+    pub limit: usize,
+}
+
+impl Message for GetEntryTimings {
+    type Result = Result<EntryTimingReport, ()>;
+}
+
+impl Handler<GetEntryTimings> for MemoryStorage {
+    type Result = Result<EntryTimingReport, ()>;
+
+    fn handle(&mut self, msg: GetEntryTimings, _: &mut Self::Context) -> Self::Result {
+        Ok(EntryTimingReport {
+            histogram: self.timings.histogram(),
+            entries: self.timings.range(msg.from, msg.limit),
+        })
+    }
+}
+
+/// Log entry as shown by the log inspection API.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct InspectedEntry {
+    pub index: u64,
+    pub term: u64,
+    /// `normal`, `blank`, `config_change` or `snapshot_pointer`
+    pub kind: String,
+    pub hlc: Option<Hlc>,
+    /// Command of a normal entry, redacted by the configured redactor
+    pub data: Option<MemoryStorageData>,
+}
+
+/// Read up to `limit` log entries from index `from`, with their commands redacted.
+pub struct ReadLogEntries {
+    pub from: u64,
+    pub limit: usize,
+}
+
+impl Message for ReadLogEntries {
+    type Result = Result<Vec<InspectedEntry>, ()>;
+}
+
+impl Handler<ReadLogEntries> for MemoryStorage {
+    type Result = Result<Vec<InspectedEntry>, ()>;
+
+    fn handle(&mut self, msg: ReadLogEntries, _: &mut Self::Context) -> Self::Result {
+        let stop = msg.from.saturating_add(msg.limit as u64);
+        let entries = self.log.range(msg.from..stop).map_err(|err| {
+            error!("Error reading spilled log entries. {}", err);
+        })?;
+
+        Ok(entries.iter().map(|e| self.inspect(e)).collect())
+    }
+}
+
+//////////////////////////////////////////////////////////////////////////////
+// Log sinks /////////////////////////////////////////////////////////////////
+
+/// Up to `limit` applied entries to publish to a log sink, those after its checkpoint and
+/// after `after`, the last index this node already published. Entries only made of sink
+/// checkpoints aren't published.
+pub struct GetSinkBatch {
+    pub sink: String,
+    pub after: Option<u64>,
+    pub limit: usize,
+}
+
+impl Message for GetSinkBatch {
+    type Result = Result<Vec<ShippedEntry>, ()>;
+}
+
+impl Handler<GetSinkBatch> for MemoryStorage {
+    type Result = Result<Vec<ShippedEntry>, ()>;
+
+    fn handle(&mut self, msg: GetSinkBatch, _: &mut Self::Context) -> Self::Result {
+        let checkpoint = self.sink_checkpoints.get(&msg.sink).cloned().unwrap_or(0);
+        let from = msg.after.unwrap_or(0).max(checkpoint) + 1;
+
+        Ok(self
+            .state_machine
+            .range(from..)
+            .filter_map(|(_, e)| match e.payload {
+                EntryPayload::Normal(ref entry) => Some((e, entry)),
+                _ => None,
+            })
+            .filter(|(_, entry)| {
+                entry
+                    .data
+                    .data
+                    .commands()
+                    .iter()
+                    .any(|command| match command {
+                        MemoryStorageData::SinkCheckpoint { .. }
+                        | MemoryStorageData::SetDictionary(_)
+                        | MemoryStorageData::Backup { .. }
+                        | MemoryStorageData::Purge { .. } => false,
+                        _ => true,
+                    })
+            })
+            .take(msg.limit)
+            .map(|(e, entry)| ShippedEntry {
+                index: e.index,
+                term: e.term,
+                hlc: entry.data.hlc,
+                key: entry.data.key.clone(),
+                data: entry.data.data.clone(),
+            })
+            .collect())
+    }
+}
+
+//////////////////////////////////////////////////////////////////////////////
+// Federation ////////////////////////////////////////////////////////////////
+
+/// Get the federated cluster owning a namespace, among those which announced themselves since
+/// the given unix time in milliseconds.
+pub struct LookupNamespace(pub String, pub u64);
+
+impl Message for LookupNamespace {
+    type Result = Result<Option<ClusterRecord>, ()>;
+}
+
+impl Handler<LookupNamespace> for MemoryStorage {
+    type Result = Result<Option<ClusterRecord>, ()>;
+
+    fn handle(&mut self, msg: LookupNamespace, _: &mut Self::Context) -> Self::Result {
+        Ok(self.federation.lookup(msg.0.as_str(), msg.1).cloned())
+    }
+}
+
+/// Whether a purge of the debris stamped before the given unix time in milliseconds would drop
+/// anything.
+pub struct HasDebris(pub u64);
+
+impl Message for HasDebris {
+    type Result = Result<bool, ()>;
+}
+
+impl Handler<HasDebris> for MemoryStorage {
+    type Result = Result<bool, ()>;
+
+    fn handle(&mut self, msg: HasDebris, _: &mut Self::Context) -> Self::Result {
+        Ok(self.debris_due(msg.0))
+    }
+}
+
+/// Query the application state machine, the query and answer are MessagePack encoded.
+pub struct QueryAppState(pub Vec<u8>);
+
+impl Message for QueryAppState {
+    type Result = Result<Vec<u8>, ()>;
+}
+
+impl Handler<QueryAppState> for MemoryStorage {
+    type Result = Result<Vec<u8>, ()>;
+
+    fn handle(&mut self, msg: QueryAppState, _: &mut Self::Context) -> Self::Result {
+        let state_machine = self.hooks.read().unwrap().state_machine().ok_or(())?;
+        let res = state_machine.lock().unwrap().query(&msg.0);
+        res.map_err(|err| warn!("Failed to query the application state machine: {}", err))
+    }
+}
+
+/// A page of the answer to a query of the application state machine, see `QueryAppPage`.
+pub struct QueryAppStatePage {
+    pub query: Vec<u8>,
+    pub cursor: Option<Vec<u8>>,
+    pub limit: usize,
+}
+
+impl Message for QueryAppStatePage {
+    type Result = Result<AppPage, ()>;
+}
+
+impl Handler<QueryAppStatePage> for MemoryStorage {
+    type Result = Result<AppPage, ()>;
+
+    fn handle(&mut self, msg: QueryAppStatePage, _: &mut Self::Context) -> Self::Result {
+        let state_machine = self.hooks.read().unwrap().state_machine().ok_or(())?;
+        let res = state_machine.lock().unwrap().query_page(&msg.query, msg.cursor.as_ref().map(Vec::as_slice), msg.limit);
+        res.map_err(|err| warn!("Failed to query the application state machine: {}", err))
+    }
+}
+
+/// Index of the last entry of the log, committed or not.
+pub struct GetLastLogIndex;
+
+impl Message for GetLastLogIndex {
+    type Result = Result<u64, ()>;
+}
+
+impl Handler<GetLastLogIndex> for MemoryStorage {
+    type Result = Result<u64, ()>;
+
+    fn handle(&mut self, _: GetLastLogIndex, _: &mut Self::Context) -> Self::Result {
+        Ok(self.log.last().map(|(index, _)| index).unwrap_or(0))
+    }
+}
+
+/// Write a snapshot through the applied index if the furthest behind follower, at `matched`,
+/// needs entries the last snapshot compacted or lags more than `max_lag` entries. Answers with
+/// the index of the new snapshot, `None` if none was needed or one is already being written.
+pub struct TriggerSnapshot {
+    pub matched: u64,
+    pub max_lag: u64,
+}
+
+impl Message for TriggerSnapshot {
+    type Result = Result<Option<u64>, MemoryStorageError>;
+}
+
+impl Handler<TriggerSnapshot> for MemoryStorage {
+    type Result = ResponseActFuture<Self, Option<u64>, MemoryStorageError>;
+
+    fn handle(&mut self, msg: TriggerSnapshot, _: &mut Self::Context) -> Self::Result {
+        let last = self.log.last().map(|(index, _)| index).unwrap_or(0);
+        let applied = self.state_machine.iter().last().map(|e| *e.0).unwrap_or(0);
+        let snapshot = self.snapshot_data.as_ref().map(|s| s.index).unwrap_or(0);
+
+        let compacted = msg.matched < snapshot;
+        let lagging = last.saturating_sub(msg.matched) > msg.max_lag;
+
+        // the current snapshot is as fresh as it gets
+        if !(compacted || lagging) || applied <= snapshot || self.compacting {
+            return Box::new(fut::ok(None));
+        }
+
+        info!(
+            "Snapshotting through index {} for a follower at index {} ({} behind, snapshot at {})",
+            applied,
+            msg.matched,
+            last.saturating_sub(msg.matched),
+            snapshot
+        );
+        self.compacting = true;
+        Box::new(
+            self.create_snapshot(applied)
+                .map(|snapshot, _, _| Some(snapshot.index))
+                .map_err(|err, act, _| {
+                    act.compacting = false;
+                    err
+                }),
+        )
+    }
+}
+
+/// Whether a node is on the hash ring according to the entries applied so far.
+pub struct LookupRingMember(pub NodeId);
+
+impl Message for LookupRingMember {
+    type Result = Result<bool, ()>;
+}
+
+impl Handler<LookupRingMember> for MemoryStorage {
+    type Result = Result<bool, ()>;
+
+    fn handle(&mut self, msg: LookupRingMember, _: &mut Self::Context) -> Self::Result {
+        Ok(self.ring_member(msg.0))
+    }
+}
+
+impl MemoryStorage {
+    /// Whether the node is on the hash ring as of the applied entries, the last add or remove
+    /// of the node decides.
+    fn ring_member(&self, id: NodeId) -> bool {
+        let member = self.state_machine.values().rev().find_map(|e| match &e.payload {
+            EntryPayload::Normal(entry) => entry.data.data.commands().into_iter().rev().find_map(|command| {
+                match *command {
+                    MemoryStorageData::Add(added) if added == id => Some(true),
+                    MemoryStorageData::Remove(removed) if removed == id => Some(false),
+                    _ => None,
+                }
+            }),
+            _ => None,
+        });
+
+        member.unwrap_or(false)
+    }
+}
+
+/// The state a node applied so far, as seen by a `QueryLocalState` query.
+pub struct LocalState<'a> {
+    storage: &'a MemoryStorage,
+}
+
+impl<'a> LocalState<'a> {
+    /// Term and index of the last applied entry.
+    pub fn applied(&self) -> (u64, u64) {
+        self.storage
+            .state_machine
+            .values()
+            .next_back()
+            .map_or((0, 0), |entry| (entry.term, entry.index))
+    }
+
+    /// An applied entry, `None` once compacted.
+    pub fn entry(&self, index: u64) -> Option<&Entry> {
+        self.storage.state_machine.get(&index)
+    }
+
+    pub fn ring_member(&self, id: NodeId) -> bool {
+        self.storage.ring_member(id)
+    }
+
+    pub fn cordoned(&self) -> &BTreeSet<NodeId> {
+        &self.storage.cordoned
+    }
+
+    pub fn session(&self, session: &str) -> Option<&ClientSession> {
+        self.storage.sessions.get(session)
+    }
+
+    /// Query the application state machine, `None` without one.
+    pub fn query_app(&self, query: &[u8]) -> Option<Result<Vec<u8>, String>> {
+        let state_machine = self.storage.hooks.read().unwrap().state_machine()?;
+        let res = state_machine.lock().unwrap().query(query);
+        Some(res)
+    }
+}
+
+/// Answer of a `QueryLocalState` with the last entry applied when it ran.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LocalRead<R> {
+    pub term: u64,
+    pub index: u64,
+    pub value: R,
+}
+
+/// Read-only query of the state this node applied so far, run right away on the storage
+/// actor so it sees a consistent applied index. Nothing is waited for: followers may lag
+/// the leader, wait for `ReadIndex` first to read at least at the index of a leader.
+pub struct QueryLocalState<R> {
+    query: Box<dyn FnOnce(&LocalState) -> R + Send>,
+}
+
+impl<R> QueryLocalState<R> {
+    pub fn new<F>(query: F) -> QueryLocalState<R>
+    where
+        F: FnOnce(&LocalState) -> R + Send + 'static,
+    {
+        QueryLocalState { query: Box::new(query) }
+    }
+}
+
+impl QueryLocalState<Option<Result<Vec<u8>, String>>> {
+    /// Query of the application state machine, see `app::query`.
+    pub fn app(query: Vec<u8>) -> Self {
+        QueryLocalState::new(move |state| state.query_app(&query))
+    }
+}
+
+impl<R: Send + 'static> Message for QueryLocalState<R> {
+    type Result = Result<LocalRead<R>, ()>;
+}
+
+impl<R: Send + 'static> Handler<QueryLocalState<R>> for MemoryStorage {
+    type Result = Result<LocalRead<R>, ()>;
+
+    fn handle(&mut self, msg: QueryLocalState<R>, _: &mut Self::Context) -> Self::Result {
+        let state = LocalState { storage: self };
+        let (term, index) = state.applied();
+
+        Ok(LocalRead {
+            term: term,
+            index: index,
+            value: (msg.query)(&state),
+        })
+    }
+}
+
+/// Get the current holder and fencing token of a lock.
+pub struct GetLock(pub String);
+
+impl Message for GetLock {
+    type Result = Result<Option<LockState>, ()>;
+}
+
+impl Handler<GetLock> for MemoryStorage {
+    type Result = Result<Option<LockState>, ()>;
+
+    fn handle(&mut self, msg: GetLock, _: &mut Self::Context) -> Self::Result {
+        Ok(self.locks.get(msg.0.as_str()).cloned())
+    }
+}
+
+/// State of a client session as of the entries applied by this node.
+pub struct LookupClientSession(pub String);
+
+impl Message for LookupClientSession {
+    type Result = Result<Option<ClientSession>, ()>;
+}
+
+impl Handler<LookupClientSession> for MemoryStorage {
+    type Result = Result<Option<ClientSession>, ()>;
+
+    fn handle(&mut self, msg: LookupClientSession, _: &mut Self::Context) -> Self::Result {
+        Ok(self.sessions.get(msg.0.as_str()).cloned())
+    }
+}
+
+/// Whether a fencing token is the one of the current holder of the lock.
+pub struct CheckFencingToken(pub String, pub FencingToken);
+
+impl Message for CheckFencingToken {
+    type Result = Result<bool, ()>;
+}
+
+impl Handler<CheckFencingToken> for MemoryStorage {
+    type Result = Result<bool, ()>;
+
+    fn handle(&mut self, msg: CheckFencingToken, _: &mut Self::Context) -> Self::Result {
+        Ok(self.locks.is_fresh(msg.0.as_str(), msg.1))
+    }
+}
+
+/// Get the federated clusters known to this node which announced themselves since the given
+/// unix time in milliseconds.
+pub struct GetClusterRecords(pub u64);
+
+impl Message for GetClusterRecords {
+    type Result = Result<Vec<ClusterRecord>, ()>;
+}
+
+impl Handler<GetClusterRecords> for MemoryStorage {
+    type Result = Result<Vec<ClusterRecord>, ()>;
+
+    fn handle(&mut self, msg: GetClusterRecords, _: &mut Self::Context) -> Self::Result {
+        Ok(self.federation.clusters(msg.0))
+    }
+}
+
+/// Truncate the log prefix through the given applied index once the archiver confirmed it
+/// copied those entries, the prefix is replaced by a snapshot like a regular compaction.
+pub struct TruncateLog(pub u64);
+
+impl Message for TruncateLog {
+    type Result = Result<u64, MemoryStorageError>;
+}
+
+impl Handler<TruncateLog> for MemoryStorage {
+    type Result = ResponseActFuture<Self, u64, MemoryStorageError>;
+
+    fn handle(&mut self, msg: TruncateLog, _: &mut Self::Context) -> Self::Result {
+        let through = msg.0;
+        let applied = self.state_machine.iter().last().map(|e| *e.0).unwrap_or(0);
+
+        let archiver = match self.hooks.read().unwrap().archiver() {
+            Some(archiver) => archiver,
+            None => {
+                error!("Refusing to truncate the log without an archiver");
+                return Box::new(fut::err(MemoryStorageError::default()));
+            }
+        };
+
+        if through > applied || self.compacting {
+            return Box::new(fut::err(MemoryStorageError::default()));
+        }
+
+        let entries = match self.log.range(0u64..=through) {
+            Ok(entries) => entries,
+            Err(err) => {
+                error!("Error reading spilled log entries for archiving. {}", err);
+                return Box::new(fut::err(MemoryStorageError::default()));
+            }
+        };
+
+        self.compacting = true;
+        Box::new(
+            fut::wrap_future(self.snapshot_actor.send(ArchiveEntries(archiver, entries)))
+                .map_err(|err, _, _| panic!("Error communicating with snapshot actor. {}", err))
+                .and_then(|res, act: &mut Self, _| fut::result(res.map_err(|err| act.snapshot_error(err))))
+                .and_then(move |_, act: &mut Self, _| act.create_snapshot(through))
+                .map(move |_, _, _| through)
+                .map_err(|err, act, _| {
+                    act.compacting = false;
+                    err
+                }),
+        )
+    }
+}
+
+/// Enter or leave maintenance mode: while in maintenance committed entries keep being received
+/// and logged but aren't applied, so the state machine stays still. Answers with the index the
+/// state machine is at.
+pub struct SetMaintenance(pub bool);
+
+impl Message for SetMaintenance {
+    type Result = Result<u64, MemoryStorageError>;
+}
+
+impl Handler<SetMaintenance> for MemoryStorage {
+    type Result = Result<u64, MemoryStorageError>;
+
+    fn handle(&mut self, msg: SetMaintenance, _: &mut Self::Context) -> Self::Result {
+        match (msg.0, self.paused.is_some()) {
+            (true, false) => {
+                info!("Entering maintenance mode, entries won't be applied until resumed");
+                self.paused = Some(Vec::new());
+            }
+            (false, true) => {
+                info!("Leaving maintenance mode");
+                self.resume()?;
+            }
+            _ => (),
+        }
+
+        Ok(self.state_machine.iter().last().map(|e| *e.0).unwrap_or(0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_raft::messages::EntryNormal;
+
+    use crate::hash_ring;
+    use crate::raft::hooks::HookChain;
+    use crate::raft::hlc::HybridClock;
+
+    fn blank(index: u64, term: u64) -> Entry {
+        Entry {
+            index: index,
+            term: term,
+            payload: EntryPayload::Blank,
+        }
+    }
+
+    /// An entry of term 1 stamped `at`, proposed under `key` if any.
+    fn normal(index: u64, at: u64, key: Option<&str>, data: MemoryStorageData) -> Entry {
+        Entry {
+            index: index,
+            term: 1,
+            payload: EntryPayload::Normal(EntryNormal {
+                data: StampedData {
+                    hlc: Hlc { physical: at, logical: 0 },
+                    data: data,
+                    key: key.map(|key| key.to_owned()),
+                },
+            }),
+        }
+    }
+
+    struct Ignore;
+
+    impl Actor for Ignore {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<StorageFaulted> for Ignore {
+        type Result = ();
+
+        fn handle(&mut self, _: StorageFaulted, _: &mut Self::Context) {}
+    }
+
+    impl Handler<AddressChanged> for Ignore {
+        type Result = ();
+
+        fn handle(&mut self, _: AddressChanged, _: &mut Self::Context) {}
+    }
+
+    /// A storage whose actor isn't started, its methods are called directly. Needs a system
+    /// for its snapshot actor.
+    fn storage(dir: &std::path::Path) -> MemoryStorage {
+        let ignore = Context::<Ignore>::new().address();
+        MemoryStorage::new(
+            vec![0],
+            dir.to_string_lossy().to_string(),
+            hash_ring::Ring::new(10),
+            Context::<Server>::new().address(),
+            ignore.clone().recipient(),
+            ignore.recipient(),
+            None,
+            None,
+            HookChain::new(),
+            HybridClock::new(),
+            SnapshotVersionPolicy::default(),
+            WriteFreeze::default(),
+            None,
+            1,
+            EventLog::new(0),
+        )
+    }
+
+    #[test]
+    fn replacing_an_entry_of_another_term_drops_the_tail() {
+        let dir = tempfile::tempdir().unwrap();
+        // most of the log is spilled, conflicts are found there too
+        let mut log = LogCache::new(dir.path().join("spill"), Some(2));
+        for index in 1..=6 {
+            log.insert(index, Arc::new(blank(index, 1)));
+        }
+
+        // the same entries again, e.g. a retried append
+        assert_eq!(conflicting_tail(&mut log, &[blank(2, 1), blank(3, 1)]).unwrap(), None);
+        // appended past the end of the log
+        assert_eq!(conflicting_tail(&mut log, &[blank(7, 2)]).unwrap(), None);
+        // a new leader replacing the uncommitted tail from 3 on
+        assert_eq!(conflicting_tail(&mut log, &[blank(3, 2), blank(4, 2)]).unwrap(), Some(4));
+
+        log.insert(3, Arc::new(blank(3, 2)));
+        log.insert(4, Arc::new(blank(4, 2)));
+        log.truncate_after(4);
+        let terms = log.range(..).unwrap().iter().map(|e| (e.index, e.term)).collect::<Vec<_>>();
+        assert_eq!(terms, vec![(1, 1), (2, 1), (3, 2), (4, 2)]);
+    }
+
+    #[test]
+    fn full_disks_are_told_apart_from_other_faults() {
+        assert_eq!(StorageFault::from_io(&io::Error::from_raw_os_error(libc::ENOSPC)), StorageFault::DiskFull);
+        match StorageFault::from_io(&io::Error::from_raw_os_error(libc::EIO)) {
+            StorageFault::Io(_) => (),
+            fault => panic!("EIO taken for {:?}", fault),
+        }
+    }
+
+    #[test]
+    fn snapshots_taken_after_a_purge_rebuild_the_same_state() {
+        let _sys = System::new("purge");
+        let dir = tempfile::tempdir().unwrap();
+        let mut purged = storage(dir.path());
+        let lock = |name: &str| MemoryStorageData::AcquireLock { name: name.to_owned(), holder: "h".to_owned() };
+        let checkpoint = |index| MemoryStorageData::SinkCheckpoint { sink: "s".to_owned(), index: index };
+
+        let entries = vec![
+            normal(1, 10, None, lock("a")),
+            normal(2, 20, None, MemoryStorageData::ReleaseLock { name: "a".to_owned(), holder: "h".to_owned() }),
+            normal(3, 30, None, checkpoint(1)),
+            normal(4, 40, None, checkpoint(3)),
+            normal(5, 50, Some("k"), MemoryStorageData::Freeze(true)),
+            normal(6, 60, Some("k"), MemoryStorageData::Freeze(true)),
+            normal(7, 70, None, MemoryStorageData::Purge { before: 100 }),
+            normal(8, 200, None, lock("b")),
+        ];
+        for e in entries.iter() {
+            if e.index == 7 {
+                assert!(purged.debris_due(100));
+            }
+            purged.apply(e).unwrap();
+        }
+        // an earlier snapshot covers the log through 4, taken before the purge
+        purged.log.insert(4, Arc::new(Entry::new_snapshot_pointer(EntrySnapshotPointer { path: "4".to_owned() }, 4, 1)));
+        for e in entries[4..].iter() {
+            purged.log.insert(e.index, Arc::new(e.clone()));
         }
+        // nothing left to purge until more debris ages
+        assert!(!purged.debris_due(100));
+
+        let snapshot = purged.snapshot_entries(purged.log.range(..=8).unwrap());
+        let indexes = snapshot.iter().map(|e| e.index).collect::<Vec<_>>();
+        assert_eq!(indexes, vec![4, 5, 7, 8]);
+
+        let mut rebuilt = storage(dir.path());
+        rebuilt.state_machine.extend(snapshot.iter().map(|e| (e.index, (**e).clone())));
+        rebuilt.replay_state_machine(8);
+        assert_eq!(rebuilt.state_machine.keys().collect::<Vec<_>>(), purged.state_machine.keys().collect::<Vec<_>>());
+        assert_eq!(rebuilt.applied_keys, purged.applied_keys);
+        assert_eq!(rebuilt.sink_checkpoints, purged.sink_checkpoints);
+        assert_eq!(rebuilt.locks.get("a"), None);
+        assert_eq!(rebuilt.locks.get("b"), purged.locks.get("b"));
+        assert!(rebuilt.frozen.load(Ordering::SeqCst));
     }
 }
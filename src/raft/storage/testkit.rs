@@ -0,0 +1,562 @@
+//! Conformance kit for `LogStore` backends: cases exercising the contract the storage relies
+//! on, i.e. append, truncate, snapshot pointer and hard state semantics, and what a store
+//! recovers once it's reopened after a restart or crash. Third-party backends run it from
+//! their own tests,
+//!
+//! ```ignore
+//! let report = testkit::run(|dir| Ok(Box::new(MyLogStore::open(dir)?) as Box<dyn LogStore>));
+//! report.assert_passed();
+//! ```
+//!
+//! and `raftor storage-testkit` runs it against the built-in stores. `check_model` runs a
+//! sequence of raft writes against a store and a log kept in memory, for property tests
+//! generating the sequences.
+
+use actix_raft::messages::{EntryNormal, EntryPayload, EntrySnapshotPointer, MembershipConfig};
+use actix_raft::storage::HardState;
+use actix_raft::NodeId;
+use rmp_serde as rmps;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::path::Path;
+use tempfile::tempdir;
+
+use super::{Entry, MemoryStorageData, StampedData};
+use crate::raft::durable::LogStore;
+use crate::raft::hlc::Hlc;
+use crate::raft::recovery::LogReplay;
+
+/// Opens the store kept in the given directory, called again on the same directory to
+/// reopen it once the previous store was dropped.
+pub type OpenStore<'a> = dyn FnMut(&Path) -> io::Result<Box<dyn LogStore>> + 'a;
+
+type CaseFn = fn(&mut OpenStore, &Path) -> Result<(), String>;
+
+/// Cases in the order they're run, each in a directory of its own.
+const CASES: &[(&str, CaseFn)] = &[
+    ("empty store", empty_store),
+    ("hard state round trip", hard_state_round_trip),
+    ("hard state overwritten", hard_state_overwritten),
+    ("hard state survives reopen", hard_state_survives_reopen),
+    ("append in order", append_in_order),
+    ("append sorts by index", append_sorts_by_index),
+    ("empty append", empty_append),
+    ("append replaces same index", append_replaces_same_index),
+    ("truncate before", truncate_before),
+    ("truncate past the last entry", truncate_past_last_entry),
+    ("truncate an empty log", truncate_empty_log),
+    ("truncate after", truncate_after),
+    ("snapshot pointer compaction", snapshot_pointer_compaction),
+    ("log survives reopen", log_survives_reopen),
+    ("truncation survives reopen", truncation_survives_reopen),
+    ("conflicting tail survives reopen", conflicting_tail_survives_reopen),
+    ("flushed buffered writes survive", flushed_buffered_writes_survive),
+    ("writes after reopen", writes_after_reopen),
+    ("batched replay", batched_replay),
+    ("vacuum keeps the log", vacuum_keeps_log),
+];
+
+/// Outcome of one case.
+pub struct CaseResult {
+    pub name: &'static str,
+    pub outcome: Result<(), String>,
+}
+
+/// Outcomes of all the cases of a run.
+pub struct Report {
+    pub cases: Vec<CaseResult>,
+}
+
+impl Report {
+    pub fn passed(&self) -> bool {
+        self.cases.iter().all(|case| case.outcome.is_ok())
+    }
+
+    pub fn failures(&self) -> impl Iterator<Item = &CaseResult> {
+        self.cases.iter().filter(|case| case.outcome.is_err())
+    }
+
+    /// Panic listing the failed cases, for running the kit from a `#[test]`.
+    pub fn assert_passed(&self) {
+        if !self.passed() {
+            panic!("log store conformance failed\n{}", self);
+        }
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for case in &self.cases {
+            match case.outcome {
+                Ok(_) => writeln!(f, "[storage-testkit] {:<32} PASS", case.name)?,
+                Err(ref reason) => writeln!(f, "[storage-testkit] {:<32} FAIL ({})", case.name, reason)?,
+            }
+        }
+        let passed = self.cases.iter().filter(|case| case.outcome.is_ok()).count();
+        write!(f, "[storage-testkit] {}/{} cases passed", passed, self.cases.len())
+    }
+}
+
+/// Run every case against stores opened by `open`, each in a fresh temporary directory.
+pub fn run<F>(mut open: F) -> Report
+where
+    F: FnMut(&Path) -> io::Result<Box<dyn LogStore>>,
+{
+    let cases = CASES
+        .iter()
+        .map(|(name, case)| {
+            let outcome = tempdir()
+                .map_err(|err| format!("can't create a directory: {}", err))
+                .and_then(|dir| case(&mut open, dir.path()));
+
+            CaseResult {
+                name: name,
+                outcome: outcome,
+            }
+        })
+        .collect();
+
+    Report { cases: cases }
+}
+
+fn empty_store(open: &mut OpenStore, dir: &Path) -> Result<(), String> {
+    let store = reopen(open, dir)?;
+    let (hs, entries) = load(&*store)?;
+
+    if hs.is_some() {
+        return Err("a new store has a hard state".to_owned());
+    }
+    expect_indexes(&entries, &[])
+}
+
+fn hard_state_round_trip(open: &mut OpenStore, dir: &Path) -> Result<(), String> {
+    let mut store = reopen(open, dir)?;
+    let hs = hard_state(3, Some(2), vec![1, 2, 3]);
+    store.save_hard_state(&hs).map_err(io_err("save_hard_state"))?;
+
+    expect_hard_state(&*store, &hs)
+}
+
+fn hard_state_overwritten(open: &mut OpenStore, dir: &Path) -> Result<(), String> {
+    let mut store = reopen(open, dir)?;
+    store.save_hard_state(&hard_state(1, Some(1), vec![1])).map_err(io_err("save_hard_state"))?;
+    let hs = hard_state(2, None, vec![1, 2]);
+    store.save_hard_state(&hs).map_err(io_err("save_hard_state"))?;
+
+    expect_hard_state(&*store, &hs)
+}
+
+fn hard_state_survives_reopen(open: &mut OpenStore, dir: &Path) -> Result<(), String> {
+    let hs = hard_state(7, Some(3), vec![1, 2, 3]);
+    {
+        let mut store = reopen(open, dir)?;
+        store.save_hard_state(&hs).map_err(io_err("save_hard_state"))?;
+    }
+
+    let store = reopen(open, dir)?;
+    expect_hard_state(&*store, &hs)
+}
+
+fn append_in_order(open: &mut OpenStore, dir: &Path) -> Result<(), String> {
+    let mut store = reopen(open, dir)?;
+    let entries: Vec<Entry> = (1..=6).map(|index| normal(index, 1)).collect();
+    append(&mut *store, &entries[..3])?;
+    append(&mut *store, &entries[3..])?;
+
+    let (_, loaded) = load(&*store)?;
+    expect_entries(&loaded, &entries)
+}
+
+fn append_sorts_by_index(open: &mut OpenStore, dir: &Path) -> Result<(), String> {
+    let mut store = reopen(open, dir)?;
+    append(&mut *store, &[normal(3, 1), normal(1, 1)])?;
+    append(&mut *store, &[normal(2, 1)])?;
+
+    let (_, loaded) = load(&*store)?;
+    expect_entries(&loaded, &[normal(1, 1), normal(2, 1), normal(3, 1)])
+}
+
+fn empty_append(open: &mut OpenStore, dir: &Path) -> Result<(), String> {
+    let mut store = reopen(open, dir)?;
+    append(&mut *store, &[normal(1, 1)])?;
+    append(&mut *store, &[])?;
+
+    let (_, loaded) = load(&*store)?;
+    expect_entries(&loaded, &[normal(1, 1)])
+}
+
+fn append_replaces_same_index(open: &mut OpenStore, dir: &Path) -> Result<(), String> {
+    let mut store = reopen(open, dir)?;
+    append(&mut *store, &(1..=4).map(|index| normal(index, 1)).collect::<Vec<_>>())?;
+    // a new leader overwriting the uncommitted tail of the log
+    append(&mut *store, &[normal(3, 2), normal(4, 2)])?;
+
+    let (_, loaded) = load(&*store)?;
+    expect_entries(&loaded, &[normal(1, 1), normal(2, 1), normal(3, 2), normal(4, 2)])
+}
+
+fn truncate_before(open: &mut OpenStore, dir: &Path) -> Result<(), String> {
+    let mut store = reopen(open, dir)?;
+    append(&mut *store, &(1..=10).map(|index| normal(index, 1)).collect::<Vec<_>>())?;
+    store.truncate_before(4).map_err(io_err("truncate_before"))?;
+
+    let (_, loaded) = load(&*store)?;
+    expect_indexes(&loaded, &(4..=10).collect::<Vec<_>>())
+}
+
+fn truncate_past_last_entry(open: &mut OpenStore, dir: &Path) -> Result<(), String> {
+    let mut store = reopen(open, dir)?;
+    append(&mut *store, &(1..=5).map(|index| normal(index, 1)).collect::<Vec<_>>())?;
+    // an installed snapshot covers more than the follower had
+    store.truncate_before(20).map_err(io_err("truncate_before"))?;
+    let (_, loaded) = load(&*store)?;
+    expect_indexes(&loaded, &[])?;
+
+    append(&mut *store, &[normal(20, 2)])?;
+    let (_, loaded) = load(&*store)?;
+    expect_entries(&loaded, &[normal(20, 2)])
+}
+
+fn truncate_empty_log(open: &mut OpenStore, dir: &Path) -> Result<(), String> {
+    let mut store = reopen(open, dir)?;
+    store.truncate_before(5).map_err(io_err("truncate_before"))?;
+
+    let (_, loaded) = load(&*store)?;
+    expect_indexes(&loaded, &[])
+}
+
+fn truncate_after(open: &mut OpenStore, dir: &Path) -> Result<(), String> {
+    let mut store = reopen(open, dir)?;
+    append(&mut *store, &(1..=6).map(|index| normal(index, 1)).collect::<Vec<_>>())?;
+    store.truncate_after(3).map_err(io_err("truncate_after"))?;
+    let (_, loaded) = load(&*store)?;
+    expect_indexes(&loaded, &[1, 2, 3])?;
+
+    store.truncate_after(10).map_err(io_err("truncate_after"))?;
+    append(&mut *store, &[normal(4, 2)])?;
+    let (_, loaded) = load(&*store)?;
+    expect_entries(&loaded, &[normal(1, 1), normal(2, 1), normal(3, 1), normal(4, 2)])
+}
+
+fn snapshot_pointer_compaction(open: &mut OpenStore, dir: &Path) -> Result<(), String> {
+    let mut store = reopen(open, dir)?;
+    append(&mut *store, &(1..=10).map(|index| normal(index, 1)).collect::<Vec<_>>())?;
+    // what the storage does once a snapshot through 6 is written
+    let pointer = snapshot_pointer(6, 1);
+    store.truncate_before(6).map_err(io_err("truncate_before"))?;
+    append(&mut *store, &[pointer.clone()])?;
+
+    let mut expected = vec![pointer];
+    expected.extend((7..=10).map(|index| normal(index, 1)));
+    let (_, loaded) = load(&*store)?;
+    expect_entries(&loaded, &expected)?;
+
+    drop(store);
+    let store = reopen(open, dir)?;
+    let (_, loaded) = load(&*store)?;
+    match loaded.first().map(|entry| &entry.payload) {
+        Some(EntryPayload::SnapshotPointer(_)) => expect_entries(&loaded, &expected),
+        _ => Err("the recovered log doesn't start with the snapshot pointer".to_owned()),
+    }
+}
+
+fn log_survives_reopen(open: &mut OpenStore, dir: &Path) -> Result<(), String> {
+    let hs = hard_state(2, Some(1), vec![1, 2, 3]);
+    let entries: Vec<Entry> = (1..=5).map(|index| normal(index, 2)).collect();
+    {
+        let mut store = reopen(open, dir)?;
+        append(&mut *store, &entries)?;
+        store.save_hard_state(&hs).map_err(io_err("save_hard_state"))?;
+    }
+
+    let store = reopen(open, dir)?;
+    let (_, loaded) = load(&*store)?;
+    expect_entries(&loaded, &entries)?;
+    expect_hard_state(&*store, &hs)
+}
+
+fn truncation_survives_reopen(open: &mut OpenStore, dir: &Path) -> Result<(), String> {
+    {
+        let mut store = reopen(open, dir)?;
+        append(&mut *store, &(1..=8).map(|index| normal(index, 1)).collect::<Vec<_>>())?;
+        store.truncate_before(5).map_err(io_err("truncate_before"))?;
+        append(&mut *store, &[normal(8, 2)])?;
+    }
+
+    let store = reopen(open, dir)?;
+    let (_, loaded) = load(&*store)?;
+    expect_entries(&loaded, &[normal(5, 1), normal(6, 1), normal(7, 1), normal(8, 2)])
+}
+
+fn conflicting_tail_survives_reopen(open: &mut OpenStore, dir: &Path) -> Result<(), String> {
+    {
+        let mut store = reopen(open, dir)?;
+        append(&mut *store, &(1..=6).map(|index| normal(index, 1)).collect::<Vec<_>>())?;
+        // what the storage does when a new leader replaces the uncommitted tail from 4 on
+        append(&mut *store, &[normal(4, 2)])?;
+        store.truncate_after(4).map_err(io_err("truncate_after"))?;
+    }
+
+    let store = reopen(open, dir)?;
+    let (_, loaded) = load(&*store)?;
+    expect_entries(&loaded, &[normal(1, 1), normal(2, 1), normal(3, 1), normal(4, 2)])
+}
+
+fn flushed_buffered_writes_survive(open: &mut OpenStore, dir: &Path) -> Result<(), String> {
+    let entries: Vec<Entry> = (1..=4).map(|index| normal(index, 1)).collect();
+    {
+        let mut store = reopen(open, dir)?;
+        // an async-ack voter, its writes are only durable once flushed by the panic hook
+        store.set_synced(false);
+        append(&mut *store, &entries)?;
+        store.save_hard_state(&hard_state(1, Some(1), vec![1])).map_err(io_err("save_hard_state"))?;
+        if let Some(flusher) = store.flusher() {
+            flusher().map_err(io_err("flusher"))?;
+        }
+    }
+
+    let store = reopen(open, dir)?;
+    let (_, loaded) = load(&*store)?;
+    expect_entries(&loaded, &entries)?;
+    expect_hard_state(&*store, &hard_state(1, Some(1), vec![1]))
+}
+
+fn writes_after_reopen(open: &mut OpenStore, dir: &Path) -> Result<(), String> {
+    {
+        let mut store = reopen(open, dir)?;
+        append(&mut *store, &[normal(1, 1), normal(2, 1)])?;
+    }
+
+    let mut store = reopen(open, dir)?;
+    append(&mut *store, &[normal(2, 2), normal(3, 2)])?;
+    store.truncate_before(2).map_err(io_err("truncate_before"))?;
+
+    let (_, loaded) = load(&*store)?;
+    expect_entries(&loaded, &[normal(2, 2), normal(3, 2)])
+}
+
+fn vacuum_keeps_log(open: &mut OpenStore, dir: &Path) -> Result<(), String> {
+    let mut store = reopen(open, dir)?;
+    append(&mut *store, &(1..=200).map(|index| normal(index, 1)).collect::<Vec<_>>())?;
+    store.truncate_before(150).map_err(io_err("truncate_before"))?;
+
+    let mut vacuum = match store.vacuum() {
+        Some(vacuum) => vacuum,
+        // nothing to vacuum, e.g. stores which don't fragment
+        None => return Ok(()),
+    };
+
+    // the steps run concurrently with the writes
+    let steps = vacuum.steps();
+    for step in 0..steps {
+        vacuum.step(step).map_err(io_err("vacuum step"))?;
+        if step == steps / 2 {
+            append(&mut *store, &[normal(201, 1)])?;
+        }
+    }
+
+    let mut expected: Vec<Entry> = (150..=200).map(|index| normal(index, 1)).collect();
+    if steps > 0 {
+        expected.push(normal(201, 1));
+    }
+    let (_, loaded) = load(&*store)?;
+    expect_entries(&loaded, &expected)
+}
+
+fn batched_replay(open: &mut OpenStore, dir: &Path) -> Result<(), String> {
+    let hs = hard_state(3, Some(2), vec![1, 2, 3]);
+    let mut entries: Vec<Entry> = vec![snapshot_pointer(10, 2)];
+    entries.extend((11..=60).map(|index| normal(index, 3)));
+    {
+        let mut store = reopen(open, dir)?;
+        append(&mut *store, &entries)?;
+        store.save_hard_state(&hs).map_err(io_err("save_hard_state"))?;
+    }
+
+    // batches which don't divide the log, decoded by several threads
+    let store = reopen(open, dir)?;
+    let replay = LogReplay::default();
+    let (replayed_hs, replayed) = store.replay(&replay, 7, 3).map_err(io_err("replay"))?;
+    expect_entries(&replayed, &entries)?;
+    if replayed_hs.map(|hs| hs.current_term) != Some(hs.current_term) {
+        return Err("replay didn't recover the hard state".to_owned());
+    }
+
+    let status = replay.status();
+    if status.total != entries.len() as u64 || status.replayed != entries.len() as u64 {
+        return Err(format!("replay reported {}/{} entries, expected {}", status.replayed, status.total, entries.len()));
+    }
+    Ok(())
+}
+
+/// A write raft makes to its log store.
+#[derive(Clone, Debug)]
+pub enum LogOp {
+    /// Append `count` entries of `term` after the last one
+    Append { count: u64, term: u64 },
+    /// Replace the last `back` entries, at most the whole log, with entries of `term`, as a
+    /// follower does with a tail conflicting with the log of its leader
+    Conflict { back: u64, term: u64 },
+    /// Drop all but the last `keep` entries, at least one, as a snapshot does
+    Compact { keep: u64 },
+    SaveHardState { term: u64, voted_for: Option<NodeId> },
+    /// Drop the store and open it again on the same directory
+    Reopen,
+}
+
+/// Run `ops` against the store opened in `dir`, checking after every one that the store loads
+/// what a log kept in memory holds.
+pub fn check_model(open: &mut OpenStore, dir: &Path, ops: &[LogOp]) -> Result<(), String> {
+    let mut store = reopen(open, dir)?;
+    let mut log = BTreeMap::new();
+    let mut hs = None;
+
+    for (i, op) in ops.iter().enumerate() {
+        let first = log.keys().next().cloned().unwrap_or(1);
+        let last = log.keys().next_back().cloned().unwrap_or(0);
+
+        match *op {
+            LogOp::Append { count, term } => {
+                let entries = (last + 1..last + 1 + count).map(|index| normal(index, term)).collect::<Vec<_>>();
+                append(&mut *store, &entries)?;
+                log.extend(entries.into_iter().map(|entry| (entry.index, entry)));
+            }
+            LogOp::Conflict { back, term } => {
+                let from = last - back.min(log.len() as u64);
+                store.truncate_after(from).map_err(io_err("truncate after"))?;
+                log.split_off(&(from + 1));
+                let entries = (from + 1..=last).map(|index| normal(index, term)).collect::<Vec<_>>();
+                append(&mut *store, &entries)?;
+                log.extend(entries.into_iter().map(|entry| (entry.index, entry)));
+            }
+            LogOp::Compact { keep } => {
+                let index = (last + 1).saturating_sub(keep.max(1)).max(first);
+                store.truncate_before(index).map_err(io_err("truncate before"))?;
+                log = log.split_off(&index);
+            }
+            LogOp::SaveHardState { term, voted_for } => {
+                let state = hard_state(term, voted_for, vec![1, 2, 3]);
+                store.save_hard_state(&state).map_err(io_err("save hard state"))?;
+                hs = Some(state);
+            }
+            LogOp::Reopen => {
+                drop(store);
+                store = reopen(open, dir)?;
+            }
+        }
+
+        let context = |err: String| format!("after op {} {:?}: {}", i, op, err);
+        let (loaded_hs, entries) = load(&*store).map_err(context)?;
+        expect_entries(&entries, &log.values().cloned().collect::<Vec<_>>()).map_err(context)?;
+        match hs {
+            Some(ref hs) => expect_hard_state(&*store, hs).map_err(context)?,
+            None if loaded_hs.is_some() => return Err(context("a hard state was loaded before one was saved".to_owned())),
+            None => (),
+        }
+    }
+    Ok(())
+}
+
+fn reopen(open: &mut OpenStore, dir: &Path) -> Result<Box<dyn LogStore>, String> {
+    open(dir).map_err(io_err("open"))
+}
+
+fn load(store: &dyn LogStore) -> Result<(Option<HardState>, Vec<Entry>), String> {
+    store.load().map_err(io_err("load"))
+}
+
+fn append(store: &mut dyn LogStore, entries: &[Entry]) -> Result<(), String> {
+    store.append(&entries.iter().collect::<Vec<_>>()).map_err(io_err("append"))
+}
+
+fn io_err(op: &'static str) -> impl Fn(io::Error) -> String {
+    move |err| format!("{} failed: {}", op, err)
+}
+
+fn expect_hard_state(store: &dyn LogStore, expected: &HardState) -> Result<(), String> {
+    let hs = match load(store)?.0 {
+        Some(hs) => hs,
+        None => return Err("no hard state recovered".to_owned()),
+    };
+
+    let same = hs.current_term == expected.current_term
+        && hs.voted_for == expected.voted_for
+        && hs.membership.members == expected.membership.members
+        && hs.membership.non_voters == expected.membership.non_voters
+        && hs.membership.removing == expected.membership.removing
+        && hs.membership.is_in_joint_consensus == expected.membership.is_in_joint_consensus;
+    if !same {
+        return Err(format!(
+            "recovered term {} vote {:?} members {:?}, expected term {} vote {:?} members {:?}",
+            hs.current_term,
+            hs.voted_for,
+            hs.membership.members,
+            expected.current_term,
+            expected.voted_for,
+            expected.membership.members
+        ));
+    }
+    Ok(())
+}
+
+fn expect_indexes(entries: &[Entry], expected: &[u64]) -> Result<(), String> {
+    let indexes: Vec<u64> = entries.iter().map(|entry| entry.index).collect();
+    if indexes != expected {
+        return Err(format!("loaded indexes {:?}, expected {:?}", indexes, expected));
+    }
+    Ok(())
+}
+
+/// Entries are compared by their serialized form, the payloads don't implement `PartialEq`.
+fn expect_entries(entries: &[Entry], expected: &[Entry]) -> Result<(), String> {
+    expect_indexes(entries, &expected.iter().map(|entry| entry.index).collect::<Vec<_>>())?;
+
+    for (entry, expected) in entries.iter().zip(expected) {
+        if entry.term != expected.term {
+            return Err(format!("entry {} has term {}, expected {}", entry.index, entry.term, expected.term));
+        }
+        if rmps::to_vec(entry).ok() != rmps::to_vec(expected).ok() {
+            return Err(format!("entry {} doesn't hold the appended payload", entry.index));
+        }
+    }
+    Ok(())
+}
+
+fn hard_state(term: u64, voted_for: Option<NodeId>, members: Vec<NodeId>) -> HardState {
+    HardState {
+        current_term: term,
+        voted_for: voted_for,
+        membership: MembershipConfig {
+            members: members,
+            non_voters: vec![],
+            removing: vec![],
+            is_in_joint_consensus: false,
+        },
+    }
+}
+
+/// A normal entry whose payload differs by index and term, so a replaced entry is told apart.
+fn normal(index: u64, term: u64) -> Entry {
+    Entry {
+        index: index,
+        term: term,
+        payload: EntryPayload::Normal(EntryNormal {
+            data: StampedData {
+                hlc: Hlc {
+                    physical: index,
+                    logical: term as u32,
+                },
+                data: MemoryStorageData::Add(index),
+                key: Some(format!("testkit-{}-{}", index, term)),
+            },
+        }),
+    }
+}
+
+fn snapshot_pointer(index: u64, term: u64) -> Entry {
+    let pointer = EntrySnapshotPointer {
+        path: format!("snapshots/{}", index),
+    };
+    Entry::new_snapshot_pointer(pointer, index, term)
+}
@@ -0,0 +1,172 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::clock;
+
+/// Upper bounds (ms) of the commit latency histogram buckets, the last bucket is unbounded.
+const LATENCY_BUCKETS: [u64; 10] = [1, 2, 5, 10, 25, 50, 100, 250, 500, 1000];
+
+/// How many entry timings are kept in memory
+const MAX_TIMINGS: usize = 10000;
+
+/// Wall-clock timestamps (ms since epoch) of an entry passing through this node.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct EntryTiming {
+    pub index: u64,
+    pub term: u64,
+    pub appended_at: u64,
+    /// When this node learned the entry is committed, i.e. was asked to apply it
+    #[serde(default)]
+    pub committed_at: Option<u64>,
+    pub applied_at: Option<u64>,
+}
+
+impl EntryTiming {
+    /// Time from being appended to the local log until being applied, i.e. commit + apply.
+    pub fn latency(&self) -> Option<u64> {
+        self.applied_at.map(|applied| applied.saturating_sub(self.appended_at))
+    }
+
+    /// Time from being appended to the local log until being known committed.
+    pub fn commit_latency(&self) -> Option<u64> {
+        self.committed_at.map(|committed| committed.saturating_sub(self.appended_at))
+    }
+
+    /// Time from being known committed until being applied, blob fetches and maintenance mode
+    /// included.
+    pub fn apply_latency(&self) -> Option<u64> {
+        match (self.committed_at, self.applied_at) {
+            (Some(committed), Some(applied)) => Some(applied.saturating_sub(committed)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LatencyHistogram {
+    /// (upper bound in ms, count), `None` is the unbounded bucket
+    pub buckets: Vec<(Option<u64>, u64)>,
+    pub count: u64,
+    pub sum_ms: u64,
+}
+
+impl LatencyHistogram {
+    pub fn new() -> Self {
+        let mut buckets = LATENCY_BUCKETS.iter().map(|b| (Some(*b), 0)).collect::<Vec<_>>();
+        buckets.push((None, 0));
+
+        LatencyHistogram {
+            buckets: buckets,
+            count: 0,
+            sum_ms: 0,
+        }
+    }
+
+    pub fn observe(&mut self, latency: u64) {
+        self.count += 1;
+        self.sum_ms += latency;
+
+        for bucket in self.buckets.iter_mut() {
+            match bucket.0 {
+                Some(bound) if latency > bound => continue,
+                _ => {
+                    bucket.1 += 1;
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Bounded record of recent entry timings plus a commit latency histogram.
+pub struct EntryTimings {
+    timings: BTreeMap<u64, EntryTiming>,
+    histogram: LatencyHistogram,
+}
+
+impl EntryTimings {
+    pub fn new() -> Self {
+        EntryTimings {
+            timings: BTreeMap::new(),
+            histogram: LatencyHistogram::new(),
+        }
+    }
+
+    pub fn appended(&mut self, index: u64, term: u64) {
+        self.timings.insert(
+            index,
+            EntryTiming {
+                index: index,
+                term: term,
+                appended_at: now_millis(),
+                committed_at: None,
+                applied_at: None,
+            },
+        );
+
+        while self.timings.len() > MAX_TIMINGS {
+            let first = *self.timings.keys().next().unwrap();
+            self.timings.remove(&first);
+        }
+    }
+
+    /// Record that an entry is committed, the first time this node is asked to apply it.
+    pub fn committed(&mut self, index: u64) {
+        if let Some(timing) = self.timings.get_mut(&index) {
+            if timing.committed_at.is_none() {
+                timing.committed_at = Some(now_millis());
+                if let Some(latency) = timing.commit_latency() {
+                    self.histogram.observe(latency);
+                }
+            }
+        }
+    }
+
+    /// Record the apply of an entry, returning its completed timing.
+    pub fn applied(&mut self, index: u64) -> Option<EntryTiming> {
+        let timing = self.timings.get_mut(&index)?;
+        timing.applied_at = Some(now_millis());
+
+        Some(timing.clone())
+    }
+
+    pub fn range(&self, from: u64, limit: usize) -> Vec<EntryTiming> {
+        self.timings.range(from..).take(limit).map(|(_, t)| t.clone()).collect()
+    }
+
+    pub fn histogram(&self) -> LatencyHistogram {
+        self.histogram.clone()
+    }
+}
+
+/// Wall clock time of the node's clock in unix milliseconds.
+pub fn now_millis() -> u64 {
+    clock::wall_millis()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commits_are_timed_apart_from_applies() {
+        let mut timings = EntryTimings::new();
+        timings.appended(1, 1);
+        timings.committed(1);
+        let committed_at = timings.range(1, 1)[0].committed_at;
+        assert!(committed_at.is_some());
+
+        // a retried apply keeps the first commit time
+        timings.committed(1);
+        let timing = timings.applied(1).unwrap();
+        assert_eq!(timing.committed_at, committed_at);
+        assert!(timing.applied_at >= timing.committed_at);
+        assert!(timing.apply_latency().is_some());
+        assert_eq!(timings.histogram().count, 1);
+
+        // entries appended before a restart aren't known, nothing is recorded for them
+        timings.committed(2);
+        assert!(timings.applied(2).is_none());
+        assert_eq!(timings.histogram().count, 1);
+    }
+}
@@ -1,4 +1,9 @@
 
+use actix::dev::ToEnvelope;
+use actix::prelude::*;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::network::RemoteMessage;
 use crate::raftor::Raftor;
 use crate::server::{CreateRoom, GetMembers, Join, SendRecipient, SendRoom};
 
@@ -13,4 +18,17 @@ impl Raftor {
         registry.register::<SendRecipient, _>(self.server.clone());
         registry.register::<Join, _>(self.server.clone());
     }
+
+    /// Handle an application defined message received from peers with the given actor. The
+    /// message type id must start with `APP_MESSAGE_PREFIX`, send it to peers through their
+    /// `PeerHandle`.
+    pub fn register_message<M, A>(&self, handler: Addr<A>) -> Result<(), String>
+    where
+        M: RemoteMessage + 'static,
+        M::Result: Send + Serialize + DeserializeOwned,
+        A: Actor + Handler<M>,
+        A::Context: ToEnvelope<A, M>,
+    {
+        self.registry.write().unwrap().register_app::<M, A>(handler)
+    }
 }
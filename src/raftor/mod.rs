@@ -1,20 +1,48 @@
 use actix::prelude::*;
 use actix_web::client::Client;
 use actix_raft::NodeId;
-use config;
+use futures::{future, Future};
+use serde::Serialize;
+use std::collections::BTreeMap;
 use std::env;
+use std::path::Path;
 use std::sync::{Arc, RwLock};
-use tokio::timer::Delay;
 use std::time::{Duration, Instant};
 
-use crate::config::{ConfigSchema, NetworkType, NodeInfo};
+use crate::clock;
+use crate::config::{ArbitrationConfig, ClockSource, ConfigSchema, NetworkType, NodeInfo, NodeRole, ReadStreaming, RemovedMemberPolicy};
+use crate::crash::{CrashRecorder, ShutdownState};
+use crate::events::EventLog;
+use crate::federation::Federation;
 use crate::hash_ring::{self, RingType};
-use crate::network::{HandlerRegistry, Network, DiscoverNodes, SetClusterState, NetworkState};
-use crate::raft::{RaftClient, MemRaft, RaftBuilder, InitRaft, AddNode};
+use crate::json_protocol::{JsonHandles, JsonListener};
+use crate::memory::MemoryCounters;
+use crate::network::{AwaitLeader, Halt, CachingResolver, EgressLimiter, HandlerRegistry, Network, DiscoverNodes, PeerResolver, Resolver, SystemResolver, Formation, SetClusterState, NetworkState, ServeClients, VERSION};
+use crate::preflight;
+use crate::raft::{RaftClient, MemRaft, RaftBuilder, InitRaft, AddNode, GetStorageInfo};
+use crate::raft::app::AppStateMachine;
+use crate::raft::projection::Projection;
+use crate::raft::group::{RaftGroups, Router, SharedRouter};
+use crate::raft::recovery::LogReplay;
+use crate::raft::hooks::{HookChain, Hooks, LogArchiver, RaftHook, SnapshotMigrator};
+use crate::raft::storage::{ClusterEpoch, MemoryStorageData, StorageStats};
+use crate::runtime::{ExitHandle, ExitReason, Runtime};
 use crate::server::Server;
+use crate::service::ServiceManager;
+use crate::standby::DataDirLock;
 use crate::utils;
 
+use self::promotion::PromotionTracker;
+use self::stall::StallDetector;
+
+mod bundle;
 mod handlers;
+mod promotion;
+mod retention;
+mod stall;
+
+pub use self::bundle::CreateSupportBundle;
+pub use self::promotion::{GetLearners, LearnerStatus};
 
 pub struct Raftor {
     id: NodeId,
@@ -22,86 +50,566 @@ pub struct Raftor {
     pub app_net: Addr<Network>,
     pub cluster_net: Addr<Network>,
     pub server: Addr<Server>,
+    pub groups: Addr<RaftGroups>,
+    router: SharedRouter,
     discovery_host: String,
     ring: RingType,
     registry: Arc<RwLock<HandlerRegistry>>,
+    hooks: Hooks,
     info: NodeInfo,
+    role: NodeRole,
+    started_at: Instant,
+    config: ConfigSchema,
+    arbiters: Vec<Arbiter>,
+    events: EventLog,
+    resolver: PeerResolver,
+    stall: Option<StallDetector>,
+    epoch: ClusterEpoch,
+    replay: LogReplay,
+    promotion: Option<PromotionTracker>,
+    data_lock: Option<DataDirLock>,
+    exit: Option<ExitHandle>,
+    /// Raft wasn't started because the node was removed while down, see `Rejoin`
+    awaiting_rejoin: bool,
 }
 
 impl Raftor {
     pub fn new() -> Raftor {
-        let mut config = config::Config::default();
-
-        config
-            .merge(config::File::with_name("Config"))
-            .unwrap()
-            .merge(config::Environment::with_prefix("APP"))
-            .unwrap();
-
-        let mut config = config.try_into::<ConfigSchema>().unwrap();
+        Raftor::load(None, None)
+    }
 
-        // create consistent hash ring
-        let ring = hash_ring::Ring::new(10);
+    /// Like `new`, the process exits through the runtime once a network of the node stops
+    /// without being shut down.
+    pub fn supervised(runtime: &Runtime) -> Raftor {
+        Raftor::load(Some(runtime.exit_handle()), None)
+    }
 
-        // create handlers registry
-        let registry = Arc::new(RwLock::new(HandlerRegistry::new()));
+    /// Like `supervised`, for a warm standby which took the data dir lock over already.
+    pub fn supervised_standby(runtime: &Runtime, data_lock: DataDirLock) -> Raftor {
+        Raftor::load(Some(runtime.exit_handle()), Some(data_lock))
+    }
 
+    fn load(exit: Option<ExitHandle>, data_lock: Option<DataDirLock>) -> Raftor {
+        let config = ConfigSchema::load().unwrap();
 
         let args: Vec<String> = env::args().collect();
         let cluster_address = args[1].as_str();
         let app_address = args[2].as_str();
         let public_address  = args[3].as_str();
 
-        let node_info = NodeInfo {
+        let node_info = Raftor::node_info(&config, cluster_address, app_address, public_address);
+        Raftor::build(config, node_info, exit, data_lock)
+    }
+
+    /// Info of the node listening on the addresses, with the id and labels it's listed with in
+    /// the configuration.
+    pub fn node_info(config: &ConfigSchema, cluster_address: &str, app_address: &str, public_address: &str) -> NodeInfo {
+        let listed = config
+            .nodes
+            .iter()
+            .find(|node| node.cluster_addr == cluster_address)
+            .cloned();
+
+        NodeInfo {
             cluster_addr: cluster_address.to_owned(),
             app_addr: app_address.to_owned(),
             public_addr: public_address.to_owned(),
-        };
+            id: listed.as_ref().and_then(|node| node.id),
+            labels: listed.map(|node| node.labels).unwrap_or_default(),
+            cluster: None,
+        }
+    }
+
+    /// Create a node from an already loaded configuration.
+    pub fn create(config: ConfigSchema, node_info: NodeInfo) -> Raftor {
+        Raftor::build(config, node_info, None, None)
+    }
+
+    fn build(mut config: ConfigSchema, mut node_info: NodeInfo, exit: Option<ExitHandle>, data_lock: Option<DataDirLock>) -> Raftor {
+        // every time read of the node follows the configured clock
+        if config.clock == ClockSource::Boottime {
+            Raftor::use_boottime();
+        }
+
+        // create consistent hash ring
+        let ring = hash_ring::Ring::new(10);
+
+        // create handlers registry
+        let registry = Arc::new(RwLock::new(HandlerRegistry::new()));
 
-        // generate local node id
-        let node_id = utils::generate_node_id(cluster_address);
+        let cluster_address = node_info.cluster_addr.clone();
+        let app_address = node_info.app_addr.clone();
+
+        // generate local node id, peers learn it from the node info sent when joining
+        let node_id = utils::local_node_id(&config.node_id, &node_info).unwrap_or_else(|err| panic!("{}", err));
+        node_info.id = Some(node_id);
+        if node_info.cluster.is_none() {
+            node_info.cluster = config.cluster.clone();
+        }
+
+        // the data dir is held by this process only
+        let data_lock = data_lock.or_else(|| {
+            config.data_dir.as_ref().map(|data_dir| {
+                DataDirLock::acquire(Path::new(data_dir)).unwrap_or_else(|err| panic!("Can't lock the data dir: {}", err))
+            })
+        });
+        if let Some(ref lock) = data_lock {
+            info!("Node {} holds its data dir with epoch {}", node_id, lock.epoch());
+        }
+
+        if config.audit_messages {
+            crate::audit::set_enabled(true);
+        }
+        if let Some(ref telemetry) = config.telemetry {
+            let cluster = config
+                .cluster
+                .as_ref()
+                .map(|cluster| cluster.name.as_str())
+                .or_else(|| config.federation.as_ref().map(|federation| federation.cluster_name.as_str()));
+            crate::telemetry::init(telemetry, node_id, cluster);
+        }
 
         Raftor::add_node_to_config(node_info.clone(), &mut config);
 
-        let cluster_arb = Arbiter::new();
-        let app_arb = Arbiter::new();
-        let raft_arb = Arbiter::new();
+        let preflight = preflight::run(&config, node_id, &node_info);
+        print!("{}", preflight);
+        let failures = preflight.failures();
+        if !failures.is_empty() {
+            let failed = failures.iter().map(|check| check.name.as_str()).collect::<Vec<_>>().join(", ");
+            if config.startup_checks.enforce {
+                panic!("Node {} refuses to start, failed startup checks: {}", node_id, failed);
+            }
+            warn!("Node {} starting despite failed startup checks: {}", node_id, failed);
+        }
+
+        let cluster_arb = clock::arbiter();
+        let app_arb = clock::arbiter();
+        let raft_arb = clock::arbiter();
+
+        // create proposal/apply hook chain
+        let hooks = HookChain::new();
 
-        let raft_client = RaftClient::new(node_id, ring.clone(), registry.clone());
+        // bytes held by the in-memory structures of this node
+        let memory = MemoryCounters::new(config.memory_limits.clone());
+
+        // events observed by this node, tailed with `raftor events`
+        let events = EventLog::new(node_id);
+        events.set_memory(memory.clone());
+
+        // panics flush the log store and leave a crash report in the data dir
+        let crash = CrashRecorder::new(node_id, config.data_dir.as_ref().map(String::as_str));
+        if config.crash_reports {
+            match CrashRecorder::install(&crash) {
+                Some(ShutdownState::Running) => {
+                    warn!("Node {} was not shut down cleanly, recovering its data dir", node_id);
+                    events.alert(format!("node {} restarted after an unclean shutdown", node_id));
+                }
+                Some(ShutdownState::Crashed) => {
+                    warn!("Node {} crashed before this start, see the crash report in its data dir", node_id);
+                    events.alert(format!("node {} restarted after a crash", node_id));
+                }
+                _ => (),
+            }
+        }
+
+        // peer addresses are resolved and cached once for both networks
+        let resolver = Arc::new(CachingResolver::new(SystemResolver::new(Duration::from_secs(config.dns_cache_ttl_secs))));
+
+        let raft_client = RaftClient::new(node_id, ring.clone(), registry.clone(), config.clone(), hooks.clone(), events.clone(), memory.clone(), crash.clone());
+        let epoch = raft_client.epoch();
+        let replay = raft_client.log_replay();
         let raft = RaftClient::start_in_arbiter(&raft_arb, |_| raft_client);
 
         // create cluster network
-        let mut cluster_net = Network::new(node_id, ring.clone(), registry.clone(), NetworkType::Cluster, raft.clone(), config.discovery_host.clone(), node_info.clone());
+        let mut cluster_net = Network::new(node_id, ring.clone(), registry.clone(), NetworkType::Cluster, raft.clone().into(), config.discovery_host.clone(), node_info.clone(), events.clone());
         // create application network
-        let mut app_net = Network::new(node_id, ring.clone(), registry.clone(), NetworkType::App, raft.clone(), config.discovery_host.clone(), node_info.clone());
+        let mut app_net = Network::new(node_id, ring.clone(), registry.clone(), NetworkType::App, raft.clone().into(), config.discovery_host.clone(), node_info.clone(), events.clone());
+
+        // both networks share the egress limit of the node
+        let egress = EgressLimiter::new(config.egress_limit_bytes_per_sec);
 
         cluster_net.configure(config.clone()); // configure network
-        cluster_net.bind(cluster_address); // listen on ip and port
+        cluster_net.set_resolver(resolver.clone());
+        cluster_net.set_memory(memory.clone());
+        cluster_net.set_egress_limiter(egress.clone());
+        cluster_net.set_hooks(hooks.clone());
+        cluster_net.set_crash_state(crash.clone());
+        cluster_net.bind(cluster_address.as_str()); // listen on ip and port
 
         app_net.configure(config.clone()); // configure network
-        app_net.bind(app_address); // listen on ip and port
+        app_net.set_resolver(resolver.clone());
+        app_net.set_memory(memory.clone());
+        app_net.set_egress_limiter(egress);
+        app_net.set_hooks(hooks.clone());
+        app_net.bind(app_address.as_str()); // listen on ip and port
+
+        if let Some(ref exit) = exit {
+            cluster_net.set_exit_handle(exit.clone());
+            app_net.set_exit_handle(exit.clone());
+        }
 
         let cluster_net_addr = Network::start_in_arbiter(&cluster_arb, |_| cluster_net);
         let app_net_addr = Network::start_in_arbiter(&app_arb, |_| app_net);
 
+        if let Some(ref arbitration) = config.arbitration {
+            Raftor::start_arbitration(node_id, arbitration, &config, cluster_net_addr.clone());
+        }
+
+        if let Some(ref federation) = config.federation {
+            Federation::new(federation.clone(), config.nodes.clone(), cluster_net_addr.clone()).start();
+        }
+
+        if let Some(ref service) = config.service {
+            ServiceManager::new(service.clone(), cluster_net_addr.clone(), app_net_addr.clone(), raft.clone(), config.role.is_member()).start();
+        }
+
         let server = Server::new(app_net_addr.clone(), ring.clone(), node_id);
         let server_addr = server.start();
 
+        // raft groups share the peer connections of the cluster network
+        let groups = RaftGroups::new(node_id, cluster_net_addr.clone(), server_addr.clone(), registry.clone(), events.clone(), config.clone()).start();
+        let router = SharedRouter::default();
+
+        // with TLS, clients may also connect on the cluster port using the `raftor-client` ALPN
+        // protocol, or on the client listener
+        let cluster_clients = config.tls.is_some() || config.client_listener.is_some();
+        if config.role.serves_clients() && (config.json_client_addr.is_some() || cluster_clients) {
+            let handles = JsonHandles {
+                id: node_id,
+                raft: raft.clone(),
+                app_net: app_net_addr.clone(),
+                cluster_net: cluster_net_addr.clone(),
+                groups: groups.clone(),
+                router: router.clone(),
+                epoch: epoch.clone(),
+                client_tokens: config.client_tokens.clone(),
+            };
+            let listener = JsonListener::new(config.json_client_addr.clone(), handles).start();
+            cluster_net_addr.do_send(ServeClients(listener.recipient()));
+        }
+
         Raftor {
             id: node_id,
             app_net: app_net_addr,
             cluster_net: cluster_net_addr,
             raft: raft,
             server: server_addr,
+            groups: groups,
+            router: router,
             ring: ring,
             registry: registry,
+            hooks: hooks,
             discovery_host: config.discovery_host.clone(),
             info: node_info,
+            role: config.role.clone(),
+            started_at: clock::now(),
+            config: config.clone(),
+            // the system's own arbiter under a virtual clock, which isn't this node's to stop
+            arbiters: if clock::is_virtual() { Vec::new() } else { vec![cluster_arb, app_arb, raft_arb] },
+            events: events,
+            resolver: resolver,
+            stall: config.write_stall.as_ref().map(StallDetector::new),
+            epoch: epoch,
+            replay: replay,
+            promotion: config.learner_promotion.as_ref().map(PromotionTracker::new),
+            data_lock: data_lock,
+            exit: exit,
+            awaiting_rejoin: false,
+        }
+    }
+
+    /// The cluster epoch as applied by this node, see `ClusterEpoch`.
+    pub fn epoch(&self) -> ClusterEpoch {
+        self.epoch.clone()
+    }
+
+    /// Progress of the replay of the persisted log when raft starts, see `LogReplay`.
+    pub fn log_replay(&self) -> LogReplay {
+        self.replay.clone()
+    }
+
+    /// Whether this node accepts client connections.
+    pub fn serves_clients(&self) -> bool {
+        self.role.serves_clients()
+    }
+
+    /// Whether this node serves the JSON proposal and read gateway.
+    pub fn http_gateway(&self) -> bool {
+        self.config.http_gateway
+    }
+
+    /// Paging of the query answers of the gateway.
+    pub fn read_streaming(&self) -> ReadStreaming {
+        self.config.read_streaming.clone()
+    }
+
+    /// Whether stop signals shut the node down cleanly, instead of the HTTP servers stopping it.
+    pub fn handles_stop_signals(&self) -> bool {
+        self.config.service.as_ref().map_or(false, |service| service.clean_stop)
+    }
+
+    /// Address of the admin API, if this node serves it.
+    pub fn admin_addr(&self) -> Option<String> {
+        self.config.admin_addr.clone()
+    }
+
+    /// Bearer token the admin API requires, if any.
+    pub fn admin_token(&self) -> Option<String> {
+        self.config.admin_token.clone()
+    }
+
+    /// Bearer tokens of the gateway clients, mapped to their client names.
+    pub fn client_tokens(&self) -> BTreeMap<String, String> {
+        self.config.client_tokens.clone()
+    }
+
+    /// Bearer token federated clusters announce themselves with, if this node takes
+    /// announcements.
+    pub fn federation_token(&self) -> Option<String> {
+        self.config.federation_registry.token.clone()
+    }
+
+    /// Add a hook around proposals and applied entries, register hooks before starting the node.
+    pub fn add_hook<H: RaftHook + 'static>(&self, hook: H) {
+        self.hooks.write().unwrap().add(hook);
+    }
+
+    /// Reject malformed client commands before they are forwarded or enter the log.
+    pub fn add_validator<F>(&self, validator: F)
+    where
+        F: Fn(&MemoryStorageData) -> Result<(), String> + Send + Sync + 'static,
+    {
+        self.hooks.write().unwrap().add_validator(validator);
+    }
+
+    /// Mask sensitive fields of commands in debugged peer frames, the log inspection API and
+    /// logs, so verbose debugging is safe in production.
+    pub fn set_redactor<F>(&self, redactor: F)
+    where
+        F: Fn(&MemoryStorageData) -> MemoryStorageData + Send + Sync + 'static,
+    {
+        self.hooks.write().unwrap().set_redactor(redactor);
+    }
+
+    /// Let the log prefix be truncated through `/admin/truncate-log` of the admin listener once
+    /// the archiver copied it.
+    pub fn set_archiver<A: LogArchiver + 'static>(&self, archiver: A) {
+        self.hooks.write().unwrap().set_archiver(archiver);
+    }
+
+    /// Install snapshots of newer state machine versions through the migrator, see
+    /// `snapshot_version_policy`.
+    pub fn set_snapshot_migrator<M: SnapshotMigrator + 'static>(&self, migrator: M) {
+        self.hooks.write().unwrap().set_migrator(migrator);
+    }
+
+    /// Resolve peer host names through another resolver, e.g. one honoring DNS record TTLs.
+    pub fn set_resolver<R: Resolver + 'static>(&self, resolver: R) {
+        self.resolver.replace(resolver);
+    }
+
+    /// Replicate application state next to the hash ring, its commands are proposed as
+    /// `MemoryStorageData::App`. Set it before starting the node.
+    pub fn set_state_machine<S: AppStateMachine>(&self, state_machine: S) {
+        self.hooks.write().unwrap().set_state_machine(state_machine);
+    }
+
+    /// Apply every command to a new version of the state machine too, in the shadow of the one
+    /// set with `set_state_machine`, comparing their `digest`s every `compare_every` commands.
+    /// Divergence is logged, alerted and reported in the storage stats of `/admin/info`.
+    pub fn set_canary_state_machine<S: AppStateMachine>(&self, state_machine: S, compare_every: u64) {
+        self.hooks.write().unwrap().set_canary(state_machine, compare_every);
+    }
+
+    /// Maintain a read model from the applied entries, checkpointing its progress next to the
+    /// snapshots, see `projection`. Add projections before starting the node.
+    pub fn add_projection<P: Projection>(&self, projection: P) {
+        self.hooks.write().unwrap().add_projection(projection);
+    }
+
+    /// Route client frames carrying a routing key to the raft group owning it, see the JSON
+    /// client protocol. Keys routed to no group go to the cluster raft.
+    pub fn set_router<R: Router>(&self, router: R) {
+        *self.router.write().unwrap() = Some(Box::new(router));
+    }
+
+    #[cfg(feature = "arbitration")]
+    fn start_arbitration(id: NodeId, arbitration: &ArbitrationConfig, config: &ConfigSchema, net: Addr<Network>) {
+        if config.nodes.len() != 2 {
+            warn!("Arbitration is only used in two node clusters, ignoring it");
+            return;
+        }
+
+        if let Some(arbitration) = crate::arbitration::Arbitration::new(id, arbitration, net) {
+            arbitration.start();
         }
     }
 
+    #[cfg(not(feature = "arbitration"))]
+    fn start_arbitration(_id: NodeId, _arbitration: &ArbitrationConfig, _config: &ConfigSchema, _net: Addr<Network>) {
+        warn!("Arbitration configured but raftor was built without the `arbitration` feature");
+    }
+
+    #[cfg(target_os = "linux")]
+    fn use_boottime() {
+        clock::set_clock(Arc::new(clock::BootTimeClock::new()));
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn use_boottime() {
+        warn!("`clock = \"Boottime\"` is only supported on Linux, using the monotonic clock");
+    }
+
+    /// Check the lock on the data dir, stopping the process once another took it over.
+    fn renew_data_lock(&mut self) {
+        let fenced = match self.data_lock {
+            Some(ref mut lock) => match lock.renew() {
+                Ok(()) => return,
+                Err(holder) => holder,
+            },
+            None => return,
+        };
+
+        error!("Node {} was fenced off its data dir by process {} (epoch {})", self.id, fenced.pid, fenced.epoch);
+        self.events.alert(format!("node {} taken over by process {}", self.id, fenced.pid));
+        match self.exit {
+            Some(ref exit) => exit.exit(ExitReason::Fenced),
+            None => System::current().stop_with_code(ExitReason::Fenced.code()),
+        }
+    }
+
+    /// How long to wait before starting raft, following the `startup_jitter` config.
+    fn startup_delay(&self) -> Duration {
+        let jitter = match self.config.startup_jitter {
+            Some(ref jitter) => jitter,
+            None => return Duration::from_millis(0),
+        };
+
+        let position = self
+            .config
+            .nodes
+            .iter()
+            .position(|node| node.cluster_addr == self.info.cluster_addr)
+            .unwrap_or(self.config.nodes.len());
+
+        Duration::from_millis(jitter.stagger_ms * position as u64) + utils::jitter(Duration::from_millis(jitter.max_jitter_ms))
+    }
+
+    /// Wait for the leader a restarted follower heard from shortly before, see `fast_restart`.
+    fn hold_election(&self, formation: &Formation) -> Box<dyn Future<Item = (), Error = ()>> {
+        match formation {
+            Formation::Restart => Box::new(self.cluster_net.send(AwaitLeader).then(|_| Ok(()))),
+            _ => Box::new(future::ok(())),
+        }
+    }
+
+    /// How a node restarting from its data enters the cluster once it checked that the
+    /// membership it persisted still lists it, following `removed_member`. Raft isn't started
+    /// on `None`.
+    fn check_membership(&mut self, formation: Formation) -> Option<Formation> {
+        if formation != Formation::Restart || !self.role.is_member() {
+            return Some(formation);
+        }
+
+        let membership = match RaftBuilder::persisted_membership(&self.config) {
+            Some(membership) => membership,
+            None => return Some(formation),
+        };
+        if membership.members.contains(&self.id) || membership.non_voters.contains(&self.id) {
+            return Some(formation);
+        }
+
+        warn!("Node {} isn't among the members {:?} it persisted, it was removed while down", self.id, membership.members);
+        self.events.alert(format!("node {} restarted after it was removed from the cluster", self.id));
+
+        match self.config.removed_member {
+            RemovedMemberPolicy::Halt => {
+                error!("Node {} refuses to start raft, wipe its data dir or set `removed_member` to rejoin", self.id);
+                match self.exit {
+                    Some(ref exit) => exit.exit(ExitReason::Removed),
+                    None => System::current().stop_with_code(ExitReason::Removed.code()),
+                }
+                None
+            }
+            RemovedMemberPolicy::Rejoin => self.wipe_for_rejoin(),
+            RemovedMemberPolicy::Wait => {
+                info!("Node {} waits for POST /admin/rejoin on the admin listener before starting raft", self.id);
+                self.awaiting_rejoin = true;
+                None
+            }
+        }
+    }
+
+    /// Wipe the log and snapshots so the node joins as a new one, it stays out of the cluster
+    /// if they can't be removed.
+    fn wipe_for_rejoin(&mut self) -> Option<Formation> {
+        match RaftBuilder::wipe_data_dir(&self.config) {
+            Ok(()) => {
+                info!("Node {} wiped its data dir, joining the cluster again", self.id);
+                Some(Formation::Join)
+            }
+            Err(err) => {
+                error!("Node {} can't wipe its data dir: {}", self.id, err);
+                None
+            }
+        }
+    }
+
+    /// Start raft once the node knows how it enters the cluster, joining it first if needed.
+    fn start_raft(&mut self, nodes: Vec<NodeId>, formation: Formation, ctx: &mut Context<Self>) {
+        let join_mode = formation == Formation::Join;
+
+        let delay = self.startup_delay();
+        if delay > Duration::from_millis(0) {
+            info!("Node {} starts raft in {}ms", self.id, delay.as_millis());
+        }
+
+        fut::wrap_future::<_, Self>(self.hold_election(&formation))
+            .and_then(move |_, _, _| fut::wrap_future::<_, Self>(clock::delay(delay)).map_err(|_, _, _| ()))
+            .and_then(move |_, act, _| {
+                fut::wrap_future::<_, Self>(act.raft.send(InitRaft{ nodes, net: act.cluster_net.clone(), server: act.server.clone(), formation: formation }))
+                    .map_err(|err, _, _| panic!(err))
+            })
+            .and_then(move |_, act, ctx| {
+                let mut client = Client::default();
+                let cluster_nodes_route = format!("http://{}/cluster/join", act.discovery_host.as_str());
+
+                let state = if act.config.single_node && formation == Formation::Bootstrap {
+                    NetworkState::SingleNode
+                } else {
+                    NetworkState::Cluster
+                };
+                act.app_net.do_send(SetClusterState(state.clone()));
+                act.cluster_net.do_send(SetClusterState(state));
+
+                if join_mode && act.role.is_member() {
+                    fut::wrap_future::<_, Self>(client.put(cluster_nodes_route)
+                                                .header("Content-Type", "application/json")
+                                                .send_json(&act.id))
+                        .map_err(|err, _, _| println!("Error joining cluster {:?}", err))
+                        .and_then(|res, act, ctx| {
+                            match res.status().as_u16() {
+                                202 => info!("Join of node {} awaits operator approval", act.id),
+                                403 => error!("Node {} was refused by the join admission policy", act.id),
+                                _ => (),
+                            }
+
+                            fut::wrap_future::<_, Self>(clock::delay(Duration::from_secs(1)))
+                                .map_err(|_, _, _| ())
+                                .and_then(|_, act, ctx| {
+//                                            act.raft.do_send(AddNode(act.id));
+                                    fut::ok(())
+                                })
+                        }).spawn(ctx);
+                }
+
+                fut::ok(())
+            })
+            .spawn(ctx);
+    }
+
     fn add_node_to_config(node: NodeInfo, config: &mut ConfigSchema) {
-        let index = config.nodes.iter().position(|r| r == &node);
+        let index = config.nodes.iter().position(|r| r.cluster_addr == node.cluster_addr);
 
         if index.is_none() {
             config.nodes.push(node);
@@ -113,42 +621,175 @@ impl Actor for Raftor {
     type Context = Context<Self>;
 
     fn started(&mut self, ctx: &mut Context<Self>) {
+        if let Some(interval) = self.stall.as_ref().map(|detector| detector.interval()) {
+            ctx.run_interval(interval, |act, ctx| act.check_write_stall(ctx));
+        }
+        if let Some(interval) = self.promotion.as_ref().map(|tracker| tracker.interval()) {
+            ctx.run_interval(interval, |act, ctx| act.check_learners(ctx));
+        }
+        if let Some(retention) = self.config.tombstone_retention.clone() {
+            let interval = Duration::from_millis(retention.interval_ms);
+            ctx.run_interval(interval, move |act, ctx| act.purge_tombstones(ctx, retention.clone()));
+        }
+        if self.data_lock.is_some() {
+            let interval = Duration::from_millis(self.config.warm_standby.fence_check_ms);
+            ctx.run_interval(interval, |act, _| act.renew_data_lock());
+        }
+
         fut::wrap_future::<_, Self>(self.cluster_net.send(DiscoverNodes))
             .map_err(|err, _, _| panic!(err))
             .and_then(|res, act, ctx| {
-                let res = res.unwrap();
-                let nodes = res.0;
-                let join_mode = res.1;
-
-                fut::wrap_future::<_, Self>(act.raft.send(InitRaft{ nodes, net: act.cluster_net.clone(), server: act.server.clone(),  join_mode: join_mode }))
-                    .map_err(|err, _, _| panic!(err))
-                    .and_then(move |_, act, ctx| {
-                        let mut client = Client::default();
-                        let cluster_nodes_route = format!("http://{}/cluster/join", act.discovery_host.as_str());
-
-                        act.app_net.do_send(SetClusterState(NetworkState::Cluster));
-                        act.cluster_net.do_send(SetClusterState(NetworkState::Cluster));
-
-                        if join_mode {
-                            fut::wrap_future::<_, Self>(client.put(cluster_nodes_route)
-                                                        .header("Content-Type", "application/json")
-                                                        .send_json(&act.id))
-                                .map_err(|err, _, _| println!("Error joining cluster {:?}", err))
-                                .and_then(|res, act, ctx| {
-                                    fut::wrap_future::<_, Self>(Delay::new(Instant::now() + Duration::from_secs(1)))
-                                        .map_err(|_, _, _| ())
-                                        .and_then(|_, act, ctx| {
-//                                            act.raft.do_send(AddNode(act.id));
-                                            fut::ok(())
-                                        })
-                                }).spawn(ctx);
-                        }
-
-                        fut::ok(())
-                    })
+                let (nodes, formation) = res.unwrap();
+                if let Some(formation) = act.check_membership(formation) {
+                    act.start_raft(nodes, formation, ctx);
+                }
+                fut::ok(())
             })
             .spawn(ctx);
 
         self.register_handlers();
     }
 }
+
+/// Abruptly stop every actor of this node, as if the process died.
+#[derive(Message)]
+pub struct Kill;
+
+impl Handler<Kill> for Raftor {
+    type Result = ();
+
+    fn handle(&mut self, _: Kill, ctx: &mut Context<Self>) {
+        println!("Killing node {}", self.id);
+
+        for arbiter in self.arbiters.iter() {
+            arbiter.stop();
+        }
+        if self.arbiters.is_empty() {
+            self.cluster_net.do_send(Halt);
+            self.app_net.do_send(Halt);
+        }
+
+        ctx.stop();
+    }
+}
+
+/// Let a node waiting after it was removed from the cluster wipe its data dir and join the
+/// cluster again, see `RemovedMemberPolicy::Wait`. Fails on other nodes.
+pub struct Rejoin;
+
+impl Message for Rejoin {
+    type Result = Result<(), ()>;
+}
+
+impl Handler<Rejoin> for Raftor {
+    type Result = Result<(), ()>;
+
+    fn handle(&mut self, _: Rejoin, ctx: &mut Context<Self>) -> Self::Result {
+        if !self.awaiting_rejoin {
+            return Err(());
+        }
+
+        let formation = self.wipe_for_rejoin().ok_or(())?;
+        self.awaiting_rejoin = false;
+        self.start_raft(Vec::new(), formation, ctx);
+        Ok(())
+    }
+}
+
+/// Self description of a node for dashboards and support bundles.
+#[derive(Serialize, Debug, Clone)]
+pub struct NodeDetails {
+    pub id: NodeId,
+    pub cluster_addr: String,
+    pub app_addr: String,
+    pub public_addr: String,
+    pub role: NodeRole,
+    pub uptime_secs: u64,
+    pub version: String,
+    pub features: Vec<&'static str>,
+    /// `None` until raft is initialized, and on gateways
+    pub storage: Option<StorageStats>,
+    /// Raft wasn't started because the node was removed from the cluster while down
+    pub awaiting_rejoin: bool,
+}
+
+pub struct GetNodeInfo;
+
+impl Message for GetNodeInfo {
+    type Result = Result<NodeDetails, ()>;
+}
+
+impl Handler<GetNodeInfo> for Raftor {
+    type Result = ResponseActFuture<Self, NodeDetails, ()>;
+
+    fn handle(&mut self, _: GetNodeInfo, _ctx: &mut Context<Self>) -> Self::Result {
+        self.node_details()
+    }
+}
+
+impl Raftor {
+    fn node_details(&self) -> ResponseActFuture<Self, NodeDetails, ()> {
+        let mut features = Vec::new();
+        if cfg!(feature = "otel") {
+            features.push("otel");
+        }
+        if cfg!(feature = "arbitration") {
+            features.push("arbitration");
+        }
+        if cfg!(feature = "tls") {
+            features.push("tls");
+        }
+
+        let details = NodeDetails {
+            id: self.id,
+            cluster_addr: self.info.cluster_addr.clone(),
+            app_addr: self.info.app_addr.clone(),
+            public_addr: self.info.public_addr.clone(),
+            role: self.role.clone(),
+            uptime_secs: clock::now().duration_since(self.started_at).as_secs(),
+            version: VERSION.to_owned(),
+            features: features,
+            storage: None,
+            awaiting_rejoin: self.awaiting_rejoin,
+        };
+
+        Box::new(
+            fut::wrap_future::<_, Self>(self.raft.send(GetStorageInfo))
+                .then(move |res, _, _| {
+                    let mut details = details;
+                    details.storage = res.ok().and_then(|res| res.ok());
+                    fut::ok(details)
+                }),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listed_nodes_keep_their_id_and_labels() {
+        let mut labels = std::collections::BTreeMap::new();
+        labels.insert("zone".to_owned(), "a".to_owned());
+        let listed = NodeInfo {
+            cluster_addr: "10.0.0.1:8000".to_owned(),
+            app_addr: "10.0.0.1:8001".to_owned(),
+            public_addr: "10.0.0.1:8002".to_owned(),
+            id: Some(7),
+            labels: labels.clone(),
+            cluster: None,
+        };
+        let mut config = ConfigSchema::new(String::new(), vec![listed.clone()]);
+
+        let info = Raftor::node_info(&config, "10.0.0.1:8000", "0.0.0.0:8001", "0.0.0.0:8002");
+        assert_eq!((info.id, &info.labels, info.app_addr.as_str()), (Some(7), &labels, "0.0.0.0:8001"));
+
+        let info = Raftor::node_info(&config, "10.0.0.2:8000", "", "");
+        assert_eq!((info.id, info.labels.len()), (None, 0));
+
+        Raftor::add_node_to_config(listed, &mut config);
+        Raftor::add_node_to_config(info, &mut config);
+        assert_eq!(config.nodes.len(), 2);
+    }
+}
@@ -0,0 +1,300 @@
+use actix::prelude::*;
+use actix_raft::NodeId;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::{Duration, Instant};
+
+use crate::clock;
+use crate::config::LearnerPromotionConfig;
+use crate::network::{ClusterStatus, GetClusterStatus};
+use crate::raft::storage::MemoryStorageData;
+use crate::raft::ClientProposal;
+use crate::raftor::Raftor;
+
+/// Members the leader saw join, held cordoned until their lag stays under the SLO.
+pub(crate) struct PromotionTracker {
+    max_lag: u64,
+    sustain: Duration,
+    interval: Duration,
+    /// Raft members as of the last check, `None` while not leading
+    members: Option<BTreeSet<NodeId>>,
+    learners: BTreeMap<NodeId, Learner>,
+}
+
+struct Learner {
+    lag: Option<u64>,
+    /// Since when the lag is under the SLO
+    caught_up_since: Option<Instant>,
+    regressions: u64,
+}
+
+/// A member waiting for its promotion on the leader.
+#[derive(Serialize, Debug, Clone)]
+pub struct LearnerStatus {
+    pub node: NodeId,
+    /// Entries behind the leader's log, `None` until the leader replicated to it
+    pub lag: Option<u64>,
+    /// Milliseconds the lag has been under the SLO
+    pub caught_up_ms: Option<u64>,
+    /// Times the lag went back over the SLO before the promotion
+    pub regressions: u64,
+}
+
+enum Step {
+    Hold(NodeId),
+    Promote(NodeId),
+    Regressed(NodeId, Option<u64>),
+}
+
+impl PromotionTracker {
+    pub(crate) fn new(config: &LearnerPromotionConfig) -> PromotionTracker {
+        PromotionTracker {
+            max_lag: config.max_lag,
+            sustain: Duration::from_millis(config.sustain_ms),
+            interval: Duration::from_millis(config.interval_ms),
+            members: None,
+            learners: BTreeMap::new(),
+        }
+    }
+
+    pub(crate) fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    pub(crate) fn learners(&self) -> Vec<LearnerStatus> {
+        self.learners
+            .iter()
+            .map(|(id, learner)| LearnerStatus {
+                node: *id,
+                lag: learner.lag,
+                caught_up_ms: learner.caught_up_since.map(|since| clock::elapsed(since).as_millis() as u64),
+                regressions: learner.regressions,
+            })
+            .collect()
+    }
+
+    /// Update with the cluster as seen from this node, returns what to do about the learners.
+    fn observe(&mut self, status: &ClusterStatus) -> Vec<Step> {
+        let raft = match status.raft {
+            Some(ref raft) if raft.leader == Some(status.node) => raft,
+            _ => {
+                // the next leader starts over with the members it finds
+                self.members = None;
+                self.learners.clear();
+                return Vec::new();
+            }
+        };
+
+        let current = status
+            .nodes
+            .iter()
+            .filter(|member| member.role != "peer" && !member.stale)
+            .map(|member| member.id)
+            .collect::<BTreeSet<_>>();
+
+        let mut steps = Vec::new();
+        if let Some(ref previous) = self.members {
+            for id in current.difference(previous) {
+                self.learners.insert(
+                    *id,
+                    Learner {
+                        lag: None,
+                        caught_up_since: None,
+                        regressions: 0,
+                    },
+                );
+                steps.push(Step::Hold(*id));
+            }
+        }
+        self.learners.retain(|id, _| current.contains(id));
+        self.members = Some(current);
+
+        for member in status.nodes.iter() {
+            let learner = match self.learners.get_mut(&member.id) {
+                Some(learner) => learner,
+                None => continue,
+            };
+            learner.lag = member.matched_index.map(|matched| raft.last_log_index.saturating_sub(matched));
+
+            match learner.lag {
+                Some(lag) if lag <= self.max_lag => {
+                    let since = *learner.caught_up_since.get_or_insert_with(clock::now);
+                    if clock::elapsed(since) >= self.sustain {
+                        steps.push(Step::Promote(member.id));
+                    }
+                }
+                lag => {
+                    if learner.caught_up_since.take().is_some() {
+                        learner.regressions += 1;
+                        steps.push(Step::Regressed(member.id, lag));
+                    }
+                }
+            }
+        }
+
+        for step in steps.iter() {
+            if let Step::Promote(id) = step {
+                self.learners.remove(id);
+            }
+        }
+        steps
+    }
+}
+
+impl Raftor {
+    /// Cordon members which just joined and uncordon the learners whose lag stayed under the
+    /// SLO long enough.
+    pub(crate) fn check_learners(&mut self, ctx: &mut Context<Self>) {
+        fut::wrap_future::<_, Self>(self.cluster_net.send(GetClusterStatus))
+            .map_err(|_, _, _| ())
+            .and_then(|status, act: &mut Self, _| {
+                let steps = match (status, act.promotion.as_mut()) {
+                    (Ok(status), Some(tracker)) => tracker.observe(&status),
+                    _ => return fut::ok(()),
+                };
+
+                for step in steps {
+                    match step {
+                        Step::Hold(id) => {
+                            info!("Node {} joined, holding it as a learner until it keeps up", id);
+                            act.propose_cordon(id, true);
+                        }
+                        Step::Promote(id) => {
+                            info!("Learner {} kept up with the log, promoting it", id);
+                            act.propose_cordon(id, false);
+                        }
+                        Step::Regressed(id, lag) => {
+                            let lag = lag.map(|lag| lag.to_string()).unwrap_or_else(|| "unknown".to_owned());
+                            warn!("Learner {} fell behind again, lag {}", id, lag);
+                            act.events.alert(format!("learner {} fell behind before its promotion, lag {}", id, lag));
+                        }
+                    }
+                }
+
+                fut::ok(())
+            })
+            .spawn(ctx);
+    }
+
+    fn propose_cordon(&self, node: NodeId, cordoned: bool) {
+        let proposal = ClientProposal {
+            client: "learner-promotion".to_owned(),
+            key: None,
+            data: MemoryStorageData::Cordon {
+                node: node,
+                cordoned: cordoned,
+            },
+            deadline_ms: None,
+        };
+
+        let raft = self.raft.clone();
+        Arbiter::spawn(raft.send(proposal).then(move |res| {
+            match res {
+                Ok(Ok(_)) => (),
+                _ => error!("Error proposing to {} learner {}", if cordoned { "hold" } else { "promote" }, node),
+            }
+            Ok(())
+        }));
+    }
+}
+
+/// Members held as learners by this node, only the leader holds any.
+pub struct GetLearners;
+
+impl Message for GetLearners {
+    type Result = Vec<LearnerStatus>;
+}
+
+impl Handler<GetLearners> for Raftor {
+    type Result = MessageResult<GetLearners>;
+
+    fn handle(&mut self, _: GetLearners, _: &mut Context<Self>) -> Self::Result {
+        MessageResult(self.promotion.as_ref().map(|tracker| tracker.learners()).unwrap_or_default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::{MemberStatus, RaftState};
+
+    fn tracker(sustain_ms: u64) -> PromotionTracker {
+        PromotionTracker::new(&LearnerPromotionConfig {
+            max_lag: 10,
+            sustain_ms: sustain_ms,
+            interval_ms: 1000,
+        })
+    }
+
+    /// The cluster as seen from node 1, leading when `leader`, with node 3 `lag` entries
+    /// behind when it's a member.
+    fn status(leader: bool, lag: Option<u64>) -> ClusterStatus {
+        let member = |id: NodeId, matched: Option<u64>| MemberStatus {
+            id: id,
+            cluster_addr: None,
+            role: "voter".to_owned(),
+            status: None,
+            last_contact_ms: None,
+            log: None,
+            matched_index: matched,
+            cordoned: false,
+            stale: false,
+            link: None,
+        };
+        let mut nodes = vec![member(1, None), member(2, Some(100))];
+        if let Some(lag) = lag {
+            nodes.push(member(3, Some(100 - lag)));
+        }
+
+        ClusterStatus {
+            node: 1,
+            raft: Some(RaftState {
+                state: "Leader".to_owned(),
+                term: 1,
+                last_log_index: 100,
+                last_applied: 100,
+                leader: if leader { Some(1) } else { Some(2) },
+                members: nodes.iter().map(|member| member.id).collect(),
+                non_voters: Vec::new(),
+            }),
+            nodes: nodes,
+        }
+    }
+
+    fn steps(steps: Vec<Step>) -> Vec<String> {
+        steps
+            .into_iter()
+            .map(|step| match step {
+                Step::Hold(id) => format!("hold {}", id),
+                Step::Promote(id) => format!("promote {}", id),
+                Step::Regressed(id, lag) => format!("regressed {} {:?}", id, lag),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn joined_members_are_held_until_they_keep_up() {
+        let mut tracker = tracker(0);
+        assert!(steps(tracker.observe(&status(true, None))).is_empty());
+
+        assert_eq!(steps(tracker.observe(&status(true, Some(50)))), vec!["hold 3"]);
+        assert_eq!(tracker.learners()[0].lag, Some(50));
+        assert_eq!(steps(tracker.observe(&status(true, Some(5)))), vec!["promote 3"]);
+        assert!(tracker.learners().is_empty());
+    }
+
+    #[test]
+    fn learners_falling_behind_are_reported() {
+        let mut tracker = tracker(3_600_000);
+        tracker.observe(&status(true, None));
+        tracker.observe(&status(true, Some(50)));
+
+        assert!(steps(tracker.observe(&status(true, Some(5)))).is_empty());
+        assert_eq!(steps(tracker.observe(&status(true, Some(50)))), vec!["regressed 3 Some(50)"]);
+        assert_eq!(tracker.learners()[0].regressions, 1);
+
+        // another leader starts over
+        tracker.observe(&status(false, Some(5)));
+        assert!(tracker.learners().is_empty());
+    }
+}
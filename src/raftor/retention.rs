@@ -0,0 +1,55 @@
+use actix::prelude::*;
+
+use crate::config::TombstoneRetentionConfig;
+use crate::network::GetClusterStatus;
+use crate::raft::storage::MemoryStorageData;
+use crate::raft::timing::now_millis;
+use crate::raft::{ClientProposal, PurgeDue};
+use crate::raftor::Raftor;
+
+impl Raftor {
+    /// Propose a purge of the debris older than the retention window, on the leader only and
+    /// when it applied some. Every node purges the same entries as it applies the proposal.
+    pub(crate) fn purge_tombstones(&mut self, ctx: &mut Context<Self>, retention: TombstoneRetentionConfig) {
+        fut::wrap_future::<_, Self>(self.cluster_net.send(GetClusterStatus))
+            .map_err(|_, _, _| ())
+            .and_then(move |status, act: &mut Self, _| {
+                let leading = match status {
+                    Ok(ref status) => status.raft.as_ref().map_or(false, |raft| raft.leader == Some(status.node)),
+                    Err(_) => false,
+                };
+                if !leading {
+                    return fut::Either::A(fut::ok(()));
+                }
+
+                let before = now_millis().saturating_sub(retention.retention_ms);
+                fut::Either::B(
+                    fut::wrap_future::<_, Self>(act.raft.send(PurgeDue(before)))
+                        .map_err(|_, _, _| ())
+                        .map(move |due, act: &mut Self, _| {
+                            if due == Ok(true) {
+                                act.propose_purge(before);
+                            }
+                        }),
+                )
+            })
+            .spawn(ctx);
+    }
+
+    fn propose_purge(&self, before: u64) {
+        let proposal = ClientProposal {
+            client: "tombstone-retention".to_owned(),
+            key: None,
+            data: MemoryStorageData::Purge { before: before },
+            deadline_ms: None,
+        };
+
+        Arbiter::spawn(self.raft.send(proposal).then(move |res| {
+            match res {
+                Ok(Ok(_)) => (),
+                _ => error!("Error proposing to purge the debris stamped before {}", before),
+            }
+            Ok(())
+        }));
+    }
+}
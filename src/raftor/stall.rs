@@ -0,0 +1,140 @@
+use actix::prelude::*;
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use crate::clock;
+use crate::config::WriteStallConfig;
+use crate::network::GetRaftMetrics;
+use crate::raft::GetPendingProposals;
+use crate::raft::timing::now_millis;
+use crate::raftor::{CreateSupportBundle, Raftor};
+
+/// Tracks whether the applied index advances while proposals are pending.
+pub(crate) struct StallDetector {
+    threshold: Duration,
+    diagnostics_dir: PathBuf,
+    last_applied: u64,
+    /// Since when proposals are pending without the applied index moving
+    stalled_since: Option<Instant>,
+    /// Whether the current stall was already reported
+    reported: bool,
+}
+
+impl StallDetector {
+    pub(crate) fn new(config: &WriteStallConfig) -> StallDetector {
+        StallDetector {
+            threshold: Duration::from_secs(config.threshold_secs),
+            diagnostics_dir: PathBuf::from(config.diagnostics_dir.clone()),
+            last_applied: 0,
+            stalled_since: None,
+            reported: false,
+        }
+    }
+
+    /// How often the detector looks at the progress of the node.
+    pub(crate) fn interval(&self) -> Duration {
+        std::cmp::max(self.threshold / 4, Duration::from_secs(1))
+    }
+
+    /// Update with the applied index and pending proposals, returns how long writes have been
+    /// stalled when a new stall crossed the threshold.
+    fn observe(&mut self, applied: u64, pending: usize) -> Option<Duration> {
+        if applied != self.last_applied || pending == 0 {
+            if self.reported {
+                info!("Writes are applied again, at index {}", applied);
+            }
+            self.last_applied = applied;
+            self.stalled_since = None;
+            self.reported = false;
+            return None;
+        }
+
+        let stalled = clock::elapsed(*self.stalled_since.get_or_insert_with(clock::now));
+        if stalled < self.threshold || self.reported {
+            return None;
+        }
+
+        self.reported = true;
+        Some(stalled)
+    }
+}
+
+impl Raftor {
+    /// Check for a write stall, capturing a diagnostics bundle when one is detected.
+    pub(crate) fn check_write_stall(&mut self, ctx: &mut Context<Self>) {
+        let progress = self
+            .cluster_net
+            .send(GetRaftMetrics)
+            .join(self.raft.send(GetPendingProposals));
+
+        fut::wrap_future::<_, Self>(progress)
+            .map_err(|_, _, _| ())
+            .and_then(|(metrics, pending), act: &mut Self, ctx| {
+                // gateways and nodes without raft have nothing to apply
+                let (metrics, pending) = match (metrics, pending) {
+                    (Ok(Some(metrics)), Ok(pending)) => (metrics, pending),
+                    _ => return fut::ok(()),
+                };
+
+                let stall = match act.stall {
+                    Some(ref mut detector) => detector
+                        .observe(metrics.last_applied, pending.count)
+                        .map(|stalled| (stalled, detector.diagnostics_dir.clone())),
+                    None => None,
+                };
+
+                if let Some((stalled, dir)) = stall {
+                    let message = format!(
+                        "writes stalled for {:?}: applied index {} with {} pending proposals ({} in flight, oldest {}ms), leader {:?}",
+                        stalled, metrics.last_applied, pending.count, pending.in_flight, pending.oldest_age_ms, metrics.current_leader
+                    );
+                    error!("CRITICAL: node {} {}", act.id, message);
+
+                    let path = dir.join(format!("stall-{}-{}.tar", act.id, now_millis()));
+                    act.events.alert(format!("{}, diagnostics in {}", message, path.to_string_lossy()));
+
+                    if let Err(err) = fs::create_dir_all(&dir) {
+                        error!("Error creating diagnostics dir {:?}: {}", dir, err);
+                    } else {
+                        ctx.notify(CreateSupportBundle(path));
+                    }
+                }
+
+                fut::ok(())
+            })
+            .spawn(ctx);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn detector() -> StallDetector {
+        StallDetector::new(&WriteStallConfig {
+            threshold_secs: 0,
+            diagnostics_dir: "diagnostics".to_owned(),
+        })
+    }
+
+    #[test]
+    fn a_stall_is_reported_once() {
+        let mut detector = detector();
+        assert_eq!(detector.observe(5, 2), None);
+        assert!(detector.observe(5, 2).is_some());
+        assert_eq!(detector.observe(5, 2), None);
+
+        // writes applied again end the stall, the next one is reported anew
+        assert_eq!(detector.observe(6, 2), None);
+        assert!(detector.observe(6, 2).is_some());
+    }
+
+    #[test]
+    fn an_idle_node_isnt_stalled() {
+        let mut detector = detector();
+        detector.observe(5, 0);
+        assert_eq!(detector.observe(5, 0), None);
+        assert_eq!(detector.interval(), Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,159 @@
+//! Process runtime: ties the lifecycle of the networks of a node to the actix `System`, so a
+//! node whose network died exits with a code telling its supervisor why, instead of
+//! lingering half-dead.
+
+use actix::{System, SystemRunner};
+use std::panic;
+use std::sync::{Arc, Mutex};
+
+use crate::clock;
+
+/// Why the process exits.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExitReason {
+    /// The networks were stopped through `Shutdown`, e.g. by `/admin/shutdown`
+    Shutdown,
+    /// A network stopped without being shut down
+    NetworkStopped,
+    /// A network couldn't listen on its address
+    ListenFailed,
+    /// A thread of the node panicked, leaving its actors dead
+    Panicked,
+    /// A standby took the data dir of the node over
+    Fenced,
+    /// The node restarted after it was removed from the cluster, see `RemovedMemberPolicy`
+    Removed,
+    /// The persisted raft log couldn't be recovered, e.g. it's corrupt
+    StorageFailed,
+}
+
+impl ExitReason {
+    /// Exit code of the process, following `sysexits.h` where it has a fitting code.
+    pub fn code(&self) -> i32 {
+        match self {
+            ExitReason::Shutdown => 0,
+            ExitReason::NetworkStopped => 70,
+            ExitReason::ListenFailed => 71,
+            ExitReason::Panicked => 101,
+            ExitReason::Fenced => 75,
+            ExitReason::Removed => 78,
+            ExitReason::StorageFailed => 74,
+        }
+    }
+
+    fn is_fatal(&self) -> bool {
+        *self != ExitReason::Shutdown
+    }
+}
+
+/// Records why the node stops, shared with the networks of the node.
+#[derive(Clone)]
+pub struct ExitHandle {
+    system: System,
+    reason: Arc<Mutex<Option<ExitReason>>>,
+}
+
+impl ExitHandle {
+    /// Record why the node stops, the first reason wins. Fatal reasons stop the system right
+    /// away, a shutdown leaves stopping it to whoever shut the node down.
+    pub fn exit(&self, reason: ExitReason) {
+        let mut current = self.reason.lock().unwrap_or_else(|err| err.into_inner());
+        if current.is_some() {
+            return;
+        }
+        *current = Some(reason);
+
+        if reason.is_fatal() {
+            error!("Stopping the process, {:?}, exit code {}", reason, reason.code());
+            self.system.stop_with_code(reason.code());
+        }
+    }
+
+    /// The reason recorded first, if any.
+    pub fn reason(&self) -> Option<ExitReason> {
+        *self.reason.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+/// The actix system of a node, exiting with the code of the reason it stopped for.
+pub struct Runtime {
+    runner: SystemRunner,
+    exit: ExitHandle,
+}
+
+impl Runtime {
+    /// Create the system, timed by the installed clock, and stop it once any thread panics.
+    pub fn new(name: &str) -> Runtime {
+        let runner = System::builder().name(name.to_owned()).clock(clock::timer_clock()).build();
+        let exit = ExitHandle {
+            system: System::current(),
+            reason: Arc::new(Mutex::new(None)),
+        };
+
+        // a panicking arbiter takes its actors down, the others would carry on without them
+        let panicked = exit.clone();
+        let next = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            next(info);
+            panicked.exit(ExitReason::Panicked);
+        }));
+
+        Runtime {
+            runner: runner,
+            exit: exit,
+        }
+    }
+
+    pub fn exit_handle(&self) -> ExitHandle {
+        self.exit.clone()
+    }
+
+    /// Run the system until it stops, returns the exit code of the process.
+    pub fn run(self) -> i32 {
+        let res = self.runner.run();
+        crate::telemetry::shutdown();
+
+        match self.exit.reason() {
+            Some(reason) => reason.code(),
+            None if res.is_ok() => 0,
+            None => 1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_first_reason_wins() {
+        let _sys = System::new("exit");
+        let exit = ExitHandle {
+            system: System::current(),
+            reason: Arc::new(Mutex::new(None)),
+        };
+        assert_eq!(exit.reason(), None);
+
+        exit.exit(ExitReason::Shutdown);
+        exit.exit(ExitReason::NetworkStopped);
+        assert_eq!(exit.reason(), Some(ExitReason::Shutdown));
+        assert_eq!(exit.reason().unwrap().code(), 0);
+    }
+
+    #[test]
+    fn only_a_shutdown_exits_successfully() {
+        let reasons = [
+            ExitReason::NetworkStopped,
+            ExitReason::ListenFailed,
+            ExitReason::Panicked,
+            ExitReason::Fenced,
+            ExitReason::Removed,
+            ExitReason::StorageFailed,
+        ];
+        for reason in reasons.iter() {
+            assert!(reason.is_fatal());
+            assert_ne!(reason.code(), 0);
+        }
+        assert!(!ExitReason::Shutdown.is_fatal());
+    }
+}
@@ -0,0 +1,312 @@
+use actix::prelude::*;
+use actix_raft::{
+    messages::ClientPayloadResponse,
+    NodeId,
+};
+use futures::future::{join_all, Future};
+use std::net::TcpListener;
+use std::time::{Duration, Instant};
+
+use crate::config::{ConfigSchema, NodeInfo};
+use crate::network::{GetRaftMetrics, Network};
+use crate::raft::{storage::{MemoryStorageData, MemoryStorageResponse}, ClientProposal, IsRingMember, RaftClient};
+use crate::raftor::{Kill, Raftor};
+use crate::utils;
+
+/// How often the self test polls the nodes
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+/// How long every stage may take before it is considered failed
+const STAGE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Id written to (and removed from) the hash ring as test entry
+const TEST_ENTRY_ID: NodeId = 0;
+
+pub(crate) struct TestNode {
+    pub(crate) id: NodeId,
+    pub(crate) raftor: Addr<Raftor>,
+    pub(crate) raft: Addr<RaftClient>,
+    pub(crate) net: Addr<Network>,
+    pub(crate) alive: bool,
+}
+
+/// Base port of a local cluster whose cluster, app and public ports are all free right now,
+/// around one the OS hands out.
+pub fn free_base_port() -> Option<u16> {
+    (0..16).find_map(|_| {
+        let base = TcpListener::bind("127.0.0.1:0").ok()?.local_addr().ok()?.port();
+        let ports = [0, 100, 200]
+            .iter()
+            .flat_map(|offset| (0..3).map(move |i| offset + i))
+            .map(|offset| base.checked_add(offset))
+            .collect::<Option<Vec<_>>>()?;
+        // the listeners are held until all ports were tried, then released for the nodes
+        let listeners = ports
+            .iter()
+            .map(|port| TcpListener::bind(("127.0.0.1", *port)).ok())
+            .collect::<Option<Vec<_>>>()?;
+        drop(listeners);
+        Some(base)
+    })
+}
+
+/// Start a local three node cluster on consecutive ports from `base_port`.
+pub(crate) fn local_cluster(base_port: u16) -> Vec<TestNode> {
+    let infos = (0..3)
+        .map(|i| NodeInfo {
+            cluster_addr: format!("127.0.0.1:{}", base_port + i),
+            app_addr: format!("127.0.0.1:{}", base_port + 100 + i),
+            public_addr: format!("127.0.0.1:{}", base_port + 200 + i),
+            id: None,
+            labels: Default::default(),
+            cluster: None,
+        })
+        .collect::<Vec<_>>();
+
+    let config = ConfigSchema::new(infos[0].public_addr.clone(), infos.clone());
+
+    infos
+        .into_iter()
+        .map(|info| {
+            let id = utils::node_id(&info);
+            let raftor = Raftor::create(config.clone(), info);
+            let raft = raftor.raft.clone();
+            let net = raftor.cluster_net.clone();
+
+            TestNode {
+                id: id,
+                raftor: raftor.start(),
+                raft: raft,
+                net: net,
+                alive: true,
+            }
+        })
+        .collect()
+}
+
+enum Stage {
+    Election,
+    Write(NodeId),
+    Replicate(u64),
+    Failover(NodeId),
+    Done,
+}
+
+/// Forms a local three node cluster and exercises election, replication and failover.
+pub struct SelfTest {
+    nodes: Vec<TestNode>,
+    stage: Stage,
+    stage_started: Instant,
+    busy: bool,
+    report: Vec<(&'static str, bool, Duration)>,
+}
+
+impl SelfTest {
+    pub fn new(base_port: u16) -> SelfTest {
+        let nodes = local_cluster(base_port);
+
+        SelfTest {
+            nodes: nodes,
+            stage: Stage::Election,
+            stage_started: Instant::now(),
+            busy: false,
+            report: Vec::new(),
+        }
+    }
+
+    fn record(&mut self, name: &'static str, passed: bool, next: Stage) {
+        let elapsed = Instant::now().duration_since(self.stage_started);
+        println!("[selftest] {:<24} {} ({:?})", name, if passed { "PASS" } else { "FAIL" }, elapsed);
+
+        self.report.push((name, passed, elapsed));
+        self.stage = if passed { next } else { Stage::Done };
+        self.stage_started = Instant::now();
+    }
+
+    fn stage_name(&self) -> &'static str {
+        match self.stage {
+            Stage::Election => "leader election",
+            Stage::Write(_) => "write and read test entries",
+            Stage::Replicate(_) => "replicate test entries",
+            Stage::Failover(_) => "leader failover",
+            Stage::Done => "done",
+        }
+    }
+
+    fn tick(&mut self, ctx: &mut Context<Self>) {
+        if self.busy {
+            return;
+        }
+
+        if let Stage::Done = self.stage {
+            return self.finish(ctx);
+        }
+
+        if Instant::now().duration_since(self.stage_started) > STAGE_TIMEOUT {
+            let name = self.stage_name();
+            self.record(name, false, Stage::Done);
+            return self.finish(ctx);
+        }
+
+        self.busy = true;
+
+        if let Stage::Write(leader) = self.stage {
+            return self.write(leader, ctx);
+        }
+
+        let requests = self
+            .nodes
+            .iter()
+            .filter(|node| node.alive)
+            .map(|node| node.net.send(GetRaftMetrics))
+            .collect::<Vec<_>>();
+
+        fut::wrap_future::<_, Self>(join_all(requests))
+            .then(|res, act, ctx| {
+                act.busy = false;
+
+                let metrics = match res {
+                    Ok(metrics) => metrics
+                        .into_iter()
+                        .filter_map(|m| m.ok().and_then(|m| m))
+                        .collect::<Vec<_>>(),
+                    Err(_) => return fut::ok(()),
+                };
+
+                let alive = act.nodes.iter().filter(|node| node.alive).count();
+                let leader = metrics.first().and_then(|m| m.current_leader);
+                let agreed = metrics.len() == alive
+                    && metrics.iter().all(|m| m.current_leader.is_some() && m.current_leader == leader);
+
+                match act.stage {
+                    Stage::Election if agreed => {
+                        act.record("leader election", true, Stage::Write(leader.unwrap()));
+                    }
+                    Stage::Replicate(index) if agreed => {
+                        if metrics.iter().all(|m| m.last_applied >= index) {
+                            act.busy = true;
+                            act.read_replicas(leader.unwrap(), ctx);
+                        }
+                    }
+                    Stage::Failover(old) if agreed && leader != Some(old) => {
+                        act.record("leader failover", true, Stage::Done);
+                    }
+                    _ => (),
+                }
+
+                fut::ok(())
+            })
+            .spawn(ctx);
+    }
+
+    fn write(&mut self, leader: NodeId, ctx: &mut Context<Self>) {
+        let raft = match self.nodes.iter().find(|node| node.id == leader) {
+            Some(node) => node.raft.clone(),
+            None => {
+                self.busy = false;
+                return self.record("write test entries", false, Stage::Done);
+            }
+        };
+
+        let proposal = |data| ClientProposal {
+            client: "selftest".to_owned(),
+            key: None,
+            data: data,
+            deadline_ms: None,
+        };
+
+        // the leader applied the add once it answers, it must read it back before the remove
+        let written = raft
+            .send(proposal(MemoryStorageData::Add(TEST_ENTRY_ID)))
+            .map_err(|_| ())
+            .and_then(|res| committed(res).ok_or(()))
+            .and_then({
+                let raft = raft.clone();
+                move |_| raft.send(IsRingMember(TEST_ENTRY_ID)).map_err(|_| ())
+            })
+            .and_then(|member| if member == Ok(true) { Ok(()) } else { Err(()) })
+            .and_then(move |_| raft.send(proposal(MemoryStorageData::Remove(TEST_ENTRY_ID))).map_err(|_| ()))
+            .and_then(|res| committed(res).ok_or(()));
+
+        fut::wrap_future::<_, Self>(written)
+            .then(|res, act, _| {
+                act.busy = false;
+
+                match res {
+                    Ok(index) => act.record("write and read test entries", true, Stage::Replicate(index)),
+                    Err(_) => act.record("write and read test entries", false, Stage::Done),
+                }
+
+                fut::ok(())
+            })
+            .spawn(ctx);
+    }
+
+    /// Every live node applied the test entries, each must read the removal back.
+    fn read_replicas(&mut self, leader: NodeId, ctx: &mut Context<Self>) {
+        let reads = self
+            .nodes
+            .iter()
+            .filter(|node| node.alive)
+            .map(|node| node.raft.send(IsRingMember(TEST_ENTRY_ID)).then(|res| Ok::<_, ()>(res)))
+            .collect::<Vec<_>>();
+
+        fut::wrap_future::<_, Self>(join_all(reads))
+            .then(move |res, act, _| {
+                act.busy = false;
+
+                let removed = res.map_or(false, |reads| reads.into_iter().all(|read| matches!(read, Ok(Ok(false)))));
+                act.record("replicate test entries", removed, Stage::Failover(leader));
+                if removed {
+                    act.kill_leader();
+                }
+
+                fut::ok(())
+            })
+            .spawn(ctx);
+    }
+
+    fn kill_leader(&mut self) {
+        if let Stage::Failover(leader) = self.stage {
+            if let Some(node) = self.nodes.iter_mut().find(|node| node.id == leader) {
+                node.raftor.do_send(Kill);
+                node.alive = false;
+            }
+        }
+    }
+
+    fn finish(&mut self, ctx: &mut Context<Self>) {
+        let passed = self.report.len() == 4 && self.report.iter().all(|r| r.1);
+
+        println!("[selftest] {}", if passed { "PASSED" } else { "FAILED" });
+        ctx.stop();
+        System::current().stop_with_code(if passed { 0 } else { 1 });
+    }
+}
+
+/// Index a proposal was committed at, if it was.
+fn committed<E>(res: Result<ClientPayloadResponse<MemoryStorageResponse>, E>) -> Option<u64> {
+    match res {
+        Ok(ClientPayloadResponse::Applied { index, .. }) | Ok(ClientPayloadResponse::Committed { index }) => Some(index),
+        _ => None,
+    }
+}
+
+impl Actor for SelfTest {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        println!("[selftest] Starting local 3 node cluster");
+        self.stage_started = Instant::now();
+        ctx.run_interval(POLL_INTERVAL, |act, ctx| act.tick(ctx));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn free_base_ports_leave_room_for_three_nodes() {
+        let base = free_base_port().expect("free ports on loopback");
+        assert!(base.checked_add(202).is_some());
+    }
+}
@@ -0,0 +1,362 @@
+//! Running a node under a service manager: readiness, status and watchdog pings sent to
+//! systemd through `NOTIFY_SOCKET`, and a clean stop through `Shutdown` on SIGTERM or SIGINT,
+//! or when the Windows service control manager stops the service.
+
+use actix::prelude::*;
+use futures::{Future, Stream};
+use std::env;
+use std::io;
+use std::time::Duration;
+use tokio::timer::Timeout;
+
+use crate::config::ServiceIntegration;
+use crate::network::{GetRaftMetrics, Network, Shutdown};
+use crate::raft::{GetPendingProposals, RaftClient};
+
+/// Sends notifications to systemd, a no-op when not started by it.
+pub struct Notifier {
+    #[cfg(unix)]
+    socket: Option<(std::os::unix::net::UnixDatagram, String)>,
+}
+
+impl Notifier {
+    /// Notifier of the socket in `NOTIFY_SOCKET`, if set.
+    #[cfg(unix)]
+    pub fn from_env() -> Notifier {
+        let socket = env::var("NOTIFY_SOCKET").ok().and_then(|path| {
+            // abstract sockets can't be addressed by path with the standard library
+            if path.starts_with('@') {
+                warn!("Ignoring the abstract NOTIFY_SOCKET {}", path);
+                return None;
+            }
+            match std::os::unix::net::UnixDatagram::unbound() {
+                Ok(socket) => Some((socket, path)),
+                Err(err) => {
+                    warn!("Can't notify systemd: {}", err);
+                    None
+                }
+            }
+        });
+
+        Notifier { socket: socket }
+    }
+
+    #[cfg(not(unix))]
+    pub fn from_env() -> Notifier {
+        Notifier {}
+    }
+
+    #[cfg(unix)]
+    pub fn enabled(&self) -> bool {
+        self.socket.is_some()
+    }
+
+    #[cfg(not(unix))]
+    pub fn enabled(&self) -> bool {
+        false
+    }
+
+    /// Send `sd_notify` assignments, e.g. `READY=1`.
+    #[cfg(unix)]
+    pub fn notify(&self, state: &str) {
+        if let Some((ref socket, ref path)) = self.socket {
+            if let Err(err) = socket.send_to(state.as_bytes(), path) {
+                debug!("Error notifying systemd of {:?}: {}", state, err);
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub fn notify(&self, _state: &str) {}
+}
+
+/// Watchdog interval requested by systemd through `WATCHDOG_USEC`, for this process.
+fn watchdog_interval() -> Option<Duration> {
+    if let Some(pid) = env::var("WATCHDOG_PID").ok().and_then(|pid| pid.parse::<u32>().ok()) {
+        if pid != std::process::id() {
+            return None;
+        }
+    }
+
+    env::var("WATCHDOG_USEC")
+        .ok()
+        .and_then(|usec| usec.parse().ok())
+        .filter(|usec| *usec > 0)
+        .map(Duration::from_micros)
+}
+
+/// Reports the node to its service manager and stops it cleanly when asked to.
+pub struct ServiceManager {
+    config: ServiceIntegration,
+    cluster_net: Addr<Network>,
+    app_net: Addr<Network>,
+    raft: Addr<RaftClient>,
+    /// Whether the node waits for a leader before it's ready
+    member: bool,
+    notifier: Notifier,
+    ready: bool,
+    stopping: bool,
+}
+
+impl ServiceManager {
+    pub fn new(
+        config: ServiceIntegration,
+        cluster_net: Addr<Network>,
+        app_net: Addr<Network>,
+        raft: Addr<RaftClient>,
+        member: bool,
+    ) -> ServiceManager {
+        ServiceManager {
+            config: config,
+            cluster_net: cluster_net,
+            app_net: app_net,
+            raft: raft,
+            member: member,
+            notifier: Notifier::from_env(),
+            ready: false,
+            stopping: false,
+        }
+    }
+
+    /// Ready once raft knows a leader, gateways right away. The status tells the leader, term
+    /// and applied index.
+    fn report_status(&mut self, ctx: &mut Context<Self>) {
+        fut::wrap_future::<_, Self>(self.cluster_net.send(GetRaftMetrics))
+            .map_err(|_, _, _| ())
+            .map(|res, act, _| {
+                let metrics = res.ok().and_then(|metrics| metrics);
+                let status = match metrics {
+                    Some(ref metrics) => format!(
+                        "STATUS=leader {:?}, term {}, applied {}",
+                        metrics.current_leader, metrics.current_term, metrics.last_applied
+                    ),
+                    None => "STATUS=no raft on this node".to_owned(),
+                };
+
+                let ready = !act.member || metrics.map_or(false, |metrics| metrics.current_leader.is_some());
+                if ready && !act.ready && !act.stopping {
+                    act.ready = true;
+                    info!("Node ready, notifying the service manager");
+                    act.notifier.notify(&format!("READY=1\n{}", status));
+                } else {
+                    act.notifier.notify(&status);
+                }
+            })
+            .spawn(ctx);
+    }
+
+    /// Ping the watchdog once the networks and the raft client handle messages within half
+    /// the interval, a node whose actors hang is restarted by systemd.
+    fn feed_watchdog(&mut self, interval: Duration, ctx: &mut Context<Self>) {
+        let alive = self
+            .cluster_net
+            .send(GetRaftMetrics)
+            .join3(self.app_net.send(GetRaftMetrics), self.raft.send(GetPendingProposals));
+
+        fut::wrap_future::<_, Self>(Timeout::new(alive, interval / 2))
+            .then(|res, act, _| {
+                match res {
+                    Ok(_) => act.notifier.notify("WATCHDOG=1"),
+                    Err(ref err) if err.is_elapsed() => warn!("Node actors unresponsive, skipping the watchdog ping"),
+                    Err(_) => warn!("Node actors stopped, skipping the watchdog ping"),
+                }
+                fut::ok(())
+            })
+            .spawn(ctx);
+    }
+
+    /// Shut both networks down and stop the system, a second stop request stops it right away.
+    fn stop(&mut self, ctx: &mut Context<Self>) {
+        if self.stopping {
+            warn!("Stopping the node without waiting for its shutdown");
+            System::current().stop();
+            return;
+        }
+
+        info!("Stop requested, shutting the node down");
+        self.stopping = true;
+        self.notifier.notify("STOPPING=1");
+
+        let app_net = self.app_net.clone();
+        fut::wrap_future::<_, Self>(self.cluster_net.send(Shutdown).then(move |_| app_net.send(Shutdown)))
+            .then(|_, _, _| {
+                System::current().stop();
+                fut::ok(())
+            })
+            .spawn(ctx);
+    }
+}
+
+impl Actor for ServiceManager {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        if self.config.clean_stop {
+            ctx.add_stream(stop_signals());
+            #[cfg(all(windows, feature = "win-service"))]
+            windows::set_stop_handler(ctx.address().recipient());
+        }
+
+        if !self.notifier.enabled() {
+            return;
+        }
+
+        self.report_status(ctx);
+        ctx.run_interval(Duration::from_millis(self.config.status_interval_ms), |act, ctx| act.report_status(ctx));
+
+        if let Some(interval) = watchdog_interval() {
+            info!("Pinging the systemd watchdog every {:?}", interval / 2);
+            ctx.run_interval(interval / 2, move |act, ctx| act.feed_watchdog(interval, ctx));
+        }
+    }
+}
+
+/// SIGTERM and SIGINT.
+#[cfg(unix)]
+fn stop_signals() -> Box<dyn Stream<Item = (), Error = io::Error>> {
+    use tokio_signal::unix::{Signal, SIGTERM};
+
+    let term = Signal::new(SIGTERM).flatten_stream().map(|_| ());
+    Box::new(tokio_signal::ctrl_c().flatten_stream().select(term))
+}
+
+/// Ctrl-C and Ctrl-Break.
+#[cfg(not(unix))]
+fn stop_signals() -> Box<dyn Stream<Item = (), Error = io::Error>> {
+    Box::new(tokio_signal::ctrl_c().flatten_stream())
+}
+
+impl StreamHandler<(), io::Error> for ServiceManager {
+    fn handle(&mut self, _: (), ctx: &mut Context<Self>) {
+        self.stop(ctx);
+    }
+
+    fn error(&mut self, err: io::Error, _ctx: &mut Context<Self>) -> Running {
+        error!("Error listening for stop signals: {}", err);
+        Running::Continue
+    }
+}
+
+/// Stop the node as on SIGTERM.
+#[derive(Message)]
+pub struct StopService;
+
+impl Handler<StopService> for ServiceManager {
+    type Result = ();
+
+    fn handle(&mut self, _: StopService, ctx: &mut Context<Self>) {
+        self.stop(ctx);
+    }
+}
+
+/// Running the process as a Windows service, built with the `win-service` feature.
+#[cfg(all(windows, feature = "win-service"))]
+pub mod windows {
+    use actix::Recipient;
+    use lazy_static::lazy_static;
+    use std::ffi::OsString;
+    use std::sync::Mutex;
+    use std::time::Duration;
+    use windows_service::service::{
+        ServiceControl, ServiceControlAccept, ServiceExitCode, ServiceState, ServiceStatus, ServiceType,
+    };
+    use windows_service::service_control_handler::{self, ServiceControlHandlerResult};
+    use windows_service::{define_windows_service, service_dispatcher};
+
+    use super::StopService;
+
+    lazy_static! {
+        /// Entry point of the process run by the service
+        static ref ENTRY: Mutex<Option<fn() -> i32>> = Mutex::new(None);
+        static ref STOP: Mutex<Option<Recipient<StopService>>> = Mutex::new(None);
+    }
+
+    define_windows_service!(ffi_service_main, service_main);
+
+    /// Run `entry` as the service `name` registered with the service control manager,
+    /// returns once the service stopped.
+    pub fn run(name: &str, entry: fn() -> i32) -> windows_service::Result<()> {
+        *ENTRY.lock().unwrap() = Some(entry);
+        service_dispatcher::start(name, ffi_service_main)
+    }
+
+    pub(super) fn set_stop_handler(stop: Recipient<StopService>) {
+        *STOP.lock().unwrap() = Some(stop);
+    }
+
+    fn service_main(arguments: Vec<OsString>) {
+        let name = arguments.get(0).and_then(|name| name.to_str()).unwrap_or("raftor").to_owned();
+        let handler = |control| match control {
+            ServiceControl::Stop | ServiceControl::Shutdown => {
+                match *STOP.lock().unwrap() {
+                    Some(ref stop) => {
+                        let _ = stop.do_send(StopService);
+                    }
+                    None => warn!("Service stop requested before the node started"),
+                }
+                ServiceControlHandlerResult::NoError
+            }
+            ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+            _ => ServiceControlHandlerResult::NotImplemented,
+        };
+
+        let status = match service_control_handler::register(&name, handler) {
+            Ok(status) => status,
+            Err(err) => {
+                error!("Can't register the service control handler: {}", err);
+                return;
+            }
+        };
+        let report = |state, accepted, code| {
+            let _ = status.set_service_status(ServiceStatus {
+                service_type: ServiceType::OWN_PROCESS,
+                current_state: state,
+                controls_accepted: accepted,
+                exit_code: ServiceExitCode::Win32(code),
+                checkpoint: 0,
+                wait_hint: Duration::from_secs(15),
+                process_id: None,
+            });
+        };
+
+        report(ServiceState::Running, ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN, 0);
+        let entry = (*ENTRY.lock().unwrap()).expect("service entry point");
+        let code = entry();
+        report(ServiceState::Stopped, ServiceControlAccept::empty(), code as u32);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn notifications_reach_the_socket() {
+        use std::os::unix::net::UnixDatagram;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notify");
+        let systemd = UnixDatagram::bind(&path).unwrap();
+
+        let notifier = Notifier {
+            socket: Some((UnixDatagram::unbound().unwrap(), path.to_str().unwrap().to_owned())),
+        };
+        assert!(notifier.enabled());
+        notifier.notify("READY=1");
+
+        let mut buf = [0; 64];
+        let len = systemd.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+    }
+
+    #[test]
+    fn notifying_without_systemd_does_nothing() {
+        let notifier = Notifier {
+            #[cfg(unix)]
+            socket: None,
+        };
+        assert!(!notifier.enabled());
+        notifier.notify("READY=1");
+    }
+}
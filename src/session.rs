@@ -4,6 +4,7 @@ use serde::{Deserialize, Serialize};
 use serde_json;
 use std::time::{Duration, Instant};
 
+use crate::clock;
 use crate::server::{self, Server};
 
 /// How often heartbeat pings are sent
@@ -26,14 +27,14 @@ impl Session {
             room: room.to_owned(),
             server: server,
             count: 0,
-            hb: Instant::now(),
+            hb: clock::now(),
         }
     }
 
     fn hb(&self, ctx: &mut ws::WebsocketContext<Self>) {
         ctx.run_interval(HEARTBEAT_INTERVAL, |act, ctx| {
             // check client heartbeats
-            if Instant::now().duration_since(act.hb) > CLIENT_TIMEOUT {
+            if clock::now().duration_since(act.hb) > CLIENT_TIMEOUT {
                 // heartbeat timed out
                 println!("Websocket Client heartbeat failed, disconnecting!");
 
@@ -138,11 +139,11 @@ impl StreamHandler<ws::Message, ws::ProtocolError> for Session {
 
         match msg {
             ws::Message::Ping(msg) => {
-                self.hb = Instant::now();
+                self.hb = clock::now();
                 ctx.pong(&msg);
             }
             ws::Message::Pong(_) => {
-                self.hb = Instant::now();
+                self.hb = clock::now();
             }
             ws::Message::Text(msg) => {
                 let msg = serde_json::from_slice::<Message>(msg.as_ref());
@@ -0,0 +1,206 @@
+//! Local test cluster running in virtual time.
+//!
+//! A `Simulation` installs a `ManualClock` and starts a local three node cluster on a system
+//! timed by it, so elections, heartbeats and the bootstrap delay fire as the harness moves the
+//! clock instead of after real sleeps. Time moves in steps of `STEP`, after each step the
+//! system turns a few times so the messages the fired timers sent get handled. Peers still
+//! talk over loopback sockets: runs take no longer than the work they do, and a schedule fires
+//! the same timers in the same order every time, but which message in flight is handled first
+//! is up to the operating system.
+//!
+//! The clock is process wide, run one simulation at a time and not alongside tests reading it.
+
+use actix::prelude::*;
+use actix_raft::NodeId;
+use futures::{future, sync::oneshot, task, Async, Future, Poll};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::clock::{self, ManualClock, SystemClock};
+use crate::network::GetRaftMetrics;
+use crate::raftor::Kill;
+use crate::selftest::{local_cluster, TestNode};
+
+/// Virtual time the clock moves by at once
+pub const STEP: Duration = Duration::from_millis(10);
+/// Turns of the runtime after each step, enough for loopback messages to go back and forth
+const TURNS: usize = 16;
+
+/// Local three node cluster timed by a `ManualClock`.
+pub struct Simulation {
+    clock: Arc<ManualClock>,
+    runner: SystemRunner,
+    nodes: Vec<TestNode>,
+    elapsed: Duration,
+}
+
+impl Simulation {
+    /// Install a manual clock and start the nodes, on consecutive ports from `base_port`.
+    pub fn new(base_port: u16) -> Simulation {
+        let clock = Arc::new(ManualClock::new(clock::wall_millis()));
+        clock::set_clock(clock.clone());
+
+        let mut runner = System::builder().name("raftor-simulation").clock(clock::timer_clock()).build();
+        let nodes = runner
+            .block_on(future::lazy(move || Ok::<_, ()>(local_cluster(base_port))))
+            .expect("local cluster to start");
+
+        Simulation {
+            clock: clock,
+            runner: runner,
+            nodes: nodes,
+            elapsed: Duration::from_millis(0),
+        }
+    }
+
+    pub fn clock(&self) -> &Arc<ManualClock> {
+        &self.clock
+    }
+
+    /// Virtual time elapsed since the nodes started.
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    /// Ids of the nodes, dead ones included.
+    pub fn node_ids(&self) -> Vec<NodeId> {
+        self.nodes.iter().map(|node| node.id).collect()
+    }
+
+    pub(crate) fn nodes(&self) -> &[TestNode] {
+        &self.nodes
+    }
+
+    /// Move the clock forward by `by`, step by step.
+    pub fn advance(&mut self, by: Duration) {
+        let mut left = by;
+        while left > Duration::from_millis(0) {
+            let step = left.min(STEP);
+            self.clock.advance(step);
+            self.elapsed += step;
+            left -= step;
+            let _ = self.runner.block_on(Turns(TURNS));
+        }
+    }
+
+    /// Move the clock until `done` holds, at most by `limit`. Whether it held.
+    pub fn run_until(&mut self, limit: Duration, mut done: impl FnMut(&mut Simulation) -> bool) -> bool {
+        let until = self.elapsed + limit;
+        while self.elapsed < until {
+            if done(self) {
+                return true;
+            }
+            self.advance(STEP);
+        }
+        done(self)
+    }
+
+    /// Run `fut` on the system while moving the clock, `None` when it didn't resolve within
+    /// `limit`.
+    pub fn run<F>(&mut self, fut: F, limit: Duration) -> Option<Result<F::Item, F::Error>>
+    where
+        F: Future + 'static,
+        F::Item: 'static,
+        F::Error: 'static,
+    {
+        let (tx, mut rx) = oneshot::channel();
+        let _ = self.runner.block_on(future::lazy(move || {
+            Arbiter::spawn(fut.then(move |res| {
+                let _ = tx.send(res);
+                Ok(())
+            }));
+            Ok::<_, ()>(())
+        }));
+
+        let mut res = None;
+        self.run_until(limit, |_| {
+            if let Ok(Some(done)) = rx.try_recv() {
+                res = Some(done);
+            }
+            res.is_some()
+        });
+        res
+    }
+
+    /// The leader all live nodes agree on, if any.
+    pub fn leader(&mut self) -> Option<NodeId> {
+        let requests = self
+            .nodes
+            .iter()
+            .filter(|node| node.alive)
+            .map(|node| node.net.send(GetRaftMetrics))
+            .collect::<Vec<_>>();
+        let alive = requests.len();
+
+        let metrics = self.runner.block_on(future::join_all(requests)).ok()?;
+        let leaders = metrics
+            .into_iter()
+            .filter_map(|metrics| metrics.ok().and_then(|metrics| metrics))
+            .map(|metrics| metrics.current_leader)
+            .collect::<Vec<_>>();
+
+        match leaders.first() {
+            Some(&Some(leader)) if leaders.len() == alive && leaders.iter().all(|other| *other == Some(leader)) => Some(leader),
+            _ => None,
+        }
+    }
+
+    /// Move the clock until the live nodes agree on a leader, at most by `limit`.
+    pub fn await_leader(&mut self, limit: Duration) -> Option<NodeId> {
+        let mut leader = None;
+        self.run_until(limit, |sim| {
+            leader = sim.leader();
+            leader.is_some()
+        });
+        leader
+    }
+
+    /// Stop node `id` as if its process died.
+    pub fn kill(&mut self, id: NodeId) {
+        if let Some(node) = self.nodes.iter_mut().find(|node| node.id == id && node.alive) {
+            node.raftor.do_send(Kill);
+            node.alive = false;
+        }
+        let _ = self.runner.block_on(Turns(TURNS));
+    }
+}
+
+impl Drop for Simulation {
+    fn drop(&mut self) {
+        clock::set_clock(Arc::new(SystemClock));
+    }
+}
+
+/// Resolves once the runtime turned `n` times, handling the I/O and messages ready meanwhile.
+struct Turns(usize);
+
+impl Future for Turns {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        if self.0 == 0 {
+            return Ok(Async::Ready(()));
+        }
+        self.0 -= 1;
+        task::current().notify();
+        Ok(Async::NotReady)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[ignore] // binds ports and installs the process wide clock, run with --test-threads=1
+    fn elections_run_in_virtual_time() {
+        let mut sim = Simulation::new(19300);
+
+        let leader = sim.await_leader(Duration::from_secs(30)).expect("a leader within 30s of virtual time");
+        sim.kill(leader);
+
+        let successor = sim.await_leader(Duration::from_secs(30)).expect("a successor within 30s of virtual time");
+        assert_ne!(successor, leader);
+    }
+}
@@ -0,0 +1,286 @@
+//! Data dir lock of a node and the warm standby waiting to take it over.
+//!
+//! The process running a node holds an exclusive `flock` on `node.lock` in its data dir for
+//! as long as it runs, the kernel releases it when the process dies. A warm standby started
+//! with `--standby` on the same host tails the data dir, reading what the node writes as it
+//! writes it, until it gets the lock. It then records a new epoch and starts the node from the
+//! data dir. A holder finding another epoch in the lock file, e.g. because the file was
+//! removed and locked again, has been fenced and stops.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+use crate::config::WarmStandby;
+
+/// File in the data dir holding the process the node runs in
+const LOCK_FILE: &str = "node.lock";
+
+/// Process holding the data dir of a node.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LockHolder {
+    pub pid: u32,
+    /// Bumped by every takeover, a holder whose epoch changed was fenced
+    pub epoch: u64,
+}
+
+/// Lock of this process on the data dir, released when dropped or when the process dies. The
+/// holder record stays in the file, the next process takes the lock over with the next epoch.
+pub struct DataDirLock {
+    path: PathBuf,
+    /// Open for as long as the lock is held, closing it releases the lock
+    file: File,
+    holder: LockHolder,
+}
+
+impl DataDirLock {
+    /// Lock the data dir. Fails while another process holds it.
+    pub fn acquire(data_dir: &Path) -> io::Result<DataDirLock> {
+        let path = data_dir.join(LOCK_FILE);
+
+        match try_take_over(&path)? {
+            Some(lock) => Ok(lock),
+            None => Err(held_by(&path)),
+        }
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.holder.epoch
+    }
+
+    /// Check the lock is still ours, answers the holder that fenced this process off if the
+    /// lock file was replaced and taken by another process.
+    pub fn renew(&mut self) -> Result<(), LockHolder> {
+        match read_holder(&self.path) {
+            Some(ref holder) if *holder != self.holder || !self.locks(&self.path) => Err(holder.clone()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether the file at `path` is the one this process locked.
+    #[cfg(unix)]
+    fn locks(&self, path: &Path) -> bool {
+        use std::os::unix::fs::MetadataExt;
+
+        match (fs::metadata(path), self.file.metadata()) {
+            (Ok(current), Ok(locked)) => current.dev() == locked.dev() && current.ino() == locked.ino(),
+            _ => true,
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn locks(&self, _path: &Path) -> bool {
+        true
+    }
+}
+
+/// Tail the data dir until its holder is gone, then take the lock over. Blocks the calling
+/// thread, meant to run before the node is started.
+pub fn wait_for_takeover(data_dir: &Path, config: &WarmStandby) -> io::Result<DataDirLock> {
+    let path = data_dir.join(LOCK_FILE);
+    let mut tail = Tail::default();
+
+    info!("Standing by for the node in {:?}", data_dir);
+    loop {
+        let previous = read_holder(&path);
+        if let Some(lock) = try_take_over(&path)? {
+            match previous {
+                Some(holder) => warn!("Process {} left {:?}, taking the node over with epoch {}", holder.pid, data_dir, lock.epoch()),
+                None => info!("No process holds {:?}, taking the node over with epoch {}", data_dir, lock.epoch()),
+            }
+            // pick up what the holder wrote last
+            tail.follow(data_dir);
+            return Ok(lock);
+        }
+
+        let bytes = tail.follow(data_dir);
+        if bytes > 0 {
+            debug!("Standby read {} new bytes of {:?}", bytes, data_dir);
+        }
+        thread::sleep(Duration::from_millis(config.poll_ms));
+    }
+}
+
+/// Lock the file without waiting and record the next epoch in it, `None` while another
+/// process holds it.
+fn try_take_over(path: &Path) -> io::Result<Option<DataDirLock>> {
+    let mut file = OpenOptions::new().read(true).write(true).create(true).open(path)?;
+    if !try_lock_exclusive(&file)? {
+        return Ok(None);
+    }
+
+    let mut data = Vec::new();
+    file.read_to_end(&mut data)?;
+    let epoch = serde_json::from_slice::<LockHolder>(&data).map_or(1, |holder| holder.epoch + 1);
+
+    let holder = LockHolder {
+        pid: process::id(),
+        epoch: epoch,
+    };
+    let data = serde_json::to_vec(&holder).map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+    // readers only ever see a partial holder, which they ignore
+    file.set_len(0)?;
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(&data)?;
+    file.sync_all()?;
+
+    Ok(Some(DataDirLock {
+        path: path.to_owned(),
+        file: file,
+        holder: holder,
+    }))
+}
+
+fn held_by(path: &Path) -> io::Error {
+    let holder = match read_holder(path) {
+        Some(holder) => format!("process {} (epoch {})", holder.pid, holder.epoch),
+        None => "another process".to_owned(),
+    };
+    io::Error::new(io::ErrorKind::WouldBlock, format!("{:?} is held by {}", path, holder))
+}
+
+fn read_holder(path: &Path) -> Option<LockHolder> {
+    fs::read(path).ok().and_then(|data| serde_json::from_slice(&data).ok())
+}
+
+#[cfg(unix)]
+fn try_lock_exclusive(file: &File) -> io::Result<bool> {
+    use std::os::unix::io::AsRawFd;
+
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+        return Ok(true);
+    }
+    match io::Error::last_os_error() {
+        ref err if err.kind() == io::ErrorKind::WouldBlock => Ok(false),
+        err => Err(err),
+    }
+}
+
+#[cfg(not(unix))]
+fn try_lock_exclusive(_file: &File) -> io::Result<bool> {
+    Err(io::Error::new(io::ErrorKind::Other, "locking the data dir needs flock"))
+}
+
+/// Offsets of the files of the data dir the standby read up to.
+#[derive(Default)]
+struct Tail {
+    offsets: HashMap<PathBuf, u64>,
+}
+
+impl Tail {
+    /// Read what was written to the data dir since the last call, read-only, so the page
+    /// cache holds it when the standby takes over. Files which shrank were rewritten and are
+    /// read again. Answers the bytes read.
+    fn follow(&mut self, dir: &Path) -> u64 {
+        let mut seen = Vec::new();
+        let bytes = self.follow_dir(dir, &mut seen);
+        // forget removed files
+        self.offsets.retain(|path, _| seen.contains(path));
+        bytes
+    }
+
+    fn follow_dir(&mut self, dir: &Path, seen: &mut Vec<PathBuf>) -> u64 {
+        let mut bytes = 0;
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => return 0,
+        };
+
+        for entry in entries.filter_map(Result::ok) {
+            let path = entry.path();
+            if path.is_dir() {
+                bytes += self.follow_dir(&path, seen);
+                continue;
+            }
+
+            let offset = self.offsets.get(&path).cloned().unwrap_or(0);
+            let offset = match entry.metadata() {
+                Ok(ref metadata) if metadata.len() < offset => 0,
+                Ok(ref metadata) if metadata.len() == offset => {
+                    seen.push(path);
+                    continue;
+                }
+                Ok(_) => offset,
+                Err(_) => continue,
+            };
+
+            let read = read_from(&path, offset);
+            self.offsets.insert(path.clone(), offset + read);
+            seen.push(path);
+            bytes += read;
+        }
+
+        bytes
+    }
+}
+
+/// Read a file from `offset` to its end, answers the bytes read.
+fn read_from(path: &Path, offset: u64) -> u64 {
+    let mut bytes = 0;
+    let mut buf = vec![0; 64 * 1024];
+
+    if let Ok(mut file) = File::open(path) {
+        if file.seek(SeekFrom::Start(offset)).is_err() {
+            return 0;
+        }
+        while let Ok(read) = file.read(&mut buf) {
+            if read == 0 {
+                break;
+            }
+            bytes += read as u64;
+        }
+    }
+
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lock_is_exclusive_until_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let lock = DataDirLock::acquire(dir.path()).unwrap();
+        assert_eq!(lock.epoch(), 1);
+        assert_eq!(DataDirLock::acquire(dir.path()).err().map(|err| err.kind()), Some(io::ErrorKind::WouldBlock));
+
+        drop(lock);
+        assert_eq!(DataDirLock::acquire(dir.path()).unwrap().epoch(), 2);
+    }
+
+    #[test]
+    fn replaced_lock_fences_the_holder() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut lock = DataDirLock::acquire(dir.path()).unwrap();
+        assert!(lock.renew().is_ok());
+
+        fs::remove_file(dir.path().join(LOCK_FILE)).unwrap();
+        let successor = DataDirLock::acquire(dir.path()).unwrap();
+        assert_eq!(lock.renew().err().map(|holder| holder.epoch), Some(successor.epoch()));
+    }
+
+    #[test]
+    fn tail_reads_only_new_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("log");
+        let mut tail = Tail::default();
+
+        fs::write(&log, b"abc").unwrap();
+        assert_eq!(tail.follow(dir.path()), 3);
+        assert_eq!(tail.follow(dir.path()), 0);
+
+        OpenOptions::new().append(true).open(&log).unwrap().write_all(b"de").unwrap();
+        assert_eq!(tail.follow(dir.path()), 2);
+
+        // rewritten shorter, read again
+        fs::write(&log, b"x").unwrap();
+        assert_eq!(tail.follow(dir.path()), 1);
+    }
+}
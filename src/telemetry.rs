@@ -0,0 +1,141 @@
+use actix::prelude::*;
+use actix_raft::NodeId;
+use futures::Poll;
+
+use crate::config::TelemetryConfig;
+
+/// Runtime the batch exporter ships spans from, apart from the actix one.
+#[cfg(feature = "otel")]
+static EXPORT_RUNTIME: once_cell::sync::OnceCell<tokio1::runtime::Runtime> = once_cell::sync::OnceCell::new();
+
+/// Install the OpenTelemetry exporter so raft RPC and apply spans are shipped to the configured
+/// OTLP endpoint, tagged with the cluster, when it's named, and node they come from. Spans are
+/// exported in batches in the background.
+#[cfg(feature = "otel")]
+pub fn init(config: &TelemetryConfig, node_id: NodeId, cluster: Option<&str>) {
+    use opentelemetry::{sdk::trace, sdk::Resource, KeyValue};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    let mut attributes = vec![
+        KeyValue::new("service.name", config.service_name.clone()),
+        KeyValue::new("raftor.node_id", node_id.to_string()),
+    ];
+    if let Some(cluster) = cluster {
+        attributes.push(KeyValue::new("raftor.cluster", cluster.to_owned()));
+    }
+
+    let runtime = EXPORT_RUNTIME.get_or_try_init(|| {
+        tokio1::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .thread_name("otel-export")
+            .enable_all()
+            .build()
+    });
+    let runtime = match runtime {
+        Ok(runtime) => runtime,
+        Err(err) => {
+            error!("Failed to start the OpenTelemetry export runtime: {}", err);
+            return;
+        }
+    };
+
+    let tracer = {
+        let _runtime = runtime.enter();
+        opentelemetry_otlp::new_pipeline()
+            .with_endpoint(config.otlp_endpoint.as_str())
+            .with_trace_config(trace::config().with_resource(Resource::new(attributes)))
+            .install_batch(opentelemetry::runtime::Tokio)
+    };
+
+    let tracer = match tracer {
+        Ok(tracer) => tracer,
+        Err(err) => {
+            error!("Failed to install OpenTelemetry exporter: {:?}", err);
+            return;
+        }
+    };
+
+    let subscriber = tracing_subscriber::Registry::default()
+        .with(tracing_opentelemetry::layer().with_tracer(tracer));
+
+    if let Err(err) = tracing::subscriber::set_global_default(subscriber) {
+        error!("Failed to set tracing subscriber: {:?}", err);
+        return;
+    }
+
+    info!("Exporting traces to {}", config.otlp_endpoint);
+}
+
+#[cfg(not(feature = "otel"))]
+pub fn init(config: &TelemetryConfig, _node_id: NodeId, _cluster: Option<&str>) {
+    warn!(
+        "Telemetry endpoint {} configured but raftor was built without the `otel` feature",
+        config.otlp_endpoint
+    );
+}
+
+/// Export the spans still batched, before the process exits.
+#[cfg(feature = "otel")]
+pub fn shutdown() {
+    if EXPORT_RUNTIME.get().is_some() {
+        opentelemetry::global::shutdown_tracer_provider();
+    }
+}
+
+#[cfg(not(feature = "otel"))]
+pub fn shutdown() {}
+
+#[cfg(feature = "otel")]
+pub use tracing::Span;
+
+/// Stand-in for `tracing::Span` without the `otel` feature.
+#[cfg(not(feature = "otel"))]
+#[derive(Clone)]
+pub struct Span;
+
+/// Span covering an actor future: it's entered whenever the future is polled, so it measures
+/// the work a handler defers and not only the handler itself.
+pub struct Traced<F> {
+    fut: F,
+    #[cfg_attr(not(feature = "otel"), allow(dead_code))]
+    span: Span,
+}
+
+impl<F: ActorFuture> ActorFuture for Traced<F> {
+    type Item = F::Item;
+    type Error = F::Error;
+    type Actor = F::Actor;
+
+    fn poll(&mut self, act: &mut F::Actor, ctx: &mut <F::Actor as Actor>::Context) -> Poll<F::Item, F::Error> {
+        #[cfg(feature = "otel")]
+        let _entered = self.span.enter();
+        self.fut.poll(act, ctx)
+    }
+}
+
+/// Run actor futures in a span, see `trace_span!`.
+pub trait TracedExt: ActorFuture + Sized {
+    fn traced(self, span: Span) -> Traced<Self> {
+        Traced { fut: self, span: span }
+    }
+}
+
+impl<F: ActorFuture> TracedExt for F {}
+
+/// A tracing span when built with the `otel` feature, attach it to the future a handler
+/// returns with `TracedExt::traced`.
+#[cfg(feature = "otel")]
+#[macro_export]
+macro_rules! trace_span {
+    ($($arg:tt)*) => {
+        tracing::info_span!($($arg)*)
+    };
+}
+
+#[cfg(not(feature = "otel"))]
+#[macro_export]
+macro_rules! trace_span {
+    ($($arg:tt)*) => {
+        $crate::telemetry::Span
+    };
+}
@@ -1,5 +1,16 @@
+use actix_raft::NodeId;
 use crypto::digest::Digest;
 use crypto::sha2::Sha256;
+use crypto::util::fixed_time_eq;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use std::collections::hash_map::RandomState;
+use std::collections::BTreeMap;
+use std::hash::{BuildHasher, Hasher};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config::{NodeIdStrategy, NodeInfo};
 
 /// Generating node id from node's remote address
 pub fn generate_node_id(node_address: &str) -> u64 {
@@ -13,3 +24,172 @@ pub fn generate_node_id(node_address: &str) -> u64 {
 
     id
 }
+
+/// Id of a node as seen by its peers, the assigned id if any or the hash of its address
+pub fn node_id(info: &NodeInfo) -> NodeId {
+    info.id.unwrap_or_else(|| generate_node_id(info.cluster_addr.as_str()))
+}
+
+/// Id of the local node according to the configured strategy
+pub fn local_node_id(strategy: &NodeIdStrategy, info: &NodeInfo) -> Result<NodeId, String> {
+    match strategy {
+        NodeIdStrategy::Hash => Ok(node_id(info)),
+        NodeIdStrategy::Assigned => info
+            .id
+            .ok_or_else(|| format!("No id assigned to node {} in the node list", info.cluster_addr)),
+        NodeIdStrategy::Persisted { path } => {
+            if let Ok(content) = std::fs::read_to_string(path) {
+                return content
+                    .trim()
+                    .parse::<NodeId>()
+                    .map_err(|err| format!("Invalid node id in {}: {}", path, err));
+            }
+
+            let nanos = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_nanos())
+                .unwrap_or(0);
+            let id = generate_node_id(format!("{}-{}-{}", info.cluster_addr, std::process::id(), nanos).as_str());
+
+            std::fs::write(path, id.to_string())
+                .map(|_| id)
+                .map_err(|err| format!("Failed to persist node id to {}: {}", path, err))
+        }
+    }
+}
+
+/// `name` under `dir`, unless it's absolute or leaves `dir` through `..`. Paths coming from
+/// clients go through it so they can't read or write elsewhere on the host.
+pub fn contained_path<P: AsRef<Path>>(dir: P, name: &str) -> Option<PathBuf> {
+    let name = Path::new(name);
+    let mut components = name.components().peekable();
+    components.peek()?;
+    if !components.all(|component| match component {
+        Component::Normal(_) | Component::CurDir => true,
+        _ => false,
+    }) {
+        return None;
+    }
+
+    Some(dir.as_ref().join(name))
+}
+
+/// Whether an `Authorization` header carries the bearer token, compared in constant time.
+/// Every request is authorized when no token is configured.
+pub fn bearer_authorized(authorization: Option<&str>, token: Option<&str>) -> bool {
+    let token = match token {
+        Some(token) => token,
+        None => return true,
+    };
+
+    match authorization {
+        Some(value) if value.starts_with("Bearer ") => fixed_time_eq(value["Bearer ".len()..].as_bytes(), token.as_bytes()),
+        _ => false,
+    }
+}
+
+/// Client name an `Authorization` header authenticates as, see `token_client`.
+pub fn bearer_client(authorization: Option<&str>, tokens: &BTreeMap<String, String>, anonymous: &str) -> Option<String> {
+    let presented = authorization.and_then(|value| if value.starts_with("Bearer ") { Some(&value["Bearer ".len()..]) } else { None });
+    token_client(presented, tokens, anonymous)
+}
+
+/// Client name a token authenticates as with the `client_tokens` of the node, every token is
+/// compared in constant time. Clients are all `anonymous` when no token is configured, `None`
+/// when the token isn't a configured one.
+pub fn token_client(presented: Option<&str>, tokens: &BTreeMap<String, String>, anonymous: &str) -> Option<String> {
+    if tokens.is_empty() {
+        return Some(anonymous.to_owned());
+    }
+
+    let presented = presented?;
+    tokens.iter().fold(None, |client, (token, name)| {
+        if fixed_time_eq(presented.as_bytes(), token.as_bytes()) {
+            Some(name.clone())
+        } else {
+            client
+        }
+    })
+}
+
+/// Random token of 128 bits from the operating system, hex encoded.
+pub fn random_token() -> String {
+    let mut bytes = [0u8; 16];
+    OsRng.fill_bytes(&mut bytes);
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Random duration up to `max`, from the randomly seeded std hasher.
+pub fn jitter(max: Duration) -> Duration {
+    let millis = max.as_millis() as u64;
+    if millis == 0 {
+        return max;
+    }
+
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0));
+    Duration::from_millis(hasher.finish() % (millis + 1))
+}
+
+/// Clock ticks per second used by `/proc/<pid>/stat`, this is 100 on practically every Linux
+const CLOCK_TICKS: u64 = 100;
+
+/// CPU time (user + system) spent by this process in milliseconds, `None` if unavailable
+pub fn process_cpu_millis() -> Option<u64> {
+    let stat = std::fs::read_to_string("/proc/self/stat").ok()?;
+
+    // skip past the command name, it may contain spaces
+    let fields = stat[stat.rfind(')')? + 1..].split_whitespace().collect::<Vec<_>>();
+    let utime = fields.get(11)?.parse::<u64>().ok()?;
+    let stime = fields.get(12)?.parse::<u64>().ok()?;
+
+    Some((utime + stime) * 1000 / CLOCK_TICKS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn contained_path_stays_under_dir() {
+        assert_eq!(contained_path("dumps", "state.json"), Some(PathBuf::from("dumps/state.json")));
+        assert_eq!(contained_path("dumps", "daily/state.json"), Some(PathBuf::from("dumps/daily/state.json")));
+        assert_eq!(contained_path("dumps", "./state.json"), Some(PathBuf::from("dumps/./state.json")));
+    }
+
+    #[test]
+    fn contained_path_rejects_escapes() {
+        assert_eq!(contained_path("dumps", "/etc/passwd"), None);
+        assert_eq!(contained_path("dumps", "../state.json"), None);
+        assert_eq!(contained_path("dumps", "daily/../../state.json"), None);
+        assert_eq!(contained_path("dumps", ""), None);
+    }
+
+    #[test]
+    fn bearer_token_is_checked_when_configured() {
+        assert!(bearer_authorized(None, None));
+        assert!(bearer_authorized(Some("Bearer secret"), Some("secret")));
+        assert!(!bearer_authorized(Some("Bearer other"), Some("secret")));
+        assert!(!bearer_authorized(Some("secret"), Some("secret")));
+        assert!(!bearer_authorized(None, Some("secret")));
+    }
+
+    #[test]
+    fn random_tokens_carry_128_bits() {
+        let token = random_token();
+        assert_eq!(token.len(), 32);
+        assert!(token.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_ne!(token, random_token());
+    }
+
+    #[test]
+    fn clients_are_named_by_their_token() {
+        assert_eq!(bearer_client(Some("Bearer anything"), &BTreeMap::new(), "gateway"), Some("gateway".to_owned()));
+
+        let tokens = vec![("secret".to_owned(), "billing".to_owned())].into_iter().collect();
+        assert_eq!(bearer_client(Some("Bearer secret"), &tokens, "gateway"), Some("billing".to_owned()));
+        assert_eq!(bearer_client(Some("Bearer other"), &tokens, "gateway"), None);
+        assert_eq!(bearer_client(None, &tokens, "gateway"), None);
+        assert_eq!(token_client(Some("secret"), &tokens, "json"), Some("billing".to_owned()));
+    }
+}
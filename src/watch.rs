@@ -0,0 +1,43 @@
+use actix::prelude::*;
+use actix_web_actors::ws;
+
+use crate::network::{ClusterProgress, Network, SubscribeProgress};
+
+/// Websocket session streaming raft progress updates of a node as JSON.
+pub struct ProgressWatcher {
+    net: Addr<Network>,
+}
+
+impl ProgressWatcher {
+    pub fn new(net: Addr<Network>) -> Self {
+        ProgressWatcher { net: net }
+    }
+}
+
+impl Actor for ProgressWatcher {
+    type Context = ws::WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.net.do_send(SubscribeProgress(ctx.address().recipient()));
+    }
+}
+
+impl Handler<ClusterProgress> for ProgressWatcher {
+    type Result = ();
+
+    fn handle(&mut self, msg: ClusterProgress, ctx: &mut Self::Context) {
+        if let Ok(payload) = serde_json::to_string(&msg) {
+            ctx.text(payload);
+        }
+    }
+}
+
+impl StreamHandler<ws::Message, ws::ProtocolError> for ProgressWatcher {
+    fn handle(&mut self, msg: ws::Message, ctx: &mut Self::Context) {
+        match msg {
+            ws::Message::Ping(msg) => ctx.pong(&msg),
+            ws::Message::Close(_) => ctx.stop(),
+            _ => (),
+        }
+    }
+}